@@ -68,6 +68,7 @@ fn generate_log_data(lines: usize, new_lines: usize) -> (Vec<u8>, Vec<u8>) {
     (base.as_bytes().to_vec(), modified.as_bytes().to_vec())
 }
 
+#[allow(clippy::single_element_loop)]
 fn benchmark_json_updates(c: &mut Criterion) {
     let mut group = c.benchmark_group("json_updates");
     group.measurement_time(Duration::from_secs(1));
@@ -112,6 +113,7 @@ fn benchmark_json_updates(c: &mut Criterion) {
     group.finish();
 }
 
+#[allow(clippy::single_element_loop)]
 fn benchmark_log_streaming(c: &mut Criterion) {
     let mut group = c.benchmark_group("log_streaming");
     group.measurement_time(Duration::from_secs(1));
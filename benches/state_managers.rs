@@ -0,0 +1,98 @@
+use bpx::state::{InMemoryStateManager, ShardedStateManager, StateManager};
+use bpx::{BpxConfig, ResourcePath, SessionId, Version};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+const SESSIONS: usize = 10_000;
+const TASKS: usize = 16;
+const OPS_PER_TASK: usize = 1_000;
+
+/// Every task resumes sessions and records a version, as a request would
+async fn request_mix(state_mgr: Arc<dyn StateManager>, sessions: Arc<Vec<SessionId>>) {
+    let path = ResourcePath::new("/api/feed".to_string());
+    let tasks = (0..TASKS)
+        .map(|task| {
+            let state_mgr = Arc::clone(&state_mgr);
+            let sessions = Arc::clone(&sessions);
+            let path = path.clone();
+            tokio::spawn(async move {
+                for op in 0..OPS_PER_TASK {
+                    let id = &sessions[(task * OPS_PER_TASK + op * 7919) % sessions.len()];
+                    let id = state_mgr
+                        .get_or_create_session(Some(id.clone()))
+                        .await
+                        .into_id();
+                    let stored = state_mgr.get_version(&id, &path).await;
+                    state_mgr
+                        .compare_and_set_version(
+                            &id,
+                            &path,
+                            stored.as_ref(),
+                            Version::new(format!("v:{}", op)),
+                        )
+                        .await;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn populate(rt: &Runtime, state_mgr: &Arc<dyn StateManager>) -> Arc<Vec<SessionId>> {
+    rt.block_on(async {
+        let mut sessions = Vec::with_capacity(SESSIONS);
+        for _ in 0..SESSIONS {
+            sessions.push(state_mgr.get_or_create_session(None).await.into_id());
+        }
+        Arc::new(sessions)
+    })
+}
+
+fn benchmark_state_managers(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let config = BpxConfig {
+        max_sessions: SESSIONS * 2,
+        ..BpxConfig::default()
+    };
+
+    let managers: Vec<(&str, Arc<dyn StateManager>)> = vec![
+        (
+            "in_memory",
+            Arc::new(InMemoryStateManager::new(config.clone())),
+        ),
+        (
+            "sharded",
+            Arc::new(ShardedStateManager::new(config.clone())),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("state_managers");
+    group.throughput(Throughput::Elements((TASKS * OPS_PER_TASK) as u64));
+    for (name, state_mgr) in managers {
+        let sessions = populate(&rt, &state_mgr);
+        group.bench_with_input(
+            BenchmarkId::new("request_mix", name),
+            &state_mgr,
+            |b, state_mgr| {
+                b.iter(|| rt.block_on(request_mix(Arc::clone(state_mgr), Arc::clone(&sessions))));
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("cleanup", name),
+            &state_mgr,
+            |b, state_mgr| {
+                b.iter(|| rt.block_on(state_mgr.cleanup_expired()));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_state_managers);
+criterion_main!(benches);
@@ -0,0 +1,349 @@
+//! Collection resources and element-level diffs
+//!
+//! A collection is a list of elements, each with an ID unique within it. Its
+//! content, as served and versioned, is the elements in order:
+//! ```text
+//! +-----------+------+------------+---------+
+//! | IdLen(2B) | Id   | Len(4B)    | Content |  ... per element
+//! +-----------+------+------------+---------+
+//! ```
+//!
+//! An `element-delta` diff between two collections names elements by ID:
+//! - 0x01: REMOVE(id)                        — drop an element
+//! - 0x02: UPDATE(id, content)               — replace a kept element's content
+//! - 0x03: ORDER(count: u32, ids)            — kept elements in their new order
+//! - 0x04: INSERT(index: u32, id, content)   — add an element at its new index
+//!
+//! Operations apply in sequence; [`ElementDiffCodec::compute_diff`] emits
+//! removals, then updates, then an order only if kept elements moved, then
+//! inserts by ascending index.
+//!
+//! # Example
+//! ```
+//! use bpx_client_core::collection::{Element, ElementDiffCodec};
+//!
+//! let old = ElementDiffCodec::encode_collection(&[
+//!     Element::new("1", "pending"),
+//!     Element::new("2", "pending"),
+//! ])
+//! .unwrap();
+//! let new = ElementDiffCodec::encode_collection(&[
+//!     Element::new("2", "shipped"),
+//!     Element::new("3", "pending"),
+//! ])
+//! .unwrap();
+//!
+//! let diff = ElementDiffCodec::compute_diff(&old, &new).unwrap();
+//! assert_eq!(ElementDiffCodec::apply_diff(&old, &diff).unwrap(), new);
+//! ```
+
+use crate::DiffError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+
+/// Media type of collection content
+pub const COLLECTION_MEDIA_TYPE: &str = "application/vnd.bpx.collection";
+
+const REMOVE: u8 = 0x01;
+const UPDATE: u8 = 0x02;
+const ORDER: u8 = 0x03;
+const INSERT: u8 = 0x04;
+
+/// One element of a collection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    /// Identifier, unique within the collection
+    pub id: String,
+    /// Element content
+    pub content: Bytes,
+}
+
+impl Element {
+    /// Create an element
+    pub fn new(id: impl Into<String>, content: impl Into<Bytes>) -> Self {
+        Self {
+            id: id.into(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Collection encoder/decoder and element diffs between collections
+pub struct ElementDiffCodec;
+
+impl ElementDiffCodec {
+    /// Encode elements as collection content
+    pub fn encode_collection(elements: &[Element]) -> Result<Bytes, DiffError> {
+        let mut seen = HashSet::new();
+        let mut buf = BytesMut::new();
+        for element in elements {
+            if !seen.insert(element.id.as_str()) {
+                return Err(duplicate(&element.id));
+            }
+            put_id(&mut buf, &element.id)?;
+            put_content(&mut buf, &element.content)?;
+        }
+        Ok(buf.freeze())
+    }
+
+    /// Decode collection content into its elements
+    pub fn decode_collection(data: &[u8]) -> Result<Vec<Element>, DiffError> {
+        let mut cursor = data;
+        let mut seen = HashSet::new();
+        let mut elements = Vec::new();
+        while cursor.has_remaining() {
+            let id = get_id(&mut cursor)?;
+            if !seen.insert(id.clone()) {
+                return Err(duplicate(&id));
+            }
+            let content = get_content(&mut cursor)?;
+            elements.push(Element { id, content });
+        }
+        Ok(elements)
+    }
+
+    /// Compute the element diff turning collection `old` into `new`
+    pub fn compute_diff(old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        let old = Self::decode_collection(old)?;
+        let new = Self::decode_collection(new)?;
+        let old_content: HashMap<&str, &Bytes> =
+            old.iter().map(|e| (e.id.as_str(), &e.content)).collect();
+        let new_ids: HashSet<&str> = new.iter().map(|e| e.id.as_str()).collect();
+
+        let mut buf = BytesMut::new();
+        for element in old.iter().filter(|e| !new_ids.contains(e.id.as_str())) {
+            buf.put_u8(REMOVE);
+            put_id(&mut buf, &element.id)?;
+        }
+        for element in &new {
+            if old_content
+                .get(element.id.as_str())
+                .is_some_and(|content| **content != element.content)
+            {
+                buf.put_u8(UPDATE);
+                put_id(&mut buf, &element.id)?;
+                put_content(&mut buf, &element.content)?;
+            }
+        }
+
+        let kept_before = old.iter().filter(|e| new_ids.contains(e.id.as_str()));
+        let kept_after: Vec<&Element> = new
+            .iter()
+            .filter(|e| old_content.contains_key(e.id.as_str()))
+            .collect();
+        if !kept_before
+            .map(|e| &e.id)
+            .eq(kept_after.iter().map(|e| &e.id))
+        {
+            buf.put_u8(ORDER);
+            buf.put_u32(kept_after.len() as u32);
+            for element in kept_after {
+                put_id(&mut buf, &element.id)?;
+            }
+        }
+
+        for (index, element) in new.iter().enumerate() {
+            if !old_content.contains_key(element.id.as_str()) {
+                buf.put_u8(INSERT);
+                buf.put_u32(index as u32);
+                put_id(&mut buf, &element.id)?;
+                put_content(&mut buf, &element.content)?;
+            }
+        }
+        Ok(buf.freeze())
+    }
+
+    /// Apply an element diff to collection content
+    pub fn apply_diff(base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        let mut elements = Self::decode_collection(base)?;
+        let mut cursor = diff;
+        while cursor.has_remaining() {
+            match cursor.get_u8() {
+                REMOVE => {
+                    let id = get_id(&mut cursor)?;
+                    let index = position(&elements, &id)?;
+                    elements.remove(index);
+                }
+                UPDATE => {
+                    let id = get_id(&mut cursor)?;
+                    let index = position(&elements, &id)?;
+                    elements[index].content = get_content(&mut cursor)?;
+                }
+                ORDER => {
+                    let count = get_u32(&mut cursor, "order")? as usize;
+                    if count != elements.len() {
+                        return Err(DiffError::PatchFailed(format!(
+                            "Order lists {} elements, collection has {}",
+                            count,
+                            elements.len()
+                        )));
+                    }
+                    let mut reordered = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let id = get_id(&mut cursor)?;
+                        let index = position(&elements, &id)?;
+                        reordered.push(elements.swap_remove(index));
+                    }
+                    elements = reordered;
+                }
+                INSERT => {
+                    let index = get_u32(&mut cursor, "insert")? as usize;
+                    let id = get_id(&mut cursor)?;
+                    let content = get_content(&mut cursor)?;
+                    if index > elements.len() {
+                        return Err(DiffError::PatchFailed(format!(
+                            "Insert of {} at {} beyond the end",
+                            id, index
+                        )));
+                    }
+                    elements.insert(index, Element { id, content });
+                }
+                op => {
+                    return Err(DiffError::InvalidFormat(format!(
+                        "Unknown element operation: 0x{:02x}",
+                        op
+                    )));
+                }
+            }
+        }
+        Self::encode_collection(&elements).map_err(|e| DiffError::PatchFailed(e.to_string()))
+    }
+}
+
+fn duplicate(id: &str) -> DiffError {
+    DiffError::InvalidFormat(format!("Duplicate element ID: {}", id))
+}
+
+fn position(elements: &[Element], id: &str) -> Result<usize, DiffError> {
+    elements
+        .iter()
+        .position(|e| e.id == id)
+        .ok_or_else(|| DiffError::PatchFailed(format!("No element {} in base", id)))
+}
+
+fn put_id(buf: &mut BytesMut, id: &str) -> Result<(), DiffError> {
+    let len = u16::try_from(id.len())
+        .map_err(|_| DiffError::InvalidFormat("Element ID too long (max 16-bit)".to_string()))?;
+    buf.put_u16(len);
+    buf.put_slice(id.as_bytes());
+    Ok(())
+}
+
+fn put_content(buf: &mut BytesMut, content: &[u8]) -> Result<(), DiffError> {
+    let len = u32::try_from(content.len()).map_err(|_| {
+        DiffError::InvalidFormat("Element content too large (max 32-bit length)".to_string())
+    })?;
+    buf.put_u32(len);
+    buf.put_slice(content);
+    Ok(())
+}
+
+fn get_u32(cursor: &mut &[u8], what: &str) -> Result<u32, DiffError> {
+    if cursor.remaining() < 4 {
+        return Err(insufficient(what));
+    }
+    Ok(cursor.get_u32())
+}
+
+fn get_id(cursor: &mut &[u8]) -> Result<String, DiffError> {
+    if cursor.remaining() < 2 {
+        return Err(insufficient("element ID length"));
+    }
+    let len = cursor.get_u16() as usize;
+    if cursor.remaining() < len {
+        return Err(insufficient("element ID"));
+    }
+    let id = std::str::from_utf8(&cursor[..len])
+        .map_err(|_| DiffError::InvalidFormat("Element ID is not UTF-8".to_string()))?
+        .to_string();
+    cursor.advance(len);
+    Ok(id)
+}
+
+fn get_content(cursor: &mut &[u8]) -> Result<Bytes, DiffError> {
+    let len = get_u32(cursor, "element length")? as usize;
+    if cursor.remaining() < len {
+        return Err(insufficient("element content"));
+    }
+    let content = Bytes::copy_from_slice(&cursor[..len]);
+    cursor.advance(len);
+    Ok(content)
+}
+
+fn insufficient(what: &str) -> DiffError {
+    DiffError::InvalidFormat(format!("Insufficient data for {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collection(elements: &[(&str, &str)]) -> Bytes {
+        let elements: Vec<_> = elements
+            .iter()
+            .map(|(id, content)| Element::new(*id, content.to_string()))
+            .collect();
+        ElementDiffCodec::encode_collection(&elements).unwrap()
+    }
+
+    #[test]
+    fn test_collection_roundtrip() {
+        let content = collection(&[("a", "first"), ("b", "")]);
+        let elements = ElementDiffCodec::decode_collection(&content).unwrap();
+        assert_eq!(
+            elements,
+            vec![Element::new("a", "first"), Element::new("b", "")]
+        );
+
+        assert!(
+            ElementDiffCodec::encode_collection(&[Element::new("a", "1"), Element::new("a", "2")])
+                .is_err()
+        );
+        assert!(ElementDiffCodec::decode_collection(&content[..content.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_element_diffs() {
+        let cases = [
+            (vec![], vec![("a", "1")]),
+            (vec![("a", "1"), ("b", "2")], vec![]),
+            (vec![("a", "1"), ("b", "2")], vec![("a", "1"), ("b", "3")]),
+            (
+                vec![("a", "1"), ("b", "2"), ("c", "3")],
+                vec![("x", "0"), ("a", "1"), ("c", "4"), ("y", "5")],
+            ),
+            (
+                vec![("a", "1"), ("b", "2"), ("c", "3")],
+                vec![("c", "3"), ("n", "new"), ("a", "1")],
+            ),
+        ];
+        for (old, new) in cases {
+            let (old, new) = (collection(&old), collection(&new));
+            let diff = ElementDiffCodec::compute_diff(&old, &new).unwrap();
+            assert_eq!(ElementDiffCodec::apply_diff(&old, &diff).unwrap(), new);
+        }
+
+        // Unchanged elements cost nothing
+        let old = collection(&[("a", "a long element body"), ("b", "2")]);
+        let new = collection(&[("a", "a long element body"), ("b", "3")]);
+        let diff = ElementDiffCodec::compute_diff(&old, &new).unwrap();
+        assert!(!diff.windows(4).any(|w| w == b"long"));
+    }
+
+    #[test]
+    fn test_element_diff_needs_its_base() {
+        let old = collection(&[("a", "1"), ("b", "2")]);
+        let new = collection(&[("b", "3")]);
+        let diff = ElementDiffCodec::compute_diff(&old, &new).unwrap();
+
+        let other = collection(&[("b", "2")]);
+        assert!(matches!(
+            ElementDiffCodec::apply_diff(&other, &diff),
+            Err(DiffError::PatchFailed(_))
+        ));
+        assert!(matches!(
+            ElementDiffCodec::apply_diff(&old, &[0xff]),
+            Err(DiffError::InvalidFormat(_))
+        ));
+    }
+}
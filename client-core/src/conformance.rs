@@ -0,0 +1,233 @@
+//! Golden exchange vectors for checking client and server implementations
+//!
+//! Every directory under [`vectors_dir`] is one exchange recorded from the
+//! reference server. Other implementations (JS, Python, ...) can read the same
+//! files; the layout is described in `vectors/README.md`. [`check`] runs a
+//! client vector against [`ClientState`] and is what the Rust reference
+//! passes; `bpx::conformance::check` replays server vectors.
+
+use crate::ClientState;
+use bytes::Bytes;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Session ID used in every vector
+pub const SESSION: &str = "sess_conformance";
+
+/// Path the vectors are replayed against
+const PATH: &str = "/resource";
+
+/// One recorded exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// Directory name
+    pub name: String,
+    /// Content the client holds before the exchange
+    pub base: Option<Bytes>,
+    /// BPX headers a client holding `base` sends
+    pub request_headers: Vec<(String, String)>,
+    /// Response status
+    pub status: u16,
+    /// Response headers
+    pub response_headers: Vec<(String, String)>,
+    /// Response body (full content or diff)
+    pub response_body: Bytes,
+    /// Content after the exchange, or the error code a client must raise;
+    /// for servers, the content the resource holds when the request arrives
+    pub expected: Result<Bytes, String>,
+    /// Whether clients are checked against the vector
+    pub client: bool,
+    /// Whether servers are checked against the vector (it was recorded as
+    /// is, not tampered with)
+    pub server: bool,
+}
+
+/// Directory holding the vectors shipped with this crate
+pub fn vectors_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("vectors")
+}
+
+/// Parse `Name: value` lines
+pub fn parse_headers(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// Format headers as `Name: value` lines
+pub fn format_headers(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\n", name, value))
+        .collect()
+}
+
+impl Vector {
+    /// Read a vector from its directory
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let read_optional = |file: &str| match fs::read(dir.join(file)) {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        let read_text = |file: &str| -> io::Result<String> {
+            Ok(read_optional(file)?
+                .map(|data| String::from_utf8_lossy(&data).into_owned())
+                .unwrap_or_default())
+        };
+
+        let expected = match read_optional("expected")? {
+            Some(content) => Ok(content),
+            None => Err(read_text("expected.error")?.trim().to_string()),
+        };
+        let status = match read_optional("response.status")? {
+            Some(status) => String::from_utf8_lossy(&status)
+                .trim()
+                .parse()
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid response.status")
+                })?,
+            None => 200,
+        };
+        // Vectors are for both sides unless they say otherwise
+        let checks = read_optional("checks")?.map_or_else(
+            || "client server".to_string(),
+            |checks| String::from_utf8_lossy(&checks).into_owned(),
+        );
+        let checks = checks.split_whitespace().collect::<Vec<_>>();
+        Ok(Self {
+            name: dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            base: read_optional("base")?,
+            request_headers: parse_headers(&read_text("request.headers")?),
+            status,
+            response_headers: parse_headers(&read_text("response.headers")?),
+            response_body: read_optional("response.body")?.unwrap_or_default(),
+            expected,
+            client: checks.contains(&"client"),
+            server: checks.contains(&"server"),
+        })
+    }
+
+    /// Write the vector into `parent/<name>`
+    pub fn write(&self, parent: &Path) -> io::Result<()> {
+        let dir = parent.join(&self.name);
+        fs::create_dir_all(&dir)?;
+        if let Some(base) = &self.base {
+            fs::write(dir.join("base"), base)?;
+        }
+        fs::write(
+            dir.join("request.headers"),
+            format_headers(&self.request_headers),
+        )?;
+        if self.status != 200 {
+            fs::write(dir.join("response.status"), format!("{}\n", self.status))?;
+        }
+        fs::write(
+            dir.join("response.headers"),
+            format_headers(&self.response_headers),
+        )?;
+        fs::write(dir.join("response.body"), &self.response_body)?;
+        if !(self.client && self.server) {
+            let checks = [(self.client, "client"), (self.server, "server")]
+                .into_iter()
+                .filter_map(|(checked, role)| checked.then_some(role))
+                .collect::<Vec<_>>();
+            fs::write(dir.join("checks"), format!("{}\n", checks.join(" ")))?;
+        }
+        match &self.expected {
+            Ok(content) => fs::write(dir.join("expected"), content),
+            Err(code) => fs::write(dir.join("expected.error"), format!("{}\n", code)),
+        }
+    }
+}
+
+/// Load every vector under `dir`, sorted by name
+pub fn load_vectors(dir: &Path) -> io::Result<Vec<Vector>> {
+    let mut vectors = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| Vector::load(&entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    vectors.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(vectors)
+}
+
+/// Replay a client vector against [`ClientState`], describing the first
+/// mismatch
+pub fn check(vector: &Vector) -> Result<(), String> {
+    let mut state = ClientState::new();
+    if let Some(base) = &vector.base {
+        let seed = [("X-BPX-Session", SESSION), ("X-Diff-Type", "full")];
+        state
+            .apply_response(PATH, |name| lookup(&seed, name), base.clone())
+            .map_err(|e| format!("seeding base failed: {}", e))?;
+    }
+
+    let mut sent = state
+        .request_headers(PATH)
+        .into_iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value))
+        .collect::<Vec<_>>();
+    let mut expected_sent = vector
+        .request_headers
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+        .collect::<Vec<_>>();
+    sent.sort();
+    expected_sent.sort();
+    if sent != expected_sent {
+        return Err(format!(
+            "request headers {:?}, expected {:?}",
+            sent, expected_sent
+        ));
+    }
+
+    let headers = vector
+        .response_headers
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect::<Vec<_>>();
+    let result = state.apply_response(
+        PATH,
+        |name| lookup(&headers, name),
+        vector.response_body.clone(),
+    );
+    match (&vector.expected, result) {
+        (Ok(expected), Ok(result)) if result.content == *expected => Ok(()),
+        (Ok(_), Ok(_)) => Err("reconstructed content differs".to_string()),
+        (Ok(_), Err(e)) => Err(format!("unexpected error: {}", e)),
+        (Err(code), Err(e)) if e.code() == code => Ok(()),
+        (Err(code), Err(e)) => Err(format!("error {} ({}), expected {}", e.code(), e, code)),
+        (Err(code), Ok(_)) => Err(format!("succeeded, expected error {}", code)),
+    }
+}
+
+/// Case-insensitive header lookup
+fn lookup<'h>(headers: &[(&str, &'h str)], name: &str) -> Option<&'h str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipped_vectors() {
+        let vectors = load_vectors(&vectors_dir()).unwrap();
+        assert!(vectors.iter().filter(|v| v.client).count() >= 10);
+        for vector in vectors.iter().filter(|v| v.client) {
+            if let Err(e) = check(vector) {
+                panic!("vector {}: {}", vector.name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! BPX HTTP header names
+
+/// BPX header constants
+pub struct BpxHeaders;
+
+impl BpxHeaders {
+    /// Client session identifier
+    pub const SESSION: &'static str = "X-BPX-Session";
+    /// `resumed` or `created`; on `created` the client must drop its bases
+    pub const SESSION_STATUS: &'static str = "X-BPX-Session-Status";
+    /// Version(s) client currently has (comma-separated, most preferred first)
+    pub const BASE_VERSION: &'static str = "X-Base-Version";
+    /// Comma-separated diff formats client supports
+    pub const ACCEPT_DIFF: &'static str = "Accept-Diff";
+    /// Base version a diff was computed against (when several were offered)
+    pub const DELTA_BASE: &'static str = "X-BPX-Delta-Base";
+    /// Current version identifier
+    pub const RESOURCE_VERSION: &'static str = "X-Resource-Version";
+    /// Format of diff in body
+    pub const DIFF_TYPE: &'static str = "X-Diff-Type";
+    /// Size of full resource in bytes
+    pub const ORIGINAL_SIZE: &'static str = "X-Original-Size";
+    /// Size of diff in bytes
+    pub const DIFF_SIZE: &'static str = "X-Diff-Size";
+    /// How long client should cache this version (seconds)
+    pub const CACHE_TTL: &'static str = "X-BPX-Cache-TTL";
+    /// Machine-readable reason a full body was sent instead of a diff
+    pub const FALLBACK_REASON: &'static str = "X-BPX-Fallback-Reason";
+    /// Media type of the patched (reconstructed) resource on diff responses
+    pub const ORIGINAL_CONTENT_TYPE: &'static str = "X-Original-Content-Type";
+    /// Hash of the patched content (as [`crate::version_of`]) on diff
+    /// responses whose version is not that hash
+    pub const CONTENT_DIGEST: &'static str = "X-BPX-Content-Digest";
+    /// Response signature (`<algorithm>=<hex>`)
+    pub const SIGNATURE: &'static str = "X-BPX-Signature";
+
+    /// Get all BPX header names
+    pub fn all() -> &'static [&'static str] {
+        &[
+            Self::SESSION,
+            Self::SESSION_STATUS,
+            Self::BASE_VERSION,
+            Self::ACCEPT_DIFF,
+            Self::DELTA_BASE,
+            Self::RESOURCE_VERSION,
+            Self::DIFF_TYPE,
+            Self::ORIGINAL_SIZE,
+            Self::DIFF_SIZE,
+            Self::CACHE_TTL,
+            Self::ORIGINAL_CONTENT_TYPE,
+            Self::FALLBACK_REASON,
+            Self::CONTENT_DIGEST,
+            Self::SIGNATURE,
+        ]
+    }
+
+    /// Check if a header name is a BPX header
+    pub fn is_bpx_header(name: &str) -> bool {
+        Self::all().contains(&name)
+    }
+}
@@ -0,0 +1,71 @@
+//! # BPX client core
+//!
+//! Patch application, header names and version bookkeeping for BPX clients,
+//! free of any async runtime or HTTP stack so it also builds for
+//! `wasm32-unknown-unknown`. Browser front-ends issue requests with `fetch()`,
+//! hand the response headers and body to [`ClientState`], and get the
+//! reconstructed content back.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+#![warn(clippy::all)]
+
+use thiserror::Error;
+
+pub mod collection;
+pub mod conformance;
+pub mod headers;
+pub mod patch;
+pub mod response;
+pub mod state;
+
+pub use response::{ClientError, Reconstructed, ResponseMeta, reconstruct};
+pub use state::{CachedBase, ClientState};
+
+/// Errors that can occur during diff operations
+#[derive(Debug, Error)]
+pub enum DiffError {
+    /// Invalid diff format
+    #[error("Invalid diff format: {0}")]
+    InvalidFormat(String),
+
+    /// Diff computation failed
+    #[error("Diff computation failed: {0}")]
+    ComputationFailed(String),
+
+    /// Patch application failed
+    #[error("Patch application failed: {0}")]
+    PatchFailed(String),
+
+    /// Diff costs more to decode or apply than its [`patch::PatchLimits`] allow
+    #[error("Diff exceeds limit: {0}")]
+    LimitExceeded(String),
+
+    /// Patched content would grow past this many bytes, as a crafted diff
+    /// inflating a few wire bytes into a huge output does
+    #[error("Patched content exceeds {0} bytes")]
+    OutputLimitExceeded(usize),
+}
+
+impl DiffError {
+    /// Stable, machine-readable code for this error's kind
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidFormat(_) => "invalid-format",
+            Self::ComputationFailed(_) => "computation-failed",
+            Self::PatchFailed(_) => "patch-failed",
+            Self::LimitExceeded(_) => "limit-exceeded",
+            Self::OutputLimitExceeded(_) => "output-limit-exceeded",
+        }
+    }
+}
+
+/// Content-derived version identifier, as assigned by the BPX server
+pub fn version_of(content: &[u8]) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    format!("v:{:x}", hasher.finish())
+}
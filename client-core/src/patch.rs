@@ -0,0 +1,987 @@
+//! Binary diff format
+//!
+//! Wire Format (v1, sequential copy):
+//! ```text
+//! +--------+--------+----------------+
+//! | Op(1B) | Len(3B)| Data           |
+//! +--------+--------+----------------+
+//! ```
+//!
+//! Operations:
+//! - 0x01: COPY(length: u24)           — copy next bytes from base (sequential)
+//! - 0x02: INSERT(length: u24, data)   — insert new data
+//! - 0x03: DELETE(length: u24)         — skip bytes from base
+//! - 0x04: END                          — end of diff stream
+//!
+//! Note: The `Copy` operation uses sequential semantics in v1 (no offset is encoded).
+//! The `offset` field in `DiffOperation::Copy` is currently ignored by the encoder/decoder
+//! and reserved for potential future non-sequential variants.
+//!
+//! # Example
+//! ```
+//! use bpx_client_core::patch::{BinaryDiffCodec, DiffOperation};
+//!
+//! let operations = vec![
+//!     DiffOperation::Copy { offset: 0, length: 9 },
+//!     DiffOperation::Delete { length: 3 },
+//!     DiffOperation::Insert(b"Robert".to_vec()),
+//!     DiffOperation::Copy { offset: 0, length: 2 },
+//! ];
+//!
+//! let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+//! let base = br#"{"name":"Bob"}"#;
+//! let result = BinaryDiffCodec::apply_diff(base, &encoded).unwrap();
+//! assert_eq!(result.as_ref(), br#"{"name":"Robert"}"#);
+//! ```
+
+use crate::DiffError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Binary diff operations
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    /// Copy from old version
+    Copy = 0x01,
+    /// Insert new data
+    Insert = 0x02,
+    /// Delete/skip bytes from old version
+    Delete = 0x03,
+    /// End of diff stream
+    End = 0x04,
+}
+
+impl DiffOp {
+    /// Convert from byte value
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Copy),
+            0x02 => Some(Self::Insert),
+            0x03 => Some(Self::Delete),
+            0x04 => Some(Self::End),
+            _ => None,
+        }
+    }
+
+    /// Convert to byte value
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Get all valid operation codes
+    pub fn all() -> &'static [DiffOp] {
+        &[Self::Copy, Self::Insert, Self::Delete, Self::End]
+    }
+
+    /// Check if operation requires length parameter
+    pub fn requires_length(self) -> bool {
+        matches!(self, Self::Copy | Self::Insert | Self::Delete)
+    }
+
+    /// Check if operation requires data parameter
+    pub fn requires_data(self) -> bool {
+        matches!(self, Self::Insert)
+    }
+}
+
+/// Diff operation with data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffOperation {
+    /// Copy bytes from the base version (sequential; `offset` reserved/ignored)
+    Copy {
+        /// Offset in the original content
+        offset: u32,
+        /// Number of bytes to copy
+        length: u32,
+    },
+    /// Insert new data
+    Insert(Vec<u8>),
+    /// Delete/skip bytes from old version
+    Delete {
+        /// Number of bytes to skip/delete
+        length: u32,
+    },
+}
+
+/// Caps on what decoding and applying one diff may cost, for diffs from
+/// untrusted peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchLimits {
+    /// Operations the diff may hold
+    pub max_operations: usize,
+    /// Bytes the patched content may grow to
+    pub max_output_size: usize,
+    /// Bytes of INSERT data the diff may carry in total
+    pub max_insert_bytes: usize,
+}
+
+impl PatchLimits {
+    /// No caps beyond what the wire format allows
+    pub const UNLIMITED: Self = Self {
+        max_operations: usize::MAX,
+        max_output_size: usize::MAX,
+        max_insert_bytes: usize::MAX,
+    };
+
+    /// Only cap the patched content at `max_output_size` bytes
+    pub const fn output(max_output_size: usize) -> Self {
+        Self {
+            max_output_size,
+            ..Self::UNLIMITED
+        }
+    }
+}
+
+impl Default for PatchLimits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+fn limit_exceeded(what: &str, max: usize) -> DiffError {
+    DiffError::LimitExceeded(format!("{} (max: {})", what, max))
+}
+
+/// Count `len` more bytes of `output`, failing before anything past `max`
+/// is produced
+fn grow(output: &mut usize, len: usize, max: usize) -> Result<(), DiffError> {
+    *output = output.saturating_add(len);
+    if *output > max {
+        return Err(DiffError::OutputLimitExceeded(max));
+    }
+    Ok(())
+}
+
+/// Binary diff encoder/decoder
+pub struct BinaryDiffCodec;
+impl BinaryDiffCodec {
+    /// Encode diff operations to binary format
+    ///
+    /// # Arguments
+    /// * `operations` - List of diff operations to encode
+    ///
+    /// # Returns
+    /// Binary diff data following BPX wire format
+    pub fn encode_diff(operations: &[DiffOperation]) -> Result<Bytes, DiffError> {
+        let mut buf = BytesMut::new();
+
+        for op in operations {
+            match op {
+                DiffOperation::Copy { offset: _, length } => {
+                    // Copy format (v1 sequential): [op(1B), length(3B)]
+                    buf.put_u8(DiffOp::Copy as u8);
+                    if *length > 0xFFFFFF {
+                        return Err(DiffError::InvalidFormat(
+                            "Copy length too large (max 24-bit)".to_string(),
+                        ));
+                    }
+                    buf.put_uint(*length as u64, 3);
+                    // `offset` is ignored in this wire version (sequential copy)
+                }
+                DiffOperation::Insert(data) => {
+                    // Insert format: [op(1B), length(3B), data...]
+                    buf.put_u8(DiffOp::Insert as u8);
+                    if data.len() > 0xFFFFFF {
+                        return Err(DiffError::InvalidFormat(
+                            "Insert data too large (max 24-bit length)".to_string(),
+                        ));
+                    }
+                    buf.put_uint(data.len() as u64, 3);
+                    buf.put_slice(data);
+                }
+                DiffOperation::Delete { length } => {
+                    // Delete format: [op(1B), length(3B)]
+                    buf.put_u8(DiffOp::Delete as u8);
+                    if *length > 0xFFFFFF {
+                        return Err(DiffError::InvalidFormat(
+                            "Delete length too large (max 24-bit)".to_string(),
+                        ));
+                    }
+                    buf.put_uint(*length as u64, 3);
+                }
+            }
+        }
+
+        buf.put_u8(DiffOp::End as u8);
+        Ok(buf.freeze())
+    }
+
+    /// Decode binary diff data to operations
+    ///
+    /// # Arguments
+    /// * `diff_data` - Binary diff data following BPX wire format
+    ///
+    /// # Returns
+    /// List of decoded diff operations
+    pub fn decode_diff(diff_data: &[u8]) -> Result<Vec<DiffOperation>, DiffError> {
+        Self::decode_diff_with_limits(diff_data, &PatchLimits::UNLIMITED)
+    }
+
+    /// Decode binary diff data, failing with [`DiffError::LimitExceeded`]
+    /// once it holds more operations or INSERT data than `limits` allow
+    pub fn decode_diff_with_limits(
+        diff_data: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Vec<DiffOperation>, DiffError> {
+        let mut operations = Vec::new();
+        let mut cursor = diff_data;
+        let mut inserted = 0usize;
+
+        while !cursor.is_empty() {
+            let op_byte = cursor.get_u8();
+            let op = DiffOp::from_u8(op_byte).ok_or_else(|| {
+                DiffError::InvalidFormat(format!("Unknown operation: 0x{:02x}", op_byte))
+            })?;
+
+            match op {
+                DiffOp::Copy => {
+                    if cursor.remaining() < 3 {
+                        return Err(DiffError::InvalidFormat(
+                            "Insufficient data for Copy operation length".to_string(),
+                        ));
+                    }
+                    let length = cursor.get_uint(3) as u32;
+                    // offset is implicitly the current position
+                    operations.push(DiffOperation::Copy { offset: 0, length });
+                }
+                DiffOp::Insert => {
+                    if cursor.remaining() < 3 {
+                        return Err(DiffError::InvalidFormat(
+                            "Insufficient data for Insert operation length".to_string(),
+                        ));
+                    }
+                    let length = cursor.get_uint(3) as usize;
+                    if cursor.remaining() < length {
+                        return Err(DiffError::InvalidFormat(
+                            "Insufficient data for Insert operation payload".to_string(),
+                        ));
+                    }
+                    inserted += length;
+                    if inserted > limits.max_insert_bytes {
+                        return Err(limit_exceeded(
+                            "too much inserted data",
+                            limits.max_insert_bytes,
+                        ));
+                    }
+                    let data = cursor[..length].to_vec();
+                    cursor.advance(length);
+                    operations.push(DiffOperation::Insert(data));
+                }
+                DiffOp::Delete => {
+                    if cursor.remaining() < 3 {
+                        return Err(DiffError::InvalidFormat(
+                            "Insufficient data for Delete operation length".to_string(),
+                        ));
+                    }
+                    let length = cursor.get_uint(3) as u32;
+                    operations.push(DiffOperation::Delete { length });
+                }
+                DiffOp::End => {
+                    break;
+                }
+            }
+            if operations.len() > limits.max_operations {
+                return Err(limit_exceeded("too many operations", limits.max_operations));
+            }
+        }
+
+        Ok(operations)
+    }
+
+    /// Apply diff operations to base content
+    ///
+    /// # Arguments
+    /// * `base` - Original content to apply diff to
+    /// * `operations` - Diff operations to apply
+    ///
+    /// # Returns
+    /// Result of applying diff operations
+    pub fn apply_operations(base: &[u8], operations: &[DiffOperation]) -> Result<Bytes, DiffError> {
+        PatchApplier::new(base).apply(operations)
+    }
+
+    /// Convenience method to apply binary diff to base content
+    ///
+    /// # Arguments
+    /// * `base` - Original content
+    /// * `diff_data` - Binary diff data
+    ///
+    /// # Returns
+    /// Reconstructed content after applying diff
+    pub fn apply_diff(base: &[u8], diff_data: &[u8]) -> Result<Bytes, DiffError> {
+        let operations = Self::decode_diff(diff_data)?;
+        Self::apply_operations(base, &operations)
+    }
+
+    /// Apply binary diff to base content within `limits`
+    pub fn apply_diff_with_limits(
+        base: &[u8],
+        diff_data: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
+        let operations = Self::decode_diff_with_limits(diff_data, limits)?;
+        PatchApplier::with_limits(base, *limits).apply(&operations)
+    }
+}
+
+/// Incremental patch applier that keeps its position in the base across calls
+///
+/// Used to apply a diff frame by frame (see `bpx::protocol::wire::FrameDecoder`)
+/// without buffering the whole diff or the reconstructed output.
+pub struct PatchApplier<'a> {
+    base: &'a [u8],
+    base_pos: usize,
+    limits: PatchLimits,
+    operations: usize,
+    inserted: usize,
+    output: usize,
+}
+
+impl<'a> PatchApplier<'a> {
+    /// Create a new applier positioned at the start of `base`
+    pub fn new(base: &'a [u8]) -> Self {
+        Self::with_limits(base, PatchLimits::UNLIMITED)
+    }
+
+    /// Create an applier failing with [`DiffError::LimitExceeded`] once the
+    /// operations applied across all calls exceed `limits`, or with
+    /// [`DiffError::OutputLimitExceeded`] before their output does
+    pub fn with_limits(base: &'a [u8], limits: PatchLimits) -> Self {
+        Self {
+            base,
+            base_pos: 0,
+            limits,
+            operations: 0,
+            inserted: 0,
+            output: 0,
+        }
+    }
+
+    /// Apply a batch of operations, returning the output they produce
+    pub fn apply(&mut self, operations: &[DiffOperation]) -> Result<Bytes, DiffError> {
+        let mut result = BytesMut::new();
+
+        for op in operations {
+            self.operations += 1;
+            if self.operations > self.limits.max_operations {
+                return Err(limit_exceeded(
+                    "too many operations",
+                    self.limits.max_operations,
+                ));
+            }
+            match op {
+                DiffOperation::Copy { offset: _, length } => {
+                    let end_pos = self.base_pos + *length as usize;
+                    if end_pos > self.base.len() {
+                        return Err(DiffError::PatchFailed(
+                            "Copy operation exceeds base content length".to_string(),
+                        ));
+                    }
+                    self.grow(*length as usize)?;
+                    result.put_slice(&self.base[self.base_pos..end_pos]);
+                    self.base_pos = end_pos;
+                }
+                DiffOperation::Insert(data) => {
+                    self.inserted += data.len();
+                    if self.inserted > self.limits.max_insert_bytes {
+                        return Err(limit_exceeded(
+                            "too much inserted data",
+                            self.limits.max_insert_bytes,
+                        ));
+                    }
+                    self.grow(data.len())?;
+                    result.put_slice(data);
+                    // base_pos stays the same - we're inserting new content
+                }
+                DiffOperation::Delete { length } => {
+                    self.base_pos += *length as usize;
+                    if self.base_pos > self.base.len() {
+                        return Err(DiffError::PatchFailed(
+                            "Delete operation exceeds base content length".to_string(),
+                        ));
+                    }
+                    // Skip deleted bytes - don't copy to result
+                }
+            }
+        }
+
+        Ok(result.freeze())
+    }
+
+    /// Decode and apply an encoded chunk of operations (e.g. one frame payload)
+    pub fn apply_chunk(&mut self, chunk: &[u8]) -> Result<Bytes, DiffError> {
+        let remaining = PatchLimits {
+            max_operations: self.limits.max_operations - self.operations,
+            max_insert_bytes: self.limits.max_insert_bytes - self.inserted,
+            ..self.limits
+        };
+        let operations = BinaryDiffCodec::decode_diff_with_limits(chunk, &remaining)?;
+        self.apply(&operations)
+    }
+
+    /// Count `len` more output bytes against the output limit
+    fn grow(&mut self, len: usize) -> Result<(), DiffError> {
+        grow(&mut self.output, len, self.limits.max_output_size)
+    }
+
+    /// Number of base bytes consumed so far
+    pub fn base_position(&self) -> usize {
+        self.base_pos
+    }
+}
+
+/// Where a [`StreamingPatcher`] is within the diff
+#[derive(Debug, Clone, Copy)]
+enum StreamPosition {
+    /// Reading an op byte and its 24-bit length
+    Header { buf: [u8; 4], filled: usize },
+    /// Passing through INSERT data
+    Inserting { remaining: usize },
+    /// END seen; remaining input is ignored
+    Finished,
+}
+
+/// Applies a binary diff as it arrives, in chunks split at arbitrary byte offsets
+///
+/// Unlike [`PatchApplier`], chunks need not end on operation boundaries, so a
+/// plain (unframed) diff body can be patched straight off the network.
+pub struct StreamingPatcher {
+    base: Bytes,
+    base_pos: usize,
+    position: StreamPosition,
+    max_output_size: usize,
+    output: usize,
+}
+
+impl StreamingPatcher {
+    /// Create a patcher positioned at the start of `base`
+    pub fn new(base: Bytes) -> Self {
+        Self::with_max_output_size(base, usize::MAX)
+    }
+
+    /// Create a patcher failing with [`DiffError::OutputLimitExceeded`]
+    /// before the patched content grows past `max_output_size` bytes
+    pub fn with_max_output_size(base: Bytes, max_output_size: usize) -> Self {
+        Self {
+            base,
+            base_pos: 0,
+            position: StreamPosition::Header {
+                buf: [0; 4],
+                filled: 0,
+            },
+            max_output_size,
+            output: 0,
+        }
+    }
+
+    /// Consume the next chunk of the diff, returning the output it completes
+    pub fn push(&mut self, mut chunk: &[u8]) -> Result<Bytes, DiffError> {
+        let mut output = BytesMut::new();
+
+        while !chunk.is_empty() {
+            match &mut self.position {
+                StreamPosition::Finished => break,
+                StreamPosition::Inserting { remaining } => {
+                    let take = (*remaining).min(chunk.len());
+                    grow(&mut self.output, take, self.max_output_size)?;
+                    output.put_slice(&chunk[..take]);
+                    chunk.advance(take);
+                    *remaining -= take;
+                    if *remaining == 0 {
+                        self.position = StreamPosition::Header {
+                            buf: [0; 4],
+                            filled: 0,
+                        };
+                    }
+                }
+                StreamPosition::Header { buf, filled } => {
+                    buf[*filled] = chunk.get_u8();
+                    *filled += 1;
+
+                    let op = DiffOp::from_u8(buf[0]).ok_or_else(|| {
+                        DiffError::InvalidFormat(format!("Unknown operation: 0x{:02x}", buf[0]))
+                    })?;
+                    if op == DiffOp::End {
+                        self.position = StreamPosition::Finished;
+                        continue;
+                    }
+                    if *filled < buf.len() {
+                        continue;
+                    }
+
+                    let length = (&buf[1..]).get_uint(3) as usize;
+                    self.position = StreamPosition::Header {
+                        buf: [0; 4],
+                        filled: 0,
+                    };
+                    match op {
+                        DiffOp::Copy => {
+                            let end_pos = self.base_pos + length;
+                            if end_pos > self.base.len() {
+                                return Err(DiffError::PatchFailed(
+                                    "Copy operation exceeds base content length".to_string(),
+                                ));
+                            }
+                            grow(&mut self.output, length, self.max_output_size)?;
+                            output.put_slice(&self.base[self.base_pos..end_pos]);
+                            self.base_pos = end_pos;
+                        }
+                        DiffOp::Delete => {
+                            self.base_pos += length;
+                            if self.base_pos > self.base.len() {
+                                return Err(DiffError::PatchFailed(
+                                    "Delete operation exceeds base content length".to_string(),
+                                ));
+                            }
+                        }
+                        DiffOp::Insert if length > 0 => {
+                            self.position = StreamPosition::Inserting { remaining: length };
+                        }
+                        DiffOp::Insert | DiffOp::End => {}
+                    }
+                }
+            }
+        }
+
+        Ok(output.freeze())
+    }
+
+    /// Check the diff ended on an operation boundary
+    pub fn finish(&self) -> Result<(), DiffError> {
+        match self.position {
+            StreamPosition::Finished | StreamPosition::Header { filled: 0, .. } => Ok(()),
+            StreamPosition::Header { .. } => Err(DiffError::InvalidFormat(
+                "Diff ends inside an operation header".to_string(),
+            )),
+            StreamPosition::Inserting { .. } => Err(DiffError::InvalidFormat(
+                "Insufficient data for Insert operation payload".to_string(),
+            )),
+        }
+    }
+
+    /// Number of base bytes consumed so far
+    pub fn base_position(&self) -> usize {
+        self.base_pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_copy_operation() {
+        let operations = vec![DiffOperation::Copy {
+            offset: 0,
+            length: 5,
+        }];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+
+        // Check wire format: [COPY(1B), length(3B), END(1B)]
+        assert_eq!(encoded.len(), 5); // 1 + 3 + 1
+        assert_eq!(encoded[0], DiffOp::Copy as u8);
+        assert_eq!(encoded[4], DiffOp::End as u8);
+    }
+
+    #[test]
+    fn test_encode_decode_insert_operation() {
+        let data = b"hello world".to_vec();
+        let operations = vec![DiffOperation::Insert(data.clone())];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+
+        // Check wire format: [INSERT(1B), length(3B), data(11B), END(1B)]
+        assert_eq!(encoded.len(), 1 + 3 + 11 + 1);
+        assert_eq!(encoded[0], DiffOp::Insert as u8);
+        assert_eq!(encoded[15], DiffOp::End as u8);
+
+        // Check data is correctly encoded
+        let encoded_data = &encoded[4..15];
+        assert_eq!(encoded_data, data.as_slice());
+    }
+
+    #[test]
+    fn test_encode_decode_delete_operation() {
+        let operations = vec![DiffOperation::Delete { length: 3 }];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+
+        // Check wire format: [DELETE(1B), length(3B), END(1B)]
+        assert_eq!(encoded.len(), 5);
+        assert_eq!(encoded[0], DiffOp::Delete as u8);
+        assert_eq!(encoded[4], DiffOp::End as u8);
+    }
+
+    #[test]
+    fn test_encode_decode_complex_sequence() {
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: 7,
+            },
+            DiffOperation::Delete { length: 3 },
+            DiffOperation::Insert(b"Robert".to_vec()),
+            DiffOperation::Copy {
+                offset: 0,
+                length: 2,
+            },
+        ];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+    }
+
+    #[test]
+    fn test_apply_operations_copy() {
+        let base = b"Hello, World!";
+        let operations = vec![DiffOperation::Copy {
+            offset: 0,
+            length: 5,
+        }];
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations).unwrap();
+        assert_eq!(result.as_ref(), b"Hello");
+    }
+
+    #[test]
+    fn test_apply_operations_insert() {
+        let base = b"Hello";
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: 5,
+            },
+            DiffOperation::Insert(b", World!".to_vec()),
+        ];
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations).unwrap();
+        assert_eq!(result.as_ref(), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_apply_operations_delete() {
+        let base = b"Hello, cruel World!";
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: 7,
+            }, // "Hello, "
+            DiffOperation::Delete { length: 6 }, // skip "cruel "
+            DiffOperation::Copy {
+                offset: 0,
+                length: 6,
+            }, // "World!"
+        ];
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations).unwrap();
+        assert_eq!(result.as_ref(), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_json_name_change_example() {
+        // {"name":"Bob"} -> {"name":"Robert"}
+        let base = br#"{"name":"Bob"}"#;
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: 9,
+            }, // `{"name":"`
+            DiffOperation::Delete { length: 3 }, // delete "Bob"
+            DiffOperation::Insert(b"Robert".to_vec()), // insert "Robert"
+            DiffOperation::Copy {
+                offset: 0,
+                length: 2,
+            }, // `"}"`
+        ];
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations).unwrap();
+        assert_eq!(result.as_ref(), br#"{"name":"Robert"}"#);
+    }
+
+    #[test]
+    fn test_roundtrip_encode_apply_diff() {
+        let base = b"The quick brown fox";
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: 10,
+            }, // "The quick "
+            DiffOperation::Delete { length: 5 }, // delete "brown"
+            DiffOperation::Insert(b"red".to_vec()), // insert "red"
+            DiffOperation::Copy {
+                offset: 0,
+                length: 4,
+            }, // " fox"
+        ];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let result = BinaryDiffCodec::apply_diff(base, &encoded).unwrap();
+
+        assert_eq!(result.as_ref(), b"The quick red fox");
+    }
+
+    #[test]
+    fn test_empty_operations() {
+        let operations = vec![];
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+        assert_eq!(encoded.len(), 1);
+        assert_eq!(encoded[0], DiffOp::End as u8);
+    }
+
+    #[test]
+    fn test_apply_empty_diff() {
+        let base = b"unchanged";
+        let operations = vec![];
+        let result = BinaryDiffCodec::apply_operations(base, &operations).unwrap();
+
+        assert_eq!(result.len(), 0); // Empty result since no operations
+    }
+
+    #[test]
+    fn test_large_length_error() {
+        // Test that lengths > 24-bit (0xFFFFFF) are rejected
+        let operations = vec![DiffOperation::Copy {
+            offset: 0,
+            length: 0x1000000,
+        }]; // > 24-bit
+
+        let result = BinaryDiffCodec::encode_diff(&operations);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Copy length too large")
+        );
+    }
+
+    #[test]
+    fn test_large_insert_data_error() {
+        // Test that insert data > 24-bit length is rejected
+        let large_data = vec![0u8; 0x1000000]; // > 24-bit length
+        let operations = vec![DiffOperation::Insert(large_data)];
+
+        let result = BinaryDiffCodec::encode_diff(&operations);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Insert data too large")
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_operation() {
+        // Test decoding with invalid operation code
+        let invalid_data = vec![0xFF, 0x00, 0x00, 0x01]; // Invalid op code
+
+        let result = BinaryDiffCodec::decode_diff(&invalid_data);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Unknown operation: 0xff")
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated_data() {
+        // Test decoding with insufficient data
+        let truncated_data = vec![DiffOp::Copy as u8, 0x00]; // Missing length bytes
+
+        let result = BinaryDiffCodec::decode_diff(&truncated_data);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("Insufficient data")
+        );
+    }
+
+    #[test]
+    fn test_apply_copy_beyond_base() {
+        let base = b"short";
+        let operations = vec![DiffOperation::Copy {
+            offset: 0,
+            length: 100,
+        }]; // Beyond base length
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("exceeds base content length")
+        );
+    }
+
+    #[test]
+    fn test_apply_delete_beyond_base() {
+        let base = b"short";
+        let operations = vec![DiffOperation::Delete { length: 100 }]; // Beyond base length
+
+        let result = BinaryDiffCodec::apply_operations(base, &operations);
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("exceeds base content length")
+        );
+    }
+
+    #[test]
+    fn test_wire_format_compliance() {
+        // Test specific wire format as per specification
+        let operations = vec![DiffOperation::Insert(b"test".to_vec())];
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+
+        // Expected format: [INSERT(0x02), length(0x000004), data("test"), END(0x04)]
+        let expected = vec![
+            0x02, // INSERT
+            0x00, 0x00, 0x04, // length = 4 (24-bit big-endian)
+            b't', b'e', b's', b't', // data
+            0x04, // END
+        ];
+
+        assert_eq!(encoded.as_ref(), expected.as_slice());
+    }
+
+    #[test]
+    fn test_max_24bit_values() {
+        // Test maximum 24-bit values work correctly
+        let max_24bit = 0xFFFFFF;
+        let operations = vec![
+            DiffOperation::Copy {
+                offset: 0,
+                length: max_24bit,
+            },
+            DiffOperation::Delete { length: max_24bit },
+        ];
+
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        let decoded = BinaryDiffCodec::decode_diff(&encoded).unwrap();
+
+        assert_eq!(operations, decoded);
+    }
+
+    #[test]
+    fn test_streaming_patcher_any_split() {
+        let base = Bytes::from_static(br#"{"name":"Bob","tags":["a"]}"#);
+        let diff = BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 9,
+            },
+            DiffOperation::Delete { length: 3 },
+            DiffOperation::Insert(b"Robert".to_vec()),
+            DiffOperation::Copy {
+                offset: 0,
+                length: 15,
+            },
+        ])
+        .unwrap();
+        let expected = BinaryDiffCodec::apply_diff(&base, &diff).unwrap();
+
+        for chunk_size in 1..=diff.len() {
+            let mut patcher = StreamingPatcher::new(base.clone());
+            let mut output = Vec::new();
+            for chunk in diff.chunks(chunk_size) {
+                output.extend_from_slice(&patcher.push(chunk).unwrap());
+            }
+            patcher.finish().unwrap();
+            assert_eq!(output, expected.as_ref(), "chunk size {}", chunk_size);
+        }
+
+        // Truncated inside an insert
+        let mut patcher = StreamingPatcher::new(base.clone());
+        patcher.push(&diff[..diff.len() - 20]).unwrap();
+        assert!(patcher.finish().is_err());
+
+        // Copy past the end of the base
+        let mut patcher = StreamingPatcher::new(Bytes::from_static(b"abc"));
+        assert!(patcher.push(&diff).is_err());
+
+        // Output capped one byte short of the patched content
+        let mut patcher = StreamingPatcher::with_max_output_size(base.clone(), expected.len() - 1);
+        assert!(matches!(
+            patcher.push(&diff),
+            Err(DiffError::OutputLimitExceeded(_))
+        ));
+        let mut patcher = StreamingPatcher::with_max_output_size(base, expected.len());
+        assert_eq!(patcher.push(&diff).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_patch_limits() {
+        let base = b"0123456789";
+        let diff = BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 10,
+            },
+            DiffOperation::Insert(b"abc".to_vec()),
+            DiffOperation::Insert(b"def".to_vec()),
+        ])
+        .unwrap();
+        let limited =
+            |limits: PatchLimits| BinaryDiffCodec::apply_diff_with_limits(base, &diff, &limits);
+
+        assert_eq!(
+            limited(PatchLimits::default()).unwrap().as_ref(),
+            b"0123456789abcdef"
+        );
+        let exact = PatchLimits {
+            max_operations: 3,
+            max_output_size: 16,
+            max_insert_bytes: 6,
+        };
+        assert!(limited(exact).is_ok());
+        for limits in [
+            PatchLimits {
+                max_operations: 2,
+                ..exact
+            },
+            PatchLimits {
+                max_insert_bytes: 5,
+                ..exact
+            },
+        ] {
+            assert!(matches!(limited(limits), Err(DiffError::LimitExceeded(_))));
+        }
+        assert!(matches!(
+            limited(PatchLimits {
+                max_output_size: 15,
+                ..exact
+            }),
+            Err(DiffError::OutputLimitExceeded(15))
+        ));
+
+        // Limits hold across chunks
+        let mut applier = PatchApplier::with_limits(base, exact);
+        applier.apply_chunk(&diff).unwrap();
+        assert!(matches!(
+            applier.apply_chunk(&diff),
+            Err(DiffError::LimitExceeded(_))
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+//! Turning a received BPX response into full content
+
+use crate::{DiffError, collection::ElementDiffCodec, headers::BpxHeaders, version_of};
+use bytes::Bytes;
+use thiserror::Error;
+
+/// Content type header name (lowercase, as HTTP stacks normalise it)
+const CONTENT_TYPE: &str = "content-type";
+
+/// Diff formats this core can apply
+const BINARY_DELTA: &str = "binary-delta";
+const ELEMENT_DELTA: &str = "element-delta";
+
+/// Bytes a patched response may grow to when the server announces no
+/// smaller `X-Original-Size`
+pub const DEFAULT_MAX_OUTPUT_SIZE: usize = 64 * 1024 * 1024;
+
+/// Reasons a response cannot be turned into content
+///
+/// Every variant means the held base is unusable; drop it and refetch in full.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// Server sent a diff but no base is held
+    #[error("received diff without a base")]
+    MissingBase,
+
+    /// Server sent a diff format this client cannot apply
+    #[error("unsupported diff format: {0}")]
+    UnsupportedFormat(String),
+
+    /// Diff was computed against a different base than the one held
+    #[error("diff is against {expected}, client holds {held}")]
+    BaseMismatch {
+        /// Base named by the server
+        expected: String,
+        /// Base held by the client
+        held: String,
+    },
+
+    /// Patch could not be applied
+    #[error(transparent)]
+    Patch(#[from] DiffError),
+
+    /// Patched content does not hash to the announced digest or version
+    #[error("patched content does not match version {0}")]
+    VersionMismatch(String),
+}
+
+impl ClientError {
+    /// Stable error code, as used by conformance vectors
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::MissingBase => "missing-base",
+            ClientError::UnsupportedFormat(_) => "unsupported-format",
+            ClientError::BaseMismatch { .. } => "base-mismatch",
+            ClientError::Patch(DiffError::InvalidFormat(_)) => "invalid-diff",
+            ClientError::Patch(_) => "patch-failed",
+            ClientError::VersionMismatch(_) => "version-mismatch",
+        }
+    }
+}
+
+/// BPX metadata of a received response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResponseMeta<'a> {
+    /// Session ID assigned by the server
+    pub session: Option<&'a str>,
+    /// `resumed` or `created`
+    pub session_status: Option<&'a str>,
+    /// Version of the content after this response
+    pub version: Option<&'a str>,
+    /// `full` or the diff format of the body
+    pub diff_type: Option<&'a str>,
+    /// Base the diff was computed against
+    pub delta_base: Option<&'a str>,
+    /// Media type of a full body
+    pub content_type: Option<&'a str>,
+    /// Media type of the patched resource on diff bodies
+    pub original_content_type: Option<&'a str>,
+    /// Size of the full content, as announced in `X-Original-Size`
+    pub original_size: Option<&'a str>,
+    /// Hash of the patched content when the version isn't one
+    pub content_digest: Option<&'a str>,
+}
+
+impl<'a> ResponseMeta<'a> {
+    /// Read metadata through a header lookup (case-insensitive lookups expected)
+    pub fn from_headers(get: impl Fn(&str) -> Option<&'a str>) -> Self {
+        Self {
+            session: get(BpxHeaders::SESSION),
+            session_status: get(BpxHeaders::SESSION_STATUS),
+            version: get(BpxHeaders::RESOURCE_VERSION),
+            diff_type: get(BpxHeaders::DIFF_TYPE),
+            delta_base: get(BpxHeaders::DELTA_BASE),
+            content_type: get(CONTENT_TYPE),
+            original_content_type: get(BpxHeaders::ORIGINAL_CONTENT_TYPE),
+            original_size: get(BpxHeaders::ORIGINAL_SIZE),
+            content_digest: get(BpxHeaders::CONTENT_DIGEST),
+        }
+    }
+
+    /// Bytes patching may produce: the announced original size, capped at
+    /// `max`
+    pub fn output_limit(&self, max: usize) -> usize {
+        self.original_size
+            .and_then(|size| size.trim().parse::<usize>().ok())
+            .map_or(max, |size| size.min(max))
+    }
+
+    /// Whether the server issued a new session, so bases held under any
+    /// previous one are stale
+    pub fn session_created(&self) -> bool {
+        self.session_status == Some("created")
+    }
+
+    /// Whether the body is a diff rather than full content
+    pub fn is_diff(&self) -> bool {
+        self.diff_type.is_some_and(|t| t != "full")
+    }
+}
+
+/// Content reconstructed from a response
+#[derive(Debug, Clone)]
+pub struct Reconstructed {
+    /// Full content
+    pub content: Bytes,
+    /// Version of the content
+    pub version: String,
+    /// Media type of the content, if the server sent one
+    pub content_type: Option<String>,
+    /// Whether the content was patched from a diff
+    pub diff_applied: bool,
+}
+
+/// Reconstruct full content from a response body
+///
+/// `base` is the `(version, content)` pair the request was made with and
+/// `apply` patches it with a binary delta; pass
+/// [`crate::patch::BinaryDiffCodec::apply_diff_with_limits`] capped at
+/// [`ResponseMeta::output_limit`] unless a custom engine is in use. Element diffs of collections are applied by
+/// [`ElementDiffCodec::apply_diff`].
+pub fn reconstruct(
+    meta: &ResponseMeta<'_>,
+    base: Option<(&str, &[u8])>,
+    body: Bytes,
+    apply: impl FnOnce(&[u8], &[u8]) -> Result<Bytes, DiffError>,
+) -> Result<Reconstructed, ClientError> {
+    if !meta.is_diff() {
+        return Ok(Reconstructed {
+            version: meta
+                .version
+                .map(str::to_string)
+                .unwrap_or_else(|| version_of(&body)),
+            content: body,
+            content_type: meta.content_type.map(str::to_string),
+            diff_applied: false,
+        });
+    }
+
+    let (base_version, base_content) = base.ok_or(ClientError::MissingBase)?;
+    let diff_type = meta.diff_type.unwrap_or_default();
+    if diff_type != BINARY_DELTA && diff_type != ELEMENT_DELTA {
+        return Err(ClientError::UnsupportedFormat(diff_type.to_string()));
+    }
+    if let Some(delta_base) = meta.delta_base
+        && delta_base != base_version
+    {
+        return Err(ClientError::BaseMismatch {
+            expected: delta_base.to_string(),
+            held: base_version.to_string(),
+        });
+    }
+
+    let content = match diff_type {
+        ELEMENT_DELTA => ElementDiffCodec::apply_diff(base_content, &body)?,
+        _ => apply(base_content, &body)?,
+    };
+    // Engines that can't check while applying are at least caught here
+    let limit = meta.output_limit(usize::MAX);
+    if content.len() > limit {
+        return Err(DiffError::OutputLimitExceeded(limit).into());
+    }
+    let actual = version_of(&content);
+    // A patched body must hash to the digest the server announced, or to
+    // the version when that is the hash
+    if let Some(digest) = meta.content_digest.or(meta.version)
+        && digest != actual
+    {
+        return Err(ClientError::VersionMismatch(digest.to_string()));
+    }
+
+    Ok(Reconstructed {
+        version: meta.version.map_or(actual, str::to_string),
+        content,
+        content_type: meta.original_content_type.map(str::to_string),
+        diff_applied: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::{BinaryDiffCodec, DiffOperation};
+
+    fn diff_meta<'a>(version: &'a str, base: &'a str) -> ResponseMeta<'a> {
+        ResponseMeta {
+            version: Some(version),
+            diff_type: Some("binary-delta"),
+            delta_base: Some(base),
+            original_content_type: Some("text/plain"),
+            ..ResponseMeta::default()
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_diff() {
+        let base = b"hello";
+        let new = b"hello world";
+        let diff = BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 5,
+            },
+            DiffOperation::Insert(b" world".to_vec()),
+        ])
+        .unwrap();
+        let (base_version, new_version) = (version_of(base), version_of(new));
+
+        let result = reconstruct(
+            &diff_meta(&new_version, &base_version),
+            Some((&base_version, base)),
+            diff.clone(),
+            BinaryDiffCodec::apply_diff,
+        )
+        .unwrap();
+        assert_eq!(result.content.as_ref(), new);
+        assert!(result.diff_applied);
+        assert_eq!(result.content_type.as_deref(), Some("text/plain"));
+
+        // Wrong base
+        assert!(matches!(
+            reconstruct(
+                &diff_meta(&new_version, "v:other"),
+                Some((&base_version, base)),
+                diff.clone(),
+                BinaryDiffCodec::apply_diff,
+            ),
+            Err(ClientError::BaseMismatch { .. })
+        ));
+
+        // No base at all
+        assert!(matches!(
+            reconstruct(
+                &diff_meta(&new_version, &base_version),
+                None,
+                diff,
+                BinaryDiffCodec::apply_diff,
+            ),
+            Err(ClientError::MissingBase)
+        ));
+    }
+
+    #[test]
+    fn test_reconstruct_checks_digest() {
+        let base = b"hello";
+        let diff = BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 5,
+            },
+            DiffOperation::Insert(b" world".to_vec()),
+        ])
+        .unwrap();
+        let digest = version_of(b"hello world");
+        let meta = |digest| ResponseMeta {
+            content_digest: Some(digest),
+            ..diff_meta("rev-2", "rev-1")
+        };
+
+        // Versions the application chose are kept, the patch checked by digest
+        let result = reconstruct(
+            &meta(&digest),
+            Some(("rev-1", base)),
+            diff.clone(),
+            BinaryDiffCodec::apply_diff,
+        )
+        .unwrap();
+        assert_eq!(result.content.as_ref(), b"hello world");
+        assert_eq!(result.version, "rev-2");
+
+        assert!(matches!(
+            reconstruct(
+                &meta("v:0"),
+                Some(("rev-1", base)),
+                diff,
+                BinaryDiffCodec::apply_diff,
+            ),
+            Err(ClientError::VersionMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_reconstruct_element_diff() {
+        use crate::collection::{Element, ElementDiffCodec};
+
+        let base = ElementDiffCodec::encode_collection(&[Element::new("1", "a")]).unwrap();
+        let new = ElementDiffCodec::encode_collection(&[Element::new("1", "b")]).unwrap();
+        let diff = ElementDiffCodec::compute_diff(&base, &new).unwrap();
+        let (base_version, new_version) = (version_of(&base), version_of(&new));
+        let meta = ResponseMeta {
+            diff_type: Some("element-delta"),
+            ..diff_meta(&new_version, &base_version)
+        };
+
+        let result = reconstruct(
+            &meta,
+            Some((&base_version, &base)),
+            diff,
+            BinaryDiffCodec::apply_diff,
+        )
+        .unwrap();
+        assert_eq!(result.content, new);
+    }
+
+    #[test]
+    fn test_reconstruct_full() {
+        let meta = ResponseMeta {
+            diff_type: Some("full"),
+            content_type: Some("application/json"),
+            ..ResponseMeta::default()
+        };
+        let result = reconstruct(
+            &meta,
+            None,
+            Bytes::from_static(b"{}"),
+            BinaryDiffCodec::apply_diff,
+        )
+        .unwrap();
+        assert!(!result.diff_applied);
+        assert_eq!(result.version, version_of(b"{}"));
+    }
+}
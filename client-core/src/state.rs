@@ -0,0 +1,216 @@
+//! Session and per-path base bookkeeping
+
+use crate::{
+    headers::BpxHeaders,
+    patch::{BinaryDiffCodec, PatchLimits},
+    response::{ClientError, DEFAULT_MAX_OUTPUT_SIZE, Reconstructed, ResponseMeta, reconstruct},
+};
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Base content held for one path
+#[derive(Debug, Clone)]
+pub struct CachedBase {
+    /// Version the content corresponds to
+    pub version: String,
+    /// Full content
+    pub content: Bytes,
+    /// Media type of the content, if the server sent one
+    pub content_type: Option<String>,
+}
+
+/// Client-side BPX state for transport-agnostic front-ends
+///
+/// ```
+/// use bpx_client_core::ClientState;
+///
+/// let mut state = ClientState::new();
+/// // First request carries no BPX headers; send it with any HTTP client
+/// assert!(state.request_headers("/api/feed").is_empty());
+///
+/// let headers = [("x-bpx-session", "sess_1"), ("x-diff-type", "full")];
+/// let lookup = |name: &str| {
+///     headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| *v)
+/// };
+/// let result = state.apply_response("/api/feed", lookup, b"hello".to_vec().into()).unwrap();
+/// assert_eq!(result.content.as_ref(), b"hello");
+/// assert_eq!(state.request_headers("/api/feed").len(), 3);
+/// ```
+#[derive(Debug)]
+pub struct ClientState {
+    session: Option<String>,
+    bases: HashMap<String, CachedBase>,
+    max_output_size: usize,
+}
+
+impl Default for ClientState {
+    fn default() -> Self {
+        Self {
+            session: None,
+            bases: HashMap::new(),
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+        }
+    }
+}
+
+impl ClientState {
+    /// Create empty state
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuse diffs patching to more than `max_output_size` bytes, even if
+    /// the server announces more (default [`DEFAULT_MAX_OUTPUT_SIZE`])
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    /// Session ID assigned by the server
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    /// Base held for a path
+    pub fn base(&self, path: &str) -> Option<&CachedBase> {
+        self.bases.get(path)
+    }
+
+    /// Drop the base for a path so the next request fetches it in full
+    pub fn forget(&mut self, path: &str) {
+        self.bases.remove(path);
+    }
+
+    /// Headers to send with the next request for a path
+    pub fn request_headers(&self, path: &str) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(session) = &self.session {
+            headers.push((BpxHeaders::SESSION, session.clone()));
+        }
+        if let Some(base) = self.bases.get(path) {
+            headers.push((BpxHeaders::BASE_VERSION, base.version.clone()));
+            headers.push((BpxHeaders::ACCEPT_DIFF, "binary-delta".to_string()));
+        }
+        headers
+    }
+
+    /// Apply a response for a path and remember the result as its next base
+    ///
+    /// On error the base is dropped, so repeating the request fetches in full.
+    pub fn apply_response<'h>(
+        &mut self,
+        path: &str,
+        get_header: impl Fn(&str) -> Option<&'h str>,
+        body: Bytes,
+    ) -> Result<Reconstructed, ClientError> {
+        let meta = ResponseMeta::from_headers(get_header);
+        if let Some(session) = meta.session {
+            if meta.session_created() || self.session.as_deref().is_some_and(|s| s != session) {
+                // Bases were recorded under a session the server no longer knows
+                self.bases.clear();
+            }
+            self.session = Some(session.to_string());
+        }
+
+        let base = self
+            .bases
+            .get(path)
+            .map(|b| (b.version.as_str(), b.content.as_ref()));
+        let limits = PatchLimits::output(meta.output_limit(self.max_output_size));
+        let apply =
+            |base: &[u8], diff: &[u8]| BinaryDiffCodec::apply_diff_with_limits(base, diff, &limits);
+        match reconstruct(&meta, base, body, apply) {
+            Ok(result) => {
+                self.bases.insert(
+                    path.to_string(),
+                    CachedBase {
+                        version: result.version.clone(),
+                        content: result.content.clone(),
+                        content_type: result.content_type.clone(),
+                    },
+                );
+                Ok(result)
+            }
+            Err(e) => {
+                self.forget(path);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(state: &mut ClientState, path: &str, headers: &[(&str, &str)], body: &'static [u8]) {
+        let lookup = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| *v)
+        };
+        state
+            .apply_response(path, lookup, Bytes::from_static(body))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_new_session_drops_bases() {
+        let mut state = ClientState::new();
+        let full = [("X-BPX-Session", "sess_1"), ("X-Diff-Type", "full")];
+        apply(&mut state, "/a", &full, b"a");
+        apply(&mut state, "/b", &full, b"b");
+
+        // The server reissued the same ID after forgetting it
+        let created = [
+            ("X-BPX-Session", "sess_1"),
+            ("X-BPX-Session-Status", "created"),
+            ("X-Diff-Type", "full"),
+        ];
+        apply(&mut state, "/a", &created, b"a2");
+        assert_eq!(state.base("/a").unwrap().content.as_ref(), b"a2");
+        assert!(state.base("/b").is_none());
+
+        // A different session ID means the same
+        let replaced = [("X-BPX-Session", "sess_2"), ("X-Diff-Type", "full")];
+        apply(&mut state, "/b", &replaced, b"b2");
+        assert!(state.base("/a").is_none());
+        assert_eq!(state.session(), Some("sess_2"));
+    }
+
+    #[test]
+    fn test_output_limit_stops_inflating_diffs() {
+        use crate::{DiffError, patch::DiffOperation};
+
+        let base = b"0123456789";
+        // A few wire bytes inserting far more than the announced size
+        let bomb =
+            BinaryDiffCodec::encode_diff(&[DiffOperation::Insert(vec![b'x'; 4096])]).unwrap();
+        let lookup = |size: &'static str| {
+            move |name: &str| match name {
+                BpxHeaders::DIFF_TYPE => Some("binary-delta"),
+                BpxHeaders::ORIGINAL_SIZE => Some(size),
+                _ => None,
+            }
+        };
+
+        let mut state = ClientState::new();
+        apply(&mut state, "/a", &[("X-Diff-Type", "full")], base);
+        let result = state.apply_response("/a", lookup("10"), bomb.clone());
+        assert!(matches!(
+            result,
+            Err(ClientError::Patch(DiffError::OutputLimitExceeded(10)))
+        ));
+        assert!(state.base("/a").is_none());
+
+        // Without an announced size the configured cap applies
+        let mut state = ClientState::new().with_max_output_size(100);
+        apply(&mut state, "/a", &[("X-Diff-Type", "full")], base);
+        let result = state.apply_response("/a", lookup(""), bomb);
+        assert!(matches!(
+            result,
+            Err(ClientError::Patch(DiffError::OutputLimitExceeded(100)))
+        ));
+    }
+}
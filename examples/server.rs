@@ -1,14 +1,12 @@
 //! Demo BPX server
 
-use bpx::protocol::headers::BpxHeaders;
 use bpx::{
-    BpxConfig, BpxServer, ResourcePath, diff::similar::SimilarDiffEngine,
+    BpxConfig, BpxServer, CorsConfig, ResourcePath, diff::similar::SimilarDiffEngine,
     server::InMemoryResourceStore, state::InMemoryStateManager,
 };
 use bytes::Bytes;
 use http_body_util::Full;
-use hyper::{Method, Request, Response, server::conn::http1, service::service_fn};
-use hyper_util::rt::TokioIo;
+use hyper::{Method, Request, Response};
 use std::{convert::Infallible, sync::Arc, time::Duration};
 use tokio::time;
 
@@ -23,6 +21,10 @@ fn setup_demo_resources(store: &InMemoryResourceStore) {
         ResourcePath::new("/api/logs/server".to_string()),
         Bytes::from(log_stream),
     );
+    store.set_content_type(
+        ResourcePath::new("/api/logs/server".to_string()),
+        "text/plain; charset=utf-8",
+    );
 
     // Live metrics dashboard
     let metrics_dashboard = format!(r#"{{
@@ -57,6 +59,10 @@ fn setup_demo_resources(store: &InMemoryResourceStore) {
         ResourcePath::new("/api/dashboard/metrics".to_string()),
         Bytes::from(metrics_dashboard),
     );
+    store.set_content_type(
+        ResourcePath::new("/api/dashboard/metrics".to_string()),
+        "application/json",
+    );
 
     // Simple collaborative document for testing
     let collaborative_doc = format!(
@@ -68,6 +74,10 @@ fn setup_demo_resources(store: &InMemoryResourceStore) {
         ResourcePath::new("/api/documents/collaborative".to_string()),
         Bytes::from(collaborative_doc),
     );
+    store.set_content_type(
+        ResourcePath::new("/api/documents/collaborative".to_string()),
+        "application/json",
+    );
 
     println!("Demo resources initialized:");
     println!("  - /api/logs/server (~15KB log stream, perfect for append-only diffs)");
@@ -79,22 +89,55 @@ async fn handle_request(
     req: Request<hyper::body::Incoming>,
     bpx_server: Arc<BpxServer>,
     resource_store: Arc<InMemoryResourceStore>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let Some(cors) = bpx_server.config().cors.clone() else {
+        return route(req, bpx_server, resource_store).await;
+    };
+    if CorsConfig::is_preflight(&req) {
+        return Ok(cors.preflight(&req).map(Full::new));
+    }
+    let origin = req.headers().get(hyper::header::ORIGIN).cloned();
+    let mut response = route(req, bpx_server, resource_store).await?;
+    cors.apply(origin.as_ref(), response.headers_mut());
+    Ok(response)
+}
+
+#[allow(clippy::unnecessary_cast)]
+async fn route(
+    req: Request<hyper::body::Incoming>,
+    bpx_server: Arc<BpxServer>,
+    resource_store: Arc<InMemoryResourceStore>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
     let method = req.method();
     let uri = req.uri().clone();
 
-    if method == Method::OPTIONS {
-        let response = Response::builder()
-            .status(200)
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
-            .header(
-                "Access-Control-Allow-Headers",
-                "Content-Type, X-BPX-Session, X-Base-Version, Accept-Diff",
-            )
-            .header("Access-Control-Max-Age", "3600")
-            .body(Full::new(Bytes::new()))
-            .unwrap_or_else(|_| Response::new(Full::new(Bytes::new())));
+    // Batch exchange: many resources in one round trip
+    if method == Method::POST && uri.path() == "/batch" {
+        let response = match bpx_server
+            .handle_batch_request(req, Arc::clone(&resource_store))
+            .await
+        {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                Response::from_parts(parts, Full::new(body))
+            }
+            Err(err) => Response::from(err).map(Full::new),
+        };
+        return Ok(response);
+    }
+
+    // Uploads: full content or a diff against the current version
+    if method == Method::PUT || method == Method::PATCH {
+        let response = match bpx_server
+            .handle_write_request(req, Arc::clone(&resource_store))
+            .await
+        {
+            Ok(response) => {
+                let (parts, body) = response.into_parts();
+                Response::from_parts(parts, Full::new(body))
+            }
+            Err(err) => Response::from(err).map(Full::new),
+        };
         return Ok(response);
     }
 
@@ -102,7 +145,6 @@ async fn handle_request(
         let response = Response::builder()
             .status(405)
             .header("Content-Type", "text/plain")
-            .header("Access-Control-Allow-Origin", "*")
             .body(Full::new(Bytes::from("Method not allowed")))
             .unwrap_or_else(|_| Response::new(Full::new(Bytes::from("Error"))));
         return Ok(response);
@@ -110,34 +152,11 @@ async fn handle_request(
 
     // Special endpoints for testing
     match uri.path() {
-        "/health" => {
-            let response = Response::builder()
-                .status(200)
-                .header("Content-Type", "application/json")
-                .header("Access-Control-Allow-Origin", "*")
-                .body(Full::new(Bytes::from(
-                    r#"{"status":"healthy","protocol":"BPX/1.0"}"#,
-                )))
-                .unwrap_or_else(|_| Response::new(Full::new(Bytes::new())));
-            return Ok(response);
-        }
+        "/health" => return Ok(bpx_server.health_response().map(Full::new)),
         "/stats" => {
-            let config = bpx_server.config();
-            let stats = format!(
-                r#"{{"resources":{},"versions":{},"config":{{"max_sessions":{},"session_ttl":{},"max_diff_size":{}}}}}"#,
-                resource_store.resource_count(),
-                resource_store.version_count(),
-                config.max_sessions,
-                config.session_ttl.as_secs(),
-                config.max_diff_size
-            );
-            let response = Response::builder()
-                .status(200)
-                .header("Content-Type", "application/json")
-                .header("Access-Control-Allow-Origin", "*")
-                .body(Full::new(Bytes::from(stats)))
-                .unwrap_or_else(|_| Response::new(Full::new(Bytes::new())));
-            return Ok(response);
+            return Ok(bpx_server
+                .stats_response(resource_store.as_ref())
+                .map(Full::new));
         }
         "/demo/update" => {
             // Incremental updates for BPX demonstration
@@ -246,7 +265,6 @@ async fn handle_request(
             let response = Response::builder()
                 .status(200)
                 .header("Content-Type", "application/json")
-                .header("Access-Control-Allow-Origin", "*")
                 .body(Full::new(Bytes::from(r#"{"message":"Incremental updates applied","updated":["logs","metrics","document"],"optimized_for":"BPX differential sync"}"#)))
                 .unwrap_or_else(|_| Response::new(Full::new(Bytes::new())));
             return Ok(response);
@@ -259,45 +277,23 @@ async fn handle_request(
         .handle_request(req, Arc::clone(&resource_store))
         .await
     {
-        Ok(response) => {
-            // Convert Bytes to Full<Bytes> and add CORS headers
-            let (parts, body) = response.into_parts();
-            let mut response = Response::from_parts(parts, Full::new(body));
-
-            // Add CORS headers
-            response.headers_mut().insert(
-                hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
-                "*".parse().unwrap(),
-            );
-            let expose = BpxHeaders::all().join(",");
-            response.headers_mut().insert(
-                hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS,
-                expose.parse().unwrap(),
-            );
-
-            Ok(response)
-        }
+        Ok(response) => Ok(response.map(Full::new)),
         Err(err) => {
             eprintln!("BPX error for {}: {}", uri.path(), err);
-            let response = Response::builder()
-                .status(500)
-                .header("Content-Type", "text/plain")
-                .header("Access-Control-Allow-Origin", "*")
-                .body(Full::new(Bytes::from(format!("BPX Error: {}", err))))
-                .unwrap_or_else(|_| Response::new(Full::new(Bytes::from("Internal Server Error"))));
-            Ok(response)
+            Ok(Response::from(err).map(Full::new))
         }
     }
 }
 
 /// Cleanup task that runs periodically
-async fn cleanup_task(bpx_server: Arc<BpxServer>) {
+async fn cleanup_task(bpx_server: Arc<BpxServer>, resource_store: Arc<InMemoryResourceStore>) {
     let interval_secs = bpx_server.config().cleanup_interval.as_secs().max(1);
     let mut interval = time::interval(Duration::from_secs(interval_secs));
 
     loop {
         interval.tick().await;
         bpx_server.cleanup_expired_sessions().await;
+        resource_store.compact();
     }
 }
 
@@ -305,7 +301,7 @@ async fn cleanup_task(bpx_server: Arc<BpxServer>) {
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("Starting BPX Server...");
 
-    // Create configuration
+    // Create configuration; BPX_* environment variables override it
     let config = BpxConfig {
         max_sessions: 10_000,
         max_resources_per_session: 100,
@@ -313,12 +309,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         max_diff_size: 5 * 1024 * 1024,            // 5MB
         min_compression_ratio: 0.1,                // 10% savings required
         cleanup_interval: Duration::from_secs(60),
-    };
+        // The browser demo may be opened from anywhere
+        cors: Some(CorsConfig::default()),
+        ..BpxConfig::default()
+    }
+    .merge_env()?;
 
     let state_manager = Arc::new(InMemoryStateManager::new(config.clone()));
-    let diff_engine = Arc::new(SimilarDiffEngine::with_compression_ratio(
-        config.min_compression_ratio,
-    ));
+    let diff_engine = Arc::new(SimilarDiffEngine::new());
     let resource_store = Arc::new(InMemoryResourceStore::new());
 
     setup_demo_resources(&resource_store);
@@ -331,18 +329,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .build()?,
     );
 
+    let drain = bpx_server.config().shutdown_timeout;
     println!("BPX Server components initialized");
 
     let cleanup_server = Arc::clone(&bpx_server);
+    let cleanup_store = Arc::clone(&resource_store);
     tokio::spawn(async move {
-        cleanup_task(cleanup_server).await;
+        cleanup_task(cleanup_server, cleanup_store).await;
     });
 
     let service = {
         let bpx_server = Arc::clone(&bpx_server);
         let resource_store = Arc::clone(&resource_store);
 
-        service_fn(move |req| {
+        tower::service_fn(move |req| {
             handle_request(req, Arc::clone(&bpx_server), Arc::clone(&resource_store))
         })
     };
@@ -354,6 +354,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     println!("  /health                   - Server health check");
     println!("  /stats                    - Server statistics");
     println!("  /demo/update              - Apply incremental updates");
+    println!("  POST /batch               - Batch exchange (application/vnd.bpx.batch)");
     println!("  /api/logs/server          - Append-only log stream (great for BPX)");
     println!("  /api/dashboard/metrics    - Live metrics (line-based demo)");
     println!("  /api/documents/collaborative - Collaborative doc (single-line JSON)");
@@ -377,16 +378,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     );
     println!();
 
-    loop {
-        let (stream, _addr) = listener.accept().await?;
-
-        let io = TokioIo::new(stream);
-        let service = service.clone();
-
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                eprintln!("Error serving connection: {:?}", err);
-            }
-        });
-    }
+    // HTTP/2 for clients with prior knowledge (curl --http2-prior-knowledge), HTTP/1.1 otherwise
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+        println!("Shutting down, finishing open requests...");
+    };
+    bpx::serve::serve_connections(listener, service, shutdown, drain).await?;
+    Ok(())
 }
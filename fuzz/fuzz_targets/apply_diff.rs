@@ -0,0 +1,30 @@
+//! Applying any diff to any base fails cleanly
+//!
+//! Input: base length as a 4-byte big-endian integer, the base, then the
+//! diff.
+
+#![no_main]
+
+use bpx_client_core::patch::{BinaryDiffCodec, PatchLimits};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((len, rest)) = data.split_first_chunk::<4>() else {
+        return;
+    };
+    let (base, diff) = rest.split_at((u32::from_be_bytes(*len) as usize).min(rest.len()));
+    let patched = BinaryDiffCodec::apply_diff(base, diff);
+    if let Ok(patched) = &patched {
+        // An output limit the result fits in changes nothing
+        let limits = PatchLimits {
+            max_output_size: patched.len(),
+            ..PatchLimits::UNLIMITED
+        };
+        assert_eq!(
+            BinaryDiffCodec::apply_diff_with_limits(base, diff, &limits)
+                .ok()
+                .as_ref(),
+            Some(patched)
+        );
+    }
+});
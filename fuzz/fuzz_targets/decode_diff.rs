@@ -0,0 +1,14 @@
+//! Decoding any bytes as a `binary-delta` diff fails cleanly, and whatever
+//! decodes encodes back to the same operations
+
+#![no_main]
+
+use bpx_client_core::patch::BinaryDiffCodec;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(operations) = BinaryDiffCodec::decode_diff(data) {
+        let encoded = BinaryDiffCodec::encode_diff(&operations).unwrap();
+        assert_eq!(BinaryDiffCodec::decode_diff(&encoded).unwrap(), operations);
+    }
+});
@@ -0,0 +1,36 @@
+//! Parsing any BPX request headers fails cleanly
+//!
+//! Input: the session, base version, `Accept-Diff` and `Cookie` header
+//! values, separated by newlines; values that aren't valid header values
+//! are left out.
+
+#![no_main]
+
+use bpx::{BpxConfig, protocol::headers::BpxHeaders, server::parse_bpx_request};
+use http::{HeaderValue, Request, header};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let config = BpxConfig {
+        session_cookie: Some("bpx".to_string()),
+        ..BpxConfig::default()
+    };
+    let names = [
+        BpxHeaders::SESSION,
+        BpxHeaders::BASE_VERSION,
+        BpxHeaders::ACCEPT_DIFF,
+        header::COOKIE.as_str(),
+    ];
+    let mut req = Request::get("/resource");
+    for (name, value) in names.into_iter().zip(data.split(|&b| b == b'\n')) {
+        if let Ok(value) = HeaderValue::from_bytes(value) {
+            req = req.header(name, value);
+        }
+    }
+    let Ok(req) = req.body(()) else {
+        return;
+    };
+    if let Ok(parsed) = parse_bpx_request(&req, &config) {
+        assert_eq!(parsed.base_version.as_ref(), parsed.base_versions.first());
+    }
+});
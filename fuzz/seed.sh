@@ -0,0 +1,38 @@
+#!/bin/sh
+# Rebuild the seed corpus from the conformance vectors the test suite checks
+set -eu
+
+cd "$(dirname "$0")"
+vectors=../client-core/vectors
+rm -rf corpus
+mkdir -p corpus/decode_diff corpus/apply_diff corpus/parse_bpx_request
+
+# Header value of `name` in a vector's headers file, or nothing
+value() {
+    grep -i "^$1:" "$2" | cut -d' ' -f2- || true
+}
+
+for dir in "$vectors"/*/; do
+    name=$(basename "$dir")
+    if grep -qi '^x-diff-type: binary-delta' "$dir/response.headers"; then
+        cp "$dir/response.body" "corpus/decode_diff/$name"
+        base="$dir/base"
+        [ -f "$base" ] || base=/dev/null
+        {
+            len=$(wc -c < "$base")
+            printf "$(printf '\\%03o' $((len >> 24 & 255)) $((len >> 16 & 255)) \
+                $((len >> 8 & 255)) $((len & 255)))"
+            cat "$base" "$dir/response.body"
+        } > "corpus/apply_diff/$name"
+    fi
+done
+
+for dir in "$vectors"/*/; do
+    headers="$dir/request.headers"
+    printf '%s\n%s\n%s\n%s' \
+        "$(value X-BPX-Session "$headers")" \
+        "$(value X-Base-Version "$headers")" \
+        "$(value Accept-Diff "$headers")" \
+        "$(value Cookie "$headers")" \
+        > "corpus/parse_bpx_request/$(basename "$dir")"
+done
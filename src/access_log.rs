@@ -0,0 +1,269 @@
+//! One record per request, for deployments without a tracing backend
+//!
+//! A [`BpxServer`](crate::BpxServer) built with
+//! [`BpxServerBuilder::access_log`](crate::BpxServerBuilder::access_log)
+//! hands an [`AccessLogEntry`] to an [`AccessLog`] once each request through
+//! [`handle_request`](crate::BpxServer::handle_request),
+//! [`handle_request_streaming`](crate::BpxServer::handle_request_streaming),
+//! [`handle_write_request`](crate::BpxServer::handle_write_request) or
+//! [`handle_batch_request`](crate::BpxServer::handle_batch_request) is
+//! answered, errors included. [`JsonLinesLog`] writes them as JSON lines.
+
+use crate::{
+    BpxError, DiffDecision, ResourcePath,
+    protocol::headers::BpxHeaders,
+    server::{error_status, escape_json},
+};
+use hyper::{Method, Request, Response, StatusCode};
+use std::{
+    fmt::Write as _,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// What was asked, what was answered, and how long it took
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// When the request arrived
+    pub at: SystemTime,
+    /// Request method
+    pub method: Method,
+    /// Requested path, without its query
+    pub path: ResourcePath,
+    /// Status answered
+    pub status: StatusCode,
+    /// Session the response names
+    pub session: Option<String>,
+    /// Versions, format, sizes and fallback reason, for resource responses
+    pub decision: Option<DiffDecision>,
+    /// Code of the error answered (see [`BpxError::code`])
+    pub error: Option<&'static str>,
+    /// Body bytes sent, if known before streaming
+    pub bytes_sent: Option<u64>,
+    /// Time taken to answer, not counting streaming the body
+    pub latency: Duration,
+}
+
+impl AccessLogEntry {
+    /// `diff`, `full`, `not_modified` or `error`
+    pub fn outcome(&self) -> &'static str {
+        if self.error.is_some() {
+            "error"
+        } else if self.status == StatusCode::NOT_MODIFIED {
+            "not_modified"
+        } else if self.decision.as_ref().is_some_and(DiffDecision::is_diff) {
+            "diff"
+        } else {
+            "full"
+        }
+    }
+
+    /// Render as a single-line JSON object
+    pub fn to_json(&self) -> String {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis())
+            .unwrap_or_default();
+        let string = |value: Option<String>| match value {
+            Some(value) => format!("\"{}\"", escape_json(&value)),
+            None => "null".to_string(),
+        };
+        let number = |value: Option<u64>| value.map_or("null".to_string(), |n| n.to_string());
+        let decision = self.decision.as_ref();
+        let mut json = format!(
+            r#"{{"ts":{},"method":"{}","path":"{}","status":{},"session":{},"outcome":"{}""#,
+            at,
+            escape_json(self.method.as_str()),
+            escape_json(&self.path.to_string()),
+            self.status.as_u16(),
+            string(self.session.clone()),
+            self.outcome(),
+        );
+        let _ = write!(
+            json,
+            r#","base":{},"version":{},"format":{},"reason":{},"error":{}"#,
+            string(
+                decision
+                    .and_then(|d| d.base.as_ref())
+                    .map(ToString::to_string)
+            ),
+            string(decision.map(|d| d.current.to_string())),
+            string(
+                decision
+                    .and_then(|d| d.chosen_format)
+                    .map(|f| f.as_str().to_string())
+            ),
+            string(
+                decision
+                    .and_then(|d| d.reason)
+                    .map(|r| r.as_str().to_string())
+            ),
+            string(self.error.map(str::to_string)),
+        );
+        let _ = write!(
+            json,
+            r#","bytes":{},"full_bytes":{},"latency_ms":{:.3}}}"#,
+            number(self.bytes_sent),
+            number(decision.and_then(|d| d.full_size).map(|size| size as u64)),
+            self.latency.as_secs_f64() * 1000.0,
+        );
+        json
+    }
+}
+
+/// Receives an entry for each request answered
+///
+/// Called on the request path, so implementations should buffer or hand
+/// entries off rather than block for long.
+pub trait AccessLog: Send + Sync {
+    /// `entry` was answered
+    fn log(&self, entry: &AccessLogEntry);
+}
+
+impl<F> AccessLog for F
+where
+    F: Fn(&AccessLogEntry) + Send + Sync,
+{
+    fn log(&self, entry: &AccessLogEntry) {
+        self(entry)
+    }
+}
+
+/// [`AccessLog`] writing each entry as a line of JSON
+///
+/// Write errors are ignored, so a full disk never fails requests; wrap files
+/// in a [`BufWriter`](std::io::BufWriter) to avoid a write per request.
+#[derive(Debug)]
+pub struct JsonLinesLog<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLinesLog<W> {
+    /// Write entries to `writer`
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// The writer entries went to
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write + Send> AccessLog for JsonLinesLog<W> {
+    fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.to_json();
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writer.write_all(line.as_bytes());
+    }
+}
+
+/// A request as it arrived, to be logged once answered
+pub(crate) struct Arrival {
+    at: SystemTime,
+    started: Instant,
+    method: Method,
+    path: ResourcePath,
+}
+
+impl Arrival {
+    pub(crate) fn of<B>(req: &Request<B>) -> Self {
+        Self {
+            at: SystemTime::now(),
+            started: Instant::now(),
+            method: req.method().clone(),
+            path: ResourcePath::new(req.uri().path().to_string()),
+        }
+    }
+
+    /// Entry for the request answered with `result`, whose body is
+    /// `bytes_sent` bytes if known
+    pub(crate) fn answered<B>(
+        self,
+        result: &Result<Response<B>, BpxError>,
+        bytes_sent: Option<u64>,
+    ) -> AccessLogEntry {
+        let mut entry = AccessLogEntry {
+            at: self.at,
+            method: self.method,
+            path: self.path,
+            status: StatusCode::OK,
+            session: None,
+            decision: None,
+            error: None,
+            bytes_sent,
+            latency: self.started.elapsed(),
+        };
+        match result {
+            Ok(response) => {
+                entry.status = response.status();
+                entry.session = response
+                    .headers()
+                    .get(BpxHeaders::SESSION)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                entry.decision = response.extensions().get::<DiffDecision>().cloned();
+            }
+            Err(err) => {
+                entry.status = error_status(err);
+                entry.error = Some(err.code());
+            }
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DiffFormat, FallbackReason, Version};
+
+    #[test]
+    fn test_json_lines() {
+        let log = JsonLinesLog::new(Vec::new());
+        let mut entry = AccessLogEntry {
+            at: UNIX_EPOCH + Duration::from_millis(1500),
+            method: Method::GET,
+            path: ResourcePath::new("/api/\"feed\"".to_string()),
+            status: StatusCode::OK,
+            session: Some("s1".to_string()),
+            decision: Some(DiffDecision {
+                path: ResourcePath::new("/api/\"feed\"".to_string()),
+                base: Some(Version::new("v1".to_string())),
+                current: Version::new("v2".to_string()),
+                chosen_format: Some(DiffFormat::BinaryDelta),
+                diff_size: Some(10),
+                full_size: Some(1000),
+                reason: None,
+            }),
+            error: None,
+            bytes_sent: Some(10),
+            latency: Duration::from_micros(2500),
+        };
+        log.log(&entry);
+        entry.decision.as_mut().unwrap().chosen_format = None;
+        entry.decision.as_mut().unwrap().reason = Some(FallbackReason::TooLarge);
+        log.log(&entry);
+        entry.status = StatusCode::NOT_FOUND;
+        entry.error = Some("resource-not-found");
+        entry.decision = None;
+        entry.session = None;
+        log.log(&entry);
+
+        let written = String::from_utf8(log.into_inner()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"ts":1500,"method":"GET","path":"/api/\"feed\"","status":200,"session":"s1","outcome":"diff","base":"v1","version":"v2","format":"binary-delta","reason":null,"error":null,"bytes":10,"full_bytes":1000,"latency_ms":2.500}"#
+        );
+        assert!(lines[1].contains(r#""outcome":"full""#));
+        assert!(lines[1].contains(r#""reason":"too-large""#));
+        assert!(lines[2].contains(r#""status":404,"session":null,"outcome":"error""#));
+        assert!(lines[2].contains(r#""error":"resource-not-found""#));
+    }
+}
@@ -0,0 +1,349 @@
+//! Thresholds changed on a running server, and the route changing them
+//!
+//! [`BpxServer::tune`](crate::BpxServer::tune) changes a [`Setting`] without
+//! a restart, so operators can trade savings for CPU under pressure, and
+//! keeps who changed what, also reported to the
+//! [audit log](crate::audit) if there is one. With
+//! [`BpxConfig::admin_token`](crate::BpxConfig::admin_token) set,
+//! [`BpxLayer`](crate::BpxLayer) answers [`SETTINGS_PATH`] for requests
+//! carrying `Authorization: Bearer <token>`: `GET` returns the settings and
+//! recent changes as JSON, and `POST` with a form body such as
+//! `min_compression_ratio=0.4&max_diff_size=1048576` changes them first. The
+//! actor recorded is the `X-BPX-Actor` header, or `admin` without one.
+//!
+//! With the same token, `GET` [`DEBUG_PATH`]`?session=<id>&path=<path>`
+//! reports what the server believes one client holds: a [`SessionDebug`]
+//! with the version last sent to the session, whether the store still
+//! retains its content, and what the session's next request would get.
+
+use crate::{
+    BpxConfig, BpxError, DiffDecision, FallbackReason, ResourcePath, SessionId, Version,
+    protocol::headers::decode_value, server::escape_json,
+};
+use hyper::Request;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Route answering the current settings and their recent changes
+pub const SETTINGS_PATH: &str = "/__bpx/settings";
+
+/// Route answering what the server believes a session holds for a path
+pub const DEBUG_PATH: &str = "/__bpx/debug";
+
+/// Header naming who changes settings through [`SETTINGS_PATH`]
+pub const ACTOR_HEADER: &str = "x-bpx-actor";
+
+/// Bytes of settings form read from a request
+pub const MAX_FORM_SIZE: usize = 4096;
+
+/// Setting changes kept; older ones are dropped
+pub const AUDIT_CAPACITY: usize = 256;
+
+/// A threshold that can change while the server runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    /// [`BpxConfig::min_compression_ratio`], between 0 and 1
+    MinCompressionRatio(f32),
+    /// [`BpxConfig::max_diff_size`]
+    MaxDiffSize(usize),
+    /// Bytes the server's diff cache holds (see
+    /// [`BpxServerBuilder::diff_cache`](crate::BpxServerBuilder::diff_cache))
+    DiffCacheBytes(usize),
+}
+
+impl Setting {
+    /// Name the setting goes by in forms and the audit log
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MinCompressionRatio(_) => "min_compression_ratio",
+            Self::MaxDiffSize(_) => "max_diff_size",
+            Self::DiffCacheBytes(_) => "diff_cache_bytes",
+        }
+    }
+
+    /// Read the setting called `name` from `value`
+    pub fn parse(name: &str, value: &str) -> Result<Self, BpxError> {
+        let invalid = |reason: String| BpxError::InvalidRequest { reason };
+        let bytes = || {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| invalid(format!("{}={:?}: {}", name, value, e)))
+        };
+        match name {
+            "min_compression_ratio" => {
+                let ratio: f32 = value
+                    .trim()
+                    .parse()
+                    .map_err(|e| invalid(format!("{}={:?}: {}", name, value, e)))?;
+                if !(0.0..=1.0).contains(&ratio) {
+                    return Err(invalid(format!("{} must be between 0 and 1", name)));
+                }
+                Ok(Self::MinCompressionRatio(ratio))
+            }
+            "max_diff_size" => Ok(Self::MaxDiffSize(bytes()?)),
+            "diff_cache_bytes" => Ok(Self::DiffCacheBytes(bytes()?)),
+            _ => Err(invalid(format!("Unknown setting: {}", name))),
+        }
+    }
+
+    /// Put the setting into `config`, returning the value it replaced
+    pub(crate) fn apply(self, config: &mut BpxConfig) -> Option<Self> {
+        match self {
+            Self::MinCompressionRatio(ratio) => Some(Self::MinCompressionRatio(std::mem::replace(
+                &mut config.min_compression_ratio,
+                ratio,
+            ))),
+            Self::MaxDiffSize(size) => Some(Self::MaxDiffSize(std::mem::replace(
+                &mut config.max_diff_size,
+                size,
+            ))),
+            Self::DiffCacheBytes(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MinCompressionRatio(ratio) => write!(f, "{}", ratio),
+            Self::MaxDiffSize(bytes) | Self::DiffCacheBytes(bytes) => write!(f, "{}", bytes),
+        }
+    }
+}
+
+/// Who changed a setting, when, and from what to what
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingChange {
+    /// Who made the change
+    pub actor: String,
+    /// Value before the change
+    pub old: Setting,
+    /// Value after the change
+    pub new: Setting,
+    /// When the change was made
+    pub at: SystemTime,
+}
+
+impl SettingChange {
+    /// Render as a JSON object
+    pub fn to_json(&self) -> String {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        format!(
+            r#"{{"actor":"{}","setting":"{}","old":{},"new":{},"at":{}}}"#,
+            escape_json(&self.actor),
+            self.new.name(),
+            self.old,
+            self.new,
+            at,
+        )
+    }
+}
+
+/// Who is acting through an admin route: the [`ACTOR_HEADER`], or `admin`
+pub(crate) fn actor<B>(req: &Request<B>) -> &str {
+    req.headers()
+        .get(ACTOR_HEADER)
+        .and_then(|actor| actor.to_str().ok())
+        .unwrap_or("admin")
+}
+
+/// The most recent [`AUDIT_CAPACITY`] setting changes
+#[derive(Debug, Default)]
+pub(crate) struct SettingHistory {
+    changes: Mutex<VecDeque<SettingChange>>,
+}
+
+impl SettingHistory {
+    pub(crate) fn record(&self, change: SettingChange) {
+        let mut changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        if changes.len() == AUDIT_CAPACITY {
+            changes.pop_front();
+        }
+        changes.push_back(change);
+    }
+
+    /// Changes recorded, oldest first
+    pub(crate) fn changes(&self) -> Vec<SettingChange> {
+        let changes = self.changes.lock().unwrap_or_else(|e| e.into_inner());
+        changes.iter().cloned().collect()
+    }
+}
+
+/// What the server believes one session holds for one resource
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDebug {
+    /// Session asked about
+    pub session: SessionId,
+    /// Version last sent to the session, or `None` if the session is
+    /// unknown or was never sent the resource
+    pub stored_version: Option<Version>,
+    /// Whether the store still holds `stored_version`'s content to diff
+    /// against
+    pub base_retained: bool,
+    /// What the session's next request would get, should the client
+    /// present `stored_version` as its base and accept every format
+    pub next: DiffDecision,
+}
+
+impl SessionDebug {
+    /// Render as a JSON object
+    pub fn to_json(&self) -> String {
+        let string = |value: Option<String>| match value {
+            Some(value) => format!("\"{}\"", escape_json(&value)),
+            None => "null".to_string(),
+        };
+        let number = |value: Option<usize>| value.map_or("null".to_string(), |n| n.to_string());
+        let next = &self.next;
+        let outcome = if next.reason == Some(FallbackReason::Unchanged) {
+            "not_modified"
+        } else if next.is_diff() {
+            "diff"
+        } else {
+            "full"
+        };
+        format!(
+            r#"{{"session":"{}","path":"{}","stored_version":{},"base_retained":{},"current_version":"{}","next":{{"outcome":"{}","format":{},"reason":{},"diff_size":{},"full_size":{}}}}}"#,
+            escape_json(&self.session.to_string()),
+            escape_json(&next.path.to_string()),
+            string(self.stored_version.as_ref().map(ToString::to_string)),
+            self.base_retained,
+            escape_json(&next.current.to_string()),
+            outcome,
+            string(next.chosen_format.map(|f| f.as_str().to_string())),
+            string(next.reason.map(|r| r.as_str().to_string())),
+            number(next.diff_size),
+            number(next.full_size),
+        )
+    }
+}
+
+/// Read the session and path asked about from a [`DEBUG_PATH`] query
+pub(crate) fn parse_debug_query(
+    query: Option<&str>,
+    config: &BpxConfig,
+) -> Result<(SessionId, ResourcePath), BpxError> {
+    let (mut session, mut path) = (None, None);
+    for pair in query.unwrap_or_default().split('&') {
+        match pair.split_once('=') {
+            Some(("session", value)) => session = Some(decode_value(value).into_owned()),
+            Some(("path", value)) => path = Some(decode_value(value).into_owned()),
+            _ => {}
+        }
+    }
+    let missing = |name: &str| BpxError::InvalidRequest {
+        reason: format!("{} needs a {} query parameter", DEBUG_PATH, name),
+    };
+    let session = session
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| missing("session"))?;
+    let path = path
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| missing("path"))?;
+    Ok((
+        SessionId::new(session),
+        ResourcePath::canonical(&path, &config.query),
+    ))
+}
+
+/// Read settings from a `name=value&...` form
+pub(crate) fn parse_form(body: &[u8]) -> Result<Vec<Setting>, BpxError> {
+    let body = std::str::from_utf8(body).map_err(|_| BpxError::InvalidRequest {
+        reason: "Settings form is not UTF-8".to_string(),
+    })?;
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Setting::parse(name, value)
+        })
+        .collect()
+}
+
+/// Whether `authorization` carries the bearer `token`, compared in
+/// constant time
+pub(crate) fn authorized(authorization: Option<&[u8]>, token: &str) -> bool {
+    let Some(given) = authorization.and_then(|value| value.strip_prefix(b"Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        let settings =
+            parse_form(b"min_compression_ratio=0.5&max_diff_size=1024&diff_cache_bytes=0").unwrap();
+        assert_eq!(
+            settings,
+            [
+                Setting::MinCompressionRatio(0.5),
+                Setting::MaxDiffSize(1024),
+                Setting::DiffCacheBytes(0),
+            ]
+        );
+        assert!(parse_form(b"min_compression_ratio=1.5").is_err());
+        assert!(parse_form(b"max_diff_size=-1").is_err());
+        assert!(parse_form(b"session_ttl=1h").is_err());
+
+        let mut config = BpxConfig::default();
+        let old = Setting::MaxDiffSize(1024).apply(&mut config);
+        assert_eq!(old, Some(Setting::MaxDiffSize(10 * 1024 * 1024)));
+        assert_eq!(config.max_diff_size, 1024);
+    }
+
+    #[test]
+    fn test_audit_log_is_bounded() {
+        let log = SettingHistory::default();
+        for size in 0..AUDIT_CAPACITY + 1 {
+            log.record(SettingChange {
+                actor: "ops \"on call\"".to_string(),
+                old: Setting::MaxDiffSize(size),
+                new: Setting::MaxDiffSize(size + 1),
+                at: UNIX_EPOCH,
+            });
+        }
+        let changes = log.changes();
+        assert_eq!(changes.len(), AUDIT_CAPACITY);
+        assert_eq!(changes[0].old, Setting::MaxDiffSize(1));
+        assert_eq!(
+            changes[0].to_json(),
+            r#"{"actor":"ops \"on call\"","setting":"max_diff_size","old":1,"new":2,"at":0}"#
+        );
+    }
+
+    #[test]
+    fn test_debug_query() {
+        let config = BpxConfig::default();
+        let (session, path) =
+            parse_debug_query(Some("session=s%201&path=/api/feed%3Fpage%3D2"), &config).unwrap();
+        assert_eq!(session, SessionId::new("s 1".to_string()));
+        assert_eq!(path.to_string(), "/api/feed?page=2");
+        assert!(parse_debug_query(Some("session=s1"), &config).is_err());
+        assert!(parse_debug_query(None, &config).is_err());
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert!(authorized(Some(b"Bearer s3cret"), "s3cret"));
+        assert!(!authorized(Some(b"Bearer s3cre"), "s3cret"));
+        assert!(!authorized(Some(b"Basic s3cret"), "s3cret"));
+        assert!(!authorized(None, "s3cret"));
+    }
+}
@@ -0,0 +1,98 @@
+//! Routing a session back to the replica that holds its state
+//!
+//! Until sessions live in a shared [`StateManager`](crate::StateManager),
+//! each replica only knows the sessions it created, and a request reaching
+//! another replica is answered in full. With
+//! [`BpxConfig::affinity`](crate::BpxConfig::affinity) set, responses carry
+//! an affinity key derived from the session ID, in a header and/or a cookie,
+//! for a Layer-7 balancer to hash on (e.g. HAProxy `balance hdr(...)`,
+//! nginx `hash $cookie_...`). Balancers that route in code can use
+//! [`replica_for`].
+//!
+//! The key is a digest of the session ID rather than the ID itself, so it can
+//! show up in balancer logs or be read by scripts without handing out
+//! sessions.
+
+use crate::SessionId;
+use sha2::{Digest, Sha256};
+
+/// Header carrying the affinity key by default
+pub const AFFINITY_HEADER: &str = "X-BPX-Affinity";
+
+/// Where responses carry the affinity key
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct AffinityConfig {
+    /// Response header to set; `None` sets none
+    pub header: Option<String>,
+    /// Cookie to set, readable by scripts; `None` sets none
+    pub cookie: Option<String>,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        Self {
+            header: Some(AFFINITY_HEADER.to_string()),
+            cookie: None,
+        }
+    }
+}
+
+/// Stable key routing `session` to the same replica from any replica
+pub fn affinity_key(session: &SessionId) -> String {
+    let digest = Sha256::digest(session.to_string().as_bytes());
+    hex::encode(&digest[..8])
+}
+
+/// Replica that `key` belongs to, by rendezvous hashing
+///
+/// Every balancer and replica given the same list agrees on the answer.
+/// Adding or removing a replica only moves the keys that belonged to it.
+pub fn replica_for<'a, S: AsRef<str>>(key: &str, replicas: &'a [S]) -> Option<&'a S> {
+    replicas.iter().max_by_key(|replica| {
+        let mut hasher = Sha256::new();
+        hasher.update(replica.as_ref().as_bytes());
+        hasher.update([0]);
+        hasher.update(key.as_bytes());
+        let digest = hasher.finalize();
+        u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replica_for_is_stable_and_minimally_disruptive() {
+        let replicas = ["bpx-0", "bpx-1", "bpx-2", "bpx-3"];
+        let keys: Vec<String> = (0..400)
+            .map(|i| affinity_key(&SessionId::new(format!("sess_{}", i))))
+            .collect();
+        assert_eq!(keys[0], affinity_key(&SessionId::new("sess_0".to_string())));
+        assert_eq!(keys[0].len(), 16);
+
+        let before: Vec<_> = keys
+            .iter()
+            .map(|k| *replica_for(k, &replicas).unwrap())
+            .collect();
+        for replica in replicas {
+            let share = before.iter().filter(|r| **r == replica).count();
+            assert!(share > 50, "{} got {} of 400", replica, share);
+        }
+
+        // Dropping a replica only moves the sessions it held
+        let remaining = ["bpx-0", "bpx-1", "bpx-3"];
+        for (key, was) in keys.iter().zip(&before) {
+            let now = *replica_for(key, &remaining).unwrap();
+            if *was != "bpx-2" {
+                assert_eq!(now, *was);
+            }
+        }
+        assert!(replica_for::<&str>("key", &[]).is_none());
+    }
+}
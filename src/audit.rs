@@ -0,0 +1,228 @@
+//! Security-relevant events, for deployments that must keep a record
+//!
+//! A [`BpxServer`](crate::BpxServer) built with
+//! [`BpxServerBuilder::audit_log`](crate::BpxServerBuilder::audit_log) hands
+//! an [`AuditRecord`] to an [`AuditLog`] when it issues a session or replaces
+//! one a client presented, refuses access (by its
+//! [`Authorizer`](crate::Authorizer) or to an admin route), performs an admin
+//! action, or enforces a [`SessionQuota`](crate::SessionQuota). Unlike the
+//! [access log](crate::access_log), records are never sampled or dropped by
+//! the server. [`FileAuditLog`] appends them to a file as JSON lines.
+
+use crate::{ResourcePath, SessionId, TenantId, quota::QuotaAction, server::escape_json};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Something worth auditing that happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A session was issued to a client that presented none
+    SessionCreated {
+        /// Session issued
+        session: SessionId,
+    },
+    /// A session was issued in place of an unknown or expired one
+    SessionReplaced {
+        /// Session the client presented
+        presented: SessionId,
+        /// Session issued
+        session: SessionId,
+    },
+    /// Access to a resource or admin route was refused
+    AccessDenied {
+        /// Path requested
+        path: ResourcePath,
+    },
+    /// An operator changed a setting or inspected a session
+    AdminAction {
+        /// Who acted
+        actor: String,
+        /// What was done, e.g. `set max_diff_size from 1024 to 2048`
+        action: String,
+    },
+    /// A session past its quota was demoted or refused
+    QuotaEnforced {
+        /// Session over its quota
+        session: SessionId,
+        /// What it got
+        action: QuotaAction,
+    },
+}
+
+impl AuditEvent {
+    /// `session_created`, `session_replaced`, `access_denied`,
+    /// `admin_action` or `quota_enforced`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::SessionCreated { .. } => "session_created",
+            Self::SessionReplaced { .. } => "session_replaced",
+            Self::AccessDenied { .. } => "access_denied",
+            Self::AdminAction { .. } => "admin_action",
+            Self::QuotaEnforced { .. } => "quota_enforced",
+        }
+    }
+}
+
+/// An [`AuditEvent`], when it happened and for which tenant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the event happened
+    pub at: SystemTime,
+    /// Tenant of the request, if any (see [`crate::tenant`])
+    pub tenant: Option<TenantId>,
+    /// What happened
+    pub event: AuditEvent,
+}
+
+impl AuditRecord {
+    /// Record `event` as happening now
+    pub fn now(tenant: Option<TenantId>, event: AuditEvent) -> Self {
+        Self {
+            at: SystemTime::now(),
+            tenant,
+            event,
+        }
+    }
+
+    /// Render as a single-line JSON object
+    pub fn to_json(&self) -> String {
+        let at = self
+            .at
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis())
+            .unwrap_or_default();
+        let string = |value: &str| format!("\"{}\"", escape_json(value));
+        let tenant = match &self.tenant {
+            Some(tenant) => string(&tenant.to_string()),
+            None => "null".to_string(),
+        };
+        let fields = match &self.event {
+            AuditEvent::SessionCreated { session } => {
+                format!(r#""session":{}"#, string(&session.to_string()))
+            }
+            AuditEvent::SessionReplaced { presented, session } => format!(
+                r#""presented":{},"session":{}"#,
+                string(&presented.to_string()),
+                string(&session.to_string()),
+            ),
+            AuditEvent::AccessDenied { path } => {
+                format!(r#""path":{}"#, string(&path.to_string()))
+            }
+            AuditEvent::AdminAction { actor, action } => {
+                format!(r#""actor":{},"action":{}"#, string(actor), string(action))
+            }
+            AuditEvent::QuotaEnforced { session, action } => format!(
+                r#""session":{},"action":"{}""#,
+                string(&session.to_string()),
+                match action {
+                    QuotaAction::FullOnly => "full_only",
+                    QuotaAction::Reject => "reject",
+                },
+            ),
+        };
+        format!(
+            r#"{{"ts":{},"event":"{}","tenant":{},{}}}"#,
+            at,
+            self.event.kind(),
+            tenant,
+            fields,
+        )
+    }
+}
+
+/// Receives every audit record, in the order events happened
+///
+/// Called on the request path, so implementations should be quick; records
+/// should only ever be appended, never rewritten.
+pub trait AuditLog: Send + Sync {
+    /// `record` happened
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditLog for F
+where
+    F: Fn(&AuditRecord) + Send + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+/// [`AuditLog`] appending each record to a file as a line of JSON
+///
+/// Each record is written with a single unbuffered write to a file opened
+/// for appending, so it reaches the operating system before the request is
+/// answered. Write failures are reported on stderr rather than failing
+/// requests.
+#[derive(Debug)]
+pub struct FileAuditLog {
+    file: Mutex<File>,
+}
+
+impl FileAuditLog {
+    /// Append records to the file at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = record.to_json();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            eprintln!("Audit log write failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_audit_log_appends() {
+        let path = std::env::temp_dir().join(format!("bpx-audit-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let at = UNIX_EPOCH + Duration::from_millis(1500);
+        let session = SessionId::new("s\"1".to_string());
+
+        FileAuditLog::open(&path).unwrap().record(&AuditRecord {
+            at,
+            tenant: None,
+            event: AuditEvent::SessionCreated {
+                session: session.clone(),
+            },
+        });
+        // Reopening appends rather than truncating
+        FileAuditLog::open(&path).unwrap().record(&AuditRecord {
+            at,
+            tenant: Some(TenantId::new("acme".to_string())),
+            event: AuditEvent::QuotaEnforced {
+                session,
+                action: QuotaAction::Reject,
+            },
+        });
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"ts":1500,"event":"session_created","tenant":null,"session":"s\"1"}"#,
+                r#"{"ts":1500,"event":"quota_enforced","tenant":"acme","session":"s\"1","action":"reject"}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,55 @@
+//! Per-resource access control
+//!
+//! An [`Authorizer`] is asked before anything about a resource is served,
+//! whether full content, a diff or version metadata. Diffs are only computed
+//! once the caller is allowed to read the resource, so neither the content
+//! nor a session's stored base can leak through one, and a denied request
+//! records nothing for its session.
+
+use crate::{ResourcePath, SessionId};
+use async_trait::async_trait;
+use hyper::{HeaderMap, Method, Request, Uri};
+
+/// What an [`Authorizer`] knows about the request
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// Request method
+    pub method: Method,
+    /// Request URI
+    pub uri: Uri,
+    /// Request headers, e.g. `Authorization`
+    pub headers: HeaderMap,
+    /// Session the request presented, if any
+    pub session_id: Option<SessionId>,
+}
+
+impl RequestContext {
+    /// Context of `req`, presenting `session_id`
+    pub fn from_request<B>(req: &Request<B>, session_id: Option<SessionId>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            session_id,
+        }
+    }
+}
+
+/// Outcome of an authorization check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Serve the resource
+    Allow,
+    /// Refuse with `403 Forbidden`
+    Deny,
+}
+
+/// Decides whether a request may read or write a resource
+#[async_trait]
+pub trait Authorizer: Send + Sync {
+    /// Whether the request described by `context` may access `path`
+    ///
+    /// Called once per resource, so batch entries and each event of an
+    /// event stream are checked on their own.
+    async fn authorize(&self, context: &RequestContext, path: &ResourcePath) -> Decision;
+}
@@ -0,0 +1,237 @@
+//! Command-line BPX client for manual testing and scripting
+//!
+//! ```text
+//! bpx-cli get http://127.0.0.1:3000/api/logs/server
+//! bpx-cli get http://127.0.0.1:3000/api/logs/server --dump-diff last.diff
+//! bpx-cli decode last.diff
+//! ```
+
+use bpx::{
+    BpxError,
+    client::{
+        FileClientCache,
+        blocking::{BlockingTransport, BpxClient, UreqTransport},
+    },
+    diff::binary::{BinaryDiffCodec, DiffOperation},
+    protocol::headers::BpxHeaders,
+};
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use hyper::{Request, Response};
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Parser)]
+#[command(name = "bpx-cli", about = "Fetch resources over BPX and inspect diffs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch a URL, reusing the session and base kept in the state directory
+    Get {
+        /// Full URL, e.g. http://127.0.0.1:3000/api/logs/server
+        url: String,
+        /// Directory holding session and base content between runs (one per server)
+        #[arg(long, default_value = ".bpx-state")]
+        state_dir: PathBuf,
+        /// Write the content to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Save the raw response body to a file when it is a diff
+        #[arg(long)]
+        dump_diff: Option<PathBuf>,
+        /// Do not print the content
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Decode a binary-delta payload and list its operations
+    Decode {
+        /// File holding the payload
+        file: PathBuf,
+        /// Apply the payload to this base and print the result instead
+        #[arg(long)]
+        apply: Option<PathBuf>,
+    },
+}
+
+/// Transport remembering the last response so its raw body can be inspected
+struct RecordingTransport {
+    inner: UreqTransport,
+    last: Mutex<Option<Response<Bytes>>>,
+}
+
+impl BlockingTransport for RecordingTransport {
+    fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+        let response = self.inner.send(req)?;
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some(response.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// Split `scheme://authority/path` into the server base and the path
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let authority_start = url.find("://")? + 3;
+    match url[authority_start..].find('/') {
+        Some(i) => Some(url.split_at(authority_start + i)),
+        None => Some((url, "/")),
+    }
+}
+
+fn get(
+    url: &str,
+    state_dir: PathBuf,
+    output: Option<PathBuf>,
+    dump_diff: Option<PathBuf>,
+    quiet: bool,
+) -> Result<(), String> {
+    let (base_uri, path) = split_url(url).ok_or_else(|| format!("invalid URL: {}", url))?;
+    let cache = FileClientCache::open(&state_dir)
+        .map_err(|e| format!("cannot open {}: {}", state_dir.display(), e))?;
+    let transport = Arc::new(RecordingTransport {
+        inner: UreqTransport::new(),
+        last: Mutex::new(None),
+    });
+    let client = BpxClient::with_transport(transport.clone(), base_uri).with_cache(Arc::new(cache));
+
+    let result = client.get(path).map_err(|e| e.to_string())?;
+    let last = transport.last.lock().ok().and_then(|mut last| last.take());
+    let header = |name: &str| {
+        last.as_ref()
+            .and_then(|r| r.headers().get(name))
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+
+    let saved = if result.content.is_empty() {
+        0.0
+    } else {
+        100.0 * (1.0 - result.bytes_received as f64 / result.content.len() as f64)
+    };
+    let kind = if result.diff_applied {
+        header(BpxHeaders::DIFF_TYPE).unwrap_or_else(|| "diff".to_string())
+    } else {
+        match header(BpxHeaders::FALLBACK_REASON) {
+            Some(reason) => format!("full ({})", reason),
+            None => "full".to_string(),
+        }
+    };
+    eprintln!(
+        "{} {}: {} bytes on wire, {} bytes content, {:.1}% saved",
+        result.version,
+        kind,
+        result.bytes_received,
+        result.content.len(),
+        saved
+    );
+
+    if let Some(file) = dump_diff {
+        match last.filter(|_| result.diff_applied) {
+            Some(response) => fs::write(&file, response.body())
+                .map_err(|e| format!("cannot write {}: {}", file.display(), e))?,
+            None => eprintln!("response was not a diff; {} not written", file.display()),
+        }
+    }
+
+    match output {
+        Some(file) => fs::write(&file, &result.content)
+            .map_err(|e| format!("cannot write {}: {}", file.display(), e)),
+        None if quiet => Ok(()),
+        None => io::stdout()
+            .write_all(&result.content)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn decode(file: PathBuf, apply: Option<PathBuf>) -> Result<(), String> {
+    let read = |path: &PathBuf| {
+        fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    };
+    let payload = read(&file)?;
+
+    if let Some(base) = apply {
+        let content =
+            BinaryDiffCodec::apply_diff(&read(&base)?, &payload).map_err(|e| e.to_string())?;
+        return io::stdout().write_all(&content).map_err(|e| e.to_string());
+    }
+
+    let operations = BinaryDiffCodec::decode_diff(&payload).map_err(|e| e.to_string())?;
+    let (mut copied, mut inserted, mut deleted) = (0u64, 0u64, 0u64);
+    for op in &operations {
+        match op {
+            DiffOperation::Copy { offset, length } => {
+                copied += u64::from(*length);
+                println!("COPY   offset={} length={}", offset, length);
+            }
+            DiffOperation::Insert(data) => {
+                inserted += data.len() as u64;
+                println!(
+                    "INSERT length={} {:?}",
+                    data.len(),
+                    String::from_utf8_lossy(&data[..data.len().min(60)])
+                );
+            }
+            DiffOperation::Delete { length } => {
+                deleted += u64::from(*length);
+                println!("DELETE length={}", length);
+            }
+        }
+    }
+    println!(
+        "{} operations, {} bytes payload: {} copied, {} inserted, {} deleted",
+        operations.len(),
+        payload.len(),
+        copied,
+        inserted,
+        deleted
+    );
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Get {
+            url,
+            state_dir,
+            output,
+            dump_diff,
+            quiet,
+        } => get(&url, state_dir, output, dump_diff, quiet),
+        Command::Decode { file, apply } => decode(file, apply),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bpx-cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            split_url("http://127.0.0.1:3000/api/logs?tail=1"),
+            Some(("http://127.0.0.1:3000", "/api/logs?tail=1"))
+        );
+        assert_eq!(
+            split_url("https://example.com"),
+            Some(("https://example.com", "/"))
+        );
+        assert_eq!(split_url("example.com/api"), None);
+    }
+}
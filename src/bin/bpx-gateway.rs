@@ -0,0 +1,229 @@
+//! Reverse proxy adding BPX in front of an unmodified REST API
+//!
+//! Requests from BPX clients (those sending `X-BPX-Session`, `Accept-Diff`,
+//! `X-Base-Version` or `A-IM`) for GETs under `--prefix` are answered from
+//! an [`HttpOriginStore`] fetching the upstream, so they get diffs. Every
+//! other request, and any resource the upstream doesn't have, is forwarded
+//! to the upstream as is.
+//!
+//! Resources are fetched with the gateway's own `--header`s, not the
+//! client's, and shared by every client; only serve resources that look the
+//! same to everyone through BPX.
+//!
+//! ```text
+//! bpx-gateway --upstream http://127.0.0.1:8080 --listen 0.0.0.0:3000 \
+//!     --prefix /api/ --header "Authorization: Bearer token"
+//! ```
+//!
+//! Other [`BpxConfig`] settings are read from a TOML file given with
+//! `--config`, then from `BPX_*` environment variables; flags override both.
+
+use bpx::{
+    BpxConfig, BpxError, BpxLayer, BpxServer,
+    protocol::headers::{BpxHeaders, DeltaHeaders},
+    serve::serve_connections,
+    server::ResourceBody,
+    store::HttpOriginStore,
+};
+use clap::Parser;
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    HeaderMap, Request, Response, StatusCode, Uri,
+    body::Incoming,
+    header::{self, HeaderName, HeaderValue},
+};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use std::{convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tower::{Layer, ServiceExt};
+
+#[derive(Parser)]
+#[command(
+    name = "bpx-gateway",
+    about = "Serve an upstream REST API with BPX diffs for clients that ask for them"
+)]
+struct Args {
+    /// Upstream to proxy, e.g. http://127.0.0.1:8080
+    #[arg(long)]
+    upstream: String,
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:3000")]
+    listen: SocketAddr,
+    /// Only serve GETs for paths starting with this through BPX
+    #[arg(long, default_value = "/")]
+    prefix: String,
+    /// Header sent with every upstream fetch of a BPX resource, as "Name: value"
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// TOML file with BpxConfig settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Sessions kept at once [default: 10000 without --config]
+    #[arg(long)]
+    max_sessions: Option<usize>,
+    /// Also answer RFC 3229 delta requests (`A-IM`)
+    #[arg(long)]
+    rfc3229: bool,
+    /// Time open connections get to finish on shutdown [default: 30]
+    #[arg(long)]
+    drain_secs: Option<u64>,
+}
+
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: value\", got {:?}", header))?;
+    let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+    Ok((name, value))
+}
+
+/// Headers describing a single connection, never forwarded
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Forwards requests to the upstream unchanged but for the target
+#[derive(Clone)]
+struct Upstream {
+    client: Client<HttpConnector, Incoming>,
+    authority: Arc<Uri>,
+}
+
+impl Upstream {
+    fn new(upstream: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let authority: Uri = upstream.trim_end_matches('/').parse()?;
+        if authority.scheme_str() != Some("http") || authority.authority().is_none() {
+            return Err(format!("upstream must be an http:// URL, got {}", upstream).into());
+        }
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            authority: Arc::new(authority),
+        })
+    }
+
+    async fn forward(self, mut req: Request<Incoming>) -> Response<ResourceBody> {
+        let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let mut parts = self.authority.as_ref().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        *req.uri_mut() = Uri::from_parts(parts).expect("upstream URI with a path");
+        strip_hop_by_hop(req.headers_mut());
+        if let Some(authority) = self.authority.authority()
+            && let Ok(host) = HeaderValue::from_str(authority.as_str())
+        {
+            req.headers_mut().insert(header::HOST, host);
+        }
+
+        match self.client.request(req).await {
+            Ok(response) => {
+                let mut response = response.map(|body| {
+                    body.map_err(|e| BpxError::Transport {
+                        reason: e.to_string(),
+                    })
+                    .boxed_unsync()
+                });
+                strip_hop_by_hop(response.headers_mut());
+                response
+            }
+            Err(e) => {
+                eprintln!("Upstream request failed: {}", e);
+                let mut response = Response::new(
+                    Empty::new()
+                        .map_err(|never: Infallible| match never {})
+                        .boxed_unsync(),
+                );
+                *response.status_mut() = StatusCode::BAD_GATEWAY;
+                response
+            }
+        }
+    }
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+/// Whether a request comes from a client speaking BPX or RFC 3229
+fn participates<B>(req: &Request<B>) -> bool {
+    [
+        BpxHeaders::SESSION,
+        BpxHeaders::ACCEPT_DIFF,
+        BpxHeaders::BASE_VERSION,
+        DeltaHeaders::A_IM,
+    ]
+    .iter()
+    .any(|name| req.headers().contains_key(*name))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let upstream = Upstream::new(&args.upstream)?;
+
+    let mut config = match &args.config {
+        Some(path) => BpxConfig::from_toml(path)?,
+        None => BpxConfig {
+            max_sessions: 10_000,
+            ..BpxConfig::default()
+        },
+    }
+    .merge_env()?;
+    if let Some(max_sessions) = args.max_sessions {
+        config.max_sessions = max_sessions;
+    }
+    config.rfc3229_mode |= args.rfc3229;
+    if let Some(drain_secs) = args.drain_secs {
+        config.shutdown_timeout = Duration::from_secs(drain_secs);
+    }
+    let drain = config.shutdown_timeout;
+    let server = Arc::new(BpxServer::builder().config(config).build()?);
+    let store = Arc::new(
+        HttpOriginStore::new(args.upstream.clone())
+            .with_headers(args.headers.into_iter().collect()),
+    );
+
+    let forward = {
+        let upstream = upstream.clone();
+        tower::service_fn(move |req: Request<Incoming>| {
+            let upstream = upstream.clone();
+            async move { Ok::<_, Infallible>(upstream.forward(req).await) }
+        })
+    };
+    let bpx = BpxLayer::new(server, store)
+        .prefix(args.prefix)
+        .layer(forward.clone());
+    let service = tower::service_fn(move |req: Request<Incoming>| {
+        let bpx = bpx.clone();
+        let forward = forward.clone();
+        async move {
+            if participates(&req) {
+                bpx.oneshot(req).await
+            } else {
+                forward.oneshot(req).await
+            }
+        }
+    });
+
+    let listener = tokio::net::TcpListener::bind(args.listen).await?;
+    eprintln!(
+        "bpx-gateway listening on {}, proxying {}",
+        listener.local_addr()?,
+        args.upstream
+    );
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    serve_connections(listener, service, shutdown, drain).await?;
+    Ok(())
+}
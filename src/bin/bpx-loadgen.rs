@@ -0,0 +1,316 @@
+//! Load generator simulating many polling BPX clients
+//!
+//! Without `--url` an in-process server is started whose resources change
+//! every `--change-interval-ms`; against a real server, `--update-url` is hit
+//! at that rate instead (e.g. the demo server's `/demo/update`).
+//!
+//! ```text
+//! bpx-loadgen --clients 200 --poll-interval-ms 500 --duration-secs 60
+//! bpx-loadgen --url http://127.0.0.1:3000 --path /api/logs/server \
+//!     --update-url http://127.0.0.1:3000/demo/update
+//! ```
+
+use bpx::{
+    BpxConfig, BpxServer, ResourcePath,
+    client::{BpxClient, PathSavings},
+    server::{InMemoryResourceStore, error_response},
+};
+use bytes::Bytes;
+use clap::Parser;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{Request, server::conn::http1, service::service_fn};
+use hyper_util::{
+    client::legacy::Client,
+    rt::{TokioExecutor, TokioIo},
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time::{self, MissedTickBehavior};
+
+#[derive(Parser, Clone)]
+#[command(
+    name = "bpx-loadgen",
+    about = "Simulate polling BPX clients and report diff hit rate, savings and latency"
+)]
+struct Args {
+    /// Server to load, e.g. http://127.0.0.1:3000 (default: start one in-process)
+    #[arg(long)]
+    url: Option<String>,
+    /// Paths to poll; clients are spread across them round-robin
+    #[arg(long = "path", default_value = "/api/feed")]
+    paths: Vec<String>,
+    /// Number of simulated clients
+    #[arg(long, default_value_t = 50)]
+    clients: usize,
+    /// Delay between polls of one client
+    #[arg(long, default_value_t = 1000)]
+    poll_interval_ms: u64,
+    /// Delay between resource changes
+    #[arg(long, default_value_t = 2000)]
+    change_interval_ms: u64,
+    /// URL requested on every change when loading an external server
+    #[arg(long)]
+    update_url: Option<String>,
+    /// Length of the test
+    #[arg(long, default_value_t = 30)]
+    duration_secs: u64,
+    /// Lines in each in-process resource
+    #[arg(long, default_value_t = 500)]
+    resource_lines: usize,
+    /// Lines appended (and dropped from the front) per in-process change
+    #[arg(long, default_value_t = 5)]
+    lines_per_change: usize,
+}
+
+/// What one simulated client observed
+#[derive(Default)]
+struct ClientStats {
+    latencies: Vec<Duration>,
+    errors: u64,
+    savings: PathSavings,
+}
+
+/// Start an in-process server on an ephemeral port and return its base URL
+async fn start_server(store: Arc<InMemoryResourceStore>) -> std::io::Result<String> {
+    let config = BpxConfig {
+        max_sessions: 100_000,
+        ..BpxConfig::default()
+    };
+    let server = Arc::new(
+        BpxServer::builder()
+            .config(config)
+            .build()
+            .map_err(std::io::Error::other)?,
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let server = Arc::clone(&server);
+            let store = Arc::clone(&store);
+            let service = service_fn(move |req| {
+                let server = Arc::clone(&server);
+                let store = Arc::clone(&store);
+                async move {
+                    let response = match server.handle_request(req, store).await {
+                        Ok(response) => response,
+                        Err(e) => error_response(&e),
+                    };
+                    Ok::<_, Infallible>(response.map(Full::new))
+                }
+            });
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    Ok(format!("http://{}", addr))
+}
+
+/// Roll every in-process resource forward by `lines_per_change` lines per tick
+async fn mutate_resources(store: Arc<InMemoryResourceStore>, args: Args) {
+    let mut resources = args
+        .paths
+        .iter()
+        .map(|path| {
+            let lines = (0..args.resource_lines)
+                .map(|i| log_line(path, i))
+                .collect::<VecDeque<_>>();
+            (path.clone(), lines)
+        })
+        .collect::<Vec<_>>();
+    let mut next = args.resource_lines;
+    let mut interval = time::interval(Duration::from_millis(args.change_interval_ms.max(1)));
+    // The first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        for (path, lines) in &resources {
+            let content = lines.iter().map(String::as_str).collect::<String>();
+            store.set_resource(ResourcePath::new(path.clone()), Bytes::from(content));
+        }
+        interval.tick().await;
+        for (path, lines) in &mut resources {
+            for i in 0..args.lines_per_change {
+                lines.pop_front();
+                lines.push_back(log_line(path, next + i));
+            }
+        }
+        next += args.lines_per_change;
+    }
+}
+
+fn log_line(path: &str, i: usize) -> String {
+    format!(
+        "[seq={:08}] INFO {} request processed user_id={} duration={}ms status=200\n",
+        i,
+        path,
+        1000 + i % 997,
+        20 + i % 180
+    )
+}
+
+/// Hit an external update endpoint every change interval
+async fn trigger_updates(update_url: String, change_interval: Duration) {
+    let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+    let mut interval = time::interval(change_interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Ok(req) = Request::get(&update_url).body(Empty::new()) else {
+            eprintln!("invalid update URL: {}", update_url);
+            return;
+        };
+        match client.request(req).await {
+            Ok(response) => {
+                let _ = response.into_body().collect().await;
+            }
+            Err(e) => eprintln!("update request failed: {}", e),
+        }
+    }
+}
+
+/// Poll one path until the deadline
+async fn run_client(base_uri: String, path: String, args: Args, index: usize) -> ClientStats {
+    let poll_interval = Duration::from_millis(args.poll_interval_ms.max(1));
+    let deadline = Instant::now() + Duration::from_secs(args.duration_secs);
+    // Spread clients evenly over one poll interval
+    let offset = poll_interval.mul_f64(index as f64 / args.clients.max(1) as f64);
+    let mut interval = time::interval_at(time::Instant::now() + offset, poll_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let client = BpxClient::new(base_uri);
+    let mut stats = ClientStats::default();
+    loop {
+        interval.tick().await;
+        if Instant::now() >= deadline {
+            break;
+        }
+        let started = Instant::now();
+        match client.get(&path).await {
+            Ok(_) => stats.latencies.push(started.elapsed()),
+            Err(_) => stats.errors += 1,
+        }
+    }
+    stats.savings = client.savings_report().total();
+    stats
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn print_report(args: &Args, elapsed: Duration, stats: Vec<ClientStats>) {
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    let mut savings = PathSavings::default();
+    for client in stats {
+        latencies.extend(client.latencies);
+        errors += client.errors;
+        savings += client.savings;
+    }
+    latencies.sort_unstable();
+
+    let hit_rate = if savings.fetches == 0 {
+        0.0
+    } else {
+        100.0 * savings.diff_fetches as f64 / savings.fetches as f64
+    };
+    println!(
+        "{} clients x {} paths for {:.1}s",
+        args.clients,
+        args.paths.len(),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "requests:  {} ok, {} failed, {:.1} req/s",
+        savings.fetches,
+        errors,
+        savings.fetches as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "diffs:     {} ({:.1}% hit rate)",
+        savings.diff_fetches, hit_rate
+    );
+    println!(
+        "bandwidth: {} bytes on wire, {} bytes reconstructed, {:.1}% saved",
+        savings.bytes_on_wire,
+        savings.bytes_reconstructed,
+        100.0 * savings.savings_ratio()
+    );
+    println!(
+        "latency:   p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        latencies.last().copied().unwrap_or_default()
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let change_interval = Duration::from_millis(args.change_interval_ms.max(1));
+
+    let base_uri = match &args.url {
+        Some(url) => {
+            if let Some(update_url) = args.update_url.clone() {
+                tokio::spawn(trigger_updates(update_url, change_interval));
+            }
+            url.trim_end_matches('/').to_string()
+        }
+        None => {
+            let store = Arc::new(InMemoryResourceStore::new());
+            let base_uri = start_server(Arc::clone(&store)).await?;
+            tokio::spawn(mutate_resources(store, args.clone()));
+            // Let the first version of every resource land before polling
+            time::sleep(Duration::from_millis(50)).await;
+            base_uri
+        }
+    };
+    eprintln!(
+        "loading {} with {} clients for {}s",
+        base_uri, args.clients, args.duration_secs
+    );
+
+    let started = Instant::now();
+    let tasks = (0..args.clients)
+        .map(|i| {
+            let path = args.paths[i % args.paths.len()].clone();
+            tokio::spawn(run_client(base_uri.clone(), path, args.clone(), i))
+        })
+        .collect::<Vec<_>>();
+
+    let mut stats = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        stats.push(task.await?);
+    }
+    print_report(&args, started.elapsed(), stats);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let samples = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+}
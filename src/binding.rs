@@ -0,0 +1,329 @@
+//! Sessions bound to the client that first presented them
+//!
+//! A session ID is a bearer credential: anyone who sees the header on a
+//! shared network can replay it and be sent diffs against what its owner
+//! holds. With [`BpxConfig::session_binding`](crate::BpxConfig::session_binding)
+//! set, [`BpxServer`](crate::BpxServer) remembers the identity each session
+//! was first seen from (the peer address, the `X-Forwarded-For` entry
+//! appended by the outermost of [`SessionBinding::trusted_proxies`], or a
+//! [`PeerIdentity`] such as a TLS client certificate fingerprint) and treats
+//! a request presenting it from another identity, or from none, per
+//! [`SessionBinding::on_mismatch`]: it either gets a new session, as if it
+//! had presented none, or is refused with [`BpxError::Forbidden`]. Clients
+//! can prepend whatever they like to `X-Forwarded-For`, so only entries
+//! appended by proxies the deployment runs are read. Sessions are bound per
+//! tenant when the request names one (see [`crate::tenant`]).
+
+use crate::{
+    BpxConfig, BpxError, ResourcePath, SessionId, TenantId,
+    protocol::headers::BpxHeaders,
+    rate_limit::PeerAddr,
+    server::{forget_session, request_session, response_session},
+};
+use dashmap::{DashMap, mapref::entry::Entry};
+use hyper::{Request, Response};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Checks between sweeps of bindings for sessions that have expired
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Header a proxy lists the addresses a request came through in
+pub const FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// How sessions are bound, and what a mismatch gets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct SessionBinding {
+    /// What identifies the client
+    pub source: BindingSource,
+    /// What a request presenting a session from another identity gets
+    pub on_mismatch: BindingAction,
+    /// Proxies in front of the server that append to `X-Forwarded-For`,
+    /// for [`BindingSource::ForwardedFor`]; the client is the entry this
+    /// many from the right (default `1`)
+    pub trusted_proxies: usize,
+}
+
+impl Default for SessionBinding {
+    fn default() -> Self {
+        Self {
+            source: BindingSource::default(),
+            on_mismatch: BindingAction::default(),
+            trusted_proxies: 1,
+        }
+    }
+}
+
+/// What identifies the client a session is bound to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BindingSource {
+    /// IP address of the [`PeerAddr`] request extension
+    #[default]
+    Peer,
+    /// The `X-Forwarded-For` entry appended by the outermost of
+    /// [`SessionBinding::trusted_proxies`]; only sound when every request
+    /// comes through them
+    ForwardedFor,
+    /// The [`PeerIdentity`] request extension
+    Identity,
+}
+
+/// What a request presenting a session from another identity gets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum BindingAction {
+    /// A new session, as if it had presented none
+    #[default]
+    Reissue,
+    /// `403` with code `forbidden`
+    Reject,
+}
+
+/// Identity of the client, e.g. a TLS client certificate fingerprint, as a
+/// request extension for [`BindingSource::Identity`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerIdentity(pub String);
+
+struct Bound {
+    identity: String,
+    seen: Instant,
+}
+
+/// Remembers the identity each session was first seen from
+pub struct SessionBinder {
+    binding: SessionBinding,
+    bindings: DashMap<(Option<TenantId>, SessionId), Bound>,
+    checks: AtomicU64,
+}
+
+impl SessionBinder {
+    /// Enforce `binding`
+    pub fn new(binding: SessionBinding) -> Self {
+        Self {
+            binding,
+            bindings: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Identity `req` comes from, per [`SessionBinding::source`]
+    pub fn identity<B>(&self, req: &Request<B>) -> Option<String> {
+        match self.binding.source {
+            BindingSource::Peer => req
+                .extensions()
+                .get::<PeerAddr>()
+                .map(|peer| peer.0.ip().to_string()),
+            BindingSource::ForwardedFor => {
+                // Proxies append, so entries left of theirs are the client's say
+                let entries = req
+                    .headers()
+                    .get_all(FORWARDED_FOR)
+                    .iter()
+                    .map(|value| value.to_str().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                entries
+                    .iter()
+                    .flat_map(|value| value.split(','))
+                    .rev()
+                    .nth(self.binding.trusted_proxies.checked_sub(1)?)
+                    .map(str::trim)
+                    .filter(|entry| !entry.is_empty())
+                    .map(str::to_string)
+            }
+            BindingSource::Identity => req
+                .extensions()
+                .get::<PeerIdentity>()
+                .map(|identity| identity.0.clone()),
+        }
+    }
+
+    /// Check the session `req` presents against the identity it comes from
+    ///
+    /// On a mismatch, the session is dropped from `req` or the request
+    /// refused. Returns the identity, to bind the session answered with
+    /// (see [`bind_response`](Self::bind_response)).
+    pub fn check<B>(
+        &self,
+        req: &mut Request<B>,
+        config: &BpxConfig,
+    ) -> Result<Option<String>, BpxError> {
+        let now = Instant::now();
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            self.sweep(now, config.session_retention());
+        }
+        let identity = self.identity(req);
+        let Some(session) = request_session(req, config)? else {
+            return Ok(identity);
+        };
+        let tenant = req.extensions().get::<TenantId>().cloned();
+        // Presenting a bound session without an identity is no better than
+        // presenting it from another one
+        let matches = self
+            .bindings
+            .get(&(tenant, session))
+            .is_none_or(|bound| identity.as_deref() == Some(bound.identity.as_str()));
+        if !matches {
+            match self.binding.on_mismatch {
+                BindingAction::Reissue => forget_session(req, config),
+                BindingAction::Reject => {
+                    return Err(BpxError::Forbidden {
+                        path: ResourcePath::new(req.uri().path().to_string()),
+                    });
+                }
+            }
+        }
+        Ok(identity)
+    }
+
+    /// Bind the session `response` names to `identity`, unless it is bound
+    /// already
+    ///
+    /// Only sessions the server issued or resumed are bound, so IDs made up
+    /// by clients take no room. A session first seen after a restart is
+    /// bound to whoever presents it first.
+    pub fn bind_response<B>(
+        &self,
+        tenant: Option<&TenantId>,
+        identity: &str,
+        response: &Response<B>,
+    ) {
+        let Some(session) = response_session(response) else {
+            return;
+        };
+        let created = response
+            .headers()
+            .get(BpxHeaders::SESSION_STATUS)
+            .is_some_and(|status| status == "created");
+        let bound = Bound {
+            identity: identity.to_string(),
+            seen: Instant::now(),
+        };
+        match self.bindings.entry((tenant.cloned(), session)) {
+            Entry::Occupied(mut entry) if created => {
+                entry.insert(bound);
+            }
+            Entry::Occupied(mut entry) => entry.get_mut().seen = bound.seen,
+            Entry::Vacant(entry) => {
+                entry.insert(bound);
+            }
+        }
+    }
+
+    /// Number of sessions bound
+    pub fn tracked(&self) -> usize {
+        self.bindings.len()
+    }
+
+    /// Forget bindings of sessions unseen for longer than they are kept
+    fn sweep(&self, now: Instant, retention: Duration) {
+        self.bindings
+            .retain(|_, bound| now.saturating_duration_since(bound.seen) < retention);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header;
+
+    #[test]
+    fn test_mismatched_forwarded_for_reissues() {
+        let binder = SessionBinder::new(SessionBinding {
+            source: BindingSource::ForwardedFor,
+            on_mismatch: BindingAction::Reissue,
+            ..SessionBinding::default()
+        });
+        let config = BpxConfig {
+            session_cookie: Some("bpx".to_string()),
+            ..BpxConfig::default()
+        };
+        let request = |forwarded_for: Option<&str>| {
+            let mut req = Request::builder().header(header::COOKIE, "theme=dark; bpx=s1");
+            if let Some(forwarded_for) = forwarded_for {
+                req = req.header(FORWARDED_FOR, forwarded_for);
+            }
+            req.body(()).unwrap()
+        };
+
+        // The first client answered with the session binds it, as the proxy
+        // saw it whatever it claimed
+        let mut first = request(Some("198.51.100.9, 203.0.113.7"));
+        let identity = binder.check(&mut first, &config).unwrap().unwrap();
+        assert_eq!(identity, "203.0.113.7");
+        let answer = Response::builder()
+            .header(BpxHeaders::SESSION, "s1")
+            .header(BpxHeaders::SESSION_STATUS, "resumed")
+            .body(())
+            .unwrap();
+        binder.bind_response(None, &identity, &answer);
+        let mut again = request(Some("203.0.113.7"));
+        binder.check(&mut again, &config).unwrap();
+        assert_eq!(again.headers()[header::COOKIE], "theme=dark; bpx=s1");
+
+        // Another client presenting it loses the session, not other cookies,
+        // even claiming the first one's address
+        let mut replayed = request(Some("203.0.113.7, 198.51.100.9"));
+        replayed
+            .headers_mut()
+            .insert(BpxHeaders::SESSION, "s1".parse().unwrap());
+        binder.check(&mut replayed, &config).unwrap();
+        assert!(!replayed.headers().contains_key(BpxHeaders::SESSION));
+        assert_eq!(replayed.headers()[header::COOKIE], "theme=dark");
+        assert_eq!(request_session(&replayed, &config).unwrap(), None);
+
+        // Nor does leaving the header out get past the check
+        let mut anonymous = request(None);
+        assert_eq!(binder.check(&mut anonymous, &config).unwrap(), None);
+        assert_eq!(request_session(&anonymous, &config).unwrap(), None);
+        assert_eq!(binder.tracked(), 1);
+    }
+
+    #[test]
+    fn test_forwarded_for_behind_several_proxies() {
+        let binder = SessionBinder::new(SessionBinding {
+            source: BindingSource::ForwardedFor,
+            trusted_proxies: 2,
+            ..SessionBinding::default()
+        });
+        // Proxies may append on a header line of their own
+        let req = Request::builder()
+            .header(FORWARDED_FOR, "192.0.2.1, 203.0.113.7")
+            .header(FORWARDED_FOR, "10.0.0.1")
+            .body(())
+            .unwrap();
+        assert_eq!(binder.identity(&req).as_deref(), Some("203.0.113.7"));
+
+        // Fewer entries than proxies: the request went around them
+        let req = Request::builder()
+            .header(FORWARDED_FOR, "10.0.0.1")
+            .body(())
+            .unwrap();
+        assert_eq!(binder.identity(&req), None);
+
+        let config = BpxConfig {
+            session_binding: Some(SessionBinding {
+                source: BindingSource::ForwardedFor,
+                trusted_proxies: 0,
+                ..SessionBinding::default()
+            }),
+            ..BpxConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
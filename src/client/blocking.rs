@@ -0,0 +1,250 @@
+//! Blocking BPX client for CLI tools and non-async applications
+//!
+//! Mirrors [`super::BpxClient`] without requiring an async runtime. With the
+//! `blocking` feature, [`BpxClient::new`] sends requests through `ureq`; any
+//! other HTTP library can be plugged in via [`BlockingTransport`].
+
+use super::{
+    CachedResource, ClientCache, ClientCore, ClientObserver, FetchResult, RecoveryPolicy,
+    SavingsReport,
+};
+use crate::{BpxError, DiffEngine, SessionId, signing::SignatureVerifier};
+use bytes::Bytes;
+use hyper::{Request, Response};
+use std::sync::Arc;
+
+/// Sends a single HTTP request, blocking until the full response is read
+pub trait BlockingTransport: Send + Sync {
+    /// Perform the request
+    fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError>;
+}
+
+impl<T: BlockingTransport + ?Sized> BlockingTransport for Arc<T> {
+    fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+        (**self).send(req)
+    }
+}
+
+/// Transport backed by a `ureq` agent
+#[cfg(feature = "blocking")]
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "blocking")]
+impl UreqTransport {
+    /// Create transport with a default agent
+    pub fn new() -> Self {
+        // Error statuses are BPX responses too; let the client interpret them
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build();
+        Self {
+            agent: config.into(),
+        }
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl Default for UreqTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl BlockingTransport for UreqTransport {
+    fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+        let transport_error = |e: ureq::Error| BpxError::Transport {
+            reason: e.to_string(),
+        };
+
+        let (parts, body) = req.into_parts();
+        let response = if body.is_empty() {
+            self.agent.run(Request::from_parts(parts, ()))
+        } else {
+            self.agent.run(Request::from_parts(parts, body.to_vec()))
+        }
+        .map_err(transport_error)?;
+
+        let (parts, mut body) = response.into_parts();
+        let body = body.read_to_vec().map_err(transport_error)?;
+        Ok(Response::from_parts(parts, Bytes::from(body)))
+    }
+}
+
+/// Blocking BPX client tracking session and per-path base content
+#[cfg(feature = "blocking")]
+pub struct BpxClient<T = UreqTransport> {
+    transport: T,
+    base_uri: String,
+    core: ClientCore,
+}
+
+/// Blocking BPX client tracking session and per-path base content
+#[cfg(not(feature = "blocking"))]
+pub struct BpxClient<T> {
+    transport: T,
+    base_uri: String,
+    core: ClientCore,
+}
+
+#[cfg(feature = "blocking")]
+impl BpxClient<UreqTransport> {
+    /// Create client for a server such as `http://127.0.0.1:8080`
+    pub fn new(base_uri: impl Into<String>) -> Self {
+        Self::with_transport(UreqTransport::new(), base_uri)
+    }
+}
+
+impl<T: BlockingTransport> BpxClient<T> {
+    /// Create client sending requests through a custom transport
+    pub fn with_transport(transport: T, base_uri: impl Into<String>) -> Self {
+        Self {
+            transport,
+            base_uri: base_uri.into().trim_end_matches('/').to_string(),
+            core: ClientCore::new(),
+        }
+    }
+
+    /// Use a different diff engine to apply patches
+    pub fn with_diff_engine(mut self, diff_engine: Arc<dyn DiffEngine>) -> Self {
+        self.core = self.core.with_diff_engine(diff_engine);
+        self
+    }
+
+    /// Reject responses whose `X-BPX-Signature` does not verify
+    pub fn with_verifier(mut self, verifier: Arc<dyn SignatureVerifier>) -> Self {
+        self.core = self.core.with_verifier(verifier);
+        self
+    }
+
+    /// Keep session and bases in a different cache, e.g. one that survives restarts
+    pub fn with_cache(mut self, cache: Arc<dyn ClientCache>) -> Self {
+        self.core = self.core.with_cache(cache);
+        self
+    }
+
+    /// Report recovery events to an observer
+    pub fn with_observer(mut self, observer: Arc<dyn ClientObserver>) -> Self {
+        self.core = self.core.with_observer(observer);
+        self
+    }
+
+    /// Change how patch failures are recovered from
+    pub fn with_recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.core = self.core.with_recovery(recovery);
+        self
+    }
+
+    /// Refuse diffs patching to more than `max_output_size` bytes
+    /// (see [`ClientCore::with_max_output_size`])
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.core = self.core.with_max_output_size(max_output_size);
+        self
+    }
+
+    /// Bytes on the wire versus bytes reconstructed, per path
+    pub fn savings_report(&self) -> SavingsReport {
+        self.core.savings_report()
+    }
+
+    /// Current session ID, once the server has assigned one
+    pub fn session(&self) -> Option<SessionId> {
+        self.core.session()
+    }
+
+    /// Last known state of a path
+    pub fn cached(&self, path: &str) -> Option<CachedResource> {
+        self.core.cached(path)
+    }
+
+    /// Drop the cached base for a path so the next fetch is a full one
+    pub fn forget(&self, path: &str) {
+        self.core.forget(path);
+    }
+
+    /// Fetch the current content of a path, using a diff when possible
+    ///
+    /// Recovers from patch failures like [`super::BpxClient::get`].
+    pub fn get(&self, path: &str) -> Result<FetchResult, BpxError> {
+        let mut fetch = self.core.fetch(path);
+        loop {
+            let response = self.send(path, fetch.base())?;
+            if let Some(result) = fetch.accept(&response) {
+                return result;
+            }
+        }
+    }
+
+    /// Build and send a request carrying our session and base version
+    fn send(&self, path: &str, base: Option<&CachedResource>) -> Result<Response<Bytes>, BpxError> {
+        let mut req = Request::builder()
+            .uri(format!("{}{}", self.base_uri, path))
+            .body(Bytes::new())
+            .map_err(|e| BpxError::InvalidRequest {
+                reason: e.to_string(),
+            })?;
+        self.core.prepare(req.headers_mut(), base);
+        self.transport.send(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BpxConfig, InMemoryResourceStore, ResourcePath, StateManager,
+        diff::similar::SimilarDiffEngine, server::handle_bpx_request, state::InMemoryStateManager,
+    };
+    use http_body_util::Full;
+
+    /// Transport driving the async server handler on a private runtime
+    struct LoopbackTransport {
+        runtime: tokio::runtime::Runtime,
+        config: BpxConfig,
+        state_mgr: Arc<dyn StateManager>,
+        diff_engine: Arc<dyn DiffEngine>,
+        store: Arc<InMemoryResourceStore>,
+    }
+
+    impl BlockingTransport for LoopbackTransport {
+        fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+            self.runtime.block_on(handle_bpx_request(
+                req.map(Full::new),
+                &self.config,
+                self.state_mgr.clone(),
+                self.diff_engine.clone(),
+                self.store.clone(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_blocking_full_then_diff() {
+        let lines = |n: usize| -> Bytes {
+            Bytes::from((0..n).map(|i| format!("line {}\n", i)).collect::<String>())
+        };
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+
+        let config = BpxConfig::default();
+        let transport = LoopbackTransport {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap(),
+            state_mgr: Arc::new(InMemoryStateManager::new(config.clone())),
+            config,
+            diff_engine: Arc::new(SimilarDiffEngine::new()),
+            store: store.clone(),
+        };
+        let client = BpxClient::with_transport(transport, "");
+
+        assert!(!client.get("/api/feed").unwrap().diff_applied);
+        store.set_resource(path, lines(101));
+        let result = client.get("/api/feed").unwrap();
+        assert!(result.diff_applied);
+        assert_eq!(result.content, lines(101));
+    }
+}
@@ -0,0 +1,352 @@
+//! Client-side storage of session and base content
+
+use super::CachedResource;
+use crate::{SessionId, Version};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Storage for the session ID and the base of every fetched resource
+pub trait ClientCache: Send + Sync {
+    /// Get the base held for a resource
+    fn get(&self, key: &str) -> Option<CachedResource>;
+
+    /// Store a new base for a resource
+    fn put(&self, key: &str, resource: CachedResource);
+
+    /// Drop the base for a resource
+    fn remove(&self, key: &str);
+
+    /// Drop every base, keeping the session
+    fn clear(&self);
+
+    /// Session ID last assigned by the server
+    fn session(&self) -> Option<SessionId>;
+
+    /// Remember the session ID assigned by the server
+    fn set_session(&self, session: SessionId);
+
+    /// Drop the session ID, e.g. once the server refuses it
+    fn forget_session(&self);
+}
+
+/// Cache living only as long as the process
+#[derive(Default)]
+pub struct InMemoryClientCache {
+    resources: DashMap<String, CachedResource>,
+    session: Mutex<Option<SessionId>>,
+}
+
+impl InMemoryClientCache {
+    /// Create empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ClientCache for InMemoryClientCache {
+    fn get(&self, key: &str) -> Option<CachedResource> {
+        self.resources.get(key).map(|entry| entry.clone())
+    }
+
+    fn put(&self, key: &str, resource: CachedResource) {
+        self.resources.insert(key.to_string(), resource);
+    }
+
+    fn remove(&self, key: &str) {
+        self.resources.remove(key);
+    }
+
+    fn clear(&self) {
+        self.resources.clear();
+    }
+
+    fn session(&self) -> Option<SessionId> {
+        self.session.lock().ok().and_then(|s| s.clone())
+    }
+
+    fn set_session(&self, session: SessionId) {
+        if let Ok(mut current) = self.session.lock() {
+            *current = Some(session);
+        }
+    }
+
+    fn forget_session(&self) {
+        if let Ok(mut current) = self.session.lock() {
+            *current = None;
+        }
+    }
+}
+
+/// Magic prefix of an on-disk entry
+const ENTRY_MAGIC: &[u8; 4] = b"BPXC";
+/// On-disk entry layout version
+const ENTRY_VERSION: u8 = 1;
+/// File holding the session ID
+const SESSION_FILE: &str = "session";
+
+/// Writes started by this process, numbering their temporary files
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Cache persisted in a directory so bases survive process restarts
+///
+/// Each resource is one file named after the SHA-256 of its key; writes go to a
+/// temporary file of their own first and are renamed into place, so
+/// concurrent writers, in this process or another, never mix their bytes. Unreadable or corrupt
+/// entries are treated as missing.
+pub struct FileClientCache {
+    dir: PathBuf,
+}
+
+impl FileClientCache {
+    /// Open (and create if needed) a cache directory
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Directory holding the cache files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.entry", hex::encode(Sha256::digest(key))))
+    }
+
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp = PathBuf::from(tmp);
+        let written = fs::write(&tmp, data).and_then(|()| fs::rename(&tmp, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written
+    }
+}
+
+/// Serialize an entry: magic, layout version, key, version, content type, content
+fn encode_entry(key: &str, resource: &CachedResource) -> Bytes {
+    let version = resource.version.to_string();
+    let content_type = resource.content_type.as_deref().unwrap_or("");
+
+    let mut buf = BytesMut::with_capacity(
+        16 + key.len() + version.len() + content_type.len() + resource.content.len(),
+    );
+    buf.put_slice(ENTRY_MAGIC);
+    buf.put_u8(ENTRY_VERSION);
+    for field in [key, &version] {
+        buf.put_u32(field.len() as u32);
+        buf.put_slice(field.as_bytes());
+    }
+    buf.put_u8(u8::from(resource.content_type.is_some()));
+    buf.put_u32(content_type.len() as u32);
+    buf.put_slice(content_type.as_bytes());
+    buf.put_slice(&resource.content);
+    buf.freeze()
+}
+
+/// Parse an entry, checking it belongs to `key`
+fn decode_entry(key: &str, data: Bytes) -> Option<CachedResource> {
+    fn read_str(buf: &mut Bytes) -> Option<String> {
+        if buf.remaining() < 4 {
+            return None;
+        }
+        let len = buf.get_u32() as usize;
+        if buf.remaining() < len {
+            return None;
+        }
+        String::from_utf8(buf.split_to(len).to_vec()).ok()
+    }
+
+    let mut buf = data;
+    if buf.remaining() < ENTRY_MAGIC.len() + 1 || &buf[..ENTRY_MAGIC.len()] != ENTRY_MAGIC {
+        return None;
+    }
+    buf.advance(ENTRY_MAGIC.len());
+    if buf.get_u8() != ENTRY_VERSION {
+        return None;
+    }
+
+    // Guard against hash collisions and foreign files
+    if read_str(&mut buf)? != key {
+        return None;
+    }
+    let version = Version::new(read_str(&mut buf)?);
+    if buf.remaining() < 1 {
+        return None;
+    }
+    let has_content_type = buf.get_u8() == 1;
+    let content_type = read_str(&mut buf)?;
+
+    Some(CachedResource {
+        version,
+        content: buf,
+        content_type: has_content_type.then_some(content_type),
+    })
+}
+
+impl ClientCache for FileClientCache {
+    fn get(&self, key: &str) -> Option<CachedResource> {
+        let data = fs::read(self.entry_path(key)).ok()?;
+        decode_entry(key, Bytes::from(data))
+    }
+
+    fn put(&self, key: &str, resource: CachedResource) {
+        let path = self.entry_path(key);
+        if let Err(e) = self.write_atomic(&path, &encode_entry(key, &resource)) {
+            eprintln!("BPX client cache write failed for {}: {}", key, e);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.entry_path(key));
+    }
+
+    fn clear(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "entry") {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn session(&self) -> Option<SessionId> {
+        let session = fs::read_to_string(self.dir.join(SESSION_FILE)).ok()?;
+        let session = session.trim();
+        (!session.is_empty()).then(|| SessionId::new(session.to_string()))
+    }
+
+    fn set_session(&self, session: SessionId) {
+        let path = self.dir.join(SESSION_FILE);
+        if let Err(e) = self.write_atomic(&path, session.to_string().as_bytes()) {
+            eprintln!("BPX client cache write failed for session: {}", e);
+        }
+    }
+
+    fn forget_session(&self) {
+        match fs::remove_file(self.dir.join(SESSION_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                eprintln!("BPX client cache write failed for session: {}", e);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bpx-client-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn resource(content: &'static [u8], content_type: Option<&str>) -> CachedResource {
+        CachedResource {
+            version: Version::from_content(content),
+            content: Bytes::from_static(content),
+            content_type: content_type.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_file_cache_survives_reopen() {
+        let dir = temp_dir("reopen");
+        {
+            let cache = FileClientCache::open(&dir).unwrap();
+            cache.put("/api/a", resource(b"hello", Some("text/plain")));
+            cache.put("/api/b", resource(b"", None));
+            cache.set_session(SessionId::new("sess_1".to_string()));
+        }
+
+        let cache = FileClientCache::open(&dir).unwrap();
+        let a = cache.get("/api/a").unwrap();
+        assert_eq!(a.content, Bytes::from_static(b"hello"));
+        assert_eq!(a.version, Version::from_content(b"hello"));
+        assert_eq!(a.content_type.as_deref(), Some("text/plain"));
+        assert!(cache.get("/api/b").unwrap().content_type.is_none());
+        assert_eq!(cache.session(), Some(SessionId::new("sess_1".to_string())));
+
+        cache.remove("/api/a");
+        assert!(cache.get("/api/a").is_none());
+        assert!(cache.get("/api/missing").is_none());
+
+        cache.clear();
+        assert!(cache.get("/api/b").is_none());
+        assert!(cache.session().is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_cache_ignores_corrupt_entries() {
+        let dir = temp_dir("corrupt");
+        let cache = FileClientCache::open(&dir).unwrap();
+        cache.put("/api/a", resource(b"hello", None));
+
+        fs::write(cache.entry_path("/api/a"), b"BPXC\x01garbage").unwrap();
+        assert!(cache.get("/api/a").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_cache_concurrent_writes() {
+        let dir = temp_dir("concurrent");
+        let cache = FileClientCache::open(&dir).unwrap();
+        let contents: [&'static [u8]; 2] = [b"hello", b"a longer base than hello"];
+
+        std::thread::scope(|scope| {
+            for content in contents.into_iter().cycle().take(8) {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        cache.put("/api/a", resource(content, None));
+                    }
+                });
+            }
+        });
+
+        // Whichever write landed last, it landed whole
+        let held = cache.get("/api/a").unwrap();
+        assert!(contents.contains(&held.content.as_ref()));
+        assert_eq!(held.version, Version::from_content(&held.content));
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(files.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_cache() {
+        let cache = InMemoryClientCache::new();
+        assert!(cache.session().is_none());
+        cache.put("/api/a", resource(b"hello", None));
+        assert!(cache.get("/api/a").is_some());
+        cache.remove("/api/a");
+        assert!(cache.get("/api/a").is_none());
+    }
+}
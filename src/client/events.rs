@@ -0,0 +1,51 @@
+//! Recovery events and observer hooks
+
+use crate::{BpxError, SessionId};
+
+/// Something the client did to keep its state consistent with the server
+#[derive(Debug)]
+pub enum ClientEvent<'a> {
+    /// Server assigned a different session (the old one expired or was unknown);
+    /// every cached base was dropped
+    SessionChanged {
+        /// Session the client sent, if any
+        previous: Option<&'a SessionId>,
+        /// Session assigned by the server
+        current: &'a SessionId,
+    },
+    /// A diff could not be applied; the base for the resource was dropped
+    PatchFailed {
+        /// Resource key
+        key: &'a str,
+        /// Why the patch failed
+        error: &'a BpxError,
+        /// Resync attempt about to be made (starting at 1)
+        attempt: u32,
+    },
+    /// A full refetch succeeded after one or more patch failures
+    Resynced {
+        /// Resource key
+        key: &'a str,
+        /// Number of refetches it took
+        attempts: u32,
+    },
+}
+
+/// Receives [`ClientEvent`]s, e.g. to log or count them
+pub trait ClientObserver: Send + Sync {
+    /// Called synchronously as the event happens
+    fn on_event(&self, event: &ClientEvent<'_>);
+}
+
+/// How hard the client tries to resynchronise a resource after a patch failure
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryPolicy {
+    /// Full refetches attempted before the error is returned (0 disables recovery)
+    pub max_resyncs: u32,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self { max_resyncs: 1 }
+    }
+}
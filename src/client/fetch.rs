@@ -0,0 +1,87 @@
+//! One fetch of a resource, across the refetches recovery takes
+
+use super::{CachedResource, ClientCore, ClientEvent, FetchResult};
+use crate::BpxError;
+use bytes::Bytes;
+use hyper::Response;
+
+/// Recovery state of one fetch, driven by a client front-end
+///
+/// Send a request prepared with [`Fetch::base`] and hand the outcome to
+/// [`Fetch::accept`] (or [`Fetch::settle`]), until it returns the result.
+/// `None` means the base was dropped and the request should be sent again.
+pub struct Fetch<'a> {
+    core: &'a ClientCore,
+    key: &'a str,
+    base: Option<CachedResource>,
+    attempts: u32,
+    bytes_received: usize,
+}
+
+impl<'a> Fetch<'a> {
+    /// Start fetching a resource from its cached base, if any
+    pub(super) fn new(core: &'a ClientCore, key: &'a str) -> Self {
+        Self {
+            core,
+            key,
+            base: core.cached(key),
+            attempts: 0,
+            bytes_received: 0,
+        }
+    }
+
+    /// Base the next request is made with
+    pub fn base(&self) -> Option<&CachedResource> {
+        self.base.as_ref()
+    }
+
+    /// Turn a buffered response into the fetch result, counting it towards
+    /// the savings report
+    pub fn accept(&mut self, response: &Response<Bytes>) -> Option<Result<FetchResult, BpxError>> {
+        self.bytes_received += response.body().len();
+        let outcome = self
+            .core
+            .accept(self.key, self.base.as_ref(), response)
+            .map(|result| FetchResult {
+                bytes_received: self.bytes_received,
+                ..result
+            });
+        let settled = self.settle(outcome);
+        if let Some(Ok(result)) = &settled {
+            self.core.record(self.key, result);
+        }
+        settled
+    }
+
+    /// Finish with `outcome`, or drop the base if it is to blame and ask for
+    /// a full refetch, up to [`super::RecoveryPolicy::max_resyncs`] times
+    pub fn settle<T>(&mut self, outcome: Result<T, BpxError>) -> Option<Result<T, BpxError>> {
+        match outcome {
+            Ok(value) => {
+                if self.attempts > 0 {
+                    self.core.emit(&ClientEvent::Resynced {
+                        key: self.key,
+                        attempts: self.attempts,
+                    });
+                }
+                Some(Ok(value))
+            }
+            Err(e)
+                if self.attempts < self.core.recovery().max_resyncs
+                    && self.core.resyncs(self.base.as_ref(), &e) =>
+            {
+                self.attempts += 1;
+                self.core.emit(&ClientEvent::PatchFailed {
+                    key: self.key,
+                    error: &e,
+                    attempt: self.attempts,
+                });
+                // Drop our base and start over with a full fetch
+                self.core.forget(self.key);
+                self.base = None;
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! `tower` middleware adding BPX to any client service
+
+use super::{ClientCore, SavingsReport};
+use crate::{BpxError, SessionId};
+use bytes::Bytes;
+use hyper::{Method, Request, Response, header};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service, ServiceExt};
+
+/// Layer wrapping a client service so GET requests exchange diffs transparently
+///
+/// Responses handed back to the caller always carry the full, reconstructed
+/// body. Every service produced by one layer shares the same session and bases.
+#[derive(Clone)]
+pub struct BpxClientLayer {
+    core: Arc<ClientCore>,
+}
+
+impl BpxClientLayer {
+    /// Create layer with a fresh session
+    pub fn new() -> Self {
+        Self::with_core(ClientCore::new())
+    }
+
+    /// Create layer from a configured core (diff engine, signature verifier)
+    pub fn with_core(core: ClientCore) -> Self {
+        Self {
+            core: Arc::new(core),
+        }
+    }
+
+    /// Current session ID, once the server has assigned one
+    pub fn session(&self) -> Option<SessionId> {
+        self.core.session()
+    }
+
+    /// Bytes on the wire versus bytes reconstructed, per resource
+    pub fn savings_report(&self) -> SavingsReport {
+        self.core.savings_report()
+    }
+}
+
+impl Default for BpxClientLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for BpxClientLayer {
+    type Service = BpxClientService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BpxClientService {
+            inner,
+            core: Arc::clone(&self.core),
+        }
+    }
+}
+
+/// Service produced by [`BpxClientLayer`]
+#[derive(Clone)]
+pub struct BpxClientService<S> {
+    inner: S,
+    core: Arc<ClientCore>,
+}
+
+impl<S, E> Service<Request<Bytes>> for BpxClientService<S>
+where
+    S: Service<Request<Bytes>, Response = Response<Bytes>, Error = E> + Clone + Send + 'static,
+    S::Future: Send,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<Bytes>;
+    type Error = BpxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Bytes>, BpxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(transport_error)
+    }
+
+    fn call(&mut self, req: Request<Bytes>) -> Self::Future {
+        // Take the service that was driven to readiness and leave a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let core = Arc::clone(&self.core);
+
+        Box::pin(async move {
+            if req.method() != Method::GET {
+                return inner.call(req).await.map_err(transport_error);
+            }
+
+            let key = resource_key(&req);
+            let mut fetch = core.fetch(&key);
+            let (parts, body) = req.into_parts();
+
+            // The first call uses the service readied by `poll_ready`
+            loop {
+                let mut request = Request::from_parts(parts.clone(), body.clone());
+                core.prepare(request.headers_mut(), fetch.base());
+                let response = inner.call(request).await.map_err(transport_error)?;
+                if let Some(result) = fetch.accept(&response) {
+                    return result.map(|result| {
+                        full_response(response, result.content, result.content_type)
+                    });
+                }
+                inner.ready().await.map_err(transport_error)?;
+            }
+        })
+    }
+}
+
+/// Bases are tracked per authority and path
+fn resource_key<B>(req: &Request<B>) -> String {
+    let uri = req.uri();
+    format!(
+        "{}{}",
+        uri.authority().map(|a| a.as_str()).unwrap_or(""),
+        uri.path()
+    )
+}
+
+/// Replace a (possibly diff) response body with the reconstructed content
+fn full_response(
+    response: Response<Bytes>,
+    content: Bytes,
+    content_type: Option<String>,
+) -> Response<Bytes> {
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    if let Some(content_type) = content_type
+        && let Ok(value) = content_type.parse()
+    {
+        parts.headers.insert(header::CONTENT_TYPE, value);
+    }
+    Response::from_parts(parts, content)
+}
+
+fn transport_error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> BpxError {
+    BpxError::Transport {
+        reason: e.into().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BpxConfig, DiffEngine, InMemoryResourceStore, ResourcePath, StateManager,
+        diff::similar::SimilarDiffEngine, protocol::headers::BpxHeaders,
+        server::handle_bpx_request, state::InMemoryStateManager,
+    };
+    use http_body_util::Full;
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn test_layer_reconstructs_diffs() {
+        let config = BpxConfig::default();
+        let state_mgr: Arc<dyn StateManager> = Arc::new(InMemoryStateManager::new(config.clone()));
+        let engine: Arc<dyn DiffEngine> = Arc::new(SimilarDiffEngine::new());
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        let lines = |n: usize| -> Bytes {
+            Bytes::from((0..n).map(|i| format!("line {}\n", i)).collect::<String>())
+        };
+        store.set_resource(path.clone(), lines(100));
+        store.set_content_type(path.clone(), "text/plain");
+
+        let server = {
+            let store = store.clone();
+            tower::service_fn(move |req: Request<Bytes>| {
+                let (config, state_mgr, engine, store) = (
+                    config.clone(),
+                    state_mgr.clone(),
+                    engine.clone(),
+                    store.clone(),
+                );
+                async move {
+                    let response =
+                        handle_bpx_request(req.map(Full::new), &config, state_mgr, engine, store)
+                            .await
+                            .unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            })
+        };
+        let layer = BpxClientLayer::new();
+        let mut client = layer.layer(server);
+        let get = || Request::get("/api/feed").body(Bytes::new()).unwrap();
+
+        let first = client.ready().await.unwrap().call(get()).await.unwrap();
+        assert_eq!(first.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(first.body(), &lines(100));
+        assert!(layer.session().is_some());
+
+        store.set_resource(path.clone(), lines(101));
+        let second = client.ready().await.unwrap().call(get()).await.unwrap();
+        assert_eq!(second.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        assert_eq!(second.body(), &lines(101));
+        assert_eq!(second.headers()[header::CONTENT_TYPE], "text/plain");
+    }
+}
@@ -0,0 +1,1002 @@
+//! BPX client
+//!
+//! [`BpxClient`] keeps the session ID and the last content and version of every
+//! fetched path, sends them as BPX headers, applies diffs and falls back to a
+//! full fetch whenever a patch cannot be applied. [`BpxClientLayer`] offers the
+//! same behaviour as a `tower` middleware for existing HTTP client stacks.
+//! Response interpretation is shared with the runtime-free `bpx-client-core`
+//! crate used by WASM front-ends.
+
+use crate::{
+    BpxError, DiffEngine, DiffFormat, SessionId, Version,
+    diff::{PatchLimits, similar::SimilarDiffEngine},
+    protocol::headers::BpxHeaders,
+    signing::SignatureVerifier,
+};
+use async_trait::async_trait;
+use bpx_client_core::{ClientError, ResponseMeta, reconstruct, response::DEFAULT_MAX_OUTPUT_SIZE};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::{HeaderMap, Request, Response, StatusCode, header::HeaderValue};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use std::sync::Arc;
+
+pub mod blocking;
+mod cache;
+mod events;
+mod fetch;
+mod layer;
+mod savings;
+mod stream;
+
+pub use cache::{ClientCache, FileClientCache, InMemoryClientCache};
+pub use events::{ClientEvent, ClientObserver, RecoveryPolicy};
+pub use fetch::Fetch;
+pub use layer::{BpxClientLayer, BpxClientService};
+use savings::SavingsTracker;
+pub use savings::{PathSavings, SavingsReport};
+pub use stream::FetchStream;
+
+/// Problem code of a `404` refusing the session a request presented
+const NOT_FOUND_CODE: &[u8] = br#""code":"not-found""#;
+
+/// Sends a single HTTP request and returns the fully buffered response
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    /// Perform the request
+    async fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError>;
+
+    /// Perform the request, handing back the body as it arrives
+    ///
+    /// Defaults to buffering the body through [`HttpTransport::send`].
+    async fn send_streaming(
+        &self,
+        req: Request<Bytes>,
+    ) -> Result<Response<StreamingBody>, BpxError> {
+        let response = self.send(req).await?;
+        Ok(response.map(|body| Full::new(body).map_err(|never| match never {}).boxed()))
+    }
+}
+
+/// Response body delivered chunk by chunk
+pub type StreamingBody = BoxBody<Bytes, BpxError>;
+
+#[async_trait]
+impl<T: HttpTransport + ?Sized> HttpTransport for Arc<T> {
+    async fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+        (**self).send(req).await
+    }
+
+    async fn send_streaming(
+        &self,
+        req: Request<Bytes>,
+    ) -> Result<Response<StreamingBody>, BpxError> {
+        (**self).send_streaming(req).await
+    }
+}
+
+/// Transport backed by a pooled hyper client (HTTP/1.1 and HTTP/2)
+#[derive(Clone)]
+pub struct HyperTransport {
+    client: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl HyperTransport {
+    /// Create transport with a default connection pool
+    pub fn new() -> Self {
+        Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        }
+    }
+}
+
+impl Default for HyperTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl HttpTransport for HyperTransport {
+    async fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+        let (parts, body) = req.into_parts();
+        let response = self
+            .client
+            .request(Request::from_parts(parts, Full::new(body)))
+            .await
+            .map_err(|e| BpxError::Transport {
+                reason: e.to_string(),
+            })?;
+
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| BpxError::Transport {
+                reason: e.to_string(),
+            })?
+            .to_bytes();
+        Ok(Response::from_parts(parts, body))
+    }
+
+    async fn send_streaming(
+        &self,
+        req: Request<Bytes>,
+    ) -> Result<Response<StreamingBody>, BpxError> {
+        let (parts, body) = req.into_parts();
+        let response = self
+            .client
+            .request(Request::from_parts(parts, Full::new(body)))
+            .await
+            .map_err(|e| BpxError::Transport {
+                reason: e.to_string(),
+            })?;
+
+        Ok(response.map(|body| {
+            body.map_err(|e| BpxError::Transport {
+                reason: e.to_string(),
+            })
+            .boxed()
+        }))
+    }
+}
+
+/// Last known state of a resource on the client
+#[derive(Debug, Clone)]
+pub struct CachedResource {
+    /// Version the content corresponds to
+    pub version: Version,
+    /// Full content
+    pub content: Bytes,
+    /// Media type of the content, if the server sent one
+    pub content_type: Option<String>,
+}
+
+/// Outcome of a fetch
+#[derive(Debug, Clone)]
+pub struct FetchResult {
+    /// Current full content
+    pub content: Bytes,
+    /// Current version
+    pub version: Version,
+    /// Media type of the content, if the server sent one
+    pub content_type: Option<String>,
+    /// Whether the content was reconstructed from a diff
+    pub diff_applied: bool,
+    /// Body bytes received for this fetch, including any fallback refetch
+    pub bytes_received: usize,
+}
+
+/// Session, per-path bases and patch application shared by every client front-end
+///
+/// Paths are keys chosen by the front-end; [`BpxClient`] uses the request path.
+pub struct ClientCore {
+    diff_engine: Arc<dyn DiffEngine>,
+    accepted_formats: Vec<DiffFormat>,
+    verifier: Option<Arc<dyn SignatureVerifier>>,
+    cache: Arc<dyn ClientCache>,
+    observer: Option<Arc<dyn ClientObserver>>,
+    recovery: RecoveryPolicy,
+    savings: SavingsTracker,
+    max_output_size: usize,
+}
+
+impl ClientCore {
+    /// Create core with the default diff engine
+    pub fn new() -> Self {
+        Self {
+            diff_engine: Arc::new(SimilarDiffEngine::new()),
+            accepted_formats: vec![DiffFormat::BinaryDelta],
+            verifier: None,
+            cache: Arc::new(InMemoryClientCache::new()),
+            observer: None,
+            recovery: RecoveryPolicy::default(),
+            savings: SavingsTracker::default(),
+            max_output_size: DEFAULT_MAX_OUTPUT_SIZE,
+        }
+    }
+
+    /// Report recovery events to an observer
+    pub fn with_observer(mut self, observer: Arc<dyn ClientObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Change how patch failures are recovered from
+    pub fn with_recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Recovery policy in effect
+    pub fn recovery(&self) -> RecoveryPolicy {
+        self.recovery
+    }
+
+    /// Refuse diffs patching to more than `max_output_size` bytes, even if
+    /// the server announces more in `X-Original-Size` (default 64MB)
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.max_output_size = max_output_size;
+        self
+    }
+
+    /// Bytes the diff described by `meta` may patch to
+    pub(crate) fn output_limit(&self, meta: &ResponseMeta<'_>) -> usize {
+        meta.output_limit(self.max_output_size)
+    }
+
+    /// Forward an event to the observer, if any
+    pub fn emit(&self, event: &ClientEvent<'_>) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(event);
+        }
+    }
+
+    /// Count a completed fetch towards [`ClientCore::savings_report`]
+    pub fn record(&self, key: &str, result: &FetchResult) {
+        self.savings.record(key, result);
+    }
+
+    /// Bytes on the wire versus bytes reconstructed, per resource
+    pub fn savings_report(&self) -> SavingsReport {
+        self.savings.report()
+    }
+
+    /// Start counting from zero
+    pub fn reset_savings(&self) {
+        self.savings.reset();
+    }
+
+    /// Keep session and bases in a different cache (e.g. [`FileClientCache`])
+    pub fn with_cache(mut self, cache: Arc<dyn ClientCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Use a different diff engine to apply patches
+    pub fn with_diff_engine(mut self, diff_engine: Arc<dyn DiffEngine>) -> Self {
+        self.diff_engine = diff_engine;
+        self
+    }
+
+    /// Reject responses whose `X-BPX-Signature` does not verify
+    pub fn with_verifier(mut self, verifier: Arc<dyn SignatureVerifier>) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Current session ID, once the server has assigned one
+    pub fn session(&self) -> Option<SessionId> {
+        self.cache.session()
+    }
+
+    /// Last known state of a resource
+    pub fn cached(&self, key: &str) -> Option<CachedResource> {
+        self.cache.get(key)
+    }
+
+    /// Drop the cached base for a resource so the next fetch is a full one
+    pub fn forget(&self, key: &str) {
+        self.cache.remove(key);
+    }
+
+    /// Add session, base version and `Accept-Diff` headers to an outgoing request
+    pub fn prepare(&self, headers: &mut HeaderMap, base: Option<&CachedResource>) {
+        if let Some(session) = self.session()
+            && let Ok(value) = HeaderValue::from_str(&session.to_string())
+        {
+            headers.insert(BpxHeaders::SESSION, value);
+        }
+
+        match base {
+            Some(base) => {
+                if let Ok(value) = HeaderValue::from_str(&base.version.to_string()) {
+                    headers.insert(BpxHeaders::BASE_VERSION, value);
+                }
+                let accept = self
+                    .accepted_formats
+                    .iter()
+                    .map(DiffFormat::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if let Ok(value) = HeaderValue::from_str(&accept) {
+                    headers.insert(BpxHeaders::ACCEPT_DIFF, value);
+                }
+            }
+            None => {
+                headers.remove(BpxHeaders::BASE_VERSION);
+                headers.remove(BpxHeaders::ACCEPT_DIFF);
+            }
+        }
+    }
+
+    /// Adopt the session the server assigned, dropping bases if it changed
+    /// or the server says it issued a new one
+    fn track_session(&self, meta: &ResponseMeta) {
+        let Some(session) = meta.session else {
+            return;
+        };
+        let session = SessionId::new(session.to_string());
+        let previous = self.cache.session();
+        if previous.as_ref() == Some(&session) && meta.session_created() {
+            self.cache.clear();
+        } else if previous.as_ref() != Some(&session) {
+            if previous.is_some() {
+                // The server lost our session; bases recorded under it are stale
+                self.cache.clear();
+                self.emit(&ClientEvent::SessionChanged {
+                    previous: previous.as_ref(),
+                    current: &session,
+                });
+            }
+            self.cache.set_session(session);
+        }
+    }
+
+    /// Start a fetch of a resource, which drives recovery across the
+    /// requests it takes
+    pub fn fetch<'a>(&'a self, key: &'a str) -> Fetch<'a> {
+        Fetch::new(self, key)
+    }
+
+    /// Whether a fetch that failed with `error` should be repeated in full
+    ///
+    /// Only when the base it was made with proved unusable, or the server
+    /// refused the session it presented; other failures (a status such as
+    /// `404` or `503`, an unverified signature) would just repeat.
+    pub fn resyncs(&self, base: Option<&CachedResource>, error: &BpxError) -> bool {
+        match error {
+            BpxError::ClientStateNotFound { .. } => true,
+            BpxError::InvalidDiffFormat { .. } | BpxError::DiffComputationFailed { .. } => {
+                base.is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Turn a response into current content and remember it as the next base
+    pub fn accept(
+        &self,
+        key: &str,
+        base: Option<&CachedResource>,
+        response: &Response<Bytes>,
+    ) -> Result<FetchResult, BpxError> {
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND
+            && let Some(session) = self.session()
+            && response
+                .body()
+                .windows(NOT_FOUND_CODE.len())
+                .any(|window| window == NOT_FOUND_CODE)
+        {
+            // The server refused a session it doesn't know (see
+            // `UnknownSessionPolicy::Reject`); bases recorded under it are stale
+            self.cache.forget_session();
+            self.cache.clear();
+            return Err(BpxError::ClientStateNotFound { client_id: session });
+        }
+        if !status.is_success() {
+            return Err(BpxError::Transport {
+                reason: format!("server returned {}", status),
+            });
+        }
+
+        let headers = response.headers();
+        if let Some(verifier) = &self.verifier {
+            crate::signing::verify_response(headers, response.body(), verifier.as_ref())?;
+        }
+
+        let meta = ResponseMeta::from_headers(|name| headers.get(name)?.to_str().ok());
+        self.track_session(&meta);
+
+        let bytes_received = response.body().len();
+        let base_version = base.map(|b| b.version.to_string());
+        let result = reconstruct(
+            &meta,
+            base_version
+                .as_deref()
+                .zip(base.map(|b| b.content.as_ref())),
+            response.body().clone(),
+            |base, diff| {
+                let limits = PatchLimits::output(self.output_limit(&meta));
+                self.diff_engine.apply_diff_limited(base, diff, &limits)
+            },
+        )
+        .map_err(|e| match e {
+            ClientError::UnsupportedFormat(format) => BpxError::InvalidDiffFormat { format },
+            e => BpxError::DiffComputationFailed {
+                reason: e.to_string(),
+            },
+        })?;
+
+        let version = Version::new(result.version);
+        self.cache.put(
+            key,
+            CachedResource {
+                version: version.clone(),
+                content: result.content.clone(),
+                content_type: result.content_type.clone(),
+            },
+        );
+
+        Ok(FetchResult {
+            content: result.content,
+            version,
+            content_type: result.content_type,
+            diff_applied: result.diff_applied,
+            bytes_received,
+        })
+    }
+}
+
+impl Default for ClientCore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// BPX client tracking session and per-path base content
+pub struct BpxClient<T = HyperTransport> {
+    transport: T,
+    base_uri: String,
+    core: ClientCore,
+}
+
+impl BpxClient<HyperTransport> {
+    /// Create client for a server such as `http://127.0.0.1:8080`
+    pub fn new(base_uri: impl Into<String>) -> Self {
+        Self::with_transport(HyperTransport::new(), base_uri)
+    }
+}
+
+impl<T: HttpTransport> BpxClient<T> {
+    /// Create client sending requests through a custom transport
+    pub fn with_transport(transport: T, base_uri: impl Into<String>) -> Self {
+        Self {
+            transport,
+            base_uri: base_uri.into().trim_end_matches('/').to_string(),
+            core: ClientCore::new(),
+        }
+    }
+
+    /// Use a different diff engine to apply patches
+    pub fn with_diff_engine(mut self, diff_engine: Arc<dyn DiffEngine>) -> Self {
+        self.core = self.core.with_diff_engine(diff_engine);
+        self
+    }
+
+    /// Reject responses whose `X-BPX-Signature` does not verify
+    pub fn with_verifier(mut self, verifier: Arc<dyn SignatureVerifier>) -> Self {
+        self.core = self.core.with_verifier(verifier);
+        self
+    }
+
+    /// Keep session and bases in a different cache, e.g. one that survives restarts
+    pub fn with_cache(mut self, cache: Arc<dyn ClientCache>) -> Self {
+        self.core = self.core.with_cache(cache);
+        self
+    }
+
+    /// Current session ID, once the server has assigned one
+    pub fn session(&self) -> Option<SessionId> {
+        self.core.session()
+    }
+
+    /// Last known state of a path
+    pub fn cached(&self, path: &str) -> Option<CachedResource> {
+        self.core.cached(path)
+    }
+
+    /// Drop the cached base for a path so the next fetch is a full one
+    pub fn forget(&self, path: &str) {
+        self.core.forget(path);
+    }
+
+    /// Report recovery events to an observer
+    pub fn with_observer(mut self, observer: Arc<dyn ClientObserver>) -> Self {
+        self.core = self.core.with_observer(observer);
+        self
+    }
+
+    /// Change how patch failures are recovered from
+    pub fn with_recovery(mut self, recovery: RecoveryPolicy) -> Self {
+        self.core = self.core.with_recovery(recovery);
+        self
+    }
+
+    /// Refuse diffs patching to more than `max_output_size` bytes
+    /// (see [`ClientCore::with_max_output_size`])
+    pub fn with_max_output_size(mut self, max_output_size: usize) -> Self {
+        self.core = self.core.with_max_output_size(max_output_size);
+        self
+    }
+
+    /// Bytes on the wire versus bytes reconstructed, per path
+    pub fn savings_report(&self) -> SavingsReport {
+        self.core.savings_report()
+    }
+
+    /// Fetch the current content of a path, using a diff when possible
+    ///
+    /// If the response cannot be applied, the base is dropped and the path is
+    /// refetched in full, up to [`RecoveryPolicy::max_resyncs`] times.
+    pub async fn get(&self, path: &str) -> Result<FetchResult, BpxError> {
+        let mut fetch = self.core.fetch(path);
+        loop {
+            let response = self.send(path, fetch.base()).await?;
+            if let Some(result) = fetch.accept(&response) {
+                return result;
+            }
+        }
+    }
+
+    /// Fetch a path like [`BpxClient::get`], patching the body as it arrives
+    ///
+    /// The returned stream yields reconstructed content chunk by chunk (it is
+    /// also an `AsyncRead`), so the diff is never buffered. The content becomes
+    /// the next base once the stream is read to the end. Problems detected
+    /// before the body is read are recovered from like [`BpxClient::get`];
+    /// once content has been yielded, a failure ends the stream with an error
+    /// and drops the base so the next fetch is a full one. With a signature
+    /// verifier configured the body must be verified first, so it is buffered.
+    pub async fn get_stream(&self, path: &str) -> Result<FetchStream<'_>, BpxError> {
+        if self.core.verifier.is_some() {
+            let result = self.get(path).await?;
+            return Ok(FetchStream::buffered(&self.core, result));
+        }
+
+        let mut fetch = self.core.fetch(path);
+        loop {
+            let response = self
+                .transport
+                .send_streaming(self.request(path, fetch.base())?)
+                .await?;
+            let stream = FetchStream::start(&self.core, path, fetch.base(), response);
+            if let Some(result) = fetch.settle(stream) {
+                return result;
+            }
+        }
+    }
+
+    /// Build and send a request carrying our session and base version
+    async fn send(
+        &self,
+        path: &str,
+        base: Option<&CachedResource>,
+    ) -> Result<Response<Bytes>, BpxError> {
+        self.transport.send(self.request(path, base)?).await
+    }
+
+    /// Build a request carrying our session and base version
+    fn request(
+        &self,
+        path: &str,
+        base: Option<&CachedResource>,
+    ) -> Result<Request<Bytes>, BpxError> {
+        let mut req = Request::builder()
+            .uri(format!("{}{}", self.base_uri, path))
+            .body(Bytes::new())
+            .map_err(|e| BpxError::InvalidRequest {
+                reason: e.to_string(),
+            })?;
+        self.core.prepare(req.headers_mut(), base);
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BpxConfig, InMemoryResourceStore, ResourcePath, StateManager,
+        server::{error_response, handle_bpx_request},
+        state::InMemoryStateManager,
+    };
+
+    /// Transport calling the server handler in-process
+    struct LoopbackTransport {
+        config: BpxConfig,
+        state_mgr: Arc<dyn StateManager>,
+        diff_engine: Arc<dyn DiffEngine>,
+        store: Arc<InMemoryResourceStore>,
+        /// Corrupt diff bodies to exercise the fallback path
+        corrupt_diffs: bool,
+    }
+
+    impl LoopbackTransport {
+        fn new(store: Arc<InMemoryResourceStore>) -> Self {
+            let config = BpxConfig::default();
+            Self {
+                state_mgr: Arc::new(InMemoryStateManager::new(config.clone())),
+                config,
+                diff_engine: Arc::new(SimilarDiffEngine::new()),
+                store,
+                corrupt_diffs: false,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HttpTransport for LoopbackTransport {
+        async fn send(&self, req: Request<Bytes>) -> Result<Response<Bytes>, BpxError> {
+            let req = req.map(Full::new);
+            let response = match handle_bpx_request(
+                req,
+                &self.config,
+                self.state_mgr.clone(),
+                self.diff_engine.clone(),
+                self.store.clone(),
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(e) => return Ok(error_response(&e)),
+            };
+            let is_diff = response
+                .headers()
+                .get(BpxHeaders::DIFF_TYPE)
+                .is_some_and(|v| v != "full");
+            if self.corrupt_diffs && is_diff {
+                return Ok(response.map(|_| Bytes::from_static(b"garbage")));
+            }
+            Ok(response)
+        }
+
+        /// Deliver bodies in small frames, as a network would
+        async fn send_streaming(
+            &self,
+            req: Request<Bytes>,
+        ) -> Result<Response<StreamingBody>, BpxError> {
+            let response = self.send(req).await?;
+            Ok(response.map(|body| {
+                let frames = (0..body.len())
+                    .step_by(16)
+                    .map(|i| body.slice(i..(i + 16).min(body.len())))
+                    .collect();
+                Framed(frames).boxed()
+            }))
+        }
+    }
+
+    /// Body handing out one queued frame per poll
+    struct Framed(std::collections::VecDeque<Bytes>);
+
+    impl http_body::Body for Framed {
+        type Data = Bytes;
+        type Error = BpxError;
+
+        fn poll_frame(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<http_body::Frame<Bytes>, BpxError>>> {
+            std::task::Poll::Ready(self.0.pop_front().map(|b| Ok(http_body::Frame::data(b))))
+        }
+    }
+
+    fn lines(n: usize) -> Bytes {
+        Bytes::from((0..n).map(|i| format!("line {}\n", i)).collect::<String>())
+    }
+
+    #[tokio::test]
+    async fn test_full_then_diff() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+        store.set_content_type(path.clone(), "text/plain");
+
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "");
+
+        let first = client.get("/api/feed").await.unwrap();
+        assert!(!first.diff_applied);
+        assert_eq!(first.content, lines(100));
+        assert!(client.session().is_some());
+
+        store.set_resource(path.clone(), lines(101));
+        let second = client.get("/api/feed").await.unwrap();
+        assert!(second.diff_applied);
+        assert_eq!(second.content, lines(101));
+        assert_eq!(second.content_type.as_deref(), Some("text/plain"));
+        assert!(second.bytes_received < second.content.len());
+        assert_eq!(client.cached("/api/feed").unwrap().version, second.version);
+    }
+
+    #[tokio::test]
+    async fn test_application_supplied_versions() {
+        use tokio::io::AsyncReadExt;
+
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "");
+        let revision = |n: usize| Version::new(format!("rev-{}", n));
+
+        store.set_resource_versioned(path.clone(), revision(1), lines(100));
+        assert_eq!(client.get("/api/feed").await.unwrap().version, revision(1));
+
+        // Diffs to the application's versions are checked against the digest
+        store.set_resource_versioned(path.clone(), revision(2), lines(101));
+        let result = client.get("/api/feed").await.unwrap();
+        assert!(result.diff_applied);
+        assert_eq!(result.content, lines(101));
+        assert_eq!(result.version, revision(2));
+
+        store.set_resource_versioned(path.clone(), revision(3), lines(102));
+        let mut stream = client.get_stream("/api/feed").await.unwrap();
+        assert!(stream.diff_applied());
+        let mut content = Vec::new();
+        stream.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, lines(102));
+        drop(stream);
+        assert_eq!(client.cached("/api/feed").unwrap().version, revision(3));
+        assert_eq!(client.savings_report().paths["/api/feed"].diff_fetches, 2);
+    }
+
+    #[tokio::test]
+    async fn test_savings_report() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+        store.set_resource(ResourcePath::new("/api/other".to_string()), lines(10));
+
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "");
+        let first = client.get("/api/feed").await.unwrap();
+        store.set_resource(path, lines(101));
+        let second = client.get("/api/feed").await.unwrap();
+        client.get("/api/other").await.unwrap();
+
+        let report = client.savings_report();
+        let feed = report.paths["/api/feed"];
+        assert_eq!(feed.fetches, 2);
+        assert_eq!(feed.diff_fetches, 1);
+        assert_eq!(
+            feed.bytes_on_wire,
+            (first.bytes_received + second.bytes_received) as u64
+        );
+        assert_eq!(
+            feed.bytes_reconstructed,
+            (first.content.len() + second.content.len()) as u64
+        );
+        assert!(feed.savings_ratio() > 0.0);
+
+        let total = report.total();
+        assert_eq!(total.fetches, 3);
+        assert_eq!(total.bytes_saved(), feed.bytes_saved());
+    }
+
+    #[tokio::test]
+    async fn test_persistent_cache_resumes_with_diff() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+        let transport = Arc::new(LoopbackTransport::new(store.clone()));
+        let dir = std::env::temp_dir().join(format!("bpx-client-resume-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        {
+            let cache = Arc::new(FileClientCache::open(&dir).unwrap());
+            let client = BpxClient::with_transport(transport.clone(), "").with_cache(cache);
+            client.get("/api/feed").await.unwrap();
+        }
+
+        // A restarted client picks up session and base from disk
+        store.set_resource(path.clone(), lines(101));
+        let cache = Arc::new(FileClientCache::open(&dir).unwrap());
+        let client = BpxClient::with_transport(transport, "").with_cache(cache);
+        let result = client.get("/api/feed").await.unwrap();
+        assert!(result.diff_applied);
+        assert_eq!(result.content, lines(101));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_full_fetch_on_patch_failure() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+
+        let mut transport = LoopbackTransport::new(store.clone());
+        transport.corrupt_diffs = true;
+        let client = BpxClient::with_transport(transport, "");
+
+        client.get("/api/feed").await.unwrap();
+        store.set_resource(path.clone(), lines(101));
+
+        let result = client.get("/api/feed").await.unwrap();
+        assert!(!result.diff_applied);
+        assert_eq!(result.content, lines(101));
+    }
+
+    #[tokio::test]
+    async fn test_output_limit_refuses_oversized_patches() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "")
+            .with_max_output_size(100);
+
+        client.get("/api/feed").await.unwrap();
+        store.set_resource(path, lines(101));
+
+        // The diff would patch past the cap, so the content is fetched in full
+        let result = client.get("/api/feed").await.unwrap();
+        assert!(!result.diff_applied);
+        assert_eq!(result.content, lines(101));
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_patches_incrementally() {
+        use futures_core::Stream;
+        use tokio::io::AsyncReadExt;
+
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+        store.set_content_type(path.clone(), "text/plain");
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "");
+
+        let mut first = client.get_stream("/api/feed").await.unwrap();
+        assert!(!first.diff_applied());
+        let mut content = Vec::new();
+        first.read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, lines(100));
+
+        store.set_resource(path.clone(), lines(101));
+        let mut second = client.get_stream("/api/feed").await.unwrap();
+        assert!(second.diff_applied());
+        assert_eq!(second.content_type(), Some("text/plain"));
+        let mut chunks = 0;
+        let mut content = Vec::new();
+        while let Some(chunk) =
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut second).poll_next(cx)).await
+        {
+            content.extend_from_slice(&chunk.unwrap());
+            chunks += 1;
+        }
+        assert!(chunks > 1);
+        assert_eq!(content, lines(101));
+        assert!(second.bytes_received() < content.len());
+        drop(second);
+
+        // The streamed content became the next base
+        assert_eq!(
+            client.cached("/api/feed").unwrap().version,
+            Version::from_content(&lines(101))
+        );
+        assert_eq!(client.savings_report().paths["/api/feed"].diff_fetches, 1);
+        store.set_resource(path, lines(102));
+        assert!(client.get("/api/feed").await.unwrap().diff_applied);
+    }
+
+    #[tokio::test]
+    async fn test_get_stream_failure_drops_base() {
+        use tokio::io::AsyncReadExt;
+
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+
+        let mut transport = LoopbackTransport::new(store.clone());
+        transport.corrupt_diffs = true;
+        let client = BpxClient::with_transport(transport, "");
+        client.get("/api/feed").await.unwrap();
+        store.set_resource(path, lines(101));
+
+        let mut stream = client.get_stream("/api/feed").await.unwrap();
+        assert!(stream.read_to_end(&mut Vec::new()).await.is_err());
+        drop(stream);
+        assert!(client.cached("/api/feed").is_none());
+    }
+
+    /// Observer recording event names
+    #[derive(Default)]
+    struct RecordingObserver(std::sync::Mutex<Vec<String>>);
+
+    impl ClientObserver for RecordingObserver {
+        fn on_event(&self, event: &ClientEvent<'_>) {
+            let name = match event {
+                ClientEvent::SessionChanged { .. } => "session-changed",
+                ClientEvent::PatchFailed { .. } => "patch-failed",
+                ClientEvent::Resynced { .. } => "resynced",
+            };
+            self.0.lock().unwrap().push(name.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recovery_events() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), lines(100));
+
+        let mut transport = LoopbackTransport::new(store.clone());
+        transport.corrupt_diffs = true;
+        let observer = Arc::new(RecordingObserver::default());
+        let client = BpxClient::with_transport(transport, "").with_observer(observer.clone());
+
+        client.get("/api/feed").await.unwrap();
+        store.set_resource(path.clone(), lines(101));
+        client.get("/api/feed").await.unwrap();
+        assert_eq!(*observer.0.lock().unwrap(), ["patch-failed", "resynced"]);
+
+        // With recovery disabled the patch failure surfaces
+        let mut transport = LoopbackTransport::new(store.clone());
+        transport.corrupt_diffs = true;
+        let client = BpxClient::with_transport(transport, "")
+            .with_recovery(RecoveryPolicy { max_resyncs: 0 });
+        client.get("/api/feed").await.unwrap();
+        store.set_resource(path.clone(), lines(102));
+        assert!(client.get("/api/feed").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_loss_drops_bases() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(ResourcePath::new("/api/a".to_string()), lines(10));
+        store.set_resource(ResourcePath::new("/api/b".to_string()), lines(20));
+        let observer = Arc::new(RecordingObserver::default());
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "")
+            .with_observer(observer.clone());
+
+        client.get("/api/a").await.unwrap();
+        client.get("/api/b").await.unwrap();
+        let session = client.session().unwrap();
+
+        // A server restart forgets every session
+        let client = BpxClient {
+            transport: LoopbackTransport::new(store.clone()),
+            base_uri: String::new(),
+            core: client.core,
+        };
+        let result = client.get("/api/a").await.unwrap();
+        assert!(!result.diff_applied);
+        assert_ne!(client.session().unwrap(), session);
+        assert!(client.cached("/api/b").is_none());
+        assert!(client.cached("/api/a").is_some());
+        assert_eq!(*observer.0.lock().unwrap(), ["session-changed"]);
+    }
+
+    #[tokio::test]
+    async fn test_refused_session_is_dropped() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(ResourcePath::new("/api/a".to_string()), lines(10));
+        let client = BpxClient::with_transport(LoopbackTransport::new(store), "");
+        client.get("/api/a").await.unwrap();
+        let session = client.session().unwrap();
+
+        let refused = error_response(&BpxError::ClientStateNotFound {
+            client_id: session.clone(),
+        });
+        let result = client.core.accept("/api/a", None, &refused);
+        assert!(matches!(result, Err(BpxError::ClientStateNotFound { .. })));
+        assert!(client.session().is_none());
+        assert!(client.cached("/api/a").is_none());
+
+        // The next request is issued a new session
+        client.get("/api/a").await.unwrap();
+        assert_ne!(client.session().unwrap(), session);
+    }
+
+    #[tokio::test]
+    async fn test_error_status() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let observer = Arc::new(RecordingObserver::default());
+        let client = BpxClient::with_transport(LoopbackTransport::new(store.clone()), "")
+            .with_observer(observer.clone());
+
+        let result = client.get("/missing").await;
+        assert!(matches!(result, Err(BpxError::Transport { .. })));
+        assert!(client.cached("/missing").is_none());
+
+        // An error status isn't the base's fault, so isn't retried without it
+        let path = ResourcePath::new("/api/a".to_string());
+        store.set_resource(path.clone(), lines(10));
+        client.get("/api/a").await.unwrap();
+        store.remove_resource(&path);
+        let result = client.get("/api/a").await;
+        assert!(matches!(result, Err(BpxError::Transport { .. })));
+        assert!(observer.0.lock().unwrap().is_empty());
+    }
+}
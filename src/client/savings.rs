@@ -0,0 +1,95 @@
+//! Client-side bandwidth accounting
+
+use super::FetchResult;
+use dashmap::DashMap;
+use std::{collections::BTreeMap, ops::AddAssign};
+
+/// Bandwidth counters for one resource
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathSavings {
+    /// Successful fetches
+    pub fetches: u64,
+    /// Fetches answered with a diff
+    pub diff_fetches: u64,
+    /// Body bytes received, including refetches after patch failures
+    pub bytes_on_wire: u64,
+    /// Bytes of content handed to the application
+    pub bytes_reconstructed: u64,
+}
+
+impl PathSavings {
+    /// Bytes that did not have to be transferred
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_reconstructed.saturating_sub(self.bytes_on_wire)
+    }
+
+    /// Fraction of reconstructed bytes that did not have to be transferred
+    ///
+    /// Negative when recovery refetches cost more than diffs saved.
+    pub fn savings_ratio(&self) -> f64 {
+        if self.bytes_reconstructed == 0 {
+            return 0.0;
+        }
+        1.0 - self.bytes_on_wire as f64 / self.bytes_reconstructed as f64
+    }
+}
+
+impl AddAssign for PathSavings {
+    fn add_assign(&mut self, other: PathSavings) {
+        self.fetches += other.fetches;
+        self.diff_fetches += other.diff_fetches;
+        self.bytes_on_wire += other.bytes_on_wire;
+        self.bytes_reconstructed += other.bytes_reconstructed;
+    }
+}
+
+/// Snapshot of bandwidth counters per resource key
+#[derive(Debug, Clone, Default)]
+pub struct SavingsReport {
+    /// Counters by resource key, in key order
+    pub paths: BTreeMap<String, PathSavings>,
+}
+
+impl SavingsReport {
+    /// Counters summed over every resource
+    pub fn total(&self) -> PathSavings {
+        self.paths
+            .values()
+            .fold(PathSavings::default(), |mut total, path| {
+                total += *path;
+                total
+            })
+    }
+}
+
+/// Running counters kept by [`super::ClientCore`]
+#[derive(Default)]
+pub(crate) struct SavingsTracker {
+    paths: DashMap<String, PathSavings>,
+}
+
+impl SavingsTracker {
+    pub(crate) fn record(&self, key: &str, result: &FetchResult) {
+        let mut entry = self.paths.entry(key.to_string()).or_default();
+        *entry += PathSavings {
+            fetches: 1,
+            diff_fetches: u64::from(result.diff_applied),
+            bytes_on_wire: result.bytes_received as u64,
+            bytes_reconstructed: result.content.len() as u64,
+        };
+    }
+
+    pub(crate) fn report(&self) -> SavingsReport {
+        SavingsReport {
+            paths: self
+                .paths
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.paths.clear();
+    }
+}
@@ -0,0 +1,258 @@
+//! Streaming fetches that patch the body as it arrives
+
+use super::{CachedResource, ClientCore, FetchResult, StreamingBody};
+use crate::{BpxError, DiffFormat, Version};
+use bpx_client_core::{ClientError, ResponseMeta, patch::StreamingPatcher, version_of};
+use bytes::{Bytes, BytesMut};
+use futures_core::Stream;
+use http_body::Body;
+use http_body_util::{BodyExt, Full};
+use hyper::Response;
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Reconstructed content of one fetch, delivered as the response body arrives
+///
+/// Read it as a `Stream` of chunks or through `AsyncRead`. Reading to the end
+/// stores the content as the next base and counts it in the savings report.
+pub struct FetchStream<'a> {
+    core: &'a ClientCore,
+    key: String,
+    body: StreamingBody,
+    patcher: Option<StreamingPatcher>,
+    version: Option<String>,
+    /// Hash the patched content must have: the announced digest, else the
+    /// version
+    digest: Option<String>,
+    content_type: Option<String>,
+    output: BytesMut,
+    bytes_received: usize,
+    /// Chunk partially handed out through `AsyncRead`
+    unread: Bytes,
+    /// Whether the content still has to be stored once the body ends
+    settle: bool,
+    done: bool,
+}
+
+/// Map a reconstruction failure the way [`ClientCore::accept`] does
+fn client_error(e: ClientError) -> BpxError {
+    match e {
+        ClientError::UnsupportedFormat(format) => BpxError::InvalidDiffFormat { format },
+        e => BpxError::DiffComputationFailed {
+            reason: e.to_string(),
+        },
+    }
+}
+
+impl<'a> FetchStream<'a> {
+    /// Check the response headers and prepare to patch or pass through the body
+    pub(super) fn start(
+        core: &'a ClientCore,
+        key: &str,
+        base: Option<&CachedResource>,
+        response: Response<StreamingBody>,
+    ) -> Result<Self, BpxError> {
+        let status = response.status();
+        if !status.is_success() {
+            return Err(BpxError::Transport {
+                reason: format!("server returned {}", status),
+            });
+        }
+
+        let (parts, body) = response.into_parts();
+        let meta = ResponseMeta::from_headers(|name| parts.headers.get(name)?.to_str().ok());
+        core.track_session(&meta);
+
+        let (patcher, content_type) = if meta.is_diff() {
+            let base = base.ok_or_else(|| client_error(ClientError::MissingBase))?;
+            let diff_type = meta.diff_type.unwrap_or_default();
+            if diff_type != DiffFormat::BinaryDelta.as_str() {
+                return Err(client_error(ClientError::UnsupportedFormat(
+                    diff_type.to_string(),
+                )));
+            }
+            let held = base.version.to_string();
+            if let Some(delta_base) = meta.delta_base
+                && delta_base != held
+            {
+                return Err(client_error(ClientError::BaseMismatch {
+                    expected: delta_base.to_string(),
+                    held,
+                }));
+            }
+            (
+                Some(StreamingPatcher::with_max_output_size(
+                    base.content.clone(),
+                    core.output_limit(&meta),
+                )),
+                meta.original_content_type,
+            )
+        } else {
+            (None, meta.content_type)
+        };
+
+        Ok(Self {
+            core,
+            key: key.to_string(),
+            patcher,
+            version: meta.version.map(str::to_string),
+            digest: meta.content_digest.or(meta.version).map(str::to_string),
+            content_type: content_type.map(str::to_string),
+            body,
+            output: BytesMut::new(),
+            bytes_received: 0,
+            unread: Bytes::new(),
+            settle: true,
+            done: false,
+        })
+    }
+
+    /// Stream over content that was already fetched and stored
+    pub(super) fn buffered(core: &'a ClientCore, result: FetchResult) -> Self {
+        Self {
+            core,
+            key: String::new(),
+            body: Full::new(result.content)
+                .map_err(|never| match never {})
+                .boxed(),
+            patcher: None,
+            version: Some(result.version.to_string()),
+            digest: None,
+            content_type: result.content_type,
+            output: BytesMut::new(),
+            bytes_received: result.bytes_received,
+            unread: Bytes::new(),
+            settle: false,
+            done: false,
+        }
+    }
+
+    /// Media type of the content, if the server sent one
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    /// Whether the content is being reconstructed from a diff
+    pub fn diff_applied(&self) -> bool {
+        self.patcher.is_some()
+    }
+
+    /// Body bytes received so far
+    pub fn bytes_received(&self) -> usize {
+        self.bytes_received
+    }
+
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, BpxError>>> {
+        while !self.done {
+            let data = match Pin::new(&mut self.body).poll_frame(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(self.finish().err().map(Err)),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(self.fail(e)))),
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                },
+            };
+
+            self.bytes_received += data.len();
+            let chunk = match &mut self.patcher {
+                Some(patcher) => match patcher.push(&data) {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Poll::Ready(Some(Err(self.fail(client_error(e.into()))))),
+                },
+                None => data,
+            };
+            if chunk.is_empty() {
+                continue;
+            }
+            if self.settle {
+                self.output.extend_from_slice(&chunk);
+            }
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        Poll::Ready(None)
+    }
+
+    /// Verify the reconstructed content and store it as the next base
+    fn finish(&mut self) -> Result<(), BpxError> {
+        self.done = true;
+        if !self.settle {
+            return Ok(());
+        }
+
+        if let Some(patcher) = &self.patcher
+            && let Err(e) = patcher.finish()
+        {
+            return Err(self.fail(client_error(e.into())));
+        }
+        let content = self.output.split().freeze();
+        // A patched body must hash to what the server announced
+        if self.patcher.is_some()
+            && let Some(digest) = self.digest.take()
+            && digest != version_of(&content)
+        {
+            return Err(self.fail(client_error(ClientError::VersionMismatch(digest))));
+        }
+        let version = self.version.take().unwrap_or_else(|| version_of(&content));
+
+        let result = FetchResult {
+            content,
+            version: Version::new(version),
+            content_type: self.content_type.clone(),
+            diff_applied: self.patcher.is_some(),
+            bytes_received: self.bytes_received,
+        };
+        self.core.cache.put(
+            &self.key,
+            CachedResource {
+                version: result.version.clone(),
+                content: result.content.clone(),
+                content_type: result.content_type.clone(),
+            },
+        );
+        self.core.record(&self.key, &result);
+        Ok(())
+    }
+
+    /// End the stream and drop the base so the next fetch is a full one
+    fn fail(&mut self, e: BpxError) -> BpxError {
+        self.done = true;
+        if self.settle {
+            self.core.forget(&self.key);
+        }
+        e
+    }
+}
+
+impl Stream for FetchStream<'_> {
+    type Item = Result<Bytes, BpxError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_chunk(cx)
+    }
+}
+
+impl AsyncRead for FetchStream<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.unread.is_empty() {
+            match this.poll_chunk(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(Some(Ok(chunk))) => this.unread = chunk,
+            }
+        }
+        let take = this.unread.len().min(buf.remaining());
+        buf.put_slice(&this.unread.split_to(take));
+        Poll::Ready(Ok(()))
+    }
+}
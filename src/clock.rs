@@ -0,0 +1,78 @@
+//! Time source for session expiry and cache TTLs
+//!
+//! State managers and [`CachedStore`](crate::store::CachedStore) read the
+//! time through a [`Clock`] so tests can move it forward instead of
+//! sleeping; with the `test-util` feature, [`MockClock`] does that.
+
+#[cfg(any(test, feature = "test-util"))]
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    sync::{Arc, LazyLock},
+    time::Instant,
+};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// Current time
+    fn now(&self) -> Instant;
+}
+
+/// [`Clock`] reading the system's monotonic time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Shared [`SystemClock`], the default everywhere a clock is taken
+pub fn system() -> Arc<dyn Clock> {
+    static SYSTEM: LazyLock<Arc<dyn Clock>> = LazyLock::new(|| Arc::new(SystemClock));
+    Arc::clone(&SYSTEM)
+}
+
+/// [`Clock`] that only moves when told to
+///
+/// Starts at the time it was created.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: AtomicU64,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    /// Create a clock stopped at the current time
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: AtomicU64::new(0),
+        }
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.elapsed
+            .fetch_add(by.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_nanos(self.elapsed.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,361 @@
+//! `Content-Encoding` of full-body responses
+//!
+//! A diff is already small, but a full body sent as a fallback would
+//! otherwise go out larger than from a REST server behind compressing
+//! middleware. With [`BpxConfig::compression`](crate::BpxConfig::compression)
+//! set, [`BpxLayer`](crate::BpxLayer) compresses full bodies with the best
+//! encoding the client's `Accept-Encoding` allows, chunk by chunk as they
+//! stream.
+
+use crate::{BpxError, protocol::headers::BpxHeaders, server::ResourceBody};
+use bytes::Bytes;
+use futures_core::Stream;
+use http_body::Frame;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{
+    Response, StatusCode,
+    header::{self, HeaderValue},
+};
+use std::{
+    io::{self, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// A compression a client can accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ContentEncoding {
+    /// `br`
+    #[cfg_attr(feature = "serde", serde(rename = "br"))]
+    Brotli,
+    /// `zstd`
+    #[cfg_attr(feature = "serde", serde(rename = "zstd"))]
+    Zstd,
+    /// `gzip`
+    #[cfg_attr(feature = "serde", serde(rename = "gzip"))]
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// Token naming this encoding in `Accept-Encoding` and `Content-Encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+/// Which encodings full bodies are compressed with
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct CompressionConfig {
+    /// Encodings offered, most preferred first when the client weighs
+    /// several equally
+    pub encodings: Vec<ContentEncoding>,
+    /// Bodies smaller than this many bytes are sent as they are
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encodings: vec![
+                ContentEncoding::Brotli,
+                ContentEncoding::Zstd,
+                ContentEncoding::Gzip,
+            ],
+            min_size: 1024,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// The offered encoding `accept_encoding` weighs highest, if any
+    ///
+    /// Encodings the header doesn't name take the weight of `*`, if given.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<ContentEncoding> {
+        let weight = |token: &str| {
+            accept_encoding.split(',').find_map(|entry| {
+                let mut parts = entry.split(';');
+                if !parts.next()?.trim().eq_ignore_ascii_case(token) {
+                    return None;
+                }
+                let mut quality = 1.0;
+                for param in parts {
+                    if let Some((key, val)) = param.split_once('=')
+                        && key.trim().eq_ignore_ascii_case("q")
+                    {
+                        quality = val.trim().parse::<f32>().unwrap_or(0.0);
+                    }
+                }
+                Some(quality)
+            })
+        };
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let quality = weight(encoding.as_str())
+                .or_else(|| weight("*"))
+                .unwrap_or(0.0);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    /// Compress `response` if it's a full body of at least `min_size` bytes
+    /// and the client accepts one of `encodings`
+    ///
+    /// Diffs, event streams and responses already encoded are left alone.
+    pub fn compress(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<ResourceBody>,
+    ) -> Response<ResourceBody> {
+        let headers = response.headers();
+        let full = headers
+            .get(BpxHeaders::DIFF_TYPE)
+            .is_some_and(|format| format == "full");
+        let event_stream = headers
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|t| t.as_bytes().starts_with(b"text/event-stream"));
+        if response.status() != StatusCode::OK
+            || !full
+            || event_stream
+            || headers.contains_key(header::CONTENT_ENCODING)
+        {
+            return response;
+        }
+        let size = headers
+            .get(BpxHeaders::ORIGINAL_SIZE)
+            .and_then(|size| size.to_str().ok()?.parse::<u64>().ok())
+            .or_else(|| http_body::Body::size_hint(response.body()).exact());
+        if size.is_some_and(|size| size < self.min_size as u64) {
+            return response;
+        }
+        let Some(encoding) = accept_encoding
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| self.negotiate(value))
+        else {
+            return response;
+        };
+        let Ok(encoder) = Encoder::new(encoding) else {
+            return response;
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        parts
+            .headers
+            .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        let body = CompressedStream {
+            content: Box::pin(body.into_data_stream()),
+            encoder: Some(encoder),
+        };
+        Response::from_parts(parts, StreamBody::new(body).boxed_unsync())
+    }
+}
+
+/// Compressor writing into a buffer drained after every chunk
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Levels favor speed, as every full response is compressed afresh
+    fn new(encoding: ContentEncoding) -> io::Result<Self> {
+        Ok(match encoding {
+            ContentEncoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                5,
+                22,
+            ))),
+            ContentEncoding::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            ContentEncoding::Gzip => Self::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(6),
+            )),
+        })
+    }
+
+    /// Compress `chunk`, returning whatever output is ready
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    /// End the stream, returning the rest of the output
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Brotli(encoder) => encoder.into_inner(),
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::Gzip(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Body compressed as it streams
+struct CompressedStream {
+    content: Pin<Box<dyn Stream<Item = Result<Bytes, BpxError>> + Send>>,
+    /// `None` once the stream has ended
+    encoder: Option<Encoder>,
+}
+
+fn compression_error(e: io::Error) -> BpxError {
+    BpxError::Transport {
+        reason: format!("Failed to compress body: {}", e),
+    }
+}
+
+impl Stream for CompressedStream {
+    type Item = Result<Frame<Bytes>, BpxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if self.encoder.is_none() {
+                return Poll::Ready(None);
+            }
+            let next = match self.content.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(next) => next,
+            };
+            let output = match next {
+                Some(Ok(chunk)) => match self.encoder.as_mut().map(|e| e.write(&chunk)) {
+                    Some(Ok(output)) if output.is_empty() => continue,
+                    Some(Ok(output)) => Ok(output),
+                    Some(Err(e)) => Err(compression_error(e)),
+                    None => continue,
+                },
+                Some(Err(e)) => Err(e),
+                None => match self.encoder.take().map(Encoder::finish) {
+                    Some(Ok(output)) => return Poll::Ready(Some(Ok(Frame::data(output)))),
+                    Some(Err(e)) => Err(compression_error(e)),
+                    None => continue,
+                },
+            };
+            if output.is_err() {
+                self.encoder = None;
+            }
+            return Poll::Ready(Some(output.map(Frame::data)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::buffered_body;
+    use std::io::Read;
+
+    fn full_response(content: &'static [u8]) -> Response<ResourceBody> {
+        let mut response = Response::new(buffered_body(Bytes::from_static(content)));
+        let headers = response.headers_mut();
+        headers.insert(BpxHeaders::DIFF_TYPE, HeaderValue::from_static("full"));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        response
+    }
+
+    async fn body(response: Response<ResourceBody>) -> Vec<u8> {
+        response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let config = CompressionConfig::default();
+        assert_eq!(
+            config.negotiate("gzip, deflate, br, zstd"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            config.negotiate("br;q=0.5, gzip;q=0.8"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(config.negotiate("*"), Some(ContentEncoding::Brotli));
+        assert_eq!(
+            config.negotiate("br;q=0, *;q=0.1"),
+            Some(ContentEncoding::Zstd)
+        );
+        assert_eq!(config.negotiate("identity"), None);
+        assert_eq!(config.negotiate("deflate, gzip;q=0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_full_bodies_are_compressed() {
+        let content: &'static [u8] = "entry\n".repeat(1000).leak().as_bytes();
+        let config = CompressionConfig::default();
+
+        for (accept, encoding) in [("br", "br"), ("zstd", "zstd"), ("gzip", "gzip")] {
+            let accept = HeaderValue::from_static(accept);
+            let response = config.compress(Some(&accept), full_response(content));
+            assert_eq!(response.headers()[header::CONTENT_ENCODING], encoding);
+            assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+            let compressed = body(response).await;
+            assert!(compressed.len() < content.len() / 10);
+
+            let mut decoded = Vec::new();
+            match encoding {
+                "br" => {
+                    brotli::Decompressor::new(&compressed[..], 4096)
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                }
+                "zstd" => decoded = zstd::decode_all(&compressed[..]).unwrap(),
+                _ => {
+                    flate2::read::GzDecoder::new(&compressed[..])
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                }
+            }
+            assert_eq!(decoded, content);
+        }
+
+        // Small bodies, diffs and clients accepting nothing are left alone
+        let gzip = HeaderValue::from_static("gzip");
+        let small = config.compress(Some(&gzip), full_response(b"small"));
+        assert!(!small.headers().contains_key(header::CONTENT_ENCODING));
+        let mut diff = full_response(content);
+        diff.headers_mut().insert(
+            BpxHeaders::DIFF_TYPE,
+            HeaderValue::from_static("binary-delta"),
+        );
+        let diff = config.compress(Some(&gzip), diff);
+        assert!(!diff.headers().contains_key(header::CONTENT_ENCODING));
+        let plain = config.compress(None, full_response(content));
+        assert!(!plain.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body(plain).await, content);
+    }
+}
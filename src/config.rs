@@ -0,0 +1,348 @@
+//! Loading a [`BpxConfig`] from the environment or a file
+//!
+//! [`BpxConfig::from_env`] reads the scalar settings from `BPX_*` variables
+//! (e.g. `BPX_MAX_SESSIONS=50000`, `BPX_SESSION_TTL=2h`). With the `toml`
+//! feature, [`BpxConfig::from_toml`] reads every setting from a file whose
+//! keys are the field names; missing keys keep their defaults:
+//!
+//! ```toml
+//! max_sessions = 50000
+//! session_ttl = "2h"
+//! min_compression_ratio = 0.3
+//!
+//! [rate_limit]
+//! burst = 20
+//! per_second = 5
+//! per_peer = true
+//!
+//! [[path_methods]]
+//! prefix = "/mirror/"
+//! methods = ["GET", "HEAD"]
+//! ```
+//!
+//! Durations are written as a number with a unit (`ns`, `us`, `ms`, `s`,
+//! `m`, `h`, `d`), or as a bare number of seconds.
+
+use crate::{BpxConfig, BpxError};
+use std::time::Duration;
+
+/// Units a duration may be written in, largest first
+const UNITS: [(&str, Duration); 7] = [
+    ("d", Duration::from_secs(24 * 60 * 60)),
+    ("h", Duration::from_secs(60 * 60)),
+    ("m", Duration::from_secs(60)),
+    ("s", Duration::from_secs(1)),
+    ("ms", Duration::from_millis(1)),
+    ("us", Duration::from_micros(1)),
+    ("ns", Duration::from_nanos(1)),
+];
+
+/// Parse a duration such as `30s`, `5m` or `1500ms`; a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount: u32 = amount
+        .parse()
+        .map_err(|_| format!("invalid duration: {:?}", s))?;
+    let unit = match unit.trim() {
+        "" => Duration::from_secs(1),
+        unit => UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, unit)| *unit)
+            .ok_or_else(|| format!("unknown unit in duration: {:?}", s))?,
+    };
+    unit.checked_mul(amount)
+        .ok_or_else(|| format!("duration out of range: {:?}", s))
+}
+
+/// Write `duration` in the largest unit that holds it exactly, e.g. `24h`
+pub fn format_duration(duration: Duration) -> String {
+    if duration.is_zero() {
+        return "0s".to_string();
+    }
+    UNITS
+        .iter()
+        .filter(|(_, unit)| duration.as_nanos().is_multiple_of(unit.as_nanos()))
+        .map(|(name, unit)| format!("{}{}", duration.as_nanos() / unit.as_nanos(), name))
+        .next()
+        .unwrap_or_else(|| format!("{}ns", duration.as_nanos()))
+}
+
+impl BpxConfig {
+    /// Defaults overridden by the `BPX_*` environment variables that are set
+    ///
+    /// See [`merge_env`](Self::merge_env) for the variables read.
+    pub fn from_env() -> Result<Self, BpxError> {
+        Self::default().merge_env()
+    }
+
+    /// Override settings with the `BPX_*` environment variables that are set
+    ///
+    /// Reads `BPX_MAX_SESSIONS`, `BPX_MAX_RESOURCES_PER_SESSION`,
+    /// `BPX_MAX_MEMORY`, `BPX_SESSION_TTL`, `BPX_SESSION_GRACE`,
+    /// `BPX_MAX_DIFF_SIZE`, `BPX_MAX_DIFF_OPERATIONS`,
+    /// `BPX_MAX_PATCH_OUTPUT_SIZE`, `BPX_MAX_INSERT_BYTES`,
+    /// `BPX_MAX_BATCH_ENTRIES`, `BPX_MIN_COMPRESSION_RATIO`,
+    /// `BPX_CLEANUP_INTERVAL`, `BPX_RFC3229_MODE`, `BPX_SESSION_COOKIE`,
+    /// `BPX_SHUTDOWN_TIMEOUT`, `BPX_STATS_ENDPOINTS`, `BPX_ADMIN_TOKEN` and
+    /// `BPX_SLOW_DIFF_THRESHOLD`.
+    /// Settings that are lists or sections can only be set from a file.
+    pub fn merge_env(self) -> Result<Self, BpxError> {
+        self.merge_vars(std::env::vars().filter(|(name, _)| name.starts_with("BPX_")))
+    }
+
+    fn merge_vars(
+        mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, BpxError> {
+        fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, BpxError>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value.trim().parse().map_err(|e| BpxError::BuilderError {
+                reason: format!("{}={:?}: {}", name, value, e),
+            })
+        }
+        let duration = |name: &str, value: &str| {
+            parse_duration(value).map_err(|e| BpxError::BuilderError {
+                reason: format!("{}: {}", name, e),
+            })
+        };
+
+        for (name, value) in vars {
+            match name.as_str() {
+                "BPX_MAX_SESSIONS" => self.max_sessions = parse(&name, &value)?,
+                "BPX_MAX_RESOURCES_PER_SESSION" => {
+                    self.max_resources_per_session = parse(&name, &value)?
+                }
+                "BPX_MAX_MEMORY" => self.max_memory = parse(&name, &value)?,
+                "BPX_SESSION_TTL" => self.session_ttl = duration(&name, &value)?,
+                "BPX_SESSION_GRACE" => self.session_grace = duration(&name, &value)?,
+                "BPX_MAX_DIFF_SIZE" => self.max_diff_size = parse(&name, &value)?,
+                "BPX_MAX_DIFF_OPERATIONS" => self.max_diff_operations = parse(&name, &value)?,
+                "BPX_MAX_PATCH_OUTPUT_SIZE" => self.max_patch_output_size = parse(&name, &value)?,
+                "BPX_MAX_INSERT_BYTES" => self.max_insert_bytes = parse(&name, &value)?,
+                "BPX_MAX_BATCH_ENTRIES" => self.max_batch_entries = parse(&name, &value)?,
+                "BPX_MIN_COMPRESSION_RATIO" => self.min_compression_ratio = parse(&name, &value)?,
+                "BPX_CLEANUP_INTERVAL" => self.cleanup_interval = duration(&name, &value)?,
+                "BPX_RFC3229_MODE" => self.rfc3229_mode = parse(&name, &value)?,
+                "BPX_SESSION_COOKIE" => {
+                    self.session_cookie = Some(value).filter(|name| !name.is_empty())
+                }
+                "BPX_SHUTDOWN_TIMEOUT" => self.shutdown_timeout = duration(&name, &value)?,
+                "BPX_STATS_ENDPOINTS" => self.stats_endpoints = parse(&name, &value)?,
+                "BPX_ADMIN_TOKEN" => {
+                    self.admin_token = Some(value).filter(|token| !token.is_empty())
+                }
+                "BPX_SLOW_DIFF_THRESHOLD" => {
+                    self.slow_diff_threshold = Some(duration(&name, &value)?)
+                }
+                _ => {}
+            }
+        }
+        Ok(self)
+    }
+
+    /// Read settings from the TOML file at `path`; missing keys keep their
+    /// defaults
+    #[cfg(feature = "toml")]
+    pub fn from_toml(path: impl AsRef<std::path::Path>) -> Result<Self, BpxError> {
+        let path = path.as_ref();
+        let invalid = |reason: String| BpxError::BuilderError {
+            reason: format!("{}: {}", path.display(), reason),
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        toml::from_str(&text).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// `#[serde(with)]` for a [`Duration`] written as `30s`, `5m`, ...
+#[cfg(feature = "serde")]
+pub(crate) mod duration {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+    use std::time::Duration;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Written {
+        Secs(u64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_duration(*duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        match Written::deserialize(deserializer)? {
+            Written::Secs(secs) => Ok(Duration::from_secs(secs)),
+            Written::Text(text) => super::parse_duration(&text).map_err(D::Error::custom),
+        }
+    }
+}
+
+/// `#[serde(with)]` for an optional [`Duration`], written as by [`duration`]
+#[cfg(feature = "serde")]
+pub(crate) mod optional_duration {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    #[derive(Deserialize)]
+    struct Written(#[serde(with = "super::duration")] Duration);
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::duration::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<Written>::deserialize(deserializer)?.map(|Written(duration)| duration))
+    }
+}
+
+/// `#[serde(with)]` for methods written as their names, e.g. `"GET"`
+#[cfg(feature = "serde")]
+pub(crate) mod methods {
+    use hyper::Method;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(methods: &[Method], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(methods.iter().map(Method::as_str))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Method>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|name| Method::from_bytes(name.as_bytes()).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_roundtrip() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("1500ms").unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(parse_duration(" 2 h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("5 fortnights").is_err());
+        assert!(parse_duration("s").is_err());
+
+        assert_eq!(format_duration(Duration::from_secs(24 * 60 * 60)), "1d");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+    }
+
+    #[test]
+    fn test_env_overrides_defaults() {
+        let vars = [
+            ("BPX_MAX_SESSIONS", "500"),
+            ("BPX_SESSION_TTL", "2h"),
+            ("BPX_RFC3229_MODE", "true"),
+            ("BPX_SESSION_COOKIE", "bpx"),
+            ("BPX_SLOW_DIFF_THRESHOLD", "250ms"),
+            ("BPX_UNRELATED", "ignored"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = BpxConfig::default().merge_vars(vars).unwrap();
+        assert_eq!(config.max_sessions, 500);
+        assert_eq!(config.session_ttl, Duration::from_secs(7200));
+        assert!(config.rfc3229_mode);
+        assert_eq!(config.session_cookie.as_deref(), Some("bpx"));
+        assert_eq!(config.slow_diff_threshold, Some(Duration::from_millis(250)));
+        assert_eq!(config.max_memory, BpxConfig::default().max_memory);
+
+        let bad = [("BPX_MAX_SESSIONS".to_string(), "lots".to_string())];
+        let err = BpxConfig::default().merge_vars(bad).unwrap_err();
+        assert!(err.to_string().contains("BPX_MAX_SESSIONS"));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml() {
+        let path = std::env::temp_dir().join(format!("bpx-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            max_sessions = 500
+            session_ttl = "2h"
+            cleanup_interval = 30
+            version_storage = { sampled = 4 }
+            disabled_formats = ["element-delta"]
+            unknown_sessions = "reject"
+
+            [rate_limit]
+            burst = 20
+            per_second = 5
+            per_peer = true
+
+            [session_quota]
+            window = "1m"
+            max_bytes = 1000000
+            action = "reject"
+
+            [tenant_limits.acme]
+            max_sessions = 10
+
+            [[path_methods]]
+            prefix = "/mirror/"
+            methods = ["GET", "HEAD"]
+            "#,
+        )
+        .unwrap();
+        let config = BpxConfig::from_toml(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.max_sessions, 500);
+        assert_eq!(config.session_ttl, Duration::from_secs(7200));
+        assert_eq!(config.cleanup_interval, Duration::from_secs(30));
+        assert_eq!(config.version_storage, crate::VersionStorage::Sampled(4));
+        assert_eq!(config.disabled_formats, [crate::DiffFormat::ElementDelta]);
+        assert_eq!(config.rate_limit.as_ref().unwrap().burst, 20);
+        assert_eq!(config.unknown_sessions, crate::UnknownSessionPolicy::Reject);
+        let quota = config.session_quota.as_ref().unwrap();
+        assert_eq!(quota.window, Duration::from_secs(60));
+        assert_eq!(quota.action, crate::quota::QuotaAction::Reject);
+        let acme = &config.tenant_limits[&crate::TenantId::new("acme".to_string())];
+        assert_eq!(acme.max_sessions, 10);
+        assert_eq!(acme.max_memory, crate::TenantLimits::default().max_memory);
+        assert_eq!(
+            config.path_methods[0].methods,
+            [hyper::Method::GET, hyper::Method::HEAD]
+        );
+        assert_eq!(config.max_memory, BpxConfig::default().max_memory);
+
+        let roundtrip: BpxConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(roundtrip.session_ttl, config.session_ttl);
+        assert_eq!(roundtrip.path_methods, config.path_methods);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml_rejects_unknown_keys() {
+        let path =
+            std::env::temp_dir().join(format!("bpx-config-typo-{}.toml", std::process::id()));
+        std::fs::write(&path, "max_sesions = 500\n").unwrap();
+        let err = BpxConfig::from_toml(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("max_sesions"));
+    }
+}
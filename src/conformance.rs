@@ -0,0 +1,536 @@
+//! Golden exchange vectors for checking server implementations
+//!
+//! The vectors under [`vectors_dir`] (`client-core/vectors`) are canonical
+//! exchanges recorded from the reference server: what a client holds, the
+//! request it sends, and the response it gets. Those marked for servers are
+//! recorded as is, and [`check`] replays them against any [`Implementation`],
+//! so servers written in other languages can be validated against the same
+//! files clients are; the layout is described in `client-core/vectors/README.md`.
+//! [`Reference`] is the reference server itself, which passes every vector.
+
+use crate::{
+    BpxServer, InMemoryResourceStore, ResourcePath, protocol::headers::BpxHeaders,
+    server::error_response,
+};
+use async_trait::async_trait;
+use bpx_client_core::ClientState;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{Request, Response, StatusCode, header};
+use std::sync::Arc;
+
+pub use bpx_client_core::conformance::{SESSION, Vector, load_vectors, vectors_dir};
+
+/// Path every vector is exchanged on
+pub const PATH: &str = "/resource";
+
+/// A server under test
+///
+/// Implement this over an HTTP client and whatever lets the test set the
+/// content of [`PATH`] to check a server in another process or language.
+#[async_trait]
+pub trait Implementation: Send + Sync {
+    /// Make `content`, of `content_type` if given, the current content of
+    /// [`PATH`]
+    async fn set_resource(&self, content: Bytes, content_type: Option<&str>);
+
+    /// Answer `req`, errors included
+    async fn send(&self, req: Request<Bytes>) -> Response<Bytes>;
+}
+
+/// The reference server, with default configuration and in-memory state
+pub struct Reference {
+    server: BpxServer,
+    store: Arc<InMemoryResourceStore>,
+}
+
+impl Default for Reference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reference {
+    /// Create a server holding no resources or sessions
+    pub fn new() -> Self {
+        Self {
+            server: BpxServer::builder()
+                .build()
+                .expect("default configuration is valid"),
+            store: Arc::new(InMemoryResourceStore::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Implementation for Reference {
+    async fn set_resource(&self, content: Bytes, content_type: Option<&str>) {
+        let path = ResourcePath::new(PATH.to_string());
+        if let Some(content_type) = content_type {
+            self.store.set_content_type(path.clone(), content_type);
+        }
+        self.store.set_resource(path, content);
+    }
+
+    async fn send(&self, req: Request<Bytes>) -> Response<Bytes> {
+        match self
+            .server
+            .handle_request(req.map(Full::new), Arc::clone(&self.store))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => error_response(&e),
+        }
+    }
+}
+
+/// Headers of `response` a client interprets, with the session ID replaced
+/// by [`SESSION`]
+pub fn recorded_headers(response: &Response<Bytes>) -> Vec<(String, String)> {
+    response
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            *name == header::CONTENT_TYPE
+                || BpxHeaders::all()
+                    .iter()
+                    .any(|h| name.as_str().eq_ignore_ascii_case(h))
+        })
+        .map(|(name, value)| {
+            let value = if name.as_str().eq_ignore_ascii_case(BpxHeaders::SESSION) {
+                SESSION.to_string()
+            } else {
+                value.to_str().unwrap_or_default().to_string()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Media type the resource of `vector` is served with: that of its
+/// response, or on a diff that of the content it patches to
+fn content_type(vector: &Vector) -> Option<&str> {
+    let header = |name: &str| {
+        vector
+            .response_headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    if !(200..300).contains(&vector.status) {
+        return None;
+    }
+    header(BpxHeaders::ORIGINAL_CONTENT_TYPE).or_else(|| header(header::CONTENT_TYPE.as_str()))
+}
+
+/// Replay a server vector against `implementation`, describing the first
+/// mismatch
+///
+/// The response must have the vector's status and every header it lists,
+/// except that `X-Diff-Size` may differ and the session may have any ID. A
+/// diff needn't match the reference byte for byte, but applied to `base`
+/// it must give `expected`; a full body must be `expected` itself.
+pub async fn check<I: Implementation + ?Sized>(
+    vector: &Vector,
+    implementation: &I,
+) -> Result<(), String> {
+    let Ok(expected) = &vector.expected else {
+        return Err("vector names no content for the server to hold".to_string());
+    };
+    let content_type = content_type(vector);
+    let get = |headers: &[(String, String)]| {
+        let mut req = Request::get(PATH);
+        for (name, value) in headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        req.body(Bytes::new())
+            .map_err(|e| format!("invalid request header: {}", e))
+    };
+    let lookup = |response: &Response<Bytes>, name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    // Fetch the base in full, as the client that holds it did
+    let mut client = ClientState::new();
+    let mut session = None;
+    if let Some(base) = &vector.base {
+        implementation
+            .set_resource(base.clone(), content_type)
+            .await;
+        let first = implementation.send(get(&[])?).await;
+        if first.status() != StatusCode::OK {
+            return Err(format!("fetching base answered {}", first.status()));
+        }
+        session = lookup(&first, BpxHeaders::SESSION);
+        client
+            .apply_response(
+                PATH,
+                |name| first.headers().get(name)?.to_str().ok(),
+                first.body().clone(),
+            )
+            .map_err(|e| format!("fetching base: {}", e))?;
+    }
+
+    implementation
+        .set_resource(expected.clone(), content_type)
+        .await;
+    let request_headers: Vec<_> = vector
+        .request_headers
+        .iter()
+        .map(|(name, value)| match &session {
+            Some(session) if value == SESSION => (name.clone(), session.clone()),
+            _ => (name.clone(), value.clone()),
+        })
+        .collect();
+    let response = implementation.send(get(&request_headers)?).await;
+
+    if response.status().as_u16() != vector.status {
+        return Err(format!(
+            "status {}, expected {}",
+            response.status(),
+            vector.status
+        ));
+    }
+    for (name, value) in &vector.response_headers {
+        let got = lookup(&response, name);
+        let matches = if name.eq_ignore_ascii_case(BpxHeaders::SESSION) {
+            got.is_some()
+        } else if name.eq_ignore_ascii_case(BpxHeaders::DIFF_SIZE) {
+            true
+        } else {
+            got.as_deref() == Some(value.as_str())
+        };
+        if !matches {
+            return Err(format!(
+                "header {} is {:?}, expected {:?}",
+                name, got, value
+            ));
+        }
+    }
+    if !response.status().is_success() {
+        return Ok(());
+    }
+
+    let result = client
+        .apply_response(
+            PATH,
+            |name| response.headers().get(name)?.to_str().ok(),
+            response.body().clone(),
+        )
+        .map_err(|e| format!("response doesn't apply: {}", e))?;
+    if result.content != expected {
+        return Err("reconstructed content differs from expected".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpx_client_core::{conformance, version_of};
+    use std::fs;
+
+    const LOG: &[u8] = b"10:00:00 INFO started\n10:00:01 INFO listening on :3000\n10:00:02 INFO GET /api/feed 200 3ms\n10:00:03 INFO GET /api/feed 200 2ms\n10:00:04 INFO GET /api/users 200 5ms\n10:00:05 WARN slow query 120ms\n10:00:06 INFO GET /api/feed 200 2ms\n10:00:07 INFO GET /api/feed 200 4ms\n";
+    const LOG_APPENDED: &[u8] = b"10:00:00 INFO started\n10:00:01 INFO listening on :3000\n10:00:02 INFO GET /api/feed 200 3ms\n10:00:03 INFO GET /api/feed 200 2ms\n10:00:04 INFO GET /api/users 200 5ms\n10:00:05 WARN slow query 120ms\n10:00:06 INFO GET /api/feed 200 2ms\n10:00:07 INFO GET /api/feed 200 4ms\n10:00:08 INFO GET /api/feed 200 3ms\n";
+    const LOG_TRIMMED: &[u8] = b"10:00:00 INFO started\n10:00:01 INFO listening on :3000\n10:00:02 INFO GET /api/feed 200 3ms\n10:00:05 WARN slow query 120ms\n10:00:06 INFO GET /api/feed 200 2ms\n10:00:07 INFO GET /api/feed 200 4ms\n";
+    const JSON: &[u8] = br#"{
+  "user": {
+    "id": 42,
+    "name": "Bob",
+    "email": "bob@example.com",
+    "roles": ["reader", "writer"],
+    "settings": {
+      "theme": "dark",
+      "notifications": true,
+      "language": "en"
+    }
+  },
+  "updated": "2024-01-15T10:00:00Z"
+}
+"#;
+    const JSON_EDITED: &[u8] = br#"{
+  "user": {
+    "id": 42,
+    "name": "Robert",
+    "email": "bob@example.com",
+    "roles": ["reader", "writer"],
+    "settings": {
+      "theme": "dark",
+      "notifications": true,
+      "language": "en"
+    }
+  },
+  "updated": "2024-01-15T10:05:00Z"
+}
+"#;
+    /// Non-UTF-8 content with a few bytes changed in the middle when `edited`
+    fn binary(edited: bool) -> Vec<u8> {
+        let mut content = (0..1024u32)
+            .map(|i| (i * 37 % 256) as u8)
+            .collect::<Vec<_>>();
+        if edited {
+            content[500..504].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        }
+        content
+    }
+
+    /// Request headers of a client holding `base` under [`SESSION`]
+    fn holding(base: &[u8], accept: &str) -> Vec<(String, String)> {
+        vec![
+            (BpxHeaders::SESSION.to_string(), SESSION.to_string()),
+            (BpxHeaders::BASE_VERSION.to_string(), version_of(base)),
+            (BpxHeaders::ACCEPT_DIFF.to_string(), accept.to_string()),
+        ]
+    }
+
+    fn set_header(headers: &mut [(String, String)], name: &str, value: &str) {
+        for (n, v) in headers.iter_mut() {
+            if n.eq_ignore_ascii_case(name) {
+                *v = value.to_string();
+            }
+        }
+    }
+
+    /// Record one exchange from the reference server: optionally fetch
+    /// `base` in full, then send `request_headers` while it holds `expected`
+    async fn record(
+        name: &str,
+        base: Option<&[u8]>,
+        expected: &[u8],
+        content_type: &str,
+        request_headers: Vec<(String, String)>,
+    ) -> Vector {
+        let reference = Reference::new();
+        let mut sent = request_headers.clone();
+        if let Some(base) = base {
+            reference
+                .set_resource(Bytes::copy_from_slice(base), Some(content_type))
+                .await;
+            let first = reference
+                .send(Request::get(PATH).body(Bytes::new()).unwrap())
+                .await;
+            let session = first.headers()[BpxHeaders::SESSION].to_str().unwrap();
+            for (_, value) in sent.iter_mut().filter(|(_, value)| value == SESSION) {
+                *value = session.to_string();
+            }
+        }
+        reference
+            .set_resource(Bytes::copy_from_slice(expected), Some(content_type))
+            .await;
+        let mut req = Request::get(PATH);
+        for (name, value) in &sent {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let response = reference.send(req.body(Bytes::new()).unwrap()).await;
+        Vector {
+            name: name.to_string(),
+            base: base.map(Bytes::copy_from_slice),
+            request_headers,
+            status: response.status().as_u16(),
+            response_headers: recorded_headers(&response),
+            response_body: response.into_body(),
+            expected: Ok(Bytes::copy_from_slice(expected)),
+            client: true,
+            server: true,
+        }
+    }
+
+    /// Record the fetch of `new` by a client holding `base`, if any
+    async fn exchange(name: &str, base: Option<&[u8]>, new: &[u8], content_type: &str) -> Vector {
+        let request_headers = base.map_or_else(Vec::new, |base| holding(base, "binary-delta"));
+        record(name, base, new, content_type, request_headers).await
+    }
+
+    /// Record an exchange no client following the protocol makes
+    async fn server_only(
+        name: &str,
+        base: Option<&[u8]>,
+        request_headers: Vec<(String, String)>,
+    ) -> Vector {
+        Vector {
+            client: false,
+            ..record(name, base, LOG_APPENDED, "text/plain", request_headers).await
+        }
+    }
+
+    /// Every vector, recorded from the server and derived by tampering
+    async fn record_vectors() -> Vec<Vector> {
+        let full = exchange("full-initial", None, JSON, "application/json").await;
+        let unchanged = exchange("full-unchanged", Some(LOG), LOG, "text/plain").await;
+        let append = exchange("diff-append-line", Some(LOG), LOG_APPENDED, "text/plain").await;
+        let trim = exchange("diff-remove-lines", Some(LOG), LOG_TRIMMED, "text/plain").await;
+        let json = exchange(
+            "diff-json-edit",
+            Some(JSON),
+            JSON_EDITED,
+            "application/json",
+        )
+        .await;
+        let binary = exchange(
+            "diff-binary",
+            Some(&binary(false)),
+            &binary(true),
+            "application/octet-stream",
+        )
+        .await;
+
+        let format_not_accepted = server_only(
+            "full-format-not-accepted",
+            Some(LOG),
+            holding(LOG, "json-patch"),
+        )
+        .await;
+        let version_mismatch_full = server_only(
+            "full-version-mismatch",
+            Some(LOG),
+            holding(b"content the server never sent", "binary-delta"),
+        )
+        .await;
+        let unknown_session = server_only(
+            "full-unknown-session",
+            None,
+            vec![
+                (BpxHeaders::SESSION.to_string(), "sess_unknown".to_string()),
+                (BpxHeaders::BASE_VERSION.to_string(), version_of(LOG)),
+            ],
+        )
+        .await;
+        let invalid_header = server_only(
+            "error-invalid-header",
+            None,
+            vec![(BpxHeaders::SESSION.to_string(), "s".repeat(300))],
+        )
+        .await;
+
+        let tampered = |name: &str, expected: &str| Vector {
+            name: name.to_string(),
+            expected: Err(expected.to_string()),
+            server: false,
+            ..append.clone()
+        };
+
+        let missing_base = Vector {
+            base: None,
+            request_headers: Vec::new(),
+            ..tampered("error-missing-base", "missing-base")
+        };
+
+        let mut base_mismatch = tampered("error-base-mismatch", "base-mismatch");
+        set_header(
+            &mut base_mismatch.response_headers,
+            BpxHeaders::DELTA_BASE,
+            "v:0",
+        );
+
+        let mut unsupported = tampered("error-unsupported-format", "unsupported-format");
+        set_header(
+            &mut unsupported.response_headers,
+            BpxHeaders::DIFF_TYPE,
+            "json-patch",
+        );
+
+        // Drop END and start an INSERT whose length never arrives
+        let mut truncated = tampered("error-truncated-diff", "invalid-diff");
+        let mut body = append.response_body.to_vec();
+        body.pop();
+        body.extend_from_slice(&[0x02, 0x00]);
+        truncated.response_body = Bytes::from(body);
+
+        let mut version_mismatch = tampered("error-version-mismatch", "version-mismatch");
+        set_header(
+            &mut version_mismatch.response_headers,
+            BpxHeaders::RESOURCE_VERSION,
+            "v:0",
+        );
+
+        // A base too short for the diff's COPY operations
+        let short = Bytes::from_static(b"10:00:00 INFO started\n");
+        let mut copy_past_base = tampered("error-copy-past-base", "patch-failed");
+        set_header(
+            &mut copy_past_base.request_headers,
+            BpxHeaders::BASE_VERSION,
+            &version_of(&short),
+        );
+        set_header(
+            &mut copy_past_base.response_headers,
+            BpxHeaders::DELTA_BASE,
+            &version_of(&short),
+        );
+        copy_past_base.base = Some(short);
+
+        vec![
+            full,
+            unchanged,
+            append,
+            trim,
+            json,
+            binary,
+            format_not_accepted,
+            version_mismatch_full,
+            unknown_session,
+            invalid_header,
+            missing_base,
+            base_mismatch,
+            unsupported,
+            truncated,
+            version_mismatch,
+            copy_past_base,
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_vectors_match_reference_server() {
+        let recorded = record_vectors().await;
+        for vector in &recorded {
+            if vector.client {
+                // Every client vector but the full ones exercises a diff
+                let is_diff = vector.response_headers.iter().any(|(name, value)| {
+                    name.eq_ignore_ascii_case(BpxHeaders::DIFF_TYPE) && value != "full"
+                });
+                assert_eq!(
+                    is_diff,
+                    !vector.name.starts_with("full-"),
+                    "vector {}: {:?}",
+                    vector.name,
+                    vector.response_headers
+                );
+                assert_eq!(conformance::check(vector), Ok(()), "vector {}", vector.name);
+            }
+            if vector.server {
+                assert_eq!(
+                    check(vector, &Reference::new()).await,
+                    Ok(()),
+                    "vector {}",
+                    vector.name
+                );
+            }
+        }
+
+        let dir = vectors_dir();
+        if std::env::var_os("BPX_BLESS").is_some() {
+            for vector in &recorded {
+                let _ = fs::remove_dir_all(dir.join(&vector.name));
+                vector.write(&dir).unwrap();
+            }
+            return;
+        }
+
+        let shipped = load_vectors(&dir).unwrap();
+        let names = |vectors: &[Vector]| vectors.iter().map(|v| v.name.clone()).collect::<Vec<_>>();
+        let mut recorded_names = names(&recorded);
+        recorded_names.sort();
+        assert_eq!(names(&shipped), recorded_names);
+        for vector in &recorded {
+            let golden = shipped.iter().find(|v| v.name == vector.name).unwrap();
+            assert_eq!(
+                golden, vector,
+                "vector {} drifted from the server",
+                vector.name
+            );
+        }
+    }
+}
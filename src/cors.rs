@@ -0,0 +1,236 @@
+//! Cross-origin access for browser clients
+//!
+//! Browsers only let scripts read the `X-BPX-*` headers a client needs when
+//! the response exposes them, and only send them after a preflight allowing
+//! them. [`BpxLayer`](crate::BpxLayer) answers preflights and adds these
+//! headers once [`BpxConfig::cors`](crate::BpxConfig::cors) is set; servers
+//! built on the handlers directly can call [`CorsConfig::preflight`] and
+//! [`CorsConfig::apply`] themselves.
+
+use crate::protocol::headers::{BpxHeaders, DeltaHeaders};
+use bytes::Bytes;
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    header::{self, HeaderValue},
+};
+use std::time::Duration;
+
+/// Methods BPX answers
+const ALLOW_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, OPTIONS";
+
+/// Request headers BPX clients send
+const REQUEST_HEADERS: [&str; 9] = [
+    "Content-Type",
+    BpxHeaders::SESSION,
+    BpxHeaders::BASE_VERSION,
+    BpxHeaders::ACCEPT_DIFF,
+    BpxHeaders::DIFF_TYPE,
+    DeltaHeaders::A_IM,
+    "If-Match",
+    "If-None-Match",
+    "Last-Event-ID",
+];
+
+/// Response headers BPX clients read besides [`BpxHeaders::all`]
+const RESPONSE_HEADERS: [&str; 4] = [
+    "ETag",
+    "Retry-After",
+    DeltaHeaders::IM,
+    DeltaHeaders::DELTA_BASE,
+];
+
+/// Which origins may use the server from a browser
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct CorsConfig {
+    /// Origins allowed, e.g. `https://app.example.com`; `*` allows any
+    pub allow_origins: Vec<String>,
+    /// Let requests carry cookies (e.g. the session cookie); the origin is
+    /// then echoed instead of `*`
+    pub allow_credentials: bool,
+    /// Request headers allowed besides those BPX uses, e.g. `Authorization`
+    pub allow_headers: Vec<String>,
+    /// Response headers exposed besides those BPX sets
+    pub expose_headers: Vec<String>,
+    /// How long browsers may cache a preflight
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
+    pub max_age: Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allow_origins: vec!["*".to_string()],
+            allow_credentials: false,
+            allow_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Whether `req` is a preflight asking whether a request may be made
+    pub fn is_preflight<B>(req: &Request<B>) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ORIGIN)
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// Answer a preflight with `204`, allowing the request if its origin is
+    pub fn preflight<B>(&self, req: &Request<B>) -> Response<Bytes> {
+        let mut response = Response::new(Bytes::new());
+        *response.status_mut() = StatusCode::NO_CONTENT;
+        let origin = req.headers().get(header::ORIGIN);
+        if !self.apply(origin, response.headers_mut()) {
+            return response;
+        }
+
+        let allow_headers = REQUEST_HEADERS
+            .into_iter()
+            .chain(self.allow_headers.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let headers = response.headers_mut();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOW_METHODS),
+        );
+        if let Ok(value) = HeaderValue::from_str(&allow_headers) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+        }
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(self.max_age.as_secs()),
+        );
+        response
+    }
+
+    /// Add the headers letting `origin` read a response; returns whether it
+    /// is allowed
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) -> bool {
+        let any = self.allow_origins.iter().any(|o| o == "*");
+        let echo = !any || self.allow_credentials;
+        if echo {
+            // The answer depends on the origin; caches must keep them apart
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        let allowed = match origin {
+            None => return false,
+            Some(_) if !echo => HeaderValue::from_static("*"),
+            Some(origin)
+                if any
+                    || self
+                        .allow_origins
+                        .iter()
+                        .any(|o| o.as_bytes() == origin.as_bytes()) =>
+            {
+                origin.clone()
+            }
+            Some(_) => return false,
+        };
+
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        let expose = BpxHeaders::all()
+            .iter()
+            .copied()
+            .chain(RESPONSE_HEADERS)
+            .chain(self.expose_headers.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = HeaderValue::from_str(&expose) {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preflight(origin: &str) -> Request<()> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/api/feed")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_preflight_and_exposed_headers() {
+        let cors = CorsConfig::default();
+        let req = preflight("https://app.example.com");
+        assert!(CorsConfig::is_preflight(&req));
+        let response = cors.preflight(&req);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS]
+                .to_str()
+                .unwrap()
+                .contains(BpxHeaders::SESSION)
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "3600");
+        assert!(!headers.contains_key(header::VARY));
+
+        let mut headers = HeaderMap::new();
+        let origin = HeaderValue::from_static("https://app.example.com");
+        assert!(cors.apply(Some(&origin), &mut headers));
+        let expose = headers[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+            .to_str()
+            .unwrap();
+        assert!(expose.contains(BpxHeaders::RESOURCE_VERSION));
+        assert!(expose.contains("ETag"));
+
+        // Not a cross-origin request
+        let mut headers = HeaderMap::new();
+        assert!(!cors.apply(None, &mut headers));
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn test_listed_origins_with_credentials() {
+        let cors = CorsConfig {
+            allow_origins: vec!["https://app.example.com".to_string()],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        };
+        let response = cors.preflight(&preflight("https://app.example.com"));
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(headers[header::VARY], "Origin");
+
+        let response = cors.preflight(&preflight("https://evil.example.com"));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS)
+        );
+    }
+}
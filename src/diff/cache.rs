@@ -0,0 +1,209 @@
+//! Cache of computed diffs
+
+use super::{DiffEngine, DiffError, PatchLimits};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+type Key = [u8; 32];
+
+/// Cached diffs in least recently used order
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, (Bytes, u64)>,
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key) -> Option<Bytes> {
+        self.tick += 1;
+        let (diff, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, *key);
+        Some(diff.clone())
+    }
+
+    fn insert(&mut self, key: Key, diff: Bytes, max_bytes: usize) {
+        if diff.len() > max_bytes || self.entries.contains_key(&key) {
+            return;
+        }
+        self.tick += 1;
+        self.bytes += diff.len();
+        self.order.insert(self.tick, key);
+        self.entries.insert(key, (diff, self.tick));
+        self.shrink(max_bytes);
+    }
+
+    /// Evict the least recently used diffs until `max_bytes` are held
+    fn shrink(&mut self, max_bytes: usize) {
+        while self.bytes > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.len();
+            }
+        }
+    }
+}
+
+/// Any [`DiffEngine`] remembering the diffs it computed
+///
+/// Diffs are keyed by the content on both sides, so many clients moving
+/// between the same two versions cost one computation. Use
+/// [`BpxServer::prime`](crate::BpxServer::prime) to fill the cache before
+/// clients ask.
+pub struct CachingDiffEngine {
+    inner: Arc<dyn DiffEngine>,
+    max_bytes: AtomicUsize,
+    cache: Mutex<Lru>,
+}
+
+impl CachingDiffEngine {
+    /// Cache up to `max_bytes` of diffs computed by `inner`
+    pub fn new(inner: Arc<dyn DiffEngine>, max_bytes: usize) -> Self {
+        Self {
+            inner,
+            max_bytes: AtomicUsize::new(max_bytes),
+            cache: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Bytes of diffs held in the cache
+    pub fn cached_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    /// Bytes of diffs the cache may hold
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Hold up to `max_bytes` of diffs from now on, evicting the least
+    /// recently used ones above it
+    pub fn set_max_bytes(&self, max_bytes: usize) {
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+        self.lock().shrink(max_bytes);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Identify a pair of contents; the length keeps `old` and `new` apart
+pub(super) fn key(old: &[u8], new: &[u8]) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update((old.len() as u64).to_le_bytes());
+    hasher.update(old);
+    hasher.update(new);
+    hasher.finalize().into()
+}
+
+impl DiffEngine for CachingDiffEngine {
+    fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        let key = key(old, new);
+        if let Some(diff) = self.lock().get(&key) {
+            return Ok(diff);
+        }
+        // Computed outside the lock; racing requests may both compute it
+        let diff = self.inner.compute_diff(old, new)?;
+        self.lock().insert(key, diff.clone(), self.max_bytes());
+        Ok(diff)
+    }
+
+    fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        self.inner.apply_diff(base, diff)
+    }
+
+    fn apply_diff_limited(
+        &self,
+        base: &[u8],
+        diff: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
+        self.inner.apply_diff_limited(base, diff, limits)
+    }
+
+    fn is_diff_worthwhile(&self, original_size: usize, diff_size: usize) -> bool {
+        self.inner.is_diff_worthwhile(original_size, diff_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::similar::SimilarDiffEngine;
+
+    /// Engine counting the diffs it computes
+    #[derive(Default)]
+    struct Counting {
+        engine: SimilarDiffEngine,
+        diffs: AtomicUsize,
+    }
+
+    impl DiffEngine for Counting {
+        fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+            self.diffs.fetch_add(1, Ordering::Relaxed);
+            self.engine.compute_diff(old, new)
+        }
+
+        fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+            self.engine.apply_diff(base, diff)
+        }
+    }
+
+    #[test]
+    fn test_diffs_are_computed_once() {
+        let counting = Arc::new(Counting::default());
+        let inner: Arc<dyn DiffEngine> = counting.clone();
+        let engine = CachingDiffEngine::new(inner, 1024);
+
+        let first = engine.compute_diff(b"a\nb\n", b"a\nc\n").unwrap();
+        let second = engine.compute_diff(b"a\nb\n", b"a\nc\n").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(counting.diffs.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.apply_diff(b"a\nb\n", &first).unwrap(), "a\nc\n");
+
+        // Same bytes split differently between the two sides
+        engine.compute_diff(b"a\n", b"b\na\nc\n").unwrap();
+        assert_eq!(counting.diffs.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_cache_stays_within_size() {
+        let counting = Arc::new(Counting::default());
+        let inner: Arc<dyn DiffEngine> = counting.clone();
+        let size = inner.compute_diff(b"0\n", b"1\n").unwrap().len();
+        let engine = CachingDiffEngine::new(inner, size * 2);
+
+        for i in 0..3 {
+            engine
+                .compute_diff(b"0\n", format!("{}\n", i + 1).as_bytes())
+                .unwrap();
+        }
+        assert_eq!(engine.cached_bytes(), size * 2);
+
+        // The oldest was evicted, the most recent is still cached
+        let computed = counting.diffs.load(Ordering::Relaxed);
+        engine.compute_diff(b"0\n", b"3\n").unwrap();
+        assert_eq!(counting.diffs.load(Ordering::Relaxed), computed);
+        engine.compute_diff(b"0\n", b"1\n").unwrap();
+        assert_eq!(counting.diffs.load(Ordering::Relaxed), computed + 1);
+
+        engine.set_max_bytes(size);
+        assert_eq!(engine.max_bytes(), size);
+        assert_eq!(engine.cached_bytes(), size);
+        engine.compute_diff(b"0\n", b"1\n").unwrap();
+        assert_eq!(counting.diffs.load(Ordering::Relaxed), computed + 1);
+    }
+}
@@ -0,0 +1,26 @@
+//! Element-level diffs of collection resources
+//!
+//! List-shaped resources such as `/api/orders` mostly change by a few
+//! elements at a time. Served as collections (see
+//! [`InMemoryResourceStore::set_collection`](crate::InMemoryResourceStore::set_collection)),
+//! clients accepting `element-delta` receive the added, removed and updated
+//! elements instead of a byte diff.
+
+use super::{DiffEngine, DiffError};
+use bytes::Bytes;
+
+pub use bpx_client_core::collection::{COLLECTION_MEDIA_TYPE, Element, ElementDiffCodec};
+
+/// Diff engine for collection content, producing `element-delta` diffs
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ElementDiffEngine;
+
+impl DiffEngine for ElementDiffEngine {
+    fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        ElementDiffCodec::compute_diff(old, new)
+    }
+
+    fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        ElementDiffCodec::apply_diff(base, diff)
+    }
+}
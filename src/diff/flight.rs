@@ -0,0 +1,283 @@
+//! Sharing one computation among concurrent identical diffs
+
+use super::{DiffEngine, DiffError, PatchLimits, cache::key};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+use tokio::sync::Notify;
+
+/// Outcome of a diff, once its computation finishes
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<Result<Bytes, DiffError>>>,
+    done: Condvar,
+    /// Wakes requests waiting from async code
+    landed: Notify,
+}
+
+impl Flight {
+    fn result(&self) -> MutexGuard<'_, Option<Result<Bytes, DiffError>>> {
+        self.result.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Any [`DiffEngine`] computing each diff once however many ask at a time
+///
+/// When clients holding the same base poll a resource together, each of them
+/// needs the diff from that base to the current version. The first request
+/// computes it and the others wait for its result instead of repeating the
+/// work. Diffs are keyed by the content on both sides, which is what the base
+/// and current versions name. The server waits through
+/// [`DiffEngine::compute_diff_async`], which yields the thread to other
+/// requests meanwhile. Nothing is kept once the computation finishes;
+/// wrap a [`CachingDiffEngine`](super::CachingDiffEngine) to also reuse diffs
+/// across polls.
+pub struct SingleFlightDiffEngine {
+    inner: Arc<dyn DiffEngine>,
+    flights: Mutex<HashMap<[u8; 32], Arc<Flight>>>,
+}
+
+impl SingleFlightDiffEngine {
+    /// Share the diffs `inner` computes among concurrent requests
+    pub fn new(inner: Arc<dyn DiffEngine>) -> Self {
+        Self {
+            inner,
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Number of diffs being computed
+    pub fn in_flight(&self) -> usize {
+        self.lock().len()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<[u8; 32], Arc<Flight>>> {
+        self.flights.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start the flight computing the diff of `key`, or join the one
+    /// already computing it
+    fn board(&self, key: [u8; 32]) -> Result<Landing<'_>, Arc<Flight>> {
+        let mut flights = self.lock();
+        match flights.get(&key) {
+            Some(flight) => Err(Arc::clone(flight)),
+            None => {
+                let flight = Arc::new(Flight::default());
+                flights.insert(key, Arc::clone(&flight));
+                Ok(Landing {
+                    engine: self,
+                    key,
+                    flight,
+                    result: None,
+                })
+            }
+        }
+    }
+}
+
+/// Ends a flight even if the computation panics or is abandoned, so waiters
+/// aren't stranded
+struct Landing<'a> {
+    engine: &'a SingleFlightDiffEngine,
+    key: [u8; 32],
+    flight: Arc<Flight>,
+    result: Option<Result<Bytes, DiffError>>,
+}
+
+impl Landing<'_> {
+    /// Hand `result` to the waiters and return it
+    fn finish(mut self, result: Result<Bytes, DiffError>) -> Result<Bytes, DiffError> {
+        let shared_result = shared(&result);
+        self.result = Some(result);
+        shared_result
+    }
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        self.engine.lock().remove(&self.key);
+        let result = self.result.take().unwrap_or_else(|| {
+            Err(DiffError::ComputationFailed(
+                "Diff computation did not finish".to_string(),
+            ))
+        });
+        *self.flight.result() = Some(result);
+        self.flight.done.notify_all();
+        self.flight.landed.notify_waiters();
+    }
+}
+
+/// Copy of an error for a request that waited on another's computation
+fn shared(result: &Result<Bytes, DiffError>) -> Result<Bytes, DiffError> {
+    match result {
+        Ok(diff) => Ok(diff.clone()),
+        Err(DiffError::InvalidFormat(e)) => Err(DiffError::InvalidFormat(e.clone())),
+        Err(DiffError::ComputationFailed(e)) => Err(DiffError::ComputationFailed(e.clone())),
+        Err(DiffError::PatchFailed(e)) => Err(DiffError::PatchFailed(e.clone())),
+        Err(DiffError::LimitExceeded(e)) => Err(DiffError::LimitExceeded(e.clone())),
+        Err(DiffError::OutputLimitExceeded(max)) => Err(DiffError::OutputLimitExceeded(*max)),
+    }
+}
+
+#[async_trait]
+impl DiffEngine for SingleFlightDiffEngine {
+    fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        let flight = match self.board(key(old, new)) {
+            Ok(landing) => return landing.finish(self.inner.compute_diff(old, new)),
+            Err(flight) => flight,
+        };
+        let mut result = flight.result();
+        while result.is_none() {
+            result = flight.done.wait(result).unwrap_or_else(|e| e.into_inner());
+        }
+        shared(result.as_ref().expect("flight landed"))
+    }
+
+    async fn compute_diff_async(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        let flight = match self.board(key(old, new)) {
+            Ok(landing) => return landing.finish(self.inner.compute_diff_async(old, new).await),
+            Err(flight) => flight,
+        };
+        loop {
+            // Listen before looking, so a landing in between isn't missed
+            let landed = flight.landed.notified();
+            tokio::pin!(landed);
+            landed.as_mut().enable();
+            if let Some(result) = flight.result().as_ref() {
+                return shared(result);
+            }
+            landed.await;
+        }
+    }
+
+    fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        self.inner.apply_diff(base, diff)
+    }
+
+    fn apply_diff_limited(
+        &self,
+        base: &[u8],
+        diff: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
+        self.inner.apply_diff_limited(base, diff, limits)
+    }
+
+    fn is_diff_worthwhile(&self, original_size: usize, diff_size: usize) -> bool {
+        self.inner.is_diff_worthwhile(original_size, diff_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::similar::SimilarDiffEngine;
+    use std::{
+        sync::{
+            Barrier,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    /// Slow engine counting the diffs it computes
+    #[derive(Default)]
+    struct Slow {
+        engine: SimilarDiffEngine,
+        diffs: AtomicUsize,
+    }
+
+    impl DiffEngine for Slow {
+        fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+            self.diffs.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(100));
+            if new.is_empty() {
+                return Err(DiffError::ComputationFailed("empty".to_string()));
+            }
+            self.engine.compute_diff(old, new)
+        }
+
+        fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+            self.engine.apply_diff(base, diff)
+        }
+    }
+
+    fn concurrently(engine: &SingleFlightDiffEngine, new: &[u8]) -> Vec<Result<Bytes, DiffError>> {
+        let barrier = Barrier::new(8);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        engine.compute_diff(b"a\nb\n", new)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_async_waiters_yield_the_thread() {
+        let slow = Arc::new(Slow::default());
+        let inner: Arc<dyn DiffEngine> = slow.clone();
+        let engine = Arc::new(SingleFlightDiffEngine::new(inner));
+        let leader = {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || engine.compute_diff(b"a\nb\n", b"a\nc\n"))
+        };
+        while engine.in_flight() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // More waiters than workers, and the workers still run other tasks
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let engine = Arc::clone(&engine);
+                tokio::spawn(async move { engine.compute_diff_async(b"a\nb\n", b"a\nc\n").await })
+            })
+            .collect();
+        tokio::time::timeout(Duration::from_millis(50), tokio::spawn(async {}))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let expected = leader.join().unwrap().unwrap();
+        for waiter in waiters {
+            assert_eq!(waiter.await.unwrap().unwrap(), expected);
+        }
+        assert_eq!(slow.diffs.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_diffs_are_computed_once() {
+        let slow = Arc::new(Slow::default());
+        let inner: Arc<dyn DiffEngine> = slow.clone();
+        let engine = SingleFlightDiffEngine::new(inner);
+
+        let results = concurrently(&engine, b"a\nc\n");
+        let expected = SimilarDiffEngine::new()
+            .compute_diff(b"a\nb\n", b"a\nc\n")
+            .unwrap();
+        for result in results {
+            assert_eq!(result.unwrap(), expected);
+        }
+        assert_eq!(slow.diffs.load(Ordering::Relaxed), 1);
+        assert_eq!(engine.in_flight(), 0);
+
+        // Failures are shared too, and nothing is remembered afterwards
+        let results = concurrently(&engine, b"");
+        assert!(
+            results
+                .iter()
+                .all(|r| matches!(r, Err(DiffError::ComputationFailed(_))))
+        );
+        assert_eq!(slow.diffs.load(Ordering::Relaxed), 2);
+        engine.compute_diff(b"a\nb\n", b"a\nc\n").unwrap();
+        assert_eq!(slow.diffs.load(Ordering::Relaxed), 3);
+    }
+}
@@ -1,30 +1,24 @@
 //! Diff algorithm
 
+use async_trait::async_trait;
 use bytes::Bytes;
-use thiserror::Error;
 
 pub mod binary;
+pub mod cache;
+pub mod collection;
+pub mod flight;
 pub mod similar;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
 
-pub use binary::{BinaryDiffCodec, DiffOperation};
-
-/// Errors that can occur during diff operations
-#[derive(Debug, Error)]
-pub enum DiffError {
-    /// Invalid diff format
-    #[error("Invalid diff format: {0}")]
-    InvalidFormat(String),
-
-    /// Diff computation failed
-    #[error("Diff computation failed: {0}")]
-    ComputationFailed(String),
-
-    /// Patch application failed
-    #[error("Patch application failed: {0}")]
-    PatchFailed(String),
-}
+pub use binary::{BinaryDiffCodec, DiffOperation, PatchApplier, PatchLimits};
+pub use bpx_client_core::DiffError;
+pub use cache::CachingDiffEngine;
+pub use collection::ElementDiffEngine;
+pub use flight::SingleFlightDiffEngine;
 
 /// Trait for diff engines that can compute and apply binary diffs
+#[async_trait]
 pub trait DiffEngine: Send + Sync {
     /// Compute binary diff between old and new versions
     ///
@@ -39,6 +33,15 @@ pub trait DiffEngine: Send + Sync {
     /// Returns [`DiffError`] if diff computation fails
     fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError>;
 
+    /// Compute a diff from async code, as the server does
+    ///
+    /// Engines that may wait on work shared with other requests override
+    /// this to wait without holding the thread. Defaults to
+    /// [`compute_diff`](Self::compute_diff).
+    async fn compute_diff_async(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        self.compute_diff(old, new)
+    }
+
     /// Apply binary diff to base content
     ///
     /// # Arguments
@@ -52,6 +55,25 @@ pub trait DiffEngine: Send + Sync {
     /// Returns [`DiffError`] if patch application fails
     fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError>;
 
+    /// Apply a diff from an untrusted peer within `limits`
+    ///
+    /// Fails with [`DiffError::LimitExceeded`] past them, or with
+    /// [`DiffError::OutputLimitExceeded`] past their output size. The default
+    /// applies the diff in full and then checks the output size; engines that
+    /// can check while decoding should override it.
+    fn apply_diff_limited(
+        &self,
+        base: &[u8],
+        diff: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
+        let output = self.apply_diff(base, diff)?;
+        if output.len() > limits.max_output_size {
+            return Err(DiffError::OutputLimitExceeded(limits.max_output_size));
+        }
+        Ok(output)
+    }
+
     /// Check if diff is worthwhile (provides sufficient compression)
     ///
     /// # Arguments
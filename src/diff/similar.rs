@@ -2,10 +2,10 @@
 
 use super::{
     DiffEngine, DiffError,
-    binary::{BinaryDiffCodec, DiffOperation},
+    binary::{BinaryDiffCodec, DiffOperation, PatchLimits},
 };
 use bytes::Bytes;
-use similar::{Algorithm, ChangeTag, TextDiff};
+use similar::{Algorithm, DiffTag, capture_diff_slices};
 
 /// Diff engine using the `similar` crate with line-based diffing
 pub struct SimilarDiffEngine {
@@ -22,16 +22,27 @@ impl SimilarDiffEngine {
     }
 
     /// Create new diff engine with custom compression ratio
+    ///
+    /// Servers decide by [`BpxConfig::min_compression_ratio`](crate::BpxConfig::min_compression_ratio)
+    /// instead; this ratio only applies to direct
+    /// [`is_diff_worthwhile`](DiffEngine::is_diff_worthwhile) calls.
     pub fn with_compression_ratio(min_compression_ratio: f32) -> Self {
         Self {
             min_compression_ratio: min_compression_ratio.clamp(0.0, 1.0),
         }
     }
+}
 
-    /// Convert bytes to string for text diffing
-    fn to_string(data: &[u8]) -> String {
-        String::from_utf8_lossy(data).into_owned()
-    }
+/// Split content into lines, each keeping its trailing `\n`
+///
+/// Works on raw bytes so non-UTF-8 content keeps its exact lengths.
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+/// Total length of a run of lines
+fn span_len(lines: &[&[u8]]) -> u32 {
+    lines.iter().map(|line| line.len() as u32).sum()
 }
 
 impl Default for SimilarDiffEngine {
@@ -47,38 +58,31 @@ impl DiffEngine for SimilarDiffEngine {
             return BinaryDiffCodec::encode_diff(&[]);
         }
 
-        let old_str = Self::to_string(old);
-        let new_str = Self::to_string(new);
-
-        let diff = TextDiff::configure()
-            .algorithm(Algorithm::Myers)
-            .diff_lines(&old_str, &new_str);
+        let old_lines = split_lines(old);
+        let new_lines = split_lines(new);
 
         let mut ops = Vec::new();
 
-        for change in diff.iter_all_changes() {
-            let text = change.value();
-            let bytes = text.as_bytes();
+        for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            let removed = span_len(&old_lines[old_range]);
+            let added = &new_lines[new_range];
 
-            match change.tag() {
-                ChangeTag::Equal => {
-                    if !bytes.is_empty() {
+            match tag {
+                DiffTag::Equal => {
+                    if removed > 0 {
                         ops.push(DiffOperation::Copy {
                             offset: 0,
-                            length: bytes.len() as u32,
+                            length: removed,
                         });
                     }
                 }
-                ChangeTag::Delete => {
-                    if !bytes.is_empty() {
-                        ops.push(DiffOperation::Delete {
-                            length: bytes.len() as u32,
-                        });
+                DiffTag::Delete | DiffTag::Insert | DiffTag::Replace => {
+                    if removed > 0 {
+                        ops.push(DiffOperation::Delete { length: removed });
                     }
-                }
-                ChangeTag::Insert => {
-                    if !bytes.is_empty() {
-                        ops.push(DiffOperation::Insert(bytes.to_vec()));
+                    if !added.is_empty() {
+                        ops.push(DiffOperation::Insert(added.concat()));
                     }
                 }
             }
@@ -88,6 +92,15 @@ impl DiffEngine for SimilarDiffEngine {
     }
 
     fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        self.apply_diff_limited(base, diff, &PatchLimits::UNLIMITED)
+    }
+
+    fn apply_diff_limited(
+        &self,
+        base: &[u8],
+        diff: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
         if diff.is_empty() {
             return Err(DiffError::PatchFailed("Empty diff".to_string()));
         }
@@ -95,10 +108,13 @@ impl DiffEngine for SimilarDiffEngine {
         // Check for minimal diff (just END marker)
         if diff.len() == 1 && diff[0] == 0x04 {
             // DiffOp::End as u8
+            if base.len() > limits.max_output_size {
+                return Err(DiffError::OutputLimitExceeded(limits.max_output_size));
+            }
             return Ok(Bytes::copy_from_slice(base));
         }
 
-        BinaryDiffCodec::apply_diff(base, diff)
+        BinaryDiffCodec::apply_diff_with_limits(base, diff, limits)
     }
 
     fn is_diff_worthwhile(&self, original_size: usize, diff_size: usize) -> bool {
@@ -148,4 +164,19 @@ mod tests {
         // Should not be worthwhile (only 10% savings)
         assert!(!engine.is_diff_worthwhile(1000, 900));
     }
+
+    #[test]
+    fn test_non_utf8_content() {
+        let engine = SimilarDiffEngine::new();
+        let old = (0..1024u32)
+            .map(|i| (i * 37 % 256) as u8)
+            .collect::<Vec<_>>();
+        let mut new = old.clone();
+        new[500..504].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        let diff = engine.compute_diff(&old, &new).unwrap();
+        let result = engine.apply_diff(&old, &diff).unwrap();
+
+        assert_eq!(result.as_ref(), new.as_slice());
+    }
 }
@@ -0,0 +1,183 @@
+//! Property tests any [`DiffEngine`] should pass
+//!
+//! Available with the `test-util` feature. [`check_engine`] runs an engine
+//! over generated [`byte_pair`]s and fails with the smallest pair whose
+//! diff doesn't round-trip; the strategies and assertions it is built from
+//! are exported for tests of their own.
+//!
+//! ```
+//! use bpx::diff::{similar::SimilarDiffEngine, testing::check_engine};
+//!
+//! check_engine(&SimilarDiffEngine::new());
+//! ```
+
+use super::{BinaryDiffCodec, DiffEngine, DiffOperation, PatchLimits};
+use proptest::{
+    collection::vec,
+    prelude::*,
+    test_runner::{TestCaseError, TestRunner},
+};
+
+/// Largest content [`check_engine`] generates
+const MAX_CONTENT_LEN: usize = 4096;
+
+/// Bytes text and JSON content is mostly made of
+const TEXT_BYTES: &[u8] = b"abcdefxyz0129 \n\t{}[]\":,.";
+
+/// Up to `max_len` bytes, either arbitrary or drawn from few distinct
+/// bytes, as text is
+pub fn content(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        vec(any::<u8>(), 0..=max_len),
+        vec(prop::sample::select(TEXT_BYTES), 0..=max_len),
+    ]
+}
+
+/// Any operations the wire format can carry, whether or not they apply to
+/// a given base
+///
+/// `Copy` offsets are always `0`: they aren't encoded.
+pub fn operations() -> impl Strategy<Value = Vec<DiffOperation>> {
+    let length = prop_oneof![0..=64u32, 0..=0xFF_FFFFu32];
+    let operation = prop_oneof![
+        length
+            .clone()
+            .prop_map(|length| DiffOperation::Copy { offset: 0, length }),
+        vec(any::<u8>(), 0..64).prop_map(DiffOperation::Insert),
+        length.prop_map(|length| DiffOperation::Delete { length }),
+    ];
+    vec(operation, 0..32)
+}
+
+/// Operations that apply to `base`: copies and deletes never run past its
+/// end, with inserts anywhere
+pub fn operations_for(base: Vec<u8>) -> impl Strategy<Value = Vec<DiffOperation>> {
+    let step = (0..3u8, 0..=64u32, vec(any::<u8>(), 0..16));
+    vec(step, 0..32).prop_map(move |steps| {
+        let mut remaining = base.len() as u32;
+        steps
+            .into_iter()
+            .map(|(kind, length, data)| {
+                let length = length.min(remaining);
+                match kind {
+                    0 => {
+                        remaining -= length;
+                        DiffOperation::Copy { offset: 0, length }
+                    }
+                    1 => {
+                        remaining -= length;
+                        DiffOperation::Delete { length }
+                    }
+                    _ => DiffOperation::Insert(data),
+                }
+            })
+            .collect()
+    })
+}
+
+/// Pairs of old and new content up to `max_len` bytes: unrelated, or the
+/// new one an edit of the old, as consecutive versions of a resource are
+pub fn byte_pair(max_len: usize) -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+    let edited = content(max_len)
+        .prop_flat_map(|old| (Just(old.clone()), operations_for(old)))
+        .prop_map(|(old, operations)| {
+            let new = BinaryDiffCodec::apply_operations(&old, &operations)
+                .expect("operations_for applies to its base")
+                .to_vec();
+            (old, new)
+        });
+    prop_oneof![
+        1 => (content(max_len), content(max_len)),
+        3 => edited,
+    ]
+}
+
+/// Why `engine`'s diff from `old` to `new` doesn't give back `new`
+fn round_trip(engine: &dyn DiffEngine, old: &[u8], new: &[u8]) -> Result<(), String> {
+    let diff = engine
+        .compute_diff(old, new)
+        .map_err(|e| format!("compute_diff failed: {}", e))?;
+    let patched = engine
+        .apply_diff(old, &diff)
+        .map_err(|e| format!("apply_diff failed: {}", e))?;
+    if patched != new {
+        return Err(format!(
+            "apply_diff gave {} bytes, expected {}",
+            patched.len(),
+            new.len()
+        ));
+    }
+    let limits = PatchLimits {
+        max_output_size: new.len(),
+        ..PatchLimits::UNLIMITED
+    };
+    match engine.apply_diff_limited(old, &diff, &limits) {
+        Ok(limited) if limited == new => Ok(()),
+        Ok(_) => Err("apply_diff_limited gave other content".to_string()),
+        Err(e) => Err(format!(
+            "apply_diff_limited failed within the output size: {}",
+            e
+        )),
+    }
+}
+
+/// Assert that applying `engine`'s diff from `old` to `new` to `old` gives
+/// `new`, also when limited to the size of `new`
+#[track_caller]
+pub fn assert_round_trip(engine: &dyn DiffEngine, old: &[u8], new: &[u8]) {
+    if let Err(reason) = round_trip(engine, old, new) {
+        panic!("diff doesn't round-trip: {}", reason);
+    }
+}
+
+/// Assert that `operations` decode to themselves once encoded
+#[track_caller]
+pub fn assert_codec_round_trip(operations: &[DiffOperation]) {
+    let encoded = BinaryDiffCodec::encode_diff(operations).expect("operations encode");
+    let decoded = BinaryDiffCodec::decode_diff(&encoded).expect("encoded operations decode");
+    assert_eq!(decoded, operations, "operations change once encoded");
+}
+
+/// Run [`assert_round_trip`] over generated [`byte_pair`]s
+///
+/// Panics with the smallest failing pair found. The number of cases is
+/// proptest's, `PROPTEST_CASES` overriding it.
+#[track_caller]
+pub fn check_engine(engine: &dyn DiffEngine) {
+    let result = TestRunner::default().run(&byte_pair(MAX_CONTENT_LEN), |(old, new)| {
+        round_trip(engine, &old, &new).map_err(TestCaseError::fail)
+    });
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{CachingDiffEngine, similar::SimilarDiffEngine};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_engines_round_trip() {
+        check_engine(&SimilarDiffEngine::new());
+        check_engine(&CachingDiffEngine::new(
+            Arc::new(SimilarDiffEngine::new()),
+            1 << 20,
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn test_codec_round_trips(operations in operations()) {
+            assert_codec_round_trip(&operations);
+        }
+
+        #[test]
+        fn test_operations_for_apply(
+            (base, operations) in content(256).prop_flat_map(|base| (Just(base.clone()), operations_for(base)))
+        ) {
+            prop_assert!(BinaryDiffCodec::apply_operations(&base, &operations).is_ok());
+        }
+    }
+}
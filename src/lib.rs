@@ -10,6 +10,7 @@
 //! - [`DiffEngine`] - Binary diff computation and application
 //! - [`StateManager`] - Client state tracking and management
 //! - [`BpxConfig`] - Configuration options
+//! - [`BpxClient`] - Client applying diffs against locally held versions
 //!
 //! ## Example Usage
 //!
@@ -29,26 +30,76 @@
 #![warn(clippy::all)]
 
 use bytes::Bytes;
-use dashmap::DashMap;
-use hyper::{Request, Response};
+use dashmap::{DashMap, mapref::entry::Entry};
+use hyper::{Method, Request, Response};
 use std::{
-    sync::{Arc, atomic::AtomicUsize},
-    time::{Duration, Instant},
+    collections::HashMap,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 use thiserror::Error;
 
+pub mod access_log;
+pub mod admin;
+pub mod affinity;
+pub mod audit;
+pub mod auth;
+pub mod binding;
+pub mod client;
+pub mod clock;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod config;
+pub mod conformance;
+pub mod cors;
 pub mod diff;
+pub mod load;
+pub mod metrics;
+pub mod observer;
 pub mod protocol;
+pub mod quota;
+pub mod rate_limit;
+pub mod serve;
 pub mod server;
+pub mod service;
+pub mod signing;
 pub mod state;
-
+pub mod stats;
+pub mod store;
+pub mod tenant;
+pub mod unknown_session;
+
+pub use access_log::AccessLog;
+pub use affinity::AffinityConfig;
+pub use audit::AuditLog;
+pub use auth::Authorizer;
+pub use binding::SessionBinding;
+pub use client::BpxClient;
+pub use cors::CorsConfig;
 pub use diff::DiffEngine;
-pub use protocol::{BpxRequest, BpxResponse, ResponseBody};
-pub use server::{InMemoryResourceStore, ResourceStore};
+pub use load::LoadLimits;
+pub use observer::{DiffDecision, Observer};
+pub use protocol::{
+    BpxRequest, BpxResponse, FallbackReason, ResponseBody, negotiate_format, parse_accept_diff,
+};
+pub use quota::SessionQuota;
+pub use rate_limit::RateLimit;
+pub use server::{InMemoryResourceStore, ResourceStore, ResourceUpdate, VersionRetention};
+pub use service::{BpxLayer, BpxService};
+pub use signing::{ResponseSigner, SignatureVerifier};
 pub use state::StateManager;
+pub use unknown_session::UnknownSessionPolicy;
 
 /// Session identifier for tracking client state
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct SessionId(String);
 
 impl SessionId {
@@ -75,8 +126,45 @@ impl std::fmt::Display for SessionId {
     }
 }
 
+/// Tenant (customer) that sessions are scoped to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct TenantId(String);
+
+impl TenantId {
+    /// Create a new tenant ID
+    pub fn new(id: String) -> Self {
+        Self(id)
+    }
+
+    /// Tenant of sessions created without one
+    pub fn default_tenant() -> Self {
+        Self("default".to_string())
+    }
+
+    /// Where this tenant's `path` is kept in a shared store: `/{tenant}{path}`
+    pub fn scope(&self, path: &ResourcePath) -> ResourcePath {
+        ResourcePath::new(format!("/{}{}", self.0, path))
+    }
+}
+
+impl std::fmt::Display for TenantId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Resource path for identifying resources within sessions
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct ResourcePath(String);
 
 impl ResourcePath {
@@ -84,6 +172,77 @@ impl ResourcePath {
     pub fn new(path: String) -> Self {
         Self(path)
     }
+
+    /// Identify the resource at `path_and_query` (e.g. `/api/items?page=1`)
+    ///
+    /// The query parameters `query` keeps are part of the identity, so
+    /// `?page=1` and `?page=2` are different resources; without any the
+    /// path stands alone.
+    pub fn canonical(path_and_query: &str, query: &QueryCanonicalization) -> Self {
+        let (path, params) = path_and_query
+            .split_once('?')
+            .unwrap_or((path_and_query, ""));
+        let mut params: Vec<&str> = params
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .filter(|param| query.keeps(param.split('=').next().unwrap_or_default()))
+            .collect();
+        if query.sort {
+            // Stable, so repeated parameters keep their relative order
+            params.sort_by_key(|param| param.split('=').next().unwrap_or_default());
+        }
+        if params.is_empty() {
+            Self(path.to_string())
+        } else {
+            Self(format!("{}?{}", path, params.join("&")))
+        }
+    }
+}
+
+/// Which query parameters tell resources apart, and in what order
+///
+/// Names are compared as they appear in the query, without percent-decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct QueryCanonicalization {
+    /// Keep only these parameters; `None` keeps all but `exclude`
+    pub include: Option<Vec<String>>,
+    /// Drop these parameters, e.g. cache busters and tracking tags
+    pub exclude: Vec<String>,
+    /// Sort parameters by name so `?a=1&b=2` and `?b=2&a=1` are one resource
+    pub sort: bool,
+}
+
+impl QueryCanonicalization {
+    /// Ignore query strings: every resource is identified by its path alone
+    pub fn ignore() -> Self {
+        Self {
+            include: Some(Vec::new()),
+            ..Self::default()
+        }
+    }
+
+    /// Whether parameter `name` is part of a resource's identity
+    pub fn keeps(&self, name: &str) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.iter().any(|n| n == name))
+            && !self.exclude.iter().any(|n| n == name)
+    }
+}
+
+impl Default for QueryCanonicalization {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: Vec::new(),
+            sort: true,
+        }
+    }
 }
 
 impl std::fmt::Display for ResourcePath {
@@ -93,7 +252,12 @@ impl std::fmt::Display for ResourcePath {
 }
 
 /// Version identifier for tracking resource versions
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 pub struct Version(String);
 
 impl Version {
@@ -104,12 +268,7 @@ impl Version {
 
     /// Generate version from content hash
     pub fn from_content(content: &[u8]) -> Self {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-
-        let mut hasher = DefaultHasher::new();
-        content.hash(&mut hasher);
-        Self(format!("v:{:x}", hasher.finish()))
+        Self(bpx_client_core::version_of(content))
     }
 
     /// Generate version from timestamp
@@ -132,22 +291,32 @@ impl std::fmt::Display for Version {
 
 /// Supported diff formats
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum DiffFormat {
     /// Binary delta format (most efficient)
     BinaryDelta,
     /// JSON patch format (RFC 6902)
     JsonPatch,
     /// BSD diff format
+    #[cfg_attr(feature = "serde", serde(rename = "bsdiff"))]
     BsdDiff,
+    /// Element adds, removes and updates of a collection (see [`diff::collection`])
+    ElementDelta,
 }
 
 impl DiffFormat {
     /// Parse diff format from string
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s.to_lowercase().as_str() {
             "binary-delta" => Some(Self::BinaryDelta),
             "json-patch" => Some(Self::JsonPatch),
             "bsdiff" => Some(Self::BsdDiff),
+            "element-delta" => Some(Self::ElementDelta),
             _ => None,
         }
     }
@@ -158,6 +327,17 @@ impl DiffFormat {
             Self::BinaryDelta => "binary-delta",
             Self::JsonPatch => "json-patch",
             Self::BsdDiff => "bsdiff",
+            Self::ElementDelta => "element-delta",
+        }
+    }
+
+    /// Media type of a diff body in this format
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Self::BinaryDelta => "application/vnd.bpx.binary-delta",
+            Self::JsonPatch => "application/json-patch+json",
+            Self::BsdDiff => "application/vnd.bpx.bsdiff",
+            Self::ElementDelta => "application/vnd.bpx.element-delta",
         }
     }
 }
@@ -168,8 +348,12 @@ pub struct BpxSession {
     pub id: SessionId,
     /// Resource versions tracked for this session
     pub resources: DashMap<ResourcePath, Version>,
-    /// Last access time for TTL enforcement
-    pub last_accessed: Instant,
+    /// Time source for access times and expiry
+    clock: Arc<dyn clock::Clock>,
+    /// Creation time, which `last_accessed` is relative to
+    created: Instant,
+    /// Nanoseconds from `created` to the last access, for TTL enforcement
+    last_accessed: AtomicU64,
     /// Current memory usage in bytes
     pub memory_usage: AtomicUsize,
 }
@@ -177,40 +361,512 @@ pub struct BpxSession {
 impl BpxSession {
     /// Create a new session
     pub fn new(id: SessionId) -> Self {
+        Self::with_clock(id, clock::system())
+    }
+
+    /// Create a new session timed by `clock`
+    pub fn with_clock(id: SessionId, clock: Arc<dyn clock::Clock>) -> Self {
         Self {
             id,
             resources: DashMap::new(),
-            last_accessed: Instant::now(),
+            created: clock.now(),
+            clock,
+            last_accessed: AtomicU64::new(0),
             memory_usage: AtomicUsize::new(0),
         }
     }
 
+    /// Recreate a session last accessed `idle` ago
+    pub fn restored(id: SessionId, idle: Duration) -> Self {
+        Self::restored_with_clock(id, idle, clock::system())
+    }
+
+    /// Recreate a session last accessed `idle` ago by `clock`
+    pub fn restored_with_clock(
+        id: SessionId,
+        idle: Duration,
+        clock: Arc<dyn clock::Clock>,
+    ) -> Self {
+        let session = Self::with_clock(id, clock);
+        Self {
+            created: session.created.checked_sub(idle).unwrap_or(session.created),
+            ..session
+        }
+    }
+
+    /// Last access time
+    pub fn last_accessed(&self) -> Instant {
+        self.created + Duration::from_nanos(self.last_accessed.load(Ordering::Relaxed))
+    }
+
+    /// Time since the last access
+    pub fn idle(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.last_accessed())
+    }
+
     /// Update last accessed time
-    pub fn touch(&mut self) {
-        self.last_accessed = Instant::now();
+    pub fn touch(&self) {
+        let since_created = self
+            .clock
+            .now()
+            .saturating_duration_since(self.created)
+            .as_nanos() as u64;
+        self.last_accessed
+            .fetch_max(since_created, Ordering::Relaxed);
     }
 
     /// Check if session has expired
     pub fn is_expired(&self, ttl: Duration) -> bool {
-        self.last_accessed.elapsed() > ttl
+        self.idle() > ttl
+    }
+
+    /// Bytes accounted for one tracked resource
+    pub fn entry_size(path: &ResourcePath, version: &Version) -> usize {
+        std::mem::size_of::<(ResourcePath, Version)>() + path.0.len() + version.0.len()
+    }
+
+    /// Track `version` for `path`, returning the change in `memory_usage`
+    pub fn set_resource(&self, path: ResourcePath, version: Version) -> isize {
+        let added = Self::entry_size(&path, &version);
+        let removed = self
+            .resources
+            .insert(path.clone(), version)
+            .map_or(0, |old| Self::entry_size(&path, &old));
+        if added >= removed {
+            self.memory_usage
+                .fetch_add(added - removed, Ordering::Relaxed);
+        } else {
+            self.memory_usage
+                .fetch_sub(removed - added, Ordering::Relaxed);
+        }
+        added as isize - removed as isize
+    }
+
+    /// Track `new` for `path` only if the tracked version is still `expected`
+    /// (`None`: nothing tracked), returning the change in `memory_usage`, or
+    /// `None` if another version was found
+    pub fn compare_and_set_resource(
+        &self,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> Option<isize> {
+        let added = Self::entry_size(path, &new);
+        let removed = match self.resources.entry(path.clone()) {
+            Entry::Occupied(mut entry) if Some(entry.get()) == expected => {
+                Self::entry_size(path, &entry.insert(new))
+            }
+            Entry::Vacant(entry) if expected.is_none() => {
+                entry.insert(new);
+                0
+            }
+            _ => return None,
+        };
+        self.memory_usage.fetch_add(added, Ordering::Relaxed);
+        self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+        Some(added as isize - removed as isize)
+    }
+
+    /// Stop tracking `path`, returning the bytes released
+    pub fn remove_resource(&self, path: &ResourcePath) -> usize {
+        let removed = self
+            .resources
+            .remove(path)
+            .map_or(0, |(path, version)| Self::entry_size(&path, &version));
+        self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+        removed
+    }
+
+    /// Total bytes accounted to this session, including the session itself
+    pub fn footprint(&self) -> usize {
+        std::mem::size_of::<Self>() + self.id.0.len() + self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// Zero `memory_usage`, returning the footprint it accounted for
+    ///
+    /// Lets whoever drops the session from its manager and a writer racing
+    /// with that release each byte exactly once.
+    pub fn release(&self) -> usize {
+        std::mem::size_of::<Self>() + self.id.0.len() + self.memory_usage.swap(0, Ordering::AcqRel)
     }
 }
 
 /// Configuration for BPX server
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
 pub struct BpxConfig {
     /// Maximum sessions to track concurrently
     pub max_sessions: usize,
     /// Maximum resources tracked per session
     pub max_resources_per_session: usize,
+    /// Cap on bytes of session state across all sessions; the least recently
+    /// used sessions are evicted to stay under it
+    pub max_memory: usize,
     /// Session TTL
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
     pub session_ttl: Duration,
+    /// Time past `session_ttl` during which an idle session's versions are
+    /// kept as stale; a request inside it revives the session, one after it
+    /// gets a new session
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
+    pub session_grace: Duration,
+    /// Overrides of `session_ttl` for the versions tracked for some paths
+    pub path_ttls: Vec<PathTtl>,
     /// Maximum size of resource to diff (larger returns full)
     pub max_diff_size: usize,
-    /// Minimum compression ratio to use diff
+    /// Operations a diff written with `PATCH` may hold
+    pub max_diff_operations: usize,
+    /// Bytes a `PATCH` may grow the resource to
+    pub max_patch_output_size: usize,
+    /// Bytes of new data a `PATCH` diff may insert in total
+    pub max_insert_bytes: usize,
+    /// Entries one batch exchange may ask for
+    pub max_batch_entries: usize,
+    /// Share of the full body a diff must save to be sent, e.g. 0.2 for 20%;
+    /// decides for every diff whatever the engine's own ratio
     pub min_compression_ratio: f32,
     /// Cleanup interval
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
     pub cleanup_interval: Duration,
+    /// Honor RFC 3229 `A-IM`/`If-None-Match` and answer with `226 IM Used`
+    /// instead of the X-BPX-* headers
+    pub rfc3229_mode: bool,
+    /// Carry the session ID in a cookie with this name (`Cookie`/`Set-Cookie`)
+    /// in addition to the `X-BPX-Session` header
+    pub session_cookie: Option<String>,
+    /// Limits for specific tenants (see [`state::TenantStateManager`])
+    pub tenant_limits: HashMap<TenantId, TenantLimits>,
+    /// Limits for tenants not in `tenant_limits`
+    pub default_tenant_limits: TenantLimits,
+    /// How long [`BpxServer::serve`] lets open connections finish their
+    /// requests after shutdown begins
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
+    pub shutdown_timeout: Duration,
+    /// Which query parameters are part of a requested resource's path
+    pub query: QueryCanonicalization,
+    /// Requests allowed per session and peer; `None` doesn't limit
+    pub rate_limit: Option<RateLimit>,
+    /// Cross-origin access [`BpxLayer`] grants browsers; `None` grants none
+    pub cors: Option<CorsConfig>,
+    /// Concurrent diffs and requests allowed before degrading or shedding
+    pub load_limits: LoadLimits,
+    /// When requests keep a copy of the current version for later diffs
+    pub version_storage: VersionStorage,
+    /// Answer [`stats::HEALTH_PATH`] and [`stats::STATS_PATH`] in [`BpxLayer`]
+    pub stats_endpoints: bool,
+    /// Where responses carry a key routing the session back to this
+    /// replica; `None` sends none
+    pub affinity: Option<AffinityConfig>,
+    /// How [`BpxLayer`] compresses full bodies; `None` sends them as they are
+    #[cfg(feature = "compression")]
+    pub compression: Option<compression::CompressionConfig>,
+    /// Methods allowed on paths under a prefix; other paths allow
+    /// [`RESOURCE_METHODS`]
+    pub path_methods: Vec<PathMethods>,
+    /// Formats never produced or applied, even for clients accepting them,
+    /// e.g. ones too costly to compute
+    pub disabled_formats: Vec<DiffFormat>,
+    /// Bearer token [`BpxLayer`] requires to answer [`admin::SETTINGS_PATH`];
+    /// `None` doesn't answer it
+    pub admin_token: Option<String>,
+    /// Diffs taking longer than this to compute are logged with their path,
+    /// input sizes and format, and reported as a [`metrics::SlowDiff`];
+    /// `None` doesn't watch
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::optional_duration"))]
+    pub slow_diff_threshold: Option<Duration>,
+    /// Bytes sent and diff time allowed per session; `None` doesn't limit
+    pub session_quota: Option<SessionQuota>,
+    /// Bind each session to the client first seen with it; `None` lets any
+    /// client present any session
+    pub session_binding: Option<SessionBinding>,
+    /// What requests presenting no session, or one the server doesn't
+    /// know, get
+    pub unknown_sessions: UnknownSessionPolicy,
+    /// Paths starting with any of these are always answered in full, and
+    /// no version of them is kept in the store, the diff cache or sessions,
+    /// e.g. ones that may carry tokens or personal data
+    pub no_history: Vec<String>,
+}
+
+impl BpxConfig {
+    /// How long an idle session is kept: its TTL plus the grace period
+    pub fn session_retention(&self) -> Duration {
+        self.session_ttl + self.session_grace
+    }
+
+    /// Idle time after which the version tracked for `path` is forgotten
+    ///
+    /// The longest matching prefix in `path_ttls` wins; other paths use
+    /// `session_ttl`.
+    pub fn ttl_for(&self, path: &ResourcePath) -> Duration {
+        self.path_ttls
+            .iter()
+            .filter(|o| path.0.starts_with(&o.prefix))
+            .max_by_key(|o| o.prefix.len())
+            .map_or(self.session_ttl, |o| o.ttl)
+    }
+
+    /// Whether versions of `path` may be kept for diffing, i.e. it isn't
+    /// under a `no_history` prefix
+    pub fn keeps_history(&self, path: &ResourcePath) -> bool {
+        !self
+            .no_history
+            .iter()
+            .any(|prefix| path.0.starts_with(prefix.as_str()))
+    }
+
+    /// Whether the version tracked for `path` is gone once its session has
+    /// been idle for `idle`
+    pub fn version_expired(&self, path: &ResourcePath, idle: Duration) -> bool {
+        idle > self.ttl_for(path) + self.session_grace
+    }
+
+    /// Whether a session idle for `idle` and tracking `paths` is gone
+    ///
+    /// Sessions outlive their TTL while they hold a version whose path TTL
+    /// is longer.
+    pub fn session_expired<'a>(
+        &self,
+        idle: Duration,
+        paths: impl IntoIterator<Item = &'a ResourcePath>,
+    ) -> bool {
+        idle > self.session_retention()
+            && paths
+                .into_iter()
+                .all(|path| self.version_expired(path, idle))
+    }
+
+    /// Longest any session can be kept
+    pub fn max_session_retention(&self) -> Duration {
+        self.path_ttls
+            .iter()
+            .map(|o| o.ttl)
+            .fold(self.session_ttl, Duration::max)
+            + self.session_grace
+    }
+
+    /// Whether `format` may be negotiated, i.e. isn't in `disabled_formats`
+    pub fn format_enabled(&self, format: DiffFormat) -> bool {
+        !self.disabled_formats.contains(&format)
+    }
+
+    /// Limits on applying a diff written with `PATCH`
+    pub fn patch_limits(&self) -> diff::PatchLimits {
+        diff::PatchLimits {
+            max_operations: self.max_diff_operations,
+            max_output_size: self.max_patch_output_size,
+            max_insert_bytes: self.max_insert_bytes,
+        }
+    }
+
+    /// Whether a diff of `diff_size` bytes saves enough over a full body of
+    /// `original_size` bytes, per `min_compression_ratio`
+    pub fn diff_worthwhile(&self, original_size: usize, diff_size: usize) -> bool {
+        if original_size == 0 {
+            return false;
+        }
+        let ratio = diff_size as f32 / original_size as f32;
+        ratio <= 1.0 - self.min_compression_ratio.clamp(0.0, 1.0)
+    }
+
+    /// Methods allowed on `path`
+    ///
+    /// The longest matching prefix in `path_methods` wins; other paths
+    /// allow every method in [`RESOURCE_METHODS`]. Only those methods are
+    /// ever served, so others listed have no effect.
+    pub fn methods_for(&self, path: &ResourcePath) -> Vec<Method> {
+        let allowed = self
+            .path_methods
+            .iter()
+            .filter(|o| path.0.starts_with(&o.prefix))
+            .max_by_key(|o| o.prefix.len())
+            .map_or(&RESOURCE_METHODS[..], |o| &o.methods[..]);
+        RESOURCE_METHODS
+            .into_iter()
+            .filter(|method| allowed.contains(method))
+            .collect()
+    }
+
+    /// Check for settings that can't work, alone or together
+    ///
+    /// [`BpxServerBuilder::build`] refuses such a config with
+    /// [`BpxError::BuilderError`].
+    pub fn validate(&self) -> Result<(), BpxError> {
+        let invalid = |reason: &str| {
+            Err(BpxError::BuilderError {
+                reason: reason.to_string(),
+            })
+        };
+        let cookie_name = |name: &str| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+        };
+        if !(0.0..=1.0).contains(&self.min_compression_ratio) {
+            return invalid("min_compression_ratio is not between 0 and 1");
+        }
+        if self.max_diff_size == 0 {
+            return invalid("max_diff_size allows no diffs");
+        }
+        if self.version_storage == VersionStorage::Sampled(0) {
+            return invalid("version_storage samples one request in 0");
+        }
+        if self
+            .rate_limit
+            .as_ref()
+            .is_some_and(|limit| limit.burst == 0)
+        {
+            return invalid("rate_limit allows no requests with a burst of 0");
+        }
+        if let UnknownSessionPolicy::Limit(limit) = &self.unknown_sessions
+            && limit.burst == 0
+        {
+            return invalid("unknown_sessions allows no new sessions with a burst of 0");
+        }
+        if self
+            .session_quota
+            .as_ref()
+            .is_some_and(|quota| quota.window.is_zero())
+        {
+            return invalid("session_quota counts usage over a window of 0");
+        }
+        if let Some(name) = &self.session_cookie
+            && !cookie_name(name)
+        {
+            return invalid("session_cookie is not a valid cookie name");
+        }
+        if self.session_binding.as_ref().is_some_and(|binding| {
+            binding.source == binding::BindingSource::ForwardedFor && binding.trusted_proxies == 0
+        }) {
+            return invalid("session_binding reads X-Forwarded-For with no trusted proxies");
+        }
+        if let Some(affinity) = &self.affinity {
+            if let Some(name) = &affinity.cookie {
+                if !cookie_name(name) {
+                    return invalid("affinity cookie is not a valid cookie name");
+                }
+                if self.session_cookie.as_ref() == Some(name) {
+                    return invalid("affinity cookie would overwrite the session cookie");
+                }
+            }
+            if let Some(name) = &affinity.header
+                && hyper::header::HeaderName::try_from(name.as_str()).is_err()
+            {
+                return invalid("affinity header is not a valid header name");
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits that apply to `tenant`
+    pub fn limits_for(&self, tenant: &TenantId) -> &TenantLimits {
+        self.tenant_limits
+            .get(tenant)
+            .unwrap_or(&self.default_tenant_limits)
+    }
+}
+
+/// When a request keeps a copy of the version it was answered with, so
+/// later requests can be diffed against it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum VersionStorage {
+    /// Never; only versions the application stores are diffed against
+    Never,
+    /// Whenever the store doesn't hold the version yet
+    #[default]
+    OnChange,
+    /// Like `OnChange`, but only on one request in this many, picked at
+    /// random; clients holding a version that wasn't kept get full bodies
+    Sampled(u32),
+}
+
+impl VersionStorage {
+    /// Whether this request may keep its version
+    pub fn sample(self) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnChange => true,
+            Self::Sampled(one_in) => {
+                use std::hash::BuildHasher;
+                // Seeded differently on every call; good enough for sampling
+                let random = std::collections::hash_map::RandomState::new().hash_one(());
+                random.is_multiple_of(u64::from(one_in.max(1)))
+            }
+        }
+    }
+}
+
+/// Methods BPX answers on a resource: reads with `GET` and `HEAD`, writes
+/// with `PUT` and `PATCH`
+pub const RESOURCE_METHODS: [Method; 4] = [Method::GET, Method::HEAD, Method::PUT, Method::PATCH];
+
+/// Methods allowed on paths under a prefix, e.g. only reads on a mirror
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct PathMethods {
+    /// Paths starting with this are covered
+    pub prefix: String,
+    /// Methods allowed; others are refused with `405 Method Not Allowed`
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::methods"))]
+    pub methods: Vec<Method>,
+}
+
+/// TTL override for the versions tracked for paths under a prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct PathTtl {
+    /// Paths starting with this are covered
+    pub prefix: String,
+    /// How long a session may be idle before the version is forgotten
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
+    pub ttl: Duration,
+}
+
+/// Caps on one tenant's share of session state
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct TenantLimits {
+    /// Maximum sessions the tenant may hold; the least recently used are
+    /// evicted beyond it
+    pub max_sessions: usize,
+    /// Maximum resources tracked per session
+    pub max_resources_per_session: usize,
+    /// Cap on bytes of the tenant's session state
+    pub max_memory: usize,
+}
+
+impl Default for TenantLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 10_000,
+            max_resources_per_session: 1_000,
+            max_memory: 32 * 1024 * 1024, // 32MB
+        }
+    }
 }
 
 impl Default for BpxConfig {
@@ -218,10 +874,39 @@ impl Default for BpxConfig {
         Self {
             max_sessions: 100_000,
             max_resources_per_session: 1_000,
+            max_memory: 256 * 1024 * 1024,                  // 256MB
             session_ttl: Duration::from_secs(24 * 60 * 60), // 24 hours
-            max_diff_size: 10 * 1024 * 1024,                // 10MB
-            min_compression_ratio: 0.2,                     // 80% savings
-            cleanup_interval: Duration::from_secs(5 * 60),  // 5 minutes
+            session_grace: Duration::ZERO,
+            path_ttls: Vec::new(),
+            max_diff_size: 10 * 1024 * 1024, // 10MB
+            max_diff_operations: 1_000_000,
+            max_patch_output_size: 64 * 1024 * 1024, // 64MB
+            max_insert_bytes: 10 * 1024 * 1024,      // 10MB
+            max_batch_entries: 256,
+            min_compression_ratio: 0.2,                    // 80% savings
+            cleanup_interval: Duration::from_secs(5 * 60), // 5 minutes
+            rfc3229_mode: false,
+            session_cookie: None,
+            tenant_limits: HashMap::new(),
+            default_tenant_limits: TenantLimits::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            query: QueryCanonicalization::default(),
+            rate_limit: None,
+            cors: None,
+            load_limits: LoadLimits::default(),
+            version_storage: VersionStorage::OnChange,
+            stats_endpoints: false,
+            affinity: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            path_methods: Vec::new(),
+            disabled_formats: Vec::new(),
+            admin_token: None,
+            slow_diff_threshold: None,
+            session_quota: None,
+            session_binding: None,
+            unknown_sessions: UnknownSessionPolicy::Create,
+            no_history: Vec::new(),
         }
     }
 }
@@ -236,6 +921,22 @@ pub enum BpxError {
         client_id: SessionId,
     },
 
+    /// Resource doesn't exist in the store
+    #[error("Resource not found: {path}")]
+    ResourceNotFound {
+        /// Resource requested
+        path: ResourcePath,
+    },
+
+    /// Version of a resource isn't (or is no longer) stored
+    #[error("Version not found: {path}@{version}")]
+    VersionNotFound {
+        /// Resource requested
+        path: ResourcePath,
+        /// Version requested
+        version: Version,
+    },
+
     /// Diff computation failed
     #[error("Diff computation failed: {reason}")]
     DiffComputationFailed {
@@ -252,6 +953,22 @@ pub enum BpxError {
         max_size: usize,
     },
 
+    /// A written diff exceeds the limits of [`BpxConfig::patch_limits`]
+    #[error("Patch too large: {reason}")]
+    PatchTooLarge {
+        /// Limit exceeded
+        reason: String,
+    },
+
+    /// A batch asks for more than [`BpxConfig::max_batch_entries`]
+    #[error("Batch too large: {entries} entries (max: {max_entries})")]
+    BatchTooLarge {
+        /// Entries asked for
+        entries: usize,
+        /// Maximum allowed entries
+        max_entries: usize,
+    },
+
     /// Invalid diff format
     #[error("Invalid diff format: {format}")]
     InvalidDiffFormat {
@@ -259,6 +976,23 @@ pub enum BpxError {
         format: String,
     },
 
+    /// Malformed client request
+    #[error("Invalid request: {reason}")]
+    InvalidRequest {
+        /// Failure reason
+        reason: String,
+    },
+
+    /// A BPX header is too long, lists too many entries, or holds control
+    /// characters
+    #[error("Invalid {header} header: {reason}")]
+    InvalidHeader {
+        /// Header refused
+        header: &'static str,
+        /// What was wrong
+        reason: String,
+    },
+
     /// Session capacity exceeded
     #[error("Session capacity exceeded: {current} sessions (max: {max})")]
     SessionCapacityExceeded {
@@ -267,44 +1001,1027 @@ pub enum BpxError {
         /// Maximum allowed
         max: usize,
     },
+
+    /// Response signature missing or invalid
+    #[error("Invalid signature: {reason}")]
+    InvalidSignature {
+        /// Failure reason
+        reason: String,
+    },
+
+    /// HTTP exchange with the peer failed
+    #[error("Transport error: {reason}")]
+    Transport {
+        /// Failure reason
+        reason: String,
+    },
+
+    /// Persistent state could not be read or written
+    #[error("Storage error: {reason}")]
+    Storage {
+        /// Failure reason
+        reason: String,
+    },
+
+    /// Client is sending requests faster than allowed
+    #[error("Rate limited: retry after {retry_after:?}")]
+    RateLimited {
+        /// How long the client should wait before retrying
+        retry_after: Duration,
+    },
+
+    /// A write was based on a version the resource no longer has
+    #[error("Precondition failed: {path} is no longer at the expected version")]
+    PreconditionFailed {
+        /// Resource written
+        path: ResourcePath,
+        /// Version the resource is at, if it exists
+        current: Option<Version>,
+    },
+
+    /// The resource store doesn't accept writes
+    #[error("Resource store is read-only: {path}")]
+    ReadOnly {
+        /// Resource written
+        path: ResourcePath,
+    },
+
+    /// The [`auth::Authorizer`] refused access to the resource
+    #[error("Forbidden: {path}")]
+    Forbidden {
+        /// Resource requested
+        path: ResourcePath,
+    },
+
+    /// The method isn't allowed on the resource
+    #[error("Method {method} not allowed on {path}")]
+    MethodNotAllowed {
+        /// Method requested
+        method: Method,
+        /// Resource requested
+        path: ResourcePath,
+        /// Methods that are allowed, sent in `Allow`
+        allowed: Vec<Method>,
+    },
+
+    /// [`BpxServerBuilder`] was given settings that can't work together
+    #[error("Invalid server configuration: {reason}")]
+    BuilderError {
+        /// What was wrong
+        reason: String,
+    },
+
+    /// A response header couldn't be built, e.g. from a malformed content type
+    #[error("Invalid response: {reason}")]
+    InvalidResponse {
+        /// What was wrong
+        reason: String,
+    },
+
+    /// No tenant could be found for the request (see [`tenant`])
+    #[error("Unknown tenant for: {path}")]
+    UnknownTenant {
+        /// Resource requested
+        path: String,
+    },
+
+    /// The server is handling as many requests as [`LoadLimits`] allows
+    #[error("Overloaded: {max_outstanding} requests outstanding")]
+    Overloaded {
+        /// Requests allowed at once
+        max_outstanding: usize,
+    },
+
+    /// The session used up its [`SessionQuota`] for the current window
+    #[error("Quota exceeded for session {session}: retry after {retry_after:?}")]
+    QuotaExceeded {
+        /// Session over its quota
+        session: SessionId,
+        /// How long until the window ends
+        retry_after: Duration,
+    },
 }
 
-/// BPX server implementation
-pub struct BpxServer {
-    config: BpxConfig,
-    state_manager: Arc<dyn StateManager>,
-    diff_engine: Arc<dyn DiffEngine>,
-}
+impl BpxError {
+    /// Stable machine-readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::ClientStateNotFound { .. } => "not-found",
+            Self::ResourceNotFound { .. } => "resource-not-found",
+            Self::VersionNotFound { .. } => "version-not-found",
+            Self::DiffComputationFailed { .. } => "diff-failed",
+            Self::ResourceTooLarge { .. } => "resource-too-large",
+            Self::PatchTooLarge { .. } => "patch-too-large",
+            Self::BatchTooLarge { .. } => "batch-too-large",
+            Self::InvalidDiffFormat { .. } => "invalid-diff-format",
+            Self::InvalidRequest { .. } => "invalid-request",
+            Self::InvalidHeader { .. } => "invalid-header",
+            Self::SessionCapacityExceeded { .. } => "session-capacity-exceeded",
+            Self::InvalidSignature { .. } => "invalid-signature",
+            Self::Transport { .. } => "transport-error",
+            Self::RateLimited { .. } => "rate-limited",
+            Self::Storage { .. } => "storage-error",
+            Self::PreconditionFailed { .. } => "precondition-failed",
+            Self::ReadOnly { .. } => "read-only",
+            Self::Forbidden { .. } => "forbidden",
+            Self::Overloaded { .. } => "overloaded",
+            Self::QuotaExceeded { .. } => "quota-exceeded",
+            Self::UnknownTenant { .. } => "unknown-tenant",
+            Self::InvalidResponse { .. } => "invalid-response",
+            Self::MethodNotAllowed { .. } => "method-not-allowed",
+            Self::BuilderError { .. } => "invalid-configuration",
+        }
+    }
+}
+
+/// BPX server implementation
+pub struct BpxServer {
+    config: RwLock<Arc<BpxConfig>>,
+    diff_cache: Option<Arc<diff::cache::CachingDiffEngine>>,
+    setting_history: admin::SettingHistory,
+    state_manager: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    signer: Option<Arc<dyn ResponseSigner>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    /// Whether [`Self::serve`] accepts writes
+    writes: bool,
+    rate_limiter: Option<rate_limit::RateLimiter>,
+    quota: Option<Arc<quota::QuotaTracker>>,
+    binder: Option<binding::SessionBinder>,
+    session_gate: Option<unknown_session::SessionGate>,
+    load: Option<Arc<load::LoadShedder>>,
+    stats: stats::ServerStats,
+    tenant_resolver: Option<Arc<dyn tenant::TenantResolver>>,
+    tenant_stats: DashMap<TenantId, stats::ServerStats>,
+    metrics: Option<Arc<dyn metrics::MetricsRecorder>>,
+    observer: Option<Arc<dyn Observer>>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    audit_log: Option<Arc<dyn AuditLog>>,
+}
+
+impl BpxServer {
+    /// Create a new BPX server builder
+    pub fn builder() -> BpxServerBuilder {
+        BpxServerBuilder::new()
+    }
+
+    /// Handle a BPX request
+    pub async fn handle_request<B, R>(
+        &self,
+        req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        R: ResourceStore + 'static,
+    {
+        #[cfg(feature = "otel")]
+        let span = metrics::otel::ExchangeSpan::start(&req);
+        let arrival = self
+            .access_log
+            .as_ref()
+            .map(|_| access_log::Arrival::of(&req));
+        let path = ResourcePath::new(req.uri().path().to_string());
+        let result = self.exchange(req, &path, resource_store).await;
+        let body_len = result
+            .as_ref()
+            .ok()
+            .map(|response| response.body().len() as u64);
+        #[cfg(feature = "otel")]
+        span.end(&result, body_len);
+        self.record_failure(&path, &result);
+        self.log_access(arrival, &result, body_len);
+        result
+    }
+
+    /// Answer a BPX request for [`handle_request`](Self::handle_request)
+    async fn exchange<B, R>(
+        &self,
+        mut req: Request<B>,
+        path: &ResourcePath,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        R: ResourceStore + 'static,
+    {
+        let tenant = self.resolve_tenant(&mut req)?;
+        self.check_rate(&req)?;
+        let presented = self.presented_session(&req);
+        let identity = self
+            .check_binding(&mut req)
+            .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.check_unknown_session(&req).await?;
+        self.check_quota(&mut req)?;
+        let response = server::handle_bpx_request_guarded(
+            req,
+            &self.config(),
+            Arc::clone(&self.state_manager),
+            Arc::clone(&self.diff_engine),
+            resource_store,
+            self.guards(),
+        )
+        .await
+        .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        let body_len = response.body().len() as u64;
+        self.record(tenant.as_ref(), path, &response, Some(body_len));
+        self.audit_session(tenant.as_ref(), presented, &response);
+        self.bind_session(tenant.as_ref(), identity, &response);
+        if let Some(quota) = &self.quota {
+            quota.charge_response(tenant.as_ref(), &response, body_len);
+        }
+        Ok(self.sign(response))
+    }
+
+    /// Handle a BPX request, streaming full responses
+    /// (see [`server::handle_bpx_request_streaming`])
+    ///
+    /// Signatures cover the whole body, so with a signer configured responses
+    /// are buffered as by [`handle_request`](Self::handle_request).
+    pub async fn handle_request_streaming<B, R>(
+        &self,
+        req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<server::ResourceBody>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        R: ResourceStore + 'static,
+    {
+        if self.signer.is_some() {
+            let response = self.handle_request(req, resource_store).await?;
+            return Ok(response.map(server::buffered_body));
+        }
+        #[cfg(feature = "otel")]
+        let span = metrics::otel::ExchangeSpan::start(&req);
+        let arrival = self
+            .access_log
+            .as_ref()
+            .map(|_| access_log::Arrival::of(&req));
+        let path = ResourcePath::new(req.uri().path().to_string());
+        let result = self.exchange_streaming(req, &path, resource_store).await;
+        let body_len = result
+            .as_ref()
+            .ok()
+            .and_then(|response| http_body::Body::size_hint(response.body()).exact());
+        #[cfg(feature = "otel")]
+        span.end(&result, body_len);
+        self.record_failure(&path, &result);
+        self.log_access(arrival, &result, body_len);
+        result
+    }
+
+    /// Answer a BPX request for
+    /// [`handle_request_streaming`](Self::handle_request_streaming)
+    async fn exchange_streaming<B, R>(
+        &self,
+        mut req: Request<B>,
+        path: &ResourcePath,
+        resource_store: Arc<R>,
+    ) -> Result<Response<server::ResourceBody>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        R: ResourceStore + 'static,
+    {
+        let tenant = self.resolve_tenant(&mut req)?;
+        self.check_rate(&req)?;
+        let presented = self.presented_session(&req);
+        let identity = self
+            .check_binding(&mut req)
+            .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.check_unknown_session(&req).await?;
+        self.check_quota(&mut req)?;
+        let response = server::handle_bpx_request_streaming_guarded(
+            req,
+            &self.config(),
+            Arc::clone(&self.state_manager),
+            Arc::clone(&self.diff_engine),
+            resource_store,
+            self.guards(),
+        )
+        .await
+        .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        let body_len = http_body::Body::size_hint(response.body()).exact();
+        self.record(tenant.as_ref(), path, &response, body_len);
+        self.audit_session(tenant.as_ref(), presented, &response);
+        self.bind_session(tenant.as_ref(), identity, &response);
+        Ok(match &self.quota {
+            Some(quota) => quota.meter(tenant, response),
+            None => response,
+        })
+    }
+
+    /// Handle a `PUT` or `PATCH` update (see [`server::handle_write_request`])
+    pub async fn handle_write_request<B, R>(
+        &self,
+        req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        R: ResourceStore + 'static,
+    {
+        let arrival = self
+            .access_log
+            .as_ref()
+            .map(|_| access_log::Arrival::of(&req));
+        let path = ResourcePath::new(req.uri().path().to_string());
+        let result = self.write(req, resource_store).await;
+        let body_len = result
+            .as_ref()
+            .ok()
+            .map(|response| response.body().len() as u64);
+        self.record_failure(&path, &result);
+        self.log_access(arrival, &result, body_len);
+        result
+    }
+
+    /// Apply an update for [`handle_write_request`](Self::handle_write_request)
+    async fn write<B, R>(
+        &self,
+        mut req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        R: ResourceStore + 'static,
+    {
+        let tenant = self.resolve_tenant(&mut req)?;
+        self.check_rate(&req)?;
+        let presented = self.presented_session(&req);
+        let identity = self
+            .check_binding(&mut req)
+            .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.check_unknown_session(&req).await?;
+        self.check_quota(&mut req)?;
+        let response = server::handle_write_request_guarded(
+            req,
+            &self.config(),
+            Arc::clone(&self.state_manager),
+            Arc::clone(&self.diff_engine),
+            resource_store,
+            self.guards(),
+        )
+        .await
+        .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.audit_session(tenant.as_ref(), presented, &response);
+        self.bind_session(tenant.as_ref(), identity, &response);
+        Ok(self.sign(response))
+    }
+
+    /// Handle a batch exchange (see [`server::handle_batch_request`])
+    pub async fn handle_batch_request<B, R>(
+        &self,
+        req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        R: ResourceStore + 'static,
+    {
+        let arrival = self
+            .access_log
+            .as_ref()
+            .map(|_| access_log::Arrival::of(&req));
+        let path = ResourcePath::new(req.uri().path().to_string());
+        let result = self.exchange_batch(req, resource_store).await;
+        let body_len = result
+            .as_ref()
+            .ok()
+            .map(|response| response.body().len() as u64);
+        self.record_failure(&path, &result);
+        self.log_access(arrival, &result, body_len);
+        result
+    }
+
+    /// Answer a batch exchange for
+    /// [`handle_batch_request`](Self::handle_batch_request)
+    async fn exchange_batch<B, R>(
+        &self,
+        mut req: Request<B>,
+        resource_store: Arc<R>,
+    ) -> Result<Response<Bytes>, BpxError>
+    where
+        B: http_body::Body + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        R: ResourceStore + 'static,
+    {
+        let tenant = self.resolve_tenant(&mut req)?;
+        self.check_rate(&req)?;
+        let presented = self.presented_session(&req);
+        let identity = self
+            .check_binding(&mut req)
+            .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.check_unknown_session(&req).await?;
+        self.check_quota(&mut req)?;
+        let response = server::handle_batch_request_guarded(
+            req,
+            &self.config(),
+            Arc::clone(&self.state_manager),
+            Arc::clone(&self.diff_engine),
+            resource_store,
+            self.guards(),
+        )
+        .await
+        .inspect_err(|e| self.audit_denied(tenant.as_ref(), e))?;
+        self.record_batch(tenant.as_ref(), &response);
+        if let Some(quota) = &self.quota {
+            quota.charge_response(tenant.as_ref(), &response, response.body().len() as u64);
+        }
+        self.audit_session(tenant.as_ref(), presented, &response);
+        self.bind_session(tenant.as_ref(), identity, &response);
+        Ok(self.sign(response))
+    }
+
+    /// Warm up `path` before clients poll it, e.g. right after a deploy
+    ///
+    /// Loads the current content, which fills any cache in front of the
+    /// store, and records its version so later requests can diff against
+    /// it. Diffs from up to `recent` earlier versions are then computed so
+    /// that a [`CachingDiffEngine`](diff::CachingDiffEngine) holds them when
+    /// clients on those versions arrive; without one, pass 0. Paths under
+    /// [`BpxConfig::no_history`] are only loaded.
+    ///
+    /// Returns the current version.
+    pub async fn prime<R>(
+        &self,
+        path: &ResourcePath,
+        resource_store: &R,
+        recent: usize,
+    ) -> Result<Version, BpxError>
+    where
+        R: ResourceStore + ?Sized,
+    {
+        let config = self.config();
+        let (content, version) = resource_store.get_versioned_resource(path).await?;
+        if !config.keeps_history(path) {
+            return Ok(version);
+        }
+        resource_store
+            .store_version(path.clone(), version.clone(), content.clone())
+            .await?;
+        if recent == 0 || content.len() > config.max_diff_size {
+            return Ok(version);
+        }
+
+        let versions = resource_store.list_versions(path).await?;
+        for base in versions
+            .iter()
+            .rev()
+            .filter(|v| **v != version)
+            .take(recent)
+        {
+            let Ok(base_content) = resource_store.get_resource_version(path, base).await else {
+                continue;
+            };
+            if base_content.len() > config.max_diff_size {
+                continue;
+            }
+            // Failures are reported when a client asks for this diff
+            let _ = self
+                .diff_engine
+                .compute_diff_async(&base_content, &content)
+                .await;
+        }
+        Ok(version)
+    }
+
+    /// Listen on `addr` and serve `resource_store` until the task is dropped
+    /// (see [`Self::serve_with_shutdown`])
+    pub async fn serve<R>(
+        self: Arc<Self>,
+        addr: impl tokio::net::ToSocketAddrs,
+        resource_store: Arc<R>,
+    ) -> std::io::Result<()>
+    where
+        R: ResourceStore + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        self.serve_with_shutdown(listener, resource_store, std::future::pending())
+            .await
+    }
+
+    /// Serve `resource_store` on connections from `listener` until
+    /// `shutdown` completes
+    ///
+    /// Clients speaking HTTP/2 with prior knowledge (h2c) get HTTP/2, others
+    /// HTTP/1.1. GETs, and POSTs carrying BPX parameters in their body, are
+    /// answered by [`Self::handle_request_streaming`] and `POST /batch` as a
+    /// batch exchange. `PUT`/`PATCH` are writes if the server was built with
+    /// [`BpxServerBuilder::writes`]; they and other requests get `404` or
+    /// `405` otherwise. Use [`BpxLayer`] with [`serve::serve_connections`] to serve
+    /// other routes alongside. After `shutdown`, in-flight requests get
+    /// [`BpxConfig::shutdown_timeout`] to finish.
+    pub async fn serve_with_shutdown<R>(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+        resource_store: Arc<R>,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> std::io::Result<()>
+    where
+        R: ResourceStore + 'static,
+    {
+        serve::serve_store(self, resource_store, listener, shutdown).await
+    }
+
+    /// Listen on `addr` and serve `resource_store` over TLS until the task is
+    /// dropped (see [`Self::serve_tls_with_shutdown`])
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls<R>(
+        self: Arc<Self>,
+        addr: impl tokio::net::ToSocketAddrs,
+        tls_config: Arc<serve::rustls::ServerConfig>,
+        resource_store: Arc<R>,
+    ) -> std::io::Result<()>
+    where
+        R: ResourceStore + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        self.serve_tls_with_shutdown(listener, tls_config, resource_store, std::future::pending())
+            .await
+    }
+
+    /// Like [`Self::serve_with_shutdown`], terminating TLS with `tls_config`
+    ///
+    /// HTTP/2 is negotiated with ALPN unless `tls_config` sets its own
+    /// protocols.
+    #[cfg(feature = "tls")]
+    pub async fn serve_tls_with_shutdown<R>(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+        tls_config: Arc<serve::rustls::ServerConfig>,
+        resource_store: Arc<R>,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> std::io::Result<()>
+    where
+        R: ResourceStore + 'static,
+    {
+        serve::serve_store_tls(self, resource_store, listener, tls_config, shutdown).await
+    }
+
+    /// Find the tenant of `req` and attach it as a [`TenantId`] extension, if
+    /// a resolver is configured
+    fn resolve_tenant<B>(&self, req: &mut Request<B>) -> Result<Option<TenantId>, BpxError> {
+        let Some(resolver) = &self.tenant_resolver else {
+            return Ok(None);
+        };
+        let tenant =
+            resolver
+                .resolve(req.headers(), req.uri())
+                .ok_or_else(|| BpxError::UnknownTenant {
+                    path: req.uri().path().to_string(),
+                })?;
+        req.extensions_mut().insert(tenant.clone());
+        Ok(Some(tenant))
+    }
+
+    /// Count `response` overall and for `tenant`, and report it to the
+    /// metrics recorder and observer
+    fn record<B>(
+        &self,
+        tenant: Option<&TenantId>,
+        path: &ResourcePath,
+        response: &Response<B>,
+        body_len: Option<u64>,
+    ) {
+        self.stats.record(response, body_len);
+        if let Some(tenant) = tenant {
+            self.tenant_stats
+                .entry(tenant.clone())
+                .or_default()
+                .record(response, body_len);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics::record_response(metrics.as_ref(), path, response, body_len);
+            for slow in response
+                .extensions()
+                .get::<Vec<metrics::SlowDiff>>()
+                .into_iter()
+                .flatten()
+            {
+                metrics.slow_diff(slow);
+            }
+            metrics.sessions_active(self.state_manager.session_count());
+        }
+        if let Some(observer) = &self.observer
+            && let Some(decision) = response.extensions().get::<DiffDecision>()
+        {
+            observer.observe(decision);
+        }
+    }
+
+    /// Count each entry a batch `response` answered overall and for
+    /// `tenant`, as [`record`](Self::record) counts a resource answered alone
+    fn record_batch<B>(&self, tenant: Option<&TenantId>, response: &Response<B>) {
+        for decision in response
+            .extensions()
+            .get::<Vec<DiffDecision>>()
+            .into_iter()
+            .flatten()
+        {
+            self.stats.record_decision(decision);
+            if let Some(tenant) = tenant {
+                self.tenant_stats
+                    .entry(tenant.clone())
+                    .or_default()
+                    .record_decision(decision);
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics::record_decision(metrics.as_ref(), decision);
+            }
+            if let Some(observer) = &self.observer {
+                observer.observe(decision);
+            }
+        }
+        if let Some(metrics) = &self.metrics {
+            for slow in response
+                .extensions()
+                .get::<Vec<metrics::SlowDiff>>()
+                .into_iter()
+                .flatten()
+            {
+                metrics.slow_diff(slow);
+            }
+            metrics.sessions_active(self.state_manager.session_count());
+        }
+    }
+
+    /// Report `result` to the metrics recorder if it failed
+    fn record_failure<B>(&self, path: &ResourcePath, result: &Result<Response<B>, BpxError>) {
+        if let (Some(metrics), Err(error)) = (&self.metrics, result) {
+            metrics.request_failed(path, error);
+        }
+    }
+
+    /// Log the request that `arrival` recorded, answered with `result`
+    fn log_access<B>(
+        &self,
+        arrival: Option<access_log::Arrival>,
+        result: &Result<Response<B>, BpxError>,
+        body_len: Option<u64>,
+    ) {
+        if let (Some(log), Some(arrival)) = (&self.access_log, arrival) {
+            log.log(&arrival.answered(result, body_len));
+        }
+    }
+
+    /// Take a token from the request's rate limit buckets, if limits are configured
+    fn check_rate<B>(&self, req: &Request<B>) -> Result<(), BpxError> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.check(req, &self.config()),
+            None => Ok(()),
+        }
+    }
+
+    /// Authorizer and load shedder the handlers check
+    fn guards(&self) -> server::Guards {
+        server::Guards {
+            authorizer: self.authorizer.clone(),
+            load: self.load.clone(),
+        }
+    }
+
+    /// Refuse the request, or mark it [`quota::FullOnly`], if its session is
+    /// past its quota
+    fn check_quota<B>(&self, req: &mut Request<B>) -> Result<(), BpxError> {
+        let Some(quota) = &self.quota else {
+            return Ok(());
+        };
+        let config = self.config();
+        let result = quota.check(req, &config);
+        let enforced = match &result {
+            Err(BpxError::QuotaExceeded { session, .. }) => {
+                Some((session.clone(), quota::QuotaAction::Reject))
+            }
+            Ok(()) if req.extensions().get::<quota::FullOnly>().is_some() => {
+                server::request_session(req, &config)
+                    .ok()
+                    .flatten()
+                    .map(|session| (session, quota::QuotaAction::FullOnly))
+            }
+            _ => None,
+        };
+        if let Some((session, action)) = enforced {
+            let tenant = req.extensions().get::<TenantId>().cloned();
+            self.audit(tenant, audit::AuditEvent::QuotaEnforced { session, action });
+        }
+        result
+    }
+
+    /// Check the session `req` presents against the client it was bound to,
+    /// if sessions are bound, returning the client's identity
+    fn check_binding<B>(&self, req: &mut Request<B>) -> Result<Option<String>, BpxError> {
+        match &self.binder {
+            Some(binder) => binder.check(req, &self.config()),
+            None => Ok(None),
+        }
+    }
+
+    /// Refuse the request if it would be issued a session
+    /// [`BpxConfig::unknown_sessions`] doesn't allow
+    ///
+    /// What's needed is taken from `req` up front, so the future doesn't
+    /// hold on to it.
+    fn check_unknown_session<'a, B>(
+        &'a self,
+        req: &Request<B>,
+    ) -> impl Future<Output = Result<(), BpxError>> + use<'a, B> {
+        let gate = self.session_gate.as_ref();
+        let session = gate.map(|_| server::request_session(req, &self.config()));
+        let tenant = req.extensions().get::<TenantId>().cloned();
+        let peer = req
+            .extensions()
+            .get::<rate_limit::PeerAddr>()
+            .map(|peer| peer.0.ip());
+        async move {
+            let (Some(gate), Some(session)) = (gate, session) else {
+                return Ok(());
+            };
+            gate.check(self.state_manager.as_ref(), tenant.as_ref(), session?, peer)
+                .await
+        }
+    }
+
+    /// Bind the session `response` names to `identity`
+    fn bind_session<B>(
+        &self,
+        tenant: Option<&TenantId>,
+        identity: Option<String>,
+        response: &Response<B>,
+    ) {
+        if let (Some(binder), Some(identity)) = (&self.binder, identity) {
+            binder.bind_response(tenant, &identity, response);
+        }
+    }
+
+    /// Report `event` to the audit log, if there is one
+    fn audit(&self, tenant: Option<TenantId>, event: audit::AuditEvent) {
+        if let Some(log) = &self.audit_log {
+            log.record(&audit::AuditRecord::now(tenant, event));
+        }
+    }
+
+    /// Report `error` to the audit log if it refused access
+    fn audit_denied(&self, tenant: Option<&TenantId>, error: &BpxError) {
+        if let BpxError::Forbidden { path } = error {
+            self.audit(
+                tenant.cloned(),
+                audit::AuditEvent::AccessDenied { path: path.clone() },
+            );
+        }
+    }
+
+    /// Session `req` presents, if there is an audit log to report replacing
+    /// it to
+    fn presented_session<B>(&self, req: &Request<B>) -> Option<SessionId> {
+        self.audit_log
+            .as_ref()
+            .and_then(|_| server::request_session(req, &self.config()).ok().flatten())
+    }
+
+    /// Report the session `response` issued, if it issued one, replacing
+    /// `presented` if the client presented one
+    fn audit_session<B>(
+        &self,
+        tenant: Option<&TenantId>,
+        presented: Option<SessionId>,
+        response: &Response<B>,
+    ) {
+        if self.audit_log.is_none()
+            || response
+                .headers()
+                .get(protocol::headers::BpxHeaders::SESSION_STATUS)
+                .is_none_or(|status| status != "created")
+        {
+            return;
+        }
+        let Some(session) = server::response_session(response) else {
+            return;
+        };
+        let event = match presented {
+            Some(presented) => audit::AuditEvent::SessionReplaced { presented, session },
+            None => audit::AuditEvent::SessionCreated { session },
+        };
+        self.audit(tenant.cloned(), event);
+    }
+
+    /// Attach a signature header if a signer is configured
+    fn sign(&self, mut response: Response<Bytes>) -> Response<Bytes> {
+        if let Some(signer) = &self.signer {
+            signing::sign_response(&mut response, signer.as_ref());
+        }
+        response
+    }
+
+    /// Responses answered so far
+    pub fn stats(&self) -> &stats::ServerStats {
+        &self.stats
+    }
+
+    /// Figures [`stats::STATS_PATH`] reports, with the store's counts
+    pub fn stats_snapshot<R: ResourceStore + ?Sized>(
+        &self,
+        resource_store: &R,
+    ) -> stats::StatsSnapshot {
+        self.stats
+            .snapshot(self.state_manager.session_count(), resource_store.stats())
+    }
+
+    /// Responses answered for `tenant`, with the sessions it holds
+    pub fn tenant_stats(&self, tenant: &TenantId) -> stats::StatsSnapshot {
+        let sessions = self.state_manager.tenant_session_count(tenant);
+        match self.tenant_stats.get(tenant) {
+            Some(stats) => stats.snapshot(sessions, None),
+            None => stats::ServerStats::default().snapshot(sessions, None),
+        }
+    }
+
+    /// Answer [`stats::HEALTH_PATH`]
+    pub fn health_response(&self) -> Response<Bytes> {
+        stats::json_response(r#"{"status":"ok"}"#.to_string())
+    }
+
+    /// Answer [`stats::STATS_PATH`] with [`Self::stats_snapshot`] as JSON
+    pub fn stats_response<R: ResourceStore + ?Sized>(&self, resource_store: &R) -> Response<Bytes> {
+        stats::json_response(self.stats_snapshot(resource_store).to_json())
+    }
 
-impl BpxServer {
-    /// Create a new BPX server builder
-    pub fn builder() -> BpxServerBuilder {
-        BpxServerBuilder::new()
+    /// Get server configuration
+    ///
+    /// A snapshot: settings changed with [`Self::tune`] afterwards show up in
+    /// the next call.
+    pub fn config(&self) -> Arc<BpxConfig> {
+        Arc::clone(&self.config.read().unwrap_or_else(|e| e.into_inner()))
     }
 
-    /// Handle a BPX request
-    pub async fn handle_request<B, R>(
+    /// Whether [`Self::serve`] accepts `PUT` and `PATCH` (see
+    /// [`BpxServerBuilder::writes`])
+    pub fn accepts_writes(&self) -> bool {
+        self.writes
+    }
+
+    /// Change `setting` while the server runs, recording `actor` as having
+    /// changed it (see [`admin`])
+    ///
+    /// Requests already being answered keep the settings they started with.
+    /// Fails with [`BpxError::InvalidRequest`], changing nothing, if the
+    /// setting leaves a configuration [`BpxConfig::validate`] refuses, or for
+    /// [`DiffCacheBytes`](admin::Setting::DiffCacheBytes) if the server has
+    /// no [diff cache](BpxServerBuilder::diff_cache).
+    pub fn tune(
+        &self,
+        actor: &str,
+        setting: admin::Setting,
+    ) -> Result<admin::SettingChange, BpxError> {
+        let old = match setting {
+            admin::Setting::DiffCacheBytes(bytes) => {
+                let cache = self
+                    .diff_cache
+                    .as_ref()
+                    .ok_or_else(|| BpxError::InvalidRequest {
+                        reason: "No diff cache to resize".to_string(),
+                    })?;
+                let old = cache.max_bytes();
+                cache.set_max_bytes(bytes);
+                admin::Setting::DiffCacheBytes(old)
+            }
+            setting => {
+                let mut config = self.config.write().unwrap_or_else(|e| e.into_inner());
+                let mut updated = BpxConfig::clone(&config);
+                let old = setting.apply(&mut updated).unwrap_or(setting);
+                updated.validate().map_err(|e| match e {
+                    BpxError::BuilderError { reason } => BpxError::InvalidRequest { reason },
+                    e => e,
+                })?;
+                *config = Arc::new(updated);
+                old
+            }
+        };
+        let change = admin::SettingChange {
+            actor: actor.to_string(),
+            old,
+            new: setting,
+            at: SystemTime::now(),
+        };
+        self.setting_history.record(change.clone());
+        self.audit(
+            None,
+            audit::AuditEvent::AdminAction {
+                actor: change.actor.clone(),
+                action: format!("set {} from {} to {}", setting.name(), old, setting),
+            },
+        );
+        Ok(change)
+    }
+
+    /// Settings changed with [`Self::tune`], oldest first
+    pub fn setting_changes(&self) -> Vec<admin::SettingChange> {
+        self.setting_history.changes()
+    }
+
+    /// Answer [`admin::SETTINGS_PATH`], applying the settings a `POST` body
+    /// carries first
+    ///
+    /// Fails with [`BpxError::Forbidden`] unless the request carries
+    /// [`BpxConfig::admin_token`] as a bearer token.
+    pub async fn handle_settings_request<B>(
         &self,
         req: Request<B>,
-        resource_store: Arc<R>,
     ) -> Result<Response<Bytes>, BpxError>
     where
-        B: http_body::Body + Send + 'static,
-        R: ResourceStore + 'static,
+        B: http_body::Body,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
     {
-        server::handle_bpx_request(
-            req,
-            &self.config,
-            Arc::clone(&self.state_manager),
-            Arc::clone(&self.diff_engine),
+        self.check_admin(&req, admin::SETTINGS_PATH)?;
+        if req.method() == Method::POST {
+            let actor = admin::actor(&req).to_string();
+            let body = req.into_body();
+            let body = http_body_util::Limited::new(body, admin::MAX_FORM_SIZE);
+            let body = http_body_util::BodyExt::collect(body)
+                .await
+                .map_err(|e| BpxError::InvalidRequest {
+                    reason: format!("Failed to read settings: {}", e),
+                })?
+                .to_bytes();
+            for setting in admin::parse_form(&body)? {
+                self.tune(&actor, setting)?;
+            }
+        }
+
+        let config = self.config();
+        let cache = match &self.diff_cache {
+            Some(cache) => cache.max_bytes().to_string(),
+            None => "null".to_string(),
+        };
+        let changes: Vec<String> = self.setting_changes().iter().map(|c| c.to_json()).collect();
+        Ok(stats::json_response(format!(
+            r#"{{"min_compression_ratio":{},"max_diff_size":{},"diff_cache_bytes":{},"changes":[{}]}}"#,
+            config.min_compression_ratio,
+            config.max_diff_size,
+            cache,
+            changes.join(","),
+        )))
+    }
+
+    /// What the server believes `session` holds for `path`
+    ///
+    /// Neither resumes the session nor records anything; see
+    /// [`admin::SessionDebug`].
+    pub async fn debug_session<R: ResourceStore>(
+        &self,
+        session: SessionId,
+        path: &ResourcePath,
+        resource_store: &R,
+    ) -> Result<admin::SessionDebug, BpxError> {
+        server::debug_session(
+            &self.config(),
+            self.state_manager.as_ref(),
+            self.diff_engine.as_ref(),
             resource_store,
+            session,
+            path,
         )
         .await
     }
 
-    /// Get server configuration
-    pub fn config(&self) -> &BpxConfig {
-        &self.config
+    /// Answer [`admin::DEBUG_PATH`] for the `session` and `path` its query
+    /// names
+    ///
+    /// Fails with [`BpxError::Forbidden`] unless the request carries
+    /// [`BpxConfig::admin_token`] as a bearer token.
+    pub async fn handle_debug_request<B, R: ResourceStore>(
+        &self,
+        req: Request<B>,
+        resource_store: &R,
+    ) -> Result<Response<Bytes>, BpxError> {
+        self.check_admin(&req, admin::DEBUG_PATH)?;
+        let (session, path) = admin::parse_debug_query(req.uri().query(), &self.config())?;
+        self.audit(
+            None,
+            audit::AuditEvent::AdminAction {
+                actor: admin::actor(&req).to_string(),
+                action: format!("debug session {} for {}", session, path),
+            },
+        );
+        let debug = self.debug_session(session, &path, resource_store).await?;
+        Ok(stats::json_response(debug.to_json()))
+    }
+
+    /// Fail with [`BpxError::Forbidden`] unless `req` carries
+    /// [`BpxConfig::admin_token`] as a bearer token
+    fn check_admin<B>(&self, req: &Request<B>, route: &str) -> Result<(), BpxError> {
+        let token = self.config().admin_token.clone();
+        let authorization = req.headers().get(hyper::header::AUTHORIZATION);
+        if !token
+            .is_some_and(|token| admin::authorized(authorization.map(|v| v.as_bytes()), &token))
+        {
+            let path = ResourcePath::new(route.to_string());
+            self.audit(None, audit::AuditEvent::AccessDenied { path: path.clone() });
+            return Err(BpxError::Forbidden { path });
+        }
+        Ok(())
     }
 
     /// Get state manager reference
@@ -328,6 +2045,15 @@ pub struct BpxServerBuilder {
     config: Option<BpxConfig>,
     state_manager: Option<Arc<dyn StateManager>>,
     diff_engine: Option<Arc<dyn DiffEngine>>,
+    diff_cache: Option<Arc<diff::cache::CachingDiffEngine>>,
+    signer: Option<Arc<dyn ResponseSigner>>,
+    authorizer: Option<Arc<dyn Authorizer>>,
+    writes: bool,
+    tenant_resolver: Option<Arc<dyn tenant::TenantResolver>>,
+    metrics: Option<Arc<dyn metrics::MetricsRecorder>>,
+    observer: Option<Arc<dyn Observer>>,
+    access_log: Option<Arc<dyn AccessLog>>,
+    audit_log: Option<Arc<dyn AuditLog>>,
 }
 
 impl BpxServerBuilder {
@@ -336,6 +2062,15 @@ impl BpxServerBuilder {
             config: None,
             state_manager: None,
             diff_engine: None,
+            diff_cache: None,
+            signer: None,
+            authorizer: None,
+            writes: false,
+            tenant_resolver: None,
+            metrics: None,
+            observer: None,
+            access_log: None,
+            audit_log: None,
         }
     }
 
@@ -357,26 +2092,133 @@ impl BpxServerBuilder {
         self
     }
 
+    /// Compute diffs with `cache`, whose size [`BpxServer::tune`] can change
+    ///
+    /// Replaces any engine set with [`Self::diff_engine`].
+    pub fn diff_cache(mut self, cache: Arc<diff::cache::CachingDiffEngine>) -> Self {
+        self.diff_engine = Some(cache.clone());
+        self.diff_cache = Some(cache);
+        self
+    }
+
+    /// Sign every response so intermediaries cannot forge diffs
+    pub fn signer(mut self, signer: Arc<dyn ResponseSigner>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Check every resource served or written with `authorizer`
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Accept `PUT` and `PATCH` in [`BpxServer::serve`], which is read-only
+    /// otherwise
+    ///
+    /// [`Self::build`] refuses this without an [`Self::authorizer`] to
+    /// decide who may write.
+    pub fn writes(mut self) -> Self {
+        self.writes = true;
+        self
+    }
+
+    /// Serve each request within the tenant `resolver` finds for it,
+    /// refusing requests it finds none for (see [`tenant`])
+    pub fn tenant_resolver(mut self, resolver: Arc<dyn tenant::TenantResolver>) -> Self {
+        self.tenant_resolver = Some(resolver);
+        self
+    }
+
+    /// Report responses, sessions and diff timings to `recorder` (see
+    /// [`metrics`])
+    pub fn metrics(mut self, recorder: Arc<dyn metrics::MetricsRecorder>) -> Self {
+        self.metrics = Some(recorder);
+        self
+    }
+
+    /// Hand the [`DiffDecision`] made for each resource response to
+    /// `observer` (see [`observer`])
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Log every request answered to `log` (see [`access_log`])
+    pub fn access_log(mut self, log: Arc<dyn AccessLog>) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// Report security-relevant events to `log` (see [`audit`])
+    pub fn audit_log(mut self, log: Arc<dyn AuditLog>) -> Self {
+        self.audit_log = Some(log);
+        self
+    }
+
     /// Build the BPX server
+    ///
+    /// Without a state manager, sessions are kept in an
+    /// [`InMemoryStateManager`](state::InMemoryStateManager); without a diff
+    /// engine, diffs come from a
+    /// [`SimilarDiffEngine`](diff::similar::SimilarDiffEngine). Fails with
+    /// [`BpxError::BuilderError`] if the config doesn't
+    /// [validate](BpxConfig::validate), or if [writes](Self::writes) are
+    /// enabled without an authorizer.
     pub fn build(self) -> Result<BpxServer, BpxError> {
         let config = self.config.unwrap_or_default();
+        config.validate()?;
+        if self.writes && self.authorizer.is_none() {
+            return Err(BpxError::BuilderError {
+                reason: "writes need an authorizer".to_string(),
+            });
+        }
 
         let state_manager = self
             .state_manager
-            .ok_or_else(|| BpxError::DiffComputationFailed {
-                reason: "State manager not provided".to_string(),
-            })?;
-
+            .unwrap_or_else(|| Arc::new(state::InMemoryStateManager::new(config.clone())));
         let diff_engine = self
             .diff_engine
-            .ok_or_else(|| BpxError::DiffComputationFailed {
-                reason: "Diff engine not provided".to_string(),
-            })?;
+            .unwrap_or_else(|| Arc::new(diff::similar::SimilarDiffEngine::new()));
+        let diff_engine: Arc<dyn DiffEngine> = match &self.metrics {
+            Some(recorder) => Arc::new(metrics::MeteredDiffEngine::new(
+                diff_engine,
+                Arc::clone(recorder),
+            )),
+            None => diff_engine,
+        };
 
         Ok(BpxServer {
-            config,
+            rate_limiter: config.rate_limit.clone().map(rate_limit::RateLimiter::new),
+            quota: config
+                .session_quota
+                .clone()
+                .map(|quota| Arc::new(quota::QuotaTracker::new(quota))),
+            binder: config
+                .session_binding
+                .clone()
+                .map(binding::SessionBinder::new),
+            session_gate: (config.unknown_sessions != UnknownSessionPolicy::Create)
+                .then(|| unknown_session::SessionGate::new(config.unknown_sessions.clone())),
+            load: config
+                .load_limits
+                .is_limited()
+                .then(|| Arc::new(load::LoadShedder::new(config.load_limits.clone()))),
+            config: RwLock::new(Arc::new(config)),
+            diff_cache: self.diff_cache,
+            setting_history: admin::SettingHistory::default(),
             state_manager,
             diff_engine,
+            signer: self.signer,
+            authorizer: self.authorizer,
+            writes: self.writes,
+            stats: stats::ServerStats::default(),
+            tenant_resolver: self.tenant_resolver,
+            tenant_stats: DashMap::new(),
+            metrics: self.metrics,
+            observer: self.observer,
+            access_log: self.access_log,
+            audit_log: self.audit_log,
         })
     }
 }
@@ -419,19 +2261,38 @@ mod tests {
             Some(DiffFormat::JsonPatch)
         );
         assert_eq!(DiffFormat::from_str("bsdiff"), Some(DiffFormat::BsdDiff));
+        assert_eq!(
+            DiffFormat::from_str("element-delta"),
+            Some(DiffFormat::ElementDelta)
+        );
         assert_eq!(DiffFormat::from_str("invalid"), None);
     }
 
+    #[test]
+    fn test_diff_format_media_type() {
+        assert_eq!(
+            DiffFormat::BinaryDelta.media_type(),
+            "application/vnd.bpx.binary-delta"
+        );
+        assert_eq!(
+            DiffFormat::JsonPatch.media_type(),
+            "application/json-patch+json"
+        );
+    }
+
     #[test]
     fn test_session_expiration() {
-        let mut session = BpxSession::new(SessionId::new("test".to_string()));
-        let ttl = Duration::from_millis(100);
+        let clock = Arc::new(clock::MockClock::new());
+        let session = BpxSession::with_clock(SessionId::new("test".to_string()), clock.clone());
+        let ttl = Duration::from_millis(20);
 
         assert!(!session.is_expired(ttl));
 
-        // Manually set last_accessed to simulate expiration
-        session.last_accessed = Instant::now() - Duration::from_millis(200);
+        clock.advance(Duration::from_millis(40));
         assert!(session.is_expired(ttl));
+
+        session.touch();
+        assert!(!session.is_expired(ttl));
     }
 
     #[test]
@@ -439,10 +2300,121 @@ mod tests {
         let config = BpxConfig::default();
         assert_eq!(config.max_sessions, 100_000);
         assert_eq!(config.max_resources_per_session, 1_000);
+        assert_eq!(config.max_memory, 256 * 1024 * 1024);
         assert_eq!(config.session_ttl, Duration::from_secs(24 * 60 * 60));
+        assert_eq!(config.session_grace, Duration::ZERO);
+        assert_eq!(config.session_retention(), config.session_ttl);
+        assert!(config.path_ttls.is_empty());
         assert_eq!(config.max_diff_size, 10 * 1024 * 1024);
+        assert_eq!(config.max_diff_operations, 1_000_000);
+        assert_eq!(config.max_patch_output_size, 64 * 1024 * 1024);
+        assert_eq!(config.max_insert_bytes, 10 * 1024 * 1024);
+        assert_eq!(config.max_batch_entries, 256);
         assert_eq!(config.min_compression_ratio, 0.2);
         assert_eq!(config.cleanup_interval, Duration::from_secs(5 * 60));
+        assert!(!config.rfc3229_mode);
+        assert!(config.session_cookie.is_none());
+        assert!(config.tenant_limits.is_empty());
+        assert_eq!(config.default_tenant_limits, TenantLimits::default());
+        assert_eq!(config.default_tenant_limits.max_sessions, 10_000);
+        assert_eq!(
+            config.default_tenant_limits.max_resources_per_session,
+            1_000
+        );
+        assert_eq!(config.default_tenant_limits.max_memory, 32 * 1024 * 1024);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(config.query, QueryCanonicalization::default());
+        assert!(config.query.include.is_none());
+        assert!(config.query.exclude.is_empty());
+        assert!(config.query.sort);
+        assert!(config.rate_limit.is_none());
+        assert!(config.cors.is_none());
+        assert!(!config.load_limits.is_limited());
+        assert_eq!(config.version_storage, VersionStorage::OnChange);
+        assert!(!config.stats_endpoints);
+        assert!(config.admin_token.is_none());
+        assert!(config.affinity.is_none());
+        #[cfg(feature = "compression")]
+        assert!(config.compression.is_none());
+        assert!(config.path_methods.is_empty());
+    }
+
+    #[test]
+    fn test_canonical_resource_paths() {
+        let canonical = |uri: &str, query: &QueryCanonicalization| {
+            ResourcePath::canonical(uri, query).to_string()
+        };
+        let default = QueryCanonicalization::default();
+        assert_eq!(canonical("/api/items", &default), "/api/items");
+        assert_eq!(canonical("/api/items?", &default), "/api/items");
+        assert_eq!(
+            canonical("/api/items?page=1", &default),
+            "/api/items?page=1"
+        );
+        assert_ne!(
+            canonical("/api/items?page=1", &default),
+            canonical("/api/items?page=2", &default)
+        );
+        assert_eq!(
+            canonical("/api/items?sort=name&page=1&&tag=b&tag=a", &default),
+            "/api/items?page=1&sort=name&tag=b&tag=a"
+        );
+
+        let query = QueryCanonicalization {
+            exclude: vec!["_".to_string(), "utm_source".to_string()],
+            sort: false,
+            ..QueryCanonicalization::default()
+        };
+        assert_eq!(
+            canonical("/api/items?sort=name&_=123&page=1&utm_source=x", &query),
+            "/api/items?sort=name&page=1"
+        );
+
+        let query = QueryCanonicalization {
+            include: Some(vec!["page".to_string()]),
+            ..QueryCanonicalization::default()
+        };
+        assert_eq!(
+            canonical("/api/items?sort=name&page=2", &query),
+            "/api/items?page=2"
+        );
+        assert_eq!(
+            canonical("/api/items?page=2", &QueryCanonicalization::ignore()),
+            "/api/items"
+        );
+    }
+
+    #[test]
+    fn test_path_ttls() {
+        let config = BpxConfig {
+            session_ttl: Duration::from_secs(60),
+            path_ttls: vec![
+                PathTtl {
+                    prefix: "/api/".to_string(),
+                    ttl: Duration::from_secs(10),
+                },
+                PathTtl {
+                    prefix: "/api/catalog".to_string(),
+                    ttl: Duration::from_secs(3600),
+                },
+            ],
+            ..BpxConfig::default()
+        };
+        let path = |p: &str| ResourcePath::new(p.to_string());
+        let ticker = path("/api/ticker");
+        let catalog = path("/api/catalog/items");
+
+        assert_eq!(config.ttl_for(&ticker), Duration::from_secs(10));
+        assert_eq!(config.ttl_for(&catalog), Duration::from_secs(3600));
+        assert_eq!(config.ttl_for(&path("/other")), Duration::from_secs(60));
+        assert_eq!(config.max_session_retention(), Duration::from_secs(3600));
+
+        let idle = Duration::from_secs(120);
+        assert!(config.version_expired(&ticker, idle));
+        assert!(!config.version_expired(&catalog, idle));
+        assert!(config.session_expired(idle, [&ticker]));
+        assert!(!config.session_expired(idle, [&ticker, &catalog]));
+        assert!(!config.session_expired(Duration::from_secs(30), [&ticker]));
     }
 
     #[test]
@@ -467,6 +2439,901 @@ mod tests {
         assert!(Arc::ptr_eq(server.diff_engine(), &diff_engine));
     }
 
+    #[tokio::test]
+    async fn test_bpx_server_signs_responses() {
+        use crate::diff::similar::SimilarDiffEngine;
+        use crate::signing::{HmacSha256Signer, verify_response};
+        use crate::state::InMemoryStateManager;
+        use http_body_util::Empty;
+
+        let signer = Arc::new(HmacSha256Signer::new(b"shared secret".to_vec()));
+        let server = BpxServer::builder()
+            .state_manager(Arc::new(InMemoryStateManager::new(BpxConfig::default())))
+            .diff_engine(Arc::new(SimilarDiffEngine::new()))
+            .signer(signer.clone())
+            .build()
+            .unwrap();
+
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/doc".to_string()),
+            Bytes::from("hello"),
+        );
+        let req = Request::builder()
+            .uri("/api/doc")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = server.handle_request(req, store).await.unwrap();
+
+        assert!(verify_response(response.headers(), response.body(), signer.as_ref()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_rate_limits_sessions() {
+        use crate::diff::similar::SimilarDiffEngine;
+        use crate::protocol::headers::BpxHeaders;
+        use crate::state::InMemoryStateManager;
+        use http_body_util::Empty;
+        use hyper::StatusCode;
+
+        let config = BpxConfig {
+            rate_limit: Some(RateLimit {
+                burst: 1,
+                per_second: 1,
+                per_peer: false,
+            }),
+            ..BpxConfig::default()
+        };
+        let server = BpxServer::builder()
+            .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+            .diff_engine(Arc::new(SimilarDiffEngine::new()))
+            .config(config)
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/doc".to_string()),
+            Bytes::from("hello"),
+        );
+        let request = || {
+            Request::builder()
+                .uri("/api/doc")
+                .header(BpxHeaders::SESSION, "client-a")
+                .body(Empty::<Bytes>::new())
+                .unwrap()
+        };
+
+        assert!(
+            server
+                .handle_request(request(), store.clone())
+                .await
+                .is_ok()
+        );
+        match server.handle_request(request(), store).await {
+            Err(e @ BpxError::RateLimited { .. }) => {
+                let response = server::error_response(&e);
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert_eq!(response.headers()["retry-after"], "1");
+            }
+            other => panic!("expected rate limit, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_binds_sessions_to_peers() {
+        use crate::binding::BindingAction;
+        use crate::protocol::headers::BpxHeaders;
+        use crate::rate_limit::PeerAddr;
+        use http_body_util::{Empty, Full};
+
+        for on_mismatch in [BindingAction::Reissue, BindingAction::Reject] {
+            let server = BpxServer::builder()
+                .config(BpxConfig {
+                    session_binding: Some(SessionBinding {
+                        on_mismatch,
+                        ..SessionBinding::default()
+                    }),
+                    ..BpxConfig::default()
+                })
+                .build()
+                .unwrap();
+            let store = Arc::new(InMemoryResourceStore::new());
+            store.set_resource(
+                ResourcePath::new("/api/feed".to_string()),
+                Bytes::from("feed"),
+            );
+            let request = |peer: &str, session: Option<&SessionId>| {
+                let mut req = Request::get("/api/feed");
+                if let Some(session) = session {
+                    req = req.header(BpxHeaders::SESSION, session.to_string());
+                }
+                let mut req = req.body(Empty::<Bytes>::new()).unwrap();
+                req.extensions_mut().insert(PeerAddr(peer.parse().unwrap()));
+                req
+            };
+            let status =
+                |response: &Response<Bytes>| response.headers()[BpxHeaders::SESSION_STATUS].clone();
+
+            let first = server
+                .handle_request(request("192.0.2.1:4000", None), store.clone())
+                .await
+                .unwrap();
+            let session = server::response_session(&first).unwrap();
+            // The same client may reconnect from another port
+            let again = server
+                .handle_request(request("192.0.2.1:4001", Some(&session)), store.clone())
+                .await
+                .unwrap();
+            assert_eq!(status(&again), "resumed");
+
+            let replayed = server
+                .handle_request(request("198.51.100.2:4000", Some(&session)), store.clone())
+                .await;
+            match on_mismatch {
+                BindingAction::Reissue => {
+                    let replayed = replayed.unwrap();
+                    assert_eq!(status(&replayed), "created");
+                    assert_ne!(server::response_session(&replayed).unwrap(), session);
+                }
+                BindingAction::Reject => {
+                    assert!(matches!(replayed, Err(BpxError::Forbidden { .. })));
+                }
+            }
+
+            // Nor may a replayed session move its owner's base with a write
+            let path = ResourcePath::new("/api/feed".to_string());
+            let base = server.state_manager().get_version(&session, &path).await;
+            let mut put = Request::put("/api/feed")
+                .header(BpxHeaders::SESSION, session.to_string())
+                .body(Full::new(Bytes::from("forged")))
+                .unwrap();
+            put.extensions_mut()
+                .insert(PeerAddr("198.51.100.2:4000".parse().unwrap()));
+            let written = server.handle_write_request(put, store.clone()).await;
+            match on_mismatch {
+                BindingAction::Reissue => {
+                    let written = written.unwrap();
+                    assert_ne!(server::response_session(&written), Some(session.clone()));
+                }
+                BindingAction::Reject => {
+                    assert!(matches!(written, Err(BpxError::Forbidden { .. })));
+                }
+            }
+            assert_eq!(
+                server.state_manager().get_version(&session, &path).await,
+                base
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_holds_no_state_for_unknown_sessions() {
+        use crate::protocol::headers::BpxHeaders;
+        use crate::rate_limit::PeerAddr;
+        use http_body_util::{Empty, Full};
+
+        let limit = UnknownSessionPolicy::Limit(RateLimit {
+            burst: 2,
+            per_second: 0,
+            per_peer: true,
+        });
+        for policy in [UnknownSessionPolicy::Reject, limit] {
+            let server = BpxServer::builder()
+                .config(BpxConfig {
+                    unknown_sessions: policy.clone(),
+                    ..BpxConfig::default()
+                })
+                .build()
+                .unwrap();
+            let store = Arc::new(InMemoryResourceStore::new());
+            store.set_resource(
+                ResourcePath::new("/api/feed".to_string()),
+                Bytes::from("feed"),
+            );
+            let request = |session: Option<&SessionId>| {
+                let mut req = Request::get("/api/feed");
+                if let Some(session) = session {
+                    req = req.header(BpxHeaders::SESSION, session.to_string());
+                }
+                let mut req = req.body(Empty::<Bytes>::new()).unwrap();
+                req.extensions_mut()
+                    .insert(PeerAddr("192.0.2.1:4000".parse().unwrap()));
+                req
+            };
+
+            let first = server
+                .handle_request(request(None), store.clone())
+                .await
+                .unwrap();
+            let session = server::response_session(&first).unwrap();
+            for _ in 0..3 {
+                let resumed = server
+                    .handle_request(request(Some(&session)), store.clone())
+                    .await;
+                assert!(resumed.is_ok());
+            }
+
+            // Made-up IDs take no room, whichever way they are turned away
+            for i in 0..10 {
+                let made_up = SessionId::new(format!("made-up-{}", i));
+                let result = server
+                    .handle_request(request(Some(&made_up)), store.clone())
+                    .await;
+                match (&policy, i) {
+                    (UnknownSessionPolicy::Limit(_), 0) => assert!(result.is_ok()),
+                    (UnknownSessionPolicy::Limit(_), _) => {
+                        assert!(matches!(result, Err(BpxError::RateLimited { .. })));
+                    }
+                    _ => assert!(matches!(result, Err(BpxError::ClientStateNotFound { .. }))),
+                }
+            }
+            // Nor by writing under them
+            let mut put = Request::put("/api/feed")
+                .header(BpxHeaders::SESSION, "made-up-put")
+                .body(Full::new(Bytes::from("written")))
+                .unwrap();
+            put.extensions_mut()
+                .insert(PeerAddr("192.0.2.1:4000".parse().unwrap()));
+            let written = server.handle_write_request(put, store.clone()).await;
+            match policy {
+                UnknownSessionPolicy::Limit(_) => {
+                    assert!(matches!(written, Err(BpxError::RateLimited { .. })));
+                }
+                _ => assert!(matches!(written, Err(BpxError::ClientStateNotFound { .. }))),
+            }
+            let expected = match policy {
+                UnknownSessionPolicy::Limit(_) => 2,
+                _ => 1,
+            };
+            assert_eq!(server.state_manager().session_count(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_enforces_session_quotas() {
+        use crate::diff::similar::SimilarDiffEngine;
+        use crate::protocol::headers::BpxHeaders;
+        use crate::quota::QuotaAction;
+        use crate::state::InMemoryStateManager;
+        use http_body_util::Empty;
+        use hyper::StatusCode;
+
+        let path = ResourcePath::new("/api/doc".to_string());
+        for action in [QuotaAction::FullOnly, QuotaAction::Reject] {
+            let config = BpxConfig {
+                session_quota: Some(SessionQuota {
+                    window: Duration::from_secs(60),
+                    max_bytes: Some(16),
+                    max_diff_time: None,
+                    action,
+                }),
+                ..BpxConfig::default()
+            };
+            let server = BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .config(config)
+                .build()
+                .unwrap();
+            let store = Arc::new(InMemoryResourceStore::new());
+            store.set_resource(path.clone(), Bytes::from("line one\nline two\n"));
+            let request = |session_and_base: Option<(&str, &str)>| {
+                let mut builder = Request::builder().uri("/api/doc");
+                if let Some((session, base)) = session_and_base {
+                    builder = builder
+                        .header(BpxHeaders::SESSION, session)
+                        .header(BpxHeaders::BASE_VERSION, base);
+                }
+                builder.body(Empty::<Bytes>::new()).unwrap()
+            };
+
+            let first = server
+                .handle_request(request(None), store.clone())
+                .await
+                .unwrap();
+            let header = |name| first.headers()[name].to_str().unwrap().to_string();
+            let (session, base) = (
+                header(BpxHeaders::SESSION),
+                header(BpxHeaders::RESOURCE_VERSION),
+            );
+            store.set_resource(path.clone(), Bytes::from("line one\nline 2\n"));
+
+            let result = server
+                .handle_request(request(Some((&session, &base))), store.clone())
+                .await;
+            match action {
+                QuotaAction::FullOnly => {
+                    let response = result.unwrap();
+                    assert_eq!(
+                        response.headers()[BpxHeaders::FALLBACK_REASON],
+                        "quota-exceeded"
+                    );
+                }
+                QuotaAction::Reject => {
+                    let err = result.unwrap_err();
+                    assert!(matches!(err, BpxError::QuotaExceeded { .. }));
+                    let response = server::error_response(&err);
+                    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                    assert!(response.headers().contains_key("retry-after"));
+                }
+            }
+
+            // Other sessions are unaffected
+            let other = server
+                .handle_request(request(None), store.clone())
+                .await
+                .unwrap();
+            assert_eq!(other.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_load_limits() {
+        use crate::diff::similar::SimilarDiffEngine;
+        use crate::protocol::headers::BpxHeaders;
+        use crate::state::InMemoryStateManager;
+        use http_body_util::Empty;
+
+        let server = |load_limits: LoadLimits| {
+            let config = BpxConfig {
+                load_limits,
+                ..BpxConfig::default()
+            };
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .config(config)
+                .build()
+                .unwrap()
+        };
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        let request = |session: &str, base: &str| {
+            Request::builder()
+                .uri("/api/logs")
+                .header(BpxHeaders::SESSION, session)
+                .header(BpxHeaders::BASE_VERSION, base)
+                .header(BpxHeaders::ACCEPT_DIFF, "binary-delta")
+                .body(Empty::<Bytes>::new())
+                .unwrap()
+        };
+
+        // No diff slots: clients that could get a diff get the full body
+        let degraded = server(LoadLimits {
+            max_concurrent_diffs: Some(0),
+            ..LoadLimits::default()
+        });
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+        let first = degraded
+            .handle_request(request("sess_a", "none"), store.clone())
+            .await
+            .unwrap();
+        let session = first.headers()[BpxHeaders::SESSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let v1 = first.headers()[BpxHeaders::RESOURCE_VERSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        store.set_resource(path.clone(), Bytes::from(lines(101)));
+        let response = degraded
+            .handle_request(request(&session, &v1), store.clone())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(
+            response.headers()[BpxHeaders::FALLBACK_REASON],
+            "overloaded"
+        );
+
+        // No request slots: everything is shed
+        let shedding = server(LoadLimits {
+            max_outstanding_requests: Some(0),
+            ..LoadLimits::default()
+        });
+        assert!(matches!(
+            shedding
+                .handle_request(request("sess_b", "none"), store)
+                .await,
+            Err(BpxError::Overloaded { max_outstanding: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_isolates_tenants() {
+        use crate::diff::similar::SimilarDiffEngine;
+        use crate::protocol::headers::BpxHeaders;
+        use crate::state::TenantStateManager;
+        use crate::tenant::{HeaderTenant, TENANT_HEADER};
+        use http_body_util::Empty;
+
+        let config = BpxConfig::default();
+        let state_mgr = Arc::new(TenantStateManager::new(config.clone()));
+        let server = BpxServer::builder()
+            .state_manager(state_mgr.clone())
+            .diff_engine(Arc::new(SimilarDiffEngine::new()))
+            .tenant_resolver(Arc::new(HeaderTenant::default()))
+            .config(config)
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        let acme = TenantId::new("acme".to_string());
+        let globex = TenantId::new("globex".to_string());
+        let path = ResourcePath::new("/api/doc".to_string());
+        store.set_resource(acme.scope(&path), Bytes::from("acme doc"));
+        store.set_resource(globex.scope(&path), Bytes::from("globex doc"));
+        let request = |tenant: Option<&str>, session: Option<&str>| {
+            let mut builder = Request::builder().uri("/api/doc");
+            if let Some(tenant) = tenant {
+                builder = builder.header(TENANT_HEADER, tenant);
+            }
+            if let Some(session) = session {
+                builder = builder.header(BpxHeaders::SESSION, session);
+            }
+            builder.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        // The same path is a different resource for each tenant
+        let first = server
+            .handle_request(request(Some("acme"), None), store.clone())
+            .await
+            .unwrap();
+        assert_eq!(first.body(), "acme doc");
+        let session = first.headers()[BpxHeaders::SESSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let other = server
+            .handle_request(request(Some("globex"), Some(&session)), store.clone())
+            .await
+            .unwrap();
+        assert_eq!(other.body(), "globex doc");
+        // ...and one tenant's session means nothing to another
+        assert_eq!(other.headers()[BpxHeaders::SESSION_STATUS], "created");
+        assert_ne!(other.headers()[BpxHeaders::SESSION], session.as_str());
+
+        assert!(matches!(
+            server
+                .handle_request(request(None, None), store.clone())
+                .await,
+            Err(BpxError::UnknownTenant { .. })
+        ));
+        assert!(matches!(
+            server
+                .handle_request(request(Some("../x"), None), store)
+                .await,
+            Err(BpxError::UnknownTenant { .. })
+        ));
+
+        assert_eq!(state_mgr.tenant_sessions(&acme), 1);
+        let stats = server.tenant_stats(&acme);
+        assert_eq!(stats.full_responses, 1);
+        assert_eq!(stats.sessions, 1);
+        assert_eq!(
+            server
+                .stats_snapshot(&InMemoryResourceStore::new())
+                .full_responses,
+            2
+        );
+        assert_eq!(
+            server
+                .tenant_stats(&TenantId::new("initech".to_string()))
+                .full_responses,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_prime() {
+        use crate::diff::{CachingDiffEngine, similar::SimilarDiffEngine};
+        use crate::state::InMemoryStateManager;
+        use http_body_util::Empty;
+
+        let config = BpxConfig {
+            rfc3229_mode: true,
+            ..BpxConfig::default()
+        };
+        let diff_cache = Arc::new(CachingDiffEngine::new(
+            Arc::new(SimilarDiffEngine::new()),
+            1024 * 1024,
+        ));
+        let server = BpxServer::builder()
+            .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+            .diff_engine(diff_cache.clone())
+            .config(config)
+            .build()
+            .unwrap();
+
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+        let v1 = server.prime(&path, store.as_ref(), 2).await.unwrap();
+        assert_eq!(v1, Version::from_content(lines(100).as_bytes()));
+        assert!(store.get_resource_version(&path, &v1).await.is_ok());
+        assert_eq!(diff_cache.cached_bytes(), 0);
+
+        store.set_resource(path.clone(), Bytes::from(lines(101)));
+        let v2 = server.prime(&path, store.as_ref(), 2).await.unwrap();
+        let primed = diff_cache.cached_bytes();
+        assert!(primed > 0);
+
+        // A client on the old version is answered from the cache
+        let req = Request::builder()
+            .uri("/api/logs")
+            .header("A-IM", "binary-delta")
+            .header("If-None-Match", format!("\"{}\"", v1))
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = server.handle_request(req, store).await.unwrap();
+        assert_eq!(response.status().as_u16(), 226);
+        assert_eq!(response.headers()["ETag"], format!("\"{}\"", v2));
+        assert_eq!(diff_cache.cached_bytes(), primed);
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_records_metrics() {
+        use crate::protocol::{FallbackReason, headers::BpxHeaders};
+        use http_body_util::{Empty, Full};
+
+        let metrics = Arc::new(metrics::BpxMetrics::new());
+        let server = BpxServer::builder()
+            .config(BpxConfig {
+                slow_diff_threshold: Some(Duration::ZERO),
+                ..BpxConfig::default()
+            })
+            .metrics(metrics.clone())
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+
+        let response = server
+            .handle_request(
+                Request::builder()
+                    .uri("/api/logs")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                store.clone(),
+            )
+            .await
+            .unwrap();
+        let headers = response.headers();
+        let session = headers[BpxHeaders::SESSION].to_str().unwrap().to_string();
+        let base = headers[BpxHeaders::RESOURCE_VERSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        store.set_resource(path, Bytes::from(lines(101)));
+        let response = server
+            .handle_request(
+                Request::builder()
+                    .uri("/api/logs")
+                    .header(BpxHeaders::SESSION, session)
+                    .header(BpxHeaders::BASE_VERSION, base)
+                    .header(BpxHeaders::ACCEPT_DIFF, "binary-delta")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                store,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        server
+            .handle_request(
+                Request::builder()
+                    .uri("/api/missing")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                Arc::new(InMemoryResourceStore::new()),
+            )
+            .await
+            .unwrap_err();
+        server
+            .handle_write_request(
+                Request::patch("/api/logs")
+                    .body(Full::new(Bytes::from("patch")))
+                    .unwrap(),
+                Arc::new(InMemoryResourceStore::new()),
+            )
+            .await
+            .unwrap_err();
+        server
+            .handle_batch_request(
+                Request::post("/batch")
+                    .body(Full::new(Bytes::from_static(&[0x00, 0x05])))
+                    .unwrap(),
+                Arc::new(InMemoryResourceStore::new()),
+            )
+            .await
+            .unwrap_err();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.errors_for("resource-not-found"), 1);
+        assert_eq!(snapshot.errors_for("invalid-request"), 2);
+        assert_eq!(snapshot.full_for(FallbackReason::NoBase), 1);
+        assert_eq!(snapshot.diffs(), 1);
+        assert!(snapshot.bytes_saved() > 0);
+        assert_eq!(snapshot.sessions, 1);
+        assert_eq!(snapshot.diff_latency.count, 1);
+        assert_eq!(snapshot.slow_diffs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_observes_decisions() {
+        use crate::protocol::{FallbackReason, headers::BpxHeaders};
+        use http_body_util::Empty;
+        use std::sync::Mutex;
+
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let seen = decisions.clone();
+        let server = BpxServer::builder()
+            .observer(Arc::new(move |decision: &DiffDecision| {
+                seen.lock().unwrap().push(decision.clone());
+            }))
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+
+        let response = server
+            .handle_request(
+                Request::builder()
+                    .uri("/api/logs")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                store.clone(),
+            )
+            .await
+            .unwrap();
+        let session = response.headers()[BpxHeaders::SESSION].clone();
+        let base = response.headers()[BpxHeaders::RESOURCE_VERSION].clone();
+        store.set_resource(path.clone(), Bytes::from(lines(101)));
+        let response = server
+            .handle_request(
+                Request::builder()
+                    .uri("/api/logs")
+                    .header(BpxHeaders::SESSION, session)
+                    .header(BpxHeaders::BASE_VERSION, base.clone())
+                    .header(BpxHeaders::ACCEPT_DIFF, "binary-delta")
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                store,
+            )
+            .await
+            .unwrap();
+
+        let decisions = decisions.lock().unwrap();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].path, path);
+        assert_eq!(decisions[0].base, None);
+        assert_eq!(decisions[0].chosen_format, None);
+        assert_eq!(decisions[0].full_size, Some(lines(100).len()));
+        assert_eq!(decisions[0].reason, Some(FallbackReason::NoBase));
+
+        let diff = &decisions[1];
+        assert!(diff.is_diff());
+        assert_eq!(
+            diff.base.as_ref().unwrap().to_string(),
+            base.to_str().unwrap()
+        );
+        assert_eq!(
+            diff.current.to_string(),
+            response.headers()[BpxHeaders::RESOURCE_VERSION]
+        );
+        assert_eq!(diff.chosen_format, Some(DiffFormat::BinaryDelta));
+        assert_eq!(diff.diff_size, Some(response.body().len()));
+        assert_eq!(diff.full_size, Some(lines(101).len()));
+        assert_eq!(diff.reason, None);
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_counts_batch_entries() {
+        use crate::protocol::{
+            FallbackReason,
+            headers::BpxHeaders,
+            wire::{BatchRequest, BatchRequestEntry},
+        };
+        use http_body_util::Full;
+        use std::sync::Mutex;
+
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let seen = decisions.clone();
+        let server = BpxServer::builder()
+            .observer(Arc::new(move |decision: &DiffDecision| {
+                seen.lock().unwrap().push(decision.clone());
+            }))
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+        let batch = |session: Option<&hyper::header::HeaderValue>, base: Option<Version>| {
+            let body = BatchRequest {
+                entries: vec![BatchRequestEntry {
+                    path: path.clone(),
+                    base_version: base,
+                }],
+            }
+            .encode()
+            .unwrap();
+            let mut req = Request::post("/batch");
+            if let Some(session) = session {
+                req = req.header(BpxHeaders::SESSION, session);
+            }
+            req.body(Full::new(body)).unwrap()
+        };
+
+        let response = server
+            .handle_batch_request(batch(None, None), store.clone())
+            .await
+            .unwrap();
+        let session = response.headers()[BpxHeaders::SESSION].clone();
+        let base = store.get_versioned_resource(&path).await.unwrap().1;
+        store.set_resource(path.clone(), Bytes::from(lines(101)));
+        server
+            .handle_batch_request(batch(Some(&session), Some(base)), store)
+            .await
+            .unwrap();
+
+        let decisions = decisions.lock().unwrap();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].reason, Some(FallbackReason::NoBase));
+        assert!(decisions[1].is_diff());
+        let snapshot = server.stats().snapshot(1, None);
+        assert_eq!(snapshot.full_responses, 1);
+        assert_eq!(snapshot.diffs, 1);
+        assert_eq!(
+            snapshot.bytes_saved,
+            (lines(101).len() - decisions[1].diff_size.unwrap()) as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_logs_access() {
+        use crate::access_log::AccessLogEntry;
+        use http_body_util::{Empty, Full};
+        use std::sync::Mutex;
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let logged = entries.clone();
+        let server = BpxServer::builder()
+            .access_log(Arc::new(move |entry: &AccessLogEntry| {
+                logged.lock().unwrap().push(entry.clone());
+            }))
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+        let request = |uri: &str| Request::get(uri).body(Empty::<Bytes>::new()).unwrap();
+
+        server
+            .handle_request(request("/api/feed"), store.clone())
+            .await
+            .unwrap();
+        server
+            .handle_request_streaming(request("/api/missing"), store.clone())
+            .await
+            .unwrap_err();
+        let put = Request::put("/api/feed")
+            .body(Full::new(Bytes::from("fed")))
+            .unwrap();
+        server
+            .handle_write_request(put, store.clone())
+            .await
+            .unwrap();
+        let batch = Request::post("/batch")
+            .body(Full::new(Bytes::from_static(&[0x00, 0x05])))
+            .unwrap();
+        server.handle_batch_request(batch, store).await.unwrap_err();
+
+        let entries = entries.lock().unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].outcome(), "full");
+        assert_eq!(entries[0].bytes_sent, Some(4));
+        assert!(entries[0].session.is_some());
+        assert_eq!(
+            entries[0].decision.as_ref().unwrap().reason,
+            Some(FallbackReason::NoBase)
+        );
+        assert_eq!(entries[1].path.to_string(), "/api/missing");
+        assert_eq!(entries[1].status, hyper::StatusCode::NOT_FOUND);
+        assert_eq!(entries[1].error, Some("resource-not-found"));
+        assert_eq!(entries[2].method, hyper::Method::PUT);
+        assert_eq!(entries[2].status, hyper::StatusCode::NO_CONTENT);
+        assert_eq!(entries[3].method, hyper::Method::POST);
+        assert_eq!(entries[3].status, hyper::StatusCode::BAD_REQUEST);
+        assert_eq!(entries[3].error, Some("invalid-request"));
+    }
+
+    #[tokio::test]
+    async fn test_bpx_server_audits_sessions_and_admin() {
+        use crate::audit::{AuditEvent, AuditRecord};
+        use crate::protocol::headers::BpxHeaders;
+        use http_body_util::Empty;
+        use std::sync::Mutex;
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let recorded = records.clone();
+        let server = BpxServer::builder()
+            .config(BpxConfig {
+                admin_token: Some("secret".to_string()),
+                ..BpxConfig::default()
+            })
+            .audit_log(Arc::new(move |record: &AuditRecord| {
+                recorded.lock().unwrap().push(record.event.clone());
+            }))
+            .build()
+            .unwrap();
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+        let request = |session: Option<&str>| {
+            let mut builder = Request::get("/api/feed");
+            if let Some(session) = session {
+                builder = builder.header(BpxHeaders::SESSION, session);
+            }
+            builder.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        let response = server
+            .handle_request(request(None), store.clone())
+            .await
+            .unwrap();
+        let session = server::response_session(&response).unwrap();
+        server
+            .handle_request(request(Some(&session.to_string())), store.clone())
+            .await
+            .unwrap();
+        server
+            .handle_request(request(Some("forgotten")), store.clone())
+            .await
+            .unwrap();
+        server
+            .tune("ops", admin::Setting::MaxDiffSize(2048))
+            .unwrap();
+        server
+            .handle_debug_request(
+                Request::get(admin::DEBUG_PATH)
+                    .body(Empty::<Bytes>::new())
+                    .unwrap(),
+                store.as_ref(),
+            )
+            .await
+            .unwrap_err();
+
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], AuditEvent::SessionCreated { session });
+        assert!(matches!(
+            &records[1],
+            AuditEvent::SessionReplaced { presented, .. } if presented.to_string() == "forgotten"
+        ));
+        assert!(matches!(
+            &records[2],
+            AuditEvent::AdminAction { actor, action }
+                if actor == "ops" && action.starts_with("set max_diff_size from ")
+        ));
+        assert_eq!(
+            records[3],
+            AuditEvent::AccessDenied {
+                path: ResourcePath::new(admin::DEBUG_PATH.to_string())
+            }
+        );
+    }
+
     #[test]
     fn test_bpx_server_builder_missing_state_manager() {
         use crate::diff::similar::SimilarDiffEngine;
@@ -474,15 +3341,14 @@ mod tests {
         let config = BpxConfig::default();
         let diff_engine: Arc<dyn DiffEngine> = Arc::new(SimilarDiffEngine::new());
 
-        let result = BpxServer::builder()
+        let server = BpxServer::builder()
             .config(config)
             .diff_engine(diff_engine)
-            .build();
+            .build()
+            .unwrap();
 
-        assert!(result.is_err());
-        if let Err(e) = result {
-            assert!(matches!(e, BpxError::DiffComputationFailed { .. }));
-        }
+        // Sessions are kept in memory
+        assert_eq!(server.state_manager().session_count(), 0);
     }
 
     #[test]
@@ -493,14 +3359,63 @@ mod tests {
         let state_manager: Arc<dyn StateManager> =
             Arc::new(InMemoryStateManager::new(config.clone()));
 
-        let result = BpxServer::builder()
+        let server = BpxServer::builder()
             .config(config)
             .state_manager(state_manager)
-            .build();
+            .build()
+            .unwrap();
+
+        // Diffs are computed by the similar engine
+        let diff = server
+            .diff_engine()
+            .compute_diff(b"hello", b"hello!")
+            .unwrap();
+        assert_eq!(
+            server.diff_engine().apply_diff(b"hello", &diff).unwrap(),
+            &b"hello!"[..]
+        );
+    }
 
-        assert!(result.is_err());
-        if let Err(e) = result {
-            assert!(matches!(e, BpxError::DiffComputationFailed { .. }));
+    #[test]
+    fn test_bpx_server_builder_rejects_invalid_configs() {
+        assert!(BpxServer::builder().build().is_ok());
+        let invalid = [
+            BpxConfig {
+                version_storage: VersionStorage::Sampled(0),
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                rate_limit: Some(RateLimit {
+                    burst: 0,
+                    per_second: 10,
+                    per_peer: false,
+                }),
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                session_cookie: Some("bpx session".to_string()),
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                session_cookie: Some("bpx".to_string()),
+                affinity: Some(AffinityConfig {
+                    header: None,
+                    cookie: Some("bpx".to_string()),
+                }),
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                affinity: Some(AffinityConfig {
+                    header: Some("X-Bad Header".to_string()),
+                    cookie: None,
+                }),
+                ..BpxConfig::default()
+            },
+        ];
+        for config in invalid {
+            let err = BpxServer::builder().config(config).build().err().unwrap();
+            assert!(matches!(err, BpxError::BuilderError { .. }), "{}", err);
+            assert_eq!(err.code(), "invalid-configuration");
         }
     }
 
@@ -527,6 +3442,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_bpx_server_builder_custom_config() {
         use crate::diff::similar::SimilarDiffEngine;
         use crate::state::InMemoryStateManager;
@@ -553,10 +3469,45 @@ mod tests {
         assert_eq!(server_config.min_compression_ratio, 0.3);
     }
 
+    #[test]
+    fn test_bpx_server_tune() {
+        let server = BpxServer::builder().build().unwrap();
+        let before = server.config();
+        let change = server
+            .tune("ops", admin::Setting::MaxDiffSize(1024))
+            .unwrap();
+        assert_eq!(change.actor, "ops");
+        assert_eq!(change.old, admin::Setting::MaxDiffSize(10 * 1024 * 1024));
+        // Snapshots taken earlier keep their settings
+        assert_eq!(before.max_diff_size, 10 * 1024 * 1024);
+        assert_eq!(server.config().max_diff_size, 1024);
+
+        let err = server
+            .tune("ops", admin::Setting::DiffCacheBytes(0))
+            .unwrap_err();
+        assert!(matches!(err, BpxError::InvalidRequest { .. }));
+        // Settings the config can't run with change nothing
+        for setting in [
+            admin::Setting::MinCompressionRatio(f32::NAN),
+            admin::Setting::MinCompressionRatio(5.0),
+            admin::Setting::MaxDiffSize(0),
+        ] {
+            let err = server.tune("ops", setting).unwrap_err();
+            assert!(matches!(err, BpxError::InvalidRequest { .. }));
+        }
+        assert_eq!(server.config().max_diff_size, 1024);
+        assert_eq!(
+            server.config().min_compression_ratio,
+            before.min_compression_ratio
+        );
+        assert_eq!(server.setting_changes(), [change]);
+    }
+
     #[test]
     fn test_bpx_session_new_and_touch() {
         let session_id = SessionId::new("test_session".to_string());
-        let mut session = BpxSession::new(session_id.clone());
+        let clock = Arc::new(clock::MockClock::new());
+        let session = BpxSession::with_clock(session_id.clone(), clock.clone());
 
         assert_eq!(session.id, session_id);
         assert_eq!(session.resources.len(), 0);
@@ -567,27 +3518,26 @@ mod tests {
             0
         );
 
-        let initial_time = session.last_accessed;
+        let initial_time = session.last_accessed();
 
-        // Wait a tiny bit then touch
-        std::thread::sleep(Duration::from_millis(1));
+        clock.advance(Duration::from_millis(1));
         session.touch();
 
-        assert!(session.last_accessed > initial_time);
+        assert!(session.last_accessed() > initial_time);
     }
 
     #[test]
     fn test_bpx_session_expiration() {
         let session_id = SessionId::new("test_session".to_string());
-        let session = BpxSession::new(session_id);
+        let clock = Arc::new(clock::MockClock::new());
+        let session = BpxSession::with_clock(session_id, clock.clone());
         let very_short_ttl = Duration::from_millis(1);
         let long_ttl = Duration::from_secs(3600);
 
         // Should not be expired with long TTL
         assert!(!session.is_expired(long_ttl));
 
-        // Wait for very short TTL to pass
-        std::thread::sleep(Duration::from_millis(2));
+        clock.advance(Duration::from_millis(2));
 
         // Should be expired with very short TTL
         assert!(session.is_expired(very_short_ttl));
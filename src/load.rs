@@ -0,0 +1,126 @@
+//! Limits on concurrent work, degrading or shedding beyond them
+//!
+//! A diff costs far more than sending the body it replaces, so past
+//! [`LoadLimits::max_concurrent_diffs`] requests are answered with the full
+//! body instead (fallback reason `overloaded`). Past
+//! [`LoadLimits::max_outstanding_requests`] requests are refused with
+//! [`BpxError::Overloaded`] (`503` with `Retry-After`).
+
+use crate::BpxError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Caps on concurrent work; `None` doesn't limit
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct LoadLimits {
+    /// Diffs computed at once
+    pub max_concurrent_diffs: Option<usize>,
+    /// Requests being handled at once
+    pub max_outstanding_requests: Option<usize>,
+}
+
+impl LoadLimits {
+    /// Whether any limit is set
+    pub fn is_limited(&self) -> bool {
+        self.max_concurrent_diffs.is_some() || self.max_outstanding_requests.is_some()
+    }
+}
+
+/// Counts work in progress against [`LoadLimits`]
+#[derive(Debug, Default)]
+pub struct LoadShedder {
+    limits: LoadLimits,
+    diffs: AtomicUsize,
+    requests: AtomicUsize,
+}
+
+/// Work counted until dropped
+pub(crate) struct Slot<'a>(&'a AtomicUsize);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl LoadShedder {
+    /// Enforce `limits`
+    pub fn new(limits: LoadLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// Diffs being computed
+    pub fn in_flight_diffs(&self) -> usize {
+        self.diffs.load(Ordering::Acquire)
+    }
+
+    /// Requests being handled
+    pub fn outstanding_requests(&self) -> usize {
+        self.requests.load(Ordering::Acquire)
+    }
+
+    /// Count a request, unless as many as allowed are being handled
+    pub(crate) fn request(&self) -> Result<Slot<'_>, BpxError> {
+        take(&self.requests, self.limits.max_outstanding_requests).ok_or(BpxError::Overloaded {
+            max_outstanding: self.limits.max_outstanding_requests.unwrap_or_default(),
+        })
+    }
+
+    /// Count a diff, unless as many as allowed are being computed
+    pub(crate) fn diff(&self) -> Option<Slot<'_>> {
+        take(&self.diffs, self.limits.max_concurrent_diffs)
+    }
+}
+
+/// Count a request against `load`, if there is one
+pub(crate) fn admit(load: Option<&LoadShedder>) -> Result<Option<Slot<'_>>, BpxError> {
+    load.map(LoadShedder::request).transpose()
+}
+
+fn take(count: &AtomicUsize, max: Option<usize>) -> Option<Slot<'_>> {
+    let taken = count.fetch_add(1, Ordering::AcqRel);
+    let slot = Slot(count);
+    match max {
+        Some(max) if taken >= max => None,
+        _ => Some(slot),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_limited_and_released() {
+        let load = LoadShedder::new(LoadLimits {
+            max_concurrent_diffs: Some(1),
+            max_outstanding_requests: Some(2),
+        });
+
+        let first = load.request().unwrap();
+        let second = load.request().unwrap();
+        assert!(matches!(
+            load.request(),
+            Err(BpxError::Overloaded { max_outstanding: 2 })
+        ));
+        assert_eq!(load.outstanding_requests(), 2);
+        drop(first);
+        assert!(load.request().is_ok());
+        drop(second);
+        assert_eq!(load.outstanding_requests(), 0);
+
+        let diff = load.diff().unwrap();
+        assert!(load.diff().is_none());
+        assert_eq!(load.in_flight_diffs(), 1);
+        drop(diff);
+        assert!(load.diff().is_some());
+        assert_eq!(load.in_flight_diffs(), 0);
+    }
+}
@@ -0,0 +1,572 @@
+//! Metrics recorded while answering requests, for any monitoring backend
+//!
+//! A [`BpxServer`](crate::BpxServer) built with
+//! [`BpxServerBuilder::metrics`](crate::BpxServerBuilder::metrics) reports
+//! every response it answers, the sessions it holds and how long each diff
+//! took to compute to a [`MetricsRecorder`], along with the errors it
+//! answered and the diffs its engine failed to compute or apply, which are
+//! otherwise hidden behind full `200` responses. [`BpxMetrics`] keeps them
+//! in memory; implement the trait to forward them elsewhere.
+
+use crate::{
+    BpxError, DiffFormat, ResourcePath,
+    diff::{DiffEngine, DiffError, PatchLimits},
+    observer::DiffDecision,
+    protocol::{FallbackReason, headers::BpxHeaders},
+};
+use bytes::Bytes;
+use dashmap::DashMap;
+use hyper::{Response, StatusCode};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+/// Upper bounds of the diff latency histogram buckets; slower diffs fall in
+/// a last, unbounded bucket
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// Every diff format, in the order [`MetricsSnapshot::diffs_by_format`]
+/// counts them
+pub const FORMATS: [DiffFormat; 4] = [
+    DiffFormat::BinaryDelta,
+    DiffFormat::JsonPatch,
+    DiffFormat::BsdDiff,
+    DiffFormat::ElementDelta,
+];
+
+/// Every fallback reason, in the order [`MetricsSnapshot::full_by_reason`]
+/// counts them
+pub const FALLBACK_REASONS: [FallbackReason; 12] = [
+    FallbackReason::NoBase,
+    FallbackReason::FormatNotAccepted,
+    FallbackReason::Unchanged,
+    FallbackReason::NoHistory,
+    FallbackReason::NoSessionState,
+    FallbackReason::VersionMismatch,
+    FallbackReason::BaseUnavailable,
+    FallbackReason::TooLarge,
+    FallbackReason::Overloaded,
+    FallbackReason::QuotaExceeded,
+    FallbackReason::EngineError,
+    FallbackReason::NotWorthwhile,
+];
+
+/// Receives what a server does, as it does it
+///
+/// Called on the request path, so implementations should only update
+/// counters or hand the values off. `path` is the requested path without
+/// its query.
+pub trait MetricsRecorder: Send + Sync {
+    /// A diff in `format` of `sent` bytes was sent for `path` in place of
+    /// `original`
+    fn diff_sent(&self, path: &ResourcePath, format: DiffFormat, original: u64, sent: u64);
+
+    /// A full body of `sent` bytes was sent for `path`, for `reason` if a
+    /// diff was possible at all
+    fn full_sent(&self, path: &ResourcePath, reason: Option<FallbackReason>, sent: u64);
+
+    /// The client already held the current version of `path` (`304`)
+    fn not_modified(&self, path: &ResourcePath);
+
+    /// The server now holds `sessions` sessions
+    fn sessions_active(&self, sessions: usize);
+
+    /// A diff between `old_size` and `new_size` bytes took `elapsed` to
+    /// compute, or to fail
+    fn diff_computed(&self, elapsed: Duration, old_size: usize, new_size: usize);
+
+    /// Answering a request for `path` failed with `error`
+    fn request_failed(&self, path: &ResourcePath, error: &BpxError);
+
+    /// The diff engine failed with `error`; failed diffs are answered with
+    /// full bodies, failed patches refused
+    fn diff_failed(&self, error: &DiffError);
+
+    /// A diff took longer than
+    /// [`BpxConfig::slow_diff_threshold`](crate::BpxConfig::slow_diff_threshold)
+    fn slow_diff(&self, slow: &SlowDiff);
+}
+
+/// A diff that took longer than
+/// [`BpxConfig::slow_diff_threshold`](crate::BpxConfig::slow_diff_threshold)
+/// to compute
+///
+/// Attached to the extensions of the response it was computed for, as a
+/// `Vec<SlowDiff>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowDiff {
+    /// Resource diffed
+    pub path: ResourcePath,
+    /// Format computed, which picks the engine: element diffs have their
+    /// own, every other format uses the server's
+    pub format: DiffFormat,
+    /// Bytes of the base version
+    pub base_size: usize,
+    /// Bytes of the current version
+    pub current_size: usize,
+    /// Time the diff took, successful or not
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for SlowDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Slow {} diff for {}: {:?} ({} -> {} bytes)",
+            self.format.as_str(),
+            self.path,
+            self.elapsed,
+            self.base_size,
+            self.current_size
+        )
+    }
+}
+
+/// What a successful response sent, read from its BPX headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// `304`: the client already held the current version
+    NotModified,
+    /// A diff in `format` standing for `original` bytes
+    Diff {
+        format: DiffFormat,
+        original: Option<u64>,
+    },
+    /// A full body, for `reason` if a diff was possible at all
+    Full(Option<FallbackReason>),
+}
+
+impl Outcome {
+    /// Classify `response`, or `None` if it isn't a success
+    pub(crate) fn of<B>(response: &Response<B>) -> Option<Self> {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Some(Self::NotModified);
+        }
+        if !response.status().is_success() {
+            return None;
+        }
+        let headers = response.headers();
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        Some(
+            match header(BpxHeaders::DIFF_TYPE).and_then(DiffFormat::from_str) {
+                Some(format) => Self::Diff {
+                    format,
+                    original: header(BpxHeaders::ORIGINAL_SIZE).and_then(|size| size.parse().ok()),
+                },
+                None => Self::Full(
+                    header(BpxHeaders::FALLBACK_REASON).and_then(FallbackReason::from_str),
+                ),
+            },
+        )
+    }
+}
+
+/// Report `response` for `path`, whose body is `body_len` bytes if known,
+/// to `recorder`
+///
+/// Reads the format, original size and fallback reason from the BPX headers;
+/// errors are not reported.
+pub fn record_response<B>(
+    recorder: &dyn MetricsRecorder,
+    path: &ResourcePath,
+    response: &Response<B>,
+    body_len: Option<u64>,
+) {
+    let sent = body_len.unwrap_or_default();
+    match Outcome::of(response) {
+        Some(Outcome::NotModified) => recorder.not_modified(path),
+        Some(Outcome::Diff { format, original }) => {
+            recorder.diff_sent(path, format, original.unwrap_or(sent), sent);
+        }
+        Some(Outcome::Full(reason)) => recorder.full_sent(path, reason, sent),
+        None => {}
+    }
+}
+
+/// Report what `decision` sent for one entry of a batch to `recorder`, as
+/// [`record_response`] reports a resource answered alone
+pub fn record_decision(recorder: &dyn MetricsRecorder, decision: &DiffDecision) {
+    let full = decision.full_size.unwrap_or_default() as u64;
+    match (decision.chosen_format, decision.diff_size) {
+        (Some(format), Some(sent)) => {
+            recorder.diff_sent(&decision.path, format, full, sent as u64);
+        }
+        _ => recorder.full_sent(&decision.path, decision.reason, full),
+    }
+}
+
+/// Counts of diff computations by duration, per [`LATENCY_BUCKETS`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Computations at or under each bucket's bound (not cumulative), plus
+    /// a last count for those over every bound
+    pub buckets: Vec<u64>,
+    /// Computations recorded
+    pub count: u64,
+    /// Total time they took
+    pub sum: Duration,
+}
+
+/// [`MetricsRecorder`] keeping counts in memory
+#[derive(Debug)]
+pub struct BpxMetrics {
+    diffs: [AtomicU64; FORMATS.len()],
+    full: [AtomicU64; FALLBACK_REASONS.len() + 1],
+    not_modified: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_original: AtomicU64,
+    sessions: AtomicUsize,
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_nanos: AtomicU64,
+    errors: DashMap<&'static str, u64>,
+    diff_errors: DashMap<&'static str, u64>,
+    slow_diffs: AtomicU64,
+}
+
+impl Default for BpxMetrics {
+    fn default() -> Self {
+        Self {
+            diffs: Default::default(),
+            full: Default::default(),
+            not_modified: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_original: AtomicU64::new(0),
+            sessions: AtomicUsize::new(0),
+            latency: Default::default(),
+            latency_nanos: AtomicU64::new(0),
+            errors: DashMap::new(),
+            diff_errors: DashMap::new(),
+            slow_diffs: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BpxMetrics {
+    /// Create a recorder with every count at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Current counts
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counts: &[AtomicU64]| -> Vec<u64> {
+            counts.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+        };
+        let full = load(&self.full);
+        let latency = load(&self.latency);
+        MetricsSnapshot {
+            diffs_by_format: load(&self.diffs),
+            full_by_reason: full[..FALLBACK_REASONS.len()].to_vec(),
+            full_without_reason: full[FALLBACK_REASONS.len()],
+            not_modified: self.not_modified.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_original: self.bytes_original.load(Ordering::Relaxed),
+            sessions: self.sessions.load(Ordering::Relaxed),
+            diff_latency: LatencyHistogram {
+                count: latency.iter().sum(),
+                buckets: latency,
+                sum: Duration::from_nanos(self.latency_nanos.load(Ordering::Relaxed)),
+            },
+            errors: counts(&self.errors),
+            diff_errors: counts(&self.diff_errors),
+            slow_diffs: self.slow_diffs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MetricsRecorder for BpxMetrics {
+    fn diff_sent(&self, _path: &ResourcePath, format: DiffFormat, original: u64, sent: u64) {
+        if let Some(i) = FORMATS.iter().position(|f| *f == format) {
+            self.diffs[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_original.fetch_add(original, Ordering::Relaxed);
+    }
+
+    fn full_sent(&self, _path: &ResourcePath, reason: Option<FallbackReason>, sent: u64) {
+        let i = reason
+            .and_then(|reason| FALLBACK_REASONS.iter().position(|r| *r == reason))
+            .unwrap_or(FALLBACK_REASONS.len());
+        self.full[i].fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_original.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    fn not_modified(&self, _path: &ResourcePath) {
+        self.not_modified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn sessions_active(&self, sessions: usize) {
+        self.sessions.store(sessions, Ordering::Relaxed);
+    }
+
+    fn diff_computed(&self, elapsed: Duration, _old_size: usize, _new_size: usize) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn request_failed(&self, _path: &ResourcePath, error: &BpxError) {
+        *self.errors.entry(error.code()).or_default() += 1;
+    }
+
+    fn diff_failed(&self, error: &DiffError) {
+        *self.diff_errors.entry(error.code()).or_default() += 1;
+    }
+
+    fn slow_diff(&self, _slow: &SlowDiff) {
+        self.slow_diffs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts held in `map`, by key
+fn counts(map: &DashMap<&'static str, u64>) -> BTreeMap<&'static str, u64> {
+    map.iter()
+        .map(|entry| (*entry.key(), *entry.value()))
+        .collect()
+}
+
+/// Counts a [`BpxMetrics`] held at one point
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Diffs sent in each of [`FORMATS`]
+    pub diffs_by_format: Vec<u64>,
+    /// Full bodies sent for each of [`FALLBACK_REASONS`]
+    pub full_by_reason: Vec<u64>,
+    /// Full bodies sent without a reason, e.g. to HEAD requests
+    pub full_without_reason: u64,
+    /// `304` responses
+    pub not_modified: u64,
+    /// Body bytes sent
+    pub bytes_sent: u64,
+    /// Body bytes full responses would have sent instead
+    pub bytes_original: u64,
+    /// Sessions held when last reported
+    pub sessions: usize,
+    /// How long diffs took to compute
+    pub diff_latency: LatencyHistogram,
+    /// Errors answered, by [`BpxError::code`]
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Diffs the engine failed to compute or apply, by [`DiffError::code`]
+    pub diff_errors: BTreeMap<&'static str, u64>,
+    /// Diffs slower than the configured threshold
+    pub slow_diffs: u64,
+}
+
+impl MetricsSnapshot {
+    /// Diffs sent in any format
+    pub fn diffs(&self) -> u64 {
+        self.diffs_by_format.iter().sum()
+    }
+
+    /// Full bodies sent for any reason or none
+    pub fn full_responses(&self) -> u64 {
+        self.full_by_reason.iter().sum::<u64>() + self.full_without_reason
+    }
+
+    /// Full bodies sent for `reason`
+    pub fn full_for(&self, reason: FallbackReason) -> u64 {
+        FALLBACK_REASONS
+            .iter()
+            .position(|r| *r == reason)
+            .and_then(|i| self.full_by_reason.get(i).copied())
+            .unwrap_or_default()
+    }
+
+    /// Errors answered with `code` (see [`BpxError::code`])
+    pub fn errors_for(&self, code: &str) -> u64 {
+        self.errors.get(code).copied().unwrap_or_default()
+    }
+
+    /// Body bytes diffs saved
+    pub fn bytes_saved(&self) -> u64 {
+        self.bytes_original.saturating_sub(self.bytes_sent)
+    }
+
+    /// Share of responses with a body that carried a diff, 0 before any
+    pub fn diff_hit_rate(&self) -> f64 {
+        let diffs = self.diffs();
+        let total = diffs + self.full_responses();
+        if total == 0 {
+            0.0
+        } else {
+            diffs as f64 / total as f64
+        }
+    }
+}
+
+/// [`DiffEngine`] reporting how long each diff takes, and which fail, to a
+/// [`MetricsRecorder`]
+pub struct MeteredDiffEngine {
+    inner: Arc<dyn DiffEngine>,
+    recorder: Arc<dyn MetricsRecorder>,
+}
+
+impl MeteredDiffEngine {
+    /// Time diffs computed by `inner`
+    pub fn new(inner: Arc<dyn DiffEngine>, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        Self { inner, recorder }
+    }
+
+    /// Report `result` if it failed
+    fn failed(&self, result: Result<Bytes, DiffError>) -> Result<Bytes, DiffError> {
+        if let Err(error) = &result {
+            self.recorder.diff_failed(error);
+        }
+        result
+    }
+}
+
+impl DiffEngine for MeteredDiffEngine {
+    fn compute_diff(&self, old: &[u8], new: &[u8]) -> Result<Bytes, DiffError> {
+        let started = Instant::now();
+        let diff = self.inner.compute_diff(old, new);
+        self.recorder
+            .diff_computed(started.elapsed(), old.len(), new.len());
+        self.failed(diff)
+    }
+
+    fn apply_diff(&self, base: &[u8], diff: &[u8]) -> Result<Bytes, DiffError> {
+        self.failed(self.inner.apply_diff(base, diff))
+    }
+
+    fn apply_diff_limited(
+        &self,
+        base: &[u8],
+        diff: &[u8],
+        limits: &PatchLimits,
+    ) -> Result<Bytes, DiffError> {
+        self.failed(self.inner.apply_diff_limited(base, diff, limits))
+    }
+
+    fn is_diff_worthwhile(&self, original_size: usize, diff_size: usize) -> bool {
+        self.inner.is_diff_worthwhile(original_size, diff_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode, headers: &[(&str, &str)]) -> Response<()> {
+        let mut builder = Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_records_responses() {
+        let metrics = BpxMetrics::new();
+        let path = ResourcePath::new("/api/feed".to_string());
+        let diff = response(
+            StatusCode::OK,
+            &[
+                (BpxHeaders::DIFF_TYPE, "binary-delta"),
+                (BpxHeaders::ORIGINAL_SIZE, "1000"),
+            ],
+        );
+        record_response(&metrics, &path, &diff, Some(100));
+        let full = response(
+            StatusCode::OK,
+            &[
+                (BpxHeaders::DIFF_TYPE, "full"),
+                (BpxHeaders::FALLBACK_REASON, "version-mismatch"),
+            ],
+        );
+        record_response(&metrics, &path, &full, Some(1000));
+        record_response(
+            &metrics,
+            &path,
+            &response(StatusCode::NOT_MODIFIED, &[]),
+            None,
+        );
+        record_response(
+            &metrics,
+            &path,
+            &response(StatusCode::NOT_FOUND, &[]),
+            Some(10),
+        );
+        metrics.sessions_active(3);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.diffs(), 1);
+        assert_eq!(snapshot.diffs_by_format[0], 1);
+        assert_eq!(snapshot.full_responses(), 1);
+        assert_eq!(snapshot.full_for(FallbackReason::VersionMismatch), 1);
+        assert_eq!(snapshot.not_modified, 1);
+        assert_eq!(snapshot.bytes_sent, 1100);
+        assert_eq!(snapshot.bytes_saved(), 900);
+        assert_eq!(snapshot.diff_hit_rate(), 0.5);
+        assert_eq!(snapshot.sessions, 3);
+    }
+
+    #[test]
+    fn test_counts_errors() {
+        let metrics = BpxMetrics::new();
+        let path = ResourcePath::new("/api/feed".to_string());
+        let missing = BpxError::ResourceNotFound { path: path.clone() };
+        metrics.request_failed(&path, &missing);
+        metrics.request_failed(&path, &missing);
+        metrics.request_failed(&path, &BpxError::Overloaded { max_outstanding: 8 });
+
+        let recorder = Arc::new(BpxMetrics::new());
+        let engine = MeteredDiffEngine::new(
+            Arc::new(crate::diff::similar::SimilarDiffEngine::new()),
+            recorder.clone(),
+        );
+        assert!(engine.apply_diff(b"base", b"not a diff").is_err());
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.errors_for("resource-not-found"), 2);
+        assert_eq!(snapshot.errors_for("overloaded"), 1);
+        assert_eq!(snapshot.errors_for("forbidden"), 0);
+        assert_eq!(recorder.snapshot().diff_errors.values().sum::<u64>(), 1);
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let metrics = BpxMetrics::new();
+        metrics.diff_computed(Duration::from_micros(50), 10, 10);
+        metrics.diff_computed(Duration::from_millis(3), 10, 10);
+        metrics.diff_computed(Duration::from_secs(10), 10, 10);
+
+        let latency = metrics.snapshot().diff_latency;
+        assert_eq!(latency.count, 3);
+        assert_eq!(latency.buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert_eq!(latency.buckets[0], 1);
+        assert_eq!(latency.buckets[3], 1);
+        assert_eq!(latency.buckets[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(
+            latency.sum,
+            Duration::from_secs(10) + Duration::from_millis(3) + Duration::from_micros(50)
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! OpenTelemetry traces and metrics
+//!
+//! With the `otel` feature, [`BpxServer`](crate::BpxServer) opens a server
+//! span for every exchange it answers, continuing the trace the request's
+//! headers carry (as read by the global text map propagator), and
+//! [`OtelRecorder`] reports the metrics subsystem's events as OpenTelemetry
+//! instruments. Both go through the global providers under the [`SCOPE`]
+//! instrumentation scope, so they cost next to nothing until the
+//! application installs providers, e.g. OTLP exporters from
+//! `opentelemetry-otlp`.
+
+use super::{MetricsRecorder, Outcome, SlowDiff};
+use crate::{
+    BpxError, DiffFormat, ResourcePath,
+    diff::DiffError,
+    protocol::{FallbackReason, headers::BpxHeaders},
+    server::error_status,
+};
+use hyper::{HeaderMap, Request, Response};
+use opentelemetry::{
+    Context, KeyValue, global,
+    metrics::{Counter, Gauge, Histogram, Meter},
+    propagation::Extractor,
+    trace::{Span, SpanKind, Status, Tracer},
+};
+use std::time::Duration;
+
+/// Instrumentation scope spans and instruments are reported under
+pub const SCOPE: &str = "bpx";
+
+/// Name of the span covering an exchange
+pub const EXCHANGE_SPAN: &str = "bpx.exchange";
+
+/// [`MetricsRecorder`] reporting to OpenTelemetry instruments
+///
+/// Responses are counted by `bpx.responses`, with a `bpx.outcome` of
+/// `diff`, `full` or `not_modified` and the `bpx.diff.format` or
+/// `bpx.fallback_reason`. Paths aren't attributes, as their number is
+/// unbounded.
+#[derive(Debug)]
+pub struct OtelRecorder {
+    responses: Counter<u64>,
+    bytes_sent: Counter<u64>,
+    bytes_original: Counter<u64>,
+    sessions: Gauge<u64>,
+    diff_duration: Histogram<f64>,
+    errors: Counter<u64>,
+    diff_errors: Counter<u64>,
+    slow_diffs: Counter<u64>,
+}
+
+impl Default for OtelRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtelRecorder {
+    /// Create a recorder reporting through the global meter provider
+    pub fn new() -> Self {
+        Self::with_meter(&global::meter(SCOPE))
+    }
+
+    /// Create a recorder reporting through `meter`
+    pub fn with_meter(meter: &Meter) -> Self {
+        Self {
+            responses: meter
+                .u64_counter("bpx.responses")
+                .with_description("Responses answered, by outcome")
+                .build(),
+            bytes_sent: meter
+                .u64_counter("bpx.bytes.sent")
+                .with_description("Body bytes sent")
+                .with_unit("By")
+                .build(),
+            bytes_original: meter
+                .u64_counter("bpx.bytes.original")
+                .with_description("Body bytes full responses would have sent")
+                .with_unit("By")
+                .build(),
+            sessions: meter
+                .u64_gauge("bpx.sessions.active")
+                .with_description("Sessions held")
+                .build(),
+            diff_duration: meter
+                .f64_histogram("bpx.diff.duration")
+                .with_description("Time taken to compute diffs")
+                .with_unit("s")
+                .build(),
+            errors: meter
+                .u64_counter("bpx.errors")
+                .with_description("Errors answered, by `error.type`")
+                .build(),
+            diff_errors: meter
+                .u64_counter("bpx.diff.errors")
+                .with_description("Diffs the engine failed to compute or apply")
+                .build(),
+            slow_diffs: meter
+                .u64_counter("bpx.diff.slow")
+                .with_description("Diffs slower than the configured threshold")
+                .build(),
+        }
+    }
+}
+
+impl MetricsRecorder for OtelRecorder {
+    fn diff_sent(&self, _path: &ResourcePath, format: DiffFormat, original: u64, sent: u64) {
+        self.responses.add(
+            1,
+            &[
+                KeyValue::new("bpx.outcome", "diff"),
+                KeyValue::new("bpx.diff.format", format.as_str()),
+            ],
+        );
+        self.bytes_sent.add(sent, &[]);
+        self.bytes_original.add(original, &[]);
+    }
+
+    fn full_sent(&self, _path: &ResourcePath, reason: Option<FallbackReason>, sent: u64) {
+        let mut attributes = vec![KeyValue::new("bpx.outcome", "full")];
+        if let Some(reason) = reason {
+            attributes.push(KeyValue::new("bpx.fallback_reason", reason.as_str()));
+        }
+        self.responses.add(1, &attributes);
+        self.bytes_sent.add(sent, &[]);
+        self.bytes_original.add(sent, &[]);
+    }
+
+    fn not_modified(&self, _path: &ResourcePath) {
+        self.responses
+            .add(1, &[KeyValue::new("bpx.outcome", "not_modified")]);
+    }
+
+    fn sessions_active(&self, sessions: usize) {
+        self.sessions.record(sessions as u64, &[]);
+    }
+
+    fn diff_computed(&self, elapsed: Duration, _old_size: usize, _new_size: usize) {
+        self.diff_duration.record(elapsed.as_secs_f64(), &[]);
+    }
+
+    fn request_failed(&self, _path: &ResourcePath, error: &BpxError) {
+        self.errors
+            .add(1, &[KeyValue::new("error.type", error.code())]);
+    }
+
+    fn slow_diff(&self, slow: &SlowDiff) {
+        self.slow_diffs
+            .add(1, &[KeyValue::new("bpx.diff.format", slow.format.as_str())]);
+    }
+
+    fn diff_failed(&self, error: &DiffError) {
+        self.diff_errors
+            .add(1, &[KeyValue::new("error.type", error.code())]);
+    }
+}
+
+/// Span covering one exchange, ended with what it answered
+pub(crate) struct ExchangeSpan {
+    span: global::BoxedSpan,
+    /// Whether the client named a version it holds
+    base_sent: bool,
+}
+
+impl ExchangeSpan {
+    /// Open the span for `req`, as a child of the span its headers name
+    pub(crate) fn start<B>(req: &Request<B>) -> Self {
+        let parent: Context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        let tracer = global::tracer(SCOPE);
+        let span = tracer
+            .span_builder(EXCHANGE_SPAN)
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("http.request.method", req.method().to_string()),
+                KeyValue::new("url.path", req.uri().path().to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        Self {
+            span,
+            base_sent: req.headers().contains_key(BpxHeaders::BASE_VERSION),
+        }
+    }
+
+    /// End the span with `result`, whose body is `body_len` bytes if known
+    pub(crate) fn end<B>(mut self, result: &Result<Response<B>, BpxError>, body_len: Option<u64>) {
+        match result {
+            Ok(response) => {
+                let attributes = exchange_attributes(response, self.base_sent, body_len);
+                self.span.set_attributes(attributes);
+            }
+            Err(err) => {
+                self.span.set_attributes([
+                    KeyValue::new(
+                        "http.response.status_code",
+                        i64::from(error_status(err).as_u16()),
+                    ),
+                    KeyValue::new("error.type", err.code()),
+                ]);
+                self.span.set_status(Status::error(err.to_string()));
+            }
+        }
+        self.span.end();
+    }
+}
+
+/// Attributes describing what `response` sent
+///
+/// `bpx.version_match` is whether the server still held the version the
+/// client diffs against, and `bpx.diff.ratio` the diff's size over the full
+/// body's.
+fn exchange_attributes<B>(
+    response: &Response<B>,
+    base_sent: bool,
+    body_len: Option<u64>,
+) -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new(
+        "http.response.status_code",
+        i64::from(response.status().as_u16()),
+    )];
+    let version_match = match Outcome::of(response) {
+        Some(Outcome::NotModified) => true,
+        Some(Outcome::Diff { format, original }) => {
+            attributes.push(KeyValue::new("bpx.diff.format", format.as_str()));
+            if let (Some(sent), Some(original @ 1..)) = (body_len, original) {
+                attributes.push(KeyValue::new(
+                    "bpx.diff.ratio",
+                    sent as f64 / original as f64,
+                ));
+            }
+            true
+        }
+        Some(Outcome::Full(reason)) => {
+            attributes.push(KeyValue::new("bpx.diff.format", "full"));
+            if let Some(reason) = reason {
+                attributes.push(KeyValue::new("bpx.fallback_reason", reason.as_str()));
+            }
+            base_sent
+                && !matches!(
+                    reason,
+                    Some(
+                        FallbackReason::NoBase
+                            | FallbackReason::NoSessionState
+                            | FallbackReason::VersionMismatch
+                            | FallbackReason::BaseUnavailable
+                    )
+                )
+        }
+        None => return attributes,
+    };
+    attributes.push(KeyValue::new("bpx.version_match", version_match));
+    attributes
+}
+
+/// Reads trace context from request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(attributes: &[KeyValue], key: &str) -> Option<String> {
+        attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.to_string())
+    }
+
+    #[test]
+    fn test_exchange_attributes() {
+        let diff = Response::builder()
+            .header(BpxHeaders::DIFF_TYPE, "binary-delta")
+            .header(BpxHeaders::ORIGINAL_SIZE, "1000")
+            .body(())
+            .unwrap();
+        let attributes = exchange_attributes(&diff, true, Some(250));
+        assert_eq!(
+            attribute(&attributes, "bpx.diff.format").as_deref(),
+            Some("binary-delta")
+        );
+        assert_eq!(
+            attribute(&attributes, "bpx.diff.ratio").as_deref(),
+            Some("0.25")
+        );
+        assert_eq!(
+            attribute(&attributes, "bpx.version_match").as_deref(),
+            Some("true")
+        );
+
+        let mismatch = Response::builder()
+            .header(BpxHeaders::DIFF_TYPE, "full")
+            .header(BpxHeaders::FALLBACK_REASON, "version-mismatch")
+            .body(())
+            .unwrap();
+        let attributes = exchange_attributes(&mismatch, true, Some(1000));
+        assert_eq!(
+            attribute(&attributes, "bpx.diff.format").as_deref(),
+            Some("full")
+        );
+        assert_eq!(
+            attribute(&attributes, "bpx.fallback_reason").as_deref(),
+            Some("version-mismatch")
+        );
+        assert_eq!(
+            attribute(&attributes, "bpx.version_match").as_deref(),
+            Some("false")
+        );
+
+        let not_modified = Response::builder().status(304).body(()).unwrap();
+        let attributes = exchange_attributes(&not_modified, true, None);
+        assert_eq!(
+            attribute(&attributes, "http.response.status_code").as_deref(),
+            Some("304")
+        );
+        assert_eq!(
+            attribute(&attributes, "bpx.version_match").as_deref(),
+            Some("true")
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Metrics in the Prometheus text format
+//!
+//! [`PrometheusRecorder`] is a [`MetricsRecorder`] whose counts
+//! [`gather`](PrometheusRecorder::gather) renders for scraping. Response
+//! counts are labeled with the path pattern the request matched (see
+//! [`PrometheusRecorder::path_patterns`]), the diff format and the fallback
+//! reason. Register it with both
+//! [`BpxServerBuilder::metrics`](crate::BpxServerBuilder::metrics) and
+//! [`BpxLayer::prometheus`](crate::BpxLayer::prometheus) to have
+//! [`METRICS_PATH`] answered.
+
+use super::{
+    BpxMetrics, FALLBACK_REASONS, FORMATS, LATENCY_BUCKETS, MetricsRecorder, MetricsSnapshot,
+    SlowDiff,
+};
+use crate::{BpxError, DiffFormat, ResourcePath, diff::DiffError, protocol::FallbackReason};
+use bytes::Bytes;
+use dashmap::DashMap;
+use hyper::{Response, header};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Route answering [`PrometheusRecorder::gather`]
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Media type of the Prometheus text format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Label of paths matching no pattern
+pub const OTHER_PATHS: &str = "other";
+
+/// Responses answered for the paths under one pattern
+#[derive(Debug, Default)]
+struct PathCounts {
+    diffs: [AtomicU64; FORMATS.len()],
+    full: [AtomicU64; FALLBACK_REASONS.len() + 1],
+    not_modified: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_original: AtomicU64,
+    errors: DashMap<&'static str, u64>,
+    slow_diffs: AtomicU64,
+}
+
+/// Reads one count from [`PathCounts`]
+type PathCount = fn(&PathCounts) -> u64;
+
+/// [`MetricsRecorder`] rendering its counts for Prometheus
+#[derive(Debug)]
+pub struct PrometheusRecorder {
+    /// Path prefixes responses are labeled with
+    patterns: Vec<String>,
+    /// Counts per pattern, then for [`OTHER_PATHS`]
+    paths: Vec<PathCounts>,
+    /// Sessions and diff latency, which aren't labeled
+    totals: BpxMetrics,
+}
+
+impl Default for PrometheusRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrometheusRecorder {
+    /// Create a recorder labeling every path [`OTHER_PATHS`]
+    pub fn new() -> Self {
+        Self {
+            patterns: Vec::new(),
+            paths: vec![PathCounts::default()],
+            totals: BpxMetrics::new(),
+        }
+    }
+
+    /// Label responses with the longest of `patterns` their path starts
+    /// with, e.g. `/api/users/`, keeping the number of series bounded
+    pub fn path_patterns(mut self, patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.patterns = patterns.into_iter().map(Into::into).collect();
+        self.paths = (0..=self.patterns.len())
+            .map(|_| PathCounts::default())
+            .collect();
+        self
+    }
+
+    /// Unlabeled counts, as [`BpxMetrics::snapshot`] reports them
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut snapshot = self.totals.snapshot();
+        for counts in &self.paths {
+            for (total, count) in snapshot.diffs_by_format.iter_mut().zip(&counts.diffs) {
+                *total += count.load(Ordering::Relaxed);
+            }
+            let full = &counts.full;
+            for (total, count) in snapshot.full_by_reason.iter_mut().zip(full) {
+                *total += count.load(Ordering::Relaxed);
+            }
+            snapshot.full_without_reason += full[FALLBACK_REASONS.len()].load(Ordering::Relaxed);
+            snapshot.not_modified += counts.not_modified.load(Ordering::Relaxed);
+            snapshot.bytes_sent += counts.bytes_sent.load(Ordering::Relaxed);
+            snapshot.bytes_original += counts.bytes_original.load(Ordering::Relaxed);
+            snapshot.slow_diffs += counts.slow_diffs.load(Ordering::Relaxed);
+            for entry in counts.errors.iter() {
+                *snapshot.errors.entry(*entry.key()).or_default() += *entry.value();
+            }
+        }
+        snapshot
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn gather(&self) -> String {
+        let mut out = String::new();
+        let labels: Vec<String> = self
+            .patterns
+            .iter()
+            .map(|pattern| escape_label(pattern))
+            .chain([OTHER_PATHS.to_string()])
+            .collect();
+        let paths = || labels.iter().zip(&self.paths);
+
+        header(&mut out, "bpx_diffs_total", "counter", "Diffs sent");
+        for (path, counts) in paths() {
+            for (format, count) in FORMATS.iter().zip(&counts.diffs) {
+                let _ = writeln!(
+                    out,
+                    "bpx_diffs_total{{path=\"{}\",format=\"{}\"}} {}",
+                    path,
+                    format.as_str(),
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bpx_full_responses_total",
+            "counter",
+            "Full bodies sent, by why no diff was",
+        );
+        let reasons = FALLBACK_REASONS.iter().map(FallbackReason::as_str);
+        let reasons: Vec<&str> = reasons.chain(["none"]).collect();
+        for (path, counts) in paths() {
+            for (reason, count) in reasons.iter().zip(&counts.full) {
+                let _ = writeln!(
+                    out,
+                    "bpx_full_responses_total{{path=\"{}\",reason=\"{}\"}} {}",
+                    path,
+                    reason,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let per_path: [(&str, &str, PathCount); 4] = [
+            (
+                "bpx_not_modified_total",
+                "Responses telling the client it is up to date",
+                |c| c.not_modified.load(Ordering::Relaxed),
+            ),
+            ("bpx_bytes_sent_total", "Body bytes sent", |c| {
+                c.bytes_sent.load(Ordering::Relaxed)
+            }),
+            ("bpx_bytes_saved_total", "Body bytes diffs saved", |c| {
+                c.bytes_original
+                    .load(Ordering::Relaxed)
+                    .saturating_sub(c.bytes_sent.load(Ordering::Relaxed))
+            }),
+            (
+                "bpx_slow_diffs_total",
+                "Diffs slower than the configured threshold",
+                |c| c.slow_diffs.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, value) in per_path {
+            header(&mut out, name, "counter", help);
+            for (path, counts) in paths() {
+                let _ = writeln!(out, "{}{{path=\"{}\"}} {}", name, path, value(counts));
+            }
+        }
+
+        header(
+            &mut out,
+            "bpx_errors_total",
+            "counter",
+            "Errors answered, by code",
+        );
+        for (path, counts) in paths() {
+            for (code, count) in super::counts(&counts.errors) {
+                let _ = writeln!(
+                    out,
+                    "bpx_errors_total{{path=\"{}\",code=\"{}\"}} {}",
+                    path, code, count
+                );
+            }
+        }
+
+        let totals = self.totals.snapshot();
+        header(
+            &mut out,
+            "bpx_diff_errors_total",
+            "counter",
+            "Diffs the engine failed to compute or apply",
+        );
+        for (code, count) in &totals.diff_errors {
+            let _ = writeln!(out, "bpx_diff_errors_total{{code=\"{}\"}} {}", code, count);
+        }
+
+        header(&mut out, "bpx_sessions_active", "gauge", "Sessions held");
+        let _ = writeln!(out, "bpx_sessions_active {}", totals.sessions);
+
+        let latency = totals.diff_latency;
+        header(
+            &mut out,
+            "bpx_diff_duration_seconds",
+            "histogram",
+            "Time taken to compute diffs",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "bpx_diff_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound.as_secs_f64(),
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "bpx_diff_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            latency.count
+        );
+        let _ = writeln!(
+            out,
+            "bpx_diff_duration_seconds_sum {}",
+            latency.sum.as_secs_f64()
+        );
+        let _ = writeln!(out, "bpx_diff_duration_seconds_count {}", latency.count);
+        out
+    }
+
+    /// Answer [`METRICS_PATH`] with [`Self::gather`]
+    pub fn response(&self) -> Response<Bytes> {
+        let mut response = Response::new(Bytes::from(self.gather()));
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(CONTENT_TYPE),
+        );
+        response
+    }
+
+    /// Counts for the pattern `path` matches
+    fn counts(&self, path: &ResourcePath) -> &PathCounts {
+        let matched = self
+            .patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| path.0.starts_with(pattern.as_str()))
+            .max_by_key(|(_, pattern)| pattern.len())
+            .map_or(self.patterns.len(), |(i, _)| i);
+        &self.paths[matched]
+    }
+}
+
+impl MetricsRecorder for PrometheusRecorder {
+    fn diff_sent(&self, path: &ResourcePath, format: DiffFormat, original: u64, sent: u64) {
+        let counts = self.counts(path);
+        if let Some(i) = FORMATS.iter().position(|f| *f == format) {
+            counts.diffs[i].fetch_add(1, Ordering::Relaxed);
+        }
+        counts.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        counts.bytes_original.fetch_add(original, Ordering::Relaxed);
+    }
+
+    fn full_sent(&self, path: &ResourcePath, reason: Option<FallbackReason>, sent: u64) {
+        let counts = self.counts(path);
+        let i = reason
+            .and_then(|reason| FALLBACK_REASONS.iter().position(|r| *r == reason))
+            .unwrap_or(FALLBACK_REASONS.len());
+        counts.full[i].fetch_add(1, Ordering::Relaxed);
+        counts.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        counts.bytes_original.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    fn not_modified(&self, path: &ResourcePath) {
+        self.counts(path)
+            .not_modified
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn sessions_active(&self, sessions: usize) {
+        self.totals.sessions_active(sessions);
+    }
+
+    fn diff_computed(&self, elapsed: Duration, old_size: usize, new_size: usize) {
+        self.totals.diff_computed(elapsed, old_size, new_size);
+    }
+
+    fn request_failed(&self, path: &ResourcePath, error: &BpxError) {
+        *self.counts(path).errors.entry(error.code()).or_default() += 1;
+    }
+
+    fn diff_failed(&self, error: &DiffError) {
+        self.totals.diff_failed(error);
+    }
+
+    fn slow_diff(&self, slow: &SlowDiff) {
+        self.counts(&slow.path)
+            .slow_diffs
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// `# HELP` and `# TYPE` lines introducing a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value per the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        let recorder = PrometheusRecorder::new().path_patterns(["/api/", "/api/users/"]);
+        let path = |p: &str| ResourcePath::new(p.to_string());
+        recorder.diff_sent(&path("/api/users/1"), DiffFormat::BinaryDelta, 1000, 100);
+        recorder.full_sent(
+            &path("/api/feed"),
+            Some(FallbackReason::VersionMismatch),
+            500,
+        );
+        recorder.full_sent(&path("/static/app.js"), None, 50);
+        recorder.not_modified(&path("/api/feed"));
+        recorder.sessions_active(2);
+        recorder.diff_computed(Duration::from_millis(3), 1000, 1000);
+        recorder.request_failed(
+            &path("/api/missing"),
+            &BpxError::ResourceNotFound {
+                path: path("/api/missing"),
+            },
+        );
+        recorder.diff_failed(&DiffError::PatchFailed("bad diff".to_string()));
+
+        let text = recorder.gather();
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# TYPE bpx_diffs_total counter",
+            r#"bpx_diffs_total{path="/api/users/",format="binary-delta"} 1"#,
+            r#"bpx_diffs_total{path="/api/",format="binary-delta"} 0"#,
+            r#"bpx_full_responses_total{path="/api/",reason="version-mismatch"} 1"#,
+            r#"bpx_full_responses_total{path="other",reason="none"} 1"#,
+            r#"bpx_not_modified_total{path="/api/"} 1"#,
+            r#"bpx_bytes_saved_total{path="/api/users/"} 900"#,
+            r#"bpx_bytes_sent_total{path="other"} 50"#,
+            "bpx_sessions_active 2",
+            r#"bpx_diff_duration_seconds_bucket{le="0.001"} 0"#,
+            r#"bpx_diff_duration_seconds_bucket{le="0.005"} 1"#,
+            r#"bpx_diff_duration_seconds_bucket{le="+Inf"} 1"#,
+            "bpx_diff_duration_seconds_count 1",
+            r#"bpx_errors_total{path="/api/",code="resource-not-found"} 1"#,
+            r#"bpx_diff_errors_total{code="patch-failed"} 1"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {}", expected);
+        }
+
+        let snapshot = recorder.snapshot();
+        assert_eq!(snapshot.diffs(), 1);
+        assert_eq!(snapshot.full_responses(), 2);
+        assert_eq!(snapshot.bytes_saved(), 900);
+        assert_eq!(snapshot.errors_for("resource-not-found"), 1);
+        assert_eq!(
+            recorder.response().headers()[header::CONTENT_TYPE],
+            CONTENT_TYPE
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Hook receiving every diff-or-full decision as it is made
+//!
+//! [`handle_bpx_request`](crate::server::handle_bpx_request) and
+//! [`handle_bpx_request_streaming`](crate::server::handle_bpx_request_streaming)
+//! attach a [`DiffDecision`] to the extensions of each resource response
+//! they build, and [`handle_batch_request`](crate::server::handle_batch_request)
+//! one per entry it answers. A [`BpxServer`](crate::BpxServer) built with
+//! [`BpxServerBuilder::observer`](crate::BpxServerBuilder::observer) hands
+//! it to an [`Observer`], so decisions can be shipped to an analytics
+//! pipeline without parsing logs or headers.
+
+use crate::{BpxResponse, DiffFormat, FallbackReason, ResourcePath, ResponseBody, Version};
+
+/// What was sent for one resource, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffDecision {
+    /// Resource requested
+    pub path: ResourcePath,
+    /// Version the diff applies to, or else the client's most preferred
+    /// base, if it named any
+    pub base: Option<Version>,
+    /// Version sent
+    pub current: Version,
+    /// Format of the diff sent, or `None` for a full body or `304`
+    pub chosen_format: Option<DiffFormat>,
+    /// Bytes of the diff sent
+    pub diff_size: Option<usize>,
+    /// Bytes of the current content, unless it was streamed
+    pub full_size: Option<usize>,
+    /// Why no diff was sent; [`FallbackReason::Unchanged`] for a `304`
+    pub reason: Option<FallbackReason>,
+}
+
+impl DiffDecision {
+    /// Decision `response` records for `path`, answering a client holding
+    /// `base_versions`, for current content of `full_size` bytes
+    pub(crate) fn new(
+        path: &ResourcePath,
+        base_versions: &[Version],
+        response: &BpxResponse,
+        full_size: Option<usize>,
+    ) -> Self {
+        let (chosen_format, diff_size) = match &response.body {
+            ResponseBody::Diff { format, data } => (Some(*format), Some(data.len())),
+            ResponseBody::Full(_) => (None, None),
+        };
+        Self {
+            path: path.clone(),
+            base: response
+                .delta_base
+                .clone()
+                .or_else(|| base_versions.first().cloned()),
+            current: response.version.clone(),
+            chosen_format,
+            diff_size,
+            full_size,
+            reason: response.fallback_reason,
+        }
+    }
+
+    /// Whether a diff was sent
+    pub fn is_diff(&self) -> bool {
+        self.chosen_format.is_some()
+    }
+}
+
+/// Receives the decision made for each resource response
+///
+/// Called on the request path, so implementations should hand the decision
+/// off rather than block on I/O.
+pub trait Observer: Send + Sync {
+    /// `decision` was made for a response about to be sent
+    fn observe(&self, decision: &DiffDecision);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&DiffDecision) + Send + Sync,
+{
+    fn observe(&self, decision: &DiffDecision) {
+        self(decision)
+    }
+}
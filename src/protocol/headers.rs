@@ -1,42 +1,94 @@
 //! BPX HTTP headers handling
 
-/// BPX header constants
-pub struct BpxHeaders;
+pub use bpx_client_core::headers::BpxHeaders;
 
-impl BpxHeaders {
-    /// Client session identifier
-    pub const SESSION: &'static str = "X-BPX-Session";
-    /// Version client currently has
-    pub const BASE_VERSION: &'static str = "X-Base-Version";
-    /// Comma-separated diff formats client supports
-    pub const ACCEPT_DIFF: &'static str = "Accept-Diff";
-    /// Current version identifier
-    pub const RESOURCE_VERSION: &'static str = "X-Resource-Version";
-    /// Format of diff in body
-    pub const DIFF_TYPE: &'static str = "X-Diff-Type";
-    /// Size of full resource in bytes
-    pub const ORIGINAL_SIZE: &'static str = "X-Original-Size";
-    /// Size of diff in bytes
-    pub const DIFF_SIZE: &'static str = "X-Diff-Size";
-    /// How long client should cache this version (seconds)
-    pub const CACHE_TTL: &'static str = "X-BPX-Cache-TTL";
+/// RFC 3229 delta-encoding header constants
+pub struct DeltaHeaders;
 
-    /// Get all BPX header names
-    pub fn all() -> &'static [&'static str] {
-        &[
-            Self::SESSION,
-            Self::BASE_VERSION,
-            Self::ACCEPT_DIFF,
-            Self::RESOURCE_VERSION,
-            Self::DIFF_TYPE,
-            Self::ORIGINAL_SIZE,
-            Self::DIFF_SIZE,
-            Self::CACHE_TTL,
-        ]
+impl DeltaHeaders {
+    /// Instance manipulations the client accepts
+    pub const A_IM: &'static str = "A-IM";
+    /// Instance manipulations applied to the response
+    pub const IM: &'static str = "IM";
+    /// Entity tag of the base the delta was computed against
+    pub const DELTA_BASE: &'static str = "Delta-Base";
+    /// `226 IM Used` status
+    pub const IM_USED: u16 = 226;
+}
+
+/// `value` made safe to carry in a header, percent-encoding `%`, quotes,
+/// list and parameter separators, backslashes and anything outside visible
+/// ASCII
+///
+/// Versions and session IDs are opaque strings a store or client may
+/// choose, yet they go out in headers (and quoted in `ETag`) and come back
+/// in comma-separated lists; [`decode_value`] recovers them.
+pub fn encode_value(value: &str) -> std::borrow::Cow<'_, str> {
+    let unsafe_byte =
+        |b: u8| !b.is_ascii_graphic() || matches!(b, b'%' | b'"' | b',' | b';' | b'\\');
+    if !value.bytes().any(unsafe_byte) {
+        return value.into();
+    }
+    let mut out = String::with_capacity(value.len() + 8);
+    for b in value.bytes() {
+        if unsafe_byte(b) {
+            out.push_str(&format!("%{:02X}", b));
+        } else {
+            out.push(b as char);
+        }
     }
+    out.into()
+}
+
+/// Reverse [`encode_value`]; `%` not followed by two hex digits is kept
+pub fn decode_value(value: &str) -> std::borrow::Cow<'_, str> {
+    if !value.contains('%') {
+        return value.into();
+    }
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
 
-    /// Check if a header name is a BPX header
-    pub fn is_bpx_header(name: &str) -> bool {
-        Self::all().contains(&name)
+    #[test]
+    fn test_hostile_values_round_trip() {
+        assert_eq!(encode_value("v:0123abcd"), "v:0123abcd");
+        for hostile in [
+            "v1\r\nSet-Cookie: x=1",
+            "\"quoted\", v2",
+            "100% ; path=/",
+            "back\\slash\0",
+            "versión ✓",
+            "%41",
+        ] {
+            let encoded = encode_value(hostile);
+            assert!(HeaderValue::from_str(&encoded).is_ok(), "{:?}", encoded);
+            assert!(!encoded.contains([',', '"', ';', ' ']), "{:?}", encoded);
+            assert_eq!(decode_value(&encoded), hostile);
+        }
+        assert_eq!(decode_value("100%"), "100%");
+        assert_eq!(decode_value("%zz%4"), "%zz%4");
     }
 }
@@ -1,12 +1,131 @@
 //! BPX protocol types and wire format definitions
 
-use crate::{DiffFormat, ResourcePath, SessionId, Version};
+use crate::{DiffFormat, ResourcePath, SessionId, TenantId, Version, state::SessionStatus};
 use bytes::Bytes;
 use std::time::Duration;
 
 pub mod headers;
+pub mod params;
 pub mod wire;
 
+/// Parse an `Accept-Diff` (or `A-IM`) header value into formats ordered by preference
+///
+/// Entries may carry a quality value (`json-patch;q=0.9, binary-delta;q=0.5`).
+/// Formats are sorted by descending q, keeping header order for ties. Unknown
+/// formats, entries with `q=0`, and entries with a malformed q are dropped.
+pub fn parse_accept_diff(value: &str) -> Vec<DiffFormat> {
+    let mut weighted: Vec<(DiffFormat, f32)> = value
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let format = DiffFormat::from_str(parts.next()?.trim())?;
+            let mut quality = 1.0;
+            for param in parts {
+                if let Some((key, val)) = param.split_once('=')
+                    && key.trim().eq_ignore_ascii_case("q")
+                {
+                    quality = val.trim().parse::<f32>().ok()?;
+                }
+            }
+            (quality > 0.0 && quality <= 1.0).then_some((format, quality))
+        })
+        .collect();
+
+    // Stable sort keeps the client's order among equal weights
+    weighted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut formats = Vec::with_capacity(weighted.len());
+    for (format, _) in weighted {
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    formats
+}
+
+/// Pick the client's most preferred format that the other side supports
+pub fn negotiate_format(accepted: &[DiffFormat], supported: &[DiffFormat]) -> Option<DiffFormat> {
+    accepted.iter().copied().find(|f| supported.contains(f))
+}
+
+/// Why the server sent a full body instead of a diff
+///
+/// Variants are declared in pipeline order, so when several candidate bases
+/// fail at different stages the furthest one (`max`) is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FallbackReason {
+    /// Client sent no base version
+    NoBase,
+    /// None of the client's accepted formats is supported
+    FormatNotAccepted,
+    /// Client's base is already the current version
+    Unchanged,
+    /// Resource is under a `no_history` prefix, so no version of it is kept
+    NoHistory,
+    /// Server holds no version for this session and path (new or expired session)
+    NoSessionState,
+    /// Version recorded for the session is not among the client's bases
+    VersionMismatch,
+    /// Base content is no longer retained by the resource store
+    BaseUnavailable,
+    /// Base or current content exceeds `max_diff_size`
+    TooLarge,
+    /// As many diffs as `load_limits` allows are being computed
+    Overloaded,
+    /// Session used up its `session_quota` for the current window
+    QuotaExceeded,
+    /// Diff engine failed
+    EngineError,
+    /// Diff would not save enough bytes
+    NotWorthwhile,
+}
+
+impl FallbackReason {
+    /// Parse the code emitted in `X-BPX-Fallback-Reason`
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "no-base" => Some(Self::NoBase),
+            "format-not-accepted" => Some(Self::FormatNotAccepted),
+            "unchanged" => Some(Self::Unchanged),
+            "no-history" => Some(Self::NoHistory),
+            "no-session-state" => Some(Self::NoSessionState),
+            "version-mismatch" => Some(Self::VersionMismatch),
+            "base-unavailable" => Some(Self::BaseUnavailable),
+            "too-large" => Some(Self::TooLarge),
+            "overloaded" => Some(Self::Overloaded),
+            "quota-exceeded" => Some(Self::QuotaExceeded),
+            "engine-error" => Some(Self::EngineError),
+            "not-worthwhile" => Some(Self::NotWorthwhile),
+            _ => None,
+        }
+    }
+
+    /// Machine-readable code emitted in `X-BPX-Fallback-Reason`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NoBase => "no-base",
+            Self::FormatNotAccepted => "format-not-accepted",
+            Self::Unchanged => "unchanged",
+            Self::NoHistory => "no-history",
+            Self::NoSessionState => "no-session-state",
+            Self::VersionMismatch => "version-mismatch",
+            Self::BaseUnavailable => "base-unavailable",
+            Self::TooLarge => "too-large",
+            Self::Overloaded => "overloaded",
+            Self::QuotaExceeded => "quota-exceeded",
+            Self::EngineError => "engine-error",
+            Self::NotWorthwhile => "not-worthwhile",
+        }
+    }
+}
+
+impl std::fmt::Display for FallbackReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// BPX request containing client state and preferences
 #[derive(Debug, Clone)]
 pub struct BpxRequest {
@@ -16,8 +135,14 @@ pub struct BpxRequest {
     pub session_id: Option<SessionId>,
     /// Version client currently has
     pub base_version: Option<Version>,
+    /// Every version the client still holds, most preferred first
+    /// (`base_version` is the first of these)
+    pub base_versions: Vec<Version>,
     /// Diff formats client supports
     pub accepted_formats: Vec<DiffFormat>,
+    /// Tenant the request belongs to (see [`crate::tenant`]); `path` is
+    /// already scoped to it
+    pub tenant: Option<TenantId>,
 }
 
 impl BpxRequest {
@@ -27,7 +152,9 @@ impl BpxRequest {
             path,
             session_id: None,
             base_version: None,
+            base_versions: Vec::new(),
             accepted_formats: vec![DiffFormat::BinaryDelta],
+            tenant: None,
         }
     }
 
@@ -39,10 +166,18 @@ impl BpxRequest {
 
     /// Set base version
     pub fn with_base_version(mut self, version: Version) -> Self {
+        self.base_versions = vec![version.clone()];
         self.base_version = Some(version);
         self
     }
 
+    /// Set several candidate base versions the client still holds
+    pub fn with_base_versions(mut self, versions: Vec<Version>) -> Self {
+        self.base_version = versions.first().cloned();
+        self.base_versions = versions;
+        self
+    }
+
     /// Set accepted diff formats
     pub fn with_formats(mut self, formats: Vec<DiffFormat>) -> Self {
         self.accepted_formats = formats;
@@ -71,6 +206,16 @@ pub struct BpxResponse {
     pub cache_ttl: Option<Duration>,
     /// Session ID for client state tracking
     pub session_id: Option<SessionId>,
+    /// Whether the session was resumed or newly created
+    pub session_status: Option<SessionStatus>,
+    /// Media type of the full resource
+    pub content_type: Option<String>,
+    /// Base version a diff body applies to
+    pub delta_base: Option<Version>,
+    /// Hash of the patched content, on diffs whose version isn't one
+    pub content_digest: Option<Version>,
+    /// Why a full body was sent instead of a diff
+    pub fallback_reason: Option<FallbackReason>,
 }
 
 impl BpxResponse {
@@ -81,6 +226,11 @@ impl BpxResponse {
             body: ResponseBody::Full(content),
             cache_ttl: None,
             session_id: None,
+            session_status: None,
+            content_type: None,
+            delta_base: None,
+            content_digest: None,
+            fallback_reason: None,
         }
     }
 
@@ -94,6 +244,11 @@ impl BpxResponse {
             },
             cache_ttl: None,
             session_id: None,
+            session_status: None,
+            content_type: None,
+            delta_base: None,
+            content_digest: None,
+            fallback_reason: None,
         }
     }
 
@@ -103,12 +258,44 @@ impl BpxResponse {
         self
     }
 
+    /// Set the session and whether it was resumed or newly created
+    pub fn with_session_status(mut self, status: SessionStatus) -> Self {
+        self.session_id = Some(status.id().clone());
+        self.session_status = Some(status);
+        self
+    }
+
     /// Set cache TTL
     pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
         self.cache_ttl = Some(ttl);
         self
     }
 
+    /// Set the base version a diff body applies to
+    pub fn with_delta_base(mut self, base: Version) -> Self {
+        self.delta_base = Some(base);
+        self
+    }
+
+    /// Set the hash clients check the patched content against, for
+    /// versions that aren't content hashes
+    pub fn with_content_digest(mut self, digest: Version) -> Self {
+        self.content_digest = Some(digest);
+        self
+    }
+
+    /// Record why a full body was sent instead of a diff
+    pub fn with_fallback_reason(mut self, reason: FallbackReason) -> Self {
+        self.fallback_reason = Some(reason);
+        self
+    }
+
+    /// Set media type of the full resource
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
     /// Get the size of the response body
     pub fn body_size(&self) -> usize {
         match &self.body {
@@ -194,6 +381,10 @@ mod tests {
         assert_eq!(full_response.body_size(), content.len());
         assert_eq!(full_response.session_id, Some(session_id.clone()));
         assert_eq!(full_response.cache_ttl, Some(Duration::from_secs(300)));
+        assert!(full_response.content_type.is_none());
+
+        let typed = full_response.with_content_type("application/json");
+        assert_eq!(typed.content_type.as_deref(), Some("application/json"));
 
         // Test diff response
         let diff_data = Bytes::from("diff data");
@@ -208,6 +399,88 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_accept_diff_quality_values() {
+        let formats = parse_accept_diff("json-patch;q=0.5, binary-delta;q=0.9, bsdiff");
+        assert_eq!(
+            formats,
+            vec![
+                DiffFormat::BsdDiff,
+                DiffFormat::BinaryDelta,
+                DiffFormat::JsonPatch
+            ]
+        );
+
+        // Ties keep header order
+        let formats = parse_accept_diff("json-patch, binary-delta");
+        assert_eq!(
+            formats,
+            vec![DiffFormat::JsonPatch, DiffFormat::BinaryDelta]
+        );
+    }
+
+    #[test]
+    fn test_parse_accept_diff_rejections() {
+        // q=0 means "not acceptable"
+        assert_eq!(
+            parse_accept_diff("binary-delta;q=0, json-patch"),
+            vec![DiffFormat::JsonPatch]
+        );
+        // Malformed and out-of-range q values are dropped
+        assert!(parse_accept_diff("binary-delta;q=abc, json-patch;q=1.5").is_empty());
+        // Unknown formats and duplicates
+        assert_eq!(
+            parse_accept_diff("vcdiff, binary-delta, BINARY-DELTA;q=0.1"),
+            vec![DiffFormat::BinaryDelta]
+        );
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        let supported = [DiffFormat::BinaryDelta];
+        assert_eq!(
+            negotiate_format(
+                &[DiffFormat::JsonPatch, DiffFormat::BinaryDelta],
+                &supported
+            ),
+            Some(DiffFormat::BinaryDelta)
+        );
+        assert_eq!(negotiate_format(&[DiffFormat::JsonPatch], &supported), None);
+        assert_eq!(negotiate_format(&[], &supported), None);
+    }
+
+    #[test]
+    fn test_bpx_request_multiple_bases() {
+        let path = ResourcePath::new("/api/test".to_string());
+        let bases = vec![
+            Version::new("v:a".to_string()),
+            Version::new("v:b".to_string()),
+        ];
+
+        let request = BpxRequest::new(path.clone()).with_base_versions(bases.clone());
+        assert_eq!(request.base_version, Some(bases[0].clone()));
+        assert_eq!(request.base_versions, bases);
+
+        let request = BpxRequest::new(path).with_base_version(bases[1].clone());
+        assert_eq!(request.base_versions, vec![bases[1].clone()]);
+    }
+
+    #[test]
+    fn test_fallback_reason_codes() {
+        assert_eq!(FallbackReason::NoBase.as_str(), "no-base");
+        assert_eq!(FallbackReason::NotWorthwhile.to_string(), "not-worthwhile");
+
+        // Later pipeline stages win
+        assert_eq!(
+            FallbackReason::BaseUnavailable.max(FallbackReason::EngineError),
+            FallbackReason::EngineError
+        );
+
+        let response = BpxResponse::full(Version::new("v1".to_string()), Bytes::new())
+            .with_fallback_reason(FallbackReason::TooLarge);
+        assert_eq!(response.fallback_reason, Some(FallbackReason::TooLarge));
+    }
+
     #[test]
     fn test_request_without_state() {
         let path = ResourcePath::new("/api/test".to_string());
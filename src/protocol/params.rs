@@ -0,0 +1,234 @@
+//! BPX request parameters carried in a request body
+//!
+//! Clients that can't set custom headers (some proxies and platforms strip
+//! them) `POST` to the resource with a JSON object instead, typed
+//! [`PARAMS_MEDIA_TYPE`]:
+//! ```text
+//! {"session":"sess_…","base_version":"v:…","accept_diff":"binary-delta"}
+//! ```
+//!
+//! Every member is optional and holds what the header of the same meaning
+//! would, so `base_version` may list several versions separated by commas.
+//! Other members are ignored.
+
+use super::headers::BpxHeaders;
+use crate::diff::DiffError;
+use bytes::Bytes;
+use hyper::{HeaderMap, header::HeaderValue};
+
+/// Media type of a body carrying [`BodyParams`]
+pub const PARAMS_MEDIA_TYPE: &str = "application/vnd.bpx.params+json";
+
+/// Session, base versions and accepted formats sent in a body
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BodyParams {
+    /// As `X-BPX-Session`
+    pub session: Option<String>,
+    /// As `X-Base-Version`
+    pub base_version: Option<String>,
+    /// As `Accept-Diff`
+    pub accept_diff: Option<String>,
+}
+
+impl BodyParams {
+    /// Encode as a JSON object, leaving out members that aren't set
+    pub fn encode(&self) -> Bytes {
+        let members: Vec<String> = [
+            ("session", &self.session),
+            ("base_version", &self.base_version),
+            ("accept_diff", &self.accept_diff),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = value.as_deref()?;
+            Some(format!("\"{}\":\"{}\"", name, escape(value)))
+        })
+        .collect();
+        Bytes::from(format!("{{{}}}", members.join(",")))
+    }
+
+    /// Decode a JSON object whose members are all strings
+    pub fn decode(data: &[u8]) -> Result<Self, DiffError> {
+        let text = std::str::from_utf8(data).map_err(|_| invalid("body is not UTF-8"))?;
+        let mut parser = Parser {
+            rest: text.trim_start(),
+        };
+        let mut params = Self::default();
+        parser.expect('{')?;
+        if !parser.eat('}') {
+            loop {
+                let name = parser.string()?;
+                parser.expect(':')?;
+                let value = parser.string()?;
+                match name.as_str() {
+                    "session" => params.session = Some(value),
+                    "base_version" => params.base_version = Some(value),
+                    "accept_diff" => params.accept_diff = Some(value),
+                    _ => {}
+                }
+                if parser.eat('}') {
+                    break;
+                }
+                parser.expect(',')?;
+            }
+        }
+        if !parser.rest.trim().is_empty() {
+            return Err(invalid("trailing data after the object"));
+        }
+        Ok(params)
+    }
+
+    /// Set the headers these parameters stand for, replacing any present
+    pub fn apply(&self, headers: &mut HeaderMap) -> Result<(), DiffError> {
+        for (name, value) in [
+            (BpxHeaders::SESSION, &self.session),
+            (BpxHeaders::BASE_VERSION, &self.base_version),
+            (BpxHeaders::ACCEPT_DIFF, &self.accept_diff),
+        ] {
+            if let Some(value) = value {
+                let value = HeaderValue::from_str(value)
+                    .map_err(|_| invalid(&format!("{} isn't a valid header value", name)))?;
+                headers.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> DiffError {
+    DiffError::InvalidFormat(format!("Invalid BPX parameters: {}", reason))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reads the little JSON a flat object of strings needs
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    /// Consume `c` after any whitespace, if it comes next
+    fn eat(&mut self, c: char) -> bool {
+        self.rest = self.rest.trim_start();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), DiffError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(invalid(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, DiffError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(out);
+                }
+                '\\' => {
+                    let escaped = match chars.next().map(|(_, c)| c) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .filter(|_| hex.len() == 4)
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| invalid("bad \\u escape"))?
+                        }
+                        _ => return Err(invalid("bad escape")),
+                    };
+                    out.push(escaped);
+                }
+                c if c.is_control() => return Err(invalid("control character in string")),
+                c => out.push(c),
+            }
+        }
+        Err(invalid("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_round_trip_and_apply() {
+        let params = BodyParams {
+            session: Some("sess_1".to_string()),
+            base_version: Some("v:a, v:b".to_string()),
+            accept_diff: None,
+        };
+        let encoded = params.encode();
+        assert_eq!(encoded, r#"{"session":"sess_1","base_version":"v:a, v:b"}"#);
+        assert_eq!(BodyParams::decode(&encoded).unwrap(), params);
+
+        let decoded = BodyParams::decode(
+            br#" { "accept_diff" : "binary-delta", "extra": "ignored", "session": "s1\"" } "#,
+        )
+        .unwrap();
+        assert_eq!(decoded.session.as_deref(), Some("s1\""));
+        assert_eq!(decoded.accept_diff.as_deref(), Some("binary-delta"));
+        assert_eq!(BodyParams::decode(b"{}").unwrap(), BodyParams::default());
+
+        let mut headers = HeaderMap::new();
+        params.apply(&mut headers).unwrap();
+        assert_eq!(headers[BpxHeaders::SESSION], "sess_1");
+        assert_eq!(headers[BpxHeaders::BASE_VERSION], "v:a, v:b");
+        assert!(!headers.contains_key(BpxHeaders::ACCEPT_DIFF));
+    }
+
+    #[test]
+    fn test_invalid_params_are_rejected() {
+        for body in [
+            &b""[..],
+            b"[]",
+            br#"{"session":1}"#,
+            br#"{"session":"a""#,
+            br#"{"session":"a"} x"#,
+            br#"{"session":"\x"}"#,
+            b"\xff",
+        ] {
+            assert!(
+                matches!(BodyParams::decode(body), Err(DiffError::InvalidFormat(_))),
+                "{:?}",
+                body
+            );
+        }
+        let params = BodyParams {
+            session: Some("a\nb".to_string()),
+            ..BodyParams::default()
+        };
+        assert!(params.apply(&mut HeaderMap::new()).is_err());
+    }
+}
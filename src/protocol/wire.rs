@@ -1,55 +1,428 @@
 //! BPX wire format definitions
+//!
+//! Streaming framing (for HTTP/2 DATA frames, WebSocket messages, etc.):
+//! ```text
+//! +-----------+---------------------------+
+//! | Len(4B)   | Ops (Len bytes, no END)   |
+//! +-----------+---------------------------+
+//! ```
+//!
+//! A diff is split on operation boundaries into length-prefixed frames so each
+//! frame can be decoded and applied as soon as it arrives. A zero-length frame
+//! terminates the stream and replaces the trailing `END` op of the buffered form.
+//!
+//! Batch envelope (`application/vnd.bpx.batch`, big-endian lengths):
+//! ```text
+//! Request:  Count(2B) { PathLen(2B) Path BaseLen(2B) Base }*
+//! Response: Count(2B) { PathLen(2B) Path Kind(1B) VerLen(2B) Ver
+//!                       DigestLen(2B) Digest OrigSize(4B) BodyLen(4B) Body }*
+//! ```
+//!
+//! `BaseLen` 0 means the client holds no base. `Digest` is the hash of the
+//! patched content when the version isn't one (see
+//! [`BpxHeaders::CONTENT_DIGEST`](crate::protocol::headers::BpxHeaders::CONTENT_DIGEST)),
+//! `DigestLen` 0 otherwise. `Kind` is 0x00 for a full body,
+//! 0x01 binary-delta, 0x02 json-patch, 0x03 bsdiff, 0x04 element-delta, and
+//! 0xFF for a per-entry error whose body is a UTF-8 message.
 
-/// Binary diff operations
-#[repr(u8)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum DiffOp {
-    /// Copy from old version
-    Copy = 0x01,
-    /// Insert new data
-    Insert = 0x02,
-    /// Delete/skip bytes from old version
-    Delete = 0x03,
-    /// End of diff stream
-    End = 0x04,
+pub use bpx_client_core::patch::DiffOp;
+
+use crate::{DiffFormat, ResourcePath, ResponseBody, Version, diff::DiffError};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Size of the length prefix preceding every frame
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Default maximum frame payload (fits a default HTTP/2 DATA frame)
+pub const DEFAULT_MAX_FRAME_PAYLOAD: usize = 16 * 1024 - FRAME_HEADER_LEN;
+
+/// Length-prefixed framing for streaming diffs
+pub struct FrameCodec;
+
+impl FrameCodec {
+    /// Encode a single frame around an operations payload
+    pub fn encode_frame(payload: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_LEN + payload.len());
+        buf.put_u32(payload.len() as u32);
+        buf.put_slice(payload);
+        buf.freeze()
+    }
+
+    /// Encode the zero-length frame that terminates a stream
+    pub fn end_frame() -> Bytes {
+        Self::encode_frame(&[])
+    }
+
+    /// Split a buffered diff into frames of at most `max_payload` bytes each
+    ///
+    /// Operations never straddle frames; an operation larger than `max_payload`
+    /// is emitted in a frame of its own. The returned list always ends with the
+    /// terminating empty frame.
+    pub fn split(diff: &[u8], max_payload: usize) -> Result<Vec<Bytes>, DiffError> {
+        let mut frames = Vec::new();
+        let mut frame_start = 0;
+        let mut pos = 0;
+
+        while pos < diff.len() {
+            if diff[pos] == DiffOp::End as u8 {
+                break;
+            }
+            let op_len = Self::op_len(&diff[pos..])?;
+            if pos > frame_start && pos + op_len - frame_start > max_payload {
+                frames.push(Self::encode_frame(&diff[frame_start..pos]));
+                frame_start = pos;
+            }
+            pos += op_len;
+        }
+
+        if pos > frame_start {
+            frames.push(Self::encode_frame(&diff[frame_start..pos]));
+        }
+        frames.push(Self::end_frame());
+        Ok(frames)
+    }
+
+    /// Encoded length of the operation at the start of `data`
+    fn op_len(data: &[u8]) -> Result<usize, DiffError> {
+        let op = DiffOp::from_u8(data[0]).ok_or_else(|| {
+            DiffError::InvalidFormat(format!("Unknown operation: 0x{:02x}", data[0]))
+        })?;
+        if !op.requires_length() {
+            return Ok(1);
+        }
+        if data.len() < 4 {
+            return Err(DiffError::InvalidFormat(
+                "Insufficient data for operation length".to_string(),
+            ));
+        }
+        let mut header = &data[1..4];
+        let length = header.get_uint(3) as usize;
+        let total = if op.requires_data() { 4 + length } else { 4 };
+        if data.len() < total {
+            return Err(DiffError::InvalidFormat(
+                "Insufficient data for Insert operation payload".to_string(),
+            ));
+        }
+        Ok(total)
+    }
+}
+
+/// Incremental decoder reassembling frames from arbitrary byte chunks
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+    max_payload: Option<usize>,
+    finished: bool,
+}
+
+impl FrameDecoder {
+    /// Create a new frame decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a frame decoder rejecting frames larger than `max_payload`
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        Self {
+            max_payload: Some(max_payload),
+            ..Self::default()
+        }
+    }
+
+    /// Feed received bytes into the decoder
+    pub fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Take the next complete frame payload, if one is buffered
+    ///
+    /// Returns `Ok(None)` when more bytes are needed or after the terminating
+    /// frame has been seen.
+    pub fn next_frame(&mut self) -> Result<Option<Bytes>, DiffError> {
+        if self.finished || self.buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if let Some(max) = self.max_payload
+            && len > max
+        {
+            return Err(DiffError::InvalidFormat(format!(
+                "Frame too large: {} bytes (max: {})",
+                len, max
+            )));
+        }
+        if self.buf.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        self.buf.advance(FRAME_HEADER_LEN);
+        let payload = self.buf.split_to(len).freeze();
+        if payload.is_empty() {
+            self.finished = true;
+            return Ok(None);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Whether the terminating frame has been received
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
 }
 
-impl DiffOp {
-    /// Convert from byte value
-    pub fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0x01 => Some(Self::Copy),
-            0x02 => Some(Self::Insert),
-            0x03 => Some(Self::Delete),
-            0x04 => Some(Self::End),
-            _ => None,
+/// Media type of batch request and response bodies
+pub const BATCH_MEDIA_TYPE: &str = "application/vnd.bpx.batch";
+
+/// Maximum number of entries in one batch
+pub const MAX_BATCH_ENTRIES: usize = u16::MAX as usize;
+
+const BATCH_KIND_FULL: u8 = 0x00;
+const BATCH_KIND_ERROR: u8 = 0xFF;
+
+/// One resource in a batch request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchRequestEntry {
+    /// Resource path
+    pub path: ResourcePath,
+    /// Version the client currently holds for this path
+    pub base_version: Option<Version>,
+}
+
+/// Batch request carrying base versions for many resources
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchRequest {
+    /// Requested resources
+    pub entries: Vec<BatchRequestEntry>,
+}
+
+impl BatchRequest {
+    /// Encode to the batch wire format
+    pub fn encode(&self) -> Result<Bytes, DiffError> {
+        let mut buf = BytesMut::new();
+        put_count(&mut buf, self.entries.len())?;
+        for entry in &self.entries {
+            put_str16(&mut buf, &entry.path.to_string())?;
+            let base = entry
+                .base_version
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            put_str16(&mut buf, &base)?;
         }
+        Ok(buf.freeze())
     }
 
-    /// Convert to byte value
-    pub fn as_u8(self) -> u8 {
-        self as u8
+    /// Decode from the batch wire format
+    pub fn decode(data: &[u8]) -> Result<Self, DiffError> {
+        let mut cursor = data;
+        let count = get_u16(&mut cursor, "entry count")? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let path = ResourcePath::new(get_str16(&mut cursor, "path")?);
+            let base = get_str16(&mut cursor, "base version")?;
+            entries.push(BatchRequestEntry {
+                path,
+                base_version: (!base.is_empty()).then(|| Version::new(base)),
+            });
+        }
+        expect_consumed(cursor)?;
+        Ok(Self { entries })
     }
+}
+
+/// One resource in a batch response
+#[derive(Debug, Clone)]
+pub struct BatchResponseEntry {
+    /// Resource path
+    pub path: ResourcePath,
+    /// Current version (`None` when the entry failed)
+    pub version: Option<Version>,
+    /// Hash of the content a diff patches to, when `version` isn't one
+    pub content_digest: Option<Version>,
+    /// Size of the full current content in bytes
+    pub original_size: usize,
+    /// Full or diff body, or an error message for this entry
+    pub body: Result<ResponseBody, String>,
+}
+
+/// Batch response carrying per-resource bodies
+#[derive(Debug, Clone, Default)]
+pub struct BatchResponse {
+    /// Per-resource results, in request order
+    pub entries: Vec<BatchResponseEntry>,
+}
 
-    /// Get all valid operation codes
-    pub fn all() -> &'static [DiffOp] {
-        &[Self::Copy, Self::Insert, Self::Delete, Self::End]
+impl BatchResponse {
+    /// Encode to the batch wire format
+    pub fn encode(&self) -> Result<Bytes, DiffError> {
+        let mut buf = BytesMut::new();
+        put_count(&mut buf, self.entries.len())?;
+        for entry in &self.entries {
+            put_str16(&mut buf, &entry.path.to_string())?;
+            let (kind, body): (u8, &[u8]) = match &entry.body {
+                Ok(ResponseBody::Full(data)) => (BATCH_KIND_FULL, data),
+                Ok(ResponseBody::Diff { format, data }) => (format_code(*format), data),
+                Err(message) => (BATCH_KIND_ERROR, message.as_bytes()),
+            };
+            buf.put_u8(kind);
+            let version = entry
+                .version
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            put_str16(&mut buf, &version)?;
+            let digest = entry
+                .content_digest
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            put_str16(&mut buf, &digest)?;
+            buf.put_u32(
+                u32::try_from(entry.original_size)
+                    .map_err(|_| DiffError::InvalidFormat("Batch entry too large".to_string()))?,
+            );
+            buf.put_u32(
+                u32::try_from(body.len()).map_err(|_| {
+                    DiffError::InvalidFormat("Batch entry body too large".to_string())
+                })?,
+            );
+            buf.put_slice(body);
+        }
+        Ok(buf.freeze())
     }
 
-    /// Check if operation requires length parameter
-    pub fn requires_length(self) -> bool {
-        matches!(self, Self::Copy | Self::Insert | Self::Delete)
+    /// Decode from the batch wire format
+    pub fn decode(data: &[u8]) -> Result<Self, DiffError> {
+        let mut cursor = data;
+        let count = get_u16(&mut cursor, "entry count")? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let path = ResourcePath::new(get_str16(&mut cursor, "path")?);
+            if cursor.remaining() < 1 {
+                return Err(insufficient("entry kind"));
+            }
+            let kind = cursor.get_u8();
+            let version = get_str16(&mut cursor, "version")?;
+            let digest = get_str16(&mut cursor, "content digest")?;
+            let original_size = get_u32(&mut cursor, "original size")? as usize;
+            let body_len = get_u32(&mut cursor, "body length")? as usize;
+            if cursor.remaining() < body_len {
+                return Err(insufficient("entry body"));
+            }
+            let body = Bytes::copy_from_slice(&cursor[..body_len]);
+            cursor.advance(body_len);
+
+            let body = match kind {
+                BATCH_KIND_FULL => Ok(ResponseBody::Full(body)),
+                BATCH_KIND_ERROR => Err(String::from_utf8_lossy(&body).into_owned()),
+                code => Ok(ResponseBody::Diff {
+                    format: format_from_code(code).ok_or_else(|| {
+                        DiffError::InvalidFormat(format!(
+                            "Unknown batch entry kind: 0x{:02x}",
+                            code
+                        ))
+                    })?,
+                    data: body,
+                }),
+            };
+            entries.push(BatchResponseEntry {
+                path,
+                version: (!version.is_empty()).then(|| Version::new(version)),
+                content_digest: (!digest.is_empty()).then(|| Version::new(digest)),
+                original_size,
+                body,
+            });
+        }
+        expect_consumed(cursor)?;
+        Ok(Self { entries })
     }
+}
+
+/// Wire code of a diff format in the batch envelope
+fn format_code(format: DiffFormat) -> u8 {
+    match format {
+        DiffFormat::BinaryDelta => 0x01,
+        DiffFormat::JsonPatch => 0x02,
+        DiffFormat::BsdDiff => 0x03,
+        DiffFormat::ElementDelta => 0x04,
+    }
+}
 
-    /// Check if operation requires data parameter
-    pub fn requires_data(self) -> bool {
-        matches!(self, Self::Insert)
+/// Diff format for a batch envelope wire code
+fn format_from_code(code: u8) -> Option<DiffFormat> {
+    match code {
+        0x01 => Some(DiffFormat::BinaryDelta),
+        0x02 => Some(DiffFormat::JsonPatch),
+        0x03 => Some(DiffFormat::BsdDiff),
+        0x04 => Some(DiffFormat::ElementDelta),
+        _ => None,
+    }
+}
+
+fn insufficient(what: &str) -> DiffError {
+    DiffError::InvalidFormat(format!("Insufficient data for batch {}", what))
+}
+
+fn put_count(buf: &mut BytesMut, count: usize) -> Result<(), DiffError> {
+    if count > MAX_BATCH_ENTRIES {
+        return Err(DiffError::InvalidFormat(format!(
+            "Too many batch entries: {} (max: {})",
+            count, MAX_BATCH_ENTRIES
+        )));
+    }
+    buf.put_u16(count as u16);
+    Ok(())
+}
+
+fn put_str16(buf: &mut BytesMut, value: &str) -> Result<(), DiffError> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| DiffError::InvalidFormat("Batch string too long".to_string()))?;
+    buf.put_u16(len);
+    buf.put_slice(value.as_bytes());
+    Ok(())
+}
+
+fn get_u16(cursor: &mut &[u8], what: &str) -> Result<u16, DiffError> {
+    if cursor.remaining() < 2 {
+        return Err(insufficient(what));
+    }
+    Ok(cursor.get_u16())
+}
+
+fn get_u32(cursor: &mut &[u8], what: &str) -> Result<u32, DiffError> {
+    if cursor.remaining() < 4 {
+        return Err(insufficient(what));
+    }
+    Ok(cursor.get_u32())
+}
+
+fn get_str16(cursor: &mut &[u8], what: &str) -> Result<String, DiffError> {
+    let len = get_u16(cursor, what)? as usize;
+    if cursor.remaining() < len {
+        return Err(insufficient(what));
+    }
+    let value = std::str::from_utf8(&cursor[..len])
+        .map_err(|_| DiffError::InvalidFormat(format!("Batch {} is not valid UTF-8", what)))?
+        .to_string();
+    cursor.advance(len);
+    Ok(value)
+}
+
+fn expect_consumed(cursor: &[u8]) -> Result<(), DiffError> {
+    if cursor.is_empty() {
+        Ok(())
+    } else {
+        Err(DiffError::InvalidFormat(
+            "Trailing bytes after batch entries".to_string(),
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::diff::{BinaryDiffCodec, DiffOperation, binary::PatchApplier};
 
     #[test]
     fn test_diff_op_values() {
@@ -133,6 +506,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_operation_semantics() {
         // Test the logical meaning of operations
         assert_eq!(
@@ -152,4 +526,188 @@ mod tests {
             true
         );
     }
+
+    fn sample_diff() -> Bytes {
+        BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 10,
+            },
+            DiffOperation::Delete { length: 5 },
+            DiffOperation::Insert(b"red".to_vec()),
+            DiffOperation::Copy {
+                offset: 0,
+                length: 4,
+            },
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_frame_encoding() {
+        let frame = FrameCodec::encode_frame(b"abc");
+        assert_eq!(frame.as_ref(), &[0x00, 0x00, 0x00, 0x03, b'a', b'b', b'c']);
+        assert_eq!(FrameCodec::end_frame().as_ref(), &[0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_split_respects_op_boundaries() {
+        let diff = sample_diff();
+
+        // Small frame budget: every op ends up in its own frame
+        let frames = FrameCodec::split(&diff, 4).unwrap();
+        assert_eq!(frames.len(), 5); // 4 ops + terminator
+        assert_eq!(frames.last().unwrap().as_ref(), &[0, 0, 0, 0]);
+
+        // Large frame budget: everything in one frame
+        let frames = FrameCodec::split(&diff, DEFAULT_MAX_FRAME_PAYLOAD).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(&frames[0][FRAME_HEADER_LEN..], &diff[..diff.len() - 1]);
+    }
+
+    #[test]
+    fn test_split_rejects_truncated_diff() {
+        let result = FrameCodec::split(&[DiffOp::Insert as u8, 0x00, 0x00, 0x05, b'a'], 64);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_decoder_reassembles_byte_by_byte() {
+        let frames = FrameCodec::split(&sample_diff(), 4).unwrap();
+        let stream: Vec<u8> = frames.iter().flat_map(|f| f.to_vec()).collect();
+
+        let mut decoder = FrameDecoder::new();
+        let mut payloads = Vec::new();
+        for byte in stream {
+            decoder.push(&[byte]);
+            while let Some(payload) = decoder.next_frame().unwrap() {
+                payloads.push(payload);
+            }
+        }
+
+        assert!(decoder.is_finished());
+        assert_eq!(payloads.len(), 4);
+    }
+
+    #[test]
+    fn test_decoder_max_payload() {
+        let mut decoder = FrameDecoder::with_max_payload(2);
+        decoder.push(&FrameCodec::encode_frame(b"abc"));
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_incremental_apply_matches_buffered() {
+        let base = b"The quick brown fox";
+        let diff = sample_diff();
+        let frames = FrameCodec::split(&diff, 4).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        let mut applier = PatchApplier::new(base);
+        let mut output = Vec::new();
+        for frame in frames {
+            decoder.push(&frame);
+            while let Some(payload) = decoder.next_frame().unwrap() {
+                output.extend_from_slice(&applier.apply_chunk(&payload).unwrap());
+            }
+        }
+
+        assert_eq!(output, BinaryDiffCodec::apply_diff(base, &diff).unwrap());
+        assert_eq!(output, b"The quick red fox");
+    }
+
+    #[test]
+    fn test_batch_request_round_trip() {
+        let request = BatchRequest {
+            entries: vec![
+                BatchRequestEntry {
+                    path: ResourcePath::new("/api/a".to_string()),
+                    base_version: Some(Version::new("v:1".to_string())),
+                },
+                BatchRequestEntry {
+                    path: ResourcePath::new("/api/b".to_string()),
+                    base_version: None,
+                },
+            ],
+        };
+
+        let encoded = request.encode().unwrap();
+        assert_eq!(&encoded[..2], &[0x00, 0x02]);
+        assert_eq!(BatchRequest::decode(&encoded).unwrap(), request);
+    }
+
+    #[test]
+    fn test_batch_response_round_trip() {
+        let response = BatchResponse {
+            entries: vec![
+                BatchResponseEntry {
+                    path: ResourcePath::new("/api/a".to_string()),
+                    version: Some(Version::new("v:2".to_string())),
+                    content_digest: Some(Version::new("v:5e1f".to_string())),
+                    original_size: 100,
+                    body: Ok(ResponseBody::Diff {
+                        format: DiffFormat::BinaryDelta,
+                        data: Bytes::from_static(&[0x04]),
+                    }),
+                },
+                BatchResponseEntry {
+                    path: ResourcePath::new("/api/b".to_string()),
+                    version: Some(Version::new("v:3".to_string())),
+                    content_digest: None,
+                    original_size: 4,
+                    body: Ok(ResponseBody::Full(Bytes::from_static(b"full"))),
+                },
+                BatchResponseEntry {
+                    path: ResourcePath::new("/api/missing".to_string()),
+                    version: None,
+                    content_digest: None,
+                    original_size: 0,
+                    body: Err("not found".to_string()),
+                },
+            ],
+        };
+
+        let decoded = BatchResponse::decode(&response.encode().unwrap()).unwrap();
+        assert_eq!(decoded.entries.len(), 3);
+        assert_eq!(decoded.entries[0].original_size, 100);
+        assert_eq!(
+            decoded.entries[0].content_digest,
+            Some(Version::new("v:5e1f".to_string()))
+        );
+        assert_eq!(decoded.entries[1].content_digest, None);
+        assert_eq!(
+            decoded.entries[0].body.as_ref().unwrap().diff_format(),
+            Some(DiffFormat::BinaryDelta)
+        );
+        assert_eq!(
+            decoded.entries[1]
+                .body
+                .as_ref()
+                .unwrap()
+                .as_bytes()
+                .as_ref(),
+            b"full"
+        );
+        assert!(decoded.entries[2].version.is_none());
+        assert_eq!(decoded.entries[2].body.as_ref().unwrap_err(), "not found");
+    }
+
+    #[test]
+    fn test_batch_decode_rejects_malformed() {
+        // Count says one entry, but nothing follows
+        assert!(BatchRequest::decode(&[0x00, 0x01]).is_err());
+        // Trailing garbage
+        assert!(BatchRequest::decode(&[0x00, 0x00, 0xFF]).is_err());
+        // Unknown entry kind
+        let mut bad = BytesMut::new();
+        bad.put_u16(1);
+        bad.put_u16(1);
+        bad.put_slice(b"/");
+        bad.put_u8(0x7F);
+        bad.put_u16(0);
+        bad.put_u16(0);
+        bad.put_u32(0);
+        bad.put_u32(0);
+        assert!(BatchResponse::decode(&bad).is_err());
+    }
 }
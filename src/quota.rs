@@ -0,0 +1,295 @@
+//! Bandwidth and diff CPU quotas per session
+//!
+//! A client keeping many bases alive, or asking for diffs of large
+//! resources, makes the server spend far more than the request costs the
+//! client. With [`BpxConfig::session_quota`](crate::BpxConfig::session_quota)
+//! set, [`BpxServer`](crate::BpxServer) counts the body bytes it sends each
+//! session and the time it spends computing diffs for it, over windows of
+//! [`SessionQuota::window`]. Past either limit the session's requests are
+//! either answered with full bodies only, with fallback reason
+//! `quota-exceeded` and no diff computed, or refused with
+//! [`BpxError::QuotaExceeded`] until the window ends, per
+//! [`SessionQuota::action`]. Sessions are counted per tenant when the
+//! request names one (see [`crate::tenant`]).
+
+use crate::{
+    BpxConfig, BpxError, SessionId, TenantId,
+    server::{ResourceBody, request_session, response_session},
+};
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Checks between sweeps of sessions whose window has ended
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// What one session may consume per window; `None` doesn't limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct SessionQuota {
+    /// Length of the window usage is counted over
+    #[cfg_attr(feature = "serde", serde(with = "crate::config::duration"))]
+    pub window: Duration,
+    /// Body bytes sent to the session
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub max_bytes: Option<u64>,
+    /// Time spent computing diffs for the session
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "crate::config::optional_duration")
+    )]
+    pub max_diff_time: Option<Duration>,
+    /// What a session past either limit gets
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub action: QuotaAction,
+}
+
+/// What a session past its [`SessionQuota`] gets until the window ends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum QuotaAction {
+    /// Full bodies, with no diff computed
+    #[default]
+    FullOnly,
+    /// `429` with code `quota-exceeded`
+    Reject,
+}
+
+/// Request extension telling the handlers to send full bodies only
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullOnly;
+
+/// What a session has consumed in the current window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionUsage {
+    /// Body bytes sent
+    pub bytes_sent: u64,
+    /// Time spent computing diffs
+    pub diff_time: Duration,
+}
+
+/// Diff computation time spent answering a response, as a response
+/// extension
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffTime(pub Duration);
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started: Instant,
+    usage: SessionUsage,
+}
+
+/// Counts each session's usage against a [`SessionQuota`]
+pub struct QuotaTracker {
+    quota: SessionQuota,
+    windows: DashMap<(Option<TenantId>, SessionId), Window>,
+    checks: AtomicU64,
+}
+
+impl QuotaTracker {
+    /// Enforce `quota`
+    pub fn new(quota: SessionQuota) -> Self {
+        Self {
+            quota,
+            windows: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Action the quota takes
+    pub fn action(&self) -> QuotaAction {
+        self.quota.action
+    }
+
+    /// Time until the window of `session` ends, if it is past its quota
+    pub fn exceeded(&self, tenant: Option<&TenantId>, session: &SessionId) -> Option<Duration> {
+        let now = Instant::now();
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            self.sweep(now);
+        }
+        let window = self.windows.get(&(tenant.cloned(), session.clone()))?;
+        let elapsed = now.saturating_duration_since(window.started);
+        if elapsed >= self.quota.window {
+            return None;
+        }
+        let usage = window.usage;
+        let over = self
+            .quota
+            .max_bytes
+            .is_some_and(|max| usage.bytes_sent > max)
+            || self
+                .quota
+                .max_diff_time
+                .is_some_and(|max| usage.diff_time > max);
+        over.then(|| self.quota.window - elapsed)
+    }
+
+    /// Count `bytes_sent` and `diff_time` against `session`
+    pub fn charge(
+        &self,
+        tenant: Option<&TenantId>,
+        session: SessionId,
+        bytes_sent: u64,
+        diff_time: Duration,
+    ) {
+        let now = Instant::now();
+        let mut window = self
+            .windows
+            .entry((tenant.cloned(), session))
+            .or_insert(Window {
+                started: now,
+                usage: SessionUsage::default(),
+            });
+        if now.saturating_duration_since(window.started) >= self.quota.window {
+            window.started = now;
+            window.usage = SessionUsage::default();
+        }
+        window.usage.bytes_sent = window.usage.bytes_sent.saturating_add(bytes_sent);
+        window.usage.diff_time = window.usage.diff_time.saturating_add(diff_time);
+    }
+
+    /// What `session` has consumed in its current window
+    pub fn usage(&self, tenant: Option<&TenantId>, session: &SessionId) -> SessionUsage {
+        self.windows
+            .get(&(tenant.cloned(), session.clone()))
+            .filter(|window| window.started.elapsed() < self.quota.window)
+            .map(|window| window.usage)
+            .unwrap_or_default()
+    }
+
+    /// Refuse `req`, or mark it [`FullOnly`], if its session is past its
+    /// quota
+    ///
+    /// Requests presenting no session aren't limited.
+    pub fn check<B>(&self, req: &mut Request<B>, config: &BpxConfig) -> Result<(), BpxError> {
+        let Some(session) = request_session(req, config)? else {
+            return Ok(());
+        };
+        let tenant = req.extensions().get::<TenantId>();
+        let Some(retry_after) = self.exceeded(tenant, &session) else {
+            return Ok(());
+        };
+        match self.quota.action {
+            QuotaAction::FullOnly => {
+                req.extensions_mut().insert(FullOnly);
+                Ok(())
+            }
+            QuotaAction::Reject => Err(BpxError::QuotaExceeded {
+                session,
+                retry_after,
+            }),
+        }
+    }
+
+    /// Charge the session `response` names with `bytes_sent` and its
+    /// [`DiffTime`]
+    pub(crate) fn charge_response<B>(
+        &self,
+        tenant: Option<&TenantId>,
+        response: &Response<B>,
+        bytes_sent: u64,
+    ) {
+        if let Some(session) = response_session(response) {
+            let diff_time = response.extensions().get::<DiffTime>().copied();
+            self.charge(tenant, session, bytes_sent, diff_time.unwrap_or_default().0);
+        }
+    }
+
+    /// Charge the session `response` names, counting a body of unknown
+    /// length as it is streamed
+    pub(crate) fn meter(
+        self: &Arc<Self>,
+        tenant: Option<TenantId>,
+        response: Response<ResourceBody>,
+    ) -> Response<ResourceBody> {
+        let Some(session) = response_session(&response) else {
+            return response;
+        };
+        let body_len = http_body::Body::size_hint(response.body()).exact();
+        self.charge_response(tenant.as_ref(), &response, body_len.unwrap_or(0));
+        if body_len.is_some() {
+            return response;
+        }
+        let tracker = Arc::clone(self);
+        response.map(|body| {
+            body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    tracker.charge(
+                        tenant.as_ref(),
+                        session.clone(),
+                        data.len() as u64,
+                        Duration::ZERO,
+                    );
+                }
+                frame
+            })
+            .boxed_unsync()
+        })
+    }
+
+    /// Number of sessions with a window open
+    pub fn tracked(&self) -> usize {
+        self.windows.len()
+    }
+
+    /// Forget windows that have ended; they'd be restarted empty
+    fn sweep(&self, now: Instant) {
+        self.windows
+            .retain(|_, window| now.saturating_duration_since(window.started) < self.quota.window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_per_session_and_window() {
+        let tracker = QuotaTracker::new(SessionQuota {
+            window: Duration::from_millis(50),
+            max_bytes: Some(100),
+            max_diff_time: Some(Duration::from_millis(10)),
+            action: QuotaAction::Reject,
+        });
+        let (a, b) = (
+            SessionId::new("a".to_string()),
+            SessionId::new("b".to_string()),
+        );
+        let tenant = TenantId::new("acme".to_string());
+
+        tracker.charge(None, a.clone(), 100, Duration::ZERO);
+        assert_eq!(tracker.exceeded(None, &a), None);
+        tracker.charge(None, a.clone(), 1, Duration::ZERO);
+        let retry_after = tracker.exceeded(None, &a).unwrap();
+        assert!(retry_after <= Duration::from_millis(50));
+        assert_eq!(tracker.usage(None, &a).bytes_sent, 101);
+
+        // Sessions are counted apart, and per tenant
+        assert_eq!(tracker.exceeded(None, &b), None);
+        assert_eq!(tracker.exceeded(Some(&tenant), &a), None);
+        tracker.charge(Some(&tenant), a.clone(), 0, Duration::from_millis(11));
+        assert!(tracker.exceeded(Some(&tenant), &a).is_some());
+
+        // A new window starts empty
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(tracker.exceeded(None, &a), None);
+        assert_eq!(tracker.usage(None, &a), SessionUsage::default());
+        tracker.charge(None, a.clone(), 1, Duration::ZERO);
+        assert_eq!(tracker.usage(None, &a).bytes_sent, 1);
+    }
+}
@@ -0,0 +1,228 @@
+//! Token-bucket rate limiting per session and peer address
+//!
+//! Every request costs a diff or a session lookup, so a server tracking
+//! state is worth protecting. Each session, and with
+//! [`RateLimit::per_peer`] each peer address, gets a bucket of
+//! [`RateLimit::burst`] requests refilled at [`RateLimit::per_second`];
+//! a request finding its bucket empty fails with [`BpxError::RateLimited`].
+//!
+//! A client can present a new session ID with every request, so only the
+//! peer limit holds back clients that don't cooperate. Peer addresses come
+//! from the [`PeerAddr`] request extension, which
+//! [`serve`](crate::serve) inserts. Session buckets are kept per tenant
+//! when the request names one (see [`crate::tenant`]).
+
+use crate::{BpxConfig, BpxError, SessionId, TenantId, server::request_session};
+use dashmap::DashMap;
+use hyper::Request;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Checks between sweeps of buckets that have refilled
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Address of the peer a request came from, as a request extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Request rate allowed per session and peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct RateLimit {
+    /// Requests allowed in a burst
+    pub burst: u32,
+    /// Sustained requests per second; with 0 only the burst is ever allowed
+    pub per_second: u32,
+    /// Also limit each peer IP address to this rate
+    pub per_peer: bool,
+}
+
+/// What a bucket is kept for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    /// Sessions are only unique within a tenant
+    Session(Option<TenantId>, SessionId),
+    Peer(IpAddr),
+    /// One bucket for every request
+    Shared,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets enforcing a [`RateLimit`]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: DashMap<Key, Bucket>,
+    checks: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Enforce `limit`
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a token for the session and peer of `req`
+    ///
+    /// Requests presenting no session and with no known peer aren't limited.
+    pub fn check<B>(&self, req: &Request<B>, config: &BpxConfig) -> Result<(), BpxError> {
+        let now = Instant::now();
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            self.sweep(now);
+        }
+
+        let peer = req
+            .extensions()
+            .get::<PeerAddr>()
+            .filter(|_| self.limit.per_peer)
+            .map(|peer| Key::Peer(peer.0.ip()));
+        let session = request_session(req, config)?.map(|session| {
+            let tenant = req.extensions().get::<TenantId>().cloned();
+            Key::Session(tenant, session)
+        });
+        for key in peer.into_iter().chain(session) {
+            self.take(key, now)?;
+        }
+        Ok(())
+    }
+
+    /// Take a token for `peer` alone, or with [`RateLimit::per_peer`] unset
+    /// from one bucket shared by every request
+    ///
+    /// For limiting something only some requests do, e.g. create sessions
+    /// (see [`UnknownSessionPolicy`](crate::UnknownSessionPolicy)). Requests
+    /// with no known peer aren't limited per peer.
+    pub fn check_peer(&self, peer: Option<IpAddr>) -> Result<(), BpxError> {
+        let now = Instant::now();
+        if self.checks.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            self.sweep(now);
+        }
+        let key = match peer {
+            _ if !self.limit.per_peer => Key::Shared,
+            Some(peer) => Key::Peer(peer),
+            None => return Ok(()),
+        };
+        self.take(key, now)
+    }
+
+    /// Number of sessions and peers with a bucket
+    pub fn tracked(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn take(&self, key: Key, now: Instant) -> Result<(), BpxError> {
+        let burst = f64::from(self.limit.burst);
+        let rate = f64::from(self.limit.per_second);
+        let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = if rate > 0.0 {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+        } else {
+            // Never refilled; any finite answer keeps Retry-After representable
+            Duration::from_secs(u64::from(u32::MAX))
+        };
+        Err(BpxError::RateLimited { retry_after })
+    }
+
+    /// Forget buckets that have refilled; they'd be recreated full
+    fn sweep(&self, now: Instant) {
+        let burst = f64::from(self.limit.burst);
+        let rate = f64::from(self.limit.per_second);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::headers::BpxHeaders;
+
+    fn request(session: Option<&str>, peer: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().uri("/api/feed");
+        if let Some(session) = session {
+            builder = builder.header(BpxHeaders::SESSION, session);
+        }
+        let mut req = builder.body(()).unwrap();
+        if let Some(peer) = peer {
+            req.extensions_mut().insert(PeerAddr(peer.parse().unwrap()));
+        }
+        req
+    }
+
+    #[test]
+    fn test_buckets_per_session_and_peer() {
+        let config = BpxConfig::default();
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            per_second: 1,
+            per_peer: true,
+        });
+
+        let a = request(Some("a"), Some("10.0.0.1:1000"));
+        assert!(limiter.check(&a, &config).is_ok());
+        assert!(limiter.check(&a, &config).is_ok());
+        match limiter.check(&a, &config) {
+            Err(BpxError::RateLimited { retry_after }) => {
+                assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+            }
+            other => panic!("expected rate limit, got {:?}", other),
+        }
+
+        // The peer's bucket is empty whatever session it presents
+        let fresh_session = request(Some("b"), Some("10.0.0.1:2000"));
+        assert!(limiter.check(&fresh_session, &config).is_err());
+        let other_peer = request(Some("c"), Some("10.0.0.2:1000"));
+        assert!(limiter.check(&other_peer, &config).is_ok());
+
+        // Nothing to key on
+        for _ in 0..5 {
+            assert!(limiter.check(&request(None, None), &config).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_buckets_refill() {
+        let config = BpxConfig::default();
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 1,
+            per_second: 1000,
+            per_peer: false,
+        });
+        let req = request(Some("a"), Some("10.0.0.1:1000"));
+        assert!(limiter.check(&req, &config).is_ok());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.check(&req, &config).is_ok());
+        assert_eq!(limiter.tracked(), 1);
+
+        std::thread::sleep(Duration::from_millis(5));
+        limiter.sweep(Instant::now());
+        assert_eq!(limiter.tracked(), 0);
+    }
+}
@@ -0,0 +1,432 @@
+//! Accepting connections and serving them over HTTP/2 or HTTP/1.1
+
+#[cfg(feature = "tls")]
+pub use rustls;
+
+use crate::{
+    BpxLayer, BpxServer, BpxService, ResourceStore, rate_limit::PeerAddr, server::ResourceBody,
+};
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::{Method, Request, Response, StatusCode, body::Incoming};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use std::{convert::Infallible, future::Future, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tower::{Layer, Service, ServiceExt};
+
+/// Pause after a failed accept (e.g. out of file descriptors) before retrying
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve `service` on every connection accepted from `listener` until
+/// `shutdown` completes
+///
+/// Each connection speaks HTTP/2 if it opens with the HTTP/2 preface (h2c
+/// with prior knowledge) and HTTP/1.1 otherwise. On shutdown the listener
+/// is closed, open connections are asked to finish their in-flight requests
+/// and are dropped after `drain`.
+pub async fn serve_connections<S, B>(
+    listener: TcpListener,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+    drain: Duration,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    serve_accepted(listener, service, shutdown, drain, |stream| async {
+        Ok(stream)
+    })
+    .await
+}
+
+/// Like [`serve_connections`], terminating TLS on every connection first
+///
+/// Unless `tls_config` sets its own ALPN protocols, `h2` and `http/1.1` are
+/// offered so TLS clients can negotiate HTTP/2. Handshakes not done within
+/// ten seconds are abandoned.
+#[cfg(feature = "tls")]
+pub async fn serve_tls_connections<S, B>(
+    listener: TcpListener,
+    tls_config: Arc<rustls::ServerConfig>,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+    drain: Duration,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let acceptor = tokio_rustls::TlsAcceptor::from(with_alpn(tls_config));
+    serve_accepted(listener, service, shutdown, drain, move |stream| {
+        let handshake = acceptor.accept(stream);
+        async move {
+            tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+        }
+    })
+    .await
+}
+
+/// Time a client gets to complete the TLS handshake
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Offer HTTP/2 and HTTP/1.1 over ALPN unless the config names protocols
+#[cfg(feature = "tls")]
+fn with_alpn(tls_config: Arc<rustls::ServerConfig>) -> Arc<rustls::ServerConfig> {
+    if !tls_config.alpn_protocols.is_empty() {
+        return tls_config;
+    }
+    let mut tls_config = Arc::unwrap_or_clone(tls_config);
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(tls_config)
+}
+
+/// Accept connections, set each up with `accept` and serve it
+///
+/// `accept` runs in the connection's own task, so a slow handshake doesn't
+/// hold up the listener.
+async fn serve_accepted<S, B, A, F, I>(
+    listener: TcpListener,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+    drain: Duration,
+    accept: A,
+) -> io::Result<()>
+where
+    S: Service<Request<Incoming>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    A: Fn(TcpStream) -> F,
+    F: Future<Output = io::Result<I>> + Send + 'static,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Accept failed: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            () = &mut shutdown => break,
+        };
+        // Diffs are small; don't hold them back waiting for more to send
+        let _ = stream.set_nodelay(true);
+
+        let io = accept(stream);
+        let service = TowerToHyperService::new(service.clone().map_request(
+            move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(PeerAddr(peer));
+                req
+            },
+        ));
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let io = match io.await {
+                Ok(io) => io,
+                Err(e) => {
+                    eprintln!("Connection setup failed: {}", e);
+                    return;
+                }
+            };
+            let connection = builder.serve_connection(TokioIo::new(io), service);
+            if let Err(e) = watcher.watch(connection.into_owned()).await {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+
+    drop(listener);
+    tokio::select! {
+        () = graceful.shutdown() => {}
+        () = tokio::time::sleep(drain) => {}
+    }
+    Ok(())
+}
+
+/// Answer requests BPX doesn't handle
+async fn not_found(req: Request<Incoming>) -> Result<Response<Empty<Bytes>>, Infallible> {
+    let status = match *req.method() {
+        Method::GET | Method::HEAD => StatusCode::NOT_FOUND,
+        _ => StatusCode::METHOD_NOT_ALLOWED,
+    };
+    let mut response = Response::new(Empty::new());
+    *response.status_mut() = status;
+    Ok(response)
+}
+
+/// Serve `store` through `server` with batch exchanges at `/batch`, and
+/// writes if `server` accepts them; everything else is answered `404` or
+/// `405`
+fn store_service<R>(
+    server: Arc<BpxServer>,
+    store: Arc<R>,
+) -> BpxService<
+    impl Service<Request<Incoming>, Response = Response<Empty<Bytes>>, Error = Infallible, Future: Send>
+    + Clone
+    + Send
+    + 'static,
+    R,
+>
+where
+    R: ResourceStore + 'static,
+{
+    let writes = server.accepts_writes();
+    BpxLayer::new(server, store)
+        .batch_path("/batch")
+        .writes(writes)
+        .layer(tower::service_fn(not_found))
+}
+
+/// Serve `store` through `server` on connections from `listener`
+pub(crate) async fn serve_store<R>(
+    server: Arc<BpxServer>,
+    store: Arc<R>,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    R: ResourceStore + 'static,
+{
+    let drain = server.config().shutdown_timeout;
+    let service = store_service(server, store);
+    serve_connections::<_, ResourceBody>(listener, service, shutdown, drain).await
+}
+
+/// Serve `store` through `server` on TLS connections from `listener`
+#[cfg(feature = "tls")]
+pub(crate) async fn serve_store_tls<R>(
+    server: Arc<BpxServer>,
+    store: Arc<R>,
+    listener: TcpListener,
+    tls_config: Arc<rustls::ServerConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    R: ResourceStore + 'static,
+{
+    let drain = server.config().shutdown_timeout;
+    let service = store_service(server, store);
+    serve_tls_connections::<_, ResourceBody>(listener, tls_config, service, shutdown, drain).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BpxConfig, InMemoryResourceStore, ResourcePath, diff::similar::SimilarDiffEngine,
+        protocol::headers::BpxHeaders, state::InMemoryStateManager,
+    };
+    use http_body_util::BodyExt;
+    use hyper::Version;
+    use hyper_util::client::legacy::Client;
+
+    #[tokio::test]
+    async fn test_serves_http2_and_http1_until_shutdown() {
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(BpxConfig::default())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_with_shutdown(listener, store, async {
+            let _ = stopped.await;
+        }));
+
+        let h2 = Client::builder(TokioExecutor::new())
+            .http2_only(true)
+            .build_http::<Empty<Bytes>>();
+        let h1 = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        for (client, version) in [(h2, Version::HTTP_2), (h1, Version::HTTP_11)] {
+            let uri = format!("http://{}/api/feed", addr).parse().unwrap();
+            let response = client.get(uri).await.unwrap();
+            assert_eq!(response.version(), version);
+            assert!(response.headers().contains_key(BpxHeaders::SESSION));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "feed");
+
+            let uri = format!("http://{}/missing", addr).parse().unwrap();
+            let response = client.get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_opt_in() {
+        use crate::auth::{Authorizer, Decision, RequestContext};
+        use http_body_util::Full;
+
+        /// Lets `X-User: editor` write
+        struct Editors;
+
+        #[async_trait::async_trait]
+        impl Authorizer for Editors {
+            async fn authorize(&self, context: &RequestContext, _path: &ResourcePath) -> Decision {
+                let editor = context.headers.get("X-User").is_some_and(|u| u == "editor");
+                if context.method == Method::GET || editor {
+                    Decision::Allow
+                } else {
+                    Decision::Deny
+                }
+            }
+        }
+
+        assert!(matches!(
+            BpxServer::builder().writes().build(),
+            Err(crate::BpxError::BuilderError { .. })
+        ));
+
+        let put = |addr, user: &str| {
+            let request = Request::put(format!("http://{}/api/doc", addr))
+                .header("X-User", user)
+                .body(Full::new(Bytes::from("defaced")))
+                .unwrap();
+            Client::builder(TokioExecutor::new())
+                .build_http::<Full<Bytes>>()
+                .request(request)
+        };
+        for (server, expected) in [
+            (BpxServer::builder(), [StatusCode::METHOD_NOT_ALLOWED; 2]),
+            (
+                BpxServer::builder().authorizer(Arc::new(Editors)).writes(),
+                [StatusCode::FORBIDDEN, StatusCode::NO_CONTENT],
+            ),
+        ] {
+            let store = Arc::new(InMemoryResourceStore::new());
+            let path = ResourcePath::new("/api/doc".to_string());
+            store.set_resource(path.clone(), Bytes::from("doc"));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serving = tokio::spawn(Arc::new(server.build().unwrap()).serve_with_shutdown(
+                listener,
+                store.clone(),
+                std::future::pending(),
+            ));
+
+            for (user, expected) in ["guest", "editor"].into_iter().zip(expected) {
+                let response = put(addr, user).await.unwrap();
+                assert_eq!(response.status(), expected);
+            }
+            let written = expected[1] == StatusCode::NO_CONTENT;
+            assert_eq!(
+                store.get_current_resource(&path).unwrap() == "defaced",
+                written
+            );
+            serving.abort();
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_serves_tls_with_alpn() {
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject};
+
+        let certs = CertificateDer::pem_slice_iter(include_bytes!("../testdata/tls/localhost.crt"))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key =
+            PrivateKeyDer::from_pem_slice(include_bytes!("../testdata/tls/localhost.key")).unwrap();
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap();
+
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(BpxConfig::default())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_tls_with_shutdown(
+            listener,
+            Arc::new(tls_config),
+            store,
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let mut roots = rustls::RootCertStore::empty();
+        let ca = CertificateDer::from_pem_slice(include_bytes!("../testdata/tls/ca.crt")).unwrap();
+        roots.add(ca).unwrap();
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let tls = connector
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get(format!("https://localhost:{}/api/feed", addr.port()))
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "feed");
+
+        drop(sender);
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+}
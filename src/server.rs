@@ -1,15 +1,217 @@
 //! HTTP/2 server implementation for BPX
 
 use crate::{
-    BpxConfig, BpxError, DiffEngine, DiffFormat, ResourcePath, SessionId, StateManager, Version,
-    protocol::{BpxRequest, BpxResponse, ResponseBody, headers::BpxHeaders},
+    BpxConfig, BpxError, DiffEngine, DiffFormat, ResourcePath, SessionId, StateManager, TenantId,
+    Version, VersionStorage,
+    admin::SessionDebug,
+    affinity::affinity_key,
+    auth::{Authorizer, Decision, RequestContext},
+    diff::{
+        DiffError,
+        collection::{COLLECTION_MEDIA_TYPE, Element, ElementDiffCodec, ElementDiffEngine},
+    },
+    load::{self, LoadShedder},
+    metrics::SlowDiff,
+    observer::DiffDecision,
+    protocol::{
+        BpxRequest, BpxResponse, FallbackReason, ResponseBody,
+        headers::{BpxHeaders, DeltaHeaders, decode_value, encode_value},
+        negotiate_format,
+        params::{BodyParams, PARAMS_MEDIA_TYPE},
+        parse_accept_diff,
+        wire::{BATCH_MEDIA_TYPE, BatchRequest, BatchResponse, BatchResponseEntry},
+    },
+    quota::{DiffTime, FullOnly},
+    state::SessionStatus,
+    stats::StoreStats,
+    store::{VersionBroadcast, VersionStream},
 };
 use async_trait::async_trait;
-use bytes::Bytes;
-use hyper::{Request, Response};
-use std::sync::Arc;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use dashmap::mapref::entry::Entry;
+use futures_core::Stream;
+use http_body::Frame;
+use http_body_util::{BodyExt, Empty, Full, Limited, StreamBody, combinators::UnsyncBoxBody};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    header::{self, HeaderValue},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, OnceLock, RwLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Maximum accepted size of a batch request body
+pub const MAX_BATCH_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Maximum accepted size of a body carrying BPX parameters
+pub const MAX_PARAMS_REQUEST_SIZE: usize = 4 * 1024;
+
+/// Maximum accepted size of a `PUT` or `PATCH` body
+pub const MAX_WRITE_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+/// Maximum number of candidate base versions considered per request
+pub const MAX_BASE_VERSIONS: usize = 8;
+
+/// Maximum length of a session ID a request presents, once decoded
+pub const MAX_SESSION_ID_LEN: usize = 256;
+
+/// Maximum length of each base version a request names, once decoded
+pub const MAX_VERSION_LEN: usize = 256;
+
+/// Maximum number of entries in an `Accept-Diff` or `A-IM` list
+pub const MAX_ACCEPT_DIFF_ENTRIES: usize = 16;
+
+/// Diff formats this server can produce, in server preference order
+pub const SUPPORTED_FORMATS: &[DiffFormat] = &[DiffFormat::BinaryDelta];
+
+/// Diff formats this server can produce for collection resources
+pub const COLLECTION_FORMATS: &[DiffFormat] = &[DiffFormat::ElementDelta, DiffFormat::BinaryDelta];
+
+/// Media type of Server-Sent Events streams
+pub const EVENT_STREAM_MEDIA_TYPE: &str = "text/event-stream";
+
+/// Request header naming the last event an `EventSource` received
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+/// Events buffered per event stream before changes wait for the client
+const EVENT_STREAM_BUFFER: usize = 4;
+
+/// Interval of comments keeping idle event streams open
+const EVENT_STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Media type of structured error bodies (RFC 7807)
+pub const PROBLEM_JSON_MEDIA_TYPE: &str = "application/problem+json";
+
+/// `Retry-After` sent when the server holds all the sessions it may
+const CAPACITY_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// `Retry-After` sent when the server is handling all the requests it may
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// HTTP status an error should be reported with
+pub fn error_status(err: &BpxError) -> StatusCode {
+    match err {
+        BpxError::ClientStateNotFound { .. }
+        | BpxError::ResourceNotFound { .. }
+        | BpxError::VersionNotFound { .. }
+        | BpxError::UnknownTenant { .. } => StatusCode::NOT_FOUND,
+        BpxError::InvalidDiffFormat { .. }
+        | BpxError::InvalidRequest { .. }
+        | BpxError::InvalidHeader { .. } => StatusCode::BAD_REQUEST,
+        BpxError::ResourceTooLarge { .. }
+        | BpxError::PatchTooLarge { .. }
+        | BpxError::BatchTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        BpxError::PreconditionFailed { .. } => StatusCode::PRECONDITION_FAILED,
+        BpxError::Forbidden { .. } => StatusCode::FORBIDDEN,
+        BpxError::ReadOnly { .. } | BpxError::MethodNotAllowed { .. } => {
+            StatusCode::METHOD_NOT_ALLOWED
+        }
+        BpxError::RateLimited { .. } | BpxError::QuotaExceeded { .. } => {
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        BpxError::SessionCapacityExceeded { .. } | BpxError::Overloaded { .. } => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        BpxError::DiffComputationFailed { .. }
+        | BpxError::Storage { .. }
+        | BpxError::InvalidResponse { .. }
+        | BpxError::BuilderError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        // Only raised client-side, against a response received from upstream
+        BpxError::InvalidSignature { .. } | BpxError::Transport { .. } => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Render an error as an `application/problem+json` response
+///
+/// The body carries the standard `type`, `title`, `status` and `detail`
+/// members plus a BPX-specific `code` (see [`BpxError::code`]).
+pub fn error_response(err: &BpxError) -> Response<Bytes> {
+    let status = error_status(err);
+    let body = format!(
+        r#"{{"type":"urn:bpx:error:{code}","title":"{title}","status":{status},"detail":"{detail}","code":"{code}"}}"#,
+        code = err.code(),
+        title = status.canonical_reason().unwrap_or("Error"),
+        status = status.as_u16(),
+        detail = escape_json(&err.to_string()),
+    );
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, PROBLEM_JSON_MEDIA_TYPE);
+    let retry_after = match err {
+        BpxError::RateLimited { retry_after } | BpxError::QuotaExceeded { retry_after, .. } => {
+            Some(*retry_after)
+        }
+        // Sessions are freed as they expire or are evicted
+        BpxError::SessionCapacityExceeded { .. } => Some(CAPACITY_RETRY_AFTER),
+        // Outstanding requests finish quickly
+        BpxError::Overloaded { .. } => Some(OVERLOADED_RETRY_AFTER),
+        _ => None,
+    };
+    if let Some(retry_after) = retry_after {
+        // Round up so clients never retry early
+        let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response = response.header(header::RETRY_AFTER, secs.to_string());
+    }
+    if let BpxError::PreconditionFailed {
+        current: Some(version),
+        ..
+    } = err
+    {
+        // Lets the client rebase its write without another round trip
+        response = response
+            .header(BpxHeaders::RESOURCE_VERSION, version_header(version))
+            .header(header::ETAG, etag(version));
+    }
+
+    if let BpxError::MethodNotAllowed { allowed, .. } = err {
+        let allowed: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+        response = response.header(header::ALLOW, allowed.join(", "));
+    }
+
+    // Every value above is header-safe, so this can't fail
+    response
+        .body(Bytes::from(body))
+        .unwrap_or_else(|_| Response::new(Bytes::new()))
+}
+
+impl From<BpxError> for Response<Bytes> {
+    /// The [`error_response`] for `err`
+    fn from(err: BpxError) -> Self {
+        error_response(&err)
+    }
+}
+
+/// Escape a string for embedding in a JSON string literal
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
 
 /// BPX HTTP request handler
+///
+/// The free handlers check neither an [`Authorizer`] nor the load limits;
+/// [`BpxServer`](crate::BpxServer) adds both, from its builder and config.
 pub async fn handle_bpx_request<B, R>(
     req: Request<B>,
     config: &BpxConfig,
@@ -21,360 +223,4542 @@ where
     B: http_body::Body + Send + 'static,
     R: ResourceStore + 'static,
 {
-    // Parse BPX headers from request
-    let bpx_request = parse_bpx_request(&req)?;
+    handle_bpx_request_guarded(
+        req,
+        config,
+        state_mgr,
+        diff_engine,
+        resource_store,
+        Guards::default(),
+    )
+    .await
+}
+
+/// [`handle_bpx_request`] behind `guards`
+pub(crate) async fn handle_bpx_request_guarded<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+    guards: Guards,
+) -> Result<Response<Bytes>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    R: ResourceStore + 'static,
+{
+    let Guards { authorizer, load } = guards;
+    check_method(&req, config, &[Method::GET, Method::HEAD])?;
+    let _admitted = load::admit(load.as_deref())?;
+    // Parse BPX headers (or their RFC 3229 equivalents) from request
+    let bpx_request = if config.rfc3229_mode {
+        parse_rfc3229_request(&req, config)?
+    } else {
+        parse_bpx_request(&req, config)?
+    };
 
-    // Fetch current resource
-    let current_content = resource_store.get_resource(&bpx_request.path).await?;
+    let access = Access::new(authorizer, &req, &bpx_request);
 
-    let current_version = Version::from_content(&current_content);
+    // Get or create session (RFC 3229 clients name their base explicitly and carry no session)
+    let session = if config.rfc3229_mode {
+        None
+    } else {
+        Some(
+            open_session(
+                state_mgr.as_ref(),
+                bpx_request.tenant.as_ref(),
+                bpx_request.session_id.clone(),
+            )
+            .await,
+        )
+    };
 
-    // Get or create session
-    let session_id = state_mgr
-        .get_or_create_session(bpx_request.session_id.clone())
-        .await;
+    if req.method() == Method::HEAD {
+        Access::check(access.as_ref(), &bpx_request.path).await?;
+        return build_head_response(&bpx_request, config, session, resource_store.as_ref()).await;
+    }
 
-    // Determine if client accepts any server-supported diff format (binary-delta for now)
-    let client_accepts_binary = bpx_request
-        .accepted_formats
-        .iter()
-        .any(|f| matches!(f, DiffFormat::BinaryDelta));
-
-    // Check if client has compatible state and we should send diff
-    let should_send_diff = if let Some(base_version) = &bpx_request.base_version {
-        // Client has state, check if we can compute diff
-        if let Some(stored_version) = state_mgr.get_version(&session_id, &bpx_request.path).await {
-            // Only send diff if client's base version matches what we have stored
-            // AND the current content is actually different
-            let versions_match = &stored_version == base_version;
-            let content_changed = &stored_version != &current_version;
-
-            versions_match && content_changed && client_accepts_binary
-        } else {
-            false
+    let exchange = Exchange {
+        config,
+        state_mgr: state_mgr.as_ref(),
+        diff_engine: diff_engine.as_ref(),
+        resource_store: resource_store.as_ref(),
+        session: session.as_ref(),
+        tenant: bpx_request.tenant.as_ref(),
+        accepted_formats: &bpx_request.accepted_formats,
+        access: access.as_ref(),
+        load: load.as_deref(),
+        slow_diffs: Mutex::default(),
+        diff_time: Mutex::default(),
+        full_only: req.extensions().get::<FullOnly>().is_some(),
+    };
+    let (response, original_size) = exchange
+        .resolve(&bpx_request.path, &bpx_request.base_versions)
+        .await?;
+    let changed = !bpx_request.base_versions.contains(&response.version);
+    let slow_diffs = exchange
+        .slow_diffs
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    let diff_time = exchange
+        .diff_time
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    let decision = DiffDecision::new(
+        &bpx_request.path,
+        &bpx_request.base_versions,
+        &response,
+        Some(original_size),
+    );
+
+    let mut http_response = if config.rfc3229_mode {
+        // RFC 3229 clients validate with If-None-Match; nothing changed since their base
+        if !changed {
+            let mut not_modified = build_not_modified_response(&response.version)?;
+            not_modified.extensions_mut().insert(decision);
+            return Ok(not_modified);
         }
+        build_rfc3229_response(response)?
     } else {
-        false
+        build_http_response_with_original_size(response, original_size, config)?
     };
+    http_response.extensions_mut().insert(decision);
+    if !slow_diffs.is_empty() {
+        http_response.extensions_mut().insert(slow_diffs);
+    }
+    if !diff_time.is_zero() {
+        http_response.extensions_mut().insert(DiffTime(diff_time));
+    }
+    Ok(http_response)
+}
 
-    let response = if should_send_diff {
-        let base_version = bpx_request.base_version.as_ref().unwrap();
+/// Body of a response from [`handle_bpx_request_streaming`]
+pub type ResourceBody = UnsyncBoxBody<Bytes, BpxError>;
 
-        match resource_store
-            .get_resource_version(&bpx_request.path, base_version)
-            .await
+/// [`handle_bpx_request`] that streams full responses instead of buffering them
+///
+/// When the client holds no base or accepts no format this server produces
+/// there is nothing to diff, so the body comes straight from
+/// [`ResourceStore::get_resource_stream`]. Content up to `max_diff_size` is
+/// recorded as a version once it has been streamed, so the client's next
+/// request can be answered with a diff; larger resources, and those under
+/// [`BpxConfig::no_history`], are never diffed.
+/// Everything else, including RFC 3229 mode, is answered as by
+/// [`handle_bpx_request`].
+pub async fn handle_bpx_request_streaming<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+) -> Result<Response<ResourceBody>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    R: ResourceStore + 'static,
+{
+    handle_bpx_request_streaming_guarded(
+        req,
+        config,
+        state_mgr,
+        diff_engine,
+        resource_store,
+        Guards::default(),
+    )
+    .await
+}
+
+/// [`handle_bpx_request_streaming`] behind `guards`
+pub(crate) async fn handle_bpx_request_streaming_guarded<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+    guards: Guards,
+) -> Result<Response<ResourceBody>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    R: ResourceStore + 'static,
+{
+    let Guards { authorizer, load } = guards;
+    check_method(&req, config, &[Method::GET, Method::HEAD])?;
+    let mut bpx_request = parse_bpx_request(&req, config)?;
+    if !config.rfc3229_mode && accepts_event_stream(&req) {
+        // Counted while opening; an open stream only costs its diffs
+        let _admitted = load::admit(load.as_deref())?;
+        let access = Access::new(authorizer, &req, &bpx_request);
+        // A reconnecting EventSource names the version it last received
+        if let Some(last) = req
+            .headers()
+            .get(LAST_EVENT_ID)
+            .and_then(|value| value.to_str().ok())
         {
-            Ok(base_content) => {
-                // Enforce max_diff_size: if either side exceeds threshold, send full
-                if base_content.len() > config.max_diff_size
-                    || current_content.len() > config.max_diff_size
-                {
-                    BpxResponse::full(current_version.clone(), current_content.clone())
-                        .with_session(session_id.clone())
-                } else {
-                    // Compute diff between base and current content
-                    match diff_engine.compute_diff(&base_content, &current_content) {
-                        Ok(diff_data) => {
-                            if diff_engine
-                                .is_diff_worthwhile(current_content.len(), diff_data.len())
-                            {
-                                // Negotiated format is binary-delta for now
-                                BpxResponse::diff(
-                                    current_version.clone(),
-                                    DiffFormat::BinaryDelta,
-                                    diff_data,
-                                )
-                                .with_session(session_id.clone())
-                            } else {
-                                BpxResponse::full(current_version.clone(), current_content.clone())
-                                    .with_session(session_id.clone())
-                            }
+            bpx_request
+                .base_versions
+                .push(Version::new(decode_value(last).into_owned()));
+        }
+        return handle_event_stream(
+            bpx_request,
+            config,
+            state_mgr,
+            diff_engine,
+            resource_store,
+            access,
+            load.clone(),
+        )
+        .await;
+    }
+    let reason = if req.method() == Method::HEAD {
+        // No body to stream
+        None
+    } else if bpx_request.base_versions.is_empty() {
+        Some(FallbackReason::NoBase)
+    } else if negotiate_format(
+        &bpx_request.accepted_formats,
+        &enabled_formats(config, COLLECTION_FORMATS),
+    )
+    .is_none()
+    {
+        // Every format this server produces, whatever the resource
+        Some(FallbackReason::FormatNotAccepted)
+    } else {
+        None
+    };
+    let Some(reason) = reason.filter(|_| !config.rfc3229_mode) else {
+        let response = handle_bpx_request_guarded(
+            req,
+            config,
+            state_mgr,
+            diff_engine,
+            resource_store,
+            Guards { authorizer, load },
+        )
+        .await?;
+        return Ok(response.map(buffered_body));
+    };
+
+    let _admitted = load::admit(load.as_deref())?;
+    let access = Access::new(authorizer, &req, &bpx_request);
+    Access::check(access.as_ref(), &bpx_request.path).await?;
+    let keeps_history = config.keeps_history(&request_path(&req, config));
+    let path = bpx_request.path;
+    let session = open_session(
+        state_mgr.as_ref(),
+        bpx_request.tenant.as_ref(),
+        bpx_request.session_id,
+    )
+    .await;
+    let stored_version = if session.is_resumed() {
+        state_mgr.get_version(session.id(), &path).await
+    } else {
+        None
+    };
+    let (content, version) = resource_store.get_resource_stream(&path).await?;
+    let mut response = BpxResponse::full(version.clone(), Bytes::new())
+        .with_fallback_reason(reason)
+        .with_session_status(session.clone());
+    if let Some(content_type) = resource_store.get_content_type(&path).await {
+        response = response.with_content_type(content_type);
+    }
+    let decision = DiffDecision::new(&path, &bpx_request.base_versions, &response, None);
+    let (mut parts, _) = build_http_response_with_original_size(response, 0, config)?.into_parts();
+    parts.headers.remove(BpxHeaders::ORIGINAL_SIZE);
+    parts.extensions.insert(decision);
+
+    // Record the version once the client has it all, as a buffered exchange would
+    let storage = config.version_storage;
+    let record = move |content: Bytes| {
+        tokio::spawn(async move {
+            let stored =
+                keep_version(resource_store.as_ref(), storage, &path, &version, content).await;
+            if stored.is_ok() {
+                state_mgr
+                    .compare_and_set_version(session.id(), &path, stored_version.as_ref(), version)
+                    .await;
+            }
+        });
+    };
+    let body = RecordingStream {
+        content,
+        recorded: keeps_history.then(BytesMut::new),
+        limit: config.max_diff_size,
+        record: Some(Box::new(record)),
+    };
+    Ok(Response::from_parts(
+        parts,
+        StreamBody::new(body).boxed_unsync(),
+    ))
+}
+
+/// Whether the client asked for a Server-Sent Events stream
+fn accepts_event_stream<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| range.split(';').next().map(str::trim) == Some(EVENT_STREAM_MEDIA_TYPE))
+}
+
+/// Answer with a Server-Sent Events stream carrying one event per change
+///
+/// Each event is diffed against the version the previous one carried, which
+/// the session records in the [`StateManager`] as for a `GET`. The first
+/// event brings the client up to date from its `X-Base-Version` (or the
+/// `Last-Event-ID` a reconnecting `EventSource` sends) and is skipped if it
+/// already holds the current version. See [`encode_event`] for the format.
+async fn handle_event_stream<R>(
+    bpx_request: BpxRequest,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+    access: Option<Access>,
+    load: Option<Arc<LoadShedder>>,
+) -> Result<Response<ResourceBody>, BpxError>
+where
+    R: ResourceStore + 'static,
+{
+    let path = bpx_request.path.clone();
+    Access::check(access.as_ref(), &path).await?;
+    // Subscribe first so no change between the first event and the next is missed
+    let Some(mut changes) = resource_store.watch(&path) else {
+        return Err(BpxError::InvalidRequest {
+            reason: format!(
+                "{} can't be streamed: its store doesn't announce changes",
+                path
+            ),
+        });
+    };
+    let session = open_session(
+        state_mgr.as_ref(),
+        bpx_request.tenant.as_ref(),
+        bpx_request.session_id,
+    )
+    .await;
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, EVENT_STREAM_MEDIA_TYPE)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(BpxHeaders::SESSION, session_header(session.id()))
+        .header(BpxHeaders::SESSION_STATUS, session.as_str());
+    response = with_session_cookies(response, config, session.id());
+
+    let (events, received) = tokio::sync::mpsc::channel(EVENT_STREAM_BUFFER);
+    let config = config.clone();
+    let mut base_versions = bpx_request.base_versions;
+    let accepted_formats = bpx_request.accepted_formats;
+    let tenant = bpx_request.tenant;
+    tokio::spawn(async move {
+        let mut session = session;
+        let mut keep_alive = tokio::time::interval(EVENT_STREAM_KEEP_ALIVE);
+        keep_alive.reset();
+        loop {
+            let exchange = Exchange {
+                config: &config,
+                state_mgr: state_mgr.as_ref(),
+                diff_engine: diff_engine.as_ref(),
+                resource_store: resource_store.as_ref(),
+                session: Some(&session),
+                tenant: tenant.as_ref(),
+                accepted_formats: &accepted_formats,
+                access: access.as_ref(),
+                load: load.as_deref(),
+                slow_diffs: Mutex::default(),
+                diff_time: Mutex::default(),
+                // Quotas are checked as streams are opened
+                full_only: false,
+            };
+            let resolved = exchange.resolve(&path, &base_versions).await;
+            // Later events build on what this connection recorded
+            session = SessionStatus::Resumed(session.into_id());
+            match resolved {
+                Ok((response, _))
+                    if response.fallback_reason == Some(FallbackReason::Unchanged) => {}
+                Ok((response, _)) => {
+                    base_versions = vec![response.version.clone()];
+                    if events.send(encode_event(&response)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    let event = format!("event: error\ndata: {{\"code\":\"{}\"}}\n\n", e.code());
+                    let _ = events.send(Bytes::from(event)).await;
+                    return;
+                }
+            }
+
+            // Wait for the next change; keep idle connections open through proxies
+            loop {
+                tokio::select! {
+                    // A client gone before a change must not record its version
+                    biased;
+                    () = events.closed() => return,
+                    version = std::future::poll_fn(|cx| changes.as_mut().poll_next(cx)) => {
+                        if version.is_none() {
+                            return;
                         }
-                        Err(e) => {
-                            eprintln!("Diff computation failed: {}", e);
-                            BpxResponse::full(current_version.clone(), current_content.clone())
-                                .with_session(session_id.clone())
+                        break;
+                    }
+                    _ = keep_alive.tick() => {
+                        if events.send(Bytes::from_static(b": keep-alive\n\n")).await.is_err() {
+                            return;
                         }
                     }
                 }
             }
-            Err(_) => BpxResponse::full(current_version.clone(), current_content.clone())
-                .with_session(session_id.clone()),
         }
-    } else {
-        // Send full content
-        BpxResponse::full(current_version.clone(), current_content.clone())
-            .with_session(session_id.clone())
+    });
+
+    let body = EventStream { events: received };
+    response
+        .body(StreamBody::new(body).boxed_unsync())
+        .map_err(|e| BpxError::InvalidRequest {
+            reason: format!("Failed to build event stream response: {}", e),
+        })
+}
+
+/// Encode a response as one Server-Sent Event
+///
+/// The event ID is the version, so a reconnecting `EventSource` names it as
+/// its base. The event type is the diff type (`full` for full content) and
+/// the data a JSON object:
+/// `{"version":..,"delta_base":..,"content_digest":..,"fallback_reason":..,"data":"<base64>"}`,
+/// where `delta_base` is only present for diffs, `content_digest` for diffs
+/// to versions that aren't content hashes (see
+/// [`BpxHeaders::CONTENT_DIGEST`]) and `fallback_reason` for full content
+/// sent for a reason. A stream that can't go on ends with
+/// an `error` event whose data is `{"code":..}` (see [`BpxError::code`]).
+fn encode_event(response: &BpxResponse) -> Bytes {
+    let diff_type = match &response.body {
+        ResponseBody::Full(_) => "full",
+        ResponseBody::Diff { format, .. } => format.as_str(),
     };
+    let version = escape_json(&response.version.to_string());
+    let mut data = format!("{{\"version\":\"{}\"", version);
+    if let Some(base) = &response.delta_base {
+        data.push_str(&format!(
+            ",\"delta_base\":\"{}\"",
+            escape_json(&base.to_string())
+        ));
+    }
+    if let Some(digest) = &response.content_digest {
+        data.push_str(&format!(
+            ",\"content_digest\":\"{}\"",
+            escape_json(&digest.to_string())
+        ));
+    }
+    if let Some(reason) = response.fallback_reason {
+        data.push_str(&format!(",\"fallback_reason\":\"{}\"", reason.as_str()));
+    }
+    data.push_str(&format!(
+        ",\"data\":\"{}\"}}",
+        BASE64_STANDARD.encode(response.body.as_bytes())
+    ));
+    // IDs end at a line break and come back in `Last-Event-ID`, so they're
+    // encoded as the version header is
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        version_header(&response.version),
+        diff_type,
+        data
+    ))
+}
 
-    // Update stored version for future requests (store both in state manager and resource store)
-    state_mgr
-        .set_version(&session_id, &bpx_request.path, current_version.clone())
-        .await;
+/// Events produced for a Server-Sent Events response
+struct EventStream {
+    events: tokio::sync::mpsc::Receiver<Bytes>,
+}
 
-    // Store current content version in resource store for future diff operations
-    resource_store.store_version(
-        bpx_request.path.clone(),
-        current_version.clone(),
-        current_content.clone(),
-    );
+impl Stream for EventStream {
+    type Item = Result<Frame<Bytes>, BpxError>;
 
-    Ok(build_http_response_with_original_size(
-        response,
-        current_content.len(),
-    ))
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events
+            .poll_recv(cx)
+            .map(|event| event.map(|event| Ok(Frame::data(event))))
+    }
 }
 
-/// Parse BPX request from HTTP headers
-fn parse_bpx_request<B>(req: &Request<B>) -> Result<BpxRequest, BpxError> {
-    let path = ResourcePath::new(req.uri().path().to_string());
-    let mut bpx_request = BpxRequest::new(path);
+/// Whether `req` is a `POST` carrying BPX parameters in its body (see
+/// [`crate::protocol::params`])
+pub fn has_body_params<B>(req: &Request<B>) -> bool {
+    req.method() == Method::POST
+        && req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(PARAMS_MEDIA_TYPE))
+}
+
+/// Turn a `POST` carrying BPX parameters in its body into the `GET` they
+/// stand for, to answer with [`handle_bpx_request`]
+///
+/// Parameters in the body replace headers of the same meaning.
+pub async fn body_params_request<B>(req: Request<B>) -> Result<Request<Empty<Bytes>>, BpxError>
+where
+    B: http_body::Body,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let (mut parts, body) = req.into_parts();
+    let body = Limited::new(body, MAX_PARAMS_REQUEST_SIZE)
+        .collect()
+        .await
+        .map_err(|e| BpxError::InvalidRequest {
+            reason: format!("Failed to read parameters: {}", e),
+        })?
+        .to_bytes();
+    BodyParams::decode(&body)
+        .and_then(|params| params.apply(&mut parts.headers))
+        .map_err(|e| BpxError::InvalidRequest {
+            reason: e.to_string(),
+        })?;
+    parts.method = Method::GET;
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Request::from_parts(parts, Empty::new()))
+}
+
+/// [`ResourceBody`] of content already in memory
+pub(crate) fn buffered_body(content: Bytes) -> ResourceBody {
+    Full::new(content).map_err(|e| match e {}).boxed_unsync()
+}
+
+/// Streams a resource's content, keeping a copy of up to `limit` bytes to
+/// hand to `record` at the end
+struct RecordingStream {
+    content: ResourceStream,
+    recorded: Option<BytesMut>,
+    limit: usize,
+    record: Option<Box<dyn FnOnce(Bytes) + Send>>,
+}
+
+impl Stream for RecordingStream {
+    type Item = Result<Frame<Bytes>, BpxError>;
 
-    // Parse session header
-    if let Some(session_header) = req.headers().get(BpxHeaders::SESSION) {
-        if let Ok(session_str) = session_header.to_str() {
-            bpx_request = bpx_request.with_session(SessionId::new(session_str.to_string()));
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.content.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let limit = self.limit;
+                if let Some(recorded) = &mut self.recorded {
+                    if recorded.len() + chunk.len() <= limit {
+                        recorded.extend_from_slice(&chunk);
+                    } else {
+                        self.recorded = None;
+                    }
+                }
+                Poll::Ready(Some(Ok(Frame::data(chunk))))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                self.recorded = None;
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) => {
+                if let (Some(recorded), Some(record)) = (self.recorded.take(), self.record.take()) {
+                    record(recorded.freeze());
+                }
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
         }
     }
+}
 
-    // Parse base version header
-    if let Some(version_header) = req.headers().get(BpxHeaders::BASE_VERSION) {
-        if let Ok(version_str) = version_header.to_str() {
-            bpx_request = bpx_request.with_base_version(Version::new(version_str.to_string()));
-        }
+/// Handle a batch exchange carrying base versions for many resources
+///
+/// The request body is a [`BatchRequest`] envelope; session and `Accept-Diff`
+/// come from the usual headers and apply to every entry. Resources that cannot
+/// be served are reported per entry instead of failing the whole batch.
+pub async fn handle_batch_request<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+) -> Result<Response<Bytes>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    R: ResourceStore + 'static,
+{
+    handle_batch_request_guarded(
+        req,
+        config,
+        state_mgr,
+        diff_engine,
+        resource_store,
+        Guards::default(),
+    )
+    .await
+}
+
+/// [`handle_batch_request`] behind `guards`
+pub(crate) async fn handle_batch_request_guarded<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+    guards: Guards,
+) -> Result<Response<Bytes>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    R: ResourceStore + 'static,
+{
+    let Guards { authorizer, load } = guards;
+    if req.method() != Method::POST {
+        return Err(BpxError::MethodNotAllowed {
+            method: req.method().clone(),
+            path: request_path(&req, config),
+            allowed: vec![Method::POST],
+        });
+    }
+    let _admitted = load::admit(load.as_deref())?;
+    let headers = parse_bpx_request(&req, config)?;
+    let (req_context, body) = req.into_parts();
+    let req_context = Request::from_parts(req_context, ());
+    let body = Limited::new(body, MAX_BATCH_REQUEST_SIZE)
+        .collect()
+        .await
+        .map_err(|e| BpxError::InvalidRequest {
+            reason: format!("Failed to read batch body: {}", e),
+        })?
+        .to_bytes();
+    let batch = BatchRequest::decode(&body).map_err(|e| BpxError::InvalidRequest {
+        reason: e.to_string(),
+    })?;
+    // Every entry may cost a diff, so one request can't ask for too many
+    if batch.entries.len() > config.max_batch_entries {
+        return Err(BpxError::BatchTooLarge {
+            entries: batch.entries.len(),
+            max_entries: config.max_batch_entries,
+        });
     }
 
-    // Parse accepted diff formats
-    if let Some(accept_header) = req.headers().get(BpxHeaders::ACCEPT_DIFF) {
-        if let Ok(formats_str) = accept_header.to_str() {
-            let formats: Vec<DiffFormat> = formats_str
-                .split(',')
-                .filter_map(|s| DiffFormat::from_str(s.trim()))
-                .collect();
-            if !formats.is_empty() {
-                bpx_request = bpx_request.with_formats(formats);
+    let session = open_session(
+        state_mgr.as_ref(),
+        headers.tenant.as_ref(),
+        headers.session_id.clone(),
+    )
+    .await;
+
+    let access = Access::new(authorizer, &req_context, &headers);
+    let exchange = Exchange {
+        config,
+        state_mgr: state_mgr.as_ref(),
+        diff_engine: diff_engine.as_ref(),
+        resource_store: resource_store.as_ref(),
+        session: Some(&session),
+        tenant: headers.tenant.as_ref(),
+        accepted_formats: &headers.accepted_formats,
+        access: access.as_ref(),
+        load: load.as_deref(),
+        slow_diffs: Mutex::default(),
+        diff_time: Mutex::default(),
+        full_only: req_context.extensions().get::<FullOnly>().is_some(),
+    };
+
+    // What we last sent this session, read for every entry at once; entries
+    // are answered under the paths they were asked for
+    let paths: Vec<_> = batch
+        .entries
+        .iter()
+        .map(|e| {
+            let path = ResourcePath::canonical(&e.path.to_string(), &config.query);
+            match &headers.tenant {
+                Some(tenant) => tenant.scope(&path),
+                None => path,
             }
-        }
+        })
+        .collect();
+    let stored_versions = if session.is_resumed() {
+        state_mgr.get_versions(session.id(), &paths).await
+    } else {
+        vec![None; batch.entries.len()]
+    };
+    // One consistent view, so coupled resources updated together match
+    let currents = resource_store.get_versioned_resources(&paths).await;
+
+    let mut entries = Vec::with_capacity(batch.entries.len());
+    let mut decisions = Vec::with_capacity(batch.entries.len());
+    for (((entry, path), stored_version), current) in batch
+        .entries
+        .into_iter()
+        .zip(&paths)
+        .zip(stored_versions)
+        .zip(currents)
+    {
+        // Each entry is a read of its own path
+        let allowed = config.methods_for(&ResourcePath::canonical(
+            &entry.path.to_string(),
+            &config.query,
+        ));
+        let result = match current {
+            _ if !allowed.contains(&Method::GET) => Err(BpxError::MethodNotAllowed {
+                method: Method::GET,
+                path: entry.path.clone(),
+                allowed,
+            }),
+            Ok(current) => {
+                exchange
+                    .resolve_from(path, entry.base_version.as_slice(), stored_version, current)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        entries.push(match result {
+            Ok((response, original_size)) => {
+                decisions.push(DiffDecision::new(
+                    &entry.path,
+                    entry.base_version.as_slice(),
+                    &response,
+                    Some(original_size),
+                ));
+                BatchResponseEntry {
+                    path: entry.path,
+                    version: Some(response.version),
+                    content_digest: response.content_digest,
+                    original_size,
+                    body: Ok(response.body),
+                }
+            }
+            Err(e) => BatchResponseEntry {
+                path: entry.path,
+                version: None,
+                content_digest: None,
+                original_size: 0,
+                body: Err(e.to_string()),
+            },
+        });
     }
 
-    Ok(bpx_request)
+    let body = BatchResponse { entries }
+        .encode()
+        .map_err(|e| BpxError::DiffComputationFailed {
+            reason: e.to_string(),
+        })?;
+
+    let mut response = Response::builder()
+        .header(BpxHeaders::SESSION, session_header(session.id()))
+        .header(BpxHeaders::SESSION_STATUS, session.as_str())
+        .header(header::CONTENT_TYPE, BATCH_MEDIA_TYPE);
+    response = with_session_cookies(response, config, session.id());
+    let slow_diffs = exchange
+        .slow_diffs
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    let diff_time = exchange
+        .diff_time
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    // One decision per entry answered, counted as if each was asked alone
+    response = response.extension(decisions);
+    if !slow_diffs.is_empty() {
+        response = response.extension(slow_diffs);
+    }
+    if !diff_time.is_zero() {
+        response = response.extension(DiffTime(diff_time));
+    }
+
+    finish(response, body)
 }
 
-/// Build HTTP response from BPX response with original size info
-fn build_http_response_with_original_size(
-    bpx_response: BpxResponse,
-    original_size: usize,
-) -> Response<Bytes> {
-    let mut response = Response::builder().header(
-        BpxHeaders::RESOURCE_VERSION,
-        bpx_response.version.to_string(),
-    );
+/// Handle a `PUT` (full content) or `PATCH` (diff) update to a resource
+///
+/// The version the client's write is based on goes in `If-Match` or
+/// `X-Base-Version`. A PUT without one writes unconditionally; a PATCH needs
+/// one, since its diff (in the `X-Diff-Type` format, binary delta by
+/// default; `element-delta` for collections) only applies to that version.
+/// If the resource has moved on the write fails with `412 Precondition
+/// Failed` carrying the current version.
+///
+/// Success answers `204 No Content` with the new version, which is recorded
+/// so the writer's next GET can be answered with a diff against it, unless
+/// the path is under [`BpxConfig::no_history`].
+pub async fn handle_write_request<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+) -> Result<Response<Bytes>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    R: ResourceStore + 'static,
+{
+    handle_write_request_guarded(
+        req,
+        config,
+        state_mgr,
+        diff_engine,
+        resource_store,
+        Guards::default(),
+    )
+    .await
+}
 
-    if let Some(session_id) = &bpx_response.session_id {
-        response = response.header(BpxHeaders::SESSION, session_id.to_string());
-    }
+/// [`handle_write_request`] behind `guards`
+pub(crate) async fn handle_write_request_guarded<B, R>(
+    req: Request<B>,
+    config: &BpxConfig,
+    state_mgr: Arc<dyn StateManager>,
+    diff_engine: Arc<dyn DiffEngine>,
+    resource_store: Arc<R>,
+    guards: Guards,
+) -> Result<Response<Bytes>, BpxError>
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    R: ResourceStore + 'static,
+{
+    let Guards { authorizer, load } = guards;
+    check_method(&req, config, &[Method::PUT, Method::PATCH])?;
+    let is_patch = req.method() == Method::PATCH;
+    let _admitted = load::admit(load.as_deref())?;
+    let headers = parse_bpx_request(&req, config)?;
+    let access = Access::new(authorizer, &req, &headers);
+    Access::check(access.as_ref(), &headers.path).await?;
+    let keeps_history = config.keeps_history(&request_path(&req, config));
+    let expected = req
+        .headers()
+        .get(header::IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|etag| Version::new(decode_value(unquote_etag(etag)).into_owned()))
+        .or_else(|| headers.base_versions.first().cloned());
+    let format = match req.headers().get(BpxHeaders::DIFF_TYPE) {
+        Some(value) => {
+            let name = value.to_str().unwrap_or_default();
+            DiffFormat::from_str(name)
+                .filter(|f| COLLECTION_FORMATS.contains(f))
+                .ok_or_else(|| BpxError::InvalidDiffFormat {
+                    format: name.to_string(),
+                })?
+        }
+        None => DiffFormat::BinaryDelta,
+    };
+    let body = Limited::new(req.into_body(), MAX_WRITE_REQUEST_SIZE)
+        .collect()
+        .await
+        .map_err(|e| BpxError::InvalidRequest {
+            reason: format!("Failed to read request body: {}", e),
+        })?
+        .to_bytes();
 
-    match &bpx_response.body {
-        ResponseBody::Full(content) => {
-            response = response
-                .header(BpxHeaders::DIFF_TYPE, "full")
-                .header(BpxHeaders::ORIGINAL_SIZE, content.len().to_string());
+    let path = headers.path;
+    let content = if is_patch {
+        let Some(base) = &expected else {
+            return Err(BpxError::InvalidRequest {
+                reason: "PATCH needs its base version in If-Match or X-Base-Version".to_string(),
+            });
+        };
+        if !config.format_enabled(format) {
+            return Err(BpxError::InvalidDiffFormat {
+                format: format.as_str().to_string(),
+            });
         }
-        ResponseBody::Diff { format, data } => {
-            response = response
-                .header(BpxHeaders::DIFF_TYPE, format.as_str())
-                .header(BpxHeaders::ORIGINAL_SIZE, original_size.to_string())
-                .header(BpxHeaders::DIFF_SIZE, data.len().to_string());
+        let (current, version) = resource_store.get_versioned_resource(&path).await?;
+        if version != *base {
+            return Err(BpxError::PreconditionFailed {
+                path,
+                current: Some(version),
+            });
+        }
+        engine_for(format, diff_engine.as_ref())
+            .apply_diff_limited(&current, &body, &config.patch_limits())
+            .map_err(|e| match e {
+                DiffError::LimitExceeded(reason) => BpxError::PatchTooLarge { reason },
+                e @ DiffError::OutputLimitExceeded(_) => BpxError::PatchTooLarge {
+                    reason: e.to_string(),
+                },
+                e => BpxError::InvalidRequest {
+                    reason: format!("{} diff does not apply: {}", format.as_str(), e),
+                },
+            })?
+    } else {
+        body
+    };
+
+    let version = resource_store
+        .put_resource(&path, content.clone(), expected.as_ref())
+        .await?;
+    if keeps_history {
+        resource_store
+            .store_version(path.clone(), version.clone(), content)
+            .await?;
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(BpxHeaders::RESOURCE_VERSION, version_header(&version))
+        .header(header::ETAG, etag(&version));
+    if let Some(id) = headers.session_id {
+        // The writer holds the new content; let its next GET diff against it
+        let session = open_session(state_mgr.as_ref(), headers.tenant.as_ref(), Some(id)).await;
+        if session.is_resumed() && keeps_history {
+            state_mgr.set_version(session.id(), &path, version).await;
         }
+        response = response
+            .header(BpxHeaders::SESSION, session_header(session.id()))
+            .header(BpxHeaders::SESSION_STATUS, session.as_str());
     }
 
-    if let Some(cache_ttl) = bpx_response.cache_ttl {
-        response = response.header(BpxHeaders::CACHE_TTL, cache_ttl.as_secs().to_string());
+    finish(response, Bytes::new())
+}
+
+/// What [`BpxServer`](crate::BpxServer) checks before the free handlers
+/// serve a request
+#[derive(Clone, Default)]
+pub(crate) struct Guards {
+    /// Authorizes each resource served or written
+    pub(crate) authorizer: Option<Arc<dyn Authorizer>>,
+    /// Sheds requests and diffs past the load limits
+    pub(crate) load: Option<Arc<LoadShedder>>,
+}
+
+/// An [`Authorizer`] and the request it judges
+struct Access {
+    authorizer: Arc<dyn Authorizer>,
+    context: RequestContext,
+}
+
+impl Access {
+    /// Access control for `req`, if an authorizer is configured
+    fn new<B>(
+        authorizer: Option<Arc<dyn Authorizer>>,
+        req: &Request<B>,
+        bpx_request: &BpxRequest,
+    ) -> Option<Self> {
+        authorizer.map(|authorizer| Self {
+            authorizer,
+            context: RequestContext::from_request(req, bpx_request.session_id.clone()),
+        })
+    }
+
+    /// Fail with [`BpxError::Forbidden`] unless the request may access `path`
+    async fn check(access: Option<&Self>, path: &ResourcePath) -> Result<(), BpxError> {
+        let Some(access) = access else {
+            return Ok(());
+        };
+        match access.authorizer.authorize(&access.context, path).await {
+            Decision::Allow => Ok(()),
+            Decision::Deny => Err(BpxError::Forbidden { path: path.clone() }),
+        }
+    }
+}
+
+/// Server components and negotiated parameters shared by every resource in one exchange
+struct Exchange<'a, R> {
+    config: &'a BpxConfig,
+    state_mgr: &'a dyn StateManager,
+    diff_engine: &'a dyn DiffEngine,
+    resource_store: &'a R,
+    session: Option<&'a SessionStatus>,
+    /// Tenant paths are scoped to, if the request names one
+    tenant: Option<&'a TenantId>,
+    /// Formats the client accepts, most preferred first
+    accepted_formats: &'a [DiffFormat],
+    /// Access control every resource is checked against
+    access: Option<&'a Access>,
+    /// Limits on concurrent diffs
+    load: Option<&'a LoadShedder>,
+    /// Diffs slower than `config.slow_diff_threshold`
+    slow_diffs: Mutex<Vec<SlowDiff>>,
+    /// Time spent computing diffs, counted against the session's quota
+    diff_time: Mutex<Duration>,
+    /// Send full bodies without computing diffs, as for a session past its
+    /// quota
+    full_only: bool,
+}
+
+impl<R: ResourceStore> Exchange<'_, R> {
+    /// Decide between diff and full body for one resource and record the
+    /// version the client will hold afterwards
+    ///
+    /// Returns the response together with the full size of the current content.
+    async fn resolve(
+        &self,
+        path: &ResourcePath,
+        base_versions: &[Version],
+    ) -> Result<(BpxResponse, usize), BpxError> {
+        // What we last sent this session; replaced sessions hold nothing we know of
+        let stored_version = match self.session {
+            Some(session) if session.is_resumed() => {
+                self.state_mgr.get_version(session.id(), path).await
+            }
+            _ => None,
+        };
+        let current = self.resource_store.get_versioned_resource(path).await?;
+        self.resolve_from(path, base_versions, stored_version, current)
+            .await
+    }
+
+    /// [`Self::resolve`] with the version last sent this session and the
+    /// current content and version already read
+    async fn resolve_from(
+        &self,
+        path: &ResourcePath,
+        base_versions: &[Version],
+        stored_version: Option<Version>,
+        (current_content, current_version): (Bytes, Version),
+    ) -> Result<(BpxResponse, usize), BpxError> {
+        // Before any diff or session state could reveal the content
+        Access::check(self.access, path).await?;
+        let state_mgr = self.state_mgr;
+        let resource_store = self.resource_store;
+
+        let content_type = resource_store.get_content_type(path).await;
+        let format = self.negotiate(content_type.as_deref());
+
+        // Bases we may diff against; only trusted if the client's state agrees with ours
+        let keeps_history = self.keeps_history(path);
+        let mut response = match self.diff_candidates(
+            path,
+            base_versions,
+            &current_version,
+            stored_version.as_ref(),
+            format,
+        ) {
+            Ok((format, candidates)) => match self
+                .smallest_diff(path, &candidates, &current_content, format)
+                .await
+            {
+                Ok((base, diff_data)) => {
+                    let response = BpxResponse::diff(current_version.clone(), format, diff_data)
+                        .with_delta_base(base.clone());
+                    // Clients verify patches by hash; tell them the hash when
+                    // the application's version isn't one
+                    let digest = resource_store
+                        .content_digest(path, &current_version, &current_content)
+                        .await;
+                    if digest == current_version {
+                        response
+                    } else {
+                        response.with_content_digest(digest)
+                    }
+                }
+                Err(reason) => BpxResponse::full(current_version.clone(), current_content.clone())
+                    .with_fallback_reason(reason),
+            },
+            Err(reason) => BpxResponse::full(current_version.clone(), current_content.clone())
+                .with_fallback_reason(reason),
+        };
+
+        if let Some(content_type) = content_type {
+            response = response.with_content_type(content_type);
+        }
+
+        // Keep the current content for future diff operations
+        if keeps_history {
+            keep_version(
+                resource_store,
+                self.config.version_storage,
+                path,
+                &current_version,
+                current_content.clone(),
+            )
+            .await?;
+        }
+
+        if let Some(session) = self.session {
+            response = response.with_session_status(session.clone());
+        }
+        if let Some(session) = self.session.filter(|_| keeps_history) {
+            // Update stored version for future requests, unless a concurrent
+            // request for this session already replaced the one we read; the
+            // client's next base then mismatches and is answered in full
+            state_mgr
+                .compare_and_set_version(
+                    session.id(),
+                    path,
+                    stored_version.as_ref(),
+                    current_version,
+                )
+                .await;
+        }
+
+        Ok((response, current_content.len()))
+    }
+
+    /// Pick the client's most preferred format among those the server
+    /// produces for a resource of this media type
+    fn negotiate(&self, content_type: Option<&str>) -> Option<DiffFormat> {
+        let supported = match content_type {
+            Some(COLLECTION_MEDIA_TYPE) => COLLECTION_FORMATS,
+            _ => SUPPORTED_FORMATS,
+        };
+        negotiate_format(
+            self.accepted_formats,
+            &enabled_formats(self.config, supported),
+        )
+    }
+
+    /// Bases we may diff against in the negotiated format; only trusted if
+    /// the client's state agrees with ours
+    fn diff_candidates<'v>(
+        &self,
+        path: &ResourcePath,
+        base_versions: &'v [Version],
+        current_version: &Version,
+        stored_version: Option<&Version>,
+        format: Option<DiffFormat>,
+    ) -> Result<(DiffFormat, Vec<&'v Version>), FallbackReason> {
+        if base_versions.is_empty() {
+            return Err(FallbackReason::NoBase);
+        }
+        let Some(format) = format else {
+            return Err(FallbackReason::FormatNotAccepted);
+        };
+        if base_versions.contains(current_version) {
+            return Err(FallbackReason::Unchanged);
+        }
+        if !self.keeps_history(path) {
+            return Err(FallbackReason::NoHistory);
+        }
+
+        if let Some(session) = self.session {
+            // A replaced session carries bases recorded under one we no longer know
+            if !session.is_resumed() {
+                return Err(FallbackReason::NoSessionState);
+            }
+            // The version we last sent must be among those the client says it holds
+            match stored_version {
+                None => return Err(FallbackReason::NoSessionState),
+                Some(stored_version) if !base_versions.contains(stored_version) => {
+                    return Err(FallbackReason::VersionMismatch);
+                }
+                Some(_) => {}
+            }
+        }
+
+        // RFC 3229 (no session): a base is usable if the resource store still has it
+        Ok((format, base_versions.iter().collect()))
+    }
+
+    /// Whether versions of `path` may be kept, judged by the path as the
+    /// client asked for it rather than as scoped to its tenant
+    fn keeps_history(&self, path: &ResourcePath) -> bool {
+        let scope = self.tenant.map(|tenant| format!("/{}", tenant));
+        match scope
+            .as_deref()
+            .and_then(|scope| path.0.strip_prefix(scope))
+        {
+            Some(unscoped) => self
+                .config
+                .keeps_history(&ResourcePath::new(unscoped.to_string())),
+            None => self.config.keeps_history(path),
+        }
+    }
+
+    /// Count the time the diff of `base` and `current` started at `started`
+    /// took, and log and keep it if that was longer than
+    /// `config.slow_diff_threshold`
+    fn watch(
+        &self,
+        path: &ResourcePath,
+        format: DiffFormat,
+        base: &[u8],
+        current: &[u8],
+        started: Instant,
+    ) {
+        let elapsed = started.elapsed();
+        *self.diff_time.lock().unwrap_or_else(|e| e.into_inner()) += elapsed;
+        if self
+            .config
+            .slow_diff_threshold
+            .is_none_or(|threshold| elapsed <= threshold)
+        {
+            return;
+        }
+        let slow = SlowDiff {
+            path: path.clone(),
+            format,
+            base_size: base.len(),
+            current_size: current.len(),
+            elapsed,
+        };
+        eprintln!("{}", slow);
+        let mut slow_diffs = self.slow_diffs.lock().unwrap_or_else(|e| e.into_inner());
+        slow_diffs.push(slow);
+    }
+
+    /// Compute a diff against each candidate base still held by the store and
+    /// keep the smallest, if it is worth sending at all
+    ///
+    /// When no diff qualifies, the reason from the furthest pipeline stage any
+    /// candidate reached is returned.
+    async fn smallest_diff<'v>(
+        &self,
+        path: &ResourcePath,
+        candidates: &[&'v Version],
+        current_content: &Bytes,
+        format: DiffFormat,
+    ) -> Result<(&'v Version, Bytes), FallbackReason> {
+        let diff_engine = engine_for(format, self.diff_engine);
+        let mut best: Option<(&Version, Bytes)> = None;
+        let mut reason = FallbackReason::BaseUnavailable;
+
+        for base_version in candidates.iter().take(MAX_BASE_VERSIONS) {
+            let Ok(base_content) = self
+                .resource_store
+                .get_resource_version(path, base_version)
+                .await
+            else {
+                continue;
+            };
+
+            // Enforce max_diff_size: if either side exceeds threshold, send full
+            if base_content.len() > self.config.max_diff_size
+                || current_content.len() > self.config.max_diff_size
+            {
+                reason = reason.max(FallbackReason::TooLarge);
+                continue;
+            }
+
+            // A session past its quota gets no more diff computation
+            if self.full_only {
+                reason = reason.max(FallbackReason::QuotaExceeded);
+                continue;
+            }
+
+            // Past the concurrency limit the full body is cheaper than waiting
+            let slot = match self.load {
+                Some(load) => match load.diff() {
+                    Some(slot) => Some(slot),
+                    None => {
+                        reason = reason.max(FallbackReason::Overloaded);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            // Compute diff between base and current content
+            let started = Instant::now();
+            let diff = diff_engine
+                .compute_diff_async(&base_content, current_content)
+                .await;
+            drop(slot);
+            self.watch(path, format, &base_content, current_content, started);
+            match diff {
+                Ok(diff_data) => {
+                    if best.as_ref().is_none_or(|(_, d)| diff_data.len() < d.len()) {
+                        best = Some((base_version, diff_data));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Diff computation failed: {}", e);
+                    reason = reason.max(FallbackReason::EngineError);
+                }
+            }
+        }
+
+        match best {
+            Some((base, diff_data))
+                if self
+                    .config
+                    .diff_worthwhile(current_content.len(), diff_data.len()) =>
+            {
+                Ok((base, diff_data))
+            }
+            Some(_) => Err(FallbackReason::NotWorthwhile),
+            None => Err(reason),
+        }
+    }
+}
+
+/// What the server believes `session` holds for `path`, and what its next
+/// request would get should the client present that version as its base
+///
+/// Reads session state without resuming the session, and computes the diff
+/// the next request would, without sending or recording it.
+pub(crate) async fn debug_session<R: ResourceStore>(
+    config: &BpxConfig,
+    state_mgr: &dyn StateManager,
+    diff_engine: &dyn DiffEngine,
+    resource_store: &R,
+    session: SessionId,
+    path: &ResourcePath,
+) -> Result<SessionDebug, BpxError> {
+    let stored_version = state_mgr.get_version(&session, path).await;
+    let (current_content, current_version) = resource_store.get_versioned_resource(path).await?;
+    let base_retained = match &stored_version {
+        Some(version) => resource_store.has_version(path, version).await,
+        None => false,
+    };
+
+    let status = SessionStatus::Resumed(session);
+    let exchange = Exchange {
+        config,
+        state_mgr,
+        diff_engine,
+        resource_store,
+        session: Some(&status),
+        tenant: None,
+        accepted_formats: COLLECTION_FORMATS,
+        access: None,
+        load: None,
+        slow_diffs: Mutex::default(),
+        diff_time: Mutex::default(),
+        full_only: false,
+    };
+    let content_type = resource_store.get_content_type(path).await;
+    let format = exchange.negotiate(content_type.as_deref());
+    let base_versions: Vec<Version> = stored_version.iter().cloned().collect();
+    let candidates = match &stored_version {
+        Some(stored) => {
+            exchange.diff_candidates(path, &base_versions, &current_version, Some(stored), format)
+        }
+        // A client presenting any base would find no record of it
+        None => Err(FallbackReason::NoSessionState),
+    };
+    let response =
+        match candidates {
+            Ok((format, candidates)) => match exchange
+                .smallest_diff(path, &candidates, &current_content, format)
+                .await
+            {
+                Ok((base, diff_data)) => BpxResponse::diff(current_version, format, diff_data)
+                    .with_delta_base(base.clone()),
+                Err(reason) => BpxResponse::full(current_version, current_content.clone())
+                    .with_fallback_reason(reason),
+            },
+            Err(reason) => BpxResponse::full(current_version, current_content.clone())
+                .with_fallback_reason(reason),
+        };
+
+    Ok(SessionDebug {
+        session: status.id().clone(),
+        stored_version,
+        base_retained,
+        next: DiffDecision::new(path, &base_versions, &response, Some(current_content.len())),
+    })
+}
+
+/// Keep `content` as `version` of `path` for later diffs if `storage`
+/// allows and the store doesn't hold it already
+async fn keep_version<R: ResourceStore>(
+    resource_store: &R,
+    storage: VersionStorage,
+    path: &ResourcePath,
+    version: &Version,
+    content: Bytes,
+) -> Result<(), BpxError> {
+    if !storage.sample() || resource_store.has_version(path, version).await {
+        return Ok(());
+    }
+    resource_store
+        .store_version(path.clone(), version.clone(), content)
+        .await
+}
+
+/// Those of `formats` that `config` doesn't disable
+fn enabled_formats(config: &BpxConfig, formats: &[DiffFormat]) -> Vec<DiffFormat> {
+    formats
+        .iter()
+        .copied()
+        .filter(|f| config.format_enabled(*f))
+        .collect()
+}
+
+/// Engine producing and applying diffs in `format`; the configured engine
+/// handles everything but element diffs
+fn engine_for(format: DiffFormat, diff_engine: &dyn DiffEngine) -> &dyn DiffEngine {
+    match format {
+        DiffFormat::ElementDelta => &ElementDiffEngine,
+        _ => diff_engine,
+    }
+}
+
+/// Resource a request is for: its path with the query parameters
+/// `config.query` keeps
+fn request_path<B>(req: &Request<B>, config: &BpxConfig) -> ResourcePath {
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map_or_else(|| req.uri().path(), |p| p.as_str());
+    ResourcePath::canonical(path_and_query, &config.query)
+}
+
+/// Fail with [`BpxError::MethodNotAllowed`] unless `req`'s method is one
+/// the handler `serves` and that `config` allows on the resource
+fn check_method<B>(
+    req: &Request<B>,
+    config: &BpxConfig,
+    serves: &[Method],
+) -> Result<(), BpxError> {
+    let path = request_path(req, config);
+    let allowed = config.methods_for(&path);
+    if serves.contains(req.method()) && allowed.contains(req.method()) {
+        return Ok(());
+    }
+    Err(BpxError::MethodNotAllowed {
+        method: req.method().clone(),
+        path,
+        allowed,
+    })
+}
+
+/// Request for `path`, scoped to the [`TenantId`] extension `req` carries
+fn tenant_request<B>(req: &Request<B>, path: ResourcePath) -> BpxRequest {
+    let tenant = req.extensions().get::<TenantId>().cloned();
+    let mut bpx_request = BpxRequest::new(match &tenant {
+        Some(tenant) => tenant.scope(&path),
+        None => path,
+    });
+    bpx_request.tenant = tenant;
+    bpx_request
+}
+
+/// Resume or create a session, within `tenant` if the request has one
+async fn open_session(
+    state_mgr: &dyn StateManager,
+    tenant: Option<&TenantId>,
+    id: Option<SessionId>,
+) -> SessionStatus {
+    match tenant {
+        Some(tenant) => state_mgr.get_or_create_tenant_session(tenant, id).await,
+        None => state_mgr.get_or_create_session(id).await,
+    }
+}
+
+/// Parse BPX request from HTTP headers
+///
+/// Fails with [`BpxError::InvalidHeader`] for an oversized or malformed
+/// session, base version or `Accept-Diff` value; unknown formats are
+/// skipped.
+pub fn parse_bpx_request<B>(req: &Request<B>, config: &BpxConfig) -> Result<BpxRequest, BpxError> {
+    let mut bpx_request = tenant_request(req, request_path(req, config));
+
+    if let Some(session) = request_session(req, config)? {
+        bpx_request = bpx_request.with_session(session);
+    }
+
+    // Parse base version header (one or more comma-separated versions)
+    if let Some(version_header) = req.headers().get(BpxHeaders::BASE_VERSION)
+        && let Ok(version_str) = version_header.to_str()
+    {
+        let versions = version_str
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .take(MAX_BASE_VERSIONS)
+            .map(|v| header_value(BpxHeaders::BASE_VERSION, v, MAX_VERSION_LEN).map(Version::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !versions.is_empty() {
+            bpx_request = bpx_request.with_base_versions(versions);
+        }
+    }
+
+    // Parse accepted diff formats
+    if let Some(accept_header) = req.headers().get(BpxHeaders::ACCEPT_DIFF)
+        && let Ok(formats_str) = accept_header.to_str()
+    {
+        // Even an empty list replaces the default: `q=0` refuses a format
+        let formats = parse_format_list(BpxHeaders::ACCEPT_DIFF, formats_str)?;
+        bpx_request = bpx_request.with_formats(formats);
+    }
+
+    Ok(bpx_request)
+}
+
+/// Parse an RFC 3229 delta-encoding request (`A-IM` + `If-None-Match`)
+fn parse_rfc3229_request<B>(req: &Request<B>, config: &BpxConfig) -> Result<BpxRequest, BpxError> {
+    // Without A-IM the client does not understand delta responses
+    let mut formats = Vec::new();
+    if let Some(a_im) = req.headers().get(DeltaHeaders::A_IM)
+        && let Ok(a_im_str) = a_im.to_str()
+    {
+        formats = parse_format_list(DeltaHeaders::A_IM, a_im_str)?;
+    }
+    let mut bpx_request = tenant_request(req, request_path(req, config)).with_formats(formats);
+
+    // The entity tags the client holds are its candidate bases
+    if let Some(if_none_match) = req.headers().get(header::IF_NONE_MATCH)
+        && let Ok(etags) = if_none_match.to_str()
+    {
+        let versions = etags
+            .split(',')
+            .map(unquote_etag)
+            .filter(|t| !t.is_empty())
+            .take(MAX_BASE_VERSIONS)
+            .map(|t| header_value("If-None-Match", t, MAX_VERSION_LEN).map(Version::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !versions.is_empty() {
+            bpx_request = bpx_request.with_base_versions(versions);
+        }
+    }
+
+    Ok(bpx_request)
+}
+
+/// Session `req` presents in its session header, falling back to the
+/// session cookie when enabled
+///
+/// Fails with [`BpxError::InvalidHeader`] for a session ID longer than
+/// [`MAX_SESSION_ID_LEN`] or holding control characters.
+pub(crate) fn request_session<B>(
+    req: &Request<B>,
+    config: &BpxConfig,
+) -> Result<Option<SessionId>, BpxError> {
+    let session = match req
+        .headers()
+        .get(BpxHeaders::SESSION)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(session) => session_value(BpxHeaders::SESSION, session)?,
+        None => match config
+            .session_cookie
+            .as_deref()
+            .and_then(|name| find_cookie(req, name))
+        {
+            Some(session) => session_value("Cookie", session)?,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(session))
+}
+
+/// Decode one value from `header`, refusing it if longer than `max_len`
+/// bytes
+fn header_value(header: &'static str, value: &str, max_len: usize) -> Result<String, BpxError> {
+    let invalid = |reason: String| BpxError::InvalidHeader { header, reason };
+    // An escape is three bytes, so a longer value can't decode short enough
+    if value.len() > 3 * max_len {
+        return Err(invalid(format!("value longer than {} bytes", max_len)));
+    }
+    let decoded = decode_value(value);
+    if decoded.len() > max_len {
+        return Err(invalid(format!("value longer than {} bytes", max_len)));
+    }
+    Ok(decoded.into_owned())
+}
+
+/// Decode a session ID from `header` as [`header_value`] does, also
+/// refusing control characters, which IDs this server issues never hold
+fn session_value(header: &'static str, value: &str) -> Result<SessionId, BpxError> {
+    let session = header_value(header, value, MAX_SESSION_ID_LEN)?;
+    if session.chars().any(char::is_control) {
+        return Err(BpxError::InvalidHeader {
+            header,
+            reason: "value holds control characters".to_string(),
+        });
+    }
+    Ok(SessionId::new(session))
+}
+
+/// Parse a list of formats from `header`, refusing lists of more than
+/// [`MAX_ACCEPT_DIFF_ENTRIES`] entries
+fn parse_format_list(header: &'static str, value: &str) -> Result<Vec<DiffFormat>, BpxError> {
+    if value.split(',').count() > MAX_ACCEPT_DIFF_ENTRIES {
+        return Err(BpxError::InvalidHeader {
+            header,
+            reason: format!("more than {} entries", MAX_ACCEPT_DIFF_ENTRIES),
+        });
+    }
+    Ok(parse_accept_diff(value))
+}
+
+/// Drop the session `req` presents, in its session header and the session
+/// cookie, so it is answered as if it had presented none
+pub(crate) fn forget_session<B>(req: &mut Request<B>, config: &BpxConfig) {
+    req.headers_mut().remove(BpxHeaders::SESSION);
+    let Some(name) = config.session_cookie.as_deref() else {
+        return;
+    };
+    let kept: Vec<HeaderValue> = req
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| {
+            let pairs: Vec<&str> = value
+                .to_str()
+                .ok()?
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| pair.split_once('=').is_none_or(|(key, _)| key != name))
+                .collect();
+            (!pairs.is_empty()).then(|| HeaderValue::from_str(&pairs.join("; ")).ok())?
+        })
+        .collect();
+    req.headers_mut().remove(header::COOKIE);
+    for value in kept {
+        req.headers_mut().append(header::COOKIE, value);
+    }
+}
+
+/// Session named by `response`'s session header
+pub(crate) fn response_session<B>(response: &Response<B>) -> Option<SessionId> {
+    response
+        .headers()
+        .get(BpxHeaders::SESSION)
+        .and_then(|value| value.to_str().ok())
+        .map(|session| SessionId::new(decode_value(session).into_owned()))
+}
+
+/// Look up a cookie value across all `Cookie` headers
+pub(crate) fn find_cookie<'r, B>(req: &'r Request<B>, name: &str) -> Option<&'r str> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, value)| *key == name && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+/// `Set-Cookie` value carrying the session ID, if cookie transport is enabled
+fn session_set_cookie(config: &BpxConfig, session_id: &SessionId) -> Option<String> {
+    config.session_cookie.as_ref().map(|name| {
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            name,
+            session_header(session_id),
+            config.session_retention().as_secs()
+        )
+    })
+}
+
+/// Add the session cookie and affinity key `config` asks for to a response
+/// naming `session_id`
+fn with_session_cookies(
+    mut response: hyper::http::response::Builder,
+    config: &BpxConfig,
+    session_id: &SessionId,
+) -> hyper::http::response::Builder {
+    if let Some(cookie) = session_set_cookie(config, session_id) {
+        response = response.header(header::SET_COOKIE, cookie);
+    }
+    let Some(affinity) = &config.affinity else {
+        return response;
+    };
+    let key = affinity_key(session_id);
+    if let Some(name) = &affinity.header
+        && let Ok(name) = header::HeaderName::try_from(name.as_str())
+    {
+        response = response.header(name, key.as_str());
+    }
+    if let Some(name) = &affinity.cookie {
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; SameSite=Lax",
+            name,
+            key,
+            config.session_retention().as_secs()
+        );
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response = response.header(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+/// Strip weak prefix and quotes from an entity tag
+fn unquote_etag(etag: &str) -> &str {
+    let etag = etag.trim();
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    etag.trim_matches('"')
+}
+
+/// Build RFC 3229 response: `226 IM Used` for deltas, `200 OK` for full bodies
+fn build_rfc3229_response(bpx_response: BpxResponse) -> Result<Response<Bytes>, BpxError> {
+    let mut response = Response::builder().header(header::ETAG, etag(&bpx_response.version));
+
+    if let ResponseBody::Diff { format, .. } = &bpx_response.body {
+        response = response
+            .status(DeltaHeaders::IM_USED)
+            .header(DeltaHeaders::IM, format.as_str());
+        if let Some(base) = &bpx_response.delta_base {
+            response = response.header(DeltaHeaders::DELTA_BASE, etag(base));
+        }
+    }
+
+    finish(response, bpx_response.body.as_bytes().clone())
+}
+
+/// Answer `HEAD` with the current version's metadata and no body
+///
+/// No diff is computed and nothing is recorded for the session, so clients
+/// can poll cheaply and only `GET` once `X-Resource-Version` changes.
+async fn build_head_response<R: ResourceStore>(
+    bpx_request: &BpxRequest,
+    config: &BpxConfig,
+    session: Option<SessionStatus>,
+    resource_store: &R,
+) -> Result<Response<Bytes>, BpxError> {
+    let (content, version) = resource_store
+        .get_versioned_resource(&bpx_request.path)
+        .await?;
+    if config.rfc3229_mode {
+        if bpx_request.base_versions.contains(&version) {
+            return build_not_modified_response(&version);
+        }
+        return build_rfc3229_response(BpxResponse::full(version, Bytes::new()));
+    }
+
+    let mut response = BpxResponse::full(version, content);
+    if let Some(content_type) = resource_store.get_content_type(&bpx_request.path).await {
+        response = response.with_content_type(content_type);
+    }
+    if let Some(session) = session {
+        response = response.with_session_status(session);
+    }
+    let (mut parts, _) = build_http_response_with_original_size(response, 0, config)?.into_parts();
+    // What a GET would carry depends on the diff it computes
+    parts.headers.remove(BpxHeaders::DIFF_TYPE);
+    parts.headers.remove(BpxHeaders::FALLBACK_REASON);
+    Ok(Response::from_parts(parts, Bytes::new()))
+}
+
+/// Build `304 Not Modified` for a client already holding the current version
+fn build_not_modified_response(version: &Version) -> Result<Response<Bytes>, BpxError> {
+    let response = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag(version));
+    finish(response, Bytes::new())
+}
+
+/// `version` as sent in `X-Resource-Version` and `X-BPX-Delta-Base`
+fn version_header(version: &Version) -> String {
+    encode_value(&version.to_string()).into_owned()
+}
+
+/// `version` as a strong entity tag
+fn etag(version: &Version) -> String {
+    format!("\"{}\"", encode_value(&version.to_string()))
+}
+
+/// `session_id` as sent in `X-BPX-Session` and the session cookie
+fn session_header(session_id: &SessionId) -> String {
+    encode_value(&session_id.to_string()).into_owned()
+}
+
+/// Finish `response` with `body`, failing if a header didn't make a valid
+/// value (e.g. a store's content type)
+fn finish<T>(response: hyper::http::response::Builder, body: T) -> Result<Response<T>, BpxError> {
+    response.body(body).map_err(|e| BpxError::InvalidResponse {
+        reason: e.to_string(),
+    })
+}
+
+/// Build HTTP response from BPX response with original size info
+fn build_http_response_with_original_size(
+    bpx_response: BpxResponse,
+    original_size: usize,
+    config: &BpxConfig,
+) -> Result<Response<Bytes>, BpxError> {
+    let mut response = Response::builder().header(
+        BpxHeaders::RESOURCE_VERSION,
+        version_header(&bpx_response.version),
+    );
+
+    if let Some(session_id) = &bpx_response.session_id {
+        response = response.header(BpxHeaders::SESSION, session_header(session_id));
+        if let Some(status) = &bpx_response.session_status {
+            response = response.header(BpxHeaders::SESSION_STATUS, status.as_str());
+        }
+        response = with_session_cookies(response, config, session_id);
+    }
+
+    // The body depends on who is asking and what they already hold
+    let mut vary = format!(
+        "{}, {}, {}",
+        BpxHeaders::SESSION,
+        BpxHeaders::BASE_VERSION,
+        BpxHeaders::ACCEPT_DIFF
+    );
+    if config.session_cookie.is_some() {
+        vary.push_str(", Cookie");
+    }
+    response = response.header(header::VARY, vary);
+
+    match &bpx_response.body {
+        ResponseBody::Full(content) => {
+            if let Some(cache_ttl) = bpx_response.cache_ttl {
+                response = response.header(
+                    header::CACHE_CONTROL,
+                    format!("max-age={}", cache_ttl.as_secs()),
+                );
+            }
+            response = response
+                .header(BpxHeaders::DIFF_TYPE, "full")
+                .header(BpxHeaders::ORIGINAL_SIZE, content.len().to_string());
+            if let Some(reason) = bpx_response.fallback_reason {
+                response = response.header(BpxHeaders::FALLBACK_REASON, reason.as_str());
+            }
+            if let Some(content_type) = &bpx_response.content_type {
+                response = response.header(header::CONTENT_TYPE, content_type.as_str());
+            }
+        }
+        ResponseBody::Diff { format, data } => {
+            response = response
+                .header(BpxHeaders::DIFF_TYPE, format.as_str())
+                .header(BpxHeaders::ORIGINAL_SIZE, original_size.to_string())
+                .header(BpxHeaders::DIFF_SIZE, data.len().to_string())
+                .header(header::CONTENT_TYPE, format.media_type())
+                // Only valid against this client's base; shared caches must not reuse it
+                .header(header::CACHE_CONTROL, "private");
+            if let Some(base) = &bpx_response.delta_base {
+                response = response.header(BpxHeaders::DELTA_BASE, version_header(base));
+            }
+            if let Some(digest) = &bpx_response.content_digest {
+                response = response.header(BpxHeaders::CONTENT_DIGEST, version_header(digest));
+            }
+            // The patched body keeps the resource's own media type
+            if let Some(content_type) = &bpx_response.content_type {
+                response =
+                    response.header(BpxHeaders::ORIGINAL_CONTENT_TYPE, content_type.as_str());
+            }
+        }
+    }
+
+    if let Some(cache_ttl) = bpx_response.cache_ttl {
+        response = response.header(BpxHeaders::CACHE_TTL, cache_ttl.as_secs().to_string());
+    }
+
+    finish(response, bpx_response.body.as_bytes().clone())
+}
+
+/// Trait for accessing resource storage
+#[async_trait]
+pub trait ResourceStore: Send + Sync {
+    /// Get current version of a resource
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError>;
+
+    /// Get the current content of a resource together with its version
+    ///
+    /// The default hashes the content; stores whose applications supply
+    /// their own versions return those instead.
+    async fn get_versioned_resource(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(Bytes, Version), BpxError> {
+        let content = self.get_resource(path).await?;
+        let version = Version::from_content(&content);
+        Ok((content, version))
+    }
+
+    /// Get the current content and version of several resources at once
+    ///
+    /// Stores supporting [`update_resources`](Self::update_resources) return
+    /// a consistent view: never some resources from before an update and
+    /// others from after it. The default reads them one at a time.
+    async fn get_versioned_resources(
+        &self,
+        paths: &[ResourcePath],
+    ) -> Vec<Result<(Bytes, Version), BpxError>> {
+        let mut resources = Vec::with_capacity(paths.len());
+        for path in paths {
+            resources.push(self.get_versioned_resource(path).await);
+        }
+        resources
+    }
+
+    /// Stream the current content of a resource together with its version
+    ///
+    /// Must agree with [`get_versioned_resource`](Self::get_versioned_resource).
+    /// The default buffers the content into one chunk; stores holding large
+    /// resources override it so full responses needn't be buffered.
+    async fn get_resource_stream(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(ResourceStream, Version), BpxError> {
+        let (content, version) = self.get_versioned_resource(path).await?;
+        Ok((single_chunk(content), version))
+    }
+
+    /// Hash of `content`, the current content of `path` at `version`, which
+    /// clients check patched content against when `version` isn't the hash
+    ///
+    /// Asked for every diff sent. The default hashes `content`; stores whose
+    /// applications supply their own versions should hash it once, when it
+    /// is stored, and return that.
+    async fn content_digest(
+        &self,
+        _path: &ResourcePath,
+        _version: &Version,
+        content: &Bytes,
+    ) -> Version {
+        Version::from_content(content)
+    }
+
+    /// Get specific version of a resource
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError>;
+
+    /// Whether a version of a resource is stored
+    ///
+    /// Asked before every [`store_version`](Self::store_version), so stores
+    /// should answer without fetching the content; the default does fetch it.
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.get_resource_version(path, version).await.is_ok()
+    }
+
+    /// Replace a resource's content, returning its new version
+    ///
+    /// With `expected` set the write only happens if the resource is at that
+    /// version, failing with [`BpxError::PreconditionFailed`] otherwise. The
+    /// default rejects writes with [`BpxError::ReadOnly`].
+    async fn put_resource(
+        &self,
+        path: &ResourcePath,
+        _content: Bytes,
+        _expected: Option<&Version>,
+    ) -> Result<Version, BpxError> {
+        Err(BpxError::ReadOnly { path: path.clone() })
+    }
+
+    /// Replace the content of several resources atomically
+    ///
+    /// Readers see either all of the update or none of it. The default
+    /// rejects writes with [`BpxError::ReadOnly`].
+    async fn update_resources(&self, update: ResourceUpdate) -> Result<(), BpxError> {
+        let path = update
+            .paths()
+            .next()
+            .cloned()
+            .unwrap_or_else(|| ResourcePath::new("/".to_string()));
+        Err(BpxError::ReadOnly { path })
+    }
+
+    /// Store a specific version of a resource
+    ///
+    /// The handler records a version before telling the session about it, so
+    /// an error here fails the request rather than leaving the session with a
+    /// base that can't be diffed against.
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError>;
+
+    /// Get the media type of a resource, if known
+    async fn get_content_type(&self, _path: &ResourcePath) -> Option<String> {
+        None
+    }
+
+    /// Drop stored versions past the store's retention limits
+    ///
+    /// Run periodically alongside session cleanup.
+    async fn compact(&self) {}
+
+    /// Every resource with current content
+    ///
+    /// The default lists nothing, for stores that can't enumerate resources.
+    async fn list_resources(&self) -> Result<Vec<ResourcePath>, BpxError> {
+        Ok(Vec::new())
+    }
+
+    /// Versions of `path` held for diffing, oldest first
+    ///
+    /// The default lists nothing, for stores that can't enumerate versions.
+    async fn list_versions(&self, _path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        Ok(Vec::new())
+    }
+
+    /// Stream the versions `path` changes to from now on
+    ///
+    /// Returns `None` if the store can't announce changes, in which case
+    /// clients have to poll.
+    fn watch(&self, _path: &ResourcePath) -> Option<VersionStream> {
+        None
+    }
+
+    /// How many resources and versions the store holds
+    ///
+    /// Returns `None` if the store can't count them cheaply.
+    fn stats(&self) -> Option<StoreStats> {
+        None
+    }
+}
+
+/// New content for resources that must change together, applied with
+/// [`ResourceStore::update_resources`]
+#[derive(Debug, Clone, Default)]
+pub struct ResourceUpdate {
+    resources: Vec<(ResourcePath, Bytes, Option<Version>)>,
+}
+
+impl ResourceUpdate {
+    /// Create an empty update
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a resource's content
+    pub fn set(mut self, path: ResourcePath, content: Bytes) -> Self {
+        self.resources.push((path, content, None));
+        self
+    }
+
+    /// Set a resource's content along with its version
+    /// (see [`InMemoryResourceStore::set_resource_versioned`])
+    pub fn set_versioned(mut self, path: ResourcePath, version: Version, content: Bytes) -> Self {
+        self.resources.push((path, content, Some(version)));
+        self
+    }
+
+    /// Resources changed by this update
+    pub fn paths(&self) -> impl Iterator<Item = &ResourcePath> {
+        self.resources.iter().map(|(path, _, _)| path)
+    }
+
+    /// Each resource's path, content and version if one was given
+    pub fn into_parts(self) -> Vec<(ResourcePath, Bytes, Option<Version>)> {
+        self.resources
+    }
+}
+
+/// Chunks of a resource's content, from [`ResourceStore::get_resource_stream`]
+pub type ResourceStream = Pin<Box<dyn Stream<Item = Result<Bytes, BpxError>> + Send>>;
+
+/// [`ResourceStream`] of content already in memory
+pub(crate) fn single_chunk(content: Bytes) -> ResourceStream {
+    Box::pin(Chunk(Some(content)))
+}
+
+struct Chunk(Option<Bytes>);
+
+impl Stream for Chunk {
+    type Item = Result<Bytes, BpxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.0.take().map(Ok))
+    }
+}
+
+/// Limits on the version history kept by [`InMemoryResourceStore`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRetention {
+    /// Most versions kept per path; storing another drops the oldest
+    pub max_versions_per_path: usize,
+    /// Versions stored longer ago than this are dropped by `compact`
+    pub max_age: Duration,
+    /// Cap on bytes of stored versions across all paths, enforced by
+    /// `compact` by dropping the oldest
+    pub max_total_bytes: usize,
+}
+
+impl Default for VersionRetention {
+    fn default() -> Self {
+        Self {
+            max_versions_per_path: 16,
+            max_age: Duration::from_secs(24 * 60 * 60), // 24 hours
+            max_total_bytes: 256 * 1024 * 1024,         // 256MB
+        }
+    }
+}
+
+/// SHA-256 of version content
+type ContentHash = [u8; 32];
+
+/// A resource's current content and the version its application gave it
+struct Current {
+    content: Bytes,
+    version: Option<Version>,
+    /// Hash of `content`, computed on the first read rather than every one,
+    /// or as it is stored when the application gave a version
+    hashed: OnceLock<Version>,
+}
+
+impl Current {
+    fn new(content: Bytes, version: Option<Version>) -> Self {
+        // Diffs to an application's version carry the hash for clients to
+        // check; take it now rather than on every diff
+        let hashed = match version {
+            Some(_) => OnceLock::from(Version::from_content(&content)),
+            None => OnceLock::new(),
+        };
+        Self {
+            content,
+            version,
+            hashed,
+        }
+    }
+
+    /// The application's version, or else the content's hash
+    fn version(&self) -> Version {
+        match &self.version {
+            Some(version) => version.clone(),
+            None => self.digest(),
+        }
+    }
+
+    /// Hash of the content
+    fn digest(&self) -> Version {
+        self.hashed
+            .get_or_init(|| Version::from_content(&self.content))
+            .clone()
+    }
+}
+
+/// Hash of a version's content and when it was last stored
+struct StoredVersion {
+    hash: ContentHash,
+    stored_at: Instant,
+    /// Overrides [`VersionRetention::max_age`]
+    ttl: Option<Duration>,
+    /// Exempt from every retention limit
+    pinned: bool,
+}
+
+impl StoredVersion {
+    fn expired(&self, max_age: Duration) -> bool {
+        !self.pinned && self.stored_at.elapsed() > self.ttl.unwrap_or(max_age)
+    }
+}
+
+/// Version content shared by every version with the same bytes
+struct Blob {
+    payload: Payload,
+    refs: usize,
+}
+
+/// How a blob's content is held
+#[derive(Clone)]
+enum Payload {
+    /// The content itself
+    Full(Bytes),
+    /// A diff that turns the content of blob `base` into this one
+    Delta { base: ContentHash, diff: Bytes },
+}
+
+impl Payload {
+    fn len(&self) -> usize {
+        match self {
+            Payload::Full(content) => content.len(),
+            Payload::Delta { diff, .. } => diff.len(),
+        }
+    }
+}
+
+/// In-memory resource store implementation
+///
+/// Version content is stored once per distinct content hash and shared,
+/// reference-counted, by every path and version holding those bytes, so
+/// polling an unchanged resource doesn't grow the history.
+///
+/// Version history is bounded by a [`VersionRetention`]; call
+/// [`compact`](Self::compact) periodically to apply its age and size limits.
+///
+/// With [`with_reverse_deltas`](Self::with_reverse_deltas) only the newest
+/// version of each path is kept whole; older ones are kept as diffs against
+/// the version stored after them and rebuilt on demand.
+pub struct InMemoryResourceStore {
+    resources: dashmap::DashMap<String, Current>,
+    versions: dashmap::DashMap<String, dashmap::DashMap<String, StoredVersion>>,
+    blobs: dashmap::DashMap<ContentHash, Blob>,
+    content_types: dashmap::DashMap<String, String>,
+    retention: VersionRetention,
+    version_bytes: AtomicUsize,
+    delta_engine: Option<Arc<dyn DiffEngine>>,
+    watchers: VersionBroadcast,
+    commits: RwLock<()>,
+    /// Held while re-encoding blobs, which are shared across paths
+    encoding: Mutex<()>,
+}
+
+impl InMemoryResourceStore {
+    /// Create a new in-memory resource store
+    pub fn new() -> Self {
+        Self::with_retention(VersionRetention::default())
+    }
+
+    /// Create a store keeping version history within `retention`
+    pub fn with_retention(retention: VersionRetention) -> Self {
+        Self {
+            resources: dashmap::DashMap::new(),
+            versions: dashmap::DashMap::new(),
+            blobs: dashmap::DashMap::new(),
+            content_types: dashmap::DashMap::new(),
+            retention,
+            version_bytes: AtomicUsize::new(0),
+            delta_engine: None,
+            watchers: VersionBroadcast::new(),
+            commits: RwLock::new(()),
+            encoding: Mutex::new(()),
+        }
+    }
+
+    /// Keep superseded versions as reverse deltas computed by `engine`
+    ///
+    /// Cuts history memory for slowly changing resources at the cost of
+    /// applying one diff per newer version when an old base is requested.
+    /// A version whose delta wouldn't be worthwhile is kept whole.
+    pub fn with_reverse_deltas(mut self, engine: Arc<dyn DiffEngine>) -> Self {
+        self.delta_engine = Some(engine);
+        self
+    }
+
+    /// Set a resource's current content, notifying its watchers if it changed
+    pub fn set_resource(&self, path: ResourcePath, content: Bytes) {
+        self.put_current(path, content, None);
+    }
+
+    /// Set a resource's current content along with its version
+    ///
+    /// For applications that already track ETags or revision numbers: the
+    /// server serves `version` as is instead of hashing the content on every
+    /// request. It must change whenever the content does.
+    pub fn set_resource_versioned(&self, path: ResourcePath, version: Version, content: Bytes) {
+        self.put_current(path, content, Some(version));
+    }
+
+    /// Set several resources' content so readers of
+    /// [`get_versioned_resources`](ResourceStore::get_versioned_resources)
+    /// see all of the changes or none
+    pub fn update(&self, update: ResourceUpdate) {
+        let changed: Vec<_> = {
+            let _commit = self.commits.write().unwrap_or_else(|e| e.into_inner());
+            update
+                .into_parts()
+                .into_iter()
+                .filter_map(|(path, content, version)| self.replace_current(path, content, version))
+                .collect()
+        };
+        for (path, version) in changed {
+            self.watchers.notify(&path, version);
+        }
+    }
+
+    fn put_current(&self, path: ResourcePath, content: Bytes, version: Option<Version>) {
+        if let Some((path, version)) = self.replace_current(path, content, version) {
+            self.watchers.notify(&path, version);
+        }
+    }
+
+    /// Replace a resource's content, returning the version to announce to
+    /// its watchers if it changed
+    fn replace_current(
+        &self,
+        path: ResourcePath,
+        content: Bytes,
+        version: Option<Version>,
+    ) -> Option<(ResourcePath, Version)> {
+        let current = Current::new(content.clone(), version.clone());
+        let announce = self.watchers.is_watched(&path).then(|| current.version());
+        let previous = self.resources.insert(path.to_string(), current);
+        let changed = previous
+            .is_none_or(|previous| previous.content != content || previous.version != version);
+        announce.filter(|_| changed).map(|version| (path, version))
+    }
+
+    /// Set a collection resource's current elements
+    ///
+    /// Clients accepting `element-delta` are sent the elements that changed
+    /// (see [`crate::diff::collection`]).
+    pub fn set_collection(&self, path: ResourcePath, elements: &[Element]) -> Result<(), BpxError> {
+        let content = ElementDiffCodec::encode_collection(elements).map_err(|e| {
+            BpxError::InvalidRequest {
+                reason: e.to_string(),
+            }
+        })?;
+        self.set_content_type(path.clone(), COLLECTION_MEDIA_TYPE);
+        self.set_resource(path, content);
+        Ok(())
+    }
+
+    /// Set a resource's media type
+    pub fn set_content_type(&self, path: ResourcePath, content_type: impl Into<String>) {
+        self.content_types
+            .insert(path.to_string(), content_type.into());
+    }
+
+    /// Store a specific version of a resource
+    pub fn store_version(&self, path: ResourcePath, version: Version, content: Bytes) {
+        self.store(path, version, content, None)
+    }
+
+    /// Store a version that `compact` drops after `ttl` instead of
+    /// [`VersionRetention::max_age`]
+    pub fn store_version_with_ttl(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+        ttl: Duration,
+    ) {
+        self.store(path, version, content, Some(ttl))
+    }
+
+    /// Keep a stored version regardless of the retention limits, returning
+    /// whether it was stored
+    ///
+    /// Pin the versions long-lived clients hold, such as the one bundled with
+    /// an app release, so they still get a diff rather than a full download.
+    /// Pinned versions don't count towards `max_versions_per_path`.
+    pub fn pin_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.set_pinned(path, version, true)
+    }
+
+    /// Subject a pinned version to the retention limits again, returning
+    /// whether it was stored
+    pub fn unpin_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.set_pinned(path, version, false)
+    }
+
+    fn set_pinned(&self, path: &ResourcePath, version: &Version, pinned: bool) -> bool {
+        let Some(versions) = self.versions.get(&path.to_string()) else {
+            return false;
+        };
+        let Some(mut stored) = versions.get_mut(&version.to_string()) else {
+            return false;
+        };
+        stored.pinned = pinned;
+        true
+    }
+
+    fn store(&self, path: ResourcePath, version: Version, content: Bytes, ttl: Option<Duration>) {
+        let path_str = path.to_string();
+        let version_str = version.to_string();
+
+        let hash = self.intern(content.clone());
+        let previous = {
+            let versions = self.versions.entry(path_str).or_default();
+            let previous = versions
+                .iter()
+                .max_by_key(|entry| entry.stored_at)
+                .map(|entry| entry.hash);
+            // Storing a version again keeps its pin and TTL
+            let (pinned, ttl) = versions
+                .get(&version_str)
+                .map_or((false, ttl), |held| (held.pinned, ttl.or(held.ttl)));
+            let stored = StoredVersion {
+                hash,
+                stored_at: Instant::now(),
+                ttl,
+                pinned,
+            };
+            if let Some(replaced) = versions.insert(version_str, stored) {
+                self.release(&replaced.hash);
+            }
+            self.trim(&versions, self.retention.max_versions_per_path);
+            previous
+        };
+
+        if let Some(engine) = &self.delta_engine
+            && let Some(previous) = previous
+            && previous != hash
+        {
+            // Two paths storing the same contents in opposite orders would
+            // otherwise each base one on the other
+            let _encoding = self.encoding.lock().unwrap_or_else(|e| e.into_inner());
+            self.promote(&hash, &content);
+            self.delta_encode(engine.as_ref(), &previous, hash, &content);
+        }
+    }
+
+    /// Take a reference to the blob holding `content`, storing it if new
+    fn intern(&self, content: Bytes) -> ContentHash {
+        let hash: ContentHash = Sha256::digest(&content).into();
+        self.blobs
+            .entry(hash)
+            .and_modify(|blob| blob.refs += 1)
+            .or_insert_with(|| {
+                self.version_bytes
+                    .fetch_add(content.len(), Ordering::Relaxed);
+                Blob {
+                    payload: Payload::Full(content),
+                    refs: 1,
+                }
+            });
+        hash
+    }
+
+    /// Drop a reference to a blob, returning the bytes freed
+    ///
+    /// Freeing a delta drops its reference to the blob it was based on.
+    fn release(&self, hash: &ContentHash) -> usize {
+        let mut freed = 0;
+        let mut next = Some(*hash);
+        while let Some(hash) = next.take() {
+            let Entry::Occupied(mut blob) = self.blobs.entry(hash) else {
+                break;
+            };
+            blob.get_mut().refs -= 1;
+            if blob.get().refs > 0 {
+                break;
+            }
+            let payload = blob.remove().payload;
+            freed += payload.len();
+            if let Payload::Delta { base, .. } = payload {
+                next = Some(base);
+            }
+        }
+        self.version_bytes.fetch_sub(freed, Ordering::Relaxed);
+        freed
+    }
+
+    /// Content of a blob, applying the deltas down from its full base
+    ///
+    /// A chain that loops back on itself yields `None`.
+    fn content(&self, hash: &ContentHash) -> Option<Bytes> {
+        let mut diffs = Vec::new();
+        let mut visited = HashSet::new();
+        let mut current = *hash;
+        let mut content = loop {
+            let payload = self.blobs.get(&current)?.payload.clone();
+            match payload {
+                Payload::Full(content) => break content,
+                Payload::Delta { base, diff } => {
+                    if !visited.insert(current) {
+                        return None;
+                    }
+                    diffs.push(diff);
+                    current = base;
+                }
+            }
+        };
+        if diffs.is_empty() {
+            return Some(content);
+        }
+        let engine = self.delta_engine.as_ref()?;
+        for diff in diffs.iter().rev() {
+            content = engine.apply_diff(&content, diff).ok()?;
+        }
+        Some(content)
+    }
+
+    /// Hold a blob's content whole again, so deltas can be based on it
+    ///
+    /// Deltas are only ever based on whole blobs, which keeps chains acyclic.
+    fn promote(&self, hash: &ContentHash, content: &Bytes) {
+        let base = match self.blobs.get_mut(hash) {
+            Some(mut blob) => match blob.payload {
+                Payload::Delta { base, ref diff } => {
+                    self.version_bytes.fetch_sub(diff.len(), Ordering::Relaxed);
+                    self.version_bytes
+                        .fetch_add(content.len(), Ordering::Relaxed);
+                    blob.payload = Payload::Full(content.clone());
+                    base
+                }
+                Payload::Full(_) => return,
+            },
+            None => return,
+        };
+        self.release(&base);
+    }
+
+    /// Replace the whole blob `older` with a delta from blob `newer`, if
+    /// that saves enough space
+    fn delta_encode(
+        &self,
+        engine: &dyn DiffEngine,
+        older: &ContentHash,
+        newer: ContentHash,
+        newer_content: &Bytes,
+    ) {
+        let older_content = match self.blobs.get(older).map(|blob| blob.payload.clone()) {
+            Some(Payload::Full(content)) => content,
+            _ => return,
+        };
+        let Ok(diff) = engine.compute_diff(newer_content, &older_content) else {
+            return;
+        };
+        if !engine.is_diff_worthwhile(older_content.len(), diff.len()) {
+            return;
+        }
+
+        // The delta keeps its base alive
+        match self.blobs.get_mut(&newer) {
+            Some(mut blob) => blob.refs += 1,
+            None => return,
+        }
+        let encoded = match self.blobs.get_mut(older) {
+            Some(mut blob) if matches!(blob.payload, Payload::Full(_)) => {
+                self.version_bytes
+                    .fetch_sub(older_content.len(), Ordering::Relaxed);
+                self.version_bytes.fetch_add(diff.len(), Ordering::Relaxed);
+                blob.payload = Payload::Delta { base: newer, diff };
+                true
+            }
+            _ => false,
+        };
+        if !encoded {
+            self.release(&newer);
+        }
+    }
+
+    /// Drop the oldest unpinned `versions` beyond `keep`
+    fn trim(&self, versions: &dashmap::DashMap<String, StoredVersion>, keep: usize) {
+        while versions.iter().filter(|entry| !entry.pinned).count() > keep {
+            let oldest = versions
+                .iter()
+                .filter(|entry| !entry.pinned)
+                .min_by_key(|entry| entry.stored_at)
+                .map(|entry| entry.key().clone());
+            let Some((_, dropped)) = oldest.and_then(|version| versions.remove(&version)) else {
+                break;
+            };
+            self.release(&dropped.hash);
+        }
+    }
+
+    /// Apply the retention limits: drop versions older than their TTL or
+    /// `max_age`, then the oldest ones until the total is under
+    /// `max_total_bytes`. Pinned versions are kept.
+    pub fn compact(&self) {
+        let max_age = self.retention.max_age;
+        self.versions.retain(|_, versions| {
+            versions.retain(|_, stored| {
+                let expired = stored.expired(max_age);
+                if expired {
+                    self.release(&stored.hash);
+                }
+                !expired
+            });
+            self.trim(versions, self.retention.max_versions_per_path);
+            !versions.is_empty()
+        });
+
+        let mut to_free = self
+            .version_bytes
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.retention.max_total_bytes);
+        if to_free == 0 {
+            return;
+        }
+        let mut oldest: Vec<_> = self
+            .versions
+            .iter()
+            .flat_map(|path| {
+                path.value()
+                    .iter()
+                    .filter(|v| !v.pinned)
+                    .map(|v| (v.stored_at, path.key().clone(), v.key().clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        oldest.sort();
+        for (_, path, version) in oldest {
+            if to_free == 0 {
+                break;
+            }
+            let removed = self
+                .versions
+                .get(&path)
+                .and_then(|versions| versions.remove(&version));
+            if let Some((_, dropped)) = removed {
+                to_free = to_free.saturating_sub(self.release(&dropped.hash));
+            }
+        }
+        self.versions.retain(|_, versions| !versions.is_empty());
+    }
+
+    /// Bytes of stored version content across all paths, counting shared
+    /// content once and deltas at their encoded size
+    pub fn version_bytes(&self) -> usize {
+        self.version_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of distinct version contents stored
+    pub fn blob_count(&self) -> usize {
+        self.blobs.len()
+    }
+
+    /// Get all stored versions for a resource
+    pub fn get_versions(&self, path: &ResourcePath) -> Vec<Version> {
+        if let Some(versions) = self.versions.get(&path.to_string()) {
+            versions
+                .iter()
+                .map(|entry| Version::new(entry.key().clone()))
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Remove a resource and all its versions
+    pub fn remove_resource(&self, path: &ResourcePath) {
+        let path_str = path.to_string();
+        self.resources.remove(&path_str);
+        if let Some((_, versions)) = self.versions.remove(&path_str) {
+            for (_, stored) in versions {
+                self.release(&stored.hash);
+            }
+        }
+        self.content_types.remove(&path_str);
+    }
+
+    /// Get the total number of resources
+    pub fn resource_count(&self) -> usize {
+        self.resources.len()
+    }
+
+    /// Get the total number of stored versions across all resources
+    pub fn version_count(&self) -> usize {
+        self.versions.iter().map(|entry| entry.value().len()).sum()
+    }
+
+    /// Current content and version of a resource
+    fn current(&self, path: &ResourcePath) -> Result<(Bytes, Version), BpxError> {
+        self.resources
+            .get(&path.to_string())
+            .map(|entry| (entry.content.clone(), entry.version()))
+            .ok_or_else(|| BpxError::ResourceNotFound { path: path.clone() })
+    }
+
+    /// Get current resource content (for demo purposes)
+    pub fn get_current_resource(&self, path: &ResourcePath) -> Option<Bytes> {
+        self.resources
+            .get(&path.to_string())
+            .map(|entry| entry.content.clone())
+    }
+}
+
+impl Default for InMemoryResourceStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ResourceStore for InMemoryResourceStore {
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+        self.resources
+            .get(&path.to_string())
+            .map(|entry| entry.content.clone())
+            .ok_or_else(|| BpxError::ResourceNotFound { path: path.clone() })
+    }
+
+    async fn get_versioned_resource(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(Bytes, Version), BpxError> {
+        self.current(path)
+    }
+
+    async fn get_versioned_resources(
+        &self,
+        paths: &[ResourcePath],
+    ) -> Vec<Result<(Bytes, Version), BpxError>> {
+        let _commit = self.commits.read().unwrap_or_else(|e| e.into_inner());
+        paths.iter().map(|path| self.current(path)).collect()
+    }
+
+    async fn content_digest(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+        content: &Bytes,
+    ) -> Version {
+        // The content may have been replaced since it was read
+        self.resources
+            .get(&path.to_string())
+            .filter(|current| current.version() == *version)
+            .map(|current| current.digest())
+            .unwrap_or_else(|| Version::from_content(content))
+    }
+
+    async fn update_resources(&self, update: ResourceUpdate) -> Result<(), BpxError> {
+        self.update(update);
+        Ok(())
+    }
+
+    async fn put_resource(
+        &self,
+        path: &ResourcePath,
+        content: Bytes,
+        expected: Option<&Version>,
+    ) -> Result<Version, BpxError> {
+        let version = Version::from_content(&content);
+        let current = Current {
+            hashed: OnceLock::from(version.clone()),
+            ..Current::new(content, None)
+        };
+        let previous = match self.resources.entry(path.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.get().version();
+                if expected.is_some_and(|expected| *expected != previous) {
+                    return Err(BpxError::PreconditionFailed {
+                        path: path.clone(),
+                        current: Some(previous),
+                    });
+                }
+                entry.insert(current);
+                Some(previous)
+            }
+            Entry::Vacant(entry) => {
+                if expected.is_some() {
+                    return Err(BpxError::PreconditionFailed {
+                        path: path.clone(),
+                        current: None,
+                    });
+                }
+                entry.insert(current);
+                None
+            }
+        };
+        if previous.as_ref() != Some(&version) {
+            self.watchers.notify(path, version.clone());
+        }
+        Ok(version)
+    }
+
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError> {
+        let path_str = path.to_string();
+        let version_str = version.to_string();
+
+        let hash = self
+            .versions
+            .get(&path_str)
+            .and_then(|versions| versions.get(&version_str).map(|entry| entry.hash));
+        hash.and_then(|hash| self.content(&hash))
+            .ok_or_else(|| BpxError::VersionNotFound {
+                path: path.clone(),
+                version: version.clone(),
+            })
+    }
+
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.versions
+            .get(&path.to_string())
+            .is_some_and(|versions| versions.contains_key(&version.to_string()))
+    }
+
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError> {
+        Self::store_version(self, path, version, content);
+        Ok(())
+    }
+
+    async fn get_content_type(&self, path: &ResourcePath) -> Option<String> {
+        self.content_types
+            .get(&path.to_string())
+            .map(|entry| entry.value().clone())
+    }
+
+    async fn compact(&self) {
+        Self::compact(self)
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourcePath>, BpxError> {
+        Ok(self
+            .resources
+            .iter()
+            .map(|entry| ResourcePath::new(entry.key().clone()))
+            .collect())
+    }
+
+    async fn list_versions(&self, path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        let Some(versions) = self.versions.get(&path.to_string()) else {
+            return Ok(Vec::new());
+        };
+        let mut versions: Vec<_> = versions
+            .iter()
+            .map(|entry| (entry.stored_at, Version::new(entry.key().clone())))
+            .collect();
+        versions.sort_by_key(|(stored_at, _)| *stored_at);
+        Ok(versions.into_iter().map(|(_, version)| version).collect())
+    }
+
+    fn watch(&self, path: &ResourcePath) -> Option<VersionStream> {
+        Some(self.watchers.subscribe(path))
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        Some(StoreStats {
+            resources: self.resource_count(),
+            versions: self.version_count(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff::similar::SimilarDiffEngine, state::InMemoryStateManager};
+
+    /// `n` numbered lines
+    fn lines(n: usize) -> Bytes {
+        (0..n)
+            .map(|i| format!("entry {}\n", i))
+            .collect::<String>()
+            .into()
+    }
+
+    /// A `method` request for `uri` with `headers` and `body`
+    fn request(
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<Bytes>,
+    ) -> Request<Full<Bytes>> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(Full::new(body.into())).unwrap()
+    }
+
+    /// The `name` header of `resp`
+    fn header_str<B>(resp: &Response<B>, name: impl header::AsHeaderName) -> String {
+        resp.headers()[name].to_str().unwrap().to_string()
+    }
+
+    /// The handlers' arguments, over an in-memory store
+    #[derive(Clone)]
+    struct Fixture {
+        config: BpxConfig,
+        state_mgr: Arc<dyn StateManager>,
+        engine: Arc<dyn DiffEngine>,
+        store: Arc<InMemoryResourceStore>,
+    }
+
+    impl Fixture {
+        fn new(config: BpxConfig) -> Self {
+            Self {
+                state_mgr: Arc::new(InMemoryStateManager::new(config.clone())),
+                engine: Arc::new(SimilarDiffEngine::new()),
+                store: Arc::new(InMemoryResourceStore::new()),
+                config,
+            }
+        }
+
+        /// GET `uri` with `headers`
+        async fn get(
+            &self,
+            uri: &str,
+            headers: &[(&str, &str)],
+        ) -> Result<Response<Bytes>, BpxError> {
+            self.read(request(Method::GET, uri, headers, Bytes::new()))
+                .await
+        }
+
+        async fn read(&self, req: Request<Full<Bytes>>) -> Result<Response<Bytes>, BpxError> {
+            handle_bpx_request(
+                req,
+                &self.config,
+                self.state_mgr.clone(),
+                self.engine.clone(),
+                self.store.clone(),
+            )
+            .await
+        }
+
+        async fn stream(
+            &self,
+            req: Request<Full<Bytes>>,
+        ) -> Result<Response<ResourceBody>, BpxError> {
+            handle_bpx_request_streaming(
+                req,
+                &self.config,
+                self.state_mgr.clone(),
+                self.engine.clone(),
+                self.store.clone(),
+            )
+            .await
+        }
+
+        async fn batch(&self, req: Request<Full<Bytes>>) -> Result<Response<Bytes>, BpxError> {
+            handle_batch_request(
+                req,
+                &self.config,
+                self.state_mgr.clone(),
+                self.engine.clone(),
+                self.store.clone(),
+            )
+            .await
+        }
+
+        async fn write(&self, req: Request<Full<Bytes>>) -> Result<Response<Bytes>, BpxError> {
+            handle_write_request(
+                req,
+                &self.config,
+                self.state_mgr.clone(),
+                self.engine.clone(),
+                self.store.clone(),
+            )
+            .await
+        }
+    }
+
+    impl Default for Fixture {
+        fn default() -> Self {
+            Self::new(BpxConfig::default())
+        }
+    }
+
+    #[test]
+    fn test_parse_bpx_request() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header("X-BPX-Session", "sess_123")
+            .header("X-Base-Version", "v:456")
+            .header("Accept-Diff", "binary-delta,json-patch")
+            .body(())
+            .unwrap();
+
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+
+        assert_eq!(bpx_req.path.to_string(), "/api/test");
+        assert_eq!(bpx_req.session_id.as_ref().unwrap().to_string(), "sess_123");
+        assert_eq!(bpx_req.base_version.as_ref().unwrap().to_string(), "v:456");
+        assert_eq!(bpx_req.accepted_formats.len(), 2);
+        assert_eq!(bpx_req.preferred_format(), Some(DiffFormat::BinaryDelta));
+    }
+
+    #[test]
+    fn test_parse_bpx_request_minimal() {
+        let req = Request::builder().uri("/api/minimal").body(()).unwrap();
+
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert_eq!(bpx_req.path.to_string(), "/api/minimal");
+        assert!(bpx_req.session_id.is_none());
+        assert!(bpx_req.base_version.is_none());
+        assert_eq!(bpx_req.accepted_formats, vec![DiffFormat::BinaryDelta]); // default
+    }
+
+    #[test]
+    fn test_parse_bpx_request_canonical_query() {
+        // Query parameters name distinct resources, in canonical order
+        let req = Request::builder()
+            .uri("/api/items?page=2&filter=open")
+            .body(())
+            .unwrap();
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert_eq!(bpx_req.path.to_string(), "/api/items?filter=open&page=2");
+    }
+
+    #[test]
+    fn test_parse_bpx_request_invalid_headers() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header("X-BPX-Session", "sess_123")
+            .header("X-Base-Version", "v:456")
+            .header("Accept-Diff", "invalid-format,json-patch")
+            .body(())
+            .unwrap();
+
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+
+        // Should ignore invalid format and keep valid ones
+        assert_eq!(bpx_req.accepted_formats.len(), 1);
+        assert_eq!(bpx_req.preferred_format(), Some(DiffFormat::JsonPatch));
+    }
+
+    #[test]
+    fn test_parse_bpx_request_quality_values() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header("Accept-Diff", "binary-delta;q=0.5, json-patch;q=0.9")
+            .body(())
+            .unwrap();
+
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert_eq!(
+            bpx_req.accepted_formats,
+            vec![DiffFormat::JsonPatch, DiffFormat::BinaryDelta]
+        );
+        // Client prefers json-patch, but the server only produces binary-delta
+        assert_eq!(
+            negotiate_format(&bpx_req.accepted_formats, SUPPORTED_FORMATS),
+            Some(DiffFormat::BinaryDelta)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accept_diff_refusing_every_format() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header(BpxHeaders::ACCEPT_DIFF, "binary-delta;q=0")
+            .body(())
+            .unwrap();
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert!(bpx_req.accepted_formats.is_empty());
+
+        // The refused format isn't sent: the client gets the full body
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/feed".to_string());
+        fixture.store.set_resource(path.clone(), lines(100));
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        fixture.store.set_resource(path, lines(101));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                    (BpxHeaders::ACCEPT_DIFF, "binary-delta;q=0"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(
+            resp.headers()[BpxHeaders::FALLBACK_REASON],
+            "format-not-accepted"
+        );
+        assert_eq!(resp.body(), &lines(101));
+    }
+
+    #[test]
+    fn test_parse_rfc3229_request() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header("A-IM", "binary-delta;q=1.0, vcdiff")
+            .header("If-None-Match", "W/\"v:456\"")
+            .body(())
+            .unwrap();
+
+        let bpx_req = parse_rfc3229_request(&req, &BpxConfig::default()).unwrap();
+        assert!(bpx_req.session_id.is_none());
+        assert_eq!(bpx_req.base_version.as_ref().unwrap().to_string(), "v:456");
+        assert_eq!(bpx_req.accepted_formats, vec![DiffFormat::BinaryDelta]);
+
+        // No A-IM: client cannot take deltas
+        let req = Request::builder().uri("/api/test").body(()).unwrap();
+        let bpx_req = parse_rfc3229_request(&req, &BpxConfig::default()).unwrap();
+        assert!(bpx_req.accepted_formats.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rfc3229_exchange() {
+        let fixture = Fixture::new(BpxConfig {
+            rfc3229_mode: true,
+            ..BpxConfig::default()
+        });
+        let path = ResourcePath::new("/api/logs".to_string());
+
+        let base: String = (0..100).map(|i| format!("log line {}\n", i)).collect();
+        fixture
+            .store
+            .set_resource(path.clone(), Bytes::from(base.clone()));
+
+        // Initial fetch: plain 200 with ETag, no BPX headers
+        let resp = fixture.get("/api/logs", &[]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(BpxHeaders::SESSION).is_none());
+        let etag = header_str(&resp, header::ETAG);
+
+        // Unchanged: 304
+        let known = [("A-IM", "binary-delta"), ("If-None-Match", etag.as_str())];
+        let resp = fixture.get("/api/logs", &known).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // Changed: 226 IM Used with Delta-Base
+        let updated = format!("{}log line 100\n", base);
+        fixture
+            .store
+            .set_resource(path.clone(), Bytes::from(updated.clone()));
+        let resp = fixture.get("/api/logs", &known).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 226);
+        assert_eq!(resp.headers()["IM"], "binary-delta");
+        assert_eq!(resp.headers()["Delta-Base"].to_str().unwrap(), etag);
+
+        let patched = fixture
+            .engine
+            .apply_diff(base.as_bytes(), resp.body())
+            .unwrap();
+        assert_eq!(patched.as_ref(), updated.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_content_type_headers() {
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/items".to_string());
+
+        let base: String = (0..100).map(|i| format!("{{\"id\":{}}}\n", i)).collect();
+        fixture
+            .store
+            .set_resource(path.clone(), Bytes::from(base.clone()));
+        fixture
+            .store
+            .set_content_type(path.clone(), "application/json");
+
+        // Full body carries the resource's media type
+        let resp = fixture.get("/api/items", &[]).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        assert!(
+            resp.headers()
+                .get(BpxHeaders::ORIGINAL_CONTENT_TYPE)
+                .is_none()
+        );
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        // Diff body is typed as a delta; the patched body type moves to X-Original-Content-Type
+        fixture.store.set_resource(
+            path.clone(),
+            Bytes::from(format!("{}{{\"id\":100}}\n", base)),
+        );
+        let resp = fixture
+            .get(
+                "/api/items",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/vnd.bpx.binary-delta"
+        );
+        assert_eq!(
+            resp.headers()[BpxHeaders::ORIGINAL_CONTENT_TYPE],
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn test_parse_bpx_request_multiple_bases() {
+        let req = Request::builder()
+            .uri("/api/test")
+            .header("X-Base-Version", "v:a, v:b,,v:c")
+            .body(())
+            .unwrap();
+
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert_eq!(bpx_req.base_version.as_ref().unwrap().to_string(), "v:a");
+        assert_eq!(
+            bpx_req
+                .base_versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>(),
+            vec!["v:a", "v:b", "v:c"]
+        );
+    }
+
+    #[test]
+    fn test_parse_bpx_request_header_caps() {
+        let parse = |name: &str, value: String| {
+            let req = Request::builder()
+                .uri("/api/test")
+                .header(name, value)
+                .body(())
+                .unwrap();
+            parse_bpx_request(&req, &BpxConfig::default())
+        };
+        let refused = |result: Result<BpxRequest, BpxError>, header: &str| matches!(result, Err(BpxError::InvalidHeader { header: h, .. }) if h == header);
+
+        assert!(parse(BpxHeaders::SESSION, "s".repeat(MAX_SESSION_ID_LEN)).is_ok());
+        assert!(refused(
+            parse(BpxHeaders::SESSION, "s".repeat(MAX_SESSION_ID_LEN + 1)),
+            BpxHeaders::SESSION
+        ));
+        // Control characters are refused even when escaped
+        assert!(refused(
+            parse(BpxHeaders::SESSION, "sess%0Ainjected".to_string()),
+            BpxHeaders::SESSION
+        ));
+        assert!(refused(
+            parse(
+                BpxHeaders::BASE_VERSION,
+                format!("v:a, {}", "v".repeat(MAX_VERSION_LEN + 1))
+            ),
+            BpxHeaders::BASE_VERSION
+        ));
+        let formats = vec!["binary-delta"; MAX_ACCEPT_DIFF_ENTRIES + 1].join(",");
+        assert!(refused(
+            parse(BpxHeaders::ACCEPT_DIFF, formats),
+            BpxHeaders::ACCEPT_DIFF
+        ));
+
+        let err = parse(BpxHeaders::SESSION, "a%00b".to_string()).unwrap_err();
+        assert_eq!(err.code(), "invalid-header");
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_multi_base_picks_smallest_diff() {
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/feed".to_string());
+
+        // Client sees an old version, then a newer one
+        fixture.store.set_resource(path.clone(), lines(50));
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let old = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        fixture.store.set_resource(path.clone(), lines(100));
+        let resp = fixture
+            .get("/api/feed", &[(BpxHeaders::SESSION, &session)])
+            .await
+            .unwrap();
+        let newer = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        // Client advertises both; the newer base yields the smaller diff
+        fixture.store.set_resource(path.clone(), lines(101));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &format!("{}, {}", old, newer)),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        assert_eq!(header_str(&resp, BpxHeaders::DELTA_BASE), newer);
+        let patched = fixture.engine.apply_diff(&lines(100), resp.body()).unwrap();
+        assert_eq!(patched, lines(101));
+    }
+
+    #[test]
+    fn test_cache_headers() {
+        let version = Version::new("v2".to_string());
+
+        let full = BpxResponse::full(version.clone(), Bytes::from_static(b"content"))
+            .with_cache_ttl(std::time::Duration::from_secs(60));
+        let resp = build_http_response_with_original_size(full, 7, &BpxConfig::default()).unwrap();
+        assert_eq!(
+            resp.headers()[header::VARY],
+            "X-BPX-Session, X-Base-Version, Accept-Diff"
+        );
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "max-age=60");
+
+        let full = BpxResponse::full(version.clone(), Bytes::from_static(b"content"));
+        let resp = build_http_response_with_original_size(full, 7, &BpxConfig::default()).unwrap();
+        assert!(resp.headers().get(header::CACHE_CONTROL).is_none());
+
+        // Diffs stay private even when a TTL is configured
+        let diff = BpxResponse::diff(version, DiffFormat::BinaryDelta, Bytes::from_static(b"d"))
+            .with_cache_ttl(std::time::Duration::from_secs(60));
+        let resp = build_http_response_with_original_size(diff, 7, &BpxConfig::default()).unwrap();
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "private");
+        assert!(resp.headers().contains_key(header::VARY));
+    }
+
+    #[tokio::test]
+    async fn test_collection_element_diffs() {
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/orders".to_string());
+        let orders = |ids: std::ops::Range<usize>, shipped: usize| -> Vec<Element> {
+            ids.map(|i| {
+                let status = if i == shipped { "shipped" } else { "pending" };
+                Element::new(
+                    i.to_string(),
+                    format!("{{\"id\":{},\"status\":\"{}\"}}\n", i, status),
+                )
+            })
+            .collect()
+        };
+
+        fixture
+            .store
+            .set_collection(path.clone(), &orders(0..50, 99))
+            .unwrap();
+        let resp = fixture.get("/api/orders", &[]).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], COLLECTION_MEDIA_TYPE);
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let base = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        let old = resp.into_body();
+
+        // One order ships, the oldest is archived, a new one arrives
+        fixture
+            .store
+            .set_collection(path.clone(), &orders(1..51, 10))
+            .unwrap();
+        let resp = fixture
+            .get(
+                "/api/orders",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &base),
+                    (BpxHeaders::ACCEPT_DIFF, "element-delta, binary-delta"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "element-delta");
+        assert_eq!(
+            resp.headers()[BpxHeaders::ORIGINAL_CONTENT_TYPE],
+            COLLECTION_MEDIA_TYPE
+        );
+        let new = ElementDiffCodec::apply_diff(&old, resp.body()).unwrap();
+        assert_eq!(new, fixture.store.get_resource(&path).await.unwrap());
+        assert_eq!(
+            ElementDiffCodec::decode_collection(&new).unwrap(),
+            orders(1..51, 10)
+        );
+        let base = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        // Clients without element diffs get byte diffs of the same content
+        fixture
+            .store
+            .set_collection(path.clone(), &orders(1..51, 11))
+            .unwrap();
+        let resp = fixture
+            .get(
+                "/api/orders",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &base),
+                    (BpxHeaders::ACCEPT_DIFF, "binary-delta"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        let patched = fixture.engine.apply_diff(&new, resp.body()).unwrap();
+        assert_eq!(patched, fixture.store.get_resource(&path).await.unwrap());
+        let base = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        // With element diffs disabled, clients accepting both get byte diffs
+        let no_elements = Fixture {
+            config: BpxConfig {
+                disabled_formats: vec![DiffFormat::ElementDelta],
+                ..fixture.config.clone()
+            },
+            ..fixture.clone()
+        };
+        fixture
+            .store
+            .set_collection(path.clone(), &orders(1..51, 12))
+            .unwrap();
+        let resp = no_elements
+            .get(
+                "/api/orders",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &base),
+                    (BpxHeaders::ACCEPT_DIFF, "element-delta, binary-delta"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_formats_are_not_negotiated() {
+        let fixture = Fixture::new(BpxConfig {
+            disabled_formats: vec![DiffFormat::BinaryDelta],
+            ..BpxConfig::default()
+        });
+        let path = ResourcePath::new("/api/feed".to_string());
+        let v1 = Bytes::from("entry\n".repeat(50));
+        fixture.store.set_resource(path.clone(), v1.clone());
+        let version = Version::from_content(&v1).to_string();
+
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        fixture
+            .store
+            .set_resource(path.clone(), Bytes::from("entry\n".repeat(51)));
+
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                    (BpxHeaders::ACCEPT_DIFF, "binary-delta"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(
+            resp.headers()[BpxHeaders::FALLBACK_REASON],
+            "format-not-accepted"
+        );
+
+        // Nor are diffs in them applied
+        let diff = fixture.engine.compute_diff(resp.body(), &v1).unwrap();
+        let base = Version::from_content(resp.body()).to_string();
+        let err = fixture
+            .write(request(
+                Method::PATCH,
+                "/api/feed",
+                &[(BpxHeaders::BASE_VERSION, &base)],
+                diff,
+            ))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BpxError::InvalidDiffFormat { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_session_cookie_transport() {
+        let fixture = Fixture::new(BpxConfig {
+            session_cookie: Some("bpx_session".to_string()),
+            ..BpxConfig::default()
+        });
+        let config = &fixture.config;
+        fixture.store.set_resource(
+            ResourcePath::new("/api/doc".to_string()),
+            Bytes::from("hello"),
+        );
+
+        let resp = fixture.get("/api/doc", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.starts_with(&format!("bpx_session={};", session)));
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(
+            resp.headers()[header::VARY]
+                .to_str()
+                .unwrap()
+                .ends_with("Cookie")
+        );
+
+        // The cookie alone identifies the session on the next request
+        let req = Request::builder()
+            .uri("/api/doc")
+            .header(
+                header::COOKIE,
+                format!("theme=dark; bpx_session={}", session),
+            )
+            .body(())
+            .unwrap();
+        let bpx_req = parse_bpx_request(&req, config).unwrap();
+        assert_eq!(bpx_req.session_id, Some(SessionId::new(session.clone())));
+
+        // Without cookie mode the cookie is ignored
+        let bpx_req = parse_bpx_request(&req, &BpxConfig::default()).unwrap();
+        assert!(bpx_req.session_id.is_none());
+
+        // An explicit header wins over the cookie
+        let req = Request::builder()
+            .uri("/api/doc")
+            .header(BpxHeaders::SESSION, "sess_header")
+            .header(header::COOKIE, format!("bpx_session={}", session))
+            .body(())
+            .unwrap();
+        let bpx_req = parse_bpx_request(&req, config).unwrap();
+        assert_eq!(
+            bpx_req.session_id,
+            Some(SessionId::new("sess_header".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_affinity_key_header_and_cookie() {
+        use crate::affinity::{AFFINITY_HEADER, AffinityConfig, affinity_key};
+
+        let fixture = Fixture::new(BpxConfig {
+            affinity: Some(AffinityConfig {
+                cookie: Some("bpx_route".to_string()),
+                ..AffinityConfig::default()
+            }),
+            ..BpxConfig::default()
+        });
+        fixture.store.set_resource(
+            ResourcePath::new("/api/doc".to_string()),
+            Bytes::from("hello"),
+        );
+
+        let resp = fixture.get("/api/doc", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let key = affinity_key(&SessionId::new(session.clone()));
+        assert_eq!(resp.headers()[AFFINITY_HEADER], key.as_str());
+        let set_cookie = resp.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.starts_with(&format!("bpx_route={};", key)));
+        assert!(!set_cookie.contains(&session));
+
+        // The key stays with the session
+        let resp = fixture
+            .get("/api/doc", &[(BpxHeaders::SESSION, &session)])
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[AFFINITY_HEADER], key.as_str());
+    }
+
+    #[test]
+    fn test_error_response_problem_json() {
+        let err = BpxError::ClientStateNotFound {
+            client_id: SessionId::new("/api/\"missing\"".to_string()),
+        };
+        let resp = error_response(&err);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            PROBLEM_JSON_MEDIA_TYPE
+        );
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.contains(r#""status":404"#));
+        assert!(body.contains(r#""code":"not-found""#));
+        assert!(body.contains(r#""title":"Not Found""#));
+        assert!(body.contains(r#"/api/\"missing\""#));
+
+        let cases = [
+            (
+                BpxError::ResourceNotFound {
+                    path: ResourcePath::new("/api/test".to_string()),
+                },
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                BpxError::VersionNotFound {
+                    path: ResourcePath::new("/api/test".to_string()),
+                    version: Version::new("v1".to_string()),
+                },
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                BpxError::InvalidRequest {
+                    reason: "bad".to_string(),
+                },
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                BpxError::ResourceTooLarge {
+                    size: 10,
+                    max_size: 5,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                BpxError::PatchTooLarge {
+                    reason: "too many operations".to_string(),
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                BpxError::BatchTooLarge {
+                    entries: 300,
+                    max_entries: 256,
+                },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                BpxError::SessionCapacityExceeded { current: 2, max: 1 },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                BpxError::DiffComputationFailed {
+                    reason: "boom".to_string(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                BpxError::ReadOnly {
+                    path: ResourcePath::new("/api/test".to_string()),
+                },
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                BpxError::Forbidden {
+                    path: ResourcePath::new("/api/test".to_string()),
+                },
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                BpxError::Overloaded { max_outstanding: 8 },
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                BpxError::MethodNotAllowed {
+                    method: Method::DELETE,
+                    path: ResourcePath::new("/api/test".to_string()),
+                    allowed: vec![Method::GET],
+                },
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                BpxError::BuilderError {
+                    reason: "bad config".to_string(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                BpxError::InvalidResponse {
+                    reason: "bad header".to_string(),
+                },
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                BpxError::UnknownTenant {
+                    path: "/api/test".to_string(),
+                },
+                StatusCode::NOT_FOUND,
+            ),
+        ];
+        for (err, status) in cases {
+            assert_eq!(error_response(&err).status(), status);
+        }
+
+        let resp = error_response(&BpxError::RateLimited {
+            retry_after: std::time::Duration::from_millis(1500),
+        });
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+
+        let resp = Response::from(BpxError::SessionCapacityExceeded { current: 2, max: 2 });
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+    }
+
+    #[tokio::test]
+    async fn test_hostile_versions_are_encoded_in_headers() {
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/feed".to_string());
+
+        let hostile = Version::new("v1\r\nSet-Cookie: evil=1, \"v2\"".to_string());
+        fixture
+            .store
+            .set_resource_versioned(path.clone(), hostile.clone(), lines(100));
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(header::SET_COOKIE));
+        let sent = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        assert_eq!(decode_value(&sent), hostile.to_string());
+        let session = header_str(&resp, BpxHeaders::SESSION);
+
+        // The encoded version names the same base when the client sends it back
+        fixture.store.set_resource_versioned(
+            path.clone(),
+            Version::new("v;2".to_string()),
+            lines(101),
+        );
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &sent),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        assert_eq!(resp.headers()[BpxHeaders::RESOURCE_VERSION], "v%3B2");
+
+        // Values that can't be encoded fail instead of going out empty
+        fixture.store.set_content_type(path, "text/plain\n");
+        assert!(matches!(
+            fixture.get("/api/feed", &[]).await,
+            Err(BpxError::InvalidResponse { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_reasons() {
+        let fixture = Fixture::default();
+        let path = ResourcePath::new("/api/feed".to_string());
+        let reason = |resp: &Response<Bytes>| {
+            resp.headers()
+                .get(BpxHeaders::FALLBACK_REASON)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+
+        fixture.store.set_resource(path.clone(), lines(100));
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("no-base"));
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+
+        // Nothing changed since the client's base
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("unchanged"));
+        assert_eq!(resp.headers()[BpxHeaders::SESSION_STATUS], "resumed");
+
+        // Client claims a version the server never sent it
+        fixture.store.set_resource(path.clone(), lines(101));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, "v:bogus"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("version-mismatch"));
+
+        // Unknown session: a fresh one has no state
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, "sess_unknown"),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("no-session-state"));
+        assert_eq!(resp.headers()[BpxHeaders::SESSION_STATUS], "created");
+
+        // Format not accepted
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                    (BpxHeaders::ACCEPT_DIFF, "json-patch"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("format-not-accepted"));
+
+        // Diffs carry no fallback reason
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        fixture.store.set_resource(path.clone(), lines(102));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        assert!(reason(&resp).is_none());
+
+        // Complete rewrite: diff is larger than worthwhile
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        fixture
+            .store
+            .set_resource(path.clone(), Bytes::from("completely different\n"));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(reason(&resp).as_deref(), Some("not-worthwhile"));
+    }
+
+    #[tokio::test]
+    async fn test_config_compression_ratio_decides() {
+        // The engine would accept any diff; the config asks for 99% savings
+        let config = BpxConfig {
+            min_compression_ratio: 0.99,
+            ..BpxConfig::default()
+        };
+        let lenient = BpxConfig {
+            min_compression_ratio: 0.0,
+            ..config.clone()
+        };
+        let path = ResourcePath::new("/api/feed".to_string());
+
+        // One line added to twenty: a diff saving well under 99%
+        for (config, expected) in [(config, "full"), (lenient, "binary-delta")] {
+            let fixture = Fixture {
+                engine: Arc::new(SimilarDiffEngine::with_compression_ratio(0.0)),
+                ..Fixture::new(config)
+            };
+            fixture.store.set_resource(path.clone(), lines(20));
+            let resp = fixture.get("/api/feed", &[]).await.unwrap();
+            let session = header_str(&resp, BpxHeaders::SESSION);
+            let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+            fixture.store.set_resource(path.clone(), lines(21));
+            let resp = fixture
+                .get(
+                    "/api/feed",
+                    &[
+                        (BpxHeaders::SESSION, &session),
+                        (BpxHeaders::BASE_VERSION, &version),
+                    ],
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], expected);
+            if expected == "full" {
+                assert_eq!(
+                    resp.headers()[BpxHeaders::FALLBACK_REASON],
+                    "not-worthwhile"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_exchange() {
+        use crate::protocol::wire::BatchRequestEntry;
+
+        let fixture = Fixture::default();
+        let paths: Vec<ResourcePath> = (0..3)
+            .map(|i| ResourcePath::new(format!("/api/widget/{}", i)))
+            .collect();
+        let bases: Vec<String> = (0..3)
+            .map(|i| {
+                (0..50)
+                    .map(|l| format!("widget {} line {}\n", i, l))
+                    .collect()
+            })
+            .collect();
+        for (path, base) in paths.iter().zip(&bases) {
+            fixture
+                .store
+                .set_resource(path.clone(), Bytes::from(base.clone()));
+        }
+
+        let batch = |headers: &[(&str, &str)], entries: Vec<BatchRequestEntry>| {
+            let body = BatchRequest { entries }.encode().unwrap();
+            fixture.batch(request(Method::POST, "/__bpx/batch", headers, body))
+        };
+
+        // First tick: everything full, plus one unknown path reported per entry
+        let mut entries: Vec<BatchRequestEntry> = paths
+            .iter()
+            .map(|p| BatchRequestEntry {
+                path: p.clone(),
+                base_version: None,
+            })
+            .collect();
+        entries.push(BatchRequestEntry {
+            path: ResourcePath::new("/api/missing".to_string()),
+            base_version: None,
+        });
+        let resp = batch(&[], entries).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], BATCH_MEDIA_TYPE);
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let first = BatchResponse::decode(resp.body()).unwrap();
+        assert_eq!(first.entries.len(), 4);
+        assert!(matches!(first.entries[0].body, Ok(ResponseBody::Full(_))));
+        assert!(first.entries[3].body.is_err());
+
+        // Second tick after one resource changed: one diff, others full (unchanged)
+        let updated = format!("{}widget 1 line 50\n", bases[1]);
+        fixture
+            .store
+            .set_resource(paths[1].clone(), Bytes::from(updated.clone()));
+        let entries = first.entries[..3]
+            .iter()
+            .map(|e| BatchRequestEntry {
+                path: e.path.clone(),
+                base_version: e.version.clone(),
+            })
+            .collect();
+        let resp = batch(&[(BpxHeaders::SESSION, &session)], entries)
+            .await
+            .unwrap();
+        let second = BatchResponse::decode(resp.body()).unwrap();
+        let diff = match &second.entries[1].body {
+            Ok(ResponseBody::Diff { data, .. }) => data.clone(),
+            other => panic!("expected diff, got {:?}", other),
+        };
+        assert_eq!(second.entries[1].original_size, updated.len());
+        let patched = fixture
+            .engine
+            .apply_diff(bases[1].as_bytes(), &diff)
+            .unwrap();
+        assert_eq!(patched.as_ref(), updated.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_authorizer() {
+        use crate::protocol::wire::BatchRequestEntry;
+
+        /// Guests may read everything but `/api/secret`, and write nothing
+        struct Guests;
+
+        #[async_trait]
+        impl Authorizer for Guests {
+            async fn authorize(&self, context: &RequestContext, path: &ResourcePath) -> Decision {
+                let guest = context.headers.get("X-User").is_some_and(|u| u == "guest");
+                if guest && (path.to_string() == "/api/secret" || context.method == Method::PUT) {
+                    Decision::Deny
+                } else {
+                    Decision::Allow
+                }
+            }
+        }
+
+        let Fixture {
+            config,
+            state_mgr,
+            engine,
+            store,
+        } = Fixture::default();
+        let secret = ResourcePath::new("/api/secret".to_string());
+        store.set_resource(secret.clone(), Bytes::from("secret"));
+        store.set_resource(
+            ResourcePath::new("/api/public".to_string()),
+            Bytes::from("public"),
+        );
+        let authorizer: Arc<dyn Authorizer> = Arc::new(Guests);
+        let guards = || Guards {
+            authorizer: Some(authorizer.clone()),
+            load: None,
+        };
+        let get = |method: Method, uri: &str, user: &str| {
+            handle_bpx_request_streaming_guarded(
+                request(method, uri, &[("X-User", user)], Bytes::new()),
+                &config,
+                state_mgr.clone(),
+                engine.clone(),
+                store.clone(),
+                guards(),
+            )
+        };
+
+        assert!(get(Method::GET, "/api/public", "guest").await.is_ok());
+        assert!(get(Method::GET, "/api/secret", "admin").await.is_ok());
+        for method in [Method::GET, Method::HEAD] {
+            let err = get(method, "/api/secret", "guest").await.unwrap_err();
+            assert!(matches!(err, BpxError::Forbidden { .. }));
+            assert_eq!(error_status(&err), StatusCode::FORBIDDEN);
+        }
+
+        // Batch entries are checked one by one
+        let entries = ["/api/public", "/api/secret"]
+            .iter()
+            .map(|path| BatchRequestEntry {
+                path: ResourcePath::new(path.to_string()),
+                base_version: None,
+            })
+            .collect();
+        let body = BatchRequest { entries }.encode().unwrap();
+        let resp = handle_batch_request_guarded(
+            request(Method::POST, "/batch", &[("X-User", "guest")], body),
+            &config,
+            state_mgr.clone(),
+            engine.clone(),
+            store.clone(),
+            guards(),
+        )
+        .await
+        .unwrap();
+        let session = SessionId::new(header_str(&resp, BpxHeaders::SESSION));
+        let batch = BatchResponse::decode(resp.body()).unwrap();
+        assert!(batch.entries[0].body.is_ok());
+        assert!(batch.entries[1].body.is_err());
+        assert!(state_mgr.get_version(&session, &secret).await.is_none());
+
+        let err = handle_write_request_guarded(
+            request(
+                Method::PUT,
+                "/api/public",
+                &[("X-User", "guest")],
+                "defaced",
+            ),
+            &config,
+            state_mgr.clone(),
+            engine.clone(),
+            store.clone(),
+            guards(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, BpxError::Forbidden { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_batch_rejects_malformed_body() {
+        let req = request(Method::POST, "/", &[], Bytes::from_static(&[0x00, 0x05]));
+        let result = Fixture::default().batch(req).await;
+        assert!(matches!(result, Err(BpxError::InvalidRequest { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_batch_entry_limit() {
+        use crate::protocol::wire::BatchRequestEntry;
+
+        let fixture = Fixture::new(BpxConfig {
+            max_batch_entries: 2,
+            ..BpxConfig::default()
+        });
+        let batch = |n: usize| {
+            let entries = (0..n)
+                .map(|i| BatchRequestEntry {
+                    path: ResourcePath::new(format!("/api/{i}")),
+                    base_version: None,
+                })
+                .collect();
+            let body = BatchRequest { entries }.encode().unwrap();
+            request(Method::POST, "/", &[], body)
+        };
+
+        assert!(fixture.batch(batch(2)).await.is_ok());
+        let err = fixture.batch(batch(3)).await.unwrap_err();
+        assert!(matches!(
+            err,
+            BpxError::BatchTooLarge {
+                entries: 3,
+                max_entries: 2
+            }
+        ));
+        assert_eq!(error_status(&err), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_store_version_failure_fails_request() {
+        /// Serves resources but can't record versions
+        struct ReadOnlyStore(InMemoryResourceStore);
+
+        #[async_trait]
+        impl ResourceStore for ReadOnlyStore {
+            async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+                self.0.get_resource(path).await
+            }
+
+            async fn get_resource_version(
+                &self,
+                path: &ResourcePath,
+                version: &Version,
+            ) -> Result<Bytes, BpxError> {
+                self.0.get_resource_version(path, version).await
+            }
+
+            async fn store_version(
+                &self,
+                _path: ResourcePath,
+                _version: Version,
+                _content: Bytes,
+            ) -> Result<(), BpxError> {
+                Err(BpxError::Storage {
+                    reason: "read-only".to_string(),
+                })
+            }
+        }
+
+        let config = BpxConfig::default();
+        let state_mgr = Arc::new(InMemoryStateManager::new(config.clone()));
+        let store = ReadOnlyStore(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.0.set_resource(path.clone(), Bytes::from("feed"));
+
+        let req = request(
+            Method::GET,
+            "/api/feed",
+            &[(BpxHeaders::SESSION, "sess_1")],
+            Bytes::new(),
+        );
+        let result = handle_bpx_request(
+            req,
+            &config,
+            state_mgr.clone(),
+            Arc::new(SimilarDiffEngine::new()),
+            Arc::new(store),
+        )
+        .await;
+        assert!(matches!(result, Err(BpxError::Storage { .. })));
+        // The session wasn't told about a version the store doesn't have
+        let snapshot = state_mgr.export().await;
+        assert!(snapshot.sessions.iter().all(|s| s.versions.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_versions_are_stored_only_when_new() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts the versions it's asked to store
+        #[derive(Default)]
+        struct CountingStore {
+            inner: InMemoryResourceStore,
+            stores: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl ResourceStore for CountingStore {
+            async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+                self.inner.get_resource(path).await
+            }
+
+            async fn get_resource_version(
+                &self,
+                path: &ResourcePath,
+                version: &Version,
+            ) -> Result<Bytes, BpxError> {
+                self.inner.get_resource_version(path, version).await
+            }
+
+            async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+                self.inner.has_version(path, version).await
+            }
+
+            async fn store_version(
+                &self,
+                path: ResourcePath,
+                version: Version,
+                content: Bytes,
+            ) -> Result<(), BpxError> {
+                self.stores.fetch_add(1, Ordering::Relaxed);
+                ResourceStore::store_version(&self.inner, path, version, content).await
+            }
+        }
+
+        async fn get(config: &BpxConfig, store: Arc<CountingStore>, session: &str) {
+            let state_mgr = Arc::new(InMemoryStateManager::new(config.clone()));
+            let req = request(
+                Method::GET,
+                "/api/feed",
+                &[(BpxHeaders::SESSION, session)],
+                Bytes::new(),
+            );
+            handle_bpx_request(
+                req,
+                config,
+                state_mgr,
+                Arc::new(SimilarDiffEngine::new()),
+                store,
+            )
+            .await
+            .unwrap();
+        }
+
+        let path = ResourcePath::new("/api/feed".to_string());
+        let config = BpxConfig::default();
+        let store = Arc::new(CountingStore::default());
+        store.inner.set_resource(path.clone(), Bytes::from("v1"));
+        for session in ["sess_1", "sess_2", "sess_3"] {
+            get(&config, store.clone(), session).await;
+        }
+        assert_eq!(store.stores.load(Ordering::Relaxed), 1);
+        assert_eq!(store.inner.version_count(), 1);
+
+        store.inner.set_resource(path.clone(), Bytes::from("v2"));
+        get(&config, store.clone(), "sess_1").await;
+        get(&config, store.clone(), "sess_2").await;
+        assert_eq!(store.stores.load(Ordering::Relaxed), 2);
+        assert_eq!(store.inner.version_count(), 2);
+
+        let config = BpxConfig {
+            version_storage: VersionStorage::Never,
+            ..BpxConfig::default()
+        };
+        store.inner.set_resource(path, Bytes::from("v3"));
+        get(&config, store.clone(), "sess_1").await;
+        assert_eq!(store.stores.load(Ordering::Relaxed), 2);
+        assert_eq!(store.inner.version_count(), 2);
     }
 
-    response
-        .body(bpx_response.body.as_bytes().clone())
-        .unwrap_or_else(|_| Response::new(Bytes::new()))
-}
+    #[tokio::test]
+    async fn test_no_history_paths_keep_nothing() {
+        let fixture = Fixture::new(BpxConfig {
+            no_history: vec!["/auth/".to_string()],
+            ..BpxConfig::default()
+        });
+        let store = &fixture.store;
+        let path = ResourcePath::new("/auth/token".to_string());
+        store.set_resource(path.clone(), Bytes::from("token: abc"));
 
-/// Trait for accessing resource storage
-#[async_trait]
-pub trait ResourceStore: Send + Sync {
-    /// Get current version of a resource
-    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError>;
+        let resp = fixture.get("/auth/token", &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        assert_eq!(store.version_count(), 0);
+        let id = SessionId::new(session.clone());
+        assert_eq!(fixture.state_mgr.get_version(&id, &path).await, None);
 
-    /// Get specific version of a resource
-    async fn get_resource_version(
-        &self,
-        path: &ResourcePath,
-        version: &Version,
-    ) -> Result<Bytes, BpxError>;
+        // A write isn't kept either, for the writer or anyone else
+        fixture
+            .write(request(
+                Method::PUT,
+                "/auth/token",
+                &[(BpxHeaders::SESSION, &session)],
+                "token: abd",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(store.version_count(), 0);
+        assert_eq!(fixture.state_mgr.get_version(&id, &path).await, None);
 
-    /// Store a specific version of a resource
-    fn store_version(&self, path: ResourcePath, version: Version, content: Bytes);
-}
+        let resp = fixture
+            .get(
+                "/auth/token",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, &version),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(resp.headers()[BpxHeaders::FALLBACK_REASON], "no-history");
+        assert_eq!(store.version_count(), 0);
+    }
 
-/// In-memory resource store implementation
-pub struct InMemoryResourceStore {
-    resources: dashmap::DashMap<String, Bytes>,
-    versions: dashmap::DashMap<String, dashmap::DashMap<String, Bytes>>,
-}
+    #[tokio::test]
+    async fn test_methods_are_checked_per_route() {
+        use crate::{PathMethods, protocol::wire::BatchRequestEntry};
 
-impl InMemoryResourceStore {
-    /// Create a new in-memory resource store
-    pub fn new() -> Self {
-        Self {
-            resources: dashmap::DashMap::new(),
-            versions: dashmap::DashMap::new(),
-        }
-    }
+        let fixture = Fixture::new(BpxConfig {
+            path_methods: vec![PathMethods {
+                prefix: "/api/mirror".to_string(),
+                methods: vec![Method::GET, Method::HEAD, Method::DELETE],
+            }],
+            ..BpxConfig::default()
+        });
+        fixture.store.set_resource(
+            ResourcePath::new("/api/doc".to_string()),
+            Bytes::from("doc"),
+        );
+        fixture.store.set_resource(
+            ResourcePath::new("/api/mirror/doc".to_string()),
+            Bytes::from("mirrored"),
+        );
+        let allow = |err: &BpxError| {
+            let resp = error_response(err);
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+            header_str(&resp, header::ALLOW)
+        };
 
-    /// Set a resource's current content
-    pub fn set_resource(&self, path: ResourcePath, content: Bytes) {
-        self.resources.insert(path.to_string(), content);
-    }
+        // A POST is not a read
+        let err = fixture
+            .read(request(Method::POST, "/api/doc", &[], Bytes::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(allow(&err), "GET, HEAD, PUT, PATCH");
 
-    /// Store a specific version of a resource
-    pub fn store_version(&self, path: ResourcePath, version: Version, content: Bytes) {
-        let path_str = path.to_string();
-        let version_str = version.to_string();
+        // The mirror is read-only; methods BPX doesn't serve aren't offered
+        let err = fixture
+            .write(request(Method::PUT, "/api/mirror/doc", &[], "new"))
+            .await
+            .unwrap_err();
+        assert_eq!(allow(&err), "GET, HEAD");
+        let read = fixture
+            .stream(request(Method::GET, "/api/mirror/doc", &[], Bytes::new()))
+            .await
+            .unwrap();
+        assert_eq!(read.status(), StatusCode::OK);
 
-        self.versions
-            .entry(path_str)
-            .or_insert_with(dashmap::DashMap::new)
-            .insert(version_str, content);
+        // Batches are POSTed
+        let batch = BatchRequest {
+            entries: vec![BatchRequestEntry {
+                path: ResourcePath::new("/api/doc".to_string()),
+                base_version: None,
+            }],
+        };
+        let err = fixture
+            .batch(request(Method::GET, "/batch", &[], batch.encode().unwrap()))
+            .await
+            .unwrap_err();
+        assert_eq!(allow(&err), "POST");
     }
 
-    /// Get all stored versions for a resource
-    pub fn get_versions(&self, path: &ResourcePath) -> Vec<Version> {
-        if let Some(versions) = self.versions.get(&path.to_string()) {
-            versions
-                .iter()
-                .map(|entry| Version::new(entry.key().clone()))
-                .collect()
-        } else {
-            Vec::new()
-        }
-    }
+    #[tokio::test]
+    async fn test_write_requests() {
+        let fixture = Fixture::default();
+        let store = &fixture.store;
+        let path = ResourcePath::new("/api/doc".to_string());
+        let write = |method: Method, headers: &[(&str, &str)], body: Bytes| {
+            fixture.write(request(method, "/api/doc", headers, body))
+        };
+        let v1 = Bytes::from("line one\nline two\nline three\n".repeat(20));
+        let mut v2 = v1.to_vec();
+        v2.extend_from_slice(b"line four\n");
+        let v2 = Bytes::from(v2);
 
-    /// Remove a resource and all its versions
-    pub fn remove_resource(&self, path: &ResourcePath) {
-        let path_str = path.to_string();
-        self.resources.remove(&path_str);
-        self.versions.remove(&path_str);
-    }
+        let resp = write(Method::PUT, &[], v1.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let version = Version::from_content(&v1);
+        assert_eq!(
+            resp.headers()[BpxHeaders::RESOURCE_VERSION],
+            version.to_string()
+        );
 
-    /// Get the total number of resources
-    pub fn resource_count(&self) -> usize {
-        self.resources.len()
-    }
+        // Upload only the diff against the version we hold
+        let diff = fixture.engine.compute_diff(&v1, &v2).unwrap();
+        assert!(diff.len() < v2.len());
+        let resp = write(
+            Method::PATCH,
+            &[("If-Match", &format!("\"{}\"", version))],
+            diff.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.get_current_resource(&path), Some(v2.clone()));
+        assert_eq!(
+            store
+                .get_resource_version(&path, &Version::from_content(&v2))
+                .await
+                .unwrap(),
+            v2
+        );
 
-    /// Get the total number of stored versions across all resources
-    pub fn version_count(&self) -> usize {
-        self.versions.iter().map(|entry| entry.value().len()).sum()
-    }
+        // A stale base is refused with the current version
+        let err = write(
+            Method::PATCH,
+            &[(BpxHeaders::BASE_VERSION, &version.to_string())],
+            diff,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, BpxError::PreconditionFailed { .. }));
+        let resp = error_response(&err);
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(
+            resp.headers()[BpxHeaders::RESOURCE_VERSION],
+            Version::from_content(&v2).to_string()
+        );
+        let err = write(
+            Method::PUT,
+            &[("If-Match", &version.to_string())],
+            v1.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, BpxError::PreconditionFailed { .. }));
 
-    /// Get current resource content (for demo purposes)
-    pub fn get_current_resource(&self, path: &ResourcePath) -> Option<Bytes> {
-        self.resources
-            .get(&path.to_string())
-            .map(|entry| entry.value().clone())
+        // PATCH needs a base, and the format must be one we apply
+        let err = write(Method::PATCH, &[], Bytes::new()).await.unwrap_err();
+        assert!(matches!(err, BpxError::InvalidRequest { .. }));
+        let err = write(
+            Method::PATCH,
+            &[
+                (BpxHeaders::BASE_VERSION, &version.to_string()),
+                (BpxHeaders::DIFF_TYPE, "json-patch"),
+            ],
+            Bytes::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, BpxError::InvalidDiffFormat { .. }));
+        assert_eq!(store.get_current_resource(&path), Some(v2));
     }
-}
 
-impl Default for InMemoryResourceStore {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+    #[tokio::test]
+    async fn test_write_patch_limits() {
+        use crate::diff::{BinaryDiffCodec, DiffOperation};
 
-#[async_trait]
-impl ResourceStore for InMemoryResourceStore {
-    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
-        self.resources
-            .get(&path.to_string())
-            .map(|entry| entry.value().clone())
-            .ok_or_else(|| BpxError::ClientStateNotFound {
-                client_id: SessionId::new(format!("resource:{}", path)),
-            })
-    }
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/doc".to_string());
+        let base = Bytes::from("0123456789");
+        let base_version = Version::from_content(&base).to_string();
+        let patch = |config: BpxConfig, diff: Bytes| {
+            store.set_resource(path.clone(), base.clone());
+            let fixture = Fixture {
+                store: store.clone(),
+                ..Fixture::new(config)
+            };
+            let req = request(
+                Method::PATCH,
+                "/api/doc",
+                &[(BpxHeaders::BASE_VERSION, &base_version)],
+                diff,
+            );
+            async move { fixture.write(req).await }
+        };
+        let diff = BinaryDiffCodec::encode_diff(&[
+            DiffOperation::Copy {
+                offset: 0,
+                length: 10,
+            },
+            DiffOperation::Insert(vec![b'x'; 100]),
+        ])
+        .unwrap();
 
-    async fn get_resource_version(
-        &self,
-        path: &ResourcePath,
-        version: &Version,
-    ) -> Result<Bytes, BpxError> {
-        let path_str = path.to_string();
-        let version_str = version.to_string();
+        let resp = patch(BpxConfig::default(), diff.clone()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
 
-        if let Some(versions) = self.versions.get(&path_str) {
-            versions
-                .get(&version_str)
-                .map(|entry| entry.value().clone())
-                .ok_or_else(|| BpxError::ClientStateNotFound {
-                    client_id: SessionId::new(format!("{}@{}", path, version)),
-                })
-        } else {
-            Err(BpxError::ClientStateNotFound {
-                client_id: SessionId::new(format!("{}@{}", path, version)),
-            })
+        for config in [
+            BpxConfig {
+                max_diff_operations: 1,
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                max_patch_output_size: 100,
+                ..BpxConfig::default()
+            },
+            BpxConfig {
+                max_insert_bytes: 99,
+                ..BpxConfig::default()
+            },
+        ] {
+            let err = patch(config, diff.clone()).await.unwrap_err();
+            assert!(matches!(err, BpxError::PatchTooLarge { .. }), "{:?}", err);
+            assert_eq!(error_status(&err), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(store.get_current_resource(&path), Some(base.clone()));
         }
-    }
 
-    fn store_version(&self, path: ResourcePath, version: Version, content: Bytes) {
-        Self::store_version(self, path, version, content)
+        // Malformed diffs stay client errors
+        let err = patch(BpxConfig::default(), Bytes::from_static(&[0xff]))
+            .await
+            .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_head_requests() {
+        let fixture = Fixture::default();
+        let store = &fixture.store;
+        let path = ResourcePath::new("/api/feed".to_string());
+        let fetch = |method: Method, headers: &[(&str, &str)]| {
+            fixture.stream(request(method, "/api/feed", headers, Bytes::new()))
+        };
 
-    #[test]
-    fn test_parse_bpx_request() {
-        let req = Request::builder()
-            .uri("/api/test")
-            .header("X-BPX-Session", "sess_123")
-            .header("X-Base-Version", "v:456")
-            .header("Accept-Diff", "binary-delta,json-patch")
-            .body(())
-            .unwrap();
+        store.set_resource(path.clone(), lines(100));
+        let resp = fetch(Method::GET, &[]).await.unwrap();
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let v1 = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        resp.into_body().collect().await.unwrap();
+        for _ in 0..50 {
+            if store.version_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
 
-        let bpx_req = parse_bpx_request(&req).unwrap();
+        store.set_resource(path.clone(), lines(101));
+        let known = [
+            (BpxHeaders::SESSION, session.as_str()),
+            (BpxHeaders::BASE_VERSION, v1.as_str()),
+            (BpxHeaders::ACCEPT_DIFF, "binary-delta"),
+        ];
+        let resp = fetch(Method::HEAD, &known).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[BpxHeaders::RESOURCE_VERSION],
+            Version::from_content(&lines(101)).to_string()
+        );
+        assert_eq!(
+            resp.headers()[BpxHeaders::ORIGINAL_SIZE],
+            lines(101).len().to_string()
+        );
+        assert_eq!(resp.headers()[BpxHeaders::SESSION_STATUS], "resumed");
+        assert!(resp.headers().get(BpxHeaders::DIFF_TYPE).is_none());
+        assert!(
+            resp.into_body()
+                .collect()
+                .await
+                .unwrap()
+                .to_bytes()
+                .is_empty()
+        );
 
-        assert_eq!(bpx_req.path.to_string(), "/api/test");
-        assert_eq!(bpx_req.session_id.as_ref().unwrap().to_string(), "sess_123");
-        assert_eq!(bpx_req.base_version.as_ref().unwrap().to_string(), "v:456");
-        assert_eq!(bpx_req.accepted_formats.len(), 2);
-        assert_eq!(bpx_req.preferred_format(), Some(DiffFormat::BinaryDelta));
+        // The session still holds the version from the GET, so a diff follows
+        let resp = fetch(Method::GET, &known).await.unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
     }
 
-    #[test]
-    fn test_parse_bpx_request_minimal() {
-        let req = Request::builder().uri("/api/minimal").body(()).unwrap();
+    #[tokio::test]
+    async fn test_event_stream() {
+        let fixture = Fixture::default();
+        let store = &fixture.store;
+        let path = ResourcePath::new("/api/feed".to_string());
+        let subscribe = |headers: &[(&str, &str)]| {
+            let headers = [&[("Accept", "text/event-stream")], headers].concat();
+            fixture.stream(request(Method::GET, "/api/feed", &headers, Bytes::new()))
+        };
+        // (id, event, data) of the next event
+        async fn next_event(body: &mut ResourceBody) -> (String, String, serde_json::Value) {
+            let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+            let text = std::str::from_utf8(&frame).unwrap();
+            let field = |name: &str| {
+                text.lines()
+                    .find_map(|line| line.strip_prefix(name))
+                    .unwrap()
+                    .to_string()
+            };
+            let data = serde_json::from_str(&field("data: ")).unwrap();
+            (field("id: "), field("event: "), data)
+        }
+        let decode = |data: &serde_json::Value| {
+            BASE64_STANDARD
+                .decode(data["data"].as_str().unwrap())
+                .unwrap()
+        };
 
-        let bpx_req = parse_bpx_request(&req).unwrap();
-        assert_eq!(bpx_req.path.to_string(), "/api/minimal");
-        assert!(bpx_req.session_id.is_none());
-        assert!(bpx_req.base_version.is_none());
-        assert_eq!(bpx_req.accepted_formats, vec![DiffFormat::BinaryDelta]); // default
+        store.set_resource(path.clone(), lines(100));
+        let resp = subscribe(&[(BpxHeaders::ACCEPT_DIFF, "binary-delta")])
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let mut body = resp.into_body();
+        let (v1, event, data) = next_event(&mut body).await;
+        assert_eq!(event, "full");
+        assert_eq!(data["fallback_reason"], "no-base");
+        assert_eq!(decode(&data), lines(100));
+
+        // Each change arrives as a diff from the previous event
+        store.set_resource(path.clone(), lines(101));
+        let (v2, event, data) = next_event(&mut body).await;
+        assert_eq!(event, "binary-delta");
+        assert_eq!(data["delta_base"], v1.as_str());
+        assert_eq!(
+            fixture
+                .engine
+                .apply_diff(&lines(100), &decode(&data))
+                .unwrap(),
+            lines(101)
+        );
+        assert_eq!(
+            fixture
+                .state_mgr
+                .get_version(&SessionId::new(session.clone()), &path)
+                .await,
+            Some(Version::new(v2.clone()))
+        );
+        drop(body);
+
+        // Reconnecting with the last event's ID waits for the next change
+        let resp = subscribe(&[
+            (BpxHeaders::SESSION, &session),
+            ("Last-Event-ID", &v2),
+            (BpxHeaders::ACCEPT_DIFF, "binary-delta"),
+        ])
+        .await
+        .unwrap();
+        let mut body = resp.into_body();
+        store.set_resource(path.clone(), lines(102));
+        let (_, event, data) = next_event(&mut body).await;
+        assert_eq!(event, "binary-delta");
+        assert_eq!(data["delta_base"], v2.as_str());
     }
 
-    #[test]
-    fn test_parse_bpx_request_invalid_headers() {
-        let req = Request::builder()
-            .uri("/api/test")
-            .header("X-BPX-Session", "sess_123")
-            .header("X-Base-Version", "v:456")
-            .header("Accept-Diff", "invalid-format,json-patch")
-            .body(())
-            .unwrap();
+    #[tokio::test]
+    async fn test_streaming_full_responses() {
+        let fixture = Fixture::default();
+        let store = &fixture.store;
+        let path = ResourcePath::new("/api/feed".to_string());
+        let fetch = |headers: &[(&str, &str)]| {
+            fixture.stream(request(Method::GET, "/api/feed", headers, Bytes::new()))
+        };
 
-        let bpx_req = parse_bpx_request(&req).unwrap();
+        store.set_resource(path.clone(), lines(100));
+        let resp = fetch(&[]).await.unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::FALLBACK_REASON], "no-base");
+        assert!(resp.headers().get(BpxHeaders::ORIGINAL_SIZE).is_none());
+        let session = header_str(&resp, BpxHeaders::SESSION);
+        let version = header_str(&resp, BpxHeaders::RESOURCE_VERSION);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, lines(100));
 
-        // Should ignore invalid format and keep valid ones
-        assert_eq!(bpx_req.accepted_formats.len(), 1);
-        assert_eq!(bpx_req.preferred_format(), Some(DiffFormat::JsonPatch));
+        // Once streamed the version is recorded, so the next request diffs
+        for _ in 0..50 {
+            if store.version_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        store.set_resource(path.clone(), lines(101));
+        let resp = fetch(&[
+            (BpxHeaders::SESSION, &session),
+            (BpxHeaders::BASE_VERSION, &version),
+            (BpxHeaders::ACCEPT_DIFF, "binary-delta"),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+        let diff = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(diff.len() < lines(101).len());
     }
 
     #[tokio::test]
@@ -486,7 +4870,7 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
-            BpxError::ClientStateNotFound { .. }
+            BpxError::ResourceNotFound { .. }
         ));
 
         // Get non-existent version should error
@@ -494,10 +4878,43 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
-            BpxError::ClientStateNotFound { .. }
+            BpxError::VersionNotFound { .. }
         ));
     }
 
+    #[tokio::test]
+    async fn test_current_version_is_hashed_once() {
+        let store = InMemoryResourceStore::new();
+        let path = ResourcePath::new("/api/feed".to_string());
+        let hashed = |store: &InMemoryResourceStore| {
+            store
+                .resources
+                .get(&path.to_string())
+                .and_then(|entry| entry.hashed.get().cloned())
+        };
+
+        store.set_resource(path.clone(), Bytes::from("v1"));
+        assert_eq!(hashed(&store), None);
+        let (_, version) = store.get_versioned_resource(&path).await.unwrap();
+        assert_eq!(version, Version::from_content(b"v1"));
+        assert_eq!(hashed(&store), Some(version.clone()));
+        let (_, again) = store.get_versioned_resource(&path).await.unwrap();
+        assert_eq!(again, version);
+
+        // New content is hashed afresh
+        store.set_resource(path.clone(), Bytes::from("v2"));
+        assert_eq!(hashed(&store), None);
+        let (_, version) = store.get_versioned_resource(&path).await.unwrap();
+        assert_eq!(version, Version::from_content(b"v2"));
+
+        // Writes already know the version they stored
+        let written = store
+            .put_resource(&path, Bytes::from("v3"), Some(&version))
+            .await
+            .unwrap();
+        assert_eq!(hashed(&store), Some(written));
+    }
+
     #[tokio::test]
     async fn test_resource_store_version_not_found() {
         let store = InMemoryResourceStore::new();
@@ -514,10 +4931,343 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
-            BpxError::ClientStateNotFound { .. }
+            BpxError::VersionNotFound { .. }
         ));
     }
 
+    #[test]
+    fn test_resource_store_retention() {
+        let store = InMemoryResourceStore::with_retention(VersionRetention {
+            max_versions_per_path: 2,
+            max_age: Duration::from_millis(50),
+            max_total_bytes: 10,
+        });
+        let path = ResourcePath::new("/api/data".to_string());
+        let other = ResourcePath::new("/api/other".to_string());
+        let version = |i: usize| Version::new(format!("v{}", i));
+
+        // Storing a third version drops the oldest
+        for i in 0..3 {
+            store.store_version(path.clone(), version(i), Bytes::from(format!("{:04}", i)));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        let mut kept = store.get_versions(&path);
+        kept.sort_by_key(|v| v.to_string());
+        assert_eq!(kept, vec![version(1), version(2)]);
+        assert_eq!(store.version_bytes(), 8);
+
+        // Over the byte cap: the oldest go first, across paths
+        store.store_version(other.clone(), version(3), Bytes::from("0003"));
+        assert_eq!(store.version_bytes(), 12);
+        store.compact();
+        assert_eq!(store.get_versions(&path), vec![version(2)]);
+        assert_eq!(store.version_bytes(), 8);
+
+        std::thread::sleep(Duration::from_millis(60));
+        store.compact();
+        assert_eq!(store.version_count(), 0);
+        assert_eq!(store.version_bytes(), 0);
+    }
+
+    #[test]
+    fn test_resource_store_pinning_and_ttl() {
+        let store = InMemoryResourceStore::with_retention(VersionRetention {
+            max_versions_per_path: 2,
+            max_age: Duration::from_millis(60),
+            max_total_bytes: 8,
+        });
+        let path = ResourcePath::new("/api/data".to_string());
+        let version = |i: usize| Version::new(format!("v{}", i));
+        let has = |i: usize| store.get_versions(&path).contains(&version(i));
+
+        store.store_version(path.clone(), version(0), Bytes::from("0000"));
+        assert!(store.pin_version(&path, &version(0)));
+        assert!(!store.pin_version(&path, &version(9)));
+        for i in 1..4 {
+            std::thread::sleep(Duration::from_millis(2));
+            store.store_version(path.clone(), version(i), Bytes::from(format!("{:04}", i)));
+        }
+        // The pinned version doesn't count towards the per-path limit
+        assert!(has(0) && has(2) && has(3) && !has(1));
+
+        // Nor is it evicted for size
+        store.compact();
+        assert!(has(0) && has(3) && !has(2));
+
+        // A short TTL overrides max_age
+        store.store_version_with_ttl(path.clone(), version(4), Bytes::from("04"), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(2));
+        store.compact();
+        assert!(!has(4));
+
+        std::thread::sleep(Duration::from_millis(70));
+        store.compact();
+        assert_eq!(store.get_versions(&path), vec![version(0)]);
+        assert!(store.unpin_version(&path, &version(0)));
+        store.compact();
+        assert_eq!(store.version_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_resource_store_deduplicates_content() {
+        let store = InMemoryResourceStore::new();
+        let path = ResourcePath::new("/api/data".to_string());
+        let other = ResourcePath::new("/api/mirror".to_string());
+        let content = Bytes::from("same bytes");
+        let v1 = Version::new("v1".to_string());
+        let v2 = Version::new("v2".to_string());
+
+        store.store_version(path.clone(), v1.clone(), content.clone());
+        store.store_version(path.clone(), v2.clone(), content.clone());
+        store.store_version(other.clone(), v1.clone(), content.clone());
+        assert_eq!(store.version_count(), 3);
+        assert_eq!(store.blob_count(), 1);
+        assert_eq!(store.version_bytes(), content.len());
+
+        // Shared content outlives the versions dropped around it
+        store.remove_resource(&path);
+        assert_eq!(
+            store.get_resource_version(&other, &v1).await.unwrap(),
+            content
+        );
+        assert_eq!(store.version_bytes(), content.len());
+
+        store.store_version(other.clone(), v1, Bytes::from("new bytes"));
+        assert_eq!(store.blob_count(), 1);
+        assert_eq!(store.version_bytes(), "new bytes".len());
+    }
+
+    #[tokio::test]
+    async fn test_resource_store_reverse_deltas() {
+        let engine = Arc::new(crate::diff::similar::SimilarDiffEngine::new());
+        let store = InMemoryResourceStore::new().with_reverse_deltas(engine);
+        let path = ResourcePath::new("/api/log".to_string());
+        let version = |i: usize| Version::new(format!("v{}", i));
+
+        let mut contents = Vec::new();
+        let mut log = String::new();
+        for i in 0..10 {
+            for line in 0..50 {
+                log.push_str(&format!("entry {} of batch {}\n", line, i));
+            }
+            contents.push(Bytes::from(log.clone()));
+            store.store_version(path.clone(), version(i), contents[i].clone());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let full: usize = contents.iter().map(Bytes::len).sum();
+        assert!(store.version_bytes() < full / 4);
+        for (i, content) in contents.iter().enumerate() {
+            assert_eq!(
+                &store
+                    .get_resource_version(&path, &version(i))
+                    .await
+                    .unwrap(),
+                content
+            );
+        }
+
+        // Returning to an older content doesn't form a cycle
+        store.store_version(path.clone(), version(10), contents[3].clone());
+        store.store_version(path.clone(), version(11), contents[9].clone());
+        for (i, content) in contents.iter().enumerate() {
+            assert_eq!(
+                &store
+                    .get_resource_version(&path, &version(i))
+                    .await
+                    .unwrap(),
+                content
+            );
+        }
+        assert_eq!(
+            store
+                .get_resource_version(&path, &version(10))
+                .await
+                .unwrap(),
+            contents[3]
+        );
+
+        store.remove_resource(&path);
+        assert_eq!(store.blob_count(), 0);
+        assert_eq!(store.version_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reverse_deltas_shared_across_paths() {
+        let engine = Arc::new(crate::diff::similar::SimilarDiffEngine::new());
+        let store = Arc::new(InMemoryResourceStore::new().with_reverse_deltas(engine));
+        let a = lines(200);
+        let b = Bytes::from([&a[..], b"tail\n"].concat());
+
+        // Paths storing the same two contents in opposite orders, at once
+        let writers: Vec<_> = (0..2)
+            .map(|n| {
+                let store = Arc::clone(&store);
+                let order = if n == 0 { [&a, &b] } else { [&b, &a] }.map(Bytes::clone);
+                std::thread::spawn(move || {
+                    let path = ResourcePath::new(format!("/api/{n}"));
+                    for i in 0..50 {
+                        let version = Version::new(format!("v{i}"));
+                        store.store_version(path.clone(), version, order[i % 2].clone());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for content in [&a, &b] {
+            let hash = Sha256::digest(content).into();
+            assert_eq!(store.content(&hash).as_ref(), Some(content));
+        }
+
+        // Nor does a chain that loops anyway hang
+        let (ha, hb): (ContentHash, ContentHash) =
+            (Sha256::digest(&a).into(), Sha256::digest(&b).into());
+        for (hash, base) in [(ha, hb), (hb, ha)] {
+            store.blobs.get_mut(&hash).unwrap().payload = Payload::Delta {
+                base,
+                diff: Bytes::new(),
+            };
+        }
+        assert_eq!(store.content(&ha), None);
+    }
+
+    #[tokio::test]
+    async fn test_application_supplied_versions() {
+        use crate::protocol::wire::BatchRequestEntry;
+
+        let fixture = Fixture::default();
+        let store = &fixture.store;
+        let path = ResourcePath::new("/api/feed".to_string());
+
+        store.set_resource_versioned(path.clone(), Version::new("rev-1".to_string()), lines(100));
+        let resp = fixture.get("/api/feed", &[]).await.unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::RESOURCE_VERSION], "rev-1");
+        let session = header_str(&resp, BpxHeaders::SESSION);
+
+        store.set_resource_versioned(path.clone(), Version::new("rev-2".to_string()), lines(101));
+        let resp = fixture
+            .get(
+                "/api/feed",
+                &[
+                    (BpxHeaders::SESSION, &session),
+                    (BpxHeaders::BASE_VERSION, "rev-1"),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[BpxHeaders::RESOURCE_VERSION], "rev-2");
+        assert_eq!(resp.headers()[BpxHeaders::DELTA_BASE], "rev-1");
+        // The version isn't a hash, so the patched content's hash comes with it
+        assert_eq!(
+            header_str(&resp, BpxHeaders::CONTENT_DIGEST),
+            Version::from_content(&lines(101)).to_string()
+        );
+
+        // Batch entries carry it too, hashed as the content was stored
+        let rev3 = Version::new("rev-3".to_string());
+        store.set_resource_versioned(path.clone(), rev3.clone(), lines(102));
+        assert_eq!(
+            store.content_digest(&path, &rev3, &Bytes::new()).await,
+            Version::from_content(&lines(102))
+        );
+        let body = BatchRequest {
+            entries: vec![BatchRequestEntry {
+                path: path.clone(),
+                base_version: Some(Version::new("rev-2".to_string())),
+            }],
+        }
+        .encode()
+        .unwrap();
+        let resp = fixture
+            .batch(request(
+                Method::POST,
+                "/batch",
+                &[(BpxHeaders::SESSION, &session)],
+                body,
+            ))
+            .await
+            .unwrap();
+        let entry = &BatchResponse::decode(resp.body()).unwrap().entries[0];
+        assert!(entry.body.as_ref().unwrap().diff_format().is_some());
+        assert_eq!(
+            entry.content_digest,
+            Some(Version::from_content(&lines(102)))
+        );
+
+        // Plain writes go back to content hashes
+        store.set_resource(path.clone(), lines(103));
+        let (_, version) = store.get_versioned_resource(&path).await.unwrap();
+        assert_eq!(version, Version::from_content(&lines(103)));
+    }
+
+    #[tokio::test]
+    async fn test_resource_store_listing() {
+        let store = InMemoryResourceStore::new();
+        let a = ResourcePath::new("/api/a".to_string());
+        let b = ResourcePath::new("/api/b".to_string());
+        store.set_resource(a.clone(), Bytes::from("a"));
+        store.set_resource(b.clone(), Bytes::from("b"));
+        for i in 0..3 {
+            store.store_version(a.clone(), Version::new(format!("v{}", i)), Bytes::from("a"));
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        let mut resources = store.list_resources().await.unwrap();
+        resources.sort_by_key(|p| p.to_string());
+        assert_eq!(resources, vec![a.clone(), b.clone()]);
+        let versions: Vec<_> = (0..3).map(|i| Version::new(format!("v{}", i))).collect();
+        assert_eq!(store.list_versions(&a).await.unwrap(), versions);
+        assert!(store.list_versions(&b).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resource_store_atomic_update() {
+        let store = Arc::new(InMemoryResourceStore::new());
+        let paths = [
+            ResourcePath::new("/api/order".to_string()),
+            ResourcePath::new("/api/order/items".to_string()),
+        ];
+        let update = |i: usize| {
+            paths.iter().fold(ResourceUpdate::new(), |update, path| {
+                update.set(path.clone(), Bytes::from(i.to_string()))
+            })
+        };
+        store.update(update(0));
+
+        let writer = {
+            let store = store.clone();
+            let updates: Vec<_> = (1..=200).map(update).collect();
+            std::thread::spawn(move || {
+                for update in updates {
+                    store.update(update);
+                }
+            })
+        };
+        for _ in 0..200 {
+            let read = store.get_versioned_resources(&paths).await;
+            let versions: Vec<_> = read.into_iter().map(|r| r.unwrap().1).collect();
+            assert_eq!(versions[0], versions[1]);
+        }
+        writer.join().unwrap();
+        assert_eq!(store.get_current_resource(&paths[1]).unwrap(), "200");
+    }
+
+    #[tokio::test]
+    async fn test_resource_store_watch() {
+        let store = InMemoryResourceStore::new();
+        let path = ResourcePath::new("/api/data".to_string());
+        store.set_resource(path.clone(), Bytes::from("v1"));
+        let mut changes = ResourceStore::watch(&store, &path).unwrap();
+
+        // Unchanged content isn't announced
+        store.set_resource(path.clone(), Bytes::from("v1"));
+        store.set_resource(path.clone(), Bytes::from("v2"));
+        let next = std::future::poll_fn(|cx| changes.as_mut().poll_next(cx)).await;
+        assert_eq!(next, Some(Version::from_content(b"v2")));
+    }
+
     #[tokio::test]
     async fn test_resource_store_store_version_via_trait() {
         let store = InMemoryResourceStore::new();
@@ -526,7 +5276,9 @@ mod tests {
         let content = Bytes::from("v1 content");
 
         // Store via trait method and then retrieve
-        ResourceStore::store_version(&store, path.clone(), v1.clone(), content.clone());
+        ResourceStore::store_version(&store, path.clone(), v1.clone(), content.clone())
+            .await
+            .unwrap();
         let retrieved = store.get_resource_version(&path, &v1).await.unwrap();
         assert_eq!(retrieved, content);
     }
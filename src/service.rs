@@ -0,0 +1,852 @@
+//! `tower` middleware serving BPX in front of any service
+
+use crate::{
+    BpxError, BpxServer, CorsConfig, ResourceStore,
+    admin::{DEBUG_PATH, SETTINGS_PATH},
+    server::{ResourceBody, body_params_request, buffered_body, has_body_params},
+    stats::{HEALTH_PATH, STATS_PATH},
+};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Method, Request, Response, header};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+#[cfg(feature = "prometheus")]
+use crate::metrics::prometheus::{METRICS_PATH, PrometheusRecorder};
+
+/// Layer answering requests for resources in a [`ResourceStore`] with BPX
+///
+/// GETs and HEADs for resources the store holds are answered by the
+/// [`BpxServer`]; those it doesn't hold (`ResourceNotFound`) and every other
+/// method pass through to the wrapped service. Each GET tried against the
+/// store may open a session, so limit them with [`Self::prefix`] when most
+/// traffic is for other routes. `POST`s carrying BPX parameters in their
+/// body ([`crate::protocol::params`]) are answered like the GETs they stand
+/// for. Batch exchanges and writes are handled once enabled
+/// with [`Self::batch_path`] and [`Self::writes`]; their bodies are
+/// consumed, so they never pass through. With [`BpxConfig::cors`] set,
+/// preflights for these routes are answered and BPX responses carry the
+/// CORS headers. With [`BpxConfig::stats_endpoints`] set, GETs for
+/// [`HEALTH_PATH`] and [`STATS_PATH`] are answered too, and with
+/// [`BpxConfig::admin_token`] set, GETs and POSTs for [`SETTINGS_PATH`]
+/// and GETs for [`DEBUG_PATH`].
+/// With the `prometheus` feature, [`Self::prometheus`] answers GETs for
+/// [`METRICS_PATH`](crate::metrics::prometheus::METRICS_PATH).
+///
+/// [`BpxConfig::cors`]: crate::BpxConfig::cors
+/// [`BpxConfig::stats_endpoints`]: crate::BpxConfig::stats_endpoints
+/// [`BpxConfig::admin_token`]: crate::BpxConfig::admin_token
+pub struct BpxLayer<R> {
+    server: Arc<BpxServer>,
+    store: Arc<R>,
+    prefix: Arc<str>,
+    batch_path: Option<Arc<str>>,
+    writes: bool,
+    #[cfg(feature = "prometheus")]
+    prometheus: Option<Arc<PrometheusRecorder>>,
+}
+
+impl<R> BpxLayer<R> {
+    /// Serve `store` through `server`
+    pub fn new(server: Arc<BpxServer>, store: Arc<R>) -> Self {
+        Self {
+            server,
+            store,
+            prefix: "/".into(),
+            batch_path: None,
+            writes: false,
+            #[cfg(feature = "prometheus")]
+            prometheus: None,
+        }
+    }
+
+    /// Only try GETs for paths starting with `prefix` against the store
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into().into();
+        self
+    }
+
+    /// Answer `POST` to `path` as a batch exchange
+    pub fn batch_path(mut self, path: impl Into<String>) -> Self {
+        self.batch_path = Some(path.into().into());
+        self
+    }
+
+    /// Answer `PUT` and `PATCH` as writes to the store
+    pub fn writes(mut self, enabled: bool) -> Self {
+        self.writes = enabled;
+        self
+    }
+
+    /// Answer GETs for [`METRICS_PATH`] with `recorder`'s metrics
+    ///
+    /// `recorder` should also be the server's
+    /// [`BpxServerBuilder::metrics`](crate::BpxServerBuilder::metrics).
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus(mut self, recorder: Arc<PrometheusRecorder>) -> Self {
+        self.prometheus = Some(recorder);
+        self
+    }
+}
+
+impl<R> Clone for BpxLayer<R> {
+    fn clone(&self) -> Self {
+        Self {
+            server: Arc::clone(&self.server),
+            store: Arc::clone(&self.store),
+            prefix: Arc::clone(&self.prefix),
+            batch_path: self.batch_path.clone(),
+            writes: self.writes,
+            #[cfg(feature = "prometheus")]
+            prometheus: self.prometheus.clone(),
+        }
+    }
+}
+
+impl<S, R> Layer<S> for BpxLayer<R> {
+    type Service = BpxService<S, R>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BpxService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Service produced by [`BpxLayer`]
+///
+/// BPX errors are answered with `application/problem+json` bodies (see
+/// [`error_response`](crate::server::error_response)); only the wrapped
+/// service's own errors are returned.
+pub struct BpxService<S, R> {
+    inner: S,
+    layer: BpxLayer<R>,
+}
+
+impl<S: Clone, R> Clone for BpxService<S, R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+/// What a request is answered with
+enum Route {
+    Health,
+    Stats,
+    Settings,
+    Debug,
+    #[cfg(feature = "prometheus")]
+    Metrics(Arc<PrometheusRecorder>),
+    Preflight,
+    Read,
+    ParamsRead,
+    Batch,
+    Write,
+    Inner,
+}
+
+impl<S, R> BpxService<S, R> {
+    fn route<B>(&self, req: &Request<B>) -> Route {
+        let method = req.method();
+        let path = req.uri().path();
+        let config = self.layer.server.config();
+        #[cfg(feature = "prometheus")]
+        if let Some(recorder) = &self.layer.prometheus
+            && method == Method::GET
+            && path == METRICS_PATH
+        {
+            return Route::Metrics(Arc::clone(recorder));
+        }
+        if config.stats_endpoints && method == Method::GET && path == HEALTH_PATH {
+            Route::Health
+        } else if config.stats_endpoints && method == Method::GET && path == STATS_PATH {
+            Route::Stats
+        } else if config.admin_token.is_some()
+            && (method == Method::GET || method == Method::POST)
+            && path == SETTINGS_PATH
+        {
+            Route::Settings
+        } else if config.admin_token.is_some() && method == Method::GET && path == DEBUG_PATH {
+            Route::Debug
+        } else if config.cors.is_some()
+            && CorsConfig::is_preflight(req)
+            && (path.starts_with(&*self.layer.prefix)
+                || self.layer.batch_path.as_deref() == Some(path))
+        {
+            Route::Preflight
+        } else if (method == Method::GET || method == Method::HEAD)
+            && path.starts_with(&*self.layer.prefix)
+        {
+            Route::Read
+        } else if method == Method::POST
+            && self.layer.batch_path.as_deref() == Some(req.uri().path())
+        {
+            Route::Batch
+        } else if has_body_params(req) && path.starts_with(&*self.layer.prefix) {
+            Route::ParamsRead
+        } else if self.layer.writes && (method == Method::PUT || method == Method::PATCH) {
+            Route::Write
+        } else {
+            Route::Inner
+        }
+    }
+}
+
+impl<S, R, B, ResBody> Service<Request<B>> for BpxService<S, R>
+where
+    S: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send + 'static,
+    R: ResourceStore + 'static,
+    B: http_body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<ResourceBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // Take the service that was driven to readiness and leave a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let route = self.route(&req);
+        let BpxLayer { server, store, .. } = self.layer.clone();
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        #[cfg(feature = "compression")]
+        let accept_encoding = (req.method() != Method::HEAD)
+            .then(|| req.headers().get(header::ACCEPT_ENCODING).cloned())
+            .flatten();
+
+        Box::pin(async move {
+            let config = server.config();
+            let cors = config.cors.as_ref();
+            let result = match route {
+                Route::Health => return Ok(server.health_response().map(buffered_body)),
+                Route::Stats => {
+                    return Ok(server.stats_response(store.as_ref()).map(buffered_body));
+                }
+                #[cfg(feature = "prometheus")]
+                Route::Metrics(recorder) => return Ok(recorder.response().map(buffered_body)),
+                Route::Settings => server
+                    .handle_settings_request(req)
+                    .await
+                    .map(|response| response.map(buffered_body)),
+                Route::Debug => server
+                    .handle_debug_request(req, store.as_ref())
+                    .await
+                    .map(|response| response.map(buffered_body)),
+                Route::Preflight => {
+                    let preflight = cors.map(|cors| cors.preflight(&req));
+                    return Ok(preflight.unwrap_or_default().map(buffered_body));
+                }
+                Route::Read => {
+                    // Reads carry no body; keep it in case the store lacks the resource
+                    let (parts, body) = req.into_parts();
+                    let read = Request::from_parts(parts.clone(), Empty::<Bytes>::new());
+                    match server.handle_request_streaming(read, store).await {
+                        Err(BpxError::ResourceNotFound { .. }) => {
+                            let req = Request::from_parts(parts, body);
+                            return inner.call(req).await.map(into_resource_body);
+                        }
+                        result => result,
+                    }
+                }
+                Route::ParamsRead => match body_params_request(req).await {
+                    Ok(read) => server.handle_request_streaming(read, store).await,
+                    Err(e) => Err(e),
+                },
+                Route::Batch => server
+                    .handle_batch_request(req, store)
+                    .await
+                    .map(|response| response.map(buffered_body)),
+                Route::Write => server
+                    .handle_write_request(req, store)
+                    .await
+                    .map(|response| response.map(buffered_body)),
+                Route::Inner => return inner.call(req).await.map(into_resource_body),
+            };
+            let mut response =
+                result.unwrap_or_else(|e| Response::<Bytes>::from(e).map(buffered_body));
+            if let Some(cors) = cors {
+                cors.apply(origin.as_ref(), response.headers_mut());
+            }
+            #[cfg(feature = "compression")]
+            if let Some(compression) = &config.compression {
+                response = compression.compress(accept_encoding.as_ref(), response);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Box a wrapped service's response body
+fn into_resource_body<ResBody>(response: Response<ResBody>) -> Response<ResourceBody>
+where
+    ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    response.map(|body| {
+        body.map_err(|e| BpxError::Transport {
+            reason: e.into().to_string(),
+        })
+        .boxed_unsync()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        BpxConfig, InMemoryResourceStore, ResourcePath, diff::similar::SimilarDiffEngine,
+        protocol::headers::BpxHeaders, state::InMemoryStateManager,
+    };
+    use http_body_util::Full;
+    use hyper::StatusCode;
+    use std::convert::Infallible;
+    use tower::ServiceExt;
+
+    async fn body(response: Response<ResourceBody>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_layer_serves_store_and_passes_the_rest() {
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(BpxConfig::default())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        store.set_resource(path.clone(), Bytes::from("feed"));
+
+        let fallback = tower::service_fn(|req: Request<Full<Bytes>>| async move {
+            let body = format!("fallback {} {}", req.method(), req.uri().path());
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body))))
+        });
+        let service = BpxLayer::new(server, store.clone())
+            .prefix("/api/")
+            .batch_path("/batch")
+            .writes(true)
+            .layer(fallback);
+        let request = |method: Method, uri: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Full::new(Bytes::from(body)))
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/api/feed", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[BpxHeaders::DIFF_TYPE], "full");
+        assert_eq!(body(response).await, "feed");
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/health", ""))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "fallback GET /health");
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/api/other", ""))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "fallback GET /api/other");
+        let response = service
+            .clone()
+            .oneshot(request(Method::DELETE, "/api/feed", ""))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "fallback DELETE /api/feed");
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::PUT, "/api/feed", "new"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.get_resource(&path).await.unwrap(), "new");
+
+        let response = service
+            .oneshot(request(Method::POST, "/batch", "not a batch"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_layer_applies_cors() {
+        let config = BpxConfig {
+            cors: Some(CorsConfig::default()),
+            ..BpxConfig::default()
+        };
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .config(config)
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+        let fallback = tower::service_fn(|_: Request<Empty<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server, store).prefix("/api/").layer(fallback);
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::ORIGIN, "https://app.example.com")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+                .body(Empty::<Bytes>::new())
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::OPTIONS, "/api/feed"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(
+            response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS)
+        );
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/api/feed"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(
+            response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS]
+                .to_str()
+                .unwrap()
+                .contains(BpxHeaders::SESSION)
+        );
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/api/missing"))
+            .await
+            .unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+
+        // Other routes are the wrapped service's to answer
+        let response = service
+            .oneshot(request(Method::OPTIONS, "/health"))
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "fallback");
+    }
+    #[tokio::test]
+    async fn test_layer_reads_parameters_from_post_bodies() {
+        use crate::protocol::params::{BodyParams, PARAMS_MEDIA_TYPE};
+
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(BpxConfig::default())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        let lines = |n: usize| -> Bytes {
+            (0..n)
+                .map(|i| format!("entry {}\n", i))
+                .collect::<String>()
+                .into()
+        };
+        store.set_resource(path.clone(), lines(100));
+        let fallback = tower::service_fn(|_: Request<Full<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server, store.clone())
+            .prefix("/api/")
+            .layer(fallback);
+        let post = |body: Bytes| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/api/feed")
+                .header(header::CONTENT_TYPE, PARAMS_MEDIA_TYPE)
+                .body(Full::new(body))
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(post(BodyParams::default().encode()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let params = BodyParams {
+            session: Some(
+                response.headers()[BpxHeaders::SESSION]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            base_version: Some(
+                response.headers()[BpxHeaders::RESOURCE_VERSION]
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            accept_diff: Some("binary-delta".to_string()),
+        };
+        assert_eq!(body(response).await, lines(100));
+        for _ in 0..50 {
+            if store.version_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+
+        store.set_resource(path, lines(101));
+        let response = service
+            .clone()
+            .oneshot(post(params.encode()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[BpxHeaders::DIFF_TYPE], "binary-delta");
+
+        let response = service
+            .clone()
+            .oneshot(post(Bytes::from("not json")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // POSTs of other bodies are the wrapped service's
+        let response = service
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/api/feed")
+                    .body(Full::new(Bytes::from("{}")))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(body(response).await, "fallback");
+    }
+
+    #[tokio::test]
+    async fn test_layer_serves_health_and_stats() {
+        let config = BpxConfig {
+            stats_endpoints: true,
+            ..BpxConfig::default()
+        };
+        let server = Arc::new(
+            BpxServer::builder()
+                .state_manager(Arc::new(InMemoryStateManager::new(config.clone())))
+                .diff_engine(Arc::new(SimilarDiffEngine::new()))
+                .config(config)
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/feed".to_string());
+        let lines = |n: usize| -> Bytes {
+            (0..n)
+                .map(|i| format!("entry {}\n", i))
+                .collect::<String>()
+                .into()
+        };
+        store.set_resource(path.clone(), lines(100));
+        let fallback = tower::service_fn(|_: Request<Empty<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server.clone(), store.clone())
+            .prefix("/api/")
+            .layer(fallback);
+        let get = |uri: &str, held: Option<(&str, &str)>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some((session, base)) = held {
+                builder = builder
+                    .header(BpxHeaders::SESSION, session)
+                    .header(BpxHeaders::BASE_VERSION, base)
+                    .header(BpxHeaders::ACCEPT_DIFF, "binary-delta");
+            }
+            builder.body(Empty::<Bytes>::new()).unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(get(STATS_PATH, None))
+            .await
+            .unwrap();
+        assert!(body(response).await.starts_with(br#"{"sessions":0,"#));
+
+        let response = service
+            .clone()
+            .oneshot(get("/api/feed", None))
+            .await
+            .unwrap();
+        let session = response.headers()[BpxHeaders::SESSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let base = response.headers()[BpxHeaders::RESOURCE_VERSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        body(response).await;
+        // The streamed version is recorded once the body has been sent
+        for _ in 0..50 {
+            if store.version_count() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        store.set_resource(path, lines(101));
+        let response = service
+            .clone()
+            .oneshot(get("/api/feed", Some((&session, &base))))
+            .await
+            .unwrap();
+        assert_ne!(response.headers()[BpxHeaders::DIFF_TYPE], "full");
+
+        let response = service
+            .clone()
+            .oneshot(get(HEALTH_PATH, None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = service.oneshot(get(STATS_PATH, None)).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let stats = body(response).await;
+        let stats = std::str::from_utf8(&stats).unwrap();
+        assert!(stats.contains(r#""sessions":1,"resources":1,"#));
+        assert!(stats.contains(r#""diffs":1,"full_responses":1,"#));
+        let snapshot = server.stats_snapshot(store.as_ref());
+        assert!(snapshot.bytes_saved > 0);
+        assert_eq!(snapshot.diff_hit_rate(), 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_layer_serves_settings() {
+        let config = BpxConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..BpxConfig::default()
+        };
+        let cache = Arc::new(crate::diff::cache::CachingDiffEngine::new(
+            Arc::new(SimilarDiffEngine::new()),
+            1024,
+        ));
+        let server = Arc::new(
+            BpxServer::builder()
+                .config(config)
+                .diff_cache(cache.clone())
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        let fallback = tower::service_fn(|_: Request<Full<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server.clone(), store).layer(fallback);
+        let request = |method: Method, token: &str, form: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(SETTINGS_PATH)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(crate::admin::ACTOR_HEADER, "alice")
+                .body(Full::new(Bytes::from(form)))
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "wrong", ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = service
+            .clone()
+            .oneshot(request(
+                Method::POST,
+                "s3cret",
+                "min_compression_ratio=0.5&diff_cache_bytes=512",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let settings = body(response).await;
+        let settings = std::str::from_utf8(&settings).unwrap();
+        assert!(settings.starts_with(
+            r#"{"min_compression_ratio":0.5,"max_diff_size":10485760,"diff_cache_bytes":512,"#
+        ));
+        assert!(settings.contains(
+            r#"{"actor":"alice","setting":"min_compression_ratio","old":0.2,"new":0.5,"#
+        ));
+        assert_eq!(server.config().min_compression_ratio, 0.5);
+        assert_eq!(cache.max_bytes(), 512);
+
+        let response = service
+            .oneshot(request(Method::POST, "s3cret", "max_diff_size=lots"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(server.setting_changes().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_layer_serves_session_debug() {
+        let config = BpxConfig {
+            admin_token: Some("s3cret".to_string()),
+            ..BpxConfig::default()
+        };
+        let server = Arc::new(BpxServer::builder().config(config).build().unwrap());
+        let store = Arc::new(InMemoryResourceStore::new());
+        let path = ResourcePath::new("/api/logs".to_string());
+        let lines = |n: usize| -> String { (0..n).map(|i| format!("log line {}\n", i)).collect() };
+        store.set_resource(path.clone(), Bytes::from(lines(100)));
+        let fallback = tower::service_fn(|_: Request<Empty<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server.clone(), store.clone()).layer(fallback);
+        let debug = |query: String, token: &str| {
+            Request::get(format!("{}?{}", DEBUG_PATH, query))
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Empty::new())
+                .unwrap()
+        };
+
+        let response = service
+            .clone()
+            .oneshot(Request::get("/api/logs").body(Empty::new()).unwrap())
+            .await
+            .unwrap();
+        let session = response.headers()[BpxHeaders::SESSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let version = response.headers()[BpxHeaders::RESOURCE_VERSION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        // The version is recorded once the body has been sent
+        body(response).await;
+        let id = crate::SessionId::new(session.clone());
+        for _ in 0..50 {
+            if server
+                .state_manager()
+                .get_version(&id, &path)
+                .await
+                .is_some()
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+        store.set_resource(path, Bytes::from(lines(101)));
+
+        let query = format!("session={}&path=/api/logs", session);
+        let response = service
+            .clone()
+            .oneshot(debug(query.clone(), "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = service
+            .clone()
+            .oneshot(debug(query, "s3cret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let report = body(response).await;
+        let report = std::str::from_utf8(&report).unwrap();
+        assert!(report.contains(&format!(
+            r#""stored_version":"{}","base_retained":true,"#,
+            version
+        )));
+        assert!(
+            report.contains(r#""next":{"outcome":"diff","format":"binary-delta","reason":null,"#)
+        );
+
+        let response = service
+            .oneshot(debug(
+                "session=unknown&path=/api/logs".to_string(),
+                "s3cret",
+            ))
+            .await
+            .unwrap();
+        let report = body(response).await;
+        let report = std::str::from_utf8(&report).unwrap();
+        assert!(report.contains(r#""stored_version":null,"base_retained":false,"#));
+        assert!(report.contains(r#""outcome":"full","format":null,"reason":"no-session-state","#));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_layer_serves_prometheus_metrics() {
+        use crate::metrics::prometheus::{METRICS_PATH, PrometheusRecorder};
+
+        let recorder = Arc::new(PrometheusRecorder::new().path_patterns(["/api/"]));
+        let server = Arc::new(
+            BpxServer::builder()
+                .metrics(recorder.clone())
+                .build()
+                .unwrap(),
+        );
+        let store = Arc::new(InMemoryResourceStore::new());
+        store.set_resource(
+            ResourcePath::new("/api/feed".to_string()),
+            Bytes::from("feed"),
+        );
+        let fallback = tower::service_fn(|_: Request<Empty<Bytes>>| async {
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("fallback"))))
+        });
+        let service = BpxLayer::new(server, store)
+            .prefix("/api/")
+            .prometheus(recorder)
+            .layer(fallback);
+        let request = |uri: &str| Request::get(uri).body(Empty::new()).unwrap();
+
+        service.clone().oneshot(request("/api/feed")).await.unwrap();
+        let response = service.oneshot(request(METRICS_PATH)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let metrics = body(response).await;
+        let metrics = std::str::from_utf8(&metrics).unwrap();
+        assert!(metrics.contains(r#"bpx_full_responses_total{path="/api/",reason="no-base"} 1"#));
+    }
+}
@@ -0,0 +1,280 @@
+//! Response signing for deployments behind untrusted intermediaries
+//!
+//! The signature covers the resource version, the diff type, the delta base
+//! and a SHA-256 digest of the body, so a proxy can neither forge a diff nor
+//! replay one against a different base. It is carried as
+//! `X-BPX-Signature: <algorithm>=<hex signature>`.
+
+use crate::{
+    BpxError,
+    protocol::headers::{BpxHeaders, DeltaHeaders},
+};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use hyper::{HeaderMap, Response, header};
+use sha2::{Digest, Sha256};
+
+/// Domain separator prefixed to every signed message
+const SIGNATURE_CONTEXT: &str = "bpx-sig-v1";
+
+/// Produces signatures over BPX responses
+pub trait ResponseSigner: Send + Sync {
+    /// Algorithm name emitted in the signature header
+    fn algorithm(&self) -> &'static str;
+
+    /// Sign a message
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Checks signatures produced by a [`ResponseSigner`]
+pub trait SignatureVerifier: Send + Sync {
+    /// Algorithm name expected in the signature header
+    fn algorithm(&self) -> &'static str;
+
+    /// Whether `signature` is valid for `message`
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// HMAC-SHA256 with a key shared between server and client
+#[derive(Clone)]
+pub struct HmacSha256Signer {
+    key: Vec<u8>,
+}
+
+impl HmacSha256Signer {
+    /// Create signer from a shared secret
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts any key length")
+    }
+}
+
+impl ResponseSigner for HmacSha256Signer {
+    fn algorithm(&self) -> &'static str {
+        "hmac-sha256"
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(message);
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+impl SignatureVerifier for HmacSha256Signer {
+    fn algorithm(&self) -> &'static str {
+        "hmac-sha256"
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let mut mac = self.mac();
+        mac.update(message);
+        // Constant-time comparison
+        mac.verify_slice(signature).is_ok()
+    }
+}
+
+/// Ed25519 signer; clients only need the public key
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Signer {
+    key: ed25519_dalek::SigningKey,
+}
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Signer {
+    /// Create signer from a signing key
+    pub fn new(key: ed25519_dalek::SigningKey) -> Self {
+        Self { key }
+    }
+
+    /// Verifier for the matching public key
+    pub fn verifier(&self) -> Ed25519Verifier {
+        Ed25519Verifier::new(self.key.verifying_key())
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl ResponseSigner for Ed25519Signer {
+    fn algorithm(&self) -> &'static str {
+        "ed25519"
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        use ed25519_dalek::Signer;
+        self.key.sign(message).to_bytes().to_vec()
+    }
+}
+
+/// Ed25519 verifier holding the server's public key
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Verifier {
+    key: ed25519_dalek::VerifyingKey,
+}
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Verifier {
+    /// Create verifier from a public key
+    pub fn new(key: ed25519_dalek::VerifyingKey) -> Self {
+        Self { key }
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl SignatureVerifier for Ed25519Verifier {
+    fn algorithm(&self) -> &'static str {
+        "ed25519"
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = ed25519_dalek::Signature::from_slice(signature) else {
+            return false;
+        };
+        self.key.verify_strict(message, &signature).is_ok()
+    }
+}
+
+/// Canonical message covering the security-relevant parts of a response
+pub fn signing_message(
+    version: &str,
+    diff_type: &str,
+    delta_base: Option<&str>,
+    body: &[u8],
+) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        SIGNATURE_CONTEXT,
+        version,
+        diff_type,
+        delta_base.unwrap_or(""),
+        hex::encode(Sha256::digest(body))
+    )
+    .into_bytes()
+}
+
+/// Build the signing message from response headers (BPX or RFC 3229 style)
+fn message_from_headers(headers: &HeaderMap, body: &[u8]) -> Vec<u8> {
+    let get = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| headers.get(*name).and_then(|v| v.to_str().ok()))
+            .map(|v| v.trim_matches('"'))
+    };
+
+    signing_message(
+        get(&[BpxHeaders::RESOURCE_VERSION, header::ETAG.as_str()]).unwrap_or(""),
+        get(&[BpxHeaders::DIFF_TYPE, DeltaHeaders::IM]).unwrap_or("full"),
+        get(&[BpxHeaders::DELTA_BASE, DeltaHeaders::DELTA_BASE]),
+        body,
+    )
+}
+
+/// Attach an `X-BPX-Signature` header to a built response
+pub fn sign_response(response: &mut Response<Bytes>, signer: &dyn ResponseSigner) {
+    let message = message_from_headers(response.headers(), response.body());
+    let value = format!(
+        "{}={}",
+        signer.algorithm(),
+        hex::encode(signer.sign(&message))
+    );
+    if let Ok(value) = value.parse() {
+        response.headers_mut().insert(BpxHeaders::SIGNATURE, value);
+    }
+}
+
+/// Check the `X-BPX-Signature` of a received response before applying it
+pub fn verify_response(
+    headers: &HeaderMap,
+    body: &[u8],
+    verifier: &dyn SignatureVerifier,
+) -> Result<(), BpxError> {
+    let invalid = |reason: &str| BpxError::InvalidSignature {
+        reason: reason.to_string(),
+    };
+
+    let value = headers
+        .get(BpxHeaders::SIGNATURE)
+        .ok_or_else(|| invalid("missing signature"))?
+        .to_str()
+        .map_err(|_| invalid("malformed signature header"))?;
+    let (algorithm, signature) = value
+        .split_once('=')
+        .ok_or_else(|| invalid("malformed signature header"))?;
+    if algorithm != verifier.algorithm() {
+        return Err(invalid("unexpected signature algorithm"));
+    }
+    let signature = hex::decode(signature).map_err(|_| invalid("malformed signature"))?;
+
+    if verifier.verify(&message_from_headers(headers, body), &signature) {
+        Ok(())
+    } else {
+        Err(invalid("signature mismatch"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_response() -> Response<Bytes> {
+        Response::builder()
+            .header(BpxHeaders::RESOURCE_VERSION, "v:2")
+            .header(BpxHeaders::DIFF_TYPE, "binary-delta")
+            .header(BpxHeaders::DELTA_BASE, "v:1")
+            .body(Bytes::from_static(b"diff bytes"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_hmac_sign_and_verify() {
+        let signer = HmacSha256Signer::new(b"secret".to_vec());
+        let mut response = diff_response();
+        sign_response(&mut response, &signer);
+
+        let header = response.headers()[BpxHeaders::SIGNATURE].to_str().unwrap();
+        assert!(header.starts_with("hmac-sha256="));
+        assert!(verify_response(response.headers(), response.body(), &signer).is_ok());
+
+        // Wrong key
+        let other = HmacSha256Signer::new(b"other".to_vec());
+        assert!(verify_response(response.headers(), response.body(), &other).is_err());
+    }
+
+    #[test]
+    fn test_tampering_detected() {
+        let signer = HmacSha256Signer::new(b"secret".to_vec());
+        let mut response = diff_response();
+        sign_response(&mut response, &signer);
+
+        // Forged body
+        assert!(verify_response(response.headers(), b"forged", &signer).is_err());
+
+        // Same body replayed against another base
+        let mut headers = response.headers().clone();
+        headers.insert(BpxHeaders::DELTA_BASE, "v:0".parse().unwrap());
+        assert!(verify_response(&headers, response.body(), &signer).is_err());
+
+        // Stripped signature
+        let mut headers = response.headers().clone();
+        headers.remove(BpxHeaders::SIGNATURE);
+        assert!(matches!(
+            verify_response(&headers, response.body(), &signer),
+            Err(BpxError::InvalidSignature { .. })
+        ));
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_ed25519_sign_and_verify() {
+        let signer = Ed25519Signer::new(ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]));
+        let mut response = diff_response();
+        sign_response(&mut response, &signer);
+
+        let verifier = signer.verifier();
+        assert!(verify_response(response.headers(), response.body(), &verifier).is_ok());
+        assert!(verify_response(response.headers(), b"forged", &verifier).is_err());
+    }
+}
@@ -0,0 +1,1245 @@
+//! Client state management
+
+use crate::{
+    BpxConfig, BpxSession, ResourcePath, SessionId, TenantId, Version,
+    clock::{self, Clock},
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "redb")]
+pub mod redb;
+mod sharded;
+mod tenant;
+mod tiered;
+
+#[cfg(feature = "redb")]
+pub use self::redb::{RedbStateManager, RedbStateOptions};
+pub use sharded::ShardedStateManager;
+pub use tenant::TenantStateManager;
+pub use tiered::{ColdSession, ColdStore, InMemoryColdStore, TieredOptions, TieredStateManager};
+
+/// Outcome of [`StateManager::get_or_create_session`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionStatus {
+    /// The presented session is known; its recorded versions still apply
+    Resumed(SessionId),
+    /// A new session was issued; any session the client presented was unknown
+    /// or expired, so nothing it holds can be trusted as a base
+    Created(SessionId),
+}
+
+impl SessionStatus {
+    /// Session the request belongs to
+    pub fn id(&self) -> &SessionId {
+        match self {
+            Self::Resumed(id) | Self::Created(id) => id,
+        }
+    }
+
+    /// Take the session ID
+    pub fn into_id(self) -> SessionId {
+        match self {
+            Self::Resumed(id) | Self::Created(id) => id,
+        }
+    }
+
+    /// Whether an existing session was resumed
+    pub fn is_resumed(&self) -> bool {
+        matches!(self, Self::Resumed(_))
+    }
+
+    /// Value emitted in `X-BPX-Session-Status`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Resumed(_) => "resumed",
+            Self::Created(_) => "created",
+        }
+    }
+}
+
+/// Copy of every session, for carrying state across a restart or to a peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateSnapshot {
+    /// Sessions live at export time
+    pub sessions: Vec<SessionSnapshot>,
+}
+
+/// One session in a [`StateSnapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// Session identifier
+    pub id: SessionId,
+    /// Time since the session was last accessed, so expiry carries over
+    /// without comparing clocks
+    pub idle: Duration,
+    /// Version recorded for each resource
+    pub versions: HashMap<ResourcePath, Version>,
+    /// Tenant owning the session, for managers that scope by tenant
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tenant: Option<TenantId>,
+}
+
+/// Trait for managing client state
+#[async_trait]
+pub trait StateManager: Send + Sync {
+    /// Resume an existing session or create a new one
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus;
+
+    /// Resume a session of `tenant` or create one in it
+    ///
+    /// A session is only resumed by the tenant that created it. Managers
+    /// without tenancy ignore `tenant`.
+    async fn get_or_create_tenant_session(
+        &self,
+        tenant: &TenantId,
+        id: Option<SessionId>,
+    ) -> SessionStatus {
+        let _ = tenant;
+        self.get_or_create_session(id).await
+    }
+
+    /// Whether `id` names a live session, without resuming or creating one
+    ///
+    /// Lets [`UnknownSessionPolicy`](crate::UnknownSessionPolicy) turn away
+    /// made-up IDs before a session is created for them. Managers that can't
+    /// tell report `true`, so every presented session counts as known.
+    async fn has_session(&self, id: &SessionId) -> bool {
+        let _ = id;
+        true
+    }
+
+    /// Whether `id` names a live session of `tenant`
+    ///
+    /// Managers without tenancy ignore `tenant`.
+    async fn has_tenant_session(&self, tenant: &TenantId, id: &SessionId) -> bool {
+        let _ = tenant;
+        self.has_session(id).await
+    }
+
+    /// Get version for a resource in a session
+    async fn get_version(&self, session: &SessionId, path: &ResourcePath) -> Option<Version>;
+
+    /// Set version for a resource in a session  
+    async fn set_version(&self, session: &SessionId, path: &ResourcePath, version: Version);
+
+    /// Get versions for several resources in a session, in the order of
+    /// `paths`
+    async fn get_versions(
+        &self,
+        session: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        let mut versions = Vec::with_capacity(paths.len());
+        for path in paths {
+            versions.push(self.get_version(session, path).await);
+        }
+        versions
+    }
+
+    /// Set versions for several resources in a session
+    async fn set_versions(&self, session: &SessionId, versions: &[(ResourcePath, Version)]) {
+        for (path, version) in versions {
+            self.set_version(session, path, version.clone()).await;
+        }
+    }
+
+    /// Set version for a resource only if the recorded one is still
+    /// `expected` (`None`: nothing recorded), as one atomic step
+    ///
+    /// Returns whether the version was replaced.
+    async fn compare_and_set_version(
+        &self,
+        session: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool;
+
+    /// Clean up expired sessions
+    async fn cleanup_expired(&self);
+
+    /// Snapshot every session
+    async fn export(&self) -> StateSnapshot;
+
+    /// Load sessions from a snapshot, replacing any with the same ID
+    ///
+    /// Sessions idle longer than the session TTL and grace period are skipped.
+    async fn import(&self, snapshot: StateSnapshot);
+
+    /// Bytes of session state currently held, across all sessions
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Sessions currently held
+    fn session_count(&self) -> usize {
+        0
+    }
+
+    /// Sessions currently held for `tenant`; managers without tenancy
+    /// report 0
+    fn tenant_session_count(&self, tenant: &TenantId) -> usize {
+        let _ = tenant;
+        0
+    }
+}
+
+/// Bytes to free so that `needed` more fit under `cap`
+///
+/// Evicts down to 90% of the cap so a full cache doesn't rescan the sessions
+/// on every write.
+pub(crate) fn bytes_to_free(cap: usize, used: usize, needed: usize) -> usize {
+    (used + needed).saturating_sub(cap - cap / 10)
+}
+
+/// Least recently used sessions whose footprints add up to `to_free`
+///
+/// `candidates` are `(id, last access, footprint)`.
+pub(crate) fn oldest_first<T: Ord>(
+    mut candidates: Vec<(SessionId, T, usize)>,
+    to_free: usize,
+) -> Vec<SessionId> {
+    candidates.sort_by(|a, b| a.1.cmp(&b.1));
+    let mut freed = 0;
+    candidates
+        .into_iter()
+        .take_while(|(_, _, footprint)| {
+            let more = freed < to_free;
+            freed += footprint;
+            more
+        })
+        .map(|(id, _, _)| id)
+        .collect()
+}
+
+fn snapshot(id: SessionId, session: &BpxSession) -> SessionSnapshot {
+    SessionSnapshot {
+        id,
+        idle: session.idle(),
+        versions: session
+            .resources
+            .iter()
+            .map(|r| (r.key().clone(), r.value().clone()))
+            .collect(),
+        tenant: None,
+    }
+}
+
+/// In-memory state manager implementation
+///
+/// Sessions are kept without locks (access times and byte counts are
+/// atomics), so every operation is safe on any tokio runtime.
+///
+/// Session state is accounted in bytes (see [`BpxSession::footprint`]) and
+/// kept under [`BpxConfig::max_memory`] by evicting the least recently used
+/// sessions. A resource that doesn't fit even then is not tracked, so its next
+/// request is answered in full.
+pub struct InMemoryStateManager {
+    sessions: DashMap<SessionId, Arc<BpxSession>>,
+    config: BpxConfig,
+    memory_used: AtomicUsize,
+    clock: Arc<dyn Clock>,
+}
+
+impl InMemoryStateManager {
+    /// Create new in-memory state manager
+    pub fn new(config: BpxConfig) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            config,
+            memory_used: AtomicUsize::new(0),
+            clock: clock::system(),
+        }
+    }
+
+    /// Time sessions with `clock` instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Touch `id` if it is known and within its grace period, returning
+    /// whether it was
+    pub(crate) fn resume(&self, id: &SessionId) -> bool {
+        let Some(session) = self.sessions.get(id).map(|s| Arc::clone(&s)) else {
+            return false;
+        };
+        if self.is_expired(&session) {
+            // Past its grace period; drop it now rather than at the next cleanup
+            if let Some((_, session)) = self
+                .sessions
+                .remove_if(id, |_, session| self.is_expired(session))
+            {
+                self.memory_used
+                    .fetch_sub(session.release(), Ordering::AcqRel);
+            }
+            return false;
+        }
+        self.prune(id, &session);
+        session.touch();
+        true
+    }
+
+    /// Whether `session` has been idle past its retention
+    fn is_expired(&self, session: &BpxSession) -> bool {
+        let idle = session.idle();
+        idle > self.config.session_retention()
+            && session
+                .resources
+                .iter()
+                .all(|r| self.config.version_expired(r.key(), idle))
+    }
+
+    /// Forget versions whose path TTL passed while `session` was idle
+    fn prune(&self, id: &SessionId, session: &BpxSession) {
+        if self.config.path_ttls.is_empty() {
+            return;
+        }
+        let idle = session.idle();
+        let expired: Vec<_> = session
+            .resources
+            .iter()
+            .filter(|r| self.config.version_expired(r.key(), idle))
+            .map(|r| r.key().clone())
+            .collect();
+        let released: usize = expired
+            .iter()
+            .map(|path| session.remove_resource(path))
+            .sum();
+        self.settle(id, session, -(released as isize));
+    }
+
+    /// Register a new session under `id`
+    pub(crate) fn create(&self, id: SessionId) -> SessionStatus {
+        let session = BpxSession::with_clock(id.clone(), Arc::clone(&self.clock));
+        self.memory_used
+            .fetch_add(session.footprint(), Ordering::AcqRel);
+        self.sessions.insert(id.clone(), Arc::new(session));
+        self.make_room(0, Some(&id));
+        SessionStatus::Created(id)
+    }
+
+    /// Remove `id`, returning its snapshot
+    pub(crate) fn take(&self, id: &SessionId) -> Option<SessionSnapshot> {
+        let (id, session) = self.sessions.remove(id)?;
+        let snapshot = snapshot(id, &session);
+        self.memory_used
+            .fetch_sub(session.release(), Ordering::AcqRel);
+        Some(snapshot)
+    }
+
+    /// Sessions idle longer than `idle`, plus the least recently used ones
+    /// beyond the first `keep`
+    pub(crate) fn spill_candidates(&self, idle: Duration, keep: usize) -> Vec<SessionId> {
+        let now = self.clock.now();
+        let (idle, active): (Vec<_>, Vec<_>) = self
+            .sessions
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.last_accessed(), 1))
+            .partition(|(_, last_accessed, _)| {
+                now.saturating_duration_since(*last_accessed) > idle
+            });
+        let excess = active.len().saturating_sub(keep);
+        idle.into_iter()
+            .map(|(id, _, _)| id)
+            .chain(oldest_first(active, excess))
+            .collect()
+    }
+
+    /// Evict least recently used sessions other than `keep` until `needed`
+    /// more bytes fit, returning whether they do
+    fn make_room(&self, needed: usize, keep: Option<&SessionId>) -> bool {
+        let cap = self.config.max_memory;
+        let used = self.memory_used.load(Ordering::Acquire);
+        if used + needed <= cap {
+            return true;
+        }
+
+        let ages = self
+            .sessions
+            .iter()
+            .filter(|entry| Some(entry.key()) != keep)
+            .map(|entry| {
+                let session = entry.value();
+                (
+                    entry.key().clone(),
+                    session.last_accessed(),
+                    session.footprint(),
+                )
+            })
+            .collect();
+        for id in oldest_first(ages, bytes_to_free(cap, used, needed)) {
+            if let Some((_, session)) = self.sessions.remove(&id) {
+                self.memory_used
+                    .fetch_sub(session.release(), Ordering::AcqRel);
+            }
+        }
+        self.memory_used.load(Ordering::Acquire) + needed <= cap
+    }
+
+    /// Record `version` for `path` in `session`, making room for it
+    fn store(
+        &self,
+        session_id: &SessionId,
+        session: &BpxSession,
+        path: &ResourcePath,
+        version: Version,
+    ) {
+        let held = match session.resources.get(path) {
+            Some(old) => BpxSession::entry_size(path, &old),
+            // Tracking one more resource would pass the per-session cap
+            None if session.resources.len() >= self.config.max_resources_per_session => return,
+            None => 0,
+        };
+        let needed = BpxSession::entry_size(path, &version).saturating_sub(held);
+
+        let delta = if self.make_room(needed, Some(session_id)) {
+            session.set_resource(path.clone(), version)
+        } else {
+            // Forget the stale version so the next request falls back to full
+            -(session.remove_resource(path) as isize)
+        };
+        self.settle(session_id, session, delta);
+    }
+
+    /// Settle accounting for a write to `session`, which may have been
+    /// removed while it ran
+    fn settle(&self, id: &SessionId, session: &BpxSession, delta: isize) {
+        if delta >= 0 {
+            self.memory_used.fetch_add(delta as usize, Ordering::AcqRel);
+        } else {
+            self.memory_used
+                .fetch_sub(delta.unsigned_abs(), Ordering::AcqRel);
+        }
+        if !self.sessions.contains_key(id) {
+            // Release whatever the remover didn't see
+            let leftover = session.memory_usage.swap(0, Ordering::AcqRel);
+            self.memory_used.fetch_sub(leftover, Ordering::AcqRel);
+        }
+    }
+}
+
+#[async_trait]
+impl StateManager for InMemoryStateManager {
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus {
+        if let Some(id) = id
+            && self.resume(&id)
+        {
+            return SessionStatus::Resumed(id);
+        }
+
+        // First request, or the session expired or doesn't exist
+        self.create(SessionId::generate())
+    }
+
+    async fn has_session(&self, id: &SessionId) -> bool {
+        self.sessions
+            .get(id)
+            .is_some_and(|session| !self.is_expired(&session))
+    }
+
+    async fn get_version(&self, session_id: &SessionId, path: &ResourcePath) -> Option<Version> {
+        let session = self.sessions.get(session_id)?;
+        session.resources.get(path).map(|v| v.clone())
+    }
+
+    async fn set_version(&self, session_id: &SessionId, path: &ResourcePath, version: Version) {
+        let Some(session) = self.sessions.get(session_id).map(|s| Arc::clone(&s)) else {
+            return;
+        };
+        self.store(session_id, &session, path, version);
+    }
+
+    async fn get_versions(
+        &self,
+        session_id: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        let Some(session) = self.sessions.get(session_id) else {
+            return vec![None; paths.len()];
+        };
+        paths
+            .iter()
+            .map(|path| session.resources.get(path).map(|v| v.clone()))
+            .collect()
+    }
+
+    async fn set_versions(&self, session_id: &SessionId, versions: &[(ResourcePath, Version)]) {
+        let Some(session) = self.sessions.get(session_id).map(|s| Arc::clone(&s)) else {
+            return;
+        };
+        for (path, version) in versions {
+            self.store(session_id, &session, path, version.clone());
+        }
+    }
+
+    async fn compare_and_set_version(
+        &self,
+        session_id: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool {
+        let Some(session) = self.sessions.get(session_id).map(|s| Arc::clone(&s)) else {
+            return false;
+        };
+        if expected.is_none() && session.resources.len() >= self.config.max_resources_per_session {
+            return false;
+        }
+        let held = expected.map_or(0, |old| BpxSession::entry_size(path, old));
+        let needed = BpxSession::entry_size(path, &new).saturating_sub(held);
+
+        if !self.make_room(needed, Some(session_id)) {
+            // Forget the stale version so the next request falls back to full
+            let released = session.remove_resource(path);
+            self.settle(session_id, &session, -(released as isize));
+            return false;
+        }
+        match session.compare_and_set_resource(path, expected, new) {
+            Some(delta) => {
+                self.settle(session_id, &session, delta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn cleanup_expired(&self) {
+        self.sessions.retain(|_, session| {
+            let expired = self.is_expired(session);
+            if expired {
+                self.memory_used
+                    .fetch_sub(session.release(), Ordering::AcqRel);
+            }
+            !expired
+        });
+        if !self.config.path_ttls.is_empty() {
+            let sessions: Vec<_> = self
+                .sessions
+                .iter()
+                .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+                .collect();
+            for (id, session) in sessions {
+                self.prune(&id, &session);
+            }
+        }
+    }
+
+    async fn export(&self) -> StateSnapshot {
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|entry| snapshot(entry.key().clone(), entry.value()))
+            .collect();
+        StateSnapshot { sessions }
+    }
+
+    async fn import(&self, snapshot: StateSnapshot) {
+        for imported in snapshot.sessions {
+            if self
+                .config
+                .session_expired(imported.idle, imported.versions.keys())
+            {
+                continue;
+            }
+            let session = BpxSession::restored_with_clock(
+                imported.id.clone(),
+                imported.idle,
+                Arc::clone(&self.clock),
+            );
+            for (path, version) in imported.versions {
+                session.set_resource(path, version);
+            }
+            self.memory_used
+                .fetch_add(session.footprint(), Ordering::AcqRel);
+            if let Some(replaced) = self.sessions.insert(imported.id, Arc::new(session)) {
+                self.memory_used
+                    .fetch_sub(replaced.release(), Ordering::AcqRel);
+            }
+        }
+        // Imported sessions count toward the cap like any other
+        self.make_room(0, None);
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::Acquire)
+    }
+
+    fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PathTtl;
+    use crate::clock::MockClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_get_or_create_session_new() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        // First request without session ID should create new session
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        assert!(session_id.to_string().starts_with("sess_"));
+        assert!(state_mgr.sessions.contains_key(&session_id));
+    }
+
+    #[tokio::test]
+    async fn test_get_or_create_session_existing() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        // Create initial session
+        let session_id1 = state_mgr.get_or_create_session(None).await.into_id();
+
+        // Request with existing session ID should return same session
+        let status = state_mgr
+            .get_or_create_session(Some(session_id1.clone()))
+            .await;
+        assert_eq!(status, SessionStatus::Resumed(session_id1));
+
+        // Should only have one session
+        assert_eq!(state_mgr.sessions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_create_session_nonexistent() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let fake_session = SessionId::new("fake_session".to_string());
+
+        // Request with non-existent session ID should create new session
+        let status = state_mgr
+            .get_or_create_session(Some(fake_session.clone()))
+            .await;
+        assert!(!status.is_resumed());
+        assert_eq!(status.as_str(), "created");
+        let new_session_id = status.into_id();
+        assert_ne!(new_session_id, fake_session);
+        assert!(state_mgr.sessions.contains_key(&new_session_id));
+    }
+
+    #[tokio::test]
+    async fn test_version_tracking() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        let version = Version::new("v1".to_string());
+
+        // Initially no version stored
+        let stored_version = state_mgr.get_version(&session_id, &path).await;
+        assert!(stored_version.is_none());
+
+        // Set version
+        state_mgr
+            .set_version(&session_id, &path, version.clone())
+            .await;
+
+        // Retrieve version
+        let stored_version = state_mgr.get_version(&session_id, &path).await;
+        assert_eq!(stored_version, Some(version));
+    }
+
+    #[tokio::test]
+    async fn test_version_tracking_multiple_resources() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path1 = ResourcePath::new("/api/users".to_string());
+        let path2 = ResourcePath::new("/api/orders".to_string());
+        let version1 = Version::new("v1".to_string());
+        let version2 = Version::new("v2".to_string());
+
+        // Set versions for different resources
+        state_mgr
+            .set_version(&session_id, &path1, version1.clone())
+            .await;
+        state_mgr
+            .set_version(&session_id, &path2, version2.clone())
+            .await;
+
+        // Both should be retrievable
+        assert_eq!(
+            state_mgr.get_version(&session_id, &path1).await,
+            Some(version1)
+        );
+        assert_eq!(
+            state_mgr.get_version(&session_id, &path2).await,
+            Some(version2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bulk_versions() {
+        let config = BpxConfig {
+            max_resources_per_session: 2,
+            ..BpxConfig::default()
+        };
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let paths = ["/a", "/b", "/c"].map(|p| ResourcePath::new(p.to_string()));
+        let versions: Vec<_> = paths
+            .iter()
+            .map(|path| (path.clone(), Version::new(format!("v{}", path))))
+            .collect();
+
+        state_mgr.set_versions(&session_id, &versions).await;
+        let stored = state_mgr.get_versions(&session_id, &paths).await;
+        // The third resource passes the per-session cap
+        assert_eq!(
+            stored,
+            vec![
+                Some(versions[0].1.clone()),
+                Some(versions[1].1.clone()),
+                None
+            ]
+        );
+
+        let unknown = SessionId::new("unknown".to_string());
+        assert_eq!(
+            state_mgr.get_versions(&unknown, &paths).await,
+            vec![None, None, None]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_version_overwrite() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        let version1 = Version::new("v1".to_string());
+        let version2 = Version::new("v2".to_string());
+
+        // Set initial version
+        state_mgr
+            .set_version(&session_id, &path, version1.clone())
+            .await;
+        assert_eq!(
+            state_mgr.get_version(&session_id, &path).await,
+            Some(version1)
+        );
+
+        // Overwrite with new version
+        state_mgr
+            .set_version(&session_id, &path, version2.clone())
+            .await;
+        assert_eq!(
+            state_mgr.get_version(&session_id, &path).await,
+            Some(version2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_version_nonexistent_session() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let fake_session = SessionId::new("fake_session".to_string());
+        let path = ResourcePath::new("/api/test".to_string());
+
+        // Should return None for non-existent session
+        let version = state_mgr.get_version(&fake_session, &path).await;
+        assert!(version.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_version_nonexistent_session() {
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config);
+
+        let fake_session = SessionId::new("fake_session".to_string());
+        let path = ResourcePath::new("/api/test".to_string());
+        let version = Version::new("v1".to_string());
+
+        // Setting version for non-existent session should not crash
+        state_mgr.set_version(&fake_session, &path, version).await;
+
+        // Session should not be created
+        assert!(!state_mgr.sessions.contains_key(&fake_session));
+    }
+
+    #[tokio::test]
+    async fn test_session_touch_on_access() {
+        let clock = Arc::new(MockClock::new());
+        let config = BpxConfig::default();
+        let state_mgr = InMemoryStateManager::new(config).clock(clock.clone());
+
+        // Create session
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+
+        // Get initial timestamp
+        let initial_time = {
+            let session = state_mgr.sessions.get(&session_id).unwrap();
+            session.last_accessed()
+        };
+
+        clock.advance(Duration::from_millis(10));
+
+        // Access session again
+        let _same_session = state_mgr
+            .get_or_create_session(Some(session_id.clone()))
+            .await;
+
+        // Timestamp should be updated
+        let updated_time = {
+            let session = state_mgr.sessions.get(&session_id).unwrap();
+            session.last_accessed()
+        };
+
+        assert!(updated_time > initial_time);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::field_reassign_with_default)]
+    async fn test_cleanup_expired_sessions() {
+        let clock = Arc::new(MockClock::new());
+        let mut config = BpxConfig::default();
+        config.session_ttl = Duration::from_millis(50); // Very short TTL for testing
+        let state_mgr = InMemoryStateManager::new(config).clock(clock.clone());
+
+        // Create a session
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        assert_eq!(state_mgr.sessions.len(), 1);
+
+        clock.advance(Duration::from_millis(100));
+
+        // Run cleanup
+        state_mgr.cleanup_expired().await;
+
+        // Session should be removed
+        assert_eq!(state_mgr.sessions.len(), 0);
+        assert!(!state_mgr.sessions.contains_key(&session_id));
+    }
+
+    #[tokio::test]
+    async fn test_grace_period_revives_stale_sessions() {
+        let clock = Arc::new(MockClock::new());
+        let config = BpxConfig {
+            session_ttl: Duration::from_millis(30),
+            session_grace: Duration::from_millis(200),
+            ..BpxConfig::default()
+        };
+        let state_mgr = InMemoryStateManager::new(config).clock(clock.clone());
+        let path = ResourcePath::new("/api/test".to_string());
+        let version = Version::new("v1".to_string());
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        state_mgr
+            .set_version(&session_id, &path, version.clone())
+            .await;
+
+        // Past the TTL but inside the grace period: kept and revived
+        clock.advance(Duration::from_millis(60));
+        state_mgr.cleanup_expired().await;
+        assert_eq!(
+            state_mgr
+                .get_or_create_session(Some(session_id.clone()))
+                .await,
+            SessionStatus::Resumed(session_id.clone())
+        );
+        assert_eq!(
+            state_mgr.get_version(&session_id, &path).await,
+            Some(version)
+        );
+
+        // Past the grace period: a new session, even before cleanup runs
+        clock.advance(Duration::from_millis(250));
+        let status = state_mgr
+            .get_or_create_session(Some(session_id.clone()))
+            .await;
+        assert!(!status.is_resumed());
+        assert!(!state_mgr.sessions.contains_key(&session_id));
+        assert_eq!(state_mgr.sessions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_path_ttls() {
+        let clock = Arc::new(MockClock::new());
+        let config = BpxConfig {
+            session_ttl: Duration::from_millis(50),
+            path_ttls: vec![
+                PathTtl {
+                    prefix: "/hot".to_string(),
+                    ttl: Duration::from_millis(20),
+                },
+                PathTtl {
+                    prefix: "/stable".to_string(),
+                    ttl: Duration::from_secs(60),
+                },
+            ],
+            ..BpxConfig::default()
+        };
+        let state_mgr = InMemoryStateManager::new(config).clock(clock.clone());
+        let hot = ResourcePath::new("/hot/ticker".to_string());
+        let stable = ResourcePath::new("/stable/catalog".to_string());
+        let version = Version::new("v1".to_string());
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let fresh = state_mgr.memory_usage();
+        state_mgr
+            .set_versions(
+                &session_id,
+                &[
+                    (hot.clone(), version.clone()),
+                    (stable.clone(), version.clone()),
+                ],
+            )
+            .await;
+
+        // Past the session TTL, but the stable version keeps the session
+        clock.advance(Duration::from_millis(80));
+        state_mgr.cleanup_expired().await;
+        assert!(
+            state_mgr
+                .get_or_create_session(Some(session_id.clone()))
+                .await
+                .is_resumed()
+        );
+        assert_eq!(state_mgr.get_version(&session_id, &hot).await, None);
+        assert_eq!(
+            state_mgr.get_version(&session_id, &stable).await,
+            Some(version)
+        );
+        assert_eq!(
+            state_mgr.memory_usage(),
+            fresh + BpxSession::entry_size(&stable, &Version::new("v1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    #[allow(clippy::field_reassign_with_default)]
+    async fn test_cleanup_keeps_active_sessions() {
+        let clock = Arc::new(MockClock::new());
+        let mut config = BpxConfig::default();
+        config.session_ttl = Duration::from_millis(100);
+        let state_mgr = InMemoryStateManager::new(config).clock(clock.clone());
+
+        // Create two sessions
+        let session_id1 = state_mgr.get_or_create_session(None).await.into_id();
+        let session_id2 = state_mgr.get_or_create_session(None).await.into_id();
+        assert_eq!(state_mgr.sessions.len(), 2);
+
+        // Wait a bit, then access one session to keep it active
+        clock.advance(Duration::from_millis(60));
+        let _active_session = state_mgr
+            .get_or_create_session(Some(session_id1.clone()))
+            .await;
+
+        // Wait for the other session to expire
+        clock.advance(Duration::from_millis(60));
+
+        // Run cleanup
+        state_mgr.cleanup_expired().await;
+
+        // Only the inactive session should be removed
+        assert_eq!(state_mgr.sessions.len(), 1);
+        assert!(state_mgr.sessions.contains_key(&session_id1));
+        assert!(!state_mgr.sessions.contains_key(&session_id2));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_session_creation() {
+        let config = BpxConfig::default();
+        let state_mgr = Arc::new(InMemoryStateManager::new(config));
+
+        let mut handles = vec![];
+
+        // Create multiple concurrent sessions
+        for _ in 0..10 {
+            let mgr = Arc::clone(&state_mgr);
+            let handle =
+                tokio::spawn(async move { mgr.get_or_create_session(None).await.into_id() });
+            handles.push(handle);
+        }
+
+        // Wait for all to complete
+        let mut session_ids = vec![];
+        for handle in handles {
+            session_ids.push(handle.await.expect("Task should complete"));
+        }
+
+        // All sessions should be unique
+        let unique_count = session_ids
+            .iter()
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+        assert_eq!(unique_count, session_ids.len());
+        assert_eq!(state_mgr.sessions.len(), session_ids.len());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_version_updates() {
+        let config = BpxConfig::default();
+        let state_mgr = Arc::new(InMemoryStateManager::new(config));
+
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+
+        let mut handles = vec![];
+
+        // Create multiple concurrent version updates
+        for i in 0..10 {
+            let mgr = Arc::clone(&state_mgr);
+            let session = session_id.clone();
+            let path = path.clone();
+            let handle = tokio::spawn(async move {
+                let version = Version::new(format!("v{}", i));
+                mgr.set_version(&session, &path, version).await;
+            });
+            handles.push(handle);
+        }
+
+        // Wait for all updates to complete
+        for handle in handles {
+            handle.await.expect("Update should complete");
+        }
+
+        // Final version should be one of the values (race condition is OK)
+        let final_version = state_mgr.get_version(&session_id, &path).await;
+        assert!(final_version.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_memory_accounting() {
+        let clock = Arc::new(MockClock::new());
+        let state_mgr = InMemoryStateManager::new(BpxConfig {
+            session_ttl: Duration::from_millis(50),
+            ..BpxConfig::default()
+        })
+        .clock(clock.clone());
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let empty = state_mgr.memory_usage();
+        assert!(empty > 0);
+
+        let path = ResourcePath::new("/api/test".to_string());
+        let v1 = Version::new("v1".to_string());
+        state_mgr.set_version(&session_id, &path, v1.clone()).await;
+        assert_eq!(
+            state_mgr.memory_usage(),
+            empty + BpxSession::entry_size(&path, &v1)
+        );
+
+        // Replacing a version charges only the difference
+        let v2 = Version::new("v2-longer".to_string());
+        state_mgr.set_version(&session_id, &path, v2.clone()).await;
+        assert_eq!(
+            state_mgr.memory_usage(),
+            empty + BpxSession::entry_size(&path, &v2)
+        );
+
+        clock.advance(Duration::from_millis(100));
+        state_mgr.cleanup_expired().await;
+        assert_eq!(state_mgr.memory_usage(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_cap_evicts_least_recently_used() {
+        let clock = Arc::new(MockClock::new());
+        let probe = InMemoryStateManager::new(BpxConfig::default());
+        probe.get_or_create_session(None).await.into_id();
+        let per_session = probe.memory_usage();
+
+        let state_mgr = InMemoryStateManager::new(BpxConfig {
+            max_memory: per_session * 5,
+            ..BpxConfig::default()
+        })
+        .clock(clock.clone());
+        let oldest = state_mgr.get_or_create_session(None).await.into_id();
+        clock.advance(Duration::from_millis(2));
+        let recent = state_mgr.get_or_create_session(None).await.into_id();
+        clock.advance(Duration::from_millis(2));
+        state_mgr.get_or_create_session(Some(oldest.clone())).await;
+        clock.advance(Duration::from_millis(2));
+
+        // Filling the cap evicts `recent`, the session touched longest ago
+        let path = ResourcePath::new("/api/test".to_string());
+        let newest = state_mgr.get_or_create_session(None).await.into_id();
+        // Sized so that evicting one session brings usage under 90% of the cap
+        let overhead = BpxSession::entry_size(&path, &Version::new(String::new()));
+        let big = Version::new("v".repeat(per_session * 9 / 4 - overhead));
+        state_mgr.set_version(&newest, &path, big.clone()).await;
+        assert!(!state_mgr.sessions.contains_key(&recent));
+        assert!(state_mgr.sessions.contains_key(&oldest));
+        assert_eq!(state_mgr.get_version(&newest, &path).await, Some(big));
+        assert!(state_mgr.memory_usage() <= per_session * 5);
+    }
+
+    #[tokio::test]
+    async fn test_memory_cap_refuses_oversized_resource() {
+        let state_mgr = InMemoryStateManager::new(BpxConfig {
+            max_memory: 1024,
+            ..BpxConfig::default()
+        });
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        state_mgr
+            .set_version(&session_id, &path, Version::new("v1".to_string()))
+            .await;
+        let before = state_mgr.memory_usage();
+
+        // Dropping the old version makes the next request a full one
+        let huge = Version::new("v".repeat(2048));
+        state_mgr.set_version(&session_id, &path, huge).await;
+        assert!(state_mgr.get_version(&session_id, &path).await.is_none());
+        assert!(state_mgr.memory_usage() < before);
+        assert!(state_mgr.sessions.contains_key(&session_id));
+    }
+
+    #[test]
+    fn test_oldest_first() {
+        let id = |s: &str| SessionId::new(s.to_string());
+        let candidates = vec![(id("b"), 2, 10), (id("a"), 1, 10), (id("c"), 3, 10)];
+        assert_eq!(oldest_first(candidates.clone(), 0), Vec::<SessionId>::new());
+        assert_eq!(oldest_first(candidates.clone(), 15), vec![id("a"), id("b")]);
+        assert_eq!(oldest_first(candidates, 100).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_set_version() {
+        let state_mgr = InMemoryStateManager::new(BpxConfig::default());
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        let v1 = Version::new("v1".to_string());
+        let v2 = Version::new("v2".to_string());
+
+        // Nothing recorded yet
+        assert!(
+            !state_mgr
+                .compare_and_set_version(&session_id, &path, Some(&v1), v2.clone())
+                .await
+        );
+        assert!(
+            state_mgr
+                .compare_and_set_version(&session_id, &path, None, v1.clone())
+                .await
+        );
+        assert!(
+            !state_mgr
+                .compare_and_set_version(&session_id, &path, None, v2.clone())
+                .await
+        );
+        assert!(
+            state_mgr
+                .compare_and_set_version(&session_id, &path, Some(&v1), v2.clone())
+                .await
+        );
+        assert_eq!(state_mgr.get_version(&session_id, &path).await, Some(v2));
+
+        let unknown = SessionId::new("unknown".to_string());
+        assert!(
+            !state_mgr
+                .compare_and_set_version(&unknown, &path, None, v1)
+                .await
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_compare_and_set() {
+        let state_mgr = Arc::new(InMemoryStateManager::new(BpxConfig::default()));
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        let base = Version::new("v0".to_string());
+        state_mgr
+            .set_version(&session_id, &path, base.clone())
+            .await;
+
+        // Every request read `v0`; only one may record its response
+        let handles = (0..10)
+            .map(|i| {
+                let mgr = Arc::clone(&state_mgr);
+                let (session, path, base) = (session_id.clone(), path.clone(), base.clone());
+                tokio::spawn(async move {
+                    mgr.compare_and_set_version(
+                        &session,
+                        &path,
+                        Some(&base),
+                        Version::new(format!("v{}", i + 1)),
+                    )
+                    .await
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut won = 0;
+        for handle in handles {
+            won += usize::from(handle.await.unwrap());
+        }
+        assert_eq!(won, 1);
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let clock = Arc::new(MockClock::new());
+        let source = InMemoryStateManager::new(BpxConfig::default()).clock(clock.clone());
+        let session_id = source.get_or_create_session(None).await.into_id();
+        let path = ResourcePath::new("/api/test".to_string());
+        let version = Version::new("v1".to_string());
+        source
+            .set_version(&session_id, &path, version.clone())
+            .await;
+
+        let snapshot = source.export().await;
+        assert_eq!(snapshot.sessions.len(), 1);
+        assert_eq!(snapshot.sessions[0].versions[&path], version);
+
+        let target = InMemoryStateManager::new(BpxConfig::default());
+        target.import(snapshot.clone()).await;
+        assert_eq!(
+            target.get_or_create_session(Some(session_id.clone())).await,
+            SessionStatus::Resumed(session_id.clone())
+        );
+        assert_eq!(target.get_version(&session_id, &path).await, Some(version));
+        assert_eq!(target.memory_usage(), source.memory_usage());
+
+        // Sessions that would already have expired stay behind
+        let short_ttl = InMemoryStateManager::new(BpxConfig {
+            session_ttl: Duration::from_millis(1),
+            ..BpxConfig::default()
+        });
+        clock.advance(Duration::from_millis(5));
+        short_ttl.import(source.export().await).await;
+        assert!(short_ttl.sessions.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_snapshot_serde_roundtrip() {
+        let state_mgr = InMemoryStateManager::new(BpxConfig::default());
+        let session_id = state_mgr.get_or_create_session(None).await.into_id();
+        state_mgr
+            .set_version(
+                &session_id,
+                &ResourcePath::new("/api/test".to_string()),
+                Version::new("v1".to_string()),
+            )
+            .await;
+
+        let snapshot = state_mgr.export().await;
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(json.contains("\"/api/test\":\"v1\""));
+        let decoded: StateSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, snapshot);
+    }
+}
@@ -0,0 +1,782 @@
+//! Disk-backed state manager on an embedded redb database
+//!
+//! Sessions live in memory for lookups and are mirrored to disk by a writer
+//! thread that batches updates into one transaction, so a restarted
+//! single-node server keeps serving diffs to clients it already knew.
+
+use super::{
+    SessionSnapshot, SessionStatus, StateManager, StateSnapshot, bytes_to_free, oldest_first,
+};
+use crate::{BpxConfig, BpxError, BpxSession, ResourcePath, SessionId, Version};
+use ::redb::{Database, ReadableTable, TableDefinition};
+use async_trait::async_trait;
+use dashmap::{DashMap, mapref::entry::Entry};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Session ID -> last access (unix milliseconds)
+const SESSIONS: TableDefinition<&str, u64> = TableDefinition::new("sessions");
+/// `"{session}\0{path}"` -> version
+const VERSIONS: TableDefinition<&str, &str> = TableDefinition::new("versions");
+
+/// Tuning for [`RedbStateManager`]'s writer thread
+#[derive(Debug, Clone)]
+pub struct RedbStateOptions {
+    /// Most updates written in one transaction
+    pub batch_size: usize,
+    /// How long the first update of a batch waits for more to arrive
+    pub batch_window: Duration,
+    /// Time between compactions of the database file
+    pub compaction_interval: Duration,
+}
+
+impl Default for RedbStateOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 1_024,
+            batch_window: Duration::from_millis(10),
+            compaction_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Update queued for the writer thread
+enum Op {
+    Touch { session: String, at: u64 },
+    SetVersion { key: String, version: String },
+    RemoveVersion { key: String },
+    RemoveSession { session: String },
+    Flush(Sender<Result<(), BpxError>>),
+}
+
+struct StoredSession {
+    versions: DashMap<ResourcePath, Version>,
+    last_accessed: AtomicU64,
+    memory_usage: AtomicUsize,
+}
+
+impl StoredSession {
+    fn new(at: u64) -> Self {
+        Self {
+            versions: DashMap::new(),
+            last_accessed: AtomicU64::new(at),
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    /// Track `version` for `path` if `accept` approves the tracked one,
+    /// returning the change in memory usage
+    ///
+    /// `persist` runs while the entry is locked, so writes to one path reach
+    /// the writer thread in the order they were applied.
+    fn set_if(
+        &self,
+        path: &ResourcePath,
+        accept: impl Fn(Option<&Version>) -> bool,
+        version: Version,
+        persist: impl FnOnce(&Version),
+    ) -> Option<isize> {
+        let added = BpxSession::entry_size(path, &version);
+        let removed = match self.versions.entry(path.clone()) {
+            Entry::Occupied(mut entry) if accept(Some(entry.get())) => {
+                persist(&version);
+                BpxSession::entry_size(path, &entry.insert(version))
+            }
+            Entry::Vacant(entry) if accept(None) => {
+                persist(&version);
+                entry.insert(version);
+                0
+            }
+            _ => return None,
+        };
+        self.memory_usage.fetch_add(added, Ordering::Relaxed);
+        self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+        Some(added as isize - removed as isize)
+    }
+
+    fn remove(&self, path: &ResourcePath) -> usize {
+        let removed = self
+            .versions
+            .remove(path)
+            .map_or(0, |(path, version)| BpxSession::entry_size(&path, &version));
+        self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+        removed
+    }
+
+    fn idle(&self, now: u64) -> Duration {
+        Duration::from_millis(now.saturating_sub(self.last_accessed.load(Ordering::Relaxed)))
+    }
+
+    fn footprint(&self, id: &SessionId) -> usize {
+        std::mem::size_of::<Self>()
+            + id.to_string().len()
+            + self.memory_usage.load(Ordering::Relaxed)
+    }
+}
+
+struct Writer {
+    sender: Sender<Op>,
+    handle: JoinHandle<()>,
+}
+
+/// State manager persisting sessions to a redb database file
+///
+/// Reads are served from memory; writes reach the disk asynchronously in
+/// batches. Call [`flush`](Self::flush) to wait for them. Sessions evicted to
+/// stay under [`BpxConfig::max_memory`] are deleted from disk as well.
+pub struct RedbStateManager {
+    sessions: DashMap<SessionId, Arc<StoredSession>>,
+    config: BpxConfig,
+    memory_used: AtomicUsize,
+    writer: Option<Writer>,
+}
+
+fn storage_error(e: impl Into<::redb::Error>) -> BpxError {
+    BpxError::Storage {
+        reason: e.into().to_string(),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn version_key(session: &str, path: &str) -> String {
+    format!("{}\0{}", session, path)
+}
+
+impl RedbStateManager {
+    /// Open (or create) the database at `path` with default options
+    pub fn open(path: impl AsRef<Path>, config: BpxConfig) -> Result<Self, BpxError> {
+        Self::open_with_options(path, config, RedbStateOptions::default())
+    }
+
+    /// Open (or create) the database at `path`
+    pub fn open_with_options(
+        path: impl AsRef<Path>,
+        config: BpxConfig,
+        options: RedbStateOptions,
+    ) -> Result<Self, BpxError> {
+        let db = Database::create(path).map_err(storage_error)?;
+        let sessions = load(&db)?;
+        let memory_used = sessions
+            .iter()
+            .map(|entry| entry.value().footprint(entry.key()))
+            .sum();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("bpx-redb-writer".to_string())
+            .spawn(move || write_loop(db, receiver, options))
+            .map_err(|e| BpxError::Storage {
+                reason: e.to_string(),
+            })?;
+
+        Ok(Self {
+            sessions,
+            config,
+            memory_used: AtomicUsize::new(memory_used),
+            writer: Some(Writer { sender, handle }),
+        })
+    }
+
+    /// Wait until every queued update is on disk
+    ///
+    /// Returns the first write that failed since the previous flush.
+    pub fn flush(&self) -> Result<(), BpxError> {
+        let (ack, done) = mpsc::channel();
+        self.send(Op::Flush(ack));
+        done.recv().unwrap_or_else(|_| {
+            Err(BpxError::Storage {
+                reason: "writer thread stopped".to_string(),
+            })
+        })
+    }
+
+    fn send(&self, op: Op) {
+        if let Some(writer) = &self.writer {
+            // Only fails once the writer has exited, which `flush` reports
+            let _ = writer.sender.send(op);
+        }
+    }
+
+    fn create_session(&self) -> SessionId {
+        let id = SessionId::generate();
+        let at = now_millis();
+        let session = StoredSession::new(at);
+        self.memory_used
+            .fetch_add(session.footprint(&id), Ordering::Relaxed);
+        self.sessions.insert(id.clone(), Arc::new(session));
+        self.send(Op::Touch {
+            session: id.to_string(),
+            at,
+        });
+        self.make_room(0, Some(&id));
+        id
+    }
+
+    /// Evict least recently used sessions other than `keep` until `needed`
+    /// more bytes fit, returning whether they do
+    fn make_room(&self, needed: usize, keep: Option<&SessionId>) -> bool {
+        let cap = self.config.max_memory;
+        let used = self.memory_used.load(Ordering::Relaxed);
+        if used + needed <= cap {
+            return true;
+        }
+
+        let ages = self
+            .sessions
+            .iter()
+            .filter(|entry| Some(entry.key()) != keep)
+            .map(|entry| {
+                let session = entry.value();
+                (
+                    entry.key().clone(),
+                    session.last_accessed.load(Ordering::Relaxed),
+                    session.footprint(entry.key()),
+                )
+            })
+            .collect();
+        for id in oldest_first(ages, bytes_to_free(cap, used, needed)) {
+            self.remove_session(&id);
+        }
+        self.memory_used.load(Ordering::Relaxed) + needed <= cap
+    }
+
+    fn remove_session(&self, id: &SessionId) {
+        if let Some((_, session)) = self.sessions.remove(id) {
+            self.memory_used
+                .fetch_sub(session.footprint(id), Ordering::Relaxed);
+            self.send(Op::RemoveSession {
+                session: id.to_string(),
+            });
+        }
+    }
+
+    /// Record `version` if `accept` approves the current one, making room
+    /// for it first; `held` is the version expected to be replaced
+    fn store_version(
+        &self,
+        session_id: &SessionId,
+        path: &ResourcePath,
+        accept: impl Fn(Option<&Version>) -> bool,
+        held: Option<&Version>,
+        version: Version,
+    ) -> bool {
+        let Some(session) = self.sessions.get(session_id).map(|s| Arc::clone(&s)) else {
+            return false;
+        };
+        if !session.versions.contains_key(path)
+            && session.versions.len() >= self.config.max_resources_per_session
+        {
+            return false;
+        }
+        let key = version_key(&session_id.to_string(), &path.to_string());
+        let held = held.map_or(0, |old| BpxSession::entry_size(path, old));
+        let needed = BpxSession::entry_size(path, &version).saturating_sub(held);
+
+        if !self.make_room(needed, Some(session_id)) {
+            // Forget the stale version so the next request falls back to full
+            self.send(Op::RemoveVersion { key });
+            let released = session.remove(path);
+            self.memory_used.fetch_sub(released, Ordering::Relaxed);
+            return false;
+        }
+        let persist = |version: &Version| {
+            self.send(Op::SetVersion {
+                key,
+                version: version.to_string(),
+            })
+        };
+        match session.set_if(path, accept, version, persist) {
+            Some(delta) => {
+                self.adjust(delta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `session` has been idle past its retention at `now`
+    fn is_expired(&self, session: &StoredSession, now: u64) -> bool {
+        let idle = session.idle(now);
+        idle > self.config.session_retention()
+            && session
+                .versions
+                .iter()
+                .all(|v| self.config.version_expired(v.key(), idle))
+    }
+
+    /// Forget versions whose path TTL passed while `session` was idle
+    fn prune(&self, id: &SessionId, session: &StoredSession, now: u64) {
+        if self.config.path_ttls.is_empty() {
+            return;
+        }
+        let idle = session.idle(now);
+        let expired: Vec<_> = session
+            .versions
+            .iter()
+            .filter(|v| self.config.version_expired(v.key(), idle))
+            .map(|v| v.key().clone())
+            .collect();
+        for path in expired {
+            self.send(Op::RemoveVersion {
+                key: version_key(&id.to_string(), &path.to_string()),
+            });
+            let released = session.remove(&path);
+            self.memory_used.fetch_sub(released, Ordering::Relaxed);
+        }
+    }
+
+    fn adjust(&self, delta: isize) {
+        if delta >= 0 {
+            self.memory_used
+                .fetch_add(delta as usize, Ordering::Relaxed);
+        } else {
+            self.memory_used
+                .fetch_sub(delta.unsigned_abs(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for RedbStateManager {
+    fn drop(&mut self) {
+        if let Some(Writer { sender, handle }) = self.writer.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Read every stored session into memory
+fn load(db: &Database) -> Result<DashMap<SessionId, Arc<StoredSession>>, BpxError> {
+    let txn = db.begin_write().map_err(storage_error)?;
+    let sessions = DashMap::new();
+    {
+        let table = txn.open_table(SESSIONS).map_err(storage_error)?;
+        for entry in table.iter().map_err(storage_error)? {
+            let (id, at) = entry.map_err(storage_error)?;
+            sessions.insert(
+                SessionId::new(id.value().to_string()),
+                Arc::new(StoredSession::new(at.value())),
+            );
+        }
+        let table = txn.open_table(VERSIONS).map_err(storage_error)?;
+        for entry in table.iter().map_err(storage_error)? {
+            let (key, version) = entry.map_err(storage_error)?;
+            let Some((id, path)) = key.value().split_once('\0') else {
+                continue;
+            };
+            if let Some(session) = sessions.get(&SessionId::new(id.to_string())) {
+                session.set_if(
+                    &ResourcePath::new(path.to_string()),
+                    |_| true,
+                    Version::new(version.value().to_string()),
+                    |_| {},
+                );
+            }
+        }
+    }
+    // Creates the tables on first open
+    txn.commit().map_err(storage_error)?;
+    Ok(sessions)
+}
+
+/// Drain the queue in batches until every sender is gone
+fn write_loop(mut db: Database, receiver: Receiver<Op>, options: RedbStateOptions) {
+    let mut last_error = None;
+    let mut last_compaction = Instant::now();
+    loop {
+        if last_compaction.elapsed() >= options.compaction_interval {
+            if let Err(e) = db.compact() {
+                last_error.get_or_insert(storage_error(e));
+            }
+            last_compaction = Instant::now();
+        }
+        let until_compaction = options
+            .compaction_interval
+            .saturating_sub(last_compaction.elapsed());
+        let first = match receiver.recv_timeout(until_compaction) {
+            Ok(op) => op,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        let deadline = Instant::now() + options.batch_window;
+        let mut batch = vec![first];
+        let mut disconnected = false;
+        while batch.len() < options.batch_size.max(1) {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(op) => batch.push(op),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        let mut acks = Vec::new();
+        if let Err(e) = write_batch(&db, batch, &mut acks) {
+            last_error.get_or_insert(e);
+        }
+        for ack in acks {
+            let _ = ack.send(last_error.take().map_or(Ok(()), Err));
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Apply one batch in a single transaction, collecting flush requests
+fn write_batch(
+    db: &Database,
+    batch: Vec<Op>,
+    acks: &mut Vec<Sender<Result<(), BpxError>>>,
+) -> Result<(), BpxError> {
+    let txn = db.begin_write().map_err(storage_error)?;
+    {
+        let mut sessions = txn.open_table(SESSIONS).map_err(storage_error)?;
+        let mut versions = txn.open_table(VERSIONS).map_err(storage_error)?;
+        // Only the latest access time per session is worth writing
+        let mut touches = HashMap::new();
+        for op in batch {
+            match op {
+                Op::Touch { session, at } => {
+                    touches.insert(session, at);
+                }
+                Op::SetVersion { key, version } => {
+                    versions
+                        .insert(key.as_str(), version.as_str())
+                        .map_err(storage_error)?;
+                }
+                Op::RemoveVersion { key } => {
+                    versions.remove(key.as_str()).map_err(storage_error)?;
+                }
+                Op::RemoveSession { session } => {
+                    touches.remove(&session);
+                    sessions.remove(session.as_str()).map_err(storage_error)?;
+                    let start = version_key(&session, "");
+                    let end = format!("{}\u{1}", session);
+                    versions
+                        .retain_in(start.as_str()..end.as_str(), |_, _| false)
+                        .map_err(storage_error)?;
+                }
+                Op::Flush(ack) => acks.push(ack),
+            }
+        }
+        for (session, at) in touches {
+            sessions
+                .insert(session.as_str(), at)
+                .map_err(storage_error)?;
+        }
+    }
+    txn.commit().map_err(storage_error)
+}
+
+#[async_trait]
+impl StateManager for RedbStateManager {
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus {
+        let Some(id) = id else {
+            return SessionStatus::Created(self.create_session());
+        };
+        let Some(session) = self.sessions.get(&id).map(|s| Arc::clone(&s)) else {
+            return SessionStatus::Created(self.create_session());
+        };
+        let at = now_millis();
+        if self.is_expired(&session, at) {
+            // Past its grace period; drop it now rather than at the next cleanup
+            if let Some((_, session)) = self.sessions.remove_if(&id, |_, s| self.is_expired(s, at))
+            {
+                self.memory_used
+                    .fetch_sub(session.footprint(&id), Ordering::Relaxed);
+                self.send(Op::RemoveSession {
+                    session: id.to_string(),
+                });
+            }
+            return SessionStatus::Created(self.create_session());
+        }
+        self.prune(&id, &session, at);
+        session.last_accessed.store(at, Ordering::Relaxed);
+        self.send(Op::Touch {
+            session: id.to_string(),
+            at,
+        });
+        SessionStatus::Resumed(id)
+    }
+
+    async fn has_session(&self, id: &SessionId) -> bool {
+        self.sessions
+            .get(id)
+            .is_some_and(|session| !self.is_expired(&session, now_millis()))
+    }
+
+    async fn get_version(&self, session_id: &SessionId, path: &ResourcePath) -> Option<Version> {
+        let session = self.sessions.get(session_id)?;
+        session.versions.get(path).map(|v| v.clone())
+    }
+
+    async fn set_version(&self, session_id: &SessionId, path: &ResourcePath, version: Version) {
+        let held = self
+            .sessions
+            .get(session_id)
+            .map(|session| session.versions.get(path).map(|old| old.clone()));
+        if let Some(held) = held {
+            self.store_version(session_id, path, |_| true, held.as_ref(), version);
+        }
+    }
+
+    async fn get_versions(
+        &self,
+        session_id: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        let Some(session) = self.sessions.get(session_id) else {
+            return vec![None; paths.len()];
+        };
+        paths
+            .iter()
+            .map(|path| session.versions.get(path).map(|v| v.clone()))
+            .collect()
+    }
+
+    async fn set_versions(&self, session_id: &SessionId, versions: &[(ResourcePath, Version)]) {
+        let Some(session) = self.sessions.get(session_id).map(|s| Arc::clone(&s)) else {
+            return;
+        };
+        for (path, version) in versions {
+            let held = session.versions.get(path).map(|old| old.clone());
+            self.store_version(session_id, path, |_| true, held.as_ref(), version.clone());
+        }
+    }
+
+    async fn compare_and_set_version(
+        &self,
+        session_id: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool {
+        self.store_version(session_id, path, |held| held == expected, expected, new)
+    }
+
+    async fn cleanup_expired(&self) {
+        let now = now_millis();
+        self.sessions.retain(|id, session| {
+            let expired = self.is_expired(session, now);
+            if expired {
+                self.memory_used
+                    .fetch_sub(session.footprint(id), Ordering::Relaxed);
+                self.send(Op::RemoveSession {
+                    session: id.to_string(),
+                });
+            }
+            !expired
+        });
+        if !self.config.path_ttls.is_empty() {
+            let sessions: Vec<_> = self
+                .sessions
+                .iter()
+                .map(|entry| (entry.key().clone(), Arc::clone(entry.value())))
+                .collect();
+            for (id, session) in sessions {
+                self.prune(&id, &session, now);
+            }
+        }
+    }
+
+    async fn export(&self) -> StateSnapshot {
+        let now = now_millis();
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|entry| SessionSnapshot {
+                id: entry.key().clone(),
+                idle: Duration::from_millis(
+                    now.saturating_sub(entry.last_accessed.load(Ordering::Relaxed)),
+                ),
+                versions: entry
+                    .versions
+                    .iter()
+                    .map(|v| (v.key().clone(), v.value().clone()))
+                    .collect(),
+                tenant: None,
+            })
+            .collect();
+        StateSnapshot { sessions }
+    }
+
+    async fn import(&self, snapshot: StateSnapshot) {
+        let now = now_millis();
+        for imported in snapshot.sessions {
+            if self
+                .config
+                .session_expired(imported.idle, imported.versions.keys())
+            {
+                continue;
+            }
+            // Drop whatever is stored under this ID before writing the import
+            self.remove_session(&imported.id);
+
+            let at = now.saturating_sub(imported.idle.as_millis() as u64);
+            let session = StoredSession::new(at);
+            self.send(Op::Touch {
+                session: imported.id.to_string(),
+                at,
+            });
+            for (path, version) in imported.versions {
+                let key = version_key(&imported.id.to_string(), &path.to_string());
+                let persist = |version: &Version| {
+                    self.send(Op::SetVersion {
+                        key,
+                        version: version.to_string(),
+                    })
+                };
+                session.set_if(&path, |_| true, version, persist);
+            }
+            self.memory_used
+                .fetch_add(session.footprint(&imported.id), Ordering::Relaxed);
+            self.sessions.insert(imported.id, Arc::new(session));
+        }
+        // Imported sessions count toward the cap like any other
+        self.make_room(0, None);
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_used.load(Ordering::Relaxed)
+    }
+
+    fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_db(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("bpx-redb-{}-{}.redb", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_sessions_survive_reopen() {
+        let path = temp_db("reopen");
+        let resource = ResourcePath::new("/api/feed".to_string());
+
+        let state_mgr = RedbStateManager::open(&path, BpxConfig::default()).unwrap();
+        let session = state_mgr.get_or_create_session(None).await.into_id();
+        state_mgr
+            .set_version(&session, &resource, Version::new("v1".to_string()))
+            .await;
+        assert!(
+            state_mgr
+                .compare_and_set_version(
+                    &session,
+                    &resource,
+                    Some(&Version::new("v1".to_string())),
+                    Version::new("v2".to_string()),
+                )
+                .await
+        );
+        state_mgr.flush().unwrap();
+        let memory_usage = state_mgr.memory_usage();
+        drop(state_mgr);
+
+        let state_mgr = RedbStateManager::open(&path, BpxConfig::default()).unwrap();
+        assert_eq!(
+            state_mgr.get_or_create_session(Some(session.clone())).await,
+            SessionStatus::Resumed(session.clone())
+        );
+        assert_eq!(
+            state_mgr.get_version(&session, &resource).await,
+            Some(Version::new("v2".to_string()))
+        );
+        assert_eq!(state_mgr.memory_usage(), memory_usage);
+        drop(state_mgr);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_removes_from_disk() {
+        let path = temp_db("cleanup");
+        let config = BpxConfig {
+            session_ttl: Duration::from_millis(50),
+            ..BpxConfig::default()
+        };
+        let resource = ResourcePath::new("/api/feed".to_string());
+
+        let state_mgr = RedbStateManager::open(&path, config.clone()).unwrap();
+        let session = state_mgr.get_or_create_session(None).await.into_id();
+        state_mgr
+            .set_version(&session, &resource, Version::new("v1".to_string()))
+            .await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        state_mgr.cleanup_expired().await;
+        state_mgr.flush().unwrap();
+        drop(state_mgr);
+
+        let state_mgr = RedbStateManager::open(&path, config).unwrap();
+        assert!(state_mgr.sessions.is_empty());
+        assert!(state_mgr.get_version(&session, &resource).await.is_none());
+        drop(state_mgr);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compaction_runs_in_background() {
+        let path = temp_db("compact");
+        let options = RedbStateOptions {
+            compaction_interval: Duration::from_millis(20),
+            ..RedbStateOptions::default()
+        };
+        let state_mgr =
+            RedbStateManager::open_with_options(&path, BpxConfig::default(), options).unwrap();
+        let session = state_mgr.get_or_create_session(None).await.into_id();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        state_mgr.flush().unwrap();
+        assert!(state_mgr.sessions.contains_key(&session));
+        drop(state_mgr);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_persists() {
+        let path = temp_db("import");
+        let resource = ResourcePath::new("/api/feed".to_string());
+        let source = crate::state::InMemoryStateManager::new(BpxConfig::default());
+        let session = source.get_or_create_session(None).await.into_id();
+        source
+            .set_version(&session, &resource, Version::new("v1".to_string()))
+            .await;
+
+        let state_mgr = RedbStateManager::open(&path, BpxConfig::default()).unwrap();
+        state_mgr.import(source.export().await).await;
+        state_mgr.flush().unwrap();
+        drop(state_mgr);
+
+        let state_mgr = RedbStateManager::open(&path, BpxConfig::default()).unwrap();
+        assert_eq!(
+            state_mgr.get_version(&session, &resource).await,
+            Some(Version::new("v1".to_string()))
+        );
+        drop(state_mgr);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//! State manager split into independent shards by session
+
+use super::{InMemoryStateManager, SessionStatus, StateManager, StateSnapshot};
+use crate::{BpxConfig, ResourcePath, SessionId, Version, clock::Clock};
+use async_trait::async_trait;
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::Arc,
+};
+
+/// In-memory state spread over independent shards keyed by session hash
+///
+/// Each shard is an [`InMemoryStateManager`] with its own map, memory budget
+/// (`max_memory / shards`) and cleanup pass, so heavy traffic on one part of
+/// the session space never waits on another and cleanup can run a shard at a
+/// time.
+pub struct ShardedStateManager {
+    shards: Box<[InMemoryStateManager]>,
+    hasher: RandomState,
+}
+
+impl ShardedStateManager {
+    /// Create a manager with one shard per available CPU (at least 4)
+    pub fn new(config: BpxConfig) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(config, cpus.max(4))
+    }
+
+    /// Create a manager with `shards` shards
+    pub fn with_shards(config: BpxConfig, shards: usize) -> Self {
+        let shards = shards.max(1);
+        let shard_config = BpxConfig {
+            max_sessions: config.max_sessions.div_ceil(shards),
+            max_memory: config.max_memory / shards,
+            ..config
+        };
+        Self {
+            shards: (0..shards)
+                .map(|_| InMemoryStateManager::new(shard_config.clone()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Time sessions in every shard with `clock`
+    pub fn clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            shards: self
+                .shards
+                .into_iter()
+                .map(|shard| shard.clock(Arc::clone(&clock)))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// Number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Clean up expired sessions in one shard
+    pub async fn cleanup_shard(&self, index: usize) {
+        if let Some(shard) = self.shards.get(index) {
+            shard.cleanup_expired().await;
+        }
+    }
+
+    fn shard(&self, id: &SessionId) -> &InMemoryStateManager {
+        let index = self.hasher.hash_one(id) as usize % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+#[async_trait]
+impl StateManager for ShardedStateManager {
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus {
+        if let Some(id) = id
+            && self.shard(&id).resume(&id)
+        {
+            return SessionStatus::Resumed(id);
+        }
+        let id = SessionId::generate();
+        self.shard(&id).create(id)
+    }
+
+    async fn has_session(&self, id: &SessionId) -> bool {
+        self.shard(id).has_session(id).await
+    }
+
+    async fn get_version(&self, session: &SessionId, path: &ResourcePath) -> Option<Version> {
+        self.shard(session).get_version(session, path).await
+    }
+
+    async fn set_version(&self, session: &SessionId, path: &ResourcePath, version: Version) {
+        self.shard(session)
+            .set_version(session, path, version)
+            .await
+    }
+
+    async fn get_versions(
+        &self,
+        session: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        self.shard(session).get_versions(session, paths).await
+    }
+
+    async fn set_versions(&self, session: &SessionId, versions: &[(ResourcePath, Version)]) {
+        self.shard(session).set_versions(session, versions).await
+    }
+
+    async fn compare_and_set_version(
+        &self,
+        session: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool {
+        self.shard(session)
+            .compare_and_set_version(session, path, expected, new)
+            .await
+    }
+
+    async fn cleanup_expired(&self) {
+        for index in 0..self.shards.len() {
+            self.cleanup_shard(index).await;
+            // Let request handling run between shards
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn export(&self) -> StateSnapshot {
+        let mut snapshot = StateSnapshot::default();
+        for shard in self.shards.iter() {
+            snapshot.sessions.extend(shard.export().await.sessions);
+        }
+        snapshot
+    }
+
+    async fn import(&self, snapshot: StateSnapshot) {
+        let mut parts = vec![StateSnapshot::default(); self.shards.len()];
+        for session in snapshot.sessions {
+            let index = self.hasher.hash_one(&session.id) as usize % self.shards.len();
+            parts[index].sessions.push(session);
+        }
+        for (shard, part) in self.shards.iter().zip(parts) {
+            shard.import(part).await;
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.shards.iter().map(StateManager::memory_usage).sum()
+    }
+
+    fn session_count(&self) -> usize {
+        self.shards.iter().map(StateManager::session_count).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sessions_route_to_one_shard() {
+        let state_mgr = ShardedStateManager::with_shards(BpxConfig::default(), 8);
+        let path = ResourcePath::new("/api/test".to_string());
+        let mut ids = Vec::new();
+        for i in 0..64 {
+            let id = state_mgr.get_or_create_session(None).await.into_id();
+            state_mgr
+                .set_version(&id, &path, Version::new(format!("v{}", i)))
+                .await;
+            ids.push(id);
+        }
+
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(
+                state_mgr.get_or_create_session(Some(id.clone())).await,
+                SessionStatus::Resumed(id.clone())
+            );
+            assert_eq!(
+                state_mgr.get_version(id, &path).await,
+                Some(Version::new(format!("v{}", i)))
+            );
+            let holders = state_mgr
+                .shards
+                .iter()
+                .filter(|shard| shard.sessions.contains_key(id))
+                .count();
+            assert_eq!(holders, 1);
+        }
+        assert!(
+            state_mgr
+                .shards
+                .iter()
+                .filter(|s| !s.sessions.is_empty())
+                .count()
+                > 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_and_snapshot() {
+        let clock = Arc::new(MockClock::new());
+        let state_mgr = ShardedStateManager::with_shards(
+            BpxConfig {
+                session_ttl: Duration::from_millis(50),
+                ..BpxConfig::default()
+            },
+            4,
+        )
+        .clock(clock.clone());
+        for _ in 0..16 {
+            state_mgr.get_or_create_session(None).await;
+        }
+        assert_eq!(state_mgr.export().await.sessions.len(), 16);
+
+        let copy = ShardedStateManager::with_shards(BpxConfig::default(), 3);
+        copy.import(state_mgr.export().await).await;
+        assert_eq!(copy.export().await.sessions.len(), 16);
+        assert_eq!(copy.memory_usage(), state_mgr.memory_usage());
+
+        clock.advance(Duration::from_millis(100));
+        state_mgr.cleanup_expired().await;
+        assert_eq!(state_mgr.memory_usage(), 0);
+        assert!(state_mgr.export().await.sessions.is_empty());
+    }
+}
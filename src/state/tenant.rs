@@ -0,0 +1,362 @@
+//! State manager scoping sessions and limits by tenant
+
+use super::{InMemoryStateManager, SessionStatus, StateManager, StateSnapshot};
+use crate::{
+    BpxConfig, ResourcePath, SessionId, TenantId, Version,
+    clock::{self, Clock},
+};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+/// In-memory state with a separate namespace and limits per tenant
+///
+/// Each tenant's sessions live in their own [`InMemoryStateManager`], capped
+/// by [`BpxConfig::limits_for`] that tenant: past `max_sessions` the least
+/// recently used session is evicted, past `max_memory` likewise, and a
+/// session tracks at most `max_resources_per_session` resources. One noisy
+/// customer can only evict its own sessions.
+///
+/// Sessions created through [`StateManager::get_or_create_session`] belong to
+/// [`TenantId::default_tenant`].
+pub struct TenantStateManager {
+    tenants: DashMap<TenantId, Arc<InMemoryStateManager>>,
+    owners: DashMap<SessionId, TenantId>,
+    config: BpxConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl TenantStateManager {
+    /// Create a manager applying the tenant limits in `config`
+    pub fn new(config: BpxConfig) -> Self {
+        Self {
+            tenants: DashMap::new(),
+            owners: DashMap::new(),
+            config,
+            clock: clock::system(),
+        }
+    }
+
+    /// Time every tenant's sessions with `clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Number of sessions held for `tenant`
+    pub fn tenant_sessions(&self, tenant: &TenantId) -> usize {
+        self.tenants.get(tenant).map_or(0, |t| t.sessions.len())
+    }
+
+    /// Bytes of session state held for `tenant`
+    pub fn tenant_memory_usage(&self, tenant: &TenantId) -> usize {
+        self.tenants
+            .get(tenant)
+            .map_or(0, |t| StateManager::memory_usage(t.as_ref()))
+    }
+
+    fn tenant(&self, tenant: &TenantId) -> Arc<InMemoryStateManager> {
+        let limits = self.config.limits_for(tenant);
+        let entry = self.tenants.entry(tenant.clone()).or_insert_with(|| {
+            Arc::new(
+                InMemoryStateManager::new(BpxConfig {
+                    max_sessions: limits.max_sessions,
+                    max_resources_per_session: limits.max_resources_per_session,
+                    max_memory: limits.max_memory,
+                    ..self.config.clone()
+                })
+                .clock(Arc::clone(&self.clock)),
+            )
+        });
+        Arc::clone(&entry)
+    }
+
+    /// Manager of the tenant owning `session`
+    fn owner(&self, session: &SessionId) -> Option<Arc<InMemoryStateManager>> {
+        let tenant = self.owners.get(session)?.clone();
+        self.tenants.get(&tenant).map(|t| Arc::clone(&t))
+    }
+
+    /// Evict the least recently used sessions of `tenant` beyond its limit
+    fn enforce_session_limit(&self, tenant: &TenantId, sessions: &InMemoryStateManager) {
+        let limit = self.config.limits_for(tenant).max_sessions;
+        if sessions.sessions.len() <= limit {
+            return;
+        }
+        for id in sessions.spill_candidates(Duration::MAX, limit) {
+            sessions.take(&id);
+            self.owners.remove(&id);
+        }
+    }
+}
+
+#[async_trait]
+impl StateManager for TenantStateManager {
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus {
+        self.get_or_create_tenant_session(&TenantId::default_tenant(), id)
+            .await
+    }
+
+    async fn get_or_create_tenant_session(
+        &self,
+        tenant: &TenantId,
+        id: Option<SessionId>,
+    ) -> SessionStatus {
+        let sessions = self.tenant(tenant);
+        if let Some(id) = id
+            && self.owners.get(&id).is_some_and(|owner| *owner == *tenant)
+            && sessions.resume(&id)
+        {
+            return SessionStatus::Resumed(id);
+        }
+
+        let id = SessionId::generate();
+        self.owners.insert(id.clone(), tenant.clone());
+        let status = sessions.create(id);
+        self.enforce_session_limit(tenant, &sessions);
+        status
+    }
+
+    async fn has_session(&self, id: &SessionId) -> bool {
+        self.has_tenant_session(&TenantId::default_tenant(), id)
+            .await
+    }
+
+    async fn has_tenant_session(&self, tenant: &TenantId, id: &SessionId) -> bool {
+        if self.owners.get(id).is_none_or(|owner| *owner != *tenant) {
+            return false;
+        }
+        match self.owner(id) {
+            Some(sessions) => sessions.has_session(id).await,
+            None => false,
+        }
+    }
+
+    async fn get_version(&self, session: &SessionId, path: &ResourcePath) -> Option<Version> {
+        self.owner(session)?.get_version(session, path).await
+    }
+
+    async fn set_version(&self, session: &SessionId, path: &ResourcePath, version: Version) {
+        if let Some(sessions) = self.owner(session) {
+            sessions.set_version(session, path, version).await;
+        }
+    }
+
+    async fn get_versions(
+        &self,
+        session: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        match self.owner(session) {
+            Some(sessions) => sessions.get_versions(session, paths).await,
+            None => vec![None; paths.len()],
+        }
+    }
+
+    async fn set_versions(&self, session: &SessionId, versions: &[(ResourcePath, Version)]) {
+        if let Some(sessions) = self.owner(session) {
+            sessions.set_versions(session, versions).await;
+        }
+    }
+
+    async fn compare_and_set_version(
+        &self,
+        session: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool {
+        match self.owner(session) {
+            Some(sessions) => {
+                sessions
+                    .compare_and_set_version(session, path, expected, new)
+                    .await
+            }
+            None => false,
+        }
+    }
+
+    async fn cleanup_expired(&self) {
+        let tenants: Vec<_> = self.tenants.iter().map(|t| Arc::clone(&t)).collect();
+        for sessions in tenants {
+            sessions.cleanup_expired().await;
+        }
+        // Forget owners of expired and evicted sessions
+        self.owners.retain(|id, tenant| {
+            self.tenants
+                .get(tenant)
+                .is_some_and(|t| t.sessions.contains_key(id))
+        });
+    }
+
+    async fn export(&self) -> StateSnapshot {
+        let tenants: Vec<_> = self
+            .tenants
+            .iter()
+            .map(|t| (t.key().clone(), Arc::clone(t.value())))
+            .collect();
+        let mut snapshot = StateSnapshot::default();
+        for (tenant, sessions) in tenants {
+            let exported = sessions.export().await.sessions;
+            snapshot
+                .sessions
+                .extend(exported.into_iter().map(|mut session| {
+                    session.tenant = Some(tenant.clone());
+                    session
+                }));
+        }
+        snapshot
+    }
+
+    async fn import(&self, snapshot: StateSnapshot) {
+        let mut parts: HashMap<TenantId, StateSnapshot> = HashMap::new();
+        for session in snapshot.sessions {
+            let tenant = session
+                .tenant
+                .clone()
+                .unwrap_or_else(TenantId::default_tenant);
+            self.owners.insert(session.id.clone(), tenant.clone());
+            parts.entry(tenant).or_default().sessions.push(session);
+        }
+        for (tenant, part) in parts {
+            let sessions = self.tenant(&tenant);
+            sessions.import(part).await;
+            self.enforce_session_limit(&tenant, &sessions);
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.tenants
+            .iter()
+            .map(|t| StateManager::memory_usage(t.as_ref()))
+            .sum()
+    }
+
+    fn session_count(&self) -> usize {
+        self.tenants
+            .iter()
+            .map(|t| StateManager::session_count(t.as_ref()))
+            .sum()
+    }
+
+    fn tenant_session_count(&self, tenant: &TenantId) -> usize {
+        self.tenant_sessions(tenant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TenantLimits;
+
+    fn tenant(name: &str) -> TenantId {
+        TenantId::new(name.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_scoped_to_their_tenant() {
+        let state_mgr = TenantStateManager::new(BpxConfig::default());
+        let path = ResourcePath::new("/api/test".to_string());
+
+        let id = state_mgr
+            .get_or_create_tenant_session(&tenant("acme"), None)
+            .await
+            .into_id();
+        state_mgr
+            .set_version(&id, &path, Version::new("v1".to_string()))
+            .await;
+        assert_eq!(
+            state_mgr
+                .get_or_create_tenant_session(&tenant("acme"), Some(id.clone()))
+                .await,
+            SessionStatus::Resumed(id.clone())
+        );
+
+        // Another tenant can't resume it
+        let other = state_mgr
+            .get_or_create_tenant_session(&tenant("globex"), Some(id.clone()))
+            .await;
+        assert!(!other.is_resumed());
+        assert_ne!(other.id(), &id);
+        assert_eq!(state_mgr.tenant_sessions(&tenant("acme")), 1);
+        assert_eq!(state_mgr.tenant_sessions(&tenant("globex")), 1);
+        assert_eq!(
+            state_mgr.get_version(&id, &path).await,
+            Some(Version::new("v1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenant_limits() {
+        let mut config = BpxConfig::default();
+        config.tenant_limits.insert(
+            tenant("small"),
+            TenantLimits {
+                max_sessions: 2,
+                max_resources_per_session: 1,
+                ..TenantLimits::default()
+            },
+        );
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let state_mgr = TenantStateManager::new(config).clock(clock.clone());
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(2));
+            let status = state_mgr
+                .get_or_create_tenant_session(&tenant("small"), None)
+                .await;
+            ids.push(status.into_id());
+        }
+        assert_eq!(state_mgr.tenant_sessions(&tenant("small")), 2);
+        assert!(
+            !state_mgr
+                .get_or_create_tenant_session(&tenant("small"), Some(ids[0].clone()))
+                .await
+                .is_resumed()
+        );
+
+        let first = ResourcePath::new("/a".to_string());
+        let second = ResourcePath::new("/b".to_string());
+        let id = &ids[2];
+        state_mgr
+            .set_version(id, &first, Version::new("v1".to_string()))
+            .await;
+        state_mgr
+            .set_version(id, &second, Version::new("v1".to_string()))
+            .await;
+        assert!(state_mgr.get_version(id, &first).await.is_some());
+        assert!(state_mgr.get_version(id, &second).await.is_none());
+
+        // Other tenants get the defaults
+        for _ in 0..3 {
+            state_mgr
+                .get_or_create_tenant_session(&tenant("large"), None)
+                .await;
+        }
+        assert_eq!(state_mgr.tenant_sessions(&tenant("large")), 3);
+    }
+
+    #[tokio::test]
+    async fn test_export_keeps_tenants() {
+        let state_mgr = TenantStateManager::new(BpxConfig::default());
+        let id = state_mgr
+            .get_or_create_tenant_session(&tenant("acme"), None)
+            .await
+            .into_id();
+        state_mgr.get_or_create_session(None).await;
+
+        let snapshot = state_mgr.export().await;
+        assert_eq!(snapshot.sessions.len(), 2);
+
+        let copy = TenantStateManager::new(BpxConfig::default());
+        copy.import(snapshot).await;
+        assert_eq!(copy.memory_usage(), state_mgr.memory_usage());
+        assert_eq!(copy.tenant_sessions(&TenantId::default_tenant()), 1);
+        assert!(
+            copy.get_or_create_tenant_session(&tenant("acme"), Some(id))
+                .await
+                .is_resumed()
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! State manager with a hot in-memory tier and a pluggable cold tier
+
+use super::{InMemoryStateManager, SessionSnapshot, SessionStatus, StateManager, StateSnapshot};
+use crate::{BpxConfig, BpxError, ResourcePath, SessionId, Version, clock::Clock};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// Session moved out of memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ColdSession {
+    /// Session as it was when spilled
+    pub session: SessionSnapshot,
+    /// When it was spilled
+    pub spilled_at: SystemTime,
+}
+
+impl ColdSession {
+    /// Spill `session` now
+    pub fn new(session: SessionSnapshot) -> Self {
+        Self {
+            session,
+            spilled_at: SystemTime::now(),
+        }
+    }
+
+    /// Time since the session was last accessed
+    pub fn idle(&self) -> Duration {
+        self.session.idle + self.spilled_at.elapsed().unwrap_or_default()
+    }
+
+    /// Snapshot with the idle time brought up to date
+    pub fn into_snapshot(self) -> SessionSnapshot {
+        let idle = self.idle();
+        SessionSnapshot {
+            idle,
+            ..self.session
+        }
+    }
+}
+
+/// External storage for sessions spilled by [`TieredStateManager`]
+///
+/// Implement this over Redis, a database or files; with the `serde` feature
+/// [`ColdSession`] serializes directly.
+#[async_trait]
+pub trait ColdStore: Send + Sync {
+    /// Store a session, replacing any with the same ID
+    async fn put(&self, session: ColdSession) -> Result<(), BpxError>;
+
+    /// Remove and return a session
+    async fn take(&self, id: &SessionId) -> Result<Option<ColdSession>, BpxError>;
+
+    /// Drop sessions idle longer than `ttl`
+    async fn remove_expired(&self, ttl: Duration) -> Result<(), BpxError>;
+
+    /// Every stored session
+    async fn sessions(&self) -> Result<Vec<ColdSession>, BpxError>;
+}
+
+/// [`ColdStore`] kept in process memory, for tests and as a reference
+#[derive(Default)]
+pub struct InMemoryColdStore {
+    sessions: DashMap<SessionId, ColdSession>,
+}
+
+impl InMemoryColdStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of stored sessions
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Whether no sessions are stored
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+#[async_trait]
+impl ColdStore for InMemoryColdStore {
+    async fn put(&self, session: ColdSession) -> Result<(), BpxError> {
+        self.sessions.insert(session.session.id.clone(), session);
+        Ok(())
+    }
+
+    async fn take(&self, id: &SessionId) -> Result<Option<ColdSession>, BpxError> {
+        Ok(self.sessions.remove(id).map(|(_, session)| session))
+    }
+
+    async fn remove_expired(&self, ttl: Duration) -> Result<(), BpxError> {
+        self.sessions.retain(|_, session| session.idle() <= ttl);
+        Ok(())
+    }
+
+    async fn sessions(&self) -> Result<Vec<ColdSession>, BpxError> {
+        Ok(self.sessions.iter().map(|s| s.value().clone()).collect())
+    }
+}
+
+/// Options for [`TieredStateManager`]
+#[derive(Debug, Clone)]
+pub struct TieredOptions {
+    /// Idle time after which a session is moved to the cold store
+    pub spill_after: Duration,
+}
+
+impl Default for TieredOptions {
+    fn default() -> Self {
+        Self {
+            spill_after: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Sessions kept in memory while active and in a [`ColdStore`] while idle
+///
+/// At most [`BpxConfig::max_sessions`] sessions stay in memory; the least
+/// recently used ones beyond that, and any idle longer than
+/// [`TieredOptions::spill_after`], are spilled by `cleanup_expired` and after
+/// each new session. A spilled session is restored when its ID is next
+/// presented, so infrequent pollers still get diffs.
+///
+/// Cold store failures never fail a request: a session that can't be spilled
+/// stays in memory, and one that can't be restored is replaced by a new
+/// session, whose client gets full responses.
+pub struct TieredStateManager {
+    hot: InMemoryStateManager,
+    cold: Arc<dyn ColdStore>,
+    config: BpxConfig,
+    options: TieredOptions,
+}
+
+impl TieredStateManager {
+    /// Create a manager spilling to `cold` with default options
+    pub fn new(config: BpxConfig, cold: Arc<dyn ColdStore>) -> Self {
+        Self::with_options(config, cold, TieredOptions::default())
+    }
+
+    /// Create a manager spilling to `cold`
+    pub fn with_options(
+        config: BpxConfig,
+        cold: Arc<dyn ColdStore>,
+        options: TieredOptions,
+    ) -> Self {
+        Self {
+            hot: InMemoryStateManager::new(config.clone()),
+            cold,
+            config,
+            options,
+        }
+    }
+
+    /// Time sessions held in memory with `clock`
+    ///
+    /// Spilled sessions age by [`ColdSession::spilled_at`], which is
+    /// wall-clock time.
+    pub fn clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            hot: self.hot.clock(clock),
+            ..self
+        }
+    }
+
+    /// Number of sessions held in memory
+    pub fn hot_sessions(&self) -> usize {
+        self.hot.sessions.len()
+    }
+
+    /// Move idle and excess sessions to the cold store
+    pub async fn spill(&self) {
+        let candidates = self
+            .hot
+            .spill_candidates(self.options.spill_after, self.config.max_sessions);
+        for id in candidates {
+            let Some(session) = self.hot.take(&id) else {
+                continue;
+            };
+            let cold = ColdSession::new(session);
+            if self.cold.put(cold.clone()).await.is_err() {
+                // Keep it in memory rather than lose it
+                self.hot
+                    .import(StateSnapshot {
+                        sessions: vec![cold.into_snapshot()],
+                    })
+                    .await;
+            }
+        }
+    }
+
+    /// Bring `id` back from the cold store, returning whether it was there
+    async fn restore(&self, id: &SessionId) -> bool {
+        let Ok(Some(cold)) = self.cold.take(id).await else {
+            // Another request may have restored it first
+            return self.hot.resume(id);
+        };
+        if self
+            .config
+            .session_expired(cold.idle(), cold.session.versions.keys())
+        {
+            return false;
+        }
+        self.hot
+            .import(StateSnapshot {
+                sessions: vec![cold.into_snapshot()],
+            })
+            .await;
+        self.hot.resume(id)
+    }
+}
+
+#[async_trait]
+impl StateManager for TieredStateManager {
+    async fn get_or_create_session(&self, id: Option<SessionId>) -> SessionStatus {
+        if let Some(id) = &id
+            && self.hot.resume(id)
+        {
+            return SessionStatus::Resumed(id.clone());
+        }
+        let status = match id {
+            Some(id) if self.restore(&id).await => SessionStatus::Resumed(id),
+            _ => self.hot.create(SessionId::generate()),
+        };
+        if self.hot_sessions() > self.config.max_sessions {
+            self.spill().await;
+        }
+        status
+    }
+
+    async fn has_session(&self, id: &SessionId) -> bool {
+        // A session is restored as it would be by the request presenting it
+        self.hot.has_session(id).await || self.restore(id).await
+    }
+
+    async fn get_version(&self, session: &SessionId, path: &ResourcePath) -> Option<Version> {
+        self.hot.get_version(session, path).await
+    }
+
+    async fn set_version(&self, session: &SessionId, path: &ResourcePath, version: Version) {
+        self.hot.set_version(session, path, version).await
+    }
+
+    async fn get_versions(
+        &self,
+        session: &SessionId,
+        paths: &[ResourcePath],
+    ) -> Vec<Option<Version>> {
+        self.hot.get_versions(session, paths).await
+    }
+
+    async fn set_versions(&self, session: &SessionId, versions: &[(ResourcePath, Version)]) {
+        self.hot.set_versions(session, versions).await
+    }
+
+    async fn compare_and_set_version(
+        &self,
+        session: &SessionId,
+        path: &ResourcePath,
+        expected: Option<&Version>,
+        new: Version,
+    ) -> bool {
+        self.hot
+            .compare_and_set_version(session, path, expected, new)
+            .await
+    }
+
+    async fn cleanup_expired(&self) {
+        self.hot.cleanup_expired().await;
+        self.spill().await;
+        // Expired cold sessions are also skipped on restore
+        let _ = self
+            .cold
+            .remove_expired(self.config.max_session_retention())
+            .await;
+    }
+
+    async fn export(&self) -> StateSnapshot {
+        let mut snapshot = self.hot.export().await;
+        let cold = self.cold.sessions().await.unwrap_or_default();
+        snapshot.sessions.extend(
+            cold.into_iter()
+                .filter(|s| {
+                    !self
+                        .config
+                        .session_expired(s.idle(), s.session.versions.keys())
+                })
+                .map(ColdSession::into_snapshot),
+        );
+        snapshot
+    }
+
+    async fn import(&self, snapshot: StateSnapshot) {
+        self.hot.import(snapshot).await;
+        self.spill().await;
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.hot.memory_usage()
+    }
+
+    /// Sessions in memory; those spilled to the cold store aren't counted
+    fn session_count(&self) -> usize {
+        self.hot.session_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiered(
+        max_sessions: usize,
+        spill_after: Duration,
+    ) -> (TieredStateManager, Arc<InMemoryColdStore>) {
+        let cold = Arc::new(InMemoryColdStore::new());
+        let state_mgr = TieredStateManager::with_options(
+            BpxConfig {
+                max_sessions,
+                ..BpxConfig::default()
+            },
+            cold.clone(),
+            TieredOptions { spill_after },
+        );
+        (state_mgr, cold)
+    }
+
+    #[tokio::test]
+    async fn test_excess_sessions_spill_and_restore() {
+        let (state_mgr, cold) = tiered(2, Duration::from_secs(60));
+        let path = ResourcePath::new("/api/test".to_string());
+
+        let first = state_mgr.get_or_create_session(None).await.into_id();
+        state_mgr
+            .set_version(&first, &path, Version::new("v1".to_string()))
+            .await;
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            state_mgr.get_or_create_session(None).await;
+        }
+
+        // The least recently used session went cold
+        assert_eq!(state_mgr.hot_sessions(), 2);
+        assert_eq!(cold.len(), 1);
+        assert_eq!(state_mgr.get_version(&first, &path).await, None);
+        assert_eq!(state_mgr.export().await.sessions.len(), 3);
+
+        let status = state_mgr.get_or_create_session(Some(first.clone())).await;
+        assert_eq!(status, SessionStatus::Resumed(first.clone()));
+        assert_eq!(
+            state_mgr.get_version(&first, &path).await,
+            Some(Version::new("v1".to_string()))
+        );
+        // Restoring it spilled the next least recently used session
+        assert_eq!(state_mgr.hot_sessions(), 2);
+        assert_eq!(cold.len(), 1);
+        assert!(cold.sessions.get(&first).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_spill_on_cleanup() {
+        let (state_mgr, cold) = tiered(100, Duration::from_millis(20));
+        let id = state_mgr.get_or_create_session(None).await.into_id();
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        state_mgr.cleanup_expired().await;
+        assert_eq!(state_mgr.hot_sessions(), 0);
+        assert_eq!(state_mgr.memory_usage(), 0);
+        assert_eq!(cold.len(), 1);
+
+        assert!(state_mgr.get_or_create_session(Some(id)).await.is_resumed());
+    }
+
+    #[tokio::test]
+    async fn test_expired_cold_sessions_are_not_restored() {
+        let cold = Arc::new(InMemoryColdStore::new());
+        let state_mgr = TieredStateManager::with_options(
+            BpxConfig {
+                session_ttl: Duration::from_millis(30),
+                ..BpxConfig::default()
+            },
+            cold.clone(),
+            TieredOptions {
+                spill_after: Duration::ZERO,
+            },
+        );
+        let id = state_mgr.get_or_create_session(None).await.into_id();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        state_mgr.spill().await;
+        assert_eq!(cold.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!state_mgr.get_or_create_session(Some(id)).await.is_resumed());
+    }
+}
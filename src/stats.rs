@@ -0,0 +1,211 @@
+//! Counters for what a server sends and saves, and the routes reporting them
+//!
+//! [`BpxServer`](crate::BpxServer) counts the responses it answers; with
+//! [`BpxConfig::stats_endpoints`](crate::BpxConfig::stats_endpoints) set,
+//! [`BpxLayer`](crate::BpxLayer) answers [`HEALTH_PATH`] and [`STATS_PATH`]
+//! so deployments don't each write their own.
+
+use crate::{observer::DiffDecision, protocol::headers::BpxHeaders};
+use bytes::Bytes;
+use hyper::{Response, StatusCode, header};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Route answering `200` while the server is up
+pub const HEALTH_PATH: &str = "/__bpx/health";
+
+/// Route answering a [`StatsSnapshot`] as JSON
+pub const STATS_PATH: &str = "/__bpx/stats";
+
+/// Resources and versions a [`ResourceStore`](crate::ResourceStore) holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Current resources
+    pub resources: usize,
+    /// Versions kept for diffs
+    pub versions: usize,
+}
+
+/// Responses a server answered since it started
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    diffs: AtomicU64,
+    full: AtomicU64,
+    not_modified: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_saved: AtomicU64,
+}
+
+impl ServerStats {
+    /// Count `response`, whose body is `body_len` bytes if known
+    ///
+    /// Diffs save the difference between the original size they announce and
+    /// their own; full bodies and `304`s save nothing.
+    pub fn record<B>(&self, response: &Response<B>, body_len: Option<u64>) {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            self.not_modified.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if !response.status().is_success() {
+            return;
+        }
+        let body_len = body_len.unwrap_or_default();
+        self.bytes_sent.fetch_add(body_len, Ordering::Relaxed);
+        let headers = response.headers();
+        let diff = headers
+            .get(BpxHeaders::DIFF_TYPE)
+            .is_some_and(|format| format != "full");
+        if !diff {
+            self.full.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.diffs.fetch_add(1, Ordering::Relaxed);
+        let original = headers
+            .get(BpxHeaders::ORIGINAL_SIZE)
+            .and_then(|size| size.to_str().ok()?.parse::<u64>().ok());
+        if let Some(original) = original {
+            self.bytes_saved
+                .fetch_add(original.saturating_sub(body_len), Ordering::Relaxed);
+        }
+    }
+
+    /// Count what `decision` sent for one entry of a batch, as
+    /// [`record`](Self::record) counts a resource answered alone
+    pub fn record_decision(&self, decision: &DiffDecision) {
+        let full = decision.full_size.unwrap_or_default() as u64;
+        match decision.diff_size {
+            Some(sent) => {
+                let sent = sent as u64;
+                self.diffs.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+                self.bytes_saved
+                    .fetch_add(full.saturating_sub(sent), Ordering::Relaxed);
+            }
+            None => {
+                self.full.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(full, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Current counts, alongside the session and store figures given
+    pub fn snapshot(&self, sessions: usize, store: Option<StoreStats>) -> StatsSnapshot {
+        StatsSnapshot {
+            sessions,
+            store,
+            diffs: self.diffs.load(Ordering::Relaxed),
+            full_responses: self.full.load(Ordering::Relaxed),
+            not_modified: self.not_modified.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_saved: self.bytes_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Figures reported by [`STATS_PATH`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Sessions the state manager holds
+    pub sessions: usize,
+    /// Resources and versions, if the store counts them
+    pub store: Option<StoreStats>,
+    /// Responses answered with a diff
+    pub diffs: u64,
+    /// Responses answered with the full body
+    pub full_responses: u64,
+    /// Responses answered with `304 Not Modified`
+    pub not_modified: u64,
+    /// Body bytes sent in diff and full responses
+    pub bytes_sent: u64,
+    /// Bytes diffs spared compared with sending full bodies
+    pub bytes_saved: u64,
+}
+
+impl StatsSnapshot {
+    /// Share of responses with a body that were diffs
+    pub fn diff_hit_rate(&self) -> f64 {
+        let answered = self.diffs + self.full_responses;
+        if answered == 0 {
+            return 0.0;
+        }
+        self.diffs as f64 / answered as f64
+    }
+
+    /// JSON object with every figure; store counts are `null` if unknown
+    pub fn to_json(&self) -> String {
+        let count = |n: Option<usize>| n.map_or_else(|| "null".to_string(), |n| n.to_string());
+        format!(
+            r#"{{"sessions":{},"resources":{},"versions":{},"diffs":{},"full_responses":{},"not_modified":{},"diff_hit_rate":{:.4},"bytes_sent":{},"bytes_saved":{}}}"#,
+            self.sessions,
+            count(self.store.map(|s| s.resources)),
+            count(self.store.map(|s| s.versions)),
+            self.diffs,
+            self.full_responses,
+            self.not_modified,
+            self.diff_hit_rate(),
+            self.bytes_sent,
+            self.bytes_saved,
+        )
+    }
+}
+
+/// `200` JSON response with `body`
+pub(crate) fn json_response(body: String) -> Response<Bytes> {
+    let mut response = Response::new(Bytes::from(body));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("no-store"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode, diff_type: &str, original: usize) -> Response<()> {
+        Response::builder()
+            .status(status)
+            .header(BpxHeaders::DIFF_TYPE, diff_type)
+            .header(BpxHeaders::ORIGINAL_SIZE, original.to_string())
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_records_diffs_and_savings() {
+        let stats = ServerStats::default();
+        stats.record(&response(StatusCode::OK, "full", 1000), Some(1000));
+        stats.record(&response(StatusCode::OK, "bpx-binary", 1000), Some(100));
+        stats.record(&response(StatusCode::OK, "bpx-binary", 1000), Some(300));
+        stats.record(&response(StatusCode::NOT_MODIFIED, "full", 0), None);
+        stats.record(&response(StatusCode::NOT_FOUND, "full", 0), Some(10));
+
+        let snapshot = stats.snapshot(
+            2,
+            Some(StoreStats {
+                resources: 1,
+                versions: 3,
+            }),
+        );
+        assert_eq!(snapshot.diffs, 2);
+        assert_eq!(snapshot.full_responses, 1);
+        assert_eq!(snapshot.not_modified, 1);
+        assert_eq!(snapshot.bytes_sent, 1400);
+        assert_eq!(snapshot.bytes_saved, 1600);
+        assert!((snapshot.diff_hit_rate() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{"sessions":2,"resources":1,"versions":3,"diffs":2,"full_responses":1,"not_modified":1,"diff_hit_rate":0.6667,"bytes_sent":1400,"bytes_saved":1600}"#
+        );
+        assert!(
+            stats
+                .snapshot(0, None)
+                .to_json()
+                .contains(r#""resources":null"#)
+        );
+    }
+}
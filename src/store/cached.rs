@@ -0,0 +1,415 @@
+//! In-memory cache in front of a slower resource store
+
+use super::VersionStream;
+use crate::server::{ResourceStore, ResourceStream, ResourceUpdate, single_chunk};
+use crate::{
+    BpxError, ResourcePath, Version,
+    clock::{self, Clock},
+    stats::StoreStats,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limits for [`CachedStore`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// How long current content is served from the cache before the inner
+    /// store is asked again
+    pub ttl: Duration,
+    /// Cap on cached bytes; the least recently used entries are evicted
+    /// beyond it
+    pub max_bytes: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(1),
+            max_bytes: 64 * 1024 * 1024, // 64MB
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Current(ResourcePath),
+    Version(ResourcePath, Version),
+}
+
+struct Entry {
+    content: Bytes,
+    version: Version,
+    content_type: Option<String>,
+    cached_at: Instant,
+    used: u64,
+}
+
+/// Cached entries in least recently used order
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, Entry>,
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &Key, ttl: Duration, now: Instant) -> Option<&Entry> {
+        let cached_at = self.entries.get(key)?.cached_at;
+        if matches!(key, Key::Current(_)) && now.saturating_duration_since(cached_at) > ttl {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.used);
+        entry.used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: Key, mut entry: Entry, max_bytes: usize) {
+        if entry.content.len() > max_bytes {
+            return;
+        }
+        self.remove(&key);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.bytes += entry.content.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+        while self.bytes > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = self.entries.remove(&oldest) {
+                self.bytes -= evicted.content.len();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.content.len();
+        }
+    }
+}
+
+/// Any [`ResourceStore`] fronted by an in-memory LRU cache
+///
+/// Current content is served from memory for [`CacheOptions::ttl`], so slow
+/// backends such as object storage or an upstream origin aren't fetched on
+/// every poll; clients see changes up to one TTL late. Versions never change
+/// once stored, so they are cached until evicted. Versions stored through
+/// the cache are written to the inner store and kept in the cache.
+///
+/// Reads of several resources at once go to the inner store, since entries
+/// cached at different times could mix versions from before and after an
+/// [`update_resources`](ResourceStore::update_resources).
+pub struct CachedStore<S> {
+    inner: S,
+    options: CacheOptions,
+    cache: Mutex<Lru>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<S: ResourceStore> CachedStore<S> {
+    /// Cache `inner` with default options
+    pub fn new(inner: S) -> Self {
+        Self::with_options(inner, CacheOptions::default())
+    }
+
+    /// Cache `inner` within `options`
+    pub fn with_options(inner: S, options: CacheOptions) -> Self {
+        Self {
+            inner,
+            options,
+            cache: Mutex::new(Lru::default()),
+            clock: clock::system(),
+        }
+    }
+
+    /// Time [`CacheOptions::ttl`] with `clock` instead of the system clock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The store behind the cache
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Forget the cached current content of `path`, e.g. after writing to
+    /// the inner store directly
+    pub fn invalidate(&self, path: &ResourcePath) {
+        self.lock().remove(&Key::Current(path.clone()));
+    }
+
+    /// Bytes of content held in the cache
+    pub fn cached_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn cached(&self, key: &Key) -> Option<(Bytes, Version)> {
+        self.lock()
+            .get(key, self.options.ttl, self.clock.now())
+            .map(|entry| (entry.content.clone(), entry.version.clone()))
+    }
+
+    fn cache(&self, key: Key, content: Bytes, version: Version, content_type: Option<String>) {
+        let entry = Entry {
+            content,
+            version,
+            content_type,
+            cached_at: self.clock.now(),
+            used: 0,
+        };
+        self.lock().insert(key, entry, self.options.max_bytes);
+    }
+}
+
+#[async_trait]
+impl<S: ResourceStore> ResourceStore for CachedStore<S> {
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+        let (content, _) = self.get_versioned_resource(path).await?;
+        Ok(content)
+    }
+
+    async fn get_versioned_resource(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(Bytes, Version), BpxError> {
+        let key = Key::Current(path.clone());
+        if let Some(cached) = self.cached(&key) {
+            return Ok(cached);
+        }
+        let (content, version) = self.inner.get_versioned_resource(path).await?;
+        let content_type = self.inner.get_content_type(path).await;
+        self.cache(key, content.clone(), version.clone(), content_type);
+        Ok((content, version))
+    }
+
+    async fn get_versioned_resources(
+        &self,
+        paths: &[ResourcePath],
+    ) -> Vec<Result<(Bytes, Version), BpxError>> {
+        self.inner.get_versioned_resources(paths).await
+    }
+
+    async fn get_resource_stream(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(ResourceStream, Version), BpxError> {
+        match self.cached(&Key::Current(path.clone())) {
+            Some((content, version)) => Ok((single_chunk(content), version)),
+            // Large bodies are streamed past the cache
+            None => self.inner.get_resource_stream(path).await,
+        }
+    }
+
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError> {
+        let key = Key::Version(path.clone(), version.clone());
+        if let Some((content, _)) = self.cached(&key) {
+            return Ok(content);
+        }
+        let content = self.inner.get_resource_version(path, version).await?;
+        self.cache(key, content.clone(), version.clone(), None);
+        Ok(content)
+    }
+
+    async fn put_resource(
+        &self,
+        path: &ResourcePath,
+        content: Bytes,
+        expected: Option<&Version>,
+    ) -> Result<Version, BpxError> {
+        self.invalidate(path);
+        self.inner.put_resource(path, content, expected).await
+    }
+
+    async fn update_resources(&self, update: ResourceUpdate) -> Result<(), BpxError> {
+        for path in update.paths() {
+            self.invalidate(path);
+        }
+        self.inner.update_resources(update).await
+    }
+
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        let key = Key::Version(path.clone(), version.clone());
+        self.cached(&key).is_some() || self.inner.has_version(path, version).await
+    }
+
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError> {
+        self.inner
+            .store_version(path.clone(), version.clone(), content.clone())
+            .await?;
+        self.cache(Key::Version(path, version.clone()), content, version, None);
+        Ok(())
+    }
+
+    async fn get_content_type(&self, path: &ResourcePath) -> Option<String> {
+        let cached = self
+            .lock()
+            .get(
+                &Key::Current(path.clone()),
+                self.options.ttl,
+                self.clock.now(),
+            )
+            .map(|entry| entry.content_type.clone());
+        match cached {
+            Some(content_type) => content_type,
+            None => self.inner.get_content_type(path).await,
+        }
+    }
+
+    async fn compact(&self) {
+        self.inner.compact().await
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourcePath>, BpxError> {
+        self.inner.list_resources().await
+    }
+
+    async fn list_versions(&self, path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        self.inner.list_versions(path).await
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn watch(&self, path: &ResourcePath) -> Option<VersionStream> {
+        self.inner.watch(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemoryResourceStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// In-memory store counting reads of current content
+    #[derive(Default)]
+    struct Counting {
+        store: InMemoryResourceStore,
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ResourceStore for Counting {
+        async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.store.get_resource(path).await
+        }
+
+        async fn get_resource_version(
+            &self,
+            path: &ResourcePath,
+            version: &Version,
+        ) -> Result<Bytes, BpxError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.store.get_resource_version(path, version).await
+        }
+
+        async fn store_version(
+            &self,
+            path: ResourcePath,
+            version: Version,
+            content: Bytes,
+        ) -> Result<(), BpxError> {
+            ResourceStore::store_version(&self.store, path, version, content).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_current_content_is_cached_for_ttl() {
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let cached = CachedStore::with_options(
+            Counting::default(),
+            CacheOptions {
+                ttl: Duration::from_millis(30),
+                ..CacheOptions::default()
+            },
+        )
+        .clock(clock.clone());
+        let path = ResourcePath::new("/api/feed".to_string());
+        cached
+            .inner()
+            .store
+            .set_resource(path.clone(), Bytes::from("v1"));
+
+        for _ in 0..3 {
+            assert_eq!(cached.get_resource(&path).await.unwrap(), "v1");
+        }
+        assert_eq!(cached.inner().reads.load(Ordering::Relaxed), 1);
+
+        cached
+            .inner()
+            .store
+            .set_resource(path.clone(), Bytes::from("v2"));
+        assert_eq!(cached.get_resource(&path).await.unwrap(), "v1");
+        clock.advance(Duration::from_millis(40));
+        assert_eq!(cached.get_resource(&path).await.unwrap(), "v2");
+        assert_eq!(cached.inner().reads.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_versions_are_cached_within_size() {
+        let cached = CachedStore::with_options(
+            Counting::default(),
+            CacheOptions {
+                max_bytes: 8,
+                ..CacheOptions::default()
+            },
+        );
+        let path = ResourcePath::new("/api/feed".to_string());
+        let version = |i: usize| Version::new(format!("v{}", i));
+
+        for i in 0..3 {
+            cached
+                .store_version(path.clone(), version(i), Bytes::from(format!("{:04}", i)))
+                .await
+                .unwrap();
+        }
+        // Only the two most recent fit
+        assert_eq!(cached.cached_bytes(), 8);
+        assert_eq!(
+            cached
+                .get_resource_version(&path, &version(2))
+                .await
+                .unwrap(),
+            "0002"
+        );
+        assert_eq!(cached.inner().reads.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            cached
+                .get_resource_version(&path, &version(0))
+                .await
+                .unwrap(),
+            "0000"
+        );
+        assert_eq!(cached.inner().reads.load(Ordering::Relaxed), 1);
+        assert_eq!(cached.cached_bytes(), 8);
+    }
+}
@@ -0,0 +1,273 @@
+//! Encryption of stored versions before they reach the inner store
+
+use super::VersionStream;
+use crate::server::{ResourceStore, ResourceStream, ResourceUpdate};
+use crate::{BpxError, ResourcePath, Version, stats::StoreStats};
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, OsRng, Payload},
+};
+
+/// Leading byte of a payload sealed with XChaCha20-Poly1305
+const XCHACHA20_POLY1305: u8 = 1;
+
+/// Bytes of an XChaCha20-Poly1305 nonce
+const NONCE_SIZE: usize = 24;
+
+/// Any [`ResourceStore`] whose stored versions are encrypted at rest
+///
+/// Versions kept for diffing often hold user data long after the live
+/// resource has moved on. Each version passed to
+/// [`store_version`](ResourceStore::store_version) is sealed with
+/// XChaCha20-Poly1305 under a random nonce before the inner store sees it,
+/// with its path and version as associated data, so a payload moved to
+/// another key fails to open. Versions that fail to open are reported as
+/// [`BpxError::Storage`]. Current content and everything else go to the
+/// inner store as they are.
+pub struct EncryptedStore<S> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+    previous: Vec<XChaCha20Poly1305>,
+}
+
+impl<S: ResourceStore> EncryptedStore<S> {
+    /// Encrypt the versions `inner` stores with the 256-bit `key`
+    pub fn new(inner: S, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+            previous: Vec::new(),
+        }
+    }
+
+    /// Also open versions sealed with `key`, while rotating away from it
+    pub fn previous_key(mut self, key: &[u8; 32]) -> Self {
+        self.previous.push(XChaCha20Poly1305::new(key.into()));
+        self
+    }
+
+    /// The store holding the encrypted versions
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn seal(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+        content: &[u8],
+    ) -> Result<Bytes, BpxError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(path, version);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: content,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| storage_error(path, version, "encrypt"))?;
+        let mut payload = BytesMut::with_capacity(1 + NONCE_SIZE + sealed.len());
+        payload.put_u8(XCHACHA20_POLY1305);
+        payload.put_slice(&nonce);
+        payload.put_slice(&sealed);
+        Ok(payload.freeze())
+    }
+
+    fn open(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+        payload: &[u8],
+    ) -> Result<Bytes, BpxError> {
+        let Some((&XCHACHA20_POLY1305, rest)) = payload.split_first() else {
+            return Err(storage_error(path, version, "recognize"));
+        };
+        if rest.len() < NONCE_SIZE {
+            return Err(storage_error(path, version, "recognize"));
+        }
+        let (nonce, sealed) = rest.split_at(NONCE_SIZE);
+        let nonce = XNonce::from_slice(nonce);
+        let aad = associated_data(path, version);
+        std::iter::once(&self.cipher)
+            .chain(&self.previous)
+            .find_map(|cipher| {
+                cipher
+                    .decrypt(
+                        nonce,
+                        Payload {
+                            msg: sealed,
+                            aad: &aad,
+                        },
+                    )
+                    .ok()
+            })
+            .map(Bytes::from)
+            .ok_or_else(|| storage_error(path, version, "decrypt"))
+    }
+}
+
+/// Binds a sealed payload to the path and version it is stored under
+fn associated_data(path: &ResourcePath, version: &Version) -> Vec<u8> {
+    let path = path.to_string();
+    let version = version.to_string();
+    let mut aad = Vec::with_capacity(8 + path.len() + version.len());
+    aad.extend_from_slice(&(path.len() as u64).to_be_bytes());
+    aad.extend_from_slice(path.as_bytes());
+    aad.extend_from_slice(version.as_bytes());
+    aad
+}
+
+fn storage_error(path: &ResourcePath, version: &Version, action: &str) -> BpxError {
+    BpxError::Storage {
+        reason: format!("Failed to {} version {} of {}", action, version, path),
+    }
+}
+
+#[async_trait]
+impl<S: ResourceStore> ResourceStore for EncryptedStore<S> {
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+        self.inner.get_resource(path).await
+    }
+
+    async fn get_versioned_resource(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(Bytes, Version), BpxError> {
+        self.inner.get_versioned_resource(path).await
+    }
+
+    async fn get_versioned_resources(
+        &self,
+        paths: &[ResourcePath],
+    ) -> Vec<Result<(Bytes, Version), BpxError>> {
+        self.inner.get_versioned_resources(paths).await
+    }
+
+    async fn get_resource_stream(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(ResourceStream, Version), BpxError> {
+        self.inner.get_resource_stream(path).await
+    }
+
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError> {
+        let payload = self.inner.get_resource_version(path, version).await?;
+        self.open(path, version, &payload)
+    }
+
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.inner.has_version(path, version).await
+    }
+
+    async fn put_resource(
+        &self,
+        path: &ResourcePath,
+        content: Bytes,
+        expected: Option<&Version>,
+    ) -> Result<Version, BpxError> {
+        self.inner.put_resource(path, content, expected).await
+    }
+
+    async fn update_resources(&self, update: ResourceUpdate) -> Result<(), BpxError> {
+        self.inner.update_resources(update).await
+    }
+
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError> {
+        let payload = self.seal(&path, &version, &content)?;
+        self.inner.store_version(path, version, payload).await
+    }
+
+    async fn get_content_type(&self, path: &ResourcePath) -> Option<String> {
+        self.inner.get_content_type(path).await
+    }
+
+    async fn compact(&self) {
+        self.inner.compact().await
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourcePath>, BpxError> {
+        self.inner.list_resources().await
+    }
+
+    async fn list_versions(&self, path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        self.inner.list_versions(path).await
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn watch(&self, path: &ResourcePath) -> Option<VersionStream> {
+        self.inner.watch(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InMemoryResourceStore;
+
+    #[tokio::test]
+    async fn test_versions_are_sealed_and_bound_to_their_key() {
+        let store = EncryptedStore::new(InMemoryResourceStore::new(), &[7; 32]);
+        let path = ResourcePath::new("/api/profile".to_string());
+        let (v1, v2) = (
+            Version::new("v1".to_string()),
+            Version::new("v2".to_string()),
+        );
+        let secret = Bytes::from_static(b"{\"email\":\"user@example.com\"}");
+
+        store
+            .store_version(path.clone(), v1.clone(), secret.clone())
+            .await
+            .unwrap();
+        let sealed = store
+            .inner()
+            .get_resource_version(&path, &v1)
+            .await
+            .unwrap();
+        assert_eq!(sealed[0], XCHACHA20_POLY1305);
+        assert!(!sealed.windows(5).any(|w| w == b"email"));
+        assert_eq!(
+            store.get_resource_version(&path, &v1).await.unwrap(),
+            secret
+        );
+
+        // A payload copied under another version doesn't open
+        store
+            .inner()
+            .store_version(path.clone(), v2.clone(), sealed);
+        assert!(matches!(
+            store.get_resource_version(&path, &v2).await,
+            Err(BpxError::Storage { .. })
+        ));
+
+        // After rotation, versions sealed with the old key still open
+        let payload = store.seal(&path, &v1, &secret).unwrap();
+        for (previous, opens) in [(None, false), (Some(&[7; 32]), true)] {
+            let mut rotated = EncryptedStore::new(InMemoryResourceStore::new(), &[9; 32]);
+            if let Some(key) = previous {
+                rotated = rotated.previous_key(key);
+            }
+            rotated
+                .inner()
+                .store_version(path.clone(), v1.clone(), payload.clone());
+            let opened = rotated.get_resource_version(&path, &v1).await;
+            assert_eq!(opened.ok(), opens.then(|| secret.clone()));
+        }
+    }
+}
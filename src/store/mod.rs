@@ -0,0 +1,17 @@
+//! Resource stores beyond [`InMemoryResourceStore`](crate::InMemoryResourceStore)
+
+mod cached;
+#[cfg(feature = "encryption")]
+mod encrypted;
+#[cfg(feature = "object-store")]
+mod object;
+mod origin;
+mod watch;
+
+pub use cached::{CacheOptions, CachedStore};
+#[cfg(feature = "encryption")]
+pub use encrypted::EncryptedStore;
+#[cfg(feature = "object-store")]
+pub use object::ObjectResourceStore;
+pub use origin::HttpOriginStore;
+pub use watch::{VersionBroadcast, VersionStream};
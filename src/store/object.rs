@@ -0,0 +1,406 @@
+//! Resource store backed by object storage (S3, GCS, Azure, ...)
+
+use crate::server::{ResourceStore, ResourceStream, single_chunk};
+use crate::{BpxError, ResourcePath, Version};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_core::{Stream, stream::BoxStream};
+use object_store::{
+    Attribute, Attributes, GetOptions, GetResult, ObjectMeta, ObjectStore, PutOptions, PutPayload,
+    path::Path,
+};
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// Resources and their version history kept in an [`ObjectStore`]
+///
+/// Replicas pointed at the same bucket and prefix share version history, so a
+/// base recorded by one can be diffed against by another. Current content
+/// lives under `{prefix}/current/{path}` and versions under
+/// `{prefix}/versions/{path}/{version}`.
+///
+/// A resource's version is its object's ETag, so full responses stream
+/// straight from the bucket without hashing the content first.
+///
+/// Expire old versions with the bucket's lifecycle rules.
+pub struct ObjectResourceStore {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl ObjectResourceStore {
+    /// Create a store keeping objects under `prefix` in `store`
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: Path) -> Self {
+        Self { store, prefix }
+    }
+
+    /// Publish a resource's current content
+    pub async fn set_resource(
+        &self,
+        path: &ResourcePath,
+        content: Bytes,
+        content_type: Option<&str>,
+    ) -> Result<(), BpxError> {
+        let mut attributes = Attributes::new();
+        if let Some(content_type) = content_type {
+            attributes.insert(Attribute::ContentType, content_type.to_string().into());
+        }
+        let options = PutOptions {
+            attributes,
+            ..PutOptions::default()
+        };
+        self.store
+            .put_opts(&self.current_key(path), PutPayload::from(content), options)
+            .await
+            .map_err(|e| storage_error(e, path))?;
+        Ok(())
+    }
+
+    fn current_key(&self, path: &ResourcePath) -> Path {
+        self.prefix.child("current").child(path.to_string())
+    }
+
+    /// Objects under `prefix`, oldest first
+    async fn list(&self, prefix: &Path) -> Result<Vec<ObjectMeta>, BpxError> {
+        let mut listing = self.store.list(Some(prefix));
+        let mut objects = Vec::new();
+        while let Some(meta) = std::future::poll_fn(|cx| listing.as_mut().poll_next(cx)).await {
+            objects.push(meta.map_err(|e| BpxError::Storage {
+                reason: e.to_string(),
+            })?);
+        }
+        objects.sort_by_key(|meta| meta.last_modified);
+        Ok(objects)
+    }
+
+    fn version_key(&self, path: &ResourcePath, version: &Version) -> Path {
+        self.prefix
+            .child("versions")
+            .child(path.to_string())
+            .child(version.to_string())
+    }
+}
+
+/// Decode the last segment of an object key back to the string it was made from
+fn key_name(location: &Path) -> Option<String> {
+    let encoded = location.filename()?.as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .filter(|_| encoded[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Version of the object behind `result`: its ETag, or failing that a hash
+/// of the content
+fn object_version(result: &GetResult) -> Option<Version> {
+    let e_tag = result.meta.e_tag.as_deref()?;
+    let e_tag = e_tag.strip_prefix("W/").unwrap_or(e_tag).trim_matches('"');
+    Some(Version::new(e_tag.to_string()))
+}
+
+/// Object content with storage errors mapped to [`BpxError`]
+struct ObjectStream {
+    chunks: BoxStream<'static, object_store::Result<Bytes>>,
+    path: ResourcePath,
+}
+
+impl Stream for ObjectStream {
+    type Item = Result<Bytes, BpxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.chunks
+            .as_mut()
+            .poll_next(cx)
+            .map(|chunk| chunk.map(|chunk| chunk.map_err(|e| storage_error(e, &self.path))))
+    }
+}
+
+/// Missing objects read as missing resources, anything else as a storage failure
+fn storage_error(error: object_store::Error, path: &ResourcePath) -> BpxError {
+    match error {
+        object_store::Error::NotFound { .. } => BpxError::ResourceNotFound { path: path.clone() },
+        error => BpxError::Storage {
+            reason: error.to_string(),
+        },
+    }
+}
+
+/// Like [`storage_error`], for reads of a stored version
+fn version_error(error: object_store::Error, path: &ResourcePath, version: &Version) -> BpxError {
+    match error {
+        object_store::Error::NotFound { .. } => BpxError::VersionNotFound {
+            path: path.clone(),
+            version: version.clone(),
+        },
+        error => storage_error(error, path),
+    }
+}
+
+#[async_trait]
+impl ResourceStore for ObjectResourceStore {
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+        let result = self
+            .store
+            .get(&self.current_key(path))
+            .await
+            .map_err(|e| storage_error(e, path))?;
+        result.bytes().await.map_err(|e| storage_error(e, path))
+    }
+
+    async fn get_versioned_resource(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(Bytes, Version), BpxError> {
+        let result = self
+            .store
+            .get(&self.current_key(path))
+            .await
+            .map_err(|e| storage_error(e, path))?;
+        let version = object_version(&result);
+        let content = result.bytes().await.map_err(|e| storage_error(e, path))?;
+        let version = version.unwrap_or_else(|| Version::from_content(&content));
+        Ok((content, version))
+    }
+
+    async fn get_resource_stream(
+        &self,
+        path: &ResourcePath,
+    ) -> Result<(ResourceStream, Version), BpxError> {
+        let result = self
+            .store
+            .get(&self.current_key(path))
+            .await
+            .map_err(|e| storage_error(e, path))?;
+        let Some(version) = object_version(&result) else {
+            // Without an ETag the version needs the whole content
+            let content = result.bytes().await.map_err(|e| storage_error(e, path))?;
+            let version = Version::from_content(&content);
+            return Ok((single_chunk(content), version));
+        };
+        let chunks = ObjectStream {
+            chunks: result.into_stream(),
+            path: path.clone(),
+        };
+        Ok((Box::pin(chunks), version))
+    }
+
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError> {
+        let result = self
+            .store
+            .get(&self.version_key(path, version))
+            .await
+            .map_err(|e| version_error(e, path, version))?;
+        result
+            .bytes()
+            .await
+            .map_err(|e| version_error(e, path, version))
+    }
+
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.store
+            .head(&self.version_key(path, version))
+            .await
+            .is_ok()
+    }
+
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError> {
+        self.store
+            .put(
+                &self.version_key(&path, &version),
+                PutPayload::from(content),
+            )
+            .await
+            .map_err(|e| storage_error(e, &path))?;
+        Ok(())
+    }
+
+    async fn list_resources(&self) -> Result<Vec<ResourcePath>, BpxError> {
+        let objects = self.list(&self.prefix.child("current")).await?;
+        Ok(objects
+            .iter()
+            .filter_map(|meta| key_name(&meta.location))
+            .map(ResourcePath::new)
+            .collect())
+    }
+
+    async fn list_versions(&self, path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        let prefix = self.prefix.child("versions").child(path.to_string());
+        let objects = self.list(&prefix).await?;
+        Ok(objects
+            .iter()
+            .filter_map(|meta| key_name(&meta.location))
+            .map(Version::new)
+            .collect())
+    }
+
+    async fn get_content_type(&self, path: &ResourcePath) -> Option<String> {
+        let options = GetOptions {
+            head: true,
+            ..GetOptions::default()
+        };
+        let result = self
+            .store
+            .get_opts(&self.current_key(path), options)
+            .await
+            .ok()?;
+        result
+            .attributes
+            .get(&Attribute::ContentType)
+            .map(|value| value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn test_replicas_share_versions() {
+        let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let first = ObjectResourceStore::new(Arc::clone(&bucket), Path::from("bpx"));
+        let second = ObjectResourceStore::new(bucket, Path::from("bpx"));
+        let path = ResourcePath::new("/api/users?page=1".to_string());
+        let content = Bytes::from_static(b"[{\"id\":1}]");
+
+        first
+            .set_resource(&path, content.clone(), Some("application/json"))
+            .await
+            .unwrap();
+        assert_eq!(second.get_resource(&path).await.unwrap(), content);
+        assert_eq!(
+            second.get_content_type(&path).await.as_deref(),
+            Some("application/json")
+        );
+
+        let version = Version::from_content(&content);
+        first
+            .store_version(path.clone(), version.clone(), content.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            second.get_resource_version(&path, &version).await.unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_version_waits_for_the_write() {
+        let store = ObjectResourceStore::new(Arc::new(InMemory::new()), Path::from("bpx"));
+        let path = ResourcePath::new("/api/feed".to_string());
+        let content = Bytes::from_static(b"feed");
+        let version = Version::from_content(&content);
+
+        assert!(matches!(
+            store.get_resource(&path).await,
+            Err(BpxError::ResourceNotFound { .. })
+        ));
+        assert!(matches!(
+            store.get_resource_version(&path, &version).await,
+            Err(BpxError::VersionNotFound { .. })
+        ));
+        store
+            .store_version(path.clone(), version.clone(), content.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_resource_version(&path, &version).await.unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streams_current_content_by_etag() {
+        let store = ObjectResourceStore::new(Arc::new(InMemory::new()), Path::from("bpx"));
+        let path = ResourcePath::new("/api/feed".to_string());
+        let content = Bytes::from_static(b"feed");
+        store
+            .set_resource(&path, content.clone(), None)
+            .await
+            .unwrap();
+
+        let (mut chunks, version) = store.get_resource_stream(&path).await.unwrap();
+        let mut streamed = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| chunks.as_mut().poll_next(cx)).await {
+            streamed.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(streamed, content);
+        assert_eq!(
+            store.get_versioned_resource(&path).await.unwrap(),
+            (content, version.clone())
+        );
+
+        store
+            .set_resource(&path, Bytes::from_static(b"feed 2"), None)
+            .await
+            .unwrap();
+        let (_, next) = store.get_versioned_resource(&path).await.unwrap();
+        assert_ne!(next, version);
+    }
+
+    #[tokio::test]
+    async fn test_listing() {
+        let store = ObjectResourceStore::new(Arc::new(InMemory::new()), Path::from("bpx"));
+        let path = ResourcePath::new("/api/feed".to_string());
+        let other = ResourcePath::new("/api/feed/archive".to_string());
+        store
+            .set_resource(&path, Bytes::from_static(b"feed"), None)
+            .await
+            .unwrap();
+        store
+            .set_resource(&other, Bytes::from_static(b"old"), None)
+            .await
+            .unwrap();
+        for version in ["v1", "v:2"] {
+            store
+                .store_version(
+                    path.clone(),
+                    Version::new(version.to_string()),
+                    Bytes::new(),
+                )
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        }
+
+        let mut resources = store.list_resources().await.unwrap();
+        resources.sort_by_key(|p| p.to_string());
+        assert_eq!(resources, vec![path.clone(), other.clone()]);
+        assert_eq!(
+            store.list_versions(&path).await.unwrap(),
+            vec![
+                Version::new("v1".to_string()),
+                Version::new("v:2".to_string())
+            ]
+        );
+        assert!(store.list_versions(&other).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+//! Resource store proxying an upstream HTTP service
+
+use crate::client::{HttpTransport, HyperTransport};
+use crate::server::{InMemoryResourceStore, ResourceStore, VersionRetention};
+use crate::{BpxError, ResourcePath, Version, stats::StoreStats};
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use hyper::{
+    HeaderMap, Method, Request, StatusCode,
+    header::{self, HeaderValue},
+};
+
+/// Last response from the origin for one path
+struct Cached {
+    content: Bytes,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    content_type: Option<String>,
+}
+
+/// Resources fetched from an upstream REST service
+///
+/// Every [`get_resource`](ResourceStore::get_resource) issues a GET to
+/// `{origin}{path}`, made conditional with the `ETag`/`Last-Modified` of the
+/// previous response, so an unchanged resource costs the origin a `304`.
+/// Versions sent to clients are kept locally. Put this behind a
+/// [`BpxServer`](crate::BpxServer) to run BPX as a diffing reverse proxy in
+/// front of an unmodified backend.
+///
+/// Uses a plain-HTTP [`HyperTransport`] by default; pass any
+/// [`HttpTransport`] to [`with_transport`](Self::with_transport) for TLS or
+/// custom clients.
+pub struct HttpOriginStore<T = HyperTransport> {
+    transport: T,
+    origin: String,
+    headers: HeaderMap,
+    cache: DashMap<ResourcePath, Cached>,
+    versions: InMemoryResourceStore,
+}
+
+impl HttpOriginStore<HyperTransport> {
+    /// Create a store proxying `origin` (e.g. `http://backend:8080`)
+    pub fn new(origin: impl Into<String>) -> Self {
+        Self::with_transport(origin, HyperTransport::new())
+    }
+}
+
+impl<T: HttpTransport> HttpOriginStore<T> {
+    /// Create a store proxying `origin` through `transport`
+    pub fn with_transport(origin: impl Into<String>, transport: T) -> Self {
+        Self {
+            transport,
+            origin: origin.into().trim_end_matches('/').to_string(),
+            headers: HeaderMap::new(),
+            cache: DashMap::new(),
+            versions: InMemoryResourceStore::new(),
+        }
+    }
+
+    /// Keep the local version history within `retention`
+    pub fn with_retention(mut self, retention: VersionRetention) -> Self {
+        self.versions = InMemoryResourceStore::with_retention(retention);
+        self
+    }
+
+    /// Send `headers` (e.g. `Authorization`) with every origin request
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    fn request(&self, path: &ResourcePath) -> Result<Request<Bytes>, BpxError> {
+        let mut request = Request::builder()
+            .method(Method::GET)
+            .uri(format!("{}{}", self.origin, path))
+            .body(Bytes::new())
+            .map_err(|e| BpxError::InvalidRequest {
+                reason: format!("Invalid origin URL for {}: {}", path, e),
+            })?;
+        let headers = request.headers_mut();
+        headers.extend(self.headers.clone());
+        if let Some(cached) = self.cache.get(path) {
+            if let Some(etag) = &cached.etag {
+                headers.insert(header::IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                headers.insert(header::IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[async_trait]
+impl<T: HttpTransport> ResourceStore for HttpOriginStore<T> {
+    async fn get_resource(&self, path: &ResourcePath) -> Result<Bytes, BpxError> {
+        let response = self.transport.send(self.request(path)?).await?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED => {
+                if let Some(cached) = self.cache.get(path) {
+                    return Ok(cached.content.clone());
+                }
+                Err(BpxError::Transport {
+                    reason: format!("Origin answered 304 for uncached {}", path),
+                })
+            }
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                self.cache.remove(path);
+                Err(BpxError::ResourceNotFound { path: path.clone() })
+            }
+            status if status.is_success() => {
+                let headers = response.headers();
+                let cached = Cached {
+                    content: response.body().clone(),
+                    etag: headers.get(header::ETAG).cloned(),
+                    last_modified: headers.get(header::LAST_MODIFIED).cloned(),
+                    content_type: headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string),
+                };
+                let content = cached.content.clone();
+                self.cache.insert(path.clone(), cached);
+                Ok(content)
+            }
+            status => Err(BpxError::Transport {
+                reason: format!("Origin answered {} for {}", status, path),
+            }),
+        }
+    }
+
+    async fn get_resource_version(
+        &self,
+        path: &ResourcePath,
+        version: &Version,
+    ) -> Result<Bytes, BpxError> {
+        self.versions.get_resource_version(path, version).await
+    }
+
+    async fn has_version(&self, path: &ResourcePath, version: &Version) -> bool {
+        self.versions.has_version(path, version).await
+    }
+
+    async fn store_version(
+        &self,
+        path: ResourcePath,
+        version: Version,
+        content: Bytes,
+    ) -> Result<(), BpxError> {
+        self.versions.store_version(path, version, content);
+        Ok(())
+    }
+
+    async fn list_versions(&self, path: &ResourcePath) -> Result<Vec<Version>, BpxError> {
+        self.versions.list_versions(path).await
+    }
+
+    async fn get_content_type(&self, path: &ResourcePath) -> Option<String> {
+        self.cache
+            .get(path)
+            .and_then(|cached| cached.content_type.clone())
+    }
+
+    async fn compact(&self) {
+        self.versions.compact()
+    }
+
+    fn stats(&self) -> Option<StoreStats> {
+        Some(StoreStats {
+            resources: self.cache.len(),
+            versions: self.versions.version_count(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Response;
+    use std::sync::Mutex;
+
+    /// Origin serving one resource with an `ETag`, recording requests
+    #[derive(Default)]
+    struct Origin {
+        content: Mutex<&'static str>,
+        requests: Mutex<Vec<Request<Bytes>>>,
+    }
+
+    #[async_trait]
+    impl HttpTransport for Origin {
+        async fn send(&self, req: Request<Bytes>) -> Result<hyper::Response<Bytes>, BpxError> {
+            let content = *self.content.lock().unwrap();
+            let etag = format!("\"{}\"", content.len());
+            let fresh = req
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .is_some_and(|v| v == etag.as_str());
+            let found = req.uri().path() == "/api/users";
+            self.requests.lock().unwrap().push(req);
+
+            let response = Response::builder().header(header::ETAG, &etag);
+            Ok(match (found, fresh) {
+                (false, _) => response.status(404).body(Bytes::new()),
+                (true, true) => response.status(304).body(Bytes::new()),
+                (true, false) => response
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Bytes::from(content)),
+            }
+            .unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_fetches() {
+        let origin = std::sync::Arc::new(Origin::default());
+        *origin.content.lock().unwrap() = "[1]";
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer t"));
+        let store =
+            HttpOriginStore::with_transport("http://origin/", origin.clone()).with_headers(headers);
+        let path = ResourcePath::new("/api/users".to_string());
+
+        assert_eq!(store.get_resource(&path).await.unwrap(), "[1]");
+        assert_eq!(
+            store.get_content_type(&path).await.as_deref(),
+            Some("application/json")
+        );
+        // Unchanged: answered from the cache after a 304
+        assert_eq!(store.get_resource(&path).await.unwrap(), "[1]");
+        *origin.content.lock().unwrap() = "[1,2]";
+        assert_eq!(store.get_resource(&path).await.unwrap(), "[1,2]");
+
+        let requests = origin.requests.lock().unwrap();
+        assert_eq!(requests[0].uri(), "http://origin/api/users");
+        assert_eq!(requests[0].headers()[header::AUTHORIZATION], "Bearer t");
+        assert!(!requests[0].headers().contains_key(header::IF_NONE_MATCH));
+        assert_eq!(requests[1].headers()[header::IF_NONE_MATCH], "\"3\"");
+    }
+
+    #[tokio::test]
+    async fn test_missing_resources_and_versions() {
+        let store = HttpOriginStore::with_transport(
+            "http://origin",
+            std::sync::Arc::new(Origin::default()),
+        );
+        let path = ResourcePath::new("/missing".to_string());
+        assert!(matches!(
+            store.get_resource(&path).await,
+            Err(BpxError::ResourceNotFound { .. })
+        ));
+
+        let version = Version::new("v1".to_string());
+        store
+            .store_version(path.clone(), version.clone(), Bytes::from_static(b"old"))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_resource_version(&path, &version).await.unwrap(),
+            "old"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Change notifications for resource stores
+
+use crate::{ResourcePath, Version};
+use dashmap::DashMap;
+use futures_core::Stream;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::broadcast::{self, Receiver, Sender, error::RecvError};
+
+/// Versions a watched resource changes to, as returned by
+/// [`ResourceStore::watch`](crate::ResourceStore::watch)
+pub type VersionStream = Pin<Box<dyn Stream<Item = Version> + Send>>;
+
+/// Versions buffered per watcher before slow watchers skip ahead
+const WATCH_CAPACITY: usize = 16;
+
+/// Per-path broadcast of new versions, for stores implementing `watch`
+///
+/// A watcher that falls more than a few changes behind skips to the most
+/// recent ones; only the latest version matters when diffing.
+#[derive(Default)]
+pub struct VersionBroadcast {
+    senders: DashMap<String, Sender<Version>>,
+}
+
+impl VersionBroadcast {
+    /// Create a broadcast with no watchers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stream the versions announced for `path` from now on
+    pub fn subscribe(&self, path: &ResourcePath) -> VersionStream {
+        let receiver = self
+            .senders
+            .entry(path.to_string())
+            .or_insert_with(|| broadcast::channel(WATCH_CAPACITY).0)
+            .subscribe();
+        Box::pin(WatchStream::new(receiver))
+    }
+
+    /// Announce that `path` changed to `version`
+    pub fn notify(&self, path: &ResourcePath, version: Version) {
+        let path = path.to_string();
+        let unwatched = match self.senders.get(&path) {
+            Some(sender) => sender.send(version).is_err(),
+            None => return,
+        };
+        if unwatched {
+            // Every watcher dropped its stream
+            self.senders
+                .remove_if(&path, |_, sender| sender.receiver_count() == 0);
+        }
+    }
+
+    /// Whether `path` has any watchers
+    pub fn is_watched(&self, path: &ResourcePath) -> bool {
+        self.senders
+            .get(&path.to_string())
+            .is_some_and(|sender| sender.receiver_count() > 0)
+    }
+
+    /// Number of paths with at least one watcher
+    pub fn watched_paths(&self) -> usize {
+        self.senders
+            .iter()
+            .filter(|sender| sender.receiver_count() > 0)
+            .count()
+    }
+}
+
+type Recv = Pin<Box<dyn Future<Output = (Result<Version, RecvError>, Receiver<Version>)> + Send>>;
+
+/// [`Stream`] over a broadcast receiver
+struct WatchStream {
+    recv: Recv,
+}
+
+impl WatchStream {
+    fn new(receiver: Receiver<Version>) -> Self {
+        Self {
+            recv: Self::recv(receiver),
+        }
+    }
+
+    fn recv(mut receiver: Receiver<Version>) -> Recv {
+        Box::pin(async move { (receiver.recv().await, receiver) })
+    }
+}
+
+impl Stream for WatchStream {
+    type Item = Version;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Version>> {
+        loop {
+            let (result, receiver) = match self.recv.as_mut().poll(cx) {
+                Poll::Ready(received) => received,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.recv = Self::recv(receiver);
+            match result {
+                Ok(version) => return Poll::Ready(Some(version)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next(stream: &mut VersionStream) -> Option<Version> {
+        std::future::poll_fn(|cx| stream.as_mut().poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_watchers_receive_versions_for_their_path() {
+        let broadcast = VersionBroadcast::new();
+        let path = ResourcePath::new("/api/a".to_string());
+        let other = ResourcePath::new("/api/b".to_string());
+        let mut first = broadcast.subscribe(&path);
+        let mut second = broadcast.subscribe(&path);
+
+        broadcast.notify(&other, Version::new("b1".to_string()));
+        broadcast.notify(&path, Version::new("a1".to_string()));
+        assert_eq!(next(&mut first).await, Some(Version::new("a1".to_string())));
+        assert_eq!(
+            next(&mut second).await,
+            Some(Version::new("a1".to_string()))
+        );
+        assert!(!broadcast.is_watched(&other));
+
+        // Slow watchers skip ahead
+        for i in 0..WATCH_CAPACITY * 2 {
+            broadcast.notify(&path, Version::new(format!("a{}", i)));
+        }
+        let expected = Version::new(format!("a{}", WATCH_CAPACITY));
+        assert_eq!(next(&mut first).await, Some(expected));
+
+        drop(first);
+        drop(second);
+        broadcast.notify(&path, Version::new("a2".to_string()));
+        assert_eq!(broadcast.watched_paths(), 0);
+        assert!(broadcast.senders.is_empty());
+    }
+}
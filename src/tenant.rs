@@ -0,0 +1,175 @@
+//! Serving several customers' APIs from one server
+//!
+//! With a [`TenantResolver`] set on the [`BpxServer`](crate::BpxServer),
+//! every request is assigned a [`TenantId`], e.g. from a header an
+//! authenticating gateway sets ([`HeaderTenant`]) or from the host it was
+//! sent to ([`HostTenant`]). Requests no tenant is found for fail with
+//! [`BpxError::UnknownTenant`](crate::BpxError::UnknownTenant).
+//!
+//! Within a tenant's requests:
+//! - resources are looked up in the [`ResourceStore`](crate::ResourceStore)
+//!   under `/{tenant}{path}`, so one store keeps tenants apart
+//! - sessions are opened with
+//!   [`StateManager::get_or_create_tenant_session`](crate::StateManager::get_or_create_tenant_session),
+//!   so with a [`TenantStateManager`](crate::state::TenantStateManager)
+//!   neither sessions nor their limits are shared
+//! - rate limits are kept per tenant and session
+//! - responses are counted per tenant as well
+//!   (see [`BpxServer::tenant_stats`](crate::BpxServer::tenant_stats))
+
+use crate::TenantId;
+use hyper::{HeaderMap, Uri, header};
+
+/// Header naming the tenant by default
+pub const TENANT_HEADER: &str = "X-BPX-Tenant";
+
+/// Finds the tenant a request belongs to
+pub trait TenantResolver: Send + Sync {
+    /// Tenant of the request with `headers` sent to `uri`, if any
+    fn resolve(&self, headers: &HeaderMap, uri: &Uri) -> Option<TenantId>;
+}
+
+impl<F> TenantResolver for F
+where
+    F: Fn(&HeaderMap, &Uri) -> Option<TenantId> + Send + Sync,
+{
+    fn resolve(&self, headers: &HeaderMap, uri: &Uri) -> Option<TenantId> {
+        self(headers, uri)
+    }
+}
+
+/// Tenant named by a request header
+///
+/// Only trust a header that a gateway in front of the server sets after
+/// authenticating the caller, and strips from what clients send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderTenant {
+    /// Header to read
+    pub header: String,
+}
+
+impl Default for HeaderTenant {
+    fn default() -> Self {
+        Self {
+            header: TENANT_HEADER.to_string(),
+        }
+    }
+}
+
+impl TenantResolver for HeaderTenant {
+    fn resolve(&self, headers: &HeaderMap, _uri: &Uri) -> Option<TenantId> {
+        let value = headers.get(self.header.as_str())?.to_str().ok()?;
+        tenant_id(value.trim())
+    }
+}
+
+/// Tenant named by the subdomain a request was sent to, e.g. `acme` for
+/// `acme.api.example.com` under the domain `api.example.com`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostTenant {
+    /// Domain tenants are subdomains of
+    pub domain: String,
+}
+
+impl HostTenant {
+    /// Tenants are subdomains of `domain`
+    pub fn new(domain: impl Into<String>) -> Self {
+        Self {
+            domain: domain.into(),
+        }
+    }
+}
+
+impl TenantResolver for HostTenant {
+    fn resolve(&self, headers: &HeaderMap, uri: &Uri) -> Option<TenantId> {
+        // HTTP/2 requests carry the host in the URI instead
+        let host = match headers.get(header::HOST) {
+            Some(host) => host.to_str().ok()?,
+            None => uri.host()?,
+        };
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(host, |(host, _)| host);
+        let subdomain = host
+            .len()
+            .checked_sub(self.domain.len() + 1)
+            .filter(|&split| {
+                host.as_bytes()[split] == b'.'
+                    && host[split + 1..].eq_ignore_ascii_case(&self.domain)
+            })
+            .map(|split| &host[..split])?;
+        if subdomain.contains('.') {
+            return None;
+        }
+        tenant_id(&subdomain.to_ascii_lowercase())
+    }
+}
+
+/// `id` as a tenant, if it's a non-empty run of ASCII letters, digits, `-`,
+/// `_` and `.` (other than `.` or `..`) and so safe to put in a path
+pub fn tenant_id(id: &str) -> Option<TenantId> {
+    let safe = !id.is_empty()
+        && id != "."
+        && id != ".."
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    safe.then(|| TenantId::new(id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_resolvers() {
+        let uri = Uri::from_static("/api/feed");
+        let by_header = HeaderTenant::default();
+        assert_eq!(
+            by_header.resolve(&headers(TENANT_HEADER, "acme"), &uri),
+            Some(TenantId::new("acme".to_string()))
+        );
+        assert_eq!(by_header.resolve(&HeaderMap::new(), &uri), None);
+        for unsafe_id in ["", "..", "a/b", "a b", "%2e"] {
+            assert_eq!(tenant_id(unsafe_id), None, "{:?}", unsafe_id);
+        }
+
+        let by_host = HostTenant::new("api.example.com");
+        assert_eq!(
+            by_host.resolve(&headers("host", "Acme.api.example.com:8443"), &uri),
+            Some(TenantId::new("acme".to_string()))
+        );
+        assert_eq!(
+            by_host.resolve(
+                &HeaderMap::new(),
+                &Uri::from_static("https://globex.api.example.com/api/feed")
+            ),
+            Some(TenantId::new("globex".to_string()))
+        );
+        for host in ["api.example.com", "a.b.api.example.com", "acme.example.org"] {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, HeaderValue::from_static(host));
+            assert_eq!(by_host.resolve(&headers, &uri), None, "{}", host);
+        }
+
+        let by_closure = |headers: &HeaderMap, _: &Uri| {
+            headers
+                .get(header::AUTHORIZATION)
+                .filter(|token| *token == "Bearer acme-token")
+                .map(|_| TenantId::new("acme".to_string()))
+        };
+        assert!(
+            by_closure
+                .resolve(&headers("authorization", "Bearer acme-token"), &uri)
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! What a request that would be issued a new session gets
+//!
+//! A session is created for every request presenting none, or presenting
+//! an ID the server doesn't know, so a client making up a new ID per
+//! request makes the server hold a new session per request.
+//! [`BpxConfig::unknown_sessions`](crate::BpxConfig::unknown_sessions)
+//! either creates them as usual, refuses requests presenting an unknown
+//! session with [`BpxError::ClientStateNotFound`] (`404`, code
+//! `not-found`; [`BpxClient`](crate::BpxClient) then drops the session and
+//! retries without one), or lets sessions be created at a limited rate,
+//! per peer or for the whole server, refusing requests past it with
+//! [`BpxError::RateLimited`]. Whether a session is known is asked of the
+//! [`StateManager`](crate::StateManager) (see
+//! [`StateManager::has_session`](crate::StateManager::has_session)), within
+//! the request's tenant if it names one.
+
+use crate::{
+    BpxError, SessionId, StateManager, TenantId,
+    rate_limit::{RateLimit, RateLimiter},
+};
+use std::net::IpAddr;
+
+/// What a request that would be issued a new session gets
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum UnknownSessionPolicy {
+    /// A new session
+    #[default]
+    Create,
+    /// A new session if it presented none; presenting an unknown session
+    /// gets `404` with code `not-found`
+    Reject,
+    /// A new session while the rate allows, taken from each peer's bucket
+    /// with [`RateLimit::per_peer`] or from one bucket for the whole server;
+    /// past it, `429` with code `rate-limited`
+    Limit(RateLimit),
+}
+
+/// Enforces an [`UnknownSessionPolicy`]
+pub struct SessionGate {
+    policy: UnknownSessionPolicy,
+    limiter: Option<RateLimiter>,
+}
+
+impl SessionGate {
+    /// Enforce `policy`
+    pub fn new(policy: UnknownSessionPolicy) -> Self {
+        let limiter = match &policy {
+            UnknownSessionPolicy::Limit(limit) => Some(RateLimiter::new(limit.clone())),
+            _ => None,
+        };
+        Self { policy, limiter }
+    }
+
+    /// Refuse a request presenting `session`, from `peer`, if it would be
+    /// issued a new session the policy doesn't allow
+    pub async fn check(
+        &self,
+        state_manager: &dyn StateManager,
+        tenant: Option<&TenantId>,
+        session: Option<SessionId>,
+        peer: Option<IpAddr>,
+    ) -> Result<(), BpxError> {
+        if let Some(session) = &session {
+            let known = match tenant {
+                Some(tenant) => state_manager.has_tenant_session(tenant, session).await,
+                None => state_manager.has_session(session).await,
+            };
+            if known {
+                return Ok(());
+            }
+        }
+        match (&self.limiter, session) {
+            (Some(limiter), _) => limiter.check_peer(peer),
+            (None, Some(session)) if self.policy == UnknownSessionPolicy::Reject => {
+                Err(BpxError::ClientStateNotFound { client_id: session })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of peers with a bucket of session creations
+    pub fn tracked(&self) -> usize {
+        self.limiter.as_ref().map_or(0, RateLimiter::tracked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BpxConfig, state::InMemoryStateManager};
+
+    #[tokio::test]
+    async fn test_unknown_sessions_rejected_or_limited() {
+        let state = InMemoryStateManager::new(BpxConfig::default());
+        let known = state.get_or_create_session(None).await.id().clone();
+        let made_up = SessionId::new("made-up".to_string());
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let reject = SessionGate::new(UnknownSessionPolicy::Reject);
+        for session in [None, Some(known.clone())] {
+            assert!(reject.check(&state, None, session, Some(a)).await.is_ok());
+        }
+        assert!(matches!(
+            reject
+                .check(&state, None, Some(made_up.clone()), Some(a))
+                .await,
+            Err(BpxError::ClientStateNotFound { .. })
+        ));
+        assert_eq!(state.session_count(), 1);
+
+        // Creations count against the peer, resuming doesn't
+        let limit = SessionGate::new(UnknownSessionPolicy::Limit(RateLimit {
+            burst: 1,
+            per_second: 0,
+            per_peer: true,
+        }));
+        assert!(limit.check(&state, None, None, Some(a)).await.is_ok());
+        assert!(matches!(
+            limit
+                .check(&state, None, Some(made_up.clone()), Some(a))
+                .await,
+            Err(BpxError::RateLimited { .. })
+        ));
+        assert!(
+            limit
+                .check(&state, None, Some(known), Some(a))
+                .await
+                .is_ok()
+        );
+        assert!(
+            limit
+                .check(&state, None, Some(made_up), Some(b))
+                .await
+                .is_ok()
+        );
+        assert_eq!(limit.tracked(), 2);
+    }
+}